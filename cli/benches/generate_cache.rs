@@ -0,0 +1,127 @@
+use lazy_static::lazy_static;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::{env, fs};
+use tree_sitter_cli::generate::{generate_in_memory, Diagnostics, GenerateOptions};
+
+include!("../src/tests/helpers/dirs.rs");
+
+// Compares the time that it takes to generate each fixture grammar's parser without the
+// lex table cache, with an empty cache, and with a cache that was filled by a previous
+// run, as happens when only syntax rules change between runs.
+
+lazy_static! {
+    static ref LANGUAGE_FILTER: Option<String> =
+        env::var("TREE_SITTER_BENCHMARK_LANGUAGE_FILTER").ok();
+    static ref SYNTHETIC_RULE_COUNT: usize = env::var("TREE_SITTER_BENCHMARK_RULE_COUNT")
+        .map(|s| usize::from_str_radix(&s, 10).unwrap())
+        .unwrap_or(200);
+}
+
+fn main() {
+    let mut grammar_paths = fs::read_dir(GRAMMARS_DIR.as_path())
+        .map(|entries| {
+            entries
+                .map(|entry| entry.unwrap().path().join("src").join("grammar.json"))
+                .filter(|path| path.exists())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or(Vec::new());
+    grammar_paths.sort();
+
+    // The fixture grammars are only present after running `script/fetch-fixtures`, so
+    // a large generated grammar with many keywords and token patterns is always included.
+    let mut grammars = vec![("synthetic".to_string(), synthetic_grammar())];
+    for grammar_path in grammar_paths {
+        let language_name = grammar_path
+            .parent()
+            .and_then(|p| p.parent())
+            .and_then(|p| p.file_name())
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        grammars.push((language_name, fs::read_to_string(&grammar_path).unwrap()));
+    }
+
+    for (language_name, grammar_json) in grammars {
+        if let Some(filter) = LANGUAGE_FILTER.as_ref() {
+            if language_name != *filter {
+                continue;
+            }
+        }
+
+        let cache_dir = tempfile::tempdir().unwrap();
+        let uncached = generate(&grammar_json, None);
+        let cold = generate(&grammar_json, Some(cache_dir.path()));
+        let warm = generate(&grammar_json, Some(cache_dir.path()));
+
+        eprintln!("\nLanguage: {}", language_name);
+        eprintln!("  without cache:  {} ms", uncached.as_millis());
+        eprintln!("  cold cache:     {} ms", cold.as_millis());
+        eprintln!(
+            "  warm cache:     {} ms ({:.2}x faster)",
+            warm.as_millis(),
+            uncached.as_secs_f64() / warm.as_secs_f64()
+        );
+    }
+}
+
+// A grammar with one statement rule per keyword, where each statement also uses a token
+// pattern of its own.
+fn synthetic_grammar() -> String {
+    let statement_names = (0..*SYNTHETIC_RULE_COUNT)
+        .map(|i| format!("statement{}", i))
+        .collect::<Vec<_>>();
+    let mut rules = vec![
+        r#""program": {"type": "REPEAT", "content": {"type": "SYMBOL", "name": "_statement"}}"#
+            .to_string(),
+        format!(
+            r#""_statement": {{"type": "CHOICE", "members": [{}]}}"#,
+            statement_names
+                .iter()
+                .map(|name| format!(r#"{{"type": "SYMBOL", "name": "{}"}}"#, name))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        r#""identifier": {"type": "PATTERN", "value": "[a-z_]+"}"#.to_string(),
+    ];
+    for (i, name) in statement_names.iter().enumerate() {
+        rules.push(format!(
+            r#""{}": {{"type": "SEQ", "members": [
+                {{"type": "STRING", "value": "keyword{}"}},
+                {{"type": "SYMBOL", "name": "identifier"}},
+                {{"type": "SYMBOL", "name": "literal{}"}},
+                {{"type": "STRING", "value": ";"}}
+            ]}}"#,
+            name, i, i
+        ));
+        rules.push(format!(
+            r#""literal{}": {{"type": "PATTERN", "value": "{}x[0-9a-f]+(\\.[0-9]+)?"}}"#,
+            i, i
+        ));
+    }
+    format!(
+        r#"{{
+            "name": "synthetic",
+            "word": "identifier",
+            "extras": [{{"type": "PATTERN", "value": "\\s"}}],
+            "rules": {{{}}}
+        }}"#,
+        rules.join(",\n")
+    )
+}
+
+fn generate(grammar_json: &str, cache_dir: Option<&Path>) -> Duration {
+    let time = Instant::now();
+    generate_in_memory(
+        grammar_json,
+        &GenerateOptions {
+            cache_dir,
+            ..Default::default()
+        },
+        &mut Diagnostics::new(),
+    )
+    .unwrap();
+    time.elapsed()
+}
@@ -0,0 +1,161 @@
+use lazy_static::lazy_static;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use std::{env, fs};
+use tree_sitter::Language;
+use tree_sitter_cli::generate::generate_parser_for_grammar;
+use tree_sitter_cli::loader::Loader;
+use tree_sitter_highlight::{HighlightContext, HighlightOptions, Highlighter};
+
+include!("../src/tests/helpers/dirs.rs");
+
+// Compares the time per document of highlighting many tiny documents with individual calls
+// to `Highlighter::highlight` and with a single call to `Highlighter::highlight_batch`.
+
+lazy_static! {
+    static ref DOCUMENT_COUNT: usize = env::var("TREE_SITTER_BENCHMARK_DOCUMENT_COUNT")
+        .map(|s| usize::from_str_radix(&s, 10).unwrap())
+        .unwrap_or(10000);
+    static ref REPETITION_COUNT: usize = env::var("TREE_SITTER_BENCHMARK_REPETITION_COUNT")
+        .map(|s| usize::from_str_radix(&s, 10).unwrap())
+        .unwrap_or(5);
+    static ref TEST_LOADER: Loader = Loader::new(SCRATCH_DIR.clone());
+}
+
+const GRAMMAR: &str = r#"{
+    "name": "highlight_batch_benchmark",
+    "extras": [{"type": "PATTERN", "value": "\\s"}],
+    "rules": {
+        "program": {"type": "REPEAT", "content": {"type": "SYMBOL", "name": "statement"}},
+        "statement": {
+            "type": "SEQ",
+            "members": [
+                {"type": "SYMBOL", "name": "identifier"},
+                {"type": "STRING", "value": "="},
+                {
+                    "type": "CHOICE",
+                    "members": [
+                        {"type": "SYMBOL", "name": "identifier"},
+                        {"type": "SYMBOL", "name": "number"},
+                        {"type": "SYMBOL", "name": "string"}
+                    ]
+                },
+                {"type": "STRING", "value": ";"}
+            ]
+        },
+        "identifier": {"type": "PATTERN", "value": "[a-z]+"},
+        "number": {"type": "PATTERN", "value": "\\d+"},
+        "string": {"type": "PATTERN", "value": "\"[^\"]*\""}
+    }
+}"#;
+
+const HIGHLIGHTS_QUERY: &str = r#"
+(statement (identifier) @variable)
+(identifier) @variable.parameter
+(number) @number
+(string) @string
+"=" @operator
+";" @punctuation.delimiter
+"#;
+
+fn main() {
+    let highlighter = Highlighter::new(
+        [
+            "number",
+            "operator",
+            "punctuation.delimiter",
+            "string",
+            "variable",
+            "variable.parameter",
+        ]
+        .iter()
+        .cloned()
+        .map(String::from)
+        .collect(),
+    );
+    let config = highlighter
+        .load_configuration(get_language(), HIGHLIGHTS_QUERY, "", "")
+        .unwrap();
+    let documents = (0..*DOCUMENT_COUNT)
+        .map(|i| format!("x = {};\ny = \"s{}\";\nz = x;\n", i, i).into_bytes())
+        .collect::<Vec<_>>();
+    let byte_count = documents.iter().map(Vec::len).sum::<usize>();
+
+    eprintln!(
+        "Highlighting {} documents ({} bytes) with {} repetitions",
+        documents.len(),
+        byte_count,
+        *REPETITION_COUNT
+    );
+
+    let individual = measure(|| {
+        let mut context = HighlightContext::new();
+        let mut event_count = 0;
+        for document in &documents {
+            let events = highlighter
+                .highlight(&mut context, &config, document, None, |_| None)
+                .unwrap();
+            for event in events {
+                event.unwrap();
+                event_count += 1;
+            }
+        }
+        event_count
+    });
+    report("Individual calls", individual);
+
+    let batched = measure(|| {
+        let mut context = HighlightContext::new();
+        let mut event_count = 0;
+        let jobs = documents
+            .iter()
+            .map(|document| (&config, document.as_slice()));
+        let mut batch =
+            highlighter.highlight_batch(&mut context, jobs, HighlightOptions::new(), |_| None);
+        while let Some((_, events)) = batch.next() {
+            let events = events.unwrap();
+            event_count += events.len();
+            batch.recycle(events);
+        }
+        event_count
+    });
+    report("Batched", batched);
+
+    eprintln!(
+        "  Overhead reduction:  {:.1}%\n",
+        100.0 * (1.0 - batched.as_secs_f64() / individual.as_secs_f64())
+    );
+}
+
+// Run the given function repeatedly, returning the average time per document.
+fn measure(mut f: impl FnMut() -> usize) -> Duration {
+    let mut event_counts = Vec::new();
+    let time = Instant::now();
+    for _ in 0..*REPETITION_COUNT {
+        event_counts.push(f());
+    }
+    assert!(event_counts.windows(2).all(|w| w[0] == w[1]));
+    time.elapsed() / (*REPETITION_COUNT * *DOCUMENT_COUNT) as u32
+}
+
+fn report(name: &str, duration: Duration) {
+    eprintln!(
+        "  {:20} {:.2} us per document",
+        format!("{}:", name),
+        duration.as_secs_f64() * 1_000_000.0
+    );
+}
+
+fn get_language() -> Language {
+    let (name, parser_code) = generate_parser_for_grammar(GRAMMAR).unwrap();
+    let parser_path = SCRATCH_DIR.join(format!("{}-parser.c", name));
+    if !fs::read_to_string(&parser_path)
+        .map(|content| content == parser_code)
+        .unwrap_or(false)
+    {
+        fs::write(&parser_path, parser_code).unwrap();
+    }
+    TEST_LOADER
+        .load_language_from_sources(&name, &HEADER_DIR, &parser_path, &None)
+        .unwrap()
+}
@@ -0,0 +1,186 @@
+use lazy_static::lazy_static;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use std::{env, fs};
+use tree_sitter::Language;
+use tree_sitter_cli::generate::generate_parser_for_grammar;
+use tree_sitter_cli::loader::Loader;
+use tree_sitter_highlight::{HighlightContext, HighlightOptions, Highlighter};
+
+include!("../src/tests/helpers/dirs.rs");
+
+// Compares the time of highlighting a large HTML-like document full of script elements,
+// with injections that are combined with the highlights of the document, and with
+// injections that replace them using the `injection.replace` property.
+
+lazy_static! {
+    static ref SCRIPT_COUNT: usize = env::var("TREE_SITTER_BENCHMARK_SCRIPT_COUNT")
+        .map(|s| usize::from_str_radix(&s, 10).unwrap())
+        .unwrap_or(5000);
+    static ref REPETITION_COUNT: usize = env::var("TREE_SITTER_BENCHMARK_REPETITION_COUNT")
+        .map(|s| usize::from_str_radix(&s, 10).unwrap())
+        .unwrap_or(5);
+    static ref TEST_LOADER: Loader = Loader::new(SCRATCH_DIR.clone());
+}
+
+const DOCUMENT_GRAMMAR: &str = r#"{
+    "name": "highlight_injections_benchmark_document",
+    "rules": {
+        "document": {"type": "REPEAT", "content": {"type": "SYMBOL", "name": "script_element"}},
+        "script_element": {
+            "type": "SEQ",
+            "members": [
+                {"type": "STRING", "value": "<script>"},
+                {"type": "SYMBOL", "name": "raw_text"},
+                {"type": "STRING", "value": "</script>"}
+            ]
+        },
+        "raw_text": {"type": "PATTERN", "value": "[^<]+"}
+    }
+}"#;
+
+const SCRIPT_GRAMMAR: &str = r#"{
+    "name": "highlight_injections_benchmark_script",
+    "rules": {
+        "program": {"type": "REPEAT", "content": {"type": "SYMBOL", "name": "statement"}},
+        "statement": {
+            "type": "SEQ",
+            "members": [
+                {"type": "SYMBOL", "name": "identifier"},
+                {"type": "STRING", "value": "="},
+                {
+                    "type": "CHOICE",
+                    "members": [
+                        {"type": "SYMBOL", "name": "identifier"},
+                        {"type": "SYMBOL", "name": "number"}
+                    ]
+                },
+                {"type": "STRING", "value": ";"}
+            ]
+        },
+        "identifier": {"type": "PATTERN", "value": "[a-z]+"},
+        "number": {"type": "PATTERN", "value": "\\d+"}
+    }
+}"#;
+
+const DOCUMENT_HIGHLIGHTS_QUERY: &str = r#"
+"<script>" @tag
+"</script>" @tag
+(raw_text) @string
+"#;
+
+const SCRIPT_HIGHLIGHTS_QUERY: &str = r#"
+(statement (identifier) @variable)
+(identifier) @variable.parameter
+(number) @number
+"=" @operator
+";" @punctuation.delimiter
+"#;
+
+const INJECTIONS_QUERY: &str = r#"
+((script_element (raw_text) @injection.content) @injection.site
+ (set! injection.language "script"))
+"#;
+
+const REPLACING_INJECTIONS_QUERY: &str = r#"
+((script_element (raw_text) @injection.content) @injection.site
+ (set! injection.language "script")
+ (set! injection.replace))
+"#;
+
+fn main() {
+    let highlighter = Highlighter::new(
+        [
+            "number",
+            "operator",
+            "punctuation.delimiter",
+            "string",
+            "tag",
+            "variable",
+            "variable.parameter",
+        ]
+        .iter()
+        .cloned()
+        .map(String::from)
+        .collect(),
+    );
+    let document_language = get_language(DOCUMENT_GRAMMAR);
+    let script_config = highlighter
+        .load_configuration(
+            get_language(SCRIPT_GRAMMAR),
+            SCRIPT_HIGHLIGHTS_QUERY,
+            "",
+            "",
+        )
+        .unwrap();
+    let document = (0..*SCRIPT_COUNT)
+        .map(|i| format!("<script>\nx = {};\ny = x;\n</script>\n", i))
+        .collect::<String>()
+        .into_bytes();
+
+    eprintln!(
+        "Highlighting a document with {} scripts ({} bytes) with {} repetitions",
+        *SCRIPT_COUNT,
+        document.len(),
+        *REPETITION_COUNT
+    );
+
+    for (name, injections_query) in &[
+        ("Combined highlights", INJECTIONS_QUERY),
+        ("Replaced highlights", REPLACING_INJECTIONS_QUERY),
+    ] {
+        let config = highlighter
+            .load_configuration(
+                document_language,
+                DOCUMENT_HIGHLIGHTS_QUERY,
+                injections_query,
+                "",
+            )
+            .unwrap();
+        let (duration, event_count) = measure(|| {
+            let mut context = HighlightContext::new();
+            let events = highlighter
+                .highlight_with_options(
+                    &mut context,
+                    &config,
+                    &document,
+                    HighlightOptions::new(),
+                    |_| Some(&script_config),
+                )
+                .unwrap();
+            events.map(Result::unwrap).count()
+        });
+        eprintln!(
+            "  {:20} {:.2} ms, {} events",
+            format!("{}:", name),
+            duration.as_secs_f64() * 1000.0,
+            event_count
+        );
+    }
+    eprintln!();
+}
+
+// Run the given function repeatedly, returning the average time, and the number of events.
+fn measure(mut f: impl FnMut() -> usize) -> (Duration, usize) {
+    let mut event_counts = Vec::new();
+    let time = Instant::now();
+    for _ in 0..*REPETITION_COUNT {
+        event_counts.push(f());
+    }
+    assert!(event_counts.windows(2).all(|w| w[0] == w[1]));
+    (time.elapsed() / *REPETITION_COUNT as u32, event_counts[0])
+}
+
+fn get_language(grammar: &str) -> Language {
+    let (name, parser_code) = generate_parser_for_grammar(grammar).unwrap();
+    let parser_path = SCRATCH_DIR.join(format!("{}-parser.c", name));
+    if !fs::read_to_string(&parser_path)
+        .map(|content| content == parser_code)
+        .unwrap_or(false)
+    {
+        fs::write(&parser_path, parser_code).unwrap();
+    }
+    TEST_LOADER
+        .load_language_from_sources(&name, &HEADER_DIR, &parser_path, &None)
+        .unwrap()
+}
@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::str;
+use tree_sitter_cli::generate::generate_parser_for_grammar;
+
+// Run the whole pipeline from grammar.json to C code. Invalid grammars must be
+// reported as errors, never as panics.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(grammar_json) = str::from_utf8(data) {
+        let _ = generate_parser_for_grammar(grammar_json);
+    }
+});
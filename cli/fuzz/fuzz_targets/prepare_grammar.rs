@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::str;
+use tree_sitter_cli::generate::grammar::{parse_grammar, prepare_grammar};
+
+// Run only the grammar-preparation passes. These are much cheaper than
+// building parse tables, so this target explores more inputs per second.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(grammar_json) = str::from_utf8(data) {
+        if let Ok(input_grammar) = parse_grammar(grammar_json) {
+            let _ = prepare_grammar(&input_grammar);
+        }
+    }
+});
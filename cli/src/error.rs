@@ -74,9 +74,18 @@ impl<'a> From<QueryError> for Error {
     }
 }
 
+impl<'a> From<tree_sitter_highlight::ConfigurationError> for Error {
+    fn from(error: tree_sitter_highlight::ConfigurationError) -> Self {
+        Error::new(format!("Query error: {}", error))
+    }
+}
+
 impl<'a> From<tree_sitter_highlight::Error> for Error {
     fn from(error: tree_sitter_highlight::Error) -> Self {
-        Error::new(format!("{:?}", error))
+        match error {
+            tree_sitter_highlight::Error::InvalidQuery(error) => Self::from(error),
+            error => Error::new(error.to_string()),
+        }
     }
 }
 
@@ -2,9 +2,10 @@ use super::item::{ParseItem, ParseItemSet, ParseItemSetCore};
 use super::item_set_builder::ParseItemSetBuilder;
 use crate::error::{Error, Result};
 use crate::generate::grammars::{
-    InlinedProductionMap, LexicalGrammar, SyntaxGrammar, VariableType,
+    InlinedProductionMap, LexicalGrammar, Production, SyntaxGrammar, VariableType,
 };
 use crate::generate::node_types::VariableInfo;
+use crate::generate::progress::Progress;
 use crate::generate::rules::{Associativity, Symbol, SymbolType, TokenSet};
 use crate::generate::tables::{
     FieldLocation, GotoAction, ParseAction, ParseState, ParseStateId, ParseTable, ParseTableEntry,
@@ -22,6 +23,26 @@ type SymbolSequence = Vec<Symbol>;
 
 type AuxiliarySymbolSequence = Vec<AuxiliarySymbolInfo>;
 pub(crate) type ParseStateInfo<'a> = (SymbolSequence, ParseItemSet<'a>);
+type BuildResult<'a> = (
+    ParseTable,
+    Vec<ParseStateInfo<'a>>,
+    Vec<RuntimeConflict<'a>>,
+);
+pub(crate) type ParseTableResult<'a> = (
+    ParseTable,
+    Vec<TokenSet>,
+    Vec<ParseStateInfo<'a>>,
+    Vec<RuntimeConflict<'a>>,
+);
+
+// A conflict that was not resolved statically, because it was listed in the
+// grammar's `conflicts`. At runtime, it will be resolved by comparing the
+// dynamic precedences of the competing interpretations.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct RuntimeConflict<'a> {
+    pub symbols: Vec<Symbol>,
+    pub productions: Vec<(usize, &'a Production)>,
+}
 
 #[derive(Clone)]
 struct AuxiliarySymbolInfo {
@@ -39,16 +60,18 @@ struct ParseTableBuilder<'a> {
     syntax_grammar: &'a SyntaxGrammar,
     lexical_grammar: &'a LexicalGrammar,
     variable_info: &'a Vec<VariableInfo>,
+    progress: Option<&'a Progress>,
     core_ids_by_core: HashMap<ParseItemSetCore<'a>, usize>,
     state_ids_by_item_set: HashMap<ParseItemSet<'a>, ParseStateId>,
     parse_state_info_by_id: Vec<ParseStateInfo<'a>>,
     parse_state_queue: VecDeque<ParseStateQueueEntry>,
     non_terminal_extra_states: Vec<(Symbol, usize)>,
+    runtime_conflicts: Vec<RuntimeConflict<'a>>,
     parse_table: ParseTable,
 }
 
 impl<'a> ParseTableBuilder<'a> {
-    fn build(mut self) -> Result<(ParseTable, Vec<ParseStateInfo<'a>>)> {
+    fn build(mut self) -> Result<BuildResult<'a>> {
         // Ensure that the empty alias sequence has index 0.
         self.parse_table
             .production_infos
@@ -114,11 +137,19 @@ impl<'a> ParseTableBuilder<'a> {
                 entry.state_id,
                 item_set,
             )?;
+
+            if let Some(progress) = self.progress {
+                progress.update(self.parse_table.states.len(), None);
+            }
         }
 
         self.remove_precedences();
 
-        Ok((self.parse_table, self.parse_state_info_by_id))
+        Ok((
+            self.parse_table,
+            self.parse_state_info_by_id,
+            self.runtime_conflicts,
+        ))
     }
 
     fn add_parse_state(
@@ -358,7 +389,7 @@ impl<'a> ParseTableBuilder<'a> {
 
     fn handle_conflict(
         &mut self,
-        item_set: &ParseItemSet,
+        item_set: &ParseItemSet<'a>,
         state_id: ParseStateId,
         preceding_symbols: &SymbolSequence,
         preceding_auxiliary_symbols: &Vec<AuxiliarySymbolInfo>,
@@ -512,11 +543,26 @@ impl<'a> ParseTableBuilder<'a> {
         actual_conflict.dedup();
 
         // If this set of symbols has been whitelisted, then there's no error.
+        // Record the conflict so that the productions' dynamic precedences can
+        // be validated once the whole table has been built.
         if self
             .syntax_grammar
             .expected_conflicts
             .contains(&actual_conflict)
         {
+            let mut productions = conflicting_items
+                .iter()
+                .map(|item| (item.variable_index as usize, item.production))
+                .collect::<Vec<_>>();
+            productions.sort_unstable_by_key(|(i, p)| (*i, *p as *const Production));
+            productions.dedup_by_key(|(i, p)| (*i, *p as *const Production));
+            let conflict = RuntimeConflict {
+                symbols: actual_conflict,
+                productions,
+            };
+            if !self.runtime_conflicts.contains(&conflict) {
+                self.runtime_conflicts.push(conflict);
+            }
             return Ok(());
         }
 
@@ -634,8 +680,8 @@ impl<'a> ParseTableBuilder<'a> {
                     }
                     write!(
                         &mut msg,
-                        " `{}`",
-                        self.symbol_name(&Symbol::non_terminal(item.variable_index as usize))
+                        " {}",
+                        self.rule_description(item.variable_index, preceding_auxiliary_symbols)
                     )
                     .unwrap();
                 }
@@ -646,9 +692,9 @@ impl<'a> ParseTableBuilder<'a> {
                 resolution_count += 1;
                 write!(
                     &mut msg,
-                    "  {}:  Specify a higher precedence in `{}` than in the other rules.\n",
+                    "  {}:  Specify a higher precedence in {} than in the other rules.\n",
                     resolution_count,
-                    self.symbol_name(&Symbol::non_terminal(item.variable_index as usize))
+                    self.rule_description(item.variable_index, preceding_auxiliary_symbols)
                 )
                 .unwrap();
             }
@@ -747,7 +793,7 @@ impl<'a> ParseTableBuilder<'a> {
 
         for (i, step) in item.production.steps.iter().enumerate() {
             production_info.alias_sequence.push(step.alias.clone());
-            if let Some(field_name) = &step.field_name {
+            for field_name in step.field_name.iter().chain(&step.inner_field_names) {
                 production_info
                     .field_map
                     .entry(field_name.clone())
@@ -798,6 +844,39 @@ impl<'a> ParseTableBuilder<'a> {
         }
     }
 
+    // Describe a rule in a conflict message. Auxiliary rules don't appear in the grammar,
+    // so they are described along with the rules whose repetitions they implement.
+    fn rule_description(
+        &self,
+        variable_index: u32,
+        preceding_auxiliary_symbols: &[AuxiliarySymbolInfo],
+    ) -> String {
+        let symbol = Symbol::non_terminal(variable_index as usize);
+        let name = self.symbol_name(&symbol);
+        if !self.syntax_grammar.variables[symbol.index].is_auxiliary() {
+            return format!("`{}`", name);
+        }
+        let parent_names = preceding_auxiliary_symbols
+            .iter()
+            .rev()
+            .find(|info| info.auxiliary_symbol == symbol)
+            .map_or(Vec::new(), |info| {
+                info.parent_symbols
+                    .iter()
+                    .map(|parent| format!("`{}`", self.symbol_name(parent)))
+                    .collect()
+            });
+        if parent_names.is_empty() {
+            format!("`{}`", name)
+        } else {
+            format!(
+                "`{}` (the auxiliary rule for a repetition in {})",
+                name,
+                parent_names.join(" and ")
+            )
+        }
+    }
+
     fn symbol_name(&self, symbol: &Symbol) -> String {
         match symbol.kind {
             SymbolType::End => "EOF".to_string(),
@@ -858,7 +937,8 @@ pub(crate) fn build_parse_table<'a>(
     lexical_grammar: &'a LexicalGrammar,
     inlines: &'a InlinedProductionMap,
     variable_info: &'a Vec<VariableInfo>,
-) -> Result<(ParseTable, Vec<TokenSet>, Vec<ParseStateInfo<'a>>)> {
+    progress: Option<&'a Progress>,
+) -> Result<ParseTableResult<'a>> {
     let item_set_builder = ParseItemSetBuilder::new(syntax_grammar, lexical_grammar, inlines);
     let mut following_tokens = vec![TokenSet::new(); lexical_grammar.variables.len()];
     populate_following_tokens(
@@ -868,12 +948,14 @@ pub(crate) fn build_parse_table<'a>(
         &item_set_builder,
     );
 
-    let (table, item_sets) = ParseTableBuilder {
+    let (table, item_sets, runtime_conflicts) = ParseTableBuilder {
         syntax_grammar,
         lexical_grammar,
         item_set_builder,
         variable_info,
+        progress,
         non_terminal_extra_states: Vec::new(),
+        runtime_conflicts: Vec::new(),
         state_ids_by_item_set: HashMap::new(),
         core_ids_by_core: HashMap::new(),
         parse_state_info_by_id: Vec::new(),
@@ -888,5 +970,5 @@ pub(crate) fn build_parse_table<'a>(
     }
     .build()?;
 
-    Ok((table, following_tokens, item_sets))
+    Ok((table, following_tokens, item_sets, runtime_conflicts))
 }
@@ -0,0 +1,321 @@
+use crate::generate::grammars::{LexicalGrammar, SyntaxGrammar};
+use crate::generate::rules::{Symbol, TokenSet};
+use crate::generate::tables::{LexTable, ParseTable};
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const CLI_VERSION: &'static str = env!("CARGO_PKG_VERSION");
+
+// An on-disk cache of the results of the lexical phases of table construction:
+// the keyword set and the lex tables. Besides the lexical grammar, these results
+// depend on the sets of tokens that are valid in each parse state, so those sets
+// are part of each cache key. Editing a syntax rule in a way that doesn't change
+// those sets reuses the cached tables.
+pub(crate) struct LexTableCache {
+    directory: PathBuf,
+    language_name: String,
+    pub hit_count: Cell<usize>,
+    pub miss_count: Cell<usize>,
+    // The time that it originally took to compute the results that were loaded.
+    pub time_saved: Cell<Duration>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheFile<T> {
+    key: u64,
+    checksum: u64,
+    build_duration: Duration,
+    contents: T,
+}
+
+#[derive(Serialize)]
+struct LexTablesRef<'a> {
+    main_lex_table: &'a LexTable,
+    keyword_lex_table: &'a LexTable,
+    lex_state_ids: Vec<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LexTables {
+    main_lex_table: LexTable,
+    keyword_lex_table: LexTable,
+    lex_state_ids: Vec<usize>,
+}
+
+impl LexTableCache {
+    pub fn new(directory: &Path, language_name: &str) -> Self {
+        LexTableCache {
+            directory: directory.to_owned(),
+            language_name: language_name.to_string(),
+            hit_count: Cell::new(0),
+            miss_count: Cell::new(0),
+            time_saved: Cell::new(Duration::default()),
+        }
+    }
+
+    pub fn keywords_key(
+        &self,
+        syntax_grammar: &SyntaxGrammar,
+        lexical_grammar: &LexicalGrammar,
+        parse_table: &ParseTable,
+        following_tokens: &Vec<TokenSet>,
+    ) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        CLI_VERSION.hash(&mut hasher);
+        serde_json::to_string(lexical_grammar)
+            .unwrap()
+            .hash(&mut hasher);
+        syntax_grammar.word_token.hash(&mut hasher);
+        syntax_grammar.excluded_keywords.hash(&mut hasher);
+        following_tokens.hash(&mut hasher);
+        hash_parse_state_tokens(parse_table, &mut hasher);
+        hasher.finish()
+    }
+
+    pub fn lex_tables_key(
+        &self,
+        keywords_key: u64,
+        keywords: &TokenSet,
+        parse_table: &ParseTable,
+    ) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        keywords_key.hash(&mut hasher);
+        keywords.hash(&mut hasher);
+        hash_parse_state_tokens(parse_table, &mut hasher);
+        hasher.finish()
+    }
+
+    pub fn load_keywords(&self, key: u64) -> Option<TokenSet> {
+        self.load::<Vec<usize>>("keywords", key)
+            .map(|indices| indices.into_iter().map(Symbol::terminal).collect())
+    }
+
+    pub fn store_keywords(&self, key: u64, keywords: &TokenSet, build_duration: Duration) {
+        let indices = keywords.iter().map(|s| s.index).collect::<Vec<_>>();
+        self.store("keywords", key, build_duration, indices);
+    }
+
+    pub fn load_lex_tables(
+        &self,
+        key: u64,
+        parse_table: &mut ParseTable,
+    ) -> Option<(LexTable, LexTable)> {
+        let tables = self.load::<LexTables>("lex-tables", key)?;
+        if tables.lex_state_ids.len() != parse_table.states.len() {
+            return None;
+        }
+        for (state, lex_state_id) in parse_table.states.iter_mut().zip(tables.lex_state_ids) {
+            state.lex_state_id = lex_state_id;
+        }
+        Some((tables.main_lex_table, tables.keyword_lex_table))
+    }
+
+    pub fn store_lex_tables(
+        &self,
+        key: u64,
+        main_lex_table: &LexTable,
+        keyword_lex_table: &LexTable,
+        parse_table: &ParseTable,
+        build_duration: Duration,
+    ) {
+        let lex_state_ids = parse_table.states.iter().map(|s| s.lex_state_id).collect();
+        self.store(
+            "lex-tables",
+            key,
+            build_duration,
+            LexTablesRef {
+                main_lex_table,
+                keyword_lex_table,
+                lex_state_ids,
+            },
+        );
+    }
+
+    // Any failure to read a cache file, including a mismatched checksum, is
+    // treated as a cache miss.
+    fn load<T: DeserializeOwned + serde::Serialize>(&self, kind: &str, key: u64) -> Option<T> {
+        let result = fs::read_to_string(self.path(kind))
+            .ok()
+            .and_then(|json| serde_json::from_str::<CacheFile<T>>(&json).ok())
+            .filter(|file| {
+                file.key == key
+                    && serde_json::to_string(&file.contents)
+                        .ok()
+                        .map(|json| hash(&json))
+                        == Some(file.checksum)
+            });
+        if let Some(file) = &result {
+            self.hit_count.set(self.hit_count.get() + 1);
+            self.time_saved
+                .set(self.time_saved.get() + file.build_duration);
+        } else {
+            self.miss_count.set(self.miss_count.get() + 1);
+        }
+        result.map(|file| file.contents)
+    }
+
+    // Failing to write the cache is not an error; the next run will just miss.
+    fn store<T: serde::Serialize>(
+        &self,
+        kind: &str,
+        key: u64,
+        build_duration: Duration,
+        contents: T,
+    ) {
+        if let Ok(json) = serde_json::to_string(&contents) {
+            let file = CacheFile {
+                key,
+                checksum: hash(&json),
+                build_duration,
+                contents,
+            };
+            if let Ok(json) = serde_json::to_string(&file) {
+                fs::create_dir_all(&self.directory).ok();
+                fs::write(self.path(kind), json).ok();
+            }
+        }
+    }
+
+    fn path(&self, kind: &str) -> PathBuf {
+        self.directory
+            .join(format!("{}-{}.json", self.language_name, kind))
+    }
+}
+
+fn hash_parse_state_tokens(parse_table: &ParseTable, hasher: &mut DefaultHasher) {
+    for state in &parse_table.states {
+        let mut tokens = state.terminal_entries.keys().collect::<Vec<_>>();
+        tokens.sort();
+        tokens.hash(hasher);
+    }
+}
+
+fn hash(json: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    json.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::diagnostics::Diagnostics;
+    use crate::generate::parse_grammar::parse_grammar;
+    use crate::generate::prepare_grammar::prepare_grammar;
+    use crate::generate::{generate_parser_for_grammar_with_opts, GenerateOptions};
+
+    const GRAMMAR: &'static str = r#"{
+        "name": "cached",
+        "word": "identifier",
+        "extras": [{"type": "PATTERN", "value": "\\s"}],
+        "rules": {
+            "program": {"type": "REPEAT", "content": {"type": "SYMBOL", "name": "statement"}},
+            "statement": {
+                "type": "SEQ",
+                "members": [
+                    {"type": "STRING", "value": "let"},
+                    {"type": "SYMBOL", "name": "identifier"},
+                    {"type": "STRING", "value": "="},
+                    {"type": "SYMBOL", "name": "_expression"},
+                    {"type": "STRING", "value": ";"}
+                ]
+            },
+            "_expression": {
+                "type": "CHOICE",
+                "members": [
+                    {"type": "SYMBOL", "name": "identifier"},
+                    {"type": "SYMBOL", "name": "number"}
+                ]
+            },
+            "identifier": {"type": "PATTERN", "value": "[a-z]+"},
+            "number": {"type": "PATTERN", "value": "\\d+"}
+        }
+    }"#;
+
+    #[test]
+    fn test_lex_table_cache() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache = LexTableCache::new(cache_dir.path(), "cached");
+
+        // Nothing is cached initially.
+        let c_code = generate(GRAMMAR, Some(&cache));
+        assert_eq!((cache.hit_count.get(), cache.miss_count.get()), (0, 2));
+        assert_eq!(cache.time_saved.get(), Duration::default());
+        assert_eq!(c_code, generate(GRAMMAR, None));
+
+        // Regenerating the same grammar reuses the keywords and the lex tables.
+        assert_eq!(generate(GRAMMAR, Some(&cache)), c_code);
+        assert_eq!((cache.hit_count.get(), cache.miss_count.get()), (2, 2));
+        assert!(cache.time_saved.get() > Duration::default());
+
+        // Editing a syntax rule without changing the tokens that are valid in each
+        // parse state reuses the lex tables.
+        let syntax_edit = GRAMMAR.replace(
+            r#"{"type": "SYMBOL", "name": "identifier"},
+                    {"type": "STRING", "value": "="}"#,
+            r#"{"type": "FIELD", "name": "name", "content": {"type": "SYMBOL", "name": "identifier"}},
+                    {"type": "STRING", "value": "="}"#,
+        );
+        assert_ne!(syntax_edit, GRAMMAR);
+        let c_code = generate(&syntax_edit, Some(&cache));
+        assert_eq!((cache.hit_count.get(), cache.miss_count.get()), (4, 2));
+        assert_eq!(c_code, generate(&syntax_edit, None));
+
+        // Editing a token rule invalidates the cache.
+        let token_edit = syntax_edit.replace(r#""\\d+""#, r#""\\d+(\\.\\d+)?""#);
+        assert_ne!(token_edit, syntax_edit);
+        let c_code = generate(&token_edit, Some(&cache));
+        assert_eq!((cache.hit_count.get(), cache.miss_count.get()), (4, 4));
+        assert_eq!(c_code, generate(&token_edit, None));
+    }
+
+    #[test]
+    fn test_lex_table_cache_with_corrupted_files() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache = LexTableCache::new(cache_dir.path(), "cached");
+        let c_code = generate(GRAMMAR, Some(&cache));
+
+        let path = cache_dir.path().join("cached-lex-tables.json");
+        let json = fs::read_to_string(&path).unwrap();
+        fs::write(
+            &path,
+            json.replacen(
+                "\"accept_action\":null",
+                "\"accept_action\":{\"kind\":\"Terminal\",\"index\":0}",
+                1,
+            ),
+        )
+        .unwrap();
+        fs::write(cache_dir.path().join("cached-keywords.json"), "{").unwrap();
+
+        assert_eq!(generate(GRAMMAR, Some(&cache)), c_code);
+        assert_eq!((cache.hit_count.get(), cache.miss_count.get()), (0, 4));
+    }
+
+    fn generate(grammar_json: &str, cache: Option<&LexTableCache>) -> String {
+        let input_grammar = parse_grammar(grammar_json, &mut Diagnostics::new()).unwrap();
+        let (syntax_grammar, lexical_grammar, inlines, simple_aliases) =
+            prepare_grammar(&input_grammar, &mut Diagnostics::new()).unwrap();
+        generate_parser_for_grammar_with_opts(
+            &input_grammar.name,
+            syntax_grammar,
+            lexical_grammar,
+            inlines,
+            simple_aliases,
+            &GenerateOptions {
+                next_abi: true,
+                ..Default::default()
+            },
+            cache,
+            &mut Diagnostics::new(),
+        )
+        .unwrap()
+        .c_code
+    }
+}
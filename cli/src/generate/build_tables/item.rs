@@ -21,6 +21,7 @@ lazy_static! {
             associativity: None,
             alias: None,
             field_name: None,
+            inner_field_names: Vec::new(),
         }],
     };
 }
@@ -273,6 +274,7 @@ impl<'a> Hash for ParseItem<'a> {
         for step in &self.production.steps[0..self.step_index as usize] {
             step.alias.hash(hasher);
             step.field_name.hash(hasher);
+            step.inner_field_names.hash(hasher);
         }
         for step in &self.production.steps[self.step_index as usize..] {
             step.hash(hasher);
@@ -302,6 +304,9 @@ impl<'a> PartialEq for ParseItem<'a> {
                 if step.field_name != other.production.steps[i].field_name {
                     return false;
                 }
+                if step.inner_field_names != other.production.steps[i].inner_field_names {
+                    return false;
+                }
             } else if *step != other.production.steps[i] {
                 return false;
             }
@@ -339,6 +344,10 @@ impl<'a> Ord for ParseItem<'a> {
                             .then_with(|| {
                                 step.field_name.cmp(&other.production.steps[i].field_name)
                             })
+                            .then_with(|| {
+                                step.inner_field_names
+                                    .cmp(&other.production.steps[i].inner_field_names)
+                            })
                     } else {
                         step.cmp(&other.production.steps[i])
                     };
@@ -69,6 +69,7 @@ impl<'a> Minimizer<'a> {
                         } => {
                             if !self.simple_aliases.contains_key(&symbol)
                                 && !aliased_symbols.contains(&symbol)
+                                && !self.syntax_grammar.supertype_symbols.contains(&symbol)
                                 && self.syntax_grammar.variables[symbol.index].kind
                                     != VariableType::Named
                                 && (unit_reduction_symbol.is_none()
@@ -1,5 +1,6 @@
 pub(crate) mod build_lex_table;
 pub(crate) mod build_parse_table;
+pub(crate) mod cache;
 mod coincident_tokens;
 mod item;
 mod item_set_builder;
@@ -7,18 +8,26 @@ mod minimize_parse_table;
 mod token_conflicts;
 
 use self::build_lex_table::build_lex_table;
-use self::build_parse_table::{build_parse_table, ParseStateInfo};
+use self::build_parse_table::{build_parse_table, ParseStateInfo, RuntimeConflict};
+use self::cache::LexTableCache;
 use self::coincident_tokens::CoincidentTokenIndex;
 use self::minimize_parse_table::minimize_parse_table;
 use self::token_conflicts::TokenConflictMap;
-use crate::error::Result;
-use crate::generate::grammars::{InlinedProductionMap, LexicalGrammar, SyntaxGrammar};
+use crate::error::{Error, Result};
+use crate::generate::diagnostics::Diagnostics;
+use crate::generate::grammars::{
+    InlinedProductionMap, LexicalGrammar, Production, SyntaxGrammar, VariableType,
+};
 use crate::generate::nfa::{CharacterSet, NfaCursor};
 use crate::generate::node_types::VariableInfo;
 use crate::generate::rules::{AliasMap, Symbol, SymbolType, TokenSet};
 use crate::generate::tables::{LexTable, ParseAction, ParseTable, ParseTableEntry};
+use crate::generate::GenerateOptions;
 use log::info;
+use std::cmp;
 use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+use std::time::Instant;
 
 pub(crate) fn build_tables(
     syntax_grammar: &SyntaxGrammar,
@@ -26,19 +35,63 @@ pub(crate) fn build_tables(
     simple_aliases: &AliasMap,
     variable_info: &Vec<VariableInfo>,
     inlines: &InlinedProductionMap,
-    report_symbol_name: Option<&str>,
+    options: &GenerateOptions,
+    cache: Option<&LexTableCache>,
+    diagnostics: &mut Diagnostics,
 ) -> Result<(ParseTable, LexTable, LexTable, Option<Symbol>)> {
-    let (mut parse_table, following_tokens, parse_state_info) =
-        build_parse_table(syntax_grammar, lexical_grammar, inlines, variable_info)?;
+    let progress = options.progress;
+    if let Some(progress) = progress {
+        progress.begin("parse-table", None);
+    }
+    let (mut parse_table, following_tokens, parse_state_info, runtime_conflicts) =
+        build_parse_table(
+            syntax_grammar,
+            lexical_grammar,
+            inlines,
+            variable_info,
+            progress,
+        )?;
+    if let Some(progress) = progress {
+        progress.finish(parse_table.states.len());
+        progress.begin("lex-table", None);
+    }
+    for warning in check_dynamic_precedences(
+        syntax_grammar,
+        lexical_grammar,
+        &runtime_conflicts,
+        options.report_dynamic_precedence,
+    ) {
+        diagnostics.warn("unresolvable-dynamic-precedence", warning);
+    }
+    let keywords_cache = cache.map(|cache| {
+        let key = cache.keywords_key(
+            syntax_grammar,
+            lexical_grammar,
+            &parse_table,
+            &following_tokens,
+        );
+        (cache, key)
+    });
     let token_conflict_map = TokenConflictMap::new(lexical_grammar, following_tokens);
     let coincident_token_index = CoincidentTokenIndex::new(&parse_table, lexical_grammar);
-    let keywords = identify_keywords(
-        lexical_grammar,
-        &parse_table,
-        syntax_grammar.word_token,
-        &token_conflict_map,
-        &coincident_token_index,
-    );
+    let keywords = match keywords_cache.and_then(|(cache, key)| cache.load_keywords(key)) {
+        Some(keywords) => keywords,
+        None => {
+            let start_time = Instant::now();
+            let keywords = identify_keywords(
+                lexical_grammar,
+                &parse_table,
+                syntax_grammar.word_token,
+                &syntax_grammar.excluded_keywords,
+                &token_conflict_map,
+                &coincident_token_index,
+            );
+            if let Some((cache, key)) = keywords_cache {
+                cache.store_keywords(key, &keywords, start_time.elapsed());
+            }
+            keywords
+        }
+    };
     populate_error_state(
         &mut parse_table,
         syntax_grammar,
@@ -56,24 +109,73 @@ pub(crate) fn build_tables(
         &token_conflict_map,
         &keywords,
     );
-    let (main_lex_table, keyword_lex_table) = build_lex_table(
-        &mut parse_table,
-        syntax_grammar,
-        lexical_grammar,
-        &keywords,
-        &coincident_token_index,
-        &token_conflict_map,
-    );
+    let lex_tables_cache = keywords_cache.map(|(cache, key)| {
+        let key = cache.lex_tables_key(key, &keywords, &parse_table);
+        (cache, key)
+    });
+    let (main_lex_table, keyword_lex_table) = match lex_tables_cache
+        .and_then(|(cache, key)| cache.load_lex_tables(key, &mut parse_table))
+    {
+        Some(lex_tables) => lex_tables,
+        None => {
+            let start_time = Instant::now();
+            let (main_lex_table, keyword_lex_table) = build_lex_table(
+                &mut parse_table,
+                syntax_grammar,
+                lexical_grammar,
+                &keywords,
+                &coincident_token_index,
+                &token_conflict_map,
+            );
+            if let Some((cache, key)) = lex_tables_cache {
+                cache.store_lex_tables(
+                    key,
+                    &main_lex_table,
+                    &keyword_lex_table,
+                    &parse_table,
+                    start_time.elapsed(),
+                );
+            }
+            (main_lex_table, keyword_lex_table)
+        }
+    };
     populate_external_lex_states(&mut parse_table, syntax_grammar);
+    if let Some(progress) = progress {
+        progress.finish(main_lex_table.states.len() + keyword_lex_table.states.len());
+    }
     mark_fragile_tokens(&mut parse_table, lexical_grammar, &token_conflict_map);
 
-    if let Some(report_symbol_name) = report_symbol_name {
+    if let Some(report_symbol_name) = options.report_symbol_name {
         report_state_info(
             &syntax_grammar,
             &lexical_grammar,
             &parse_table,
             &parse_state_info,
             report_symbol_name,
+            options.report_auxiliary,
+        );
+    }
+    if options.report_dynamic_precedence {
+        report_dynamic_precedence_info(syntax_grammar, lexical_grammar, &runtime_conflicts);
+    }
+    if options.report_keywords {
+        eprintln!(
+            "{}",
+            report_keyword_info(syntax_grammar, lexical_grammar, &keywords)
+        );
+    }
+    for token_name in options.report_token_names {
+        eprintln!(
+            "{}",
+            report_token_info(
+                syntax_grammar,
+                lexical_grammar,
+                &parse_table,
+                &parse_state_info,
+                &keywords,
+                token_name,
+                options.report_auxiliary,
+            )?
         );
     }
     Ok((
@@ -171,17 +273,33 @@ fn populate_used_symbols(
     let mut non_terminal_usages = vec![false; syntax_grammar.variables.len()];
     let mut external_usages = vec![false; syntax_grammar.external_tokens.len()];
     for state in &parse_table.states {
-        for symbol in state.terminal_entries.keys() {
+        for (symbol, entry) in state.terminal_entries.iter() {
             match symbol.kind {
                 SymbolType::Terminal => terminal_usages[symbol.index] = true,
                 SymbolType::External => external_usages[symbol.index] = true,
                 _ => {}
             }
+
+            // A rule can be reduced without ever appearing in a goto entry,
+            // e.g. when an inlined start rule is reduced at the top level.
+            for action in &entry.actions {
+                if let ParseAction::Reduce { symbol, .. } = action {
+                    non_terminal_usages[symbol.index] = true;
+                }
+            }
         }
         for symbol in state.nonterminal_entries.keys() {
             non_terminal_usages[symbol.index] = true;
         }
     }
+
+    // External tokens that are also defined as internal tokens refer to those
+    // internal tokens in the external scanner's symbol map.
+    for external_token in &syntax_grammar.external_tokens {
+        if let Some(symbol) = external_token.corresponding_internal_token {
+            terminal_usages[symbol.index] = true;
+        }
+    }
     parse_table.symbols.push(Symbol::end());
     for (i, value) in terminal_usages.into_iter().enumerate() {
         if value {
@@ -251,6 +369,7 @@ fn identify_keywords(
     lexical_grammar: &LexicalGrammar,
     parse_table: &ParseTable,
     word_token: Option<Symbol>,
+    excluded_keywords: &Vec<Symbol>,
     token_conflict_map: &TokenConflictMap,
     coincident_token_index: &CoincidentTokenIndex,
 ) -> TokenSet {
@@ -263,11 +382,19 @@ fn identify_keywords(
 
     // First find all of the candidate keyword tokens: tokens that start with
     // letters or underscore and can match the same string as a word token.
+    // Tokens that the grammar explicitly excludes are always lexed on their own.
     let keyword_candidates: TokenSet = lexical_grammar
         .variables
         .iter()
         .enumerate()
         .filter_map(|(i, variable)| {
+            if excluded_keywords.contains(&Symbol::terminal(i)) {
+                info!(
+                    "Keywords - exclude {} because the grammar excludes it",
+                    lexical_grammar.variables[i].name
+                );
+                return None;
+            }
             cursor.reset(vec![variable.start_state]);
             if all_chars_are_alphabetical(&cursor)
                 && token_conflict_map.does_match_same_string(i, word_token.index)
@@ -389,41 +516,19 @@ fn report_state_info<'a>(
     parse_table: &ParseTable,
     parse_state_info: &Vec<ParseStateInfo<'a>>,
     report_symbol_name: &'a str,
+    report_auxiliary: bool,
 ) {
-    let mut all_state_indices = BTreeSet::new();
-    let mut symbols_with_state_indices = (0..syntax_grammar.variables.len())
-        .map(|i| (Symbol::non_terminal(i), BTreeSet::new()))
-        .collect::<Vec<_>>();
-
-    for (i, state) in parse_table.states.iter().enumerate() {
-        all_state_indices.insert(i);
-        let item_set = &parse_state_info[state.id];
-        for (item, _) in item_set.1.entries.iter() {
-            if !item.is_augmented() {
-                symbols_with_state_indices[item.variable_index as usize]
-                    .1
-                    .insert(i);
-            }
-        }
-    }
-
-    symbols_with_state_indices.sort_unstable_by_key(|(_, states)| -(states.len() as i32));
-
-    let max_symbol_name_length = syntax_grammar
-        .variables
-        .iter()
-        .map(|v| v.name.len())
-        .max()
-        .unwrap();
-    for (symbol, states) in &symbols_with_state_indices {
-        eprintln!(
-            "{:width$}\t{}",
-            syntax_grammar.variables[symbol.index].name,
-            states.len(),
-            width = max_symbol_name_length
-        );
-    }
-    eprintln!("");
+    let all_state_indices = (0..parse_table.states.len()).collect::<BTreeSet<_>>();
+    let symbols_with_state_indices =
+        symbols_with_state_indices(syntax_grammar, parse_table, parse_state_info);
+    eprintln!(
+        "{}",
+        report_state_counts(
+            syntax_grammar,
+            &symbols_with_state_indices,
+            report_auxiliary
+        )
+    );
 
     let state_indices = if report_symbol_name == "*" {
         Some(&all_state_indices)
@@ -467,6 +572,422 @@ fn report_state_info<'a>(
     }
 }
 
+// Find the parse states in which each rule appears, sorted by the number of states
+// in descending order.
+fn symbols_with_state_indices<'a>(
+    syntax_grammar: &SyntaxGrammar,
+    parse_table: &ParseTable,
+    parse_state_info: &Vec<ParseStateInfo<'a>>,
+) -> Vec<(Symbol, BTreeSet<usize>)> {
+    let mut result = (0..syntax_grammar.variables.len())
+        .map(|i| (Symbol::non_terminal(i), BTreeSet::new()))
+        .collect::<Vec<_>>();
+
+    for (i, state) in parse_table.states.iter().enumerate() {
+        let item_set = &parse_state_info[state.id];
+        for (item, _) in item_set.1.entries.iter() {
+            if !item.is_augmented() {
+                result[item.variable_index as usize].1.insert(i);
+            }
+        }
+    }
+
+    result.sort_unstable_by_key(|(_, states)| -(states.len() as i32));
+    result
+}
+
+// Describe the number of parse states in which each rule appears. The auxiliary
+// rules that were synthesized while preparing the grammar are omitted unless
+// `include_auxiliary` is set.
+fn report_state_counts(
+    syntax_grammar: &SyntaxGrammar,
+    symbols_with_state_indices: &[(Symbol, BTreeSet<usize>)],
+    include_auxiliary: bool,
+) -> String {
+    let rows = symbols_with_state_indices
+        .iter()
+        .map(|(symbol, states)| (&syntax_grammar.variables[symbol.index], states))
+        .filter(|(variable, _)| include_auxiliary || !variable.is_auxiliary())
+        .map(|(variable, states)| (variable.name.as_str(), states))
+        .collect::<Vec<_>>();
+    let max_symbol_name_length = rows.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+
+    let mut result = String::new();
+    for (name, states) in rows {
+        writeln!(
+            &mut result,
+            "{:width$}\t{}",
+            name,
+            states.len(),
+            width = max_symbol_name_length
+        )
+        .unwrap();
+    }
+    result
+}
+
+// For each conflict that will be resolved at runtime, check that the competing
+// productions' dynamic precedences can actually break the tie. If they are all
+// equal, then the parser will choose between the interpretations arbitrarily.
+// Conflicts whose productions all have zero dynamic precedence are common in
+// grammars that rely on other ways of choosing between interpretations, so unless
+// `report_all_zero` is set, they're only reported if their rules use dynamic
+// precedence elsewhere.
+fn check_dynamic_precedences(
+    syntax_grammar: &SyntaxGrammar,
+    lexical_grammar: &LexicalGrammar,
+    runtime_conflicts: &[RuntimeConflict],
+    report_all_zero: bool,
+) -> Vec<String> {
+    let mut result = Vec::new();
+    for conflict in runtime_conflicts {
+        let first_precedence = conflict.productions[0].1.dynamic_precedence;
+        if conflict
+            .productions
+            .iter()
+            .any(|(_, p)| p.dynamic_precedence != first_precedence)
+        {
+            continue;
+        }
+        if first_precedence == 0
+            && !report_all_zero
+            && !uses_dynamic_precedence(syntax_grammar, &conflict.symbols)
+        {
+            continue;
+        }
+
+        let mut message = format!(
+            "The conflict between {} cannot be resolved by the dynamic precedence of its productions, which all have dynamic precedence {}:",
+            conflict_name(syntax_grammar, &conflict.symbols),
+            first_precedence,
+        );
+        for (variable_index, production) in &conflict.productions {
+            message += "\n  ";
+            message +=
+                &production_name(syntax_grammar, lexical_grammar, *variable_index, production);
+        }
+        if !result.contains(&message) {
+            result.push(message);
+        }
+    }
+    result
+}
+
+fn uses_dynamic_precedence(syntax_grammar: &SyntaxGrammar, symbols: &[Symbol]) -> bool {
+    symbols
+        .iter()
+        .filter(|symbol| symbol.is_non_terminal())
+        .flat_map(|symbol| &syntax_grammar.variables[symbol.index].productions)
+        .any(|production| production.dynamic_precedence != 0)
+}
+
+fn report_dynamic_precedence_info(
+    syntax_grammar: &SyntaxGrammar,
+    lexical_grammar: &LexicalGrammar,
+    runtime_conflicts: &[RuntimeConflict],
+) {
+    eprintln!("Productions with dynamic precedence:");
+    for (variable_index, variable) in syntax_grammar.variables.iter().enumerate() {
+        for production in &variable.productions {
+            if production.dynamic_precedence == 0 {
+                continue;
+            }
+
+            eprintln!(
+                "\n  {}\t{}",
+                production_name(syntax_grammar, lexical_grammar, variable_index, production),
+                production.dynamic_precedence
+            );
+            let mut conflicts = runtime_conflicts
+                .iter()
+                .filter(|conflict| {
+                    conflict
+                        .productions
+                        .iter()
+                        .any(|(i, p)| *i == variable_index && *p == production)
+                })
+                .map(|conflict| conflict_name(syntax_grammar, &conflict.symbols))
+                .collect::<Vec<_>>();
+            conflicts.dedup();
+            if conflicts.is_empty() {
+                eprintln!("    conflicts: none");
+            }
+            for conflict in conflicts {
+                eprintln!("    conflict: {}", conflict);
+            }
+        }
+    }
+    eprintln!();
+}
+
+// Describe which tokens are lexed using the keyword lex table, and which tokens
+// the grammar has excluded from keyword extraction.
+fn report_keyword_info(
+    syntax_grammar: &SyntaxGrammar,
+    lexical_grammar: &LexicalGrammar,
+    keywords: &TokenSet,
+) -> String {
+    let word_token = match syntax_grammar.word_token {
+        Some(word_token) => word_token,
+        None => return "Keywords: none, because the grammar has no word token\n".to_string(),
+    };
+
+    let mut result = format!(
+        "Keywords, lexed using the word token `{}`:\n",
+        lexical_grammar.variables[word_token.index].name
+    );
+    for keyword in keywords.iter() {
+        writeln!(
+            &mut result,
+            "  {}",
+            lexical_grammar.variables[keyword.index].name
+        )
+        .unwrap();
+    }
+    if !syntax_grammar.excluded_keywords.is_empty() {
+        result += "Excluded from keyword extraction:\n";
+        for token in &syntax_grammar.excluded_keywords {
+            writeln!(
+                &mut result,
+                "  {}",
+                lexical_grammar.variables[token.index].name
+            )
+            .unwrap();
+        }
+    }
+    result
+}
+
+// Describe the parse states in which the given token has any actions, along with the
+// rules that are in progress in those states, and whether the token is lexed using the
+// keyword lex table. The token can be given by its name, or by the string that it
+// matches, which can be quoted.
+fn report_token_info<'a>(
+    syntax_grammar: &SyntaxGrammar,
+    lexical_grammar: &LexicalGrammar,
+    parse_table: &ParseTable,
+    parse_state_info: &Vec<ParseStateInfo<'a>>,
+    keywords: &TokenSet,
+    token_name: &str,
+    report_auxiliary: bool,
+) -> Result<String> {
+    let literal = token_name
+        .strip_prefix('\'')
+        .and_then(|s| s.strip_suffix('\''))
+        .or_else(|| {
+            token_name
+                .strip_prefix('"')
+                .and_then(|s| s.strip_suffix('"'))
+        });
+    let mut tokens = Vec::new();
+    if literal.is_none() {
+        for (i, variable) in lexical_grammar.variables.iter().enumerate() {
+            if variable.name == token_name {
+                tokens.push(Symbol::terminal(i));
+            }
+        }
+        for (i, external_token) in syntax_grammar.external_tokens.iter().enumerate() {
+            if external_token.name == token_name
+                && external_token.corresponding_internal_token.is_none()
+            {
+                tokens.push(Symbol::external(i));
+            }
+        }
+    }
+    if tokens.is_empty() {
+        let literal = literal.unwrap_or(token_name);
+        for i in 0..lexical_grammar.variables.len() {
+            if token_matches_only_string(lexical_grammar, i, literal) {
+                tokens.push(Symbol::terminal(i));
+            }
+        }
+    }
+    if tokens.is_empty() {
+        let names = lexical_grammar
+            .variables
+            .iter()
+            .map(|variable| variable.name.as_str())
+            .chain(
+                syntax_grammar
+                    .external_tokens
+                    .iter()
+                    .map(|t| t.name.as_str()),
+            );
+        let mut message = format!("Unknown token `{}`", token_name);
+        if let Some(suggestion) = closest_name(token_name, names) {
+            message += &format!(". Did you mean `{}`?", suggestion);
+        }
+        return Error::err(message);
+    }
+
+    let mut result = String::new();
+    for token in tokens {
+        let (name, lex_table) = if token.is_external() {
+            (
+                &syntax_grammar.external_tokens[token.index].name,
+                "the external scanner",
+            )
+        } else if keywords.contains(&token) {
+            (
+                &lexical_grammar.variables[token.index].name,
+                "the keyword lex table",
+            )
+        } else {
+            (
+                &lexical_grammar.variables[token.index].name,
+                "the main lex table",
+            )
+        };
+        let mut rows = Vec::new();
+        for (state_index, state) in parse_table.states.iter().enumerate() {
+            let actions = match state.terminal_entries.get(&token) {
+                Some(entry) => entry
+                    .actions
+                    .iter()
+                    .filter_map(|action| match action {
+                        ParseAction::Shift { .. } => Some("shift".to_string()),
+                        ParseAction::ShiftExtra => Some("shift extra".to_string()),
+                        ParseAction::Accept => Some("accept".to_string()),
+                        ParseAction::Reduce { symbol, .. } => Some(format!(
+                            "reduce {}",
+                            syntax_grammar.variables[symbol.index].name
+                        )),
+                        ParseAction::Recover => None,
+                    })
+                    .collect::<Vec<_>>(),
+                None => continue,
+            };
+            if actions.is_empty() {
+                continue;
+            }
+            let mut variable_indices = parse_state_info[state.id]
+                .1
+                .entries
+                .iter()
+                .filter(|(item, _)| !item.is_augmented())
+                .map(|(item, _)| item.variable_index as usize)
+                .filter(|i| report_auxiliary || !syntax_grammar.variables[*i].is_auxiliary())
+                .collect::<Vec<_>>();
+            variable_indices.sort_unstable();
+            variable_indices.dedup();
+            let rules = variable_indices
+                .iter()
+                .map(|i| syntax_grammar.variables[*i].name.as_str())
+                .collect::<Vec<_>>();
+            rows.push((state_index, rules.join(", "), actions.join(", ")));
+        }
+
+        writeln!(
+            &mut result,
+            "Token `{}` is lexed using {}, and has actions in {} parse states:",
+            name,
+            lex_table,
+            rows.len()
+        )
+        .unwrap();
+        for (state_index, rules, actions) in rows {
+            if rules.is_empty() {
+                writeln!(&mut result, "  state {}: {}", state_index, actions).unwrap();
+            } else {
+                writeln!(
+                    &mut result,
+                    "  state {} ({}): {}",
+                    state_index, rules, actions
+                )
+                .unwrap();
+            }
+        }
+    }
+    Ok(result)
+}
+
+// Determine whether the given token matches the given string and nothing else.
+fn token_matches_only_string(lexical_grammar: &LexicalGrammar, index: usize, string: &str) -> bool {
+    let mut cursor = NfaCursor::new(
+        &lexical_grammar.nfa,
+        vec![lexical_grammar.variables[index].start_state],
+    );
+    for c in string.chars() {
+        if cursor.completions().next().is_some() {
+            return false;
+        }
+        let transitions = cursor
+            .transitions()
+            .into_iter()
+            .filter(|transition| !transition.is_separator)
+            .collect::<Vec<_>>();
+        if transitions.len() != 1 || transitions[0].characters != CharacterSet::empty().add_char(c)
+        {
+            return false;
+        }
+        cursor.reset(transitions[0].states.clone());
+    }
+    cursor
+        .transitions()
+        .iter()
+        .all(|transition| transition.is_separator)
+        && cursor.completions().any(|(i, _)| i == index)
+}
+
+// Find the name that is most similar to the given name, if any name is similar enough
+// to be a likely typo.
+fn closest_name<'a>(name: &str, names: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let max_distance = cmp::max(1, name.chars().count() / 3);
+    names
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = diagonal + if a_char == *b_char { 0 } else { 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = cmp::min(substitution, cmp::min(row[j], row[j + 1]) + 1);
+        }
+    }
+    row[b.len()]
+}
+
+fn conflict_name(syntax_grammar: &SyntaxGrammar, symbols: &[Symbol]) -> String {
+    symbols
+        .iter()
+        .map(|symbol| format!("`{}`", syntax_grammar.variables[symbol.index].name))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn production_name(
+    syntax_grammar: &SyntaxGrammar,
+    lexical_grammar: &LexicalGrammar,
+    variable_index: usize,
+    production: &Production,
+) -> String {
+    let mut result = format!("{} ->", syntax_grammar.variables[variable_index].name);
+    for step in &production.steps {
+        result.push(' ');
+        if step.symbol.is_terminal() {
+            let variable = &lexical_grammar.variables[step.symbol.index];
+            if variable.kind == VariableType::Named {
+                result += &variable.name;
+            } else {
+                result += &format!("'{}'", variable.name);
+            }
+        } else if step.symbol.is_external() {
+            result += &syntax_grammar.external_tokens[step.symbol.index].name;
+        } else {
+            result += &syntax_grammar.variables[step.symbol.index].name;
+        }
+    }
+    result
+}
+
 fn all_chars_are_alphabetical(cursor: &NfaCursor) -> bool {
     cursor.transition_chars().all(|(chars, is_sep)| {
         if is_sep {
@@ -478,3 +999,333 @@ fn all_chars_are_alphabetical(cursor: &NfaCursor) -> bool {
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::node_types::get_variable_info;
+    use crate::generate::parse_grammar::parse_grammar;
+    use crate::generate::prepare_grammar::prepare_grammar;
+
+    #[test]
+    fn test_dynamic_precedence_resolving_declared_conflict() {
+        let warnings = get_dynamic_precedence_warnings(&dangling_else_grammar(0, 1), true);
+        assert!(warnings.is_empty(), "unexpected warnings: {:?}", warnings);
+    }
+
+    #[test]
+    fn test_dynamic_precedence_not_resolving_declared_conflict() {
+        let warnings = get_dynamic_precedence_warnings(&dangling_else_grammar(1, 1), false);
+        assert_eq!(
+            warnings,
+            vec![concat!(
+                "The conflict between `if_statement` cannot be resolved by the dynamic ",
+                "precedence of its productions, which all have dynamic precedence 1:\n",
+                "  if_statement -> 'if' expression statement\n",
+                "  if_statement -> 'if' expression statement 'else' statement",
+            )
+            .to_string()]
+        );
+    }
+
+    #[test]
+    fn test_declared_conflict_without_dynamic_precedence() {
+        let grammar_json = dangling_else_grammar(0, 0);
+        let warnings = get_dynamic_precedence_warnings(&grammar_json, false);
+        assert!(warnings.is_empty(), "unexpected warnings: {:?}", warnings);
+
+        // When dynamic precedence is being reported, conflicts whose productions all
+        // have zero dynamic precedence are reported too.
+        let warnings = get_dynamic_precedence_warnings(&grammar_json, true);
+        assert_eq!(
+            warnings,
+            vec![concat!(
+                "The conflict between `if_statement` cannot be resolved by the dynamic ",
+                "precedence of its productions, which all have dynamic precedence 0:\n",
+                "  if_statement -> 'if' expression statement\n",
+                "  if_statement -> 'if' expression statement 'else' statement",
+            )
+            .to_string()]
+        );
+    }
+
+    #[test]
+    fn test_keyword_extraction_exclusions() {
+        let input_grammar = parse_grammar(
+            r#"{
+                "name": "contextual_keywords",
+                "word": "identifier",
+                "keywordExtractionExclude": [{"type": "STRING", "value": "await"}],
+                "rules": {
+                    "program": {
+                        "type": "REPEAT",
+                        "content": {
+                            "type": "CHOICE",
+                            "members": [
+                                {
+                                    "type": "SEQ",
+                                    "members": [
+                                        {"type": "STRING", "value": "if"},
+                                        {"type": "SYMBOL", "name": "identifier"}
+                                    ]
+                                },
+                                {
+                                    "type": "SEQ",
+                                    "members": [
+                                        {"type": "STRING", "value": "await"},
+                                        {"type": "SYMBOL", "name": "identifier"}
+                                    ]
+                                },
+                                {"type": "SYMBOL", "name": "identifier"}
+                            ]
+                        }
+                    },
+                    "identifier": {"type": "PATTERN", "value": "[a-z]+"}
+                }
+            }"#,
+            &mut Diagnostics::new(),
+        )
+        .unwrap();
+        let (syntax_grammar, lexical_grammar, inlines, _) =
+            prepare_grammar(&input_grammar, &mut Diagnostics::new()).unwrap();
+        let variable_info = get_variable_info(&syntax_grammar, &lexical_grammar).unwrap();
+        let (parse_table, following_tokens, _, _) = build_parse_table(
+            &syntax_grammar,
+            &lexical_grammar,
+            &inlines,
+            &variable_info,
+            None,
+        )
+        .unwrap();
+        let token_conflict_map = TokenConflictMap::new(&lexical_grammar, following_tokens);
+        let coincident_token_index = CoincidentTokenIndex::new(&parse_table, &lexical_grammar);
+        let keywords = identify_keywords(
+            &lexical_grammar,
+            &parse_table,
+            syntax_grammar.word_token,
+            &syntax_grammar.excluded_keywords,
+            &token_conflict_map,
+            &coincident_token_index,
+        );
+
+        assert_eq!(
+            report_keyword_info(&syntax_grammar, &lexical_grammar, &keywords),
+            concat!(
+                "Keywords, lexed using the word token `identifier`:\n",
+                "  if\n",
+                "Excluded from keyword extraction:\n",
+                "  await\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_state_count_report_hides_auxiliary_rules() {
+        let input_grammar = parse_grammar(
+            r#"{
+                "name": "lists",
+                "rules": {
+                    "program": {
+                        "type": "REPEAT",
+                        "content": {"type": "SYMBOL", "name": "list"}
+                    },
+                    "list": {
+                        "type": "SEQ",
+                        "members": [
+                            {"type": "STRING", "value": "["},
+                            {"type": "REPEAT", "content": {"type": "SYMBOL", "name": "identifier"}},
+                            {"type": "STRING", "value": "]"}
+                        ]
+                    },
+                    "identifier": {"type": "PATTERN", "value": "[a-z]+"}
+                }
+            }"#,
+            &mut Diagnostics::new(),
+        )
+        .unwrap();
+        let (syntax_grammar, lexical_grammar, inlines, _) =
+            prepare_grammar(&input_grammar, &mut Diagnostics::new()).unwrap();
+        let variable_info = get_variable_info(&syntax_grammar, &lexical_grammar).unwrap();
+        let (parse_table, _, parse_state_info, _) = build_parse_table(
+            &syntax_grammar,
+            &lexical_grammar,
+            &inlines,
+            &variable_info,
+            None,
+        )
+        .unwrap();
+        let symbols_with_state_indices =
+            symbols_with_state_indices(&syntax_grammar, &parse_table, &parse_state_info);
+
+        let rule_names = |report: String| {
+            let mut names = report
+                .lines()
+                .map(|line| line.split('\t').next().unwrap().trim().to_string())
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+        assert_eq!(
+            rule_names(report_state_counts(
+                &syntax_grammar,
+                &symbols_with_state_indices,
+                false
+            )),
+            vec!["list", "program"]
+        );
+        assert_eq!(
+            rule_names(report_state_counts(
+                &syntax_grammar,
+                &symbols_with_state_indices,
+                true
+            )),
+            vec!["__list_repeat1", "__program_repeat1", "list", "program"]
+        );
+    }
+
+    #[test]
+    fn test_token_report() {
+        let grammar = r#"{
+            "name": "lists",
+            "rules": {
+                "program": {
+                    "type": "REPEAT",
+                    "content": {"type": "SYMBOL", "name": "list"}
+                },
+                "list": {
+                    "type": "SEQ",
+                    "members": [
+                        {"type": "STRING", "value": "["},
+                        {
+                            "type": "CHOICE",
+                            "members": [
+                                {"type": "SYMBOL", "name": "identifier"},
+                                {"type": "BLANK"}
+                            ]
+                        },
+                        {"type": "STRING", "value": "]"}
+                    ]
+                },
+                "identifier": {"type": "PATTERN", "value": "[a-z]+"}
+            }
+        }"#;
+
+        let report = get_token_report(grammar, "']'").unwrap();
+        assert_eq!(report, get_token_report(grammar, "]").unwrap());
+        assert_eq!(
+            report,
+            concat!(
+                "Token `]` is lexed using the main lex table, and has actions in 2 parse states:\n",
+                "  state 2 (list): shift\n",
+                "  state 7 (list): shift\n",
+            )
+        );
+        assert_eq!(
+            get_token_report(grammar, "identfier")
+                .unwrap_err()
+                .message(),
+            "Unknown token `identfier`. Did you mean `identifier`?"
+        );
+    }
+
+    fn get_token_report(grammar_json: &str, token_name: &str) -> Result<String> {
+        let input_grammar = parse_grammar(grammar_json, &mut Diagnostics::new()).unwrap();
+        let (syntax_grammar, lexical_grammar, inlines, _) =
+            prepare_grammar(&input_grammar, &mut Diagnostics::new()).unwrap();
+        let variable_info = get_variable_info(&syntax_grammar, &lexical_grammar).unwrap();
+        let (parse_table, _, parse_state_info, _) = build_parse_table(
+            &syntax_grammar,
+            &lexical_grammar,
+            &inlines,
+            &variable_info,
+            None,
+        )
+        .unwrap();
+        report_token_info(
+            &syntax_grammar,
+            &lexical_grammar,
+            &parse_table,
+            &parse_state_info,
+            &TokenSet::new(),
+            token_name,
+            false,
+        )
+    }
+
+    fn get_dynamic_precedence_warnings(grammar_json: &str, report_all_zero: bool) -> Vec<String> {
+        let input_grammar = parse_grammar(grammar_json, &mut Diagnostics::new()).unwrap();
+        let (syntax_grammar, lexical_grammar, inlines, _) =
+            prepare_grammar(&input_grammar, &mut Diagnostics::new()).unwrap();
+        let variable_info = get_variable_info(&syntax_grammar, &lexical_grammar).unwrap();
+        let (_, _, _, runtime_conflicts) = build_parse_table(
+            &syntax_grammar,
+            &lexical_grammar,
+            &inlines,
+            &variable_info,
+            None,
+        )
+        .unwrap();
+        check_dynamic_precedences(
+            &syntax_grammar,
+            &lexical_grammar,
+            &runtime_conflicts,
+            report_all_zero,
+        )
+    }
+
+    fn dangling_else_grammar(if_dynamic_precedence: i32, else_dynamic_precedence: i32) -> String {
+        format!(
+            r#"{{
+                "name": "dangling_else",
+                "conflicts": [["if_statement"]],
+                "extras": [{{"type": "PATTERN", "value": "\\s"}}],
+                "rules": {{
+                    "program": {{
+                        "type": "REPEAT",
+                        "content": {{"type": "SYMBOL", "name": "statement"}}
+                    }},
+                    "statement": {{
+                        "type": "CHOICE",
+                        "members": [
+                            {{"type": "SYMBOL", "name": "if_statement"}},
+                            {{"type": "SYMBOL", "name": "expression"}}
+                        ]
+                    }},
+                    "if_statement": {{
+                        "type": "CHOICE",
+                        "members": [
+                            {{
+                                "type": "PREC_DYNAMIC",
+                                "value": {},
+                                "content": {{
+                                    "type": "SEQ",
+                                    "members": [
+                                        {{"type": "STRING", "value": "if"}},
+                                        {{"type": "SYMBOL", "name": "expression"}},
+                                        {{"type": "SYMBOL", "name": "statement"}}
+                                    ]
+                                }}
+                            }},
+                            {{
+                                "type": "PREC_DYNAMIC",
+                                "value": {},
+                                "content": {{
+                                    "type": "SEQ",
+                                    "members": [
+                                        {{"type": "STRING", "value": "if"}},
+                                        {{"type": "SYMBOL", "name": "expression"}},
+                                        {{"type": "SYMBOL", "name": "statement"}},
+                                        {{"type": "STRING", "value": "else"}},
+                                        {{"type": "SYMBOL", "name": "statement"}}
+                                    ]
+                                }}
+                            }}
+                        ]
+                    }},
+                    "expression": {{"type": "PATTERN", "value": "[a-z]+"}}
+                }}
+            }}"#,
+            if_dynamic_precedence, else_dynamic_precedence
+        )
+    }
+}
@@ -0,0 +1,75 @@
+use crate::error::{Error, Result};
+use serde_json::Value;
+use std::path::Path;
+
+// The flags with which the CLI's loader compiles parsers, so that editors check the
+// sources in the same way. The include path is relative to the repository, and
+// contains the generated `tree_sitter/parser.h`.
+const COMMON_ARGUMENTS: &[&str] = &["-fPIC", "-fno-exceptions", "-g", "-O2", "-I", "src"];
+const C_ARGUMENTS: &[&str] = &["-xc"];
+const C_SCANNER_ARGUMENTS: &[&str] = &["-xc", "-std=c99"];
+
+/// Updates a JSON compilation database, as read by clangd and other C tooling, with an
+/// entry for each of the given source files. The file names are relative to `repo_path`.
+///
+/// Entries for the files in `owned_files` are removed first, so that the entries for
+/// files that no longer exist, such as a scanner that was rewritten in C, are dropped.
+/// All other entries in the existing database are preserved.
+pub(crate) fn update_compile_commands(
+    existing_json: Option<&str>,
+    repo_path: &Path,
+    source_files: &[String],
+    owned_files: &[String],
+) -> Result<String> {
+    let mut entries = match existing_json {
+        Some(json) => match serde_json::from_str(json) {
+            Ok(Value::Array(entries)) => entries,
+            _ => return Error::err("Existing compile_commands.json is not a JSON array".into()),
+        },
+        None => Vec::new(),
+    };
+
+    entries.retain(|entry| {
+        !owned_files
+            .iter()
+            .any(|file| entry_refers_to(entry, &repo_path.join(file)))
+    });
+
+    let directory = repo_path.to_string_lossy();
+    for file in source_files {
+        let (compiler, language_arguments) = if file.ends_with(".c") {
+            let is_scanner = Path::new(file).file_stem() == Some("scanner".as_ref());
+            let arguments = if is_scanner {
+                C_SCANNER_ARGUMENTS
+            } else {
+                C_ARGUMENTS
+            };
+            ("cc", arguments)
+        } else {
+            ("c++", &[][..])
+        };
+        let mut arguments = vec![compiler, "-c"];
+        arguments.extend_from_slice(COMMON_ARGUMENTS);
+        arguments.extend_from_slice(language_arguments);
+        arguments.push(file);
+        entries.push(serde_json::json!({
+            "directory": directory,
+            "file": file,
+            "arguments": arguments,
+        }));
+    }
+
+    Ok(serde_json::to_string_pretty(&entries).unwrap() + "\n")
+}
+
+// Entries' file names may be absolute, or relative to their own directory.
+fn entry_refers_to(entry: &Value, path: &Path) -> bool {
+    let file = match entry.get("file").and_then(Value::as_str) {
+        Some(file) => Path::new(file),
+        None => return false,
+    };
+    match entry.get("directory").and_then(Value::as_str) {
+        Some(directory) => Path::new(directory).join(file) == path,
+        None => file == path,
+    }
+}
@@ -0,0 +1,281 @@
+use super::grammars::{LexicalGrammar, SyntaxGrammar, VariableType};
+use super::rules::TokenSet;
+use serde_derive::Serialize;
+use std::cmp::Reverse;
+use std::fmt::Write;
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct RuleComplexity {
+    pub name: String,
+    /// Whether the rule was synthesized while preparing the grammar, such as a rule that
+    /// implements a repetition.
+    #[serde(skip)]
+    pub auxiliary: bool,
+    pub production_count: usize,
+    pub first_token_count: usize,
+    pub estimated_item_count: usize,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct TokenComplexity {
+    pub name: String,
+    #[serde(skip)]
+    pub auxiliary: bool,
+    pub nfa_state_count: usize,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct GrammarComplexity {
+    pub rules: Vec<RuleComplexity>,
+    pub tokens: Vec<TokenComplexity>,
+}
+
+// Estimate how much each rule and token contributes to the cost of generating
+// a parser. Rules are ranked by the number of parse items that their productions
+// can give rise to, which is the number of productions times their average
+// length. Tokens are ranked by the number of NFA states that their own rules
+// expand to, not counting the states for the separators that can precede them.
+pub fn grammar_complexity(
+    syntax_grammar: &SyntaxGrammar,
+    lexical_grammar: &LexicalGrammar,
+) -> GrammarComplexity {
+    let first_sets = first_sets(syntax_grammar);
+
+    let mut rules = syntax_grammar
+        .variables
+        .iter()
+        .zip(first_sets.iter())
+        .map(|(variable, first_set)| RuleComplexity {
+            name: variable.name.clone(),
+            auxiliary: variable.kind == VariableType::Auxiliary,
+            production_count: variable.productions.len(),
+            first_token_count: first_set.iter().count(),
+            estimated_item_count: variable.productions.iter().map(|p| p.steps.len()).sum(),
+        })
+        .collect::<Vec<_>>();
+    rules.sort_by(|a, b| {
+        b.estimated_item_count
+            .cmp(&a.estimated_item_count)
+            .then(b.production_count.cmp(&a.production_count))
+    });
+
+    let mut tokens = lexical_grammar
+        .variables
+        .iter()
+        .enumerate()
+        .map(|(i, variable)| TokenComplexity {
+            name: variable.name.clone(),
+            auxiliary: variable.kind == VariableType::Auxiliary,
+            nfa_state_count: lexical_grammar.nfa.token_state_counts[i],
+        })
+        .collect::<Vec<_>>();
+    tokens.sort_by_key(|t| Reverse(t.nfa_state_count));
+
+    GrammarComplexity { rules, tokens }
+}
+
+impl GrammarComplexity {
+    pub fn truncate(&mut self, count: usize) {
+        self.rules.truncate(count);
+        self.tokens.truncate(count);
+    }
+
+    // Remove the rules and tokens that were synthesized while preparing the grammar,
+    // leaving only the ones that the grammar author wrote.
+    pub fn remove_auxiliary(&mut self) {
+        self.rules.retain(|rule| !rule.auxiliary);
+        self.tokens.retain(|token| !token.auxiliary);
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut result = String::new();
+        let name_width = self
+            .rules
+            .iter()
+            .map(|r| r.name.len())
+            .chain(self.tokens.iter().map(|t| t.name.len()))
+            .max()
+            .unwrap_or(0);
+
+        writeln!(
+            &mut result,
+            "{:width$}  productions  first tokens  est. items",
+            "rule",
+            width = name_width
+        )
+        .unwrap();
+        for rule in &self.rules {
+            writeln!(
+                &mut result,
+                "{:width$}  {:>11}  {:>12}  {:>10}",
+                rule.name,
+                rule.production_count,
+                rule.first_token_count,
+                rule.estimated_item_count,
+                width = name_width
+            )
+            .unwrap();
+        }
+
+        writeln!(
+            &mut result,
+            "\n{:width$}  nfa states",
+            "token",
+            width = name_width
+        )
+        .unwrap();
+        for token in &self.tokens {
+            writeln!(
+                &mut result,
+                "{:width$}  {:>10}",
+                token.name,
+                token.nfa_state_count,
+                width = name_width
+            )
+            .unwrap();
+        }
+        result
+    }
+}
+
+fn first_sets(syntax_grammar: &SyntaxGrammar) -> Vec<TokenSet> {
+    let variable_count = syntax_grammar.variables.len();
+    let mut first_sets = vec![TokenSet::new(); variable_count];
+    let mut nullable = vec![false; variable_count];
+
+    let mut done = false;
+    while !done {
+        done = true;
+        for (i, variable) in syntax_grammar.variables.iter().enumerate() {
+            for production in &variable.productions {
+                let mut production_is_nullable = true;
+                for step in &production.steps {
+                    let symbol = step.symbol;
+                    if symbol.is_non_terminal() {
+                        if symbol.index != i {
+                            let first_set = first_sets[symbol.index].clone();
+                            if first_sets[i].insert_all(&first_set) {
+                                done = false;
+                            }
+                        }
+                        if !nullable[symbol.index] {
+                            production_is_nullable = false;
+                            break;
+                        }
+                    } else {
+                        if !first_sets[i].contains(&symbol) {
+                            first_sets[i].insert(symbol);
+                            done = false;
+                        }
+                        production_is_nullable = false;
+                        break;
+                    }
+                }
+                if production_is_nullable && !nullable[i] {
+                    nullable[i] = true;
+                    done = false;
+                }
+            }
+        }
+    }
+
+    first_sets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::diagnostics::Diagnostics;
+    use crate::generate::parse_grammar::parse_grammar;
+    use crate::generate::prepare_grammar::prepare_grammar;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_grammar_complexity() {
+        let grammar_json = fs::read_to_string(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("..")
+                .join("test")
+                .join("fixtures")
+                .join("test_grammars")
+                .join("complexity_hotspot")
+                .join("grammar.json"),
+        )
+        .unwrap();
+        let input_grammar = parse_grammar(&grammar_json, &mut Diagnostics::new()).unwrap();
+        let (syntax_grammar, lexical_grammar, _, _) =
+            prepare_grammar(&input_grammar, &mut Diagnostics::new()).unwrap();
+        let mut complexity = grammar_complexity(&syntax_grammar, &lexical_grammar);
+
+        let declaration = &complexity.rules[0];
+        assert_eq!(declaration.name, "declaration");
+        assert_eq!(declaration.production_count, 64);
+        assert_eq!(declaration.first_token_count, 5);
+        assert!(declaration.estimated_item_count > 64 * 4);
+
+        let program = complexity
+            .rules
+            .iter()
+            .find(|r| r.name == "program")
+            .unwrap();
+        assert_eq!(program.first_token_count, 6);
+
+        assert_eq!(complexity.tokens[0].name, "identifier");
+        assert!(complexity.tokens[0].nfa_state_count > complexity.tokens[1].nfa_state_count);
+
+        assert!(complexity
+            .rules
+            .iter()
+            .any(|r| r.auxiliary && r.name.ends_with("_repeat1")));
+        complexity.remove_auxiliary();
+        assert!(complexity.rules.iter().all(|r| !r.auxiliary));
+        assert!(complexity.tokens.iter().all(|t| !t.auxiliary));
+
+        complexity.truncate(2);
+        assert_eq!(complexity.rules.len(), 2);
+        assert_eq!(complexity.tokens.len(), 2);
+        assert!(complexity.to_text().contains("declaration"));
+    }
+    #[test]
+    fn test_token_complexity_excludes_separators() {
+        let token_complexity = |extras: &str| {
+            let input_grammar = parse_grammar(
+                &format!(
+                    r#"{{
+                        "name": "separators",
+                        "extras": [{}],
+                        "rules": {{
+                            "program": {{
+                                "type": "SEQ",
+                                "members": [
+                                    {{"type": "STRING", "value": "let"}},
+                                    {{"type": "SYMBOL", "name": "identifier"}}
+                                ]
+                            }},
+                            "identifier": {{"type": "PATTERN", "value": "[a-z]+"}}
+                        }}
+                    }}"#,
+                    extras
+                ),
+                &mut Diagnostics::new(),
+            )
+            .unwrap();
+            let (syntax_grammar, lexical_grammar, _, _) =
+                prepare_grammar(&input_grammar, &mut Diagnostics::new()).unwrap();
+            grammar_complexity(&syntax_grammar, &lexical_grammar)
+                .tokens
+                .into_iter()
+                .map(|token| (token.name, token.nfa_state_count))
+                .collect::<Vec<_>>()
+        };
+
+        // The `let` token consists of an accepting state and one state per character.
+        let without_separators = token_complexity("");
+        assert!(without_separators.contains(&("let".to_string(), 4)));
+        assert_eq!(
+            token_complexity(r#"{"type": "PATTERN", "value": "\\s|//[^\\n]*"}"#),
+            without_separators
+        );
+    }
+}
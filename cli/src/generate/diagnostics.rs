@@ -0,0 +1,163 @@
+use serde_derive::Serialize;
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A problem that was found while generating a parser, which doesn't by itself prevent the
+/// parser from being generated.
+///
+/// Each diagnostic has a stable `code`, so that tools can recognize it, and so that specific
+/// kinds of warnings can be treated as errors.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+}
+
+/// Collects the diagnostics that are produced by each phase of parser generation, so that
+/// they can be reported together once generation is done.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Diagnostics {
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn warn(&mut self, code: &'static str, message: impl Into<String>) {
+        self.push(Severity::Warning, code, None, message.into());
+    }
+
+    pub fn warn_at(
+        &mut self,
+        code: &'static str,
+        location: impl Into<String>,
+        message: impl Into<String>,
+    ) {
+        self.push(
+            Severity::Warning,
+            code,
+            Some(location.into()),
+            message.into(),
+        );
+    }
+
+    pub fn error(&mut self, code: &'static str, message: impl Into<String>) {
+        self.push(Severity::Error, code, None, message.into());
+    }
+
+    fn push(
+        &mut self,
+        severity: Severity,
+        code: &'static str,
+        location: Option<String>,
+        message: String,
+    ) {
+        self.diagnostics.push(Diagnostic {
+            severity,
+            code,
+            message,
+            location,
+        });
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.diagnostics.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.diagnostics.is_empty()
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics
+            .iter()
+            .any(|diagnostic| diagnostic.severity == Severity::Error)
+    }
+
+    /// Turns warnings into errors. If `codes` is empty, every warning is promoted.
+    /// Otherwise, only the warnings with one of the given codes are promoted.
+    pub fn promote_warnings(&mut self, codes: &[impl AsRef<str>]) {
+        for diagnostic in &mut self.diagnostics {
+            if diagnostic.severity == Severity::Warning
+                && (codes.is_empty() || codes.iter().any(|code| code.as_ref() == diagnostic.code))
+            {
+                diagnostic.severity = Severity::Error;
+            }
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(&self.diagnostics).unwrap()
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}[{}]: ", self.severity, self.code)?;
+        if let Some(location) = &self.location {
+            write!(f, "{}: ", location)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for diagnostic in &self.diagnostics {
+            writeln!(f, "{}", diagnostic)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_promoting_warnings_by_code() {
+        let mut diagnostics = Diagnostics::new();
+        diagnostics.warn("unused-rule", "Rule `a` is never used");
+        diagnostics.warn_at(
+            "invalid-query",
+            "queries/highlights.scm:1:2",
+            "Invalid field",
+        );
+        assert!(!diagnostics.has_errors());
+
+        diagnostics.promote_warnings(&["invalid-query"]);
+        assert!(diagnostics.has_errors());
+        assert_eq!(
+            diagnostics.to_string(),
+            concat!(
+                "warning[unused-rule]: Rule `a` is never used\n",
+                "error[invalid-query]: queries/highlights.scm:1:2: Invalid field\n",
+            )
+        );
+
+        diagnostics.promote_warnings(&[] as &[&str]);
+        assert!(diagnostics
+            .iter()
+            .all(|diagnostic| diagnostic.severity == Severity::Error));
+    }
+}
@@ -225,7 +225,9 @@ function grammar(baseGrammar, options) {
       conflicts: [],
       externals: [],
       inline: [],
-      supertypes: []
+      supertypes: [],
+      keywordExtractionExclude: [],
+      recoveryTokens: []
     };
   }
 
@@ -245,6 +247,28 @@ function grammar(baseGrammar, options) {
     externals = externalRules.map(normalize);
   }
 
+  let externalScanner = baseGrammar.externalScanner;
+  if (options.externalScanner) {
+    if (typeof options.externalScanner !== "object") {
+      throw new Error("Grammar's 'externalScanner' property must be an object.");
+    }
+
+    externalScanner = Object.assign({}, externalScanner);
+    for (const key in options.externalScanner) {
+      const value = options.externalScanner[key];
+      switch (key) {
+        case 'maxSerializedSize':
+          if (!Number.isInteger(value) || value <= 0) {
+            throw new Error("Grammar's 'externalScanner.maxSerializedSize' property must be a positive integer.");
+          }
+          externalScanner.maxSerializedSize = value;
+          break;
+        default:
+          throw new Error(`Unknown grammar externalScanner property '${key}'.`);
+      }
+    }
+  }
+
   const ruleMap = {};
   for (const key in options.rules) {
     ruleMap[key] = true;
@@ -357,11 +381,99 @@ function grammar(baseGrammar, options) {
     supertypes = supertypeRules.map(symbol => symbol.name);
   }
 
+  let keywordExtractionExclude = baseGrammar.keywordExtractionExclude || [];
+  if (options.keywordExtractionExclude) {
+    if (typeof options.keywordExtractionExclude !== "function") {
+      throw new Error("Grammar's 'keywordExtractionExclude' property must be a function.");
+    }
+
+    const excludedTokens = options.keywordExtractionExclude.call(
+      ruleBuilder,
+      ruleBuilder,
+      keywordExtractionExclude
+    );
+
+    if (!Array.isArray(excludedTokens)) {
+      throw new Error("Grammar's keywordExtractionExclude must be an array of tokens.");
+    }
+
+    keywordExtractionExclude = excludedTokens.map(normalize);
+  }
+
+  let recoveryTokens = baseGrammar.recoveryTokens || [];
+  if (options.recoveryTokens) {
+    if (typeof options.recoveryTokens !== "function") {
+      throw new Error("Grammar's 'recoveryTokens' property must be a function.");
+    }
+
+    const recoveryTokenRules = options.recoveryTokens.call(
+      ruleBuilder,
+      ruleBuilder,
+      recoveryTokens
+    );
+
+    if (!Array.isArray(recoveryTokenRules)) {
+      throw new Error("Grammar's recoveryTokens must be an array of tokens.");
+    }
+
+    recoveryTokens = recoveryTokenRules.map(normalize);
+  }
+
+  let metadata = baseGrammar.metadata;
+  if (options.metadata) {
+    if (typeof options.metadata !== "object") {
+      throw new Error("Grammar's 'metadata' property must be an object.");
+    }
+
+    metadata = Object.assign({}, metadata);
+    for (const key in options.metadata) {
+      const value = options.metadata[key];
+      switch (key) {
+        case 'scope':
+          if (typeof value !== 'string') {
+            throw new Error("Grammar's 'metadata.scope' property must be a string.");
+          }
+          metadata.scope = value;
+          break;
+        case 'fileTypes':
+          if (!Array.isArray(value) || value.some(v => typeof v !== 'string')) {
+            throw new Error("Grammar's 'metadata.fileTypes' property must be an array of strings.");
+          }
+          metadata['file-types'] = value;
+          break;
+        case 'highlights':
+        case 'injections':
+        case 'locals':
+          const paths = typeof value === 'string' ? [value] : value;
+          if (!Array.isArray(paths) || paths.some(v => typeof v !== 'string')) {
+            throw new Error(`Grammar's 'metadata.${key}' property must be a path or an array of paths.`);
+          }
+          metadata[key] = paths;
+          break;
+        default:
+          throw new Error(`Unknown grammar metadata property '${key}'.`);
+      }
+    }
+  }
+
   if (Object.keys(rules).length == 0) {
     throw new Error("Grammar must have at least one rule.");
   }
 
-  return {name, word, rules, extras, conflicts, externals, inline, supertypes};
+  return {
+    name,
+    word,
+    rules,
+    extras,
+    conflicts,
+    externals,
+    externalScanner,
+    inline,
+    supertypes,
+    keywordExtractionExclude,
+    recoveryTokens,
+    metadata
+  };
 }
 
 function checkArguments(ruleCount, caller, callerName, suffix = '') {
@@ -0,0 +1,438 @@
+use super::grammars::InputGrammar;
+use super::rules::{Associativity, Rule};
+use std::fmt;
+
+/// The differences between two versions of a grammar, as found by `diff_grammars`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct GrammarDiff {
+    /// The names of the rules that only exist in the new grammar, along with their
+    /// definitions.
+    pub added_rules: Vec<(String, Rule)>,
+    /// The names of the rules that only exist in the old grammar.
+    pub removed_rules: Vec<String>,
+    /// The names of the rules whose definitions differ, along with the parts of the
+    /// definitions that differ.
+    pub modified_rules: Vec<(String, Vec<RuleDifference>)>,
+    /// The name of the start rule in the old and new grammar, if it changed.
+    pub start_rule: Option<(String, String)>,
+    /// The other properties of the grammar that differ, such as `extras` or `conflicts`,
+    /// along with their old and new values.
+    pub modified_properties: Vec<(&'static str, String, String)>,
+}
+
+/// A part of a rule's definition that differs between two versions of a grammar.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RuleDifference {
+    /// The location of the part within the definition, like `seq[1] > choice[0]`. This
+    /// is empty if the definitions differ at the top level.
+    pub path: String,
+    pub old: String,
+    pub new: String,
+}
+
+impl GrammarDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added_rules.is_empty()
+            && self.removed_rules.is_empty()
+            && self.modified_rules.is_empty()
+            && self.start_rule.is_none()
+            && self.modified_properties.is_empty()
+    }
+}
+
+// Compare two grammars rule by rule. Rules are matched by name, so renaming a rule
+// shows up as the removal of one rule and the addition of another. Within a rule, the
+// definitions are compared structurally, so that only the innermost parts that differ
+// are reported.
+pub fn diff_grammars(old: &InputGrammar, new: &InputGrammar) -> GrammarDiff {
+    let mut result = GrammarDiff::default();
+
+    for variable in &new.variables {
+        match old.variables.iter().find(|v| v.name == variable.name) {
+            Some(old_variable) => {
+                let mut differences = Vec::new();
+                diff_rules(
+                    &mut String::new(),
+                    &old_variable.rule,
+                    &variable.rule,
+                    &mut differences,
+                );
+                if !differences.is_empty() {
+                    result
+                        .modified_rules
+                        .push((variable.name.clone(), differences));
+                }
+            }
+            None => result
+                .added_rules
+                .push((variable.name.clone(), variable.rule.clone())),
+        }
+    }
+    for variable in &old.variables {
+        if !new.variables.iter().any(|v| v.name == variable.name) {
+            result.removed_rules.push(variable.name.clone());
+        }
+    }
+
+    let old_start_rule = old.variables.first().map(|v| v.name.as_str());
+    let new_start_rule = new.variables.first().map(|v| v.name.as_str());
+    if old_start_rule != new_start_rule {
+        result.start_rule = Some((
+            old_start_rule.unwrap_or("").to_string(),
+            new_start_rule.unwrap_or("").to_string(),
+        ));
+    }
+
+    let mut diff_property = |name, old: String, new: String| {
+        if old != new {
+            result.modified_properties.push((name, old, new));
+        }
+    };
+    diff_property("name", format!("{:?}", old.name), format!("{:?}", new.name));
+    diff_property(
+        "word",
+        format!("{:?}", old.word_token),
+        format!("{:?}", new.word_token),
+    );
+    diff_property(
+        "extras",
+        format_rules(&old.extra_symbols),
+        format_rules(&new.extra_symbols),
+    );
+    diff_property(
+        "conflicts",
+        format!("{:?}", old.expected_conflicts),
+        format!("{:?}", new.expected_conflicts),
+    );
+    diff_property(
+        "externals",
+        format_rules(&old.external_tokens),
+        format_rules(&new.external_tokens),
+    );
+    diff_property(
+        "inline",
+        format!("{:?}", old.variables_to_inline),
+        format!("{:?}", new.variables_to_inline),
+    );
+    diff_property(
+        "supertypes",
+        format!("{:?}", old.supertype_symbols),
+        format!("{:?}", new.supertype_symbols),
+    );
+    diff_property(
+        "keywordExtractionExclude",
+        format_rules(&old.excluded_keywords),
+        format_rules(&new.excluded_keywords),
+    );
+    diff_property(
+        "recoveryTokens",
+        format_rules(&old.recovery_tokens),
+        format_rules(&new.recovery_tokens),
+    );
+    diff_property(
+        "externalScanner.maxSerializedSize",
+        format!("{:?}", old.max_serialized_size),
+        format!("{:?}", new.max_serialized_size),
+    );
+
+    result
+}
+
+fn diff_rules(path: &mut String, old: &Rule, new: &Rule, differences: &mut Vec<RuleDifference>) {
+    if old == new {
+        return;
+    }
+
+    let path_len = path.len();
+    match (old, new) {
+        (Rule::Seq(old_members), Rule::Seq(new_members))
+        | (Rule::Choice(old_members), Rule::Choice(new_members))
+            if old_members.len() == new_members.len() =>
+        {
+            let kind = if let Rule::Seq(_) = old {
+                "seq"
+            } else {
+                "choice"
+            };
+            for (i, (old_member, new_member)) in old_members.iter().zip(new_members).enumerate() {
+                push_path_segment(path, &format!("{}[{}]", kind, i));
+                diff_rules(path, old_member, new_member, differences);
+                path.truncate(path_len);
+            }
+        }
+        (Rule::Repeat(old_content), Rule::Repeat(new_content)) => {
+            push_path_segment(path, "repeat");
+            diff_rules(path, old_content, new_content, differences);
+            path.truncate(path_len);
+        }
+        (
+            Rule::Metadata {
+                params: old_params,
+                rule: old_content,
+            },
+            Rule::Metadata {
+                params: new_params,
+                rule: new_content,
+            },
+        ) if old_params == new_params => diff_rules(path, old_content, new_content, differences),
+        _ => differences.push(RuleDifference {
+            path: path.clone(),
+            old: format_rule(old),
+            new: format_rule(new),
+        }),
+    }
+}
+
+fn push_path_segment(path: &mut String, segment: &str) {
+    if !path.is_empty() {
+        path.push_str(" > ");
+    }
+    path.push_str(segment);
+}
+
+// Format a rule in the style of the grammar DSL, so that differences can be reported in
+// the terms in which the grammar was written.
+fn format_rule(rule: &Rule) -> String {
+    match rule {
+        Rule::Blank => "blank()".to_string(),
+        Rule::String(value) => format!("{:?}", value),
+        Rule::Pattern(value) => format!("/{}/", value),
+        Rule::NamedSymbol(name) => format!("$.{}", name),
+        Rule::Symbol(symbol) => format!("{:?}", symbol),
+        Rule::Choice(members) => format!("choice({})", format_members(members)),
+        Rule::Seq(members) => format!("seq({})", format_members(members)),
+        Rule::Repeat(content) => format!("repeat1({})", format_rule(content)),
+        Rule::Metadata { params, rule } => {
+            let mut result = format_rule(rule);
+            if params.is_token {
+                let name = if params.is_main_token {
+                    "token.immediate"
+                } else {
+                    "token"
+                };
+                result = format!("{}({})", name, result);
+            }
+            if let Some(alias) = &params.alias {
+                if alias.is_named {
+                    result = format!("alias({}, $.{})", result, alias.value);
+                } else {
+                    result = format!("alias({}, {:?})", result, alias.value);
+                }
+            }
+            if let Some(precedence) = params.precedence {
+                let name = match params.associativity {
+                    Some(Associativity::Left) => "prec.left",
+                    Some(Associativity::Right) => "prec.right",
+                    None => "prec",
+                };
+                result = format!("{}({}, {})", name, precedence, result);
+            }
+            if params.dynamic_precedence != 0 {
+                result = format!("prec.dynamic({}, {})", params.dynamic_precedence, result);
+            }
+            if let Some(field_name) = &params.field_name {
+                result = format!("field({:?}, {})", field_name, result);
+            }
+            result
+        }
+    }
+}
+
+fn format_members(members: &[Rule]) -> String {
+    members
+        .iter()
+        .map(format_rule)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn format_rules(rules: &[Rule]) -> String {
+    format!("[{}]", format_members(rules))
+}
+
+impl fmt::Display for GrammarDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some((old, new)) = &self.start_rule {
+            writeln!(f, "start rule changed from `{}` to `{}`", old, new)?;
+        }
+        for (name, rule) in &self.added_rules {
+            writeln!(f, "added rule `{}`: {}", name, format_rule(rule))?;
+        }
+        for name in &self.removed_rules {
+            writeln!(f, "removed rule `{}`", name)?;
+        }
+        for (name, differences) in &self.modified_rules {
+            writeln!(f, "changed rule `{}`:", name)?;
+            for difference in differences {
+                if difference.path.is_empty() {
+                    writeln!(f, "  {} -> {}", difference.old, difference.new)?;
+                } else {
+                    writeln!(
+                        f,
+                        "  at {}: {} -> {}",
+                        difference.path, difference.old, difference.new
+                    )?;
+                }
+            }
+        }
+        for (name, old, new) in &self.modified_properties {
+            writeln!(f, "changed `{}`: {} -> {}", name, old, new)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::diagnostics::Diagnostics;
+    use crate::generate::parse_grammar::parse_grammar;
+
+    #[test]
+    fn test_diff_grammars_with_added_removed_and_modified_rules() {
+        let old = grammar(
+            r#"{
+                "name": "test",
+                "rules": {
+                    "program": {"type": "REPEAT", "content": {"type": "SYMBOL", "name": "statement"}},
+                    "statement": {
+                        "type": "SEQ",
+                        "members": [
+                            {"type": "SYMBOL", "name": "identifier"},
+                            {"type": "STRING", "value": "+"},
+                            {"type": "SYMBOL", "name": "number"}
+                        ]
+                    },
+                    "comment": {"type": "PATTERN", "value": "//.*"},
+                    "identifier": {"type": "PATTERN", "value": "[a-z]+"},
+                    "number": {"type": "PATTERN", "value": "\\d+"}
+                }
+            }"#,
+        );
+        let new = grammar(
+            r#"{
+                "name": "test",
+                "rules": {
+                    "program": {"type": "REPEAT", "content": {"type": "SYMBOL", "name": "statement"}},
+                    "statement": {
+                        "type": "SEQ",
+                        "members": [
+                            {"type": "SYMBOL", "name": "identifier"},
+                            {"type": "STRING", "value": "-"},
+                            {
+                                "type": "FIELD",
+                                "name": "value",
+                                "content": {"type": "SYMBOL", "name": "string"}
+                            }
+                        ]
+                    },
+                    "identifier": {"type": "PATTERN", "value": "[a-z]+"},
+                    "number": {"type": "PATTERN", "value": "\\d+"},
+                    "string": {"type": "PATTERN", "value": "'[^']*'"}
+                }
+            }"#,
+        );
+
+        let diff = diff_grammars(&old, &new);
+        assert_eq!(
+            diff.added_rules,
+            vec![("string".to_string(), Rule::Pattern("'[^']*'".to_string()))]
+        );
+        assert_eq!(diff.removed_rules, vec!["comment".to_string()]);
+        assert_eq!(
+            diff.modified_rules,
+            vec![(
+                "statement".to_string(),
+                vec![
+                    RuleDifference {
+                        path: "seq[1]".to_string(),
+                        old: "\"+\"".to_string(),
+                        new: "\"-\"".to_string(),
+                    },
+                    RuleDifference {
+                        path: "seq[2]".to_string(),
+                        old: "$.number".to_string(),
+                        new: "field(\"value\", $.string)".to_string(),
+                    },
+                ]
+            )]
+        );
+        assert_eq!(diff.start_rule, None);
+        assert!(diff.modified_properties.is_empty());
+        assert_eq!(
+            diff.to_string(),
+            "added rule `string`: /'[^']*'/\n\
+             removed rule `comment`\n\
+             changed rule `statement`:\n  \
+             at seq[1]: \"+\" -> \"-\"\n  \
+             at seq[2]: $.number -> field(\"value\", $.string)\n"
+        );
+
+        assert!(diff_grammars(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn test_diff_grammars_with_changed_structure_and_properties() {
+        let old = grammar(
+            r#"{
+                "name": "test",
+                "extras": [{"type": "PATTERN", "value": "\\s"}],
+                "rules": {
+                    "program": {
+                        "type": "CHOICE",
+                        "members": [
+                            {"type": "SYMBOL", "name": "identifier"},
+                            {"type": "SYMBOL", "name": "number"}
+                        ]
+                    },
+                    "identifier": {"type": "PATTERN", "value": "[a-z]+"},
+                    "number": {"type": "PATTERN", "value": "\\d+"}
+                }
+            }"#,
+        );
+        let new = grammar(
+            r#"{
+                "name": "test",
+                "rules": {
+                    "identifier": {"type": "PATTERN", "value": "[a-z]+"},
+                    "program": {
+                        "type": "CHOICE",
+                        "members": [
+                            {"type": "SYMBOL", "name": "identifier"},
+                            {"type": "SYMBOL", "name": "number"},
+                            {"type": "BLANK"}
+                        ]
+                    },
+                    "number": {
+                        "type": "PREC",
+                        "value": 1,
+                        "content": {"type": "TOKEN", "content": {"type": "PATTERN", "value": "\\d+"}}
+                    }
+                }
+            }"#,
+        );
+
+        let diff = diff_grammars(&old, &new);
+        assert!(diff.added_rules.is_empty());
+        assert!(diff.removed_rules.is_empty());
+        assert_eq!(
+            diff.start_rule,
+            Some(("program".to_string(), "identifier".to_string()))
+        );
+
+        // When the number of members differs, the whole choice is reported.
+        assert_eq!(
+            diff.to_string(),
+            "start rule changed from `program` to `identifier`\n\
+             changed rule `program`:\n  \
+             choice($.identifier, $.number) -> choice($.identifier, $.number, blank())\n\
+             changed rule `number`:\n  \
+             /\\d+/ -> prec(1, token(/\\d+/))\n\
+             changed `extras`: [/\\s/] -> []\n"
+        );
+    }
+
+    fn grammar(json: &str) -> InputGrammar {
+        parse_grammar(json, &mut Diagnostics::new()).unwrap()
+    }
+}
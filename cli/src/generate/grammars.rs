@@ -1,26 +1,32 @@
-use super::nfa::Nfa;
+use super::nfa::{Nfa, NfaState};
 use super::rules::{Alias, Associativity, Rule, Symbol};
+use serde_derive::Serialize;
 use std::collections::HashMap;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
-pub(crate) enum VariableType {
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum VariableType {
     Hidden,
     Auxiliary,
     Anonymous,
     Named,
 }
 
+/// The prefix of the names of the rules and tokens that are synthesized while preparing a
+/// grammar, such as the rules that implement repetitions. Grammars may not use it in the
+/// names of their own rules.
+pub(crate) const AUXILIARY_NAME_PREFIX: &'static str = "__";
+
 // Input grammar
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub(crate) struct Variable {
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Variable {
     pub name: String,
     pub kind: VariableType,
     pub rule: Rule,
 }
 
-#[derive(Debug, PartialEq, Eq)]
-pub(crate) struct InputGrammar {
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct InputGrammar {
     pub name: String,
     pub variables: Vec<Variable>,
     pub extra_symbols: Vec<Rule>,
@@ -29,63 +35,69 @@ pub(crate) struct InputGrammar {
     pub variables_to_inline: Vec<String>,
     pub supertype_symbols: Vec<String>,
     pub word_token: Option<String>,
+    pub excluded_keywords: Vec<Rule>,
+    pub recovery_tokens: Vec<Rule>,
+    pub max_serialized_size: Option<usize>,
 }
 
 // Extracted lexical grammar
 
-#[derive(Debug, PartialEq, Eq)]
-pub(crate) struct LexicalVariable {
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct LexicalVariable {
     pub name: String,
     pub kind: VariableType,
     pub implicit_precedence: i32,
     pub start_state: u32,
 }
 
-#[derive(Debug, Default, PartialEq, Eq)]
-pub(crate) struct LexicalGrammar {
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct LexicalGrammar {
     pub nfa: Nfa,
     pub variables: Vec<LexicalVariable>,
 }
 
 // Extracted syntax grammar
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
-pub(crate) struct ProductionStep {
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+pub struct ProductionStep {
     pub symbol: Symbol,
     pub precedence: i32,
     pub associativity: Option<Associativity>,
     pub alias: Option<Alias>,
     pub field_name: Option<String>,
+    // Fields that were assigned to this step within an inlined rule, but which
+    // were superseded by a field assigned at the inlined rule's call site.
+    pub inner_field_names: Vec<String>,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub(crate) struct Production {
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize)]
+pub struct Production {
     pub steps: Vec<ProductionStep>,
     pub dynamic_precedence: i32,
 }
 
-#[derive(Default)]
-pub(crate) struct InlinedProductionMap {
+#[derive(Debug, Default)]
+pub struct InlinedProductionMap {
     pub productions: Vec<Production>,
     pub production_map: HashMap<(*const Production, u32), Vec<usize>>,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub(crate) struct SyntaxVariable {
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct SyntaxVariable {
     pub name: String,
     pub kind: VariableType,
     pub productions: Vec<Production>,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub(crate) struct ExternalToken {
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ExternalToken {
     pub name: String,
     pub kind: VariableType,
     pub corresponding_internal_token: Option<Symbol>,
 }
 
-#[derive(Debug, Default)]
-pub(crate) struct SyntaxGrammar {
+#[derive(Debug, Default, Serialize)]
+pub struct SyntaxGrammar {
     pub variables: Vec<SyntaxVariable>,
     pub extra_symbols: Vec<Symbol>,
     pub expected_conflicts: Vec<Vec<Symbol>>,
@@ -93,6 +105,11 @@ pub(crate) struct SyntaxGrammar {
     pub supertype_symbols: Vec<Symbol>,
     pub variables_to_inline: Vec<Symbol>,
     pub word_token: Option<Symbol>,
+    pub excluded_keywords: Vec<Symbol>,
+    pub recovery_tokens: Vec<Symbol>,
+    /// The maximum number of bytes that the external scanner's state can be serialized
+    /// into, if the grammar declares it.
+    pub max_serialized_size: Option<usize>,
 }
 
 #[cfg(test)]
@@ -104,6 +121,7 @@ impl ProductionStep {
             associativity: None,
             alias: None,
             field_name: None,
+            inner_field_names: Vec::new(),
         }
     }
 
@@ -112,8 +130,7 @@ impl ProductionStep {
             symbol: self.symbol,
             precedence,
             associativity,
-            alias: self.alias,
-            field_name: self.field_name,
+            ..self
         }
     }
 
@@ -126,7 +143,7 @@ impl ProductionStep {
                 value: value.to_string(),
                 is_named,
             }),
-            field_name: self.field_name,
+            ..self
         }
     }
     pub(crate) fn with_field_name(self, name: &str) -> Self {
@@ -134,8 +151,8 @@ impl ProductionStep {
             symbol: self.symbol,
             precedence: self.precedence,
             associativity: self.associativity,
-            alias: self.alias,
             field_name: Some(name.to_string()),
+            ..self
         }
     }
 }
@@ -144,6 +161,14 @@ impl Production {
     pub fn first_symbol(&self) -> Option<Symbol> {
         self.steps.first().map(|s| s.symbol.clone())
     }
+
+    pub fn symbols<'a>(&'a self) -> impl Iterator<Item = Symbol> + 'a {
+        self.steps.iter().map(|step| step.symbol)
+    }
+
+    pub fn precedences<'a>(&'a self) -> impl Iterator<Item = i32> + 'a {
+        self.steps.iter().map(|step| step.precedence)
+    }
 }
 
 impl Default for Production {
@@ -221,12 +246,49 @@ impl LexicalGrammar {
     }
 }
 
+impl LexicalGrammar {
+    pub fn token_names<'a>(&'a self) -> impl Iterator<Item = &'a str> + 'a {
+        self.variables.iter().map(|v| v.name.as_str())
+    }
+
+    pub fn token_with_name(&self, name: &str) -> Option<&LexicalVariable> {
+        self.variables.iter().find(|v| v.name == name)
+    }
+
+    // The NFA states that make up a token's pattern, in the order that they
+    // were added to the NFA. Each token's states occupy a contiguous range
+    // ending at that token's start state.
+    pub fn token_pattern(&self, index: usize) -> &[NfaState] {
+        let end = self.variables[index].start_state as usize + 1;
+        let start = if index == 0 {
+            0
+        } else {
+            self.variables[index - 1].start_state as usize + 1
+        };
+        &self.nfa.states[start..end]
+    }
+}
+
 impl SyntaxVariable {
     pub fn is_auxiliary(&self) -> bool {
         self.kind == VariableType::Auxiliary
     }
 }
 
+impl SyntaxGrammar {
+    pub fn rule_names<'a>(&'a self) -> impl Iterator<Item = &'a str> + 'a {
+        self.variables.iter().map(|v| v.name.as_str())
+    }
+
+    pub fn rule_with_name(&self, name: &str) -> Option<&SyntaxVariable> {
+        self.variables.iter().find(|v| v.name == name)
+    }
+
+    pub fn productions_for_rule(&self, name: &str) -> Option<&[Production]> {
+        self.rule_with_name(name).map(|v| v.productions.as_slice())
+    }
+}
+
 impl InlinedProductionMap {
     pub fn inlined_productions<'a>(
         &'a self,
@@ -243,3 +305,118 @@ impl InlinedProductionMap {
             })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::generate::grammar::{parse_grammar, prepare_grammar, NfaState};
+    use crate::generate::Diagnostics;
+
+    #[test]
+    fn test_prepared_grammar_accessors() {
+        let input_grammar = parse_grammar(
+            r#"{
+                "name": "my_lang",
+                "rules": {
+                    "program": {
+                        "type": "REPEAT1",
+                        "content": {"type": "SYMBOL", "name": "statement"}
+                    },
+                    "statement": {
+                        "type": "PREC_LEFT",
+                        "value": 2,
+                        "content": {
+                            "type": "SEQ",
+                            "members": [
+                                {"type": "SYMBOL", "name": "identifier"},
+                                {"type": "STRING", "value": ";"}
+                            ]
+                        }
+                    },
+                    "identifier": {"type": "PATTERN", "value": "[a-z]+"}
+                }
+            }"#,
+            &mut Diagnostics::new(),
+        )
+        .unwrap();
+        let (syntax_grammar, lexical_grammar, _, _) =
+            prepare_grammar(&input_grammar, &mut Diagnostics::new()).unwrap();
+
+        assert_eq!(
+            syntax_grammar.rule_names().collect::<Vec<_>>(),
+            vec!["program", "statement", "__program_repeat1"]
+        );
+        assert!(syntax_grammar.rule_with_name("identifier").is_none());
+
+        let productions = syntax_grammar.productions_for_rule("statement").unwrap();
+        assert_eq!(productions.len(), 1);
+        assert_eq!(productions[0].precedences().collect::<Vec<_>>(), vec![2, 2]);
+        assert_eq!(
+            productions[0]
+                .symbols()
+                .map(|s| lexical_grammar.variables[s.index].name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["identifier", ";"]
+        );
+
+        assert_eq!(
+            lexical_grammar.token_names().collect::<Vec<_>>(),
+            vec![";", "identifier"]
+        );
+        let index = lexical_grammar
+            .token_names()
+            .position(|name| name == "identifier")
+            .unwrap();
+        let pattern = lexical_grammar.token_pattern(index);
+        assert_eq!(
+            pattern.first(),
+            Some(&NfaState::Accept {
+                variable_index: index,
+                precedence: 0,
+            })
+        );
+        assert_eq!(
+            (0..lexical_grammar.variables.len())
+                .map(|i| lexical_grammar.token_pattern(i).len())
+                .sum::<usize>(),
+            lexical_grammar.nfa.states.len()
+        );
+
+        let json = serde_json::to_value(&syntax_grammar).unwrap();
+        assert_eq!(json["variables"][1]["name"], "statement");
+    }
+
+    #[test]
+    fn test_auxiliary_rule_names_are_distinct_from_grammar_rule_names() {
+        let input_grammar = parse_grammar(
+            r#"{
+                "name": "my_lang",
+                "rules": {
+                    "program": {
+                        "type": "REPEAT1",
+                        "content": {"type": "SYMBOL", "name": "program_repeat1"}
+                    },
+                    "program_repeat1": {
+                        "type": "SEQ",
+                        "members": [
+                            {"type": "PATTERN", "value": "[a-z]+"},
+                            {"type": "STRING", "value": ";"}
+                        ]
+                    }
+                }
+            }"#,
+            &mut Diagnostics::new(),
+        )
+        .unwrap();
+        let (syntax_grammar, lexical_grammar, _, _) =
+            prepare_grammar(&input_grammar, &mut Diagnostics::new()).unwrap();
+
+        assert_eq!(
+            syntax_grammar.rule_names().collect::<Vec<_>>(),
+            vec!["program", "program_repeat1", "__program_repeat1"]
+        );
+        assert_eq!(
+            lexical_grammar.token_names().collect::<Vec<_>>(),
+            vec!["__program_repeat1_token1", ";"]
+        );
+    }
+}
@@ -1,28 +1,71 @@
 mod build_tables;
+mod compile_commands;
+mod complexity;
 mod dedup;
+mod diagnostics;
+mod grammar_diff;
 mod grammars;
 mod nfa;
 mod node_types;
 mod npm_files;
 pub mod parse_grammar;
+mod parser_header;
 mod prepare_grammar;
+mod progress;
+mod queries;
 mod render;
 mod rules;
 mod tables;
 
+// The intermediate representations of a grammar, exposed for tooling that
+// analyzes grammars. These types are not yet considered a stable API.
+pub mod grammar {
+    pub use super::complexity::{
+        grammar_complexity, GrammarComplexity, RuleComplexity, TokenComplexity,
+    };
+    pub use super::grammar_diff::{diff_grammars, GrammarDiff, RuleDifference};
+    pub use super::grammars::{
+        ExternalToken, InlinedProductionMap, InputGrammar, LexicalGrammar, LexicalVariable,
+        Production, ProductionStep, SyntaxGrammar, SyntaxVariable, Variable, VariableType,
+    };
+    pub use super::nfa::{CharacterSet, Nfa, NfaState};
+    pub use super::parse_grammar::parse_grammar;
+    pub use super::prepare_grammar::{prepare_grammar, prepare_grammar_with_limits};
+    pub use super::rules::{
+        Alias, AliasMap, Associativity, MetadataParams, Rule, Symbol, SymbolType,
+    };
+}
+
+pub use self::diagnostics::{Diagnostic, Diagnostics, Severity};
+pub use self::prepare_grammar::ProductionLimits;
+pub use self::progress::Progress;
+pub use self::render::{SmallStateThreshold, TableSplit};
+
 use self::build_tables::build_tables;
+use self::build_tables::cache::LexTableCache;
+use self::compile_commands::update_compile_commands;
+use self::complexity::grammar_complexity;
+use self::grammar_diff::diff_grammars;
 use self::grammars::{InlinedProductionMap, LexicalGrammar, SyntaxGrammar};
-use self::parse_grammar::parse_grammar;
-use self::prepare_grammar::prepare_grammar;
-use self::render::render_c_code;
+use self::parse_grammar::{
+    generate_metadata_json, normalize_grammar_json, parse_grammar, SERIALIZATION_BUFFER_SIZE,
+};
+use self::parser_header::parser_header;
+use self::prepare_grammar::{prepare_grammar, prepare_grammar_with_limits, report_external_tokens};
+use self::queries::{check_queries, check_removed_node_types};
+use self::render::{
+    external_token_type_names, render_c_code, LexMetadata, SymbolMap, STABLE_LANGUAGE_VERSION,
+};
 use self::rules::AliasMap;
 use crate::error::{Error, Result};
 use lazy_static::lazy_static;
 use regex::{Regex, RegexBuilder};
+use std::fmt;
 use std::fs;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
 
 lazy_static! {
     static ref JSON_COMMENT_REGEX: Regex = RegexBuilder::new("^\\s*//.*")
@@ -31,27 +74,142 @@ lazy_static! {
         .unwrap();
 }
 
-const NEW_HEADER_PARTS: [&'static str; 2] = [
-    "
-  uint32_t large_state_count;
-  const uint16_t *small_parse_table;
-  const uint32_t *small_parse_table_map;",
-    "
-#define SMALL_STATE(id) id - LARGE_STATE_COUNT
-",
-];
-
 struct GeneratedParser {
     c_code: String,
     node_types_json: String,
+    symbol_map: SymbolMap,
+    lex_metadata: LexMetadata,
+    table_split: TableSplit,
+}
+
+/// Configures a call to `generate_in_memory` or `generate_parser_in_directory`.
+///
+/// The `report_*` options print the same reports as the corresponding flags of the
+/// `generate` command. The external token and complexity reports are returned in the
+/// `GeneratedArtifacts` instead of being printed.
+#[derive(Clone, Debug, Default)]
+pub struct GenerateOptions<'a> {
+    pub next_abi: bool,
+    pub report_symbol_name: Option<&'a str>,
+    pub report_dynamic_precedence: bool,
+    pub report_keywords: bool,
+    pub report_externals: bool,
+    pub report_complexity: Option<usize>,
+    pub report_complexity_json: bool,
+    pub report_auxiliary: bool,
+    pub report_token_names: &'a [&'a str],
+    /// A directory in which to cache lex tables. This is the only option that accesses
+    /// the file system.
+    pub cache_dir: Option<&'a Path>,
+    pub symbol_map: bool,
+    pub lex_metadata: bool,
+    /// Whether to produce a skeleton of an external scanner, for grammars that have
+    /// `externals`.
+    pub scanner: bool,
+    pub stats: bool,
+    /// Where to report the progress of each phase of generation.
+    pub progress: Option<&'a Progress>,
+    pub production_limits: ProductionLimits,
+    pub small_state_threshold: SmallStateThreshold,
+}
+
+/// Configures how `generate_parser_in_directory` reads a grammar from a repository, and
+/// which files it writes there, in addition to the parser itself.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DirectoryOptions<'a> {
+    /// The grammar to generate the parser from, instead of the repository's `grammar.js`.
+    pub grammar_path: Option<&'a str>,
+    /// Whether to write only `src/node-types.json`.
+    pub node_types_only: bool,
+    /// Whether to check the repository's queries against the generated parser. Invalid
+    /// queries are reported as warnings, unless `strict_queries` is set.
+    pub validate_queries: bool,
+    pub strict_queries: bool,
+    /// Whether to add the generated C files to the repository's `compile_commands.json`.
+    pub compile_commands: bool,
+    pub grammar_json: WriteGrammarJson,
+    /// The codes of the warnings to treat as errors, or an empty list to treat every
+    /// warning as an error. Nothing is written if generating the parser produces an error.
+    pub warnings_as_errors: Option<&'a [&'a str]>,
+}
+
+/// Determines whether `generate_parser_in_directory` writes the grammar that it loaded to
+/// `src/grammar.json`, in a normalized form.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WriteGrammarJson {
+    /// Write `src/grammar.json` only if the grammar was loaded from the repository's
+    /// `grammar.js`, because no grammar path was given.
+    Auto,
+    Always,
+    Never,
+}
+
+impl Default for WriteGrammarJson {
+    fn default() -> Self {
+        WriteGrammarJson::Auto
+    }
+}
+
+/// The files that are generated for a parser, as produced by `generate_in_memory`.
+#[derive(Clone, Debug)]
+pub struct GeneratedArtifacts {
+    pub language_name: String,
+    /// The C code of the parser, as a list of file names and their contents. Currently,
+    /// the parser is always generated as a single file, `parser.c`.
+    pub c_files: Vec<(String, String)>,
+    pub node_types_json: String,
+    /// The contents of `tree_sitter/parser.h` for the selected ABI.
+    pub parser_header: String,
+    pub metadata_json: Option<String>,
+    pub symbol_map_json: Option<String>,
+    pub lex_metadata_json: Option<String>,
+    /// The contents of `scanner.c`, if a scanner skeleton was requested.
+    pub scanner_c: Option<String>,
+    pub stats: Option<GenerateStats>,
+    pub externals_report: Option<String>,
+    pub complexity_report: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct GenerateStats {
+    pub prepare_duration: Duration,
+    pub generate_duration: Duration,
+    pub lex_table_cache: Option<LexTableCacheStats>,
+    /// The total number of productions after the grammar's rules were flattened.
+    pub production_count: usize,
+    /// The name of the rule with the most productions, and its number of productions.
+    pub largest_rule: Option<(String, usize)>,
+    /// How the parse states were split between the large and the small parse table, for
+    /// parsers that use the next ABI.
+    pub table_split: Option<TableSplit>,
+}
+
+/// How the lex table cache was used while generating a parser.
+#[derive(Clone, Debug)]
+pub struct LexTableCacheStats {
+    pub hit_count: usize,
+    pub miss_count: usize,
+    /// The time that it originally took to compute the results that were loaded from the
+    /// cache, which is roughly the time that the cache saved.
+    pub time_saved: Duration,
 }
 
 pub fn generate_parser_in_directory(
     repo_path: &PathBuf,
-    grammar_path: Option<&str>,
-    next_abi: bool,
-    report_symbol_name: Option<&str>,
+    directory_options: &DirectoryOptions,
+    options: &GenerateOptions,
+    diagnostics: &mut Diagnostics,
 ) -> Result<()> {
+    let DirectoryOptions {
+        grammar_path,
+        node_types_only,
+        validate_queries,
+        strict_queries,
+        compile_commands: write_compile_commands,
+        grammar_json: write_grammar_json,
+        warnings_as_errors,
+    } = *directory_options;
+    let progress = options.progress;
     let src_path = repo_path.join("src");
     let header_path = src_path.join("tree_sitter");
 
@@ -59,85 +217,354 @@ pub fn generate_parser_in_directory(
     fs::create_dir_all(&src_path)?;
     fs::create_dir_all(&header_path)?;
 
-    // Read the grammar.json.
-    let grammar_json;
-    match grammar_path {
-        Some(path) => {
-            grammar_json = load_grammar_file(path.as_ref())?;
-        }
-        None => {
-            let grammar_js_path = grammar_path.map_or(repo_path.join("grammar.js"), |s| s.into());
-            grammar_json = load_grammar_file(&grammar_js_path)?;
-            fs::write(&src_path.join("grammar.json"), &grammar_json)?;
-        }
+    // Read the grammar, from the repository's `grammar.js` unless another path is given.
+    if let Some(progress) = progress {
+        progress.begin("grammar", Some(1));
+    }
+    let write_grammar_json = match write_grammar_json {
+        WriteGrammarJson::Auto => grammar_path.is_none(),
+        WriteGrammarJson::Always => true,
+        WriteGrammarJson::Never => false,
+    };
+    let grammar_path = grammar_path.map_or(repo_path.join("grammar.js"), PathBuf::from);
+    let mut grammar_json = load_grammar_file(&grammar_path)?;
+    if write_grammar_json {
+        grammar_json = normalize_grammar_json(&grammar_json)?;
+        write_file(&src_path.join("grammar.json"), &grammar_json)?;
+    }
+    if let Some(progress) = progress {
+        progress.finish(1);
     }
 
-    // Parse and preprocess the grammar.
-    let input_grammar = parse_grammar(&grammar_json)?;
-    let (syntax_grammar, lexical_grammar, inlines, simple_aliases) =
-        prepare_grammar(&input_grammar)?;
-    let language_name = input_grammar.name;
+    if node_types_only {
+        let node_types_json = node_types_json_for_grammar(&grammar_json, diagnostics)?;
+        check_warnings_as_errors(diagnostics, warnings_as_errors)?;
+        return write_file(&src_path.join("node-types.json"), node_types_json);
+    }
 
-    // Generate the parser and related files.
-    let GeneratedParser {
-        c_code,
-        node_types_json,
-    } = generate_parser_for_grammar_with_opts(
-        &language_name,
-        syntax_grammar,
-        lexical_grammar,
-        inlines,
-        simple_aliases,
-        next_abi,
-        report_symbol_name,
-    )?;
+    let artifacts = generate_in_memory(&grammar_json, options, diagnostics)?;
+    let language_name = &artifacts.language_name;
+    check_warnings_as_errors(diagnostics, warnings_as_errors)?;
 
-    write_file(&src_path.join("parser.c"), c_code)?;
-    write_file(&src_path.join("node-types.json"), node_types_json)?;
+    if let Some(report) = &artifacts.externals_report {
+        eprintln!("{}", report);
+    }
+    if let Some(report) = &artifacts.complexity_report {
+        eprint!("{}", report);
+    }
+    if let Some(stats) = &artifacts.stats {
+        eprint!("{}", stats);
+    }
 
-    if next_abi {
-        write_file(&header_path.join("parser.h"), tree_sitter::PARSER_HEADER)?;
+    let file_count = 3
+        + artifacts.c_files.len()
+        + artifacts.metadata_json.iter().count()
+        + artifacts.symbol_map_json.iter().count()
+        + artifacts.lex_metadata_json.iter().count();
+    if let Some(progress) = progress {
+        progress.begin("write", Some(file_count));
+    }
+    if let Some(metadata_json) = artifacts.metadata_json {
+        write_file(&src_path.join("metadata.json"), metadata_json)?;
+    }
+    let c_file_names = artifacts
+        .c_files
+        .iter()
+        .map(|(name, _)| name.clone())
+        .collect::<Vec<_>>();
+    for (name, c_code) in artifacts.c_files {
+        write_file(&src_path.join(name), c_code)?;
+    }
+    let node_types_path = src_path.join("node-types.json");
+    let previous_node_types_json = if validate_queries {
+        fs::read_to_string(&node_types_path).ok()
     } else {
-        let mut header = tree_sitter::PARSER_HEADER.to_string();
+        None
+    };
+    write_file(&node_types_path, &artifacts.node_types_json)?;
+    if let Some(symbol_map_json) = artifacts.symbol_map_json {
+        write_file(&src_path.join("symbol-map.json"), symbol_map_json)?;
+    }
+    if let Some(lex_metadata_json) = artifacts.lex_metadata_json {
+        write_file(&src_path.join("lex-metadata.json"), lex_metadata_json)?;
+    }
+    write_file(&header_path.join("parser.h"), artifacts.parser_header)?;
+    if let Some(progress) = progress {
+        progress.finish(file_count);
+    }
 
-        for part in &NEW_HEADER_PARTS {
-            let pos = header
-                .find(part)
-                .expect("Missing expected part of parser.h header");
-            header.replace_range(pos..(pos + part.len()), "");
+    if validate_queries {
+        if let Some(previous_node_types_json) = &previous_node_types_json {
+            let grammar = parse_grammar(&grammar_json, &mut Diagnostics::new())?;
+            let references = check_removed_node_types(
+                repo_path,
+                previous_node_types_json,
+                &artifacts.node_types_json,
+                &grammar,
+            )?;
+            for (location, message) in references {
+                diagnostics.warn_at("removed-node-type", location, message);
+            }
+        }
+        let query_errors = check_queries(repo_path, &src_path, language_name)?;
+        if strict_queries && !query_errors.is_empty() {
+            return Error::err(format!(
+                "Queries are invalid for the generated parser:\n{}",
+                query_errors
+                    .iter()
+                    .map(|(location, message)| format!("{}: {}", location, message))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            ));
         }
+        for (location, message) in query_errors {
+            diagnostics.warn_at("invalid-query", location, message);
+        }
+        check_warnings_as_errors(diagnostics, warnings_as_errors)?;
+    }
 
-        write_file(&header_path.join("parser.h"), header)?;
+    if let Some(scanner_c) = artifacts.scanner_c {
+        if !src_path.join("scanner.cc").exists() {
+            ensure_file(&src_path.join("scanner.c"), || scanner_c.as_str())?;
+        }
+    }
+
+    if write_compile_commands {
+        let repo_path = repo_path.canonicalize()?;
+        let scanner_names = ["scanner.c", "scanner.cc"];
+        let source_files = c_file_names
+            .iter()
+            .map(String::as_str)
+            .chain(
+                scanner_names
+                    .iter()
+                    .copied()
+                    .filter(|name| src_path.join(name).exists()),
+            )
+            .map(|name| format!("src/{}", name))
+            .collect::<Vec<_>>();
+        let owned_files = c_file_names
+            .iter()
+            .map(String::as_str)
+            .chain(scanner_names.iter().copied())
+            .map(|name| format!("src/{}", name))
+            .collect::<Vec<_>>();
+        let compile_commands_path = repo_path.join("compile_commands.json");
+        let existing_json = if compile_commands_path.exists() {
+            Some(fs::read_to_string(&compile_commands_path)?)
+        } else {
+            None
+        };
+        let compile_commands_json = update_compile_commands(
+            existing_json.as_deref(),
+            &repo_path,
+            &source_files,
+            &owned_files,
+        )?;
+        write_file(&compile_commands_path, compile_commands_json)?;
     }
 
     ensure_file(&repo_path.join("index.js"), || {
-        npm_files::index_js(&language_name)
+        npm_files::index_js(language_name)
     })?;
     ensure_file(&src_path.join("binding.cc"), || {
-        npm_files::binding_cc(&language_name)
+        npm_files::binding_cc(language_name)
     })?;
     ensure_file(&repo_path.join("binding.gyp"), || {
-        npm_files::binding_gyp(&language_name)
+        npm_files::binding_gyp(language_name)
+    })?;
+    fs::create_dir_all(repo_path.join("queries"))?;
+    ensure_file(&repo_path.join("queries").join("highlights.scm"), || {
+        npm_files::highlights_scm(language_name)
     })?;
 
     Ok(())
 }
 
-pub fn generate_parser_for_grammar(grammar_json: &str) -> Result<(String, String)> {
+// Promote the selected warnings to errors, and fail if there are any errors.
+fn check_warnings_as_errors(
+    diagnostics: &mut Diagnostics,
+    warnings_as_errors: Option<&[&str]>,
+) -> Result<()> {
+    if let Some(codes) = warnings_as_errors {
+        diagnostics.promote_warnings(codes);
+    }
+    if diagnostics.has_errors() {
+        return Error::err("Some warnings were treated as errors".to_string());
+    }
+    Ok(())
+}
+
+// Generate all of the files for a parser from the contents of a `grammar.json` file,
+// without writing anything to the file system, except for the lex table cache, if
+// one is used. Warnings are added to the given diagnostics.
+pub fn generate_in_memory(
+    grammar_json: &str,
+    options: &GenerateOptions,
+    diagnostics: &mut Diagnostics,
+) -> Result<GeneratedArtifacts> {
     let grammar_json = JSON_COMMENT_REGEX.replace_all(grammar_json, "\n");
-    let input_grammar = parse_grammar(&grammar_json)?;
+    let metadata_json = generate_metadata_json(&grammar_json)?;
+
+    // Parse and preprocess the grammar.
+    if let Some(progress) = options.progress {
+        progress.begin("prepare", Some(1));
+    }
+    let start_time = Instant::now();
+    let input_grammar = parse_grammar(&grammar_json, diagnostics)?;
     let (syntax_grammar, lexical_grammar, inlines, simple_aliases) =
-        prepare_grammar(&input_grammar)?;
-    let parser = generate_parser_for_grammar_with_opts(
-        &input_grammar.name,
+        prepare_grammar_with_limits(&input_grammar, &options.production_limits, diagnostics)?;
+    let language_name = input_grammar.name;
+    let prepare_duration = start_time.elapsed();
+    let production_count = syntax_grammar
+        .variables
+        .iter()
+        .map(|variable| variable.productions.len())
+        .sum();
+    let largest_rule = syntax_grammar
+        .variables
+        .iter()
+        .max_by_key(|variable| variable.productions.len())
+        .map(|variable| (variable.name.clone(), variable.productions.len()));
+    if let Some(progress) = options.progress {
+        progress.finish(1);
+    }
+
+    let externals_report = if options.report_externals {
+        Some(report_external_tokens(&syntax_grammar, &lexical_grammar))
+    } else {
+        None
+    };
+
+    let scanner_c = if options.scanner {
+        if syntax_grammar.external_tokens.is_empty() {
+            return Error::err(
+                "The grammar has no `externals`, so it doesn't need an external scanner"
+                    .to_string(),
+            );
+        }
+        Some(npm_files::scanner_c(
+            &language_name,
+            &external_token_type_names(&syntax_grammar),
+            syntax_grammar
+                .max_serialized_size
+                .unwrap_or(SERIALIZATION_BUFFER_SIZE),
+        ))
+    } else {
+        None
+    };
+
+    let complexity_report = options.report_complexity.map(|count| {
+        let mut complexity = grammar_complexity(&syntax_grammar, &lexical_grammar);
+        if !options.report_auxiliary {
+            complexity.remove_auxiliary();
+        }
+        complexity.truncate(count);
+        if options.report_complexity_json {
+            serde_json::to_string_pretty(&complexity).unwrap() + "\n"
+        } else {
+            complexity.to_text()
+        }
+    });
+
+    // Generate the parser and related files.
+    let start_time = Instant::now();
+    let cache = options
+        .cache_dir
+        .map(|dir| LexTableCache::new(dir, &language_name));
+    let GeneratedParser {
+        c_code,
+        node_types_json,
+        symbol_map,
+        lex_metadata,
+        table_split,
+    } = generate_parser_for_grammar_with_opts(
+        &language_name,
         syntax_grammar,
         lexical_grammar,
         inlines,
         simple_aliases,
-        true,
-        None,
+        options,
+        cache.as_ref(),
+        diagnostics,
     )?;
-    Ok((input_grammar.name, parser.c_code))
+    let generate_duration = start_time.elapsed();
+
+    let stats = if options.stats {
+        Some(GenerateStats {
+            prepare_duration,
+            generate_duration,
+            lex_table_cache: cache.as_ref().map(|cache| LexTableCacheStats {
+                hit_count: cache.hit_count.get(),
+                miss_count: cache.miss_count.get(),
+                time_saved: cache.time_saved.get(),
+            }),
+            production_count,
+            largest_rule,
+            table_split: if options.next_abi {
+                Some(table_split)
+            } else {
+                None
+            },
+        })
+    } else {
+        None
+    };
+
+    Ok(GeneratedArtifacts {
+        language_name,
+        c_files: vec![("parser.c".to_string(), c_code)],
+        node_types_json,
+        parser_header: parser_header(options.next_abi)?,
+        metadata_json,
+        symbol_map_json: if options.symbol_map {
+            Some(serde_json::to_string_pretty(&symbol_map).unwrap())
+        } else {
+            None
+        },
+        lex_metadata_json: if options.lex_metadata {
+            Some(serde_json::to_string_pretty(&lex_metadata).unwrap())
+        } else {
+            None
+        },
+        scanner_c,
+        stats,
+        externals_report,
+        complexity_report,
+    })
+}
+
+// Generate the C code for a parser. Any warnings are discarded.
+pub fn generate_parser_for_grammar(grammar_json: &str) -> Result<(String, String)> {
+    let options = GenerateOptions {
+        next_abi: true,
+        ..Default::default()
+    };
+    let mut artifacts = generate_in_memory(grammar_json, &options, &mut Diagnostics::new())?;
+    let (_, c_code) = artifacts.c_files.remove(0);
+    Ok((artifacts.language_name, c_code))
+}
+
+// Generate the contents of `node-types.json`. Any warnings are discarded.
+pub fn generate_node_types_for_grammar(grammar_json: &str) -> Result<String> {
+    node_types_json_for_grammar(grammar_json, &mut Diagnostics::new())
+}
+
+fn node_types_json_for_grammar(
+    grammar_json: &str,
+    diagnostics: &mut Diagnostics,
+) -> Result<String> {
+    let grammar_json = JSON_COMMENT_REGEX.replace_all(grammar_json, "\n");
+    let input_grammar = parse_grammar(&grammar_json, diagnostics)?;
+    let (syntax_grammar, lexical_grammar, _, simple_aliases) =
+        prepare_grammar(&input_grammar, diagnostics)?;
+    let variable_info = node_types::get_variable_info(&syntax_grammar, &lexical_grammar)?;
+    Ok(render_node_types_json(
+        &syntax_grammar,
+        &lexical_grammar,
+        &simple_aliases,
+        &variable_info,
+    ))
 }
 
 fn generate_parser_for_grammar_with_opts(
@@ -146,11 +573,20 @@ fn generate_parser_for_grammar_with_opts(
     lexical_grammar: LexicalGrammar,
     inlines: InlinedProductionMap,
     simple_aliases: AliasMap,
-    next_abi: bool,
-    report_symbol_name: Option<&str>,
+    options: &GenerateOptions,
+    cache: Option<&LexTableCache>,
+    diagnostics: &mut Diagnostics,
 ) -> Result<GeneratedParser> {
+    let progress = options.progress;
+    if !options.next_abi && !syntax_grammar.recovery_tokens.is_empty() {
+        return Error::err(format!(
+            "The grammar's `recoveryTokens` require language ABI version {}, but the parser is being generated for version {}. Pass `--next-abi` to use the newer ABI.",
+            tree_sitter::LANGUAGE_VERSION,
+            STABLE_LANGUAGE_VERSION
+        ));
+    }
     let variable_info = node_types::get_variable_info(&syntax_grammar, &lexical_grammar)?;
-    let node_types_json = node_types::generate_node_types_json(
+    let node_types_json = render_node_types_json(
         &syntax_grammar,
         &lexical_grammar,
         &simple_aliases,
@@ -162,9 +598,14 @@ fn generate_parser_for_grammar_with_opts(
         &simple_aliases,
         &variable_info,
         &inlines,
-        report_symbol_name,
+        options,
+        cache,
+        diagnostics,
     )?;
-    let c_code = render_c_code(
+    if let Some(progress) = progress {
+        progress.begin("render", Some(1));
+    }
+    let (c_code, symbol_map, lex_metadata, table_split) = render_c_code(
         name,
         parse_table,
         main_lex_table,
@@ -173,14 +614,145 @@ fn generate_parser_for_grammar_with_opts(
         syntax_grammar,
         lexical_grammar,
         simple_aliases,
-        next_abi,
+        options.next_abi,
+        options.small_state_threshold,
     );
+    if let Some(progress) = progress {
+        progress.finish(1);
+    }
     Ok(GeneratedParser {
         c_code,
-        node_types_json: serde_json::to_string_pretty(&node_types_json).unwrap(),
+        node_types_json,
+        symbol_map,
+        lex_metadata,
+        table_split,
     })
 }
 
+fn render_node_types_json(
+    syntax_grammar: &SyntaxGrammar,
+    lexical_grammar: &LexicalGrammar,
+    simple_aliases: &AliasMap,
+    variable_info: &Vec<node_types::VariableInfo>,
+) -> String {
+    let node_types_json = node_types::generate_node_types_json(
+        syntax_grammar,
+        lexical_grammar,
+        simple_aliases,
+        variable_info,
+    );
+    serde_json::to_string_pretty(&node_types_json).unwrap()
+}
+
+impl fmt::Display for GenerateStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "prepare grammar: {} ms\ngenerate parser: {} ms",
+            self.prepare_duration.as_millis(),
+            self.generate_duration.as_millis()
+        )?;
+        if let Some(cache) = &self.lex_table_cache {
+            writeln!(
+                f,
+                "lex table cache: {} hits, {} misses, saved {} ms",
+                cache.hit_count,
+                cache.miss_count,
+                cache.time_saved.as_millis()
+            )?;
+        }
+        write!(f, "productions: {}", self.production_count)?;
+        if let Some((name, count)) = &self.largest_rule {
+            write!(f, " (at most {}, in `{}`)", count, name)?;
+        }
+        writeln!(f)?;
+        if let Some(split) = &self.table_split {
+            writeln!(
+                f,
+                "large parse table: {} states, {} bytes\nsmall parse table: {} states, {} bytes",
+                split.large_state_count,
+                split.large_table_bytes,
+                split.small_state_count,
+                split.small_table_bytes
+            )?;
+            writeln!(
+                f,
+                "small state threshold: {} ({} states within 10%)",
+                split.small_state_threshold, split.states_near_threshold
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Evaluates a grammar and writes it to the given path as a normalized grammar.json,
+/// without generating a parser. The grammar is read from the repository's `grammar.js`
+/// unless another path is given.
+///
+/// This allows `grammar.js` to be evaluated once, on a machine where `node` is available,
+/// so that parsers can be generated from the result where it isn't.
+pub fn emit_grammar_json(
+    repo_path: &Path,
+    grammar_path: Option<&str>,
+    output_path: &Path,
+) -> Result<()> {
+    let grammar_path = grammar_path.map_or(repo_path.join("grammar.js"), PathBuf::from);
+    let grammar_json = normalize_grammar_json(&load_grammar_file(&grammar_path)?)?;
+    parse_grammar(&grammar_json, &mut Diagnostics::new())?;
+    write_file(output_path, grammar_json)
+}
+
+/// Checks that the repository's `src/grammar.json` matches the result of evaluating its
+/// grammar, which is read from `grammar.js` unless another path is given. If they differ,
+/// the error describes the differences rule by rule.
+///
+/// If the grammar needs to be evaluated with `node` and `node` isn't available, then only
+/// the validity of `src/grammar.json` is checked, and a warning is printed.
+pub fn verify_grammar_json(repo_path: &Path, grammar_path: Option<&str>) -> Result<()> {
+    let grammar_path = grammar_path.map_or(repo_path.join("grammar.js"), PathBuf::from);
+    let checked_in_path = repo_path.join("src").join("grammar.json");
+    let checked_in_json = fs::read_to_string(&checked_in_path).map_err(Error::wrap(|| {
+        format!("Failed to read {:?}", checked_in_path)
+    }))?;
+    let checked_in_grammar = parse_grammar(&checked_in_json, &mut Diagnostics::new())?;
+
+    if grammar_path.extension().map_or(false, |e| e == "js") && !node_is_available() {
+        eprintln!(
+            "Warning: `node` is not available, so {:?} was not compared with {:?}",
+            checked_in_path, grammar_path
+        );
+        return Ok(());
+    }
+
+    let grammar_json = normalize_grammar_json(&load_grammar_file(&grammar_path)?)?;
+    let grammar = parse_grammar(&grammar_json, &mut Diagnostics::new())?;
+    let diff = diff_grammars(&checked_in_grammar, &grammar);
+    if !diff.is_empty() {
+        return Error::err(format!(
+            "{:?} is out of date with {:?}:\n{}Run `tree-sitter generate --emit-grammar-json src/grammar.json` to update it.",
+            checked_in_path, grammar_path, diff
+        ));
+    }
+
+    // Properties that don't affect the generated parser, like `metadata`, are not part
+    // of the parsed grammar, so they are compared in the normalized JSON.
+    if normalize_grammar_json(&checked_in_json)? != grammar_json {
+        return Error::err(format!(
+            "{:?} is out of date with {:?}: the rules are the same, but other properties differ",
+            checked_in_path, grammar_path
+        ));
+    }
+    Ok(())
+}
+
+fn node_is_available() -> bool {
+    Command::new("node")
+        .arg("--version")
+        .stdout(Stdio::null())
+        .status()
+        .is_ok()
+}
+
 fn load_grammar_file(grammar_path: &Path) -> Result<String> {
     match grammar_path.extension().and_then(|e| e.to_str()) {
         Some("js") => Ok(load_js_grammar_file(grammar_path)?),
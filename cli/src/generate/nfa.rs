@@ -1,16 +1,17 @@
+use serde_derive::{Deserialize, Serialize};
 use std::char;
 use std::cmp::max;
 use std::cmp::Ordering;
 use std::fmt;
 use std::mem::swap;
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum CharacterSet {
     Include(Vec<char>),
     Exclude(Vec<char>),
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Serialize)]
 pub enum NfaState {
     Advance {
         chars: CharacterSet,
@@ -25,9 +26,13 @@ pub enum NfaState {
     },
 }
 
-#[derive(PartialEq, Eq)]
+#[derive(PartialEq, Eq, Serialize)]
 pub struct Nfa {
     pub states: Vec<NfaState>,
+    /// The number of states that were added for each token's own rule, indexed by the
+    /// token's variable index. This doesn't include the states that match the separators
+    /// which can precede the token.
+    pub token_state_counts: Vec<usize>,
 }
 
 #[derive(Debug)]
@@ -46,7 +51,7 @@ pub struct NfaTransition {
 
 impl Default for Nfa {
     fn default() -> Self {
-        Self { states: Vec::new() }
+        Self::new()
     }
 }
 
@@ -77,7 +82,11 @@ impl CharacterSet {
         if let CharacterSet::Include(mut chars) = self {
             let mut c = start as u32;
             while c <= end as u32 {
-                chars.push(char::from_u32(c).unwrap());
+                // Ranges that span the surrogate block contain code points
+                // that are not valid `char`s.
+                if let Some(c) = char::from_u32(c) {
+                    chars.push(c);
+                }
                 c += 1;
             }
             chars.sort_unstable();
@@ -302,7 +311,10 @@ fn order_chars(chars: &Vec<char>, other_chars: &Vec<char>) -> Ordering {
 
 impl Nfa {
     pub fn new() -> Self {
-        Nfa { states: Vec::new() }
+        Nfa {
+            states: Vec::new(),
+            token_state_counts: Vec::new(),
+        }
     }
 
     pub fn last_state_id(&self) -> u32 {
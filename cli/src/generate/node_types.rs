@@ -220,11 +220,11 @@ pub(crate) fn get_variable_info(
                     }
 
                     // Inherit information from any hidden children.
+                    let is_inlined = syntax_grammar.variables_to_inline.contains(&child_symbol);
                     if child_symbol.is_non_terminal()
                         && !syntax_grammar.supertype_symbols.contains(&child_symbol)
                         && step.alias.is_none()
-                        && (!child_type_is_visible(&child_type)
-                            || syntax_grammar.variables_to_inline.contains(&child_symbol))
+                        && (!child_type_is_visible(&child_type) || is_inlined)
                     {
                         let child_variable_info = &result[child_symbol.index];
 
@@ -287,6 +287,24 @@ pub(crate) fn get_variable_info(
                             }
                         }
                     }
+                    // When an inlined rule is aliased, all of its children take on the
+                    // alias, but they retain any fields assigned within the inlined rule.
+                    else if child_symbol.is_non_terminal() && is_inlined {
+                        for (field_name, child_field_info) in &result[child_symbol.index].fields {
+                            field_quantities
+                                .entry(field_name)
+                                .or_insert(ChildQuantity::zero())
+                                .append(child_field_info.quantity);
+                            let field_info = variable_info
+                                .fields
+                                .entry(field_name.clone())
+                                .or_insert(FieldInfo {
+                                    types: Vec::new(),
+                                    quantity: ChildQuantity::one(),
+                                });
+                            did_change |= sorted_vec_insert(&mut field_info.types, &child_type);
+                        }
+                    }
 
                     // Note whether or not this production contains children whose summaries
                     // have not yet been computed.
@@ -679,6 +697,7 @@ pub(crate) fn generate_node_types_json(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::generate::diagnostics::Diagnostics;
     use crate::generate::grammars::{
         InputGrammar, LexicalVariable, Production, ProductionStep, SyntaxVariable, Variable,
     };
@@ -694,6 +713,9 @@ mod tests {
             expected_conflicts: Vec::new(),
             variables_to_inline: Vec::new(),
             word_token: None,
+            excluded_keywords: Vec::new(),
+            recovery_tokens: Vec::new(),
+            max_serialized_size: None,
             supertype_symbols: vec![],
             variables: vec![
                 Variable {
@@ -780,6 +802,9 @@ mod tests {
             expected_conflicts: Vec::new(),
             variables_to_inline: Vec::new(),
             word_token: None,
+            excluded_keywords: Vec::new(),
+            recovery_tokens: Vec::new(),
+            max_serialized_size: None,
             supertype_symbols: vec!["_v2".to_string()],
             variables: vec![
                 Variable {
@@ -867,6 +892,9 @@ mod tests {
             expected_conflicts: Vec::new(),
             variables_to_inline: Vec::new(),
             word_token: None,
+            excluded_keywords: Vec::new(),
+            recovery_tokens: Vec::new(),
+            max_serialized_size: None,
             supertype_symbols: vec![],
             variables: vec![
                 Variable {
@@ -965,6 +993,9 @@ mod tests {
             expected_conflicts: Vec::new(),
             variables_to_inline: Vec::new(),
             word_token: None,
+            excluded_keywords: Vec::new(),
+            recovery_tokens: Vec::new(),
+            max_serialized_size: None,
             supertype_symbols: vec![],
             variables: vec![
                 Variable {
@@ -1041,6 +1072,9 @@ mod tests {
             expected_conflicts: Vec::new(),
             variables_to_inline: Vec::new(),
             word_token: None,
+            excluded_keywords: Vec::new(),
+            recovery_tokens: Vec::new(),
+            max_serialized_size: None,
             supertype_symbols: vec![],
             variables: vec![
                 Variable {
@@ -1333,9 +1367,66 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_get_variable_info_with_aliased_inlined_rules() {
+        let mut syntax_grammar = build_syntax_grammar(
+            vec![
+                SyntaxVariable {
+                    name: "rule0".to_string(),
+                    kind: VariableType::Named,
+                    productions: vec![Production {
+                        dynamic_precedence: 0,
+                        steps: vec![
+                            ProductionStep::new(Symbol::terminal(0)),
+                            ProductionStep::new(Symbol::non_terminal(1))
+                                .with_alias("rule1_alias", true),
+                        ],
+                    }],
+                },
+                // Inlined rule with fields
+                SyntaxVariable {
+                    name: "_rule1".to_string(),
+                    kind: VariableType::Hidden,
+                    productions: vec![Production {
+                        dynamic_precedence: 0,
+                        steps: vec![
+                            ProductionStep::new(Symbol::terminal(1)).with_field_name("field1"),
+                            ProductionStep::new(Symbol::terminal(2)),
+                        ],
+                    }],
+                },
+            ],
+            vec![],
+        );
+        syntax_grammar.variables_to_inline = vec![Symbol::non_terminal(1)];
+
+        let variable_info = get_variable_info(&syntax_grammar, &build_lexical_grammar()).unwrap();
+
+        // The inlined rule's children retain their fields, but take on the alias.
+        assert_eq!(
+            variable_info[0].fields,
+            vec![(
+                "field1".to_string(),
+                FieldInfo {
+                    quantity: ChildQuantity {
+                        exists: true,
+                        required: true,
+                        multiple: false,
+                    },
+                    types: vec![ChildType::Aliased(Alias {
+                        value: "rule1_alias".to_string(),
+                        is_named: true,
+                    })],
+                }
+            )]
+            .into_iter()
+            .collect::<HashMap<_, _>>()
+        );
+    }
+
     fn get_node_types(grammar: InputGrammar) -> Vec<NodeInfoJSON> {
         let (syntax_grammar, lexical_grammar, _, simple_aliases) =
-            prepare_grammar(&grammar).unwrap();
+            prepare_grammar(&grammar, &mut Diagnostics::new()).unwrap();
         let variable_info = get_variable_info(&syntax_grammar, &lexical_grammar).unwrap();
         generate_node_types_json(
             &syntax_grammar,
@@ -3,7 +3,11 @@ use std::str;
 const BINDING_CC_TEMPLATE: &'static str = include_str!("./templates/binding.cc");
 const BINDING_GYP_TEMPLATE: &'static str = include_str!("./templates/binding.gyp");
 const INDEX_JS_TEMPLATE: &'static str = include_str!("./templates/index.js");
+const HIGHLIGHTS_SCM_TEMPLATE: &'static str = include_str!("./templates/highlights.scm");
+const SCANNER_C_TEMPLATE: &'static str = include_str!("./templates/scanner.c");
 const PARSER_NAME_PLACEHOLDER: &'static str = "PARSER_NAME";
+const TOKEN_TYPES_PLACEHOLDER: &'static str = "TOKEN_TYPES";
+const MAX_SERIALIZED_SIZE_PLACEHOLDER: &'static str = "MAX_SERIALIZED_SIZE_VALUE";
 
 pub fn binding_cc(parser_name: &str) -> String {
     BINDING_CC_TEMPLATE.replace(PARSER_NAME_PLACEHOLDER, parser_name)
@@ -16,3 +20,21 @@ pub fn binding_gyp(parser_name: &str) -> String {
 pub fn index_js(parser_name: &str) -> String {
     INDEX_JS_TEMPLATE.replace(PARSER_NAME_PLACEHOLDER, parser_name)
 }
+
+pub fn highlights_scm(parser_name: &str) -> String {
+    HIGHLIGHTS_SCM_TEMPLATE.replace(PARSER_NAME_PLACEHOLDER, parser_name)
+}
+
+pub fn scanner_c(parser_name: &str, token_types: &[String], max_serialized_size: usize) -> String {
+    let token_types = token_types
+        .iter()
+        .map(|name| format!("  {},\n", name))
+        .collect::<String>();
+    SCANNER_C_TEMPLATE
+        .replace(PARSER_NAME_PLACEHOLDER, parser_name)
+        .replace(TOKEN_TYPES_PLACEHOLDER, &token_types)
+        .replace(
+            MAX_SERIALIZED_SIZE_PLACEHOLDER,
+            &max_serialized_size.to_string(),
+        )
+}
@@ -1,7 +1,8 @@
-use super::grammars::{InputGrammar, Variable, VariableType};
+use super::diagnostics::Diagnostics;
+use super::grammars::{InputGrammar, Variable, VariableType, AUXILIARY_NAME_PREFIX};
 use super::rules::Rule;
-use crate::error::Result;
-use serde_derive::Deserialize;
+use crate::error::{Error, Result};
+use serde_derive::{Deserialize, Serialize};
 use serde_json::{Map, Value};
 
 #[derive(Deserialize)]
@@ -73,17 +74,96 @@ pub(crate) struct GrammarJSON {
     inline: Option<Vec<String>>,
     supertypes: Option<Vec<String>>,
     word: Option<String>,
+    #[serde(rename = "keywordExtractionExclude")]
+    keyword_extraction_exclude: Option<Vec<RuleJSON>>,
+    #[serde(rename = "recoveryTokens")]
+    recovery_tokens: Option<Vec<RuleJSON>>,
+    #[serde(rename = "externalScanner")]
+    external_scanner: Option<ExternalScannerJSON>,
 }
 
-pub(crate) fn parse_grammar(input: &str) -> Result<InputGrammar> {
-    let grammar_json: GrammarJSON = serde_json::from_str(&input)?;
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ExternalScannerJSON {
+    #[serde(rename = "maxSerializedSize")]
+    max_serialized_size: Option<usize>,
+}
+
+// The size of the buffer into which the runtime serializes the state of external
+// scanners. This must match `TREE_SITTER_SERIALIZATION_BUFFER_SIZE` in `parser.h`.
+pub(crate) const SERIALIZATION_BUFFER_SIZE: usize = 1024;
+
+const RULE_TYPES: &'static [&'static str] = &[
+    "ALIAS",
+    "BLANK",
+    "STRING",
+    "PATTERN",
+    "SYMBOL",
+    "CHOICE",
+    "FIELD",
+    "SEQ",
+    "REPEAT",
+    "REPEAT1",
+    "PREC_DYNAMIC",
+    "PREC_LEFT",
+    "PREC_RIGHT",
+    "PREC",
+    "TOKEN",
+    "IMMEDIATE_TOKEN",
+];
+
+// The top-level fields of grammar.json that this version of the CLI understands.
+// Any other fields are reported as warnings.
+const GRAMMAR_FIELDS: &'static [&'static str] = &[
+    "name",
+    "rules",
+    "conflicts",
+    "externals",
+    "externalScanner",
+    "extras",
+    "inline",
+    "supertypes",
+    "word",
+    "keywordExtractionExclude",
+    "recoveryTokens",
+    "metadata",
+];
+
+const INCOMPATIBLE_GRAMMAR_MESSAGE: &'static str =
+    "This grammar.json appears to have been generated by an incompatible version of tree-sitter";
+
+#[derive(Deserialize, Serialize)]
+struct MetadataJSON {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+    #[serde(rename = "file-types", default, skip_serializing_if = "Vec::is_empty")]
+    file_types: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    highlights: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    injections: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    locals: Option<Vec<String>>,
+}
+
+pub fn parse_grammar(input: &str, diagnostics: &mut Diagnostics) -> Result<InputGrammar> {
+    let grammar_json: Value = serde_json::from_str(&input)?;
+    for warning in check_grammar_json(&grammar_json)? {
+        diagnostics.warn("unknown-grammar-field", warning);
+    }
+    let grammar_json: GrammarJSON =
+        serde_json::from_value(grammar_json).map_err(Error::wrap(|| "Invalid grammar.json"))?;
 
     let mut variables = Vec::with_capacity(grammar_json.rules.len());
     for (name, value) in grammar_json.rules {
+        check_rule_name(&name)?;
+        let rule = serde_json::from_value(value).map_err(Error::wrap(|| {
+            format!("Invalid rule `{}` in grammar.json", name)
+        }))?;
         variables.push(Variable {
             name: name.to_owned(),
             kind: VariableType::Named,
-            rule: parse_rule(serde_json::from_value(value)?),
+            rule: parse_rule(rule),
         })
     }
 
@@ -98,10 +178,58 @@ pub(crate) fn parse_grammar(input: &str) -> Result<InputGrammar> {
         .unwrap_or(Vec::new())
         .into_iter()
         .map(parse_rule)
-        .collect();
+        .collect::<Vec<_>>();
+    for external_token in &external_tokens {
+        if let Rule::NamedSymbol(name) = external_token {
+            check_rule_name(name)?;
+        }
+    }
     let expected_conflicts = grammar_json.conflicts.unwrap_or(Vec::new());
     let variables_to_inline = grammar_json.inline.unwrap_or(Vec::new());
     let supertype_symbols = grammar_json.supertypes.unwrap_or(Vec::new());
+    let excluded_keywords = grammar_json
+        .keyword_extraction_exclude
+        .unwrap_or(Vec::new())
+        .into_iter()
+        .map(parse_rule)
+        .collect();
+    let mut recovery_tokens = Vec::new();
+    for (i, rule) in grammar_json
+        .recovery_tokens
+        .unwrap_or(Vec::new())
+        .into_iter()
+        .enumerate()
+    {
+        match rule {
+            RuleJSON::SYMBOL { .. } | RuleJSON::STRING { .. } => {
+                recovery_tokens.push(parse_rule(rule))
+            }
+            _ => {
+                return Error::err(format!(
+                    "Entry {} in `recoveryTokens` must be a string or a reference to a token rule",
+                    i
+                ))
+            }
+        }
+    }
+
+    let max_serialized_size = grammar_json
+        .external_scanner
+        .and_then(|scanner| scanner.max_serialized_size);
+    if let Some(size) = max_serialized_size {
+        if external_tokens.is_empty() {
+            return Error::err(
+                "`externalScanner.maxSerializedSize` is declared, but the grammar has no `externals`"
+                    .to_string(),
+            );
+        }
+        if size == 0 || size > SERIALIZATION_BUFFER_SIZE {
+            return Error::err(format!(
+                "`externalScanner.maxSerializedSize` is {}, but it must be between 1 and {}, the size of the runtime's serialization buffer (`TREE_SITTER_SERIALIZATION_BUFFER_SIZE`)",
+                size, SERIALIZATION_BUFFER_SIZE
+            ));
+        }
+    }
 
     Ok(InputGrammar {
         name: grammar_json.name,
@@ -112,9 +240,219 @@ pub(crate) fn parse_grammar(input: &str) -> Result<InputGrammar> {
         external_tokens,
         supertype_symbols,
         variables_to_inline,
+        excluded_keywords,
+        recovery_tokens,
+        max_serialized_size,
     })
 }
 
+// Produce the contents of `src/metadata.json`, which describes where the
+// language's queries can be found, if the grammar declares any metadata.
+pub(crate) fn generate_metadata_json(input: &str) -> Result<Option<String>> {
+    #[derive(Deserialize)]
+    struct GrammarMetadataJSON {
+        name: String,
+        metadata: Option<MetadataJSON>,
+    }
+
+    let grammar_json: GrammarMetadataJSON = serde_json::from_str(input)
+        .map_err(Error::wrap(|| "Invalid `metadata` in grammar.json"))?;
+    if let Some(metadata) = grammar_json.metadata {
+        let mut result = Map::new();
+        result.insert("name".to_string(), Value::String(grammar_json.name));
+        if let Value::Object(metadata) = serde_json::to_value(metadata)? {
+            result.extend(metadata);
+        }
+        let mut result = serde_json::to_string_pretty(&result)?;
+        result.push('\n');
+        Ok(Some(result))
+    } else {
+        Ok(None)
+    }
+}
+
+// Re-serialize a grammar.json in a canonical form, so that the file's contents
+// don't depend on the version of node that produced it. The order of the rules
+// is preserved, since the first rule is the start rule, but all other object keys
+// are written in a fixed order.
+pub(crate) fn normalize_grammar_json(input: &str) -> Result<String> {
+    let value: Value = serde_json::from_str(input)?;
+    let mut result = serde_json::to_string_pretty(&normalize_json_value(value, false))?;
+    result.push('\n');
+    Ok(result)
+}
+
+const GRAMMAR_KEY_ORDER: &'static [&'static str] = &[
+    "name",
+    "word",
+    "rules",
+    "extras",
+    "conflicts",
+    "externals",
+    "externalScanner",
+    "inline",
+    "supertypes",
+    "keywordExtractionExclude",
+    "recoveryTokens",
+    "metadata",
+];
+
+const RULE_KEY_ORDER: &'static [&'static str] =
+    &["type", "name", "value", "named", "members", "content"];
+
+fn normalize_json_value(value: Value, is_rule_map: bool) -> Value {
+    match value {
+        Value::Object(mut map) => {
+            let mut result = Map::new();
+            if is_rule_map {
+                for (key, value) in map {
+                    result.insert(key, normalize_json_value(value, false));
+                }
+            } else {
+                let key_order = if map.contains_key("rules") {
+                    GRAMMAR_KEY_ORDER
+                } else {
+                    RULE_KEY_ORDER
+                };
+                let mut keys = map.keys().cloned().collect::<Vec<_>>();
+                keys.sort_by_key(|key| {
+                    (
+                        key_order
+                            .iter()
+                            .position(|k| k == key)
+                            .unwrap_or(key_order.len()),
+                        key.clone(),
+                    )
+                });
+                for key in keys {
+                    let value = map.remove(&key).unwrap();
+                    let is_rule_map = key == "rules" && key_order == GRAMMAR_KEY_ORDER;
+                    result.insert(key, normalize_json_value(value, is_rule_map));
+                }
+            }
+            Value::Object(result)
+        }
+        Value::Array(values) => Value::Array(
+            values
+                .into_iter()
+                .map(|value| normalize_json_value(value, false))
+                .collect(),
+        ),
+        Value::Number(number) => match number.as_f64() {
+            Some(float)
+                if !number.is_i64()
+                    && !number.is_u64()
+                    && float.fract() == 0.0
+                    && float.abs() < i64::MAX as f64 =>
+            {
+                Value::from(float as i64)
+            }
+            _ => Value::Number(number),
+        },
+        value => value,
+    }
+}
+
+// Detect grammar.json files that were produced by an older or forked version of
+// the CLI, so that they can be reported with an actionable message instead of a
+// deserialization error. Unknown top-level fields are returned as warnings.
+fn check_grammar_json(grammar_json: &Value) -> Result<Vec<String>> {
+    let grammar_json = match grammar_json.as_object() {
+        Some(object) => object,
+        None => return Error::err("A grammar.json file must contain a JSON object".to_string()),
+    };
+
+    if grammar_json.contains_key("ubiquitous") && !grammar_json.contains_key("extras") {
+        return Error::err(format!(
+            "{}: the `ubiquitous` field was renamed to `extras`. Regenerate it from grammar.js, or rename the field.",
+            INCOMPATIBLE_GRAMMAR_MESSAGE
+        ));
+    }
+
+    for field in &["name", "rules"] {
+        if !grammar_json.contains_key(*field) {
+            return Error::err(format!(
+                "{}: the required field `{}` is missing. Regenerate it from grammar.js, or add the field.",
+                INCOMPATIBLE_GRAMMAR_MESSAGE, field
+            ));
+        }
+    }
+
+    if let Some(conflicts) = grammar_json.get("conflicts").and_then(Value::as_array) {
+        if let Some(conflict) = conflicts.iter().find(|c| !c.is_array()) {
+            return Error::err(format!(
+                "{}: each entry in `conflicts` must be a list of rule names, but found {}. Regenerate it from grammar.js, or wrap each entry in a list.",
+                INCOMPATIBLE_GRAMMAR_MESSAGE, conflict
+            ));
+        }
+    }
+
+    if let Some(rules) = grammar_json.get("rules").and_then(Value::as_object) {
+        for (name, rule) in rules {
+            check_rule_json(name, rule)?;
+        }
+    }
+    for field in &[
+        "extras",
+        "externals",
+        "keywordExtractionExclude",
+        "recoveryTokens",
+    ] {
+        if let Some(rules) = grammar_json.get(*field).and_then(Value::as_array) {
+            for rule in rules {
+                check_rule_json(field, rule)?;
+            }
+        }
+    }
+
+    let unknown_fields = grammar_json
+        .keys()
+        .filter(|key| !GRAMMAR_FIELDS.contains(&key.as_str()))
+        .map(|key| format!("`{}`", key))
+        .collect::<Vec<_>>();
+    if unknown_fields.is_empty() {
+        Ok(Vec::new())
+    } else {
+        Ok(vec![format!(
+            "Ignoring unknown fields in grammar.json: {}. They may have been added by a different version of tree-sitter.",
+            unknown_fields.join(", ")
+        )])
+    }
+}
+
+fn check_rule_json(rule_name: &str, rule: &Value) -> Result<()> {
+    let rule_type = rule.get("type").and_then(Value::as_str);
+    if let Some(rule_type) = rule_type {
+        if !RULE_TYPES.contains(&rule_type) {
+            return Error::err(format!(
+                "{}: `{}` contains a rule of unknown type `{}`. Regenerate it from grammar.js.",
+                INCOMPATIBLE_GRAMMAR_MESSAGE, rule_name, rule_type
+            ));
+        }
+    }
+    if let Some(content) = rule.get("content") {
+        check_rule_json(rule_name, content)?;
+    }
+    if let Some(members) = rule.get("members").and_then(Value::as_array) {
+        for member in members {
+            check_rule_json(rule_name, member)?;
+        }
+    }
+    Ok(())
+}
+
+// Names that start with the auxiliary prefix are reserved for the rules that are
+// synthesized while preparing the grammar.
+fn check_rule_name(name: &str) -> Result<()> {
+    if name.starts_with(AUXILIARY_NAME_PREFIX) {
+        return Error::err(format!(
+            "Rule `{}` starts with `{}`, which is reserved for the names of auxiliary rules that Tree-sitter generates. Rename the rule.",
+            name, AUXILIARY_NAME_PREFIX
+        ));
+    }
+    Ok(())
+}
+
 fn parse_rule(json: RuleJSON) -> Rule {
     match json {
         RuleJSON::ALIAS {
@@ -167,6 +505,7 @@ mod tests {
                 }
             }
         }"#,
+            &mut Diagnostics::new(),
         )
         .unwrap();
 
@@ -187,4 +526,257 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_parse_grammar_with_recovery_tokens() {
+        let grammar = parse_grammar(
+            r#"{
+                "name": "my_lang",
+                "rules": {
+                    "file": {"type": "SYMBOL", "name": "semicolon"},
+                    "semicolon": {"type": "STRING", "value": ";"}
+                },
+                "recoveryTokens": [
+                    {"type": "SYMBOL", "name": "semicolon"},
+                    {"type": "STRING", "value": "}"}
+                ]
+            }"#,
+            &mut Diagnostics::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            grammar.recovery_tokens,
+            vec![
+                Rule::NamedSymbol("semicolon".to_string()),
+                Rule::String("}".to_string())
+            ]
+        );
+
+        let result = parse_grammar(
+            r#"{
+                "name": "my_lang",
+                "rules": {"file": {"type": "STRING", "value": ";"}},
+                "recoveryTokens": [
+                    {"type": "STRING", "value": ";"},
+                    {"type": "PATTERN", "value": "[;}]"}
+                ]
+            }"#,
+            &mut Diagnostics::new(),
+        );
+        assert_eq!(
+            result.unwrap_err().message(),
+            "Entry 1 in `recoveryTokens` must be a string or a reference to a token rule"
+        );
+    }
+
+    #[test]
+    fn test_parse_grammar_with_max_serialized_size() {
+        let grammar_json = |size: usize| {
+            format!(
+                r#"{{
+                    "name": "my_lang",
+                    "rules": {{"file": {{"type": "SYMBOL", "name": "heredoc"}}}},
+                    "externals": [{{"type": "SYMBOL", "name": "heredoc"}}],
+                    "externalScanner": {{"maxSerializedSize": {}}}
+                }}"#,
+                size
+            )
+        };
+
+        let grammar = parse_grammar(&grammar_json(256), &mut Diagnostics::new()).unwrap();
+        assert_eq!(grammar.max_serialized_size, Some(256));
+        let grammar = parse_grammar(&grammar_json(1024), &mut Diagnostics::new()).unwrap();
+        assert_eq!(grammar.max_serialized_size, Some(1024));
+
+        let result = parse_grammar(&grammar_json(2048), &mut Diagnostics::new());
+        assert_eq!(
+            result.unwrap_err().message(),
+            "`externalScanner.maxSerializedSize` is 2048, but it must be between 1 and 1024, the size of the runtime's serialization buffer (`TREE_SITTER_SERIALIZATION_BUFFER_SIZE`)"
+        );
+    }
+
+    #[test]
+    fn test_serialization_buffer_size_matches_parser_header() {
+        assert!(tree_sitter::PARSER_HEADER.contains(&format!(
+            "#define TREE_SITTER_SERIALIZATION_BUFFER_SIZE {}\n",
+            SERIALIZATION_BUFFER_SIZE
+        )));
+    }
+
+    #[test]
+    fn test_check_grammar_json_with_unknown_fields() {
+        let grammar_json = serde_json::from_str(
+            r#"{
+                "name": "my_lang",
+                "rules": {"file": {"type": "STRING", "value": "foo"}},
+                "precedences": [],
+                "reserved": {}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            check_grammar_json(&grammar_json).unwrap(),
+            vec![
+                "Ignoring unknown fields in grammar.json: `precedences`, `reserved`. They may have been added by a different version of tree-sitter."
+            ]
+        );
+    }
+
+    #[test]
+    fn test_grammar_key_order_includes_every_grammar_field() {
+        for field in GRAMMAR_FIELDS {
+            assert!(
+                GRAMMAR_KEY_ORDER.contains(field),
+                "`{}` has no position in the normalized grammar.json",
+                field
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_grammar_with_invalid_fields() {
+        let error = parse_grammar(
+            r#"{"name": "my_lang", "rules": {}, "inline": "foo"}"#,
+            &mut Diagnostics::new(),
+        )
+        .unwrap_err()
+        .message();
+        assert!(error.starts_with("Invalid grammar.json\nDetails:\n  invalid type: string"));
+
+        let error = parse_grammar(
+            r#"{"name": "my_lang", "rules": {"file": {"type": "STRING", "value": 1}}}"#,
+            &mut Diagnostics::new(),
+        )
+        .unwrap_err()
+        .message();
+        assert!(error
+            .starts_with("Invalid rule `file` in grammar.json\nDetails:\n  invalid type: integer"));
+    }
+
+    #[test]
+    fn test_parse_grammar_with_reserved_rule_names() {
+        let error = parse_grammar(
+            r#"{
+                "name": "my_lang",
+                "rules": {
+                    "program": {"type": "REPEAT", "content": {"type": "SYMBOL", "name": "__program_repeat1"}},
+                    "__program_repeat1": {"type": "STRING", "value": "x"}
+                }
+            }"#,
+            &mut Diagnostics::new(),
+        )
+        .unwrap_err()
+        .message();
+        assert_eq!(
+            error,
+            "Rule `__program_repeat1` starts with `__`, which is reserved for the names of auxiliary rules that Tree-sitter generates. Rename the rule."
+        );
+
+        let error = parse_grammar(
+            r#"{
+                "name": "my_lang",
+                "externals": [{"type": "SYMBOL", "name": "__indent"}],
+                "rules": {
+                    "program": {"type": "STRING", "value": "x"}
+                }
+            }"#,
+            &mut Diagnostics::new(),
+        )
+        .unwrap_err()
+        .message();
+        assert!(error.starts_with("Rule `__indent` starts with `__`"));
+    }
+
+    #[test]
+    fn test_generate_metadata_json() {
+        let metadata_json = generate_metadata_json(
+            r#"{
+                "name": "my_lang",
+                "rules": {"file": {"type": "STRING", "value": "foo"}},
+                "metadata": {
+                    "file-types": ["ml"],
+                    "scope": "source.my_lang",
+                    "highlights": ["queries/highlights.scm"]
+                }
+            }"#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            metadata_json,
+            r#"{
+  "name": "my_lang",
+  "scope": "source.my_lang",
+  "file-types": [
+    "ml"
+  ],
+  "highlights": [
+    "queries/highlights.scm"
+  ]
+}
+"#
+        );
+
+        let grammar_json = r#"{"name": "my_lang", "rules": {}}"#;
+        assert_eq!(generate_metadata_json(grammar_json).unwrap(), None);
+        assert!(
+            check_grammar_json(&serde_json::from_str(grammar_json).unwrap())
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_normalize_grammar_json() {
+        let grammar_json = r#"{
+            "rules": {
+                "file": {"content": {"name": "statement", "type": "SYMBOL"}, "type": "REPEAT1"},
+                "statement": {
+                    "content": {"value": "foo", "type": "STRING"},
+                    "value": 1.0,
+                    "type": "PREC"
+                },
+                "comment": {"value": "//.*", "type": "PATTERN"}
+            },
+            "extras": [{"type": "SYMBOL", "name": "comment"}],
+            "name": "my_lang"
+        }"#;
+
+        let normalized_json = normalize_grammar_json(grammar_json).unwrap();
+        let grammar = parse_grammar(&normalized_json, &mut Diagnostics::new()).unwrap();
+        assert_eq!(
+            grammar,
+            parse_grammar(&grammar_json.replace("1.0", "1"), &mut Diagnostics::new()).unwrap()
+        );
+        assert_eq!(
+            grammar
+                .variables
+                .iter()
+                .map(|v| v.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["file", "statement", "comment"]
+        );
+        assert!(normalized_json.starts_with("{\n  \"name\": \"my_lang\",\n  \"rules\""));
+        assert!(normalized_json.contains("\"value\": 1,"));
+    }
+
+    #[test]
+    fn test_normalize_grammar_json_is_stable() {
+        let grammar_json_1 = r#"{
+            "name": "my_lang",
+            "rules": {
+                "file": {"type": "REPEAT", "content": {"type": "SYMBOL", "name": "item"}},
+                "item": {"type": "PREC_LEFT", "value": 2, "content": {"type": "STRING", "value": "x"}}
+            }
+        }"#;
+        let grammar_json_2 = r#"{"rules":{"file":{"content":{"name":"item","type":"SYMBOL"},"type":"REPEAT"},"item":{"content":{"value":"x","type":"STRING"},"value":2.0,"type":"PREC_LEFT"}},"name":"my_lang"}"#;
+
+        let normalized_json_1 = normalize_grammar_json(grammar_json_1).unwrap();
+        let normalized_json_2 = normalize_grammar_json(grammar_json_2).unwrap();
+        assert_eq!(normalized_json_1, normalized_json_2);
+        assert_eq!(
+            normalize_grammar_json(&normalized_json_1).unwrap(),
+            normalized_json_1
+        );
+    }
 }
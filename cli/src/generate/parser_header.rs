@@ -0,0 +1,149 @@
+use super::render::STABLE_LANGUAGE_VERSION;
+use crate::error::{Error, Result};
+
+// Produce the contents of `tree_sitter/parser.h` for the selected ABI.
+//
+// The parts of the header that only apply to the next ABI are enclosed in a line with a
+// `// begin next-abi` comment and a line with an `// end next-abi` comment. These lines
+// are always removed, and the lines between them are removed for the stable ABI.
+pub(crate) fn parser_header(next_abi: bool) -> Result<String> {
+    render_header(tree_sitter::PARSER_HEADER, next_abi)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Marker {
+    Begin,
+    End,
+}
+
+fn render_header(template: &str, next_abi: bool) -> Result<String> {
+    let version = if next_abi {
+        tree_sitter::LANGUAGE_VERSION
+    } else {
+        STABLE_LANGUAGE_VERSION
+    };
+    let error = |row: usize, message: &str| {
+        Error::err(format!(
+            "Failed to produce parser.h for language ABI version {}: {} on line {}",
+            version,
+            message,
+            row + 1
+        ))
+    };
+
+    let mut result = String::with_capacity(template.len());
+    let mut region_start = None;
+    let mut region_count = 0;
+    for (row, line) in template.split_inclusive('\n').enumerate() {
+        match (marker(line), region_start) {
+            (Some(Marker::Begin), None) => region_start = Some(row),
+            (Some(Marker::Begin), Some(_)) => {
+                return error(
+                    row,
+                    "`begin next-abi` is nested in another next-abi section",
+                )
+            }
+            (Some(Marker::End), Some(_)) => {
+                region_start = None;
+                region_count += 1;
+            }
+            (Some(Marker::End), None) => {
+                return error(row, "`end next-abi` has no matching `begin next-abi`")
+            }
+            (None, Some(_)) if !next_abi => {}
+            (None, _) => result += line,
+        }
+    }
+    if let Some(row) = region_start {
+        return error(row, "`begin next-abi` has no matching `end next-abi`");
+    }
+    if region_count == 0 {
+        return Error::err(format!(
+            "Failed to produce parser.h for language ABI version {}: it has no next-abi sections",
+            version
+        ));
+    }
+    Ok(result)
+}
+
+// Recognize a line that only contains a marker comment, regardless of its whitespace and
+// case.
+fn marker(line: &str) -> Option<Marker> {
+    let comment = line.trim().strip_prefix("//")?;
+    let mut words = comment.split_whitespace();
+    let marker = match words.next()? {
+        word if word.eq_ignore_ascii_case("begin") => Marker::Begin,
+        word if word.eq_ignore_ascii_case("end") => Marker::End,
+        _ => return None,
+    };
+    match (words.next(), words.next()) {
+        (Some(word), None) if word.eq_ignore_ascii_case("next-abi") => Some(marker),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parser_header_for_each_abi() {
+        let stable_header = parser_header(false).unwrap();
+        let next_header = parser_header(true).unwrap();
+        for header in &[&stable_header, &next_header] {
+            assert!(!header.contains("next-abi"));
+            assert!(header.contains("const char **field_names;\n"));
+            assert!(header.contains("#define STATE(id) id\n"));
+        }
+        assert!(!stable_header.contains("large_state_count"));
+        assert!(!stable_header.contains("SMALL_STATE"));
+        assert!(next_header.contains("  const TSSymbol *recovery_tokens;\n};\n"));
+        assert!(next_header.contains("#define SMALL_STATE(id) id - LARGE_STATE_COUNT\n"));
+    }
+
+    #[test]
+    fn test_parser_header_markers_ignore_whitespace_and_case() {
+        let template = "a\n  //begin   next-abi\r\nb\n\t// END Next-ABI  \nc\n// begin next-abi:\n";
+        assert_eq!(
+            render_header(template, false).unwrap(),
+            "a\nc\n// begin next-abi:\n"
+        );
+        assert_eq!(
+            render_header(template, true).unwrap(),
+            "a\nb\nc\n// begin next-abi:\n"
+        );
+    }
+
+    #[test]
+    fn test_parser_header_errors() {
+        let message = |template, next_abi| render_header(template, next_abi).unwrap_err().message();
+        assert_eq!(
+            message("a\n// begin next-abi\nb\n", false),
+            format!(
+                "Failed to produce parser.h for language ABI version {}: `begin next-abi` has no matching `end next-abi` on line 2",
+                STABLE_LANGUAGE_VERSION
+            )
+        );
+        assert_eq!(
+            message("// begin next-abi\n// begin next-abi\n", true),
+            format!(
+                "Failed to produce parser.h for language ABI version {}: `begin next-abi` is nested in another next-abi section on line 2",
+                tree_sitter::LANGUAGE_VERSION
+            )
+        );
+        assert_eq!(
+            message("a\n// end next-abi\n", false),
+            format!(
+                "Failed to produce parser.h for language ABI version {}: `end next-abi` has no matching `begin next-abi` on line 2",
+                STABLE_LANGUAGE_VERSION
+            )
+        );
+        assert_eq!(
+            message("a\n", false),
+            format!(
+                "Failed to produce parser.h for language ABI version {}: it has no next-abi sections",
+                STABLE_LANGUAGE_VERSION
+            )
+        );
+    }
+}
@@ -1,7 +1,8 @@
 use super::ExtractedSyntaxGrammar;
-use crate::generate::grammars::{Variable, VariableType};
+use crate::error::{Error, Result};
+use crate::generate::grammars::{Variable, VariableType, AUXILIARY_NAME_PREFIX};
 use crate::generate::rules::{Rule, Symbol};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::mem;
 
 struct Expander {
@@ -10,10 +11,11 @@ struct Expander {
     preceding_symbol_count: usize,
     auxiliary_variables: Vec<Variable>,
     existing_repeats: HashMap<Rule, Symbol>,
+    existing_names: HashSet<String>,
 }
 
 impl Expander {
-    fn expand_variable(&mut self, index: usize, variable: &mut Variable) -> bool {
+    fn expand_variable(&mut self, index: usize, variable: &mut Variable) -> Result<bool> {
         self.variable_name.clear();
         self.variable_name.push_str(&variable.name);
         self.repeat_count_in_variable = 0;
@@ -24,53 +26,59 @@ impl Expander {
         // convert that rule itself into a binary tree structure instead of introducing
         // another auxiliary rule.
         if let (VariableType::Hidden, Rule::Repeat(repeated_content)) = (variable.kind, &rule) {
-            let inner_rule = self.expand_rule(&repeated_content);
+            let inner_rule = self.expand_rule(&repeated_content)?;
             variable.rule = self.wrap_rule_in_binary_tree(Symbol::non_terminal(index), inner_rule);
             variable.kind = VariableType::Auxiliary;
-            return true;
+            return Ok(true);
         }
 
-        variable.rule = self.expand_rule(&rule);
-        false
+        variable.rule = self.expand_rule(&rule)?;
+        Ok(false)
     }
 
-    fn expand_rule(&mut self, rule: &Rule) -> Rule {
+    fn expand_rule(&mut self, rule: &Rule) -> Result<Rule> {
         match rule {
             // For choices, sequences, and metadata, descend into the child rules,
             // replacing any nested repetitions.
-            Rule::Choice(elements) => Rule::Choice(
+            Rule::Choice(elements) => Ok(Rule::Choice(
                 elements
                     .iter()
                     .map(|element| self.expand_rule(element))
-                    .collect(),
-            ),
+                    .collect::<Result<_>>()?,
+            )),
 
-            Rule::Seq(elements) => Rule::Seq(
+            Rule::Seq(elements) => Ok(Rule::Seq(
                 elements
                     .iter()
                     .map(|element| self.expand_rule(element))
-                    .collect(),
-            ),
+                    .collect::<Result<_>>()?,
+            )),
 
-            Rule::Metadata { rule, params } => Rule::Metadata {
-                rule: Box::new(self.expand_rule(rule)),
+            Rule::Metadata { rule, params } => Ok(Rule::Metadata {
+                rule: Box::new(self.expand_rule(rule)?),
                 params: params.clone(),
-            },
+            }),
 
             // For repetitions, introduce an auxiliary rule that contains the the
             // repeated content, but can also contain a recursive binary tree structure.
             Rule::Repeat(content) => {
-                let inner_rule = self.expand_rule(content);
+                let inner_rule = self.expand_rule(content)?;
 
                 if let Some(existing_symbol) = self.existing_repeats.get(&inner_rule) {
-                    return Rule::Symbol(*existing_symbol);
+                    return Ok(Rule::Symbol(*existing_symbol));
                 }
 
                 self.repeat_count_in_variable += 1;
                 let rule_name = format!(
-                    "{}_repeat{}",
-                    self.variable_name, self.repeat_count_in_variable
+                    "{}{}_repeat{}",
+                    AUXILIARY_NAME_PREFIX, self.variable_name, self.repeat_count_in_variable
                 );
+                if !self.existing_names.insert(rule_name.clone()) {
+                    return Error::err(format!(
+                        "The auxiliary rule `{}`, which was synthesized for a repetition in `{}`, has the same name as another rule",
+                        rule_name, self.variable_name
+                    ));
+                }
                 let repeat_symbol = Symbol::non_terminal(
                     self.preceding_symbol_count + self.auxiliary_variables.len(),
                 );
@@ -82,11 +90,11 @@ impl Expander {
                     rule: self.wrap_rule_in_binary_tree(repeat_symbol, inner_rule),
                 });
 
-                Rule::Symbol(repeat_symbol)
+                Ok(Rule::Symbol(repeat_symbol))
             }
 
             // For primitive rules, don't change anything.
-            _ => rule.clone(),
+            _ => Ok(rule.clone()),
         }
     }
 
@@ -98,17 +106,24 @@ impl Expander {
     }
 }
 
-pub(super) fn expand_repeats(mut grammar: ExtractedSyntaxGrammar) -> ExtractedSyntaxGrammar {
+pub(super) fn expand_repeats(
+    mut grammar: ExtractedSyntaxGrammar,
+) -> Result<ExtractedSyntaxGrammar> {
     let mut expander = Expander {
         variable_name: String::new(),
         repeat_count_in_variable: 0,
         preceding_symbol_count: grammar.variables.len(),
         auxiliary_variables: Vec::new(),
         existing_repeats: HashMap::new(),
+        existing_names: grammar
+            .variables
+            .iter()
+            .map(|variable| variable.name.clone())
+            .collect(),
     };
 
     for (i, mut variable) in grammar.variables.iter_mut().enumerate() {
-        let expanded_top_level_repetition = expander.expand_variable(i, &mut variable);
+        let expanded_top_level_repetition = expander.expand_variable(i, &mut variable)?;
 
         // If a hidden variable had a top-level repetition and it was converted to
         // a recursive rule, then it can't be inlined.
@@ -122,7 +137,7 @@ pub(super) fn expand_repeats(mut grammar: ExtractedSyntaxGrammar) -> ExtractedSy
     grammar
         .variables
         .extend(expander.auxiliary_variables.into_iter());
-    grammar
+    Ok(grammar)
 }
 
 #[cfg(test)]
@@ -142,7 +157,8 @@ mod tests {
                 ]),
                 Rule::terminal(13),
             ]),
-        )]));
+        )]))
+        .unwrap();
 
         assert_eq!(
             grammar.variables,
@@ -156,14 +172,14 @@ mod tests {
                     ])
                 ),
                 Variable::auxiliary(
-                    "rule0_repeat1",
+                    "__rule0_repeat1",
                     Rule::choice(vec![
                         Rule::seq(vec![Rule::non_terminal(1), Rule::non_terminal(1),]),
                         Rule::terminal(11),
                     ])
                 ),
                 Variable::auxiliary(
-                    "rule0_repeat2",
+                    "__rule0_repeat2",
                     Rule::choice(vec![
                         Rule::seq(vec![Rule::non_terminal(2), Rule::non_terminal(2),]),
                         Rule::terminal(12),
@@ -188,7 +204,8 @@ mod tests {
                 "rule1",
                 Rule::seq(vec![Rule::terminal(3), Rule::repeat(Rule::terminal(4))]),
             ),
-        ]));
+        ]))
+        .unwrap();
 
         // Only one auxiliary rule is created for repeating terminal 4.
         assert_eq!(
@@ -206,7 +223,7 @@ mod tests {
                     Rule::seq(vec![Rule::terminal(3), Rule::non_terminal(2),])
                 ),
                 Variable::auxiliary(
-                    "rule0_repeat1",
+                    "__rule0_repeat1",
                     Rule::choice(vec![
                         Rule::seq(vec![Rule::non_terminal(2), Rule::non_terminal(2),]),
                         Rule::terminal(4),
@@ -227,7 +244,8 @@ mod tests {
                     Rule::repeat(Rule::terminal(12)),
                 ])),
             ]),
-        )]));
+        )]))
+        .unwrap();
 
         assert_eq!(
             grammar.variables,
@@ -237,14 +255,14 @@ mod tests {
                     Rule::seq(vec![Rule::terminal(10), Rule::non_terminal(2),])
                 ),
                 Variable::auxiliary(
-                    "rule0_repeat1",
+                    "__rule0_repeat1",
                     Rule::choice(vec![
                         Rule::seq(vec![Rule::non_terminal(1), Rule::non_terminal(1),]),
                         Rule::terminal(12),
                     ])
                 ),
                 Variable::auxiliary(
-                    "rule0_repeat2",
+                    "__rule0_repeat2",
                     Rule::choice(vec![
                         Rule::seq(vec![Rule::non_terminal(2), Rule::non_terminal(2),]),
                         Rule::seq(vec![Rule::terminal(11), Rule::non_terminal(1),]),
@@ -262,7 +280,8 @@ mod tests {
                 "_rule1",
                 Rule::repeat(Rule::choice(vec![Rule::terminal(11), Rule::terminal(12)])),
             ),
-        ]));
+        ]))
+        .unwrap();
 
         assert_eq!(
             grammar.variables,
@@ -280,6 +299,22 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_repeat_expansion_with_colliding_names() {
+        // Grammars can't normally contain rules whose names start with the auxiliary
+        // prefix, but if a synthesized name collides with an existing rule anyway, the
+        // error describes where the synthesized rule came from.
+        let result = expand_repeats(build_grammar(vec![
+            Variable::named("rule0", Rule::repeat(Rule::terminal(1))),
+            Variable::named("__rule0_repeat1", Rule::terminal(2)),
+        ]));
+
+        assert_eq!(
+            result.err().unwrap().message(),
+            "The auxiliary rule `__rule0_repeat1`, which was synthesized for a repetition in `rule0`, has the same name as another rule"
+        );
+    }
+
     fn build_grammar(variables: Vec<Variable>) -> ExtractedSyntaxGrammar {
         ExtractedSyntaxGrammar {
             variables,
@@ -289,6 +324,9 @@ mod tests {
             variables_to_inline: Vec::new(),
             supertype_symbols: Vec::new(),
             word_token: None,
+            excluded_keywords: Vec::new(),
+            recovery_tokens: Vec::new(),
+            max_serialized_size: None,
         }
     }
 }
@@ -91,6 +91,7 @@ pub(crate) fn expand_tokens(mut grammar: ExtractedLexicalGrammar) -> Result<Lexi
         };
 
         builder.is_sep = false;
+        let state_count = builder.nfa.states.len();
         builder.nfa.states.push(NfaState::Accept {
             variable_index: i,
             precedence: get_completion_precedence(&variable.rule),
@@ -101,6 +102,10 @@ pub(crate) fn expand_tokens(mut grammar: ExtractedLexicalGrammar) -> Result<Lexi
             .map_err(Error::wrap(|| {
                 format!("Error processing rule {}", variable.name)
             }))?;
+        builder
+            .nfa
+            .token_state_counts
+            .push(builder.nfa.states.len() - state_count);
 
         if !is_immediate_token {
             builder.is_sep = true;
@@ -152,6 +152,9 @@ mod tests {
             supertype_symbols: Vec::new(),
             external_tokens: Vec::new(),
             word_token: None,
+            excluded_keywords: Vec::new(),
+            recovery_tokens: Vec::new(),
+            max_serialized_size: None,
         };
 
         let lexical_grammar = LexicalGrammar {
@@ -1,8 +1,8 @@
 use super::{ExtractedLexicalGrammar, ExtractedSyntaxGrammar, InternedGrammar};
 use crate::error::{Error, Result};
-use crate::generate::grammars::{ExternalToken, Variable, VariableType};
+use crate::generate::grammars::{ExternalToken, Variable, VariableType, AUXILIARY_NAME_PREFIX};
 use crate::generate::rules::{MetadataParams, Rule, Symbol, SymbolType};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::mem;
 
 pub(super) fn extract_tokens(
@@ -13,6 +13,13 @@ pub(super) fn extract_tokens(
         current_variable_token_count: 0,
         extracted_variables: Vec::new(),
         extracted_usage_counts: Vec::new(),
+        existing_names: grammar
+            .variables
+            .iter()
+            .chain(grammar.external_tokens.iter())
+            .map(|variable| variable.name.clone())
+            .collect(),
+        name_collision: None,
     };
 
     for mut variable in grammar.variables.iter_mut() {
@@ -23,6 +30,13 @@ pub(super) fn extract_tokens(
         extractor.extract_tokens_in_variable(&mut variable);
     }
 
+    if let Some((token_name, variable_name)) = extractor.name_collision {
+        return Error::err(format!(
+            "The auxiliary token `{}`, which was synthesized for a token in `{}`, has the same name as another rule",
+            token_name, variable_name
+        ));
+    }
+
     let mut lexical_variables = Vec::with_capacity(extractor.extracted_variables.len());
     for variable in extractor.extracted_variables {
         lexical_variables.push(Variable {
@@ -77,17 +91,41 @@ pub(super) fn extract_tokens(
         })
         .collect();
 
-    let supertype_symbols = grammar
-        .supertype_symbols
-        .into_iter()
-        .map(|symbol| symbol_replacer.replace_symbol(symbol))
-        .collect();
+    let mut supertype_symbols = Vec::with_capacity(grammar.supertype_symbols.len());
+    for symbol in grammar.supertype_symbols {
+        let symbol = symbol_replacer.replace_symbol(symbol);
+        if symbol.is_external() {
+            return Error::err(format!(
+                "External token '{}' cannot be used as a supertype",
+                &grammar.external_tokens[symbol.index].name
+            ));
+        }
+        if symbol.is_terminal() {
+            return Error::err(format!(
+                "Token '{}' cannot be used as a supertype",
+                &lexical_variables[symbol.index].name
+            ));
+        }
+        supertype_symbols.push(symbol);
+    }
 
-    let variables_to_inline = grammar
-        .variables_to_inline
-        .into_iter()
-        .map(|symbol| symbol_replacer.replace_symbol(symbol))
-        .collect();
+    let mut variables_to_inline = Vec::with_capacity(grammar.variables_to_inline.len());
+    for symbol in grammar.variables_to_inline {
+        let symbol = symbol_replacer.replace_symbol(symbol);
+        if symbol.is_external() {
+            return Error::err(format!(
+                "External token '{}' cannot be inlined",
+                &grammar.external_tokens[symbol.index].name
+            ));
+        }
+        if symbol.is_terminal() {
+            return Error::err(format!(
+                "Token '{}' cannot be inlined",
+                &lexical_variables[symbol.index].name
+            ));
+        }
+        variables_to_inline.push(symbol);
+    }
 
     let mut separators = Vec::new();
     let mut extra_symbols = Vec::new();
@@ -104,7 +142,7 @@ pub(super) fn extract_tokens(
     }
 
     let mut external_tokens = Vec::new();
-    for external_token in grammar.external_tokens {
+    for (i, external_token) in grammar.external_tokens.into_iter().enumerate() {
         let rule = symbol_replacer.replace_symbols_in_rule(&external_token.rule);
         if let Rule::Symbol(symbol) = rule {
             if symbol.is_non_terminal() {
@@ -129,7 +167,8 @@ pub(super) fn extract_tokens(
             }
         } else {
             return Error::err(format!(
-                "Non-symbol rules cannot be used as external tokens"
+                "Non-symbol rules cannot be used as external tokens (entry {} in `externals`)",
+                i
             ));
         }
     }
@@ -146,6 +185,50 @@ pub(super) fn extract_tokens(
         word_token = Some(token);
     }
 
+    let mut excluded_keywords = Vec::new();
+    for (i, rule) in grammar.excluded_keywords.into_iter().enumerate() {
+        let symbol = if let Rule::Symbol(symbol) = rule {
+            symbol_replacer.replace_symbol(symbol)
+        } else if let Some(index) = lexical_variables.iter().position(|v| v.rule == rule) {
+            Symbol::terminal(index)
+        } else {
+            return Error::err(format!(
+                "Entry {} in `keywordExtractionExclude` does not match any token in the grammar",
+                i
+            ));
+        };
+        if !symbol.is_terminal() {
+            return Error::err(format!(
+                "Only tokens can be excluded from keyword extraction (entry {} in `keywordExtractionExclude`)",
+                i
+            ));
+        }
+        excluded_keywords.push(symbol);
+    }
+
+    let mut recovery_tokens = Vec::new();
+    for (i, rule) in grammar.recovery_tokens.into_iter().enumerate() {
+        let symbol = if let Rule::Symbol(symbol) = rule {
+            symbol_replacer.replace_symbol(symbol)
+        } else if let Some(index) = lexical_variables.iter().position(|v| v.rule == rule) {
+            Symbol::terminal(index)
+        } else {
+            return Error::err(format!(
+                "Entry {} in `recoveryTokens` does not match any token in the grammar",
+                i
+            ));
+        };
+        if symbol.is_non_terminal() {
+            return Error::err(format!(
+                "Only tokens can be used as recovery tokens (entry {} in `recoveryTokens`)",
+                i
+            ));
+        }
+        if !recovery_tokens.contains(&symbol) {
+            recovery_tokens.push(symbol);
+        }
+    }
+
     Ok((
         ExtractedSyntaxGrammar {
             variables,
@@ -155,6 +238,9 @@ pub(super) fn extract_tokens(
             supertype_symbols,
             external_tokens,
             word_token,
+            excluded_keywords,
+            recovery_tokens,
+            max_serialized_size: grammar.max_serialized_size,
         },
         ExtractedLexicalGrammar {
             variables: lexical_variables,
@@ -168,6 +254,8 @@ struct TokenExtractor {
     current_variable_token_count: usize,
     extracted_variables: Vec<Variable>,
     extracted_usage_counts: Vec<usize>,
+    existing_names: HashSet<String>,
+    name_collision: Option<(String, String)>,
 }
 
 struct SymbolReplacer {
@@ -246,11 +334,17 @@ impl TokenExtractor {
             }
         } else {
             self.current_variable_token_count += 1;
+            let name = format!(
+                "{}{}_token{}",
+                AUXILIARY_NAME_PREFIX,
+                &self.current_variable_name,
+                self.current_variable_token_count
+            );
+            if self.existing_names.contains(&name) && self.name_collision.is_none() {
+                self.name_collision = Some((name.clone(), self.current_variable_name.clone()));
+            }
             Variable {
-                name: format!(
-                    "{}_token{}",
-                    &self.current_variable_name, self.current_variable_token_count
-                ),
+                name,
                 kind: VariableType::Auxiliary,
                 rule: rule.clone(),
             }
@@ -377,9 +471,9 @@ mod test {
             lexical_grammar.variables,
             vec![
                 Variable::anonymous("a", Rule::string("a")),
-                Variable::auxiliary("rule_0_token1", Rule::pattern("b")),
+                Variable::auxiliary("__rule_0_token1", Rule::pattern("b")),
                 Variable::auxiliary(
-                    "rule_0_token2",
+                    "__rule_0_token2",
                     Rule::repeat(Rule::choice(vec![Rule::string("c"), Rule::string("d"),]))
                 ),
                 Variable::named("rule_1", Rule::pattern("e")),
@@ -489,6 +583,125 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_error_on_non_symbol_external() {
+        let mut grammar = build_grammar(vec![Variable::named(
+            "rule_0",
+            Rule::seq(vec![Rule::external(0), Rule::string("a")]),
+        )]);
+        grammar.external_tokens = vec![
+            Variable::named("external_0", Rule::external(0)),
+            Variable::anonymous("", Rule::Blank),
+        ];
+
+        match extract_tokens(grammar) {
+            Err(e) => {
+                assert_eq!(
+                    e.message(),
+                    "Non-symbol rules cannot be used as external tokens (entry 1 in `externals`)"
+                );
+            }
+            _ => {
+                panic!("Expected an error but got no error");
+            }
+        }
+    }
+
+    #[test]
+    fn test_extracting_excluded_keywords() {
+        let mut grammar = build_grammar(vec![
+            Variable::named(
+                "rule_0",
+                Rule::seq(vec![
+                    Rule::string("if"),
+                    Rule::non_terminal(1),
+                    Rule::string("await"),
+                ]),
+            ),
+            Variable::named("rule_1", Rule::pattern("[a-z]+")),
+        ]);
+        grammar.excluded_keywords = vec![Rule::string("await")];
+
+        let (syntax_grammar, lexical_grammar) = extract_tokens(grammar).unwrap();
+        let await_index = lexical_grammar
+            .variables
+            .iter()
+            .position(|v| v.rule == Rule::string("await"))
+            .unwrap();
+        assert_eq!(
+            syntax_grammar.excluded_keywords,
+            vec![Symbol::terminal(await_index)]
+        );
+
+        let mut grammar = build_grammar(vec![Variable::named("rule_0", Rule::string("if"))]);
+        grammar.excluded_keywords = vec![Rule::string("await")];
+        match extract_tokens(grammar) {
+            Err(e) => assert_eq!(
+                e.message(),
+                "Entry 0 in `keywordExtractionExclude` does not match any token in the grammar"
+            ),
+            _ => panic!("Expected an error but got no error"),
+        }
+    }
+
+    #[test]
+    fn test_extracting_recovery_tokens() {
+        let mut grammar = build_grammar(vec![
+            Variable::named(
+                "rule_0",
+                Rule::seq(vec![
+                    Rule::non_terminal(1),
+                    Rule::string(";"),
+                    Rule::non_terminal(2),
+                ]),
+            ),
+            Variable::named("rule_1", Rule::pattern("[a-z]+")),
+            Variable::named(
+                "rule_2",
+                Rule::seq(vec![Rule::string("{"), Rule::string("}")]),
+            ),
+        ]);
+        grammar.recovery_tokens = vec![
+            Rule::string("}"),
+            Rule::non_terminal(1),
+            Rule::string("}"),
+            Rule::external(0),
+        ];
+
+        let (syntax_grammar, lexical_grammar) = extract_tokens(grammar).unwrap();
+        let token_index = |rule| {
+            lexical_grammar
+                .variables
+                .iter()
+                .position(|v| v.rule == rule)
+                .unwrap()
+        };
+        assert_eq!(
+            syntax_grammar.recovery_tokens,
+            vec![
+                Symbol::terminal(token_index(Rule::string("}"))),
+                Symbol::terminal(token_index(Rule::pattern("[a-z]+"))),
+                Symbol::external(0),
+            ]
+        );
+
+        let mut grammar = build_grammar(vec![
+            Variable::named("rule_0", Rule::non_terminal(1)),
+            Variable::named(
+                "rule_1",
+                Rule::seq(vec![Rule::string("a"), Rule::string("b")]),
+            ),
+        ]);
+        grammar.recovery_tokens = vec![Rule::non_terminal(1)];
+        match extract_tokens(grammar) {
+            Err(e) => assert_eq!(
+                e.message(),
+                "Only tokens can be used as recovery tokens (entry 0 in `recoveryTokens`)"
+            ),
+            _ => panic!("Expected an error but got no error"),
+        }
+    }
+
     fn build_grammar(variables: Vec<Variable>) -> InternedGrammar {
         InternedGrammar {
             variables,
@@ -498,6 +711,9 @@ mod test {
             variables_to_inline: Vec::new(),
             supertype_symbols: Vec::new(),
             word_token: None,
+            excluded_keywords: Vec::new(),
+            recovery_tokens: Vec::new(),
+            max_serialized_size: None,
         }
     }
 }
@@ -1,10 +1,11 @@
-use super::ExtractedSyntaxGrammar;
+use super::{ExtractedSyntaxGrammar, ProductionLimits};
 use crate::error::{Error, Result};
 use crate::generate::grammars::{
     Production, ProductionStep, SyntaxGrammar, SyntaxVariable, Variable,
 };
 use crate::generate::rules::Symbol;
 use crate::generate::rules::{Alias, Associativity, Rule};
+use std::collections::HashSet;
 
 struct RuleFlattener {
     production: Production,
@@ -107,6 +108,7 @@ impl RuleFlattener {
                     associativity: self.associativity_stack.last().cloned(),
                     alias: self.alias_stack.last().cloned(),
                     field_name: self.field_name_stack.last().cloned(),
+                    inner_field_names: Vec::new(),
                 });
                 true
             }
@@ -115,12 +117,18 @@ impl RuleFlattener {
     }
 }
 
-fn extract_choices(rule: Rule) -> Vec<Rule> {
-    match rule {
+// Expand the rule into a list of alternatives that contain no choices. Because each
+// sequence expands into the product of its members' alternatives, this can produce a
+// huge number of alternatives, so return `None` if there would be more than `max_count`.
+fn extract_choices(rule: Rule, max_count: usize) -> Option<Vec<Rule>> {
+    Some(match rule {
         Rule::Seq(elements) => {
             let mut result = vec![Rule::Blank];
             for element in elements {
-                let extraction = extract_choices(element);
+                let extraction = extract_choices(element, max_count)?;
+                if result.len().saturating_mul(extraction.len()) > max_count {
+                    return None;
+                }
                 let mut next_result = Vec::new();
                 for entry in result {
                     for extraction_entry in extraction.iter() {
@@ -134,13 +142,16 @@ fn extract_choices(rule: Rule) -> Vec<Rule> {
         Rule::Choice(elements) => {
             let mut result = Vec::new();
             for element in elements {
-                for rule in extract_choices(element) {
+                for rule in extract_choices(element, max_count)? {
                     result.push(rule);
                 }
+                if result.len() > max_count {
+                    return None;
+                }
             }
             result
         }
-        Rule::Metadata { rule, params } => extract_choices(*rule)
+        Rule::Metadata { rule, params } => extract_choices(*rule, max_count)?
             .into_iter()
             .map(|rule| Rule::Metadata {
                 rule: Box::new(rule),
@@ -148,19 +159,35 @@ fn extract_choices(rule: Rule) -> Vec<Rule> {
             })
             .collect(),
         _ => vec![rule],
-    }
+    })
 }
 
-fn flatten_variable(variable: Variable) -> Result<SyntaxVariable> {
+fn flatten_variable(variable: Variable, limits: &ProductionLimits) -> Result<SyntaxVariable> {
+    let name = variable.name;
+    let rules =
+        extract_choices(variable.rule, limits.max_productions_per_rule).ok_or_else(|| {
+            Error::new(format!(
+                "The rule `{}` expands into more than {} productions.
+
+Each `seq` in a rule is expanded into one production for every combination of
+the alternatives of its members, so nesting many `choice` and `optional` rules
+inside of one another can cause the number of productions to explode. Consider
+moving some of the alternatives into separate rules. The limit can be raised
+using the `--max-rule-productions` option.
+",
+                name, limits.max_productions_per_rule
+            ))
+        })?;
     let mut productions = Vec::new();
-    for rule in extract_choices(variable.rule) {
+    let mut production_set = HashSet::new();
+    for rule in rules {
         let production = RuleFlattener::new().flatten(rule);
-        if !productions.contains(&production) {
+        if production_set.insert(production.clone()) {
             productions.push(production);
         }
     }
     Ok(SyntaxVariable {
-        name: variable.name,
+        name,
         kind: variable.kind,
         productions,
     })
@@ -179,14 +206,57 @@ fn symbol_is_used(variables: &Vec<SyntaxVariable>, symbol: Symbol) -> bool {
     false
 }
 
-pub(super) fn flatten_grammar(grammar: ExtractedSyntaxGrammar) -> Result<SyntaxGrammar> {
+fn inlined_symbol_is_recursive(
+    variables: &Vec<SyntaxVariable>,
+    variables_to_inline: &Vec<Symbol>,
+    symbol: Symbol,
+) -> bool {
+    let mut stack = vec![symbol];
+    let mut visited = HashSet::new();
+    while let Some(current) = stack.pop() {
+        for production in &variables[current.index].productions {
+            for step in &production.steps {
+                if variables_to_inline.contains(&step.symbol) {
+                    if step.symbol == symbol {
+                        return true;
+                    }
+                    if visited.insert(step.symbol) {
+                        stack.push(step.symbol);
+                    }
+                }
+            }
+        }
+    }
+    false
+}
+
+pub(super) fn flatten_grammar(
+    grammar: ExtractedSyntaxGrammar,
+    limits: &ProductionLimits,
+) -> Result<SyntaxGrammar> {
     let mut variables = Vec::new();
+    let mut production_count = 0;
     for variable in grammar.variables {
-        variables.push(flatten_variable(variable)?);
+        let variable = flatten_variable(variable, limits)?;
+        production_count += variable.productions.len();
+        if production_count > limits.max_productions {
+            return Error::err(format!(
+                "The grammar expands into more than {} productions, after expanding the rule `{}`.
+
+Consider moving some of the alternatives of the largest rules into separate rules.
+The limit can be raised using the `--max-productions` option.
+",
+                limits.max_productions, variable.name
+            ));
+        }
+        variables.push(variable);
     }
     for (i, variable) in variables.iter().enumerate() {
         for production in &variable.productions {
-            if production.steps.is_empty() && symbol_is_used(&variables, Symbol::non_terminal(i)) {
+            let symbol = Symbol::non_terminal(i);
+            if production.steps.is_empty()
+                && (symbol_is_used(&variables, symbol) || grammar.extra_symbols.contains(&symbol))
+            {
                 return Error::err(format!(
                     "The rule `{}` matches the empty string.
 
@@ -198,6 +268,14 @@ unless they are used only as the grammar's start rule.
             }
         }
     }
+    for symbol in &grammar.variables_to_inline {
+        if inlined_symbol_is_recursive(&variables, &grammar.variables_to_inline, *symbol) {
+            return Error::err(format!(
+                "Rule `{}` cannot be inlined because it contains a reference to itself.",
+                variables[symbol.index].name
+            ));
+        }
+    }
     Ok(SyntaxGrammar {
         extra_symbols: grammar.extra_symbols,
         expected_conflicts: grammar.expected_conflicts,
@@ -205,6 +283,9 @@ unless they are used only as the grammar's start rule.
         external_tokens: grammar.external_tokens,
         supertype_symbols: grammar.supertype_symbols,
         word_token: grammar.word_token,
+        excluded_keywords: grammar.excluded_keywords,
+        recovery_tokens: grammar.recovery_tokens,
+        max_serialized_size: grammar.max_serialized_size,
         variables,
     })
 }
@@ -217,28 +298,31 @@ mod tests {
 
     #[test]
     fn test_flatten_grammar() {
-        let result = flatten_variable(Variable {
-            name: "test".to_string(),
-            kind: VariableType::Named,
-            rule: Rule::seq(vec![
-                Rule::non_terminal(1),
-                Rule::prec_left(
-                    101,
-                    Rule::seq(vec![
-                        Rule::non_terminal(2),
-                        Rule::choice(vec![
-                            Rule::prec_right(
-                                102,
-                                Rule::seq(vec![Rule::non_terminal(3), Rule::non_terminal(4)]),
-                            ),
-                            Rule::non_terminal(5),
+        let result = flatten_variable(
+            Variable {
+                name: "test".to_string(),
+                kind: VariableType::Named,
+                rule: Rule::seq(vec![
+                    Rule::non_terminal(1),
+                    Rule::prec_left(
+                        101,
+                        Rule::seq(vec![
+                            Rule::non_terminal(2),
+                            Rule::choice(vec![
+                                Rule::prec_right(
+                                    102,
+                                    Rule::seq(vec![Rule::non_terminal(3), Rule::non_terminal(4)]),
+                                ),
+                                Rule::non_terminal(5),
+                            ]),
+                            Rule::non_terminal(6),
                         ]),
-                        Rule::non_terminal(6),
-                    ]),
-                ),
-                Rule::non_terminal(7),
-            ]),
-        })
+                    ),
+                    Rule::non_terminal(7),
+                ]),
+            },
+            &ProductionLimits::default(),
+        )
         .unwrap();
 
         assert_eq!(
@@ -276,28 +360,31 @@ mod tests {
 
     #[test]
     fn test_flatten_grammar_with_maximum_dynamic_precedence() {
-        let result = flatten_variable(Variable {
-            name: "test".to_string(),
-            kind: VariableType::Named,
-            rule: Rule::seq(vec![
-                Rule::non_terminal(1),
-                Rule::prec_dynamic(
-                    101,
-                    Rule::seq(vec![
-                        Rule::non_terminal(2),
-                        Rule::choice(vec![
-                            Rule::prec_dynamic(
-                                102,
-                                Rule::seq(vec![Rule::non_terminal(3), Rule::non_terminal(4)]),
-                            ),
-                            Rule::non_terminal(5),
+        let result = flatten_variable(
+            Variable {
+                name: "test".to_string(),
+                kind: VariableType::Named,
+                rule: Rule::seq(vec![
+                    Rule::non_terminal(1),
+                    Rule::prec_dynamic(
+                        101,
+                        Rule::seq(vec![
+                            Rule::non_terminal(2),
+                            Rule::choice(vec![
+                                Rule::prec_dynamic(
+                                    102,
+                                    Rule::seq(vec![Rule::non_terminal(3), Rule::non_terminal(4)]),
+                                ),
+                                Rule::non_terminal(5),
+                            ]),
+                            Rule::non_terminal(6),
                         ]),
-                        Rule::non_terminal(6),
-                    ]),
-                ),
-                Rule::non_terminal(7),
-            ]),
-        })
+                    ),
+                    Rule::non_terminal(7),
+                ]),
+            },
+            &ProductionLimits::default(),
+        )
         .unwrap();
 
         assert_eq!(
@@ -330,14 +417,17 @@ mod tests {
 
     #[test]
     fn test_flatten_grammar_with_final_precedence() {
-        let result = flatten_variable(Variable {
-            name: "test".to_string(),
-            kind: VariableType::Named,
-            rule: Rule::prec_left(
-                101,
-                Rule::seq(vec![Rule::non_terminal(1), Rule::non_terminal(2)]),
-            ),
-        })
+        let result = flatten_variable(
+            Variable {
+                name: "test".to_string(),
+                kind: VariableType::Named,
+                rule: Rule::prec_left(
+                    101,
+                    Rule::seq(vec![Rule::non_terminal(1), Rule::non_terminal(2)]),
+                ),
+            },
+            &ProductionLimits::default(),
+        )
         .unwrap();
 
         assert_eq!(
@@ -353,11 +443,14 @@ mod tests {
             }]
         );
 
-        let result = flatten_variable(Variable {
-            name: "test".to_string(),
-            kind: VariableType::Named,
-            rule: Rule::prec_left(101, Rule::seq(vec![Rule::non_terminal(1)])),
-        })
+        let result = flatten_variable(
+            Variable {
+                name: "test".to_string(),
+                kind: VariableType::Named,
+                rule: Rule::prec_left(101, Rule::seq(vec![Rule::non_terminal(1)])),
+            },
+            &ProductionLimits::default(),
+        )
         .unwrap();
 
         assert_eq!(
@@ -372,18 +465,21 @@ mod tests {
 
     #[test]
     fn test_flatten_grammar_with_field_names() {
-        let result = flatten_variable(Variable {
-            name: "test".to_string(),
-            kind: VariableType::Named,
-            rule: Rule::seq(vec![
-                Rule::field("first-thing".to_string(), Rule::terminal(1)),
-                Rule::terminal(2),
-                Rule::choice(vec![
-                    Rule::Blank,
-                    Rule::field("second-thing".to_string(), Rule::terminal(3)),
+        let result = flatten_variable(
+            Variable {
+                name: "test".to_string(),
+                kind: VariableType::Named,
+                rule: Rule::seq(vec![
+                    Rule::field("first-thing".to_string(), Rule::terminal(1)),
+                    Rule::terminal(2),
+                    Rule::choice(vec![
+                        Rule::Blank,
+                        Rule::field("second-thing".to_string(), Rule::terminal(3)),
+                    ]),
                 ]),
-            ]),
-        })
+            },
+            &ProductionLimits::default(),
+        )
         .unwrap();
 
         assert_eq!(
@@ -407,4 +503,100 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_flatten_grammar_with_too_many_productions() {
+        let exploding_rule = || {
+            Rule::seq(
+                (0..5)
+                    .map(|_| Rule::choice((0..10).map(Rule::terminal).collect()))
+                    .collect(),
+            )
+        };
+        let grammar = ExtractedSyntaxGrammar {
+            variables: vec![
+                Variable {
+                    name: "program".to_string(),
+                    kind: VariableType::Named,
+                    rule: Rule::non_terminal(1),
+                },
+                Variable {
+                    name: "exploding_rule".to_string(),
+                    kind: VariableType::Named,
+                    rule: exploding_rule(),
+                },
+            ],
+            extra_symbols: Vec::new(),
+            expected_conflicts: Vec::new(),
+            variables_to_inline: Vec::new(),
+            external_tokens: Vec::new(),
+            supertype_symbols: Vec::new(),
+            word_token: None,
+            excluded_keywords: Vec::new(),
+            recovery_tokens: Vec::new(),
+            max_serialized_size: None,
+        };
+
+        // The rule expands into 10^5 productions, which is above the default limit.
+        let message = flatten_grammar(grammar, &ProductionLimits::default())
+            .err()
+            .unwrap()
+            .message();
+        assert!(
+            message
+                .starts_with("The rule `exploding_rule` expands into more than 10000 productions."),
+            "unexpected message: {}",
+            message
+        );
+
+        // With a higher limit, the expansion succeeds.
+        let limits = ProductionLimits {
+            max_productions_per_rule: 100_000,
+            max_productions: 200_000,
+        };
+        let result = flatten_variable(
+            Variable {
+                name: "exploding_rule".to_string(),
+                kind: VariableType::Named,
+                rule: exploding_rule(),
+            },
+            &limits,
+        )
+        .unwrap();
+        assert_eq!(result.productions.len(), 100_000);
+    }
+
+    #[test]
+    fn test_flatten_grammar_with_too_many_productions_in_total() {
+        let grammar = ExtractedSyntaxGrammar {
+            variables: (0..3)
+                .map(|i| Variable {
+                    name: format!("rule_{}", i),
+                    kind: VariableType::Named,
+                    rule: Rule::choice((0..4).map(Rule::terminal).collect()),
+                })
+                .collect(),
+            extra_symbols: Vec::new(),
+            expected_conflicts: Vec::new(),
+            variables_to_inline: Vec::new(),
+            external_tokens: Vec::new(),
+            supertype_symbols: Vec::new(),
+            word_token: None,
+            excluded_keywords: Vec::new(),
+            recovery_tokens: Vec::new(),
+            max_serialized_size: None,
+        };
+        let limits = ProductionLimits {
+            max_productions_per_rule: 5,
+            max_productions: 10,
+        };
+        let message = flatten_grammar(grammar, &limits).err().unwrap().message();
+        assert!(
+            message.starts_with(
+                "The grammar expands into more than 10 productions, after expanding the rule `rule_2`."
+            ),
+            "unexpected message: {}",
+            message
+        );
+    }
 }
@@ -6,6 +6,10 @@ use crate::generate::rules::{Rule, Symbol};
 pub(super) fn intern_symbols(grammar: &InputGrammar) -> Result<InternedGrammar> {
     let interner = Interner { grammar };
 
+    if grammar.variables.is_empty() {
+        return Error::err("A grammar must define at least one rule.".to_string());
+    }
+
     if variable_type_for_name(&grammar.variables[0].name) == VariableType::Hidden {
         return Error::err("A grammar's start rule must be visible.".to_string());
     }
@@ -23,6 +27,12 @@ pub(super) fn intern_symbols(grammar: &InputGrammar) -> Result<InternedGrammar>
     for external_token in grammar.external_tokens.iter() {
         let rule = interner.intern_rule(&external_token)?;
         let (name, kind) = if let Rule::NamedSymbol(name) = external_token {
+            if external_tokens.iter().any(|t: &Variable| t.name == *name) {
+                return Err(Error::grammar(&format!(
+                    "External token `{}` is listed more than once in `externals`",
+                    name
+                )));
+            }
             (name.clone(), variable_type_for_name(&name))
         } else {
             (String::new(), VariableType::Anonymous)
@@ -73,6 +83,16 @@ pub(super) fn intern_symbols(grammar: &InputGrammar) -> Result<InternedGrammar>
         );
     }
 
+    let mut excluded_keywords = Vec::with_capacity(grammar.excluded_keywords.len());
+    for excluded_keyword in grammar.excluded_keywords.iter() {
+        excluded_keywords.push(interner.intern_rule(excluded_keyword)?);
+    }
+
+    let mut recovery_tokens = Vec::with_capacity(grammar.recovery_tokens.len());
+    for recovery_token in grammar.recovery_tokens.iter() {
+        recovery_tokens.push(interner.intern_rule(recovery_token)?);
+    }
+
     Ok(InternedGrammar {
         variables,
         external_tokens,
@@ -81,6 +101,9 @@ pub(super) fn intern_symbols(grammar: &InputGrammar) -> Result<InternedGrammar>
         variables_to_inline,
         supertype_symbols,
         word_token,
+        excluded_keywords,
+        recovery_tokens,
+        max_serialized_size: grammar.max_serialized_size,
     })
 }
 
@@ -232,6 +255,27 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_grammar_with_duplicate_external_tokens() {
+        let mut input_grammar = build_grammar(vec![Variable::named(
+            "x",
+            Rule::seq(vec![Rule::named("y"), Rule::named("z")]),
+        )]);
+        input_grammar.external_tokens.extend(vec![
+            Rule::named("y"),
+            Rule::named("z"),
+            Rule::named("y"),
+        ]);
+
+        match intern_symbols(&input_grammar) {
+            Err(e) => assert_eq!(
+                e.message(),
+                "Grammar error: External token `y` is listed more than once in `externals`"
+            ),
+            _ => panic!("Expected an error but got none"),
+        }
+    }
+
     fn build_grammar(variables: Vec<Variable>) -> InputGrammar {
         InputGrammar {
             variables,
@@ -242,6 +286,9 @@ mod tests {
             variables_to_inline: Vec::new(),
             supertype_symbols: Vec::new(),
             word_token: None,
+            excluded_keywords: Vec::new(),
+            recovery_tokens: Vec::new(),
+            max_serialized_size: None,
         }
     }
 }
@@ -13,11 +13,14 @@ use self::extract_tokens::extract_tokens;
 use self::flatten_grammar::flatten_grammar;
 use self::intern_symbols::intern_symbols;
 use self::process_inlines::process_inlines;
-use crate::error::Result;
+use crate::error::{Error, Result};
+use crate::generate::diagnostics::Diagnostics;
 use crate::generate::grammars::{
-    ExternalToken, InlinedProductionMap, InputGrammar, LexicalGrammar, SyntaxGrammar, Variable,
+    ExternalToken, InlinedProductionMap, InputGrammar, LexicalGrammar, ProductionStep,
+    SyntaxGrammar, Variable, VariableType,
 };
-use crate::generate::rules::{AliasMap, Rule, Symbol};
+use crate::generate::rules::{AliasMap, Rule, Symbol, SymbolType};
+use std::fmt::Write;
 
 pub(crate) struct IntermediateGrammar<T, U> {
     variables: Vec<Variable>,
@@ -27,6 +30,9 @@ pub(crate) struct IntermediateGrammar<T, U> {
     variables_to_inline: Vec<Symbol>,
     supertype_symbols: Vec<Symbol>,
     word_token: Option<Symbol>,
+    excluded_keywords: Vec<T>,
+    recovery_tokens: Vec<T>,
+    max_serialized_size: Option<usize>,
 }
 
 pub(crate) type InternedGrammar = IntermediateGrammar<Rule, Variable>;
@@ -39,8 +45,40 @@ pub(crate) struct ExtractedLexicalGrammar {
     pub separators: Vec<Rule>,
 }
 
-pub(crate) fn prepare_grammar(
+/// Limits on the number of productions that a grammar's rules can expand into when
+/// they are flattened, which guard against rules whose expansion would effectively
+/// hang parser generation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProductionLimits {
+    pub max_productions_per_rule: usize,
+    pub max_productions: usize,
+}
+
+impl Default for ProductionLimits {
+    fn default() -> Self {
+        ProductionLimits {
+            max_productions_per_rule: 10_000,
+            max_productions: 100_000,
+        }
+    }
+}
+
+pub fn prepare_grammar(
+    input_grammar: &InputGrammar,
+    diagnostics: &mut Diagnostics,
+) -> Result<(
+    SyntaxGrammar,
+    LexicalGrammar,
+    InlinedProductionMap,
+    AliasMap,
+)> {
+    prepare_grammar_with_limits(input_grammar, &ProductionLimits::default(), diagnostics)
+}
+
+pub fn prepare_grammar_with_limits(
     input_grammar: &InputGrammar,
+    limits: &ProductionLimits,
+    diagnostics: &mut Diagnostics,
 ) -> Result<(
     SyntaxGrammar,
     LexicalGrammar,
@@ -48,11 +86,250 @@ pub(crate) fn prepare_grammar(
     AliasMap,
 )> {
     let interned_grammar = intern_symbols(input_grammar)?;
+    check_unused_rules(&interned_grammar, diagnostics);
     let (syntax_grammar, lexical_grammar) = extract_tokens(interned_grammar)?;
-    let syntax_grammar = expand_repeats(syntax_grammar);
-    let mut syntax_grammar = flatten_grammar(syntax_grammar)?;
+    let syntax_grammar = expand_repeats(syntax_grammar)?;
+    let mut syntax_grammar = flatten_grammar(syntax_grammar, limits)?;
     let lexical_grammar = expand_tokens(lexical_grammar)?;
+    check_supertypes(&syntax_grammar, &lexical_grammar)?;
     let simple_aliases = extract_simple_aliases(&mut syntax_grammar, &lexical_grammar);
     let inlines = process_inlines(&syntax_grammar);
     Ok((syntax_grammar, lexical_grammar, inlines, simple_aliases))
 }
+
+// Warn about rules that can't be reached from the start rule, the extras, the
+// external tokens, or the word token. They don't affect the generated parser.
+fn check_unused_rules(grammar: &InternedGrammar, diagnostics: &mut Diagnostics) {
+    fn add_non_terminals(rule: &Rule, stack: &mut Vec<usize>) {
+        match rule {
+            Rule::Symbol(symbol) if symbol.is_non_terminal() => stack.push(symbol.index),
+            Rule::Choice(elements) | Rule::Seq(elements) => {
+                for element in elements {
+                    add_non_terminals(element, stack);
+                }
+            }
+            Rule::Metadata { rule, .. } | Rule::Repeat(rule) => add_non_terminals(rule, stack),
+            _ => {}
+        }
+    }
+
+    let mut is_used = vec![false; grammar.variables.len()];
+    let mut stack = vec![0];
+    for rule in &grammar.extra_symbols {
+        add_non_terminals(rule, &mut stack);
+    }
+    for external_token in &grammar.external_tokens {
+        add_non_terminals(&external_token.rule, &mut stack);
+    }
+    stack.extend(
+        grammar
+            .word_token
+            .filter(|symbol| symbol.is_non_terminal())
+            .map(|symbol| symbol.index),
+    );
+
+    while let Some(index) = stack.pop() {
+        if !is_used[index] {
+            is_used[index] = true;
+            add_non_terminals(&grammar.variables[index].rule, &mut stack);
+        }
+    }
+
+    for (variable, is_used) in grammar.variables.iter().zip(is_used) {
+        if !is_used {
+            diagnostics.warn(
+                "unused-rule",
+                format!(
+                    "Rule `{}` is not reachable from the start rule `{}`",
+                    variable.name, grammar.variables[0].name
+                ),
+            );
+        }
+    }
+}
+
+// Ensure that each supertype is a hidden rule that is a choice between visible nodes,
+// so that there is always a single visible node that can be matched as an instance
+// of the supertype. Supertypes may also have other supertypes as alternatives.
+fn check_supertypes(
+    syntax_grammar: &SyntaxGrammar,
+    lexical_grammar: &LexicalGrammar,
+) -> Result<()> {
+    for supertype_symbol in &syntax_grammar.supertype_symbols {
+        let variable = &syntax_grammar.variables[supertype_symbol.index];
+        if variable.kind != VariableType::Hidden {
+            return Err(Error::grammar(&format!(
+                "Supertype `{}` must be a hidden rule. Rename it to `_{}`",
+                variable.name, variable.name
+            )));
+        }
+
+        for production in &variable.productions {
+            if production.steps.len() != 1 {
+                return Err(Error::grammar(&format!(
+                    "Supertype `{}` must be a choice between single nodes, but one of its alternatives has {} nodes",
+                    variable.name,
+                    production.steps.len()
+                )));
+            }
+
+            let mut visited = Vec::new();
+            if let Some(name) = find_hidden_alternative(
+                syntax_grammar,
+                lexical_grammar,
+                &production.steps[0],
+                &mut visited,
+            ) {
+                return Err(Error::grammar(&format!(
+                    "Supertype `{}` must be a choice between visible nodes or other supertypes, but `{}` is hidden",
+                    variable.name, name
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Find the name of a hidden node that would appear in place of a supertype's
+// alternative. Hidden rules whose own alternatives are all single nodes are looked
+// through, whether or not they are inlined, because a visible node or another
+// supertype still appears in the supertype's place.
+fn find_hidden_alternative<'a>(
+    syntax_grammar: &'a SyntaxGrammar,
+    lexical_grammar: &'a LexicalGrammar,
+    step: &ProductionStep,
+    visited: &mut Vec<Symbol>,
+) -> Option<&'a str> {
+    if step.alias.is_some() || syntax_grammar.supertype_symbols.contains(&step.symbol) {
+        return None;
+    }
+    let (name, kind) = match step.symbol.kind {
+        SymbolType::NonTerminal => {
+            let variable = &syntax_grammar.variables[step.symbol.index];
+            (&variable.name, variable.kind)
+        }
+        SymbolType::Terminal => {
+            let variable = &lexical_grammar.variables[step.symbol.index];
+            (&variable.name, variable.kind)
+        }
+        SymbolType::External => {
+            let token = &syntax_grammar.external_tokens[step.symbol.index];
+            (&token.name, token.kind)
+        }
+        SymbolType::End => return None,
+    };
+    if kind != VariableType::Hidden && kind != VariableType::Auxiliary {
+        return None;
+    }
+    if !step.symbol.is_non_terminal() {
+        return Some(name);
+    }
+    if visited.contains(&step.symbol) {
+        return None;
+    }
+    visited.push(step.symbol);
+
+    for production in &syntax_grammar.variables[step.symbol.index].productions {
+        if production.steps.len() != 1 {
+            return Some(name);
+        }
+        if let Some(name) = find_hidden_alternative(
+            syntax_grammar,
+            lexical_grammar,
+            &production.steps[0],
+            visited,
+        ) {
+            return Some(name);
+        }
+    }
+    None
+}
+
+// Describe the final ordering of the grammar's external tokens, which must match
+// the order of the `TokenType` enum in the grammar's external scanner.
+pub(crate) fn report_external_tokens(
+    syntax_grammar: &SyntaxGrammar,
+    lexical_grammar: &LexicalGrammar,
+) -> String {
+    let mut result =
+        "External tokens, in the order expected by the external scanner:\n".to_string();
+    for (i, external_token) in syntax_grammar.external_tokens.iter().enumerate() {
+        let symbol = external_token
+            .corresponding_internal_token
+            .unwrap_or(Symbol::external(i));
+        let is_used = syntax_grammar.extra_symbols.contains(&symbol)
+            || syntax_grammar.variables.iter().any(|variable| {
+                variable
+                    .productions
+                    .iter()
+                    .any(|production| production.steps.iter().any(|step| step.symbol == symbol))
+            });
+
+        write!(&mut result, "  {}\t{}", i, external_token.name).unwrap();
+        if let Some(token) = external_token.corresponding_internal_token {
+            write!(
+                &mut result,
+                "\t(also defined as internal token `{}`)",
+                lexical_grammar.variables[token.index].name
+            )
+            .unwrap();
+        }
+        if !is_used {
+            write!(&mut result, "\t(not used in any rule)").unwrap();
+        }
+        result.push('\n');
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::parse_grammar::parse_grammar;
+
+    #[test]
+    fn test_report_external_tokens() {
+        let input_grammar = parse_grammar(
+            r#"{
+                "name": "the_language",
+                "externals": [
+                    {"type": "SYMBOL", "name": "string_content"},
+                    {"type": "SYMBOL", "name": "_newline"},
+                    {"type": "SYMBOL", "name": "_indent"},
+                    {"type": "STRING", "value": "\""}
+                ],
+                "rules": {
+                    "program": {
+                        "type": "REPEAT",
+                        "content": {"type": "SYMBOL", "name": "statement"}
+                    },
+                    "statement": {
+                        "type": "SEQ",
+                        "members": [
+                            {"type": "STRING", "value": "\""},
+                            {"type": "SYMBOL", "name": "string_content"},
+                            {"type": "STRING", "value": "\""},
+                            {"type": "SYMBOL", "name": "_newline"}
+                        ]
+                    },
+                    "_newline": {"type": "PATTERN", "value": "\\n"}
+                }
+            }"#,
+            &mut Diagnostics::new(),
+        )
+        .unwrap();
+        let (syntax_grammar, lexical_grammar, _, _) =
+            prepare_grammar(&input_grammar, &mut Diagnostics::new()).unwrap();
+
+        assert_eq!(
+            report_external_tokens(&syntax_grammar, &lexical_grammar),
+            concat!(
+                "External tokens, in the order expected by the external scanner:\n",
+                "  0\tstring_content\n",
+                "  1\t_newline\t(also defined as internal token `_newline`)\n",
+                "  2\t_indent\t(not used in any rule)\n",
+                "  3\t\"\t(also defined as internal token `\"`)\n",
+            )
+        );
+    }
+}
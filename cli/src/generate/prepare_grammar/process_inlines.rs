@@ -116,7 +116,22 @@ impl InlinedProductionMapBuilder {
                             }
                             if let Some(field_name) = removed_step.field_name {
                                 for inserted_step in inserted_steps.iter_mut() {
-                                    inserted_step.field_name = Some(field_name.clone());
+                                    // Retain any fields that the step had within the
+                                    // inlined rule, so that they remain accessible at
+                                    // runtime as they would be if the rule were hidden
+                                    // rather than inlined.
+                                    if let Some(inner_field_name) =
+                                        inserted_step.field_name.replace(field_name.clone())
+                                    {
+                                        inserted_step.inner_field_names.push(inner_field_name);
+                                    }
+                                }
+                            }
+                            for inserted_step in inserted_steps.iter_mut() {
+                                for field_name in &removed_step.inner_field_names {
+                                    if !inserted_step.inner_field_names.contains(field_name) {
+                                        inserted_step.inner_field_names.push(field_name.clone());
+                                    }
                                 }
                             }
                             if let Some(last_inserted_step) = inserted_steps.last_mut() {
@@ -200,6 +215,9 @@ mod tests {
             external_tokens: Vec::new(),
             supertype_symbols: Vec::new(),
             word_token: None,
+            excluded_keywords: Vec::new(),
+            recovery_tokens: Vec::new(),
+            max_serialized_size: None,
             variables_to_inline: vec![Symbol::non_terminal(1)],
             variables: vec![
                 SyntaxVariable {
@@ -331,6 +349,9 @@ mod tests {
             external_tokens: Vec::new(),
             supertype_symbols: Vec::new(),
             word_token: None,
+            excluded_keywords: Vec::new(),
+            recovery_tokens: Vec::new(),
+            max_serialized_size: None,
         };
         let inline_map = process_inlines(&grammar);
 
@@ -433,6 +454,9 @@ mod tests {
             external_tokens: Vec::new(),
             supertype_symbols: Vec::new(),
             word_token: None,
+            excluded_keywords: Vec::new(),
+            recovery_tokens: Vec::new(),
+            max_serialized_size: None,
         };
 
         let inline_map = process_inlines(&grammar);
@@ -484,4 +508,141 @@ mod tests {
             }],
         );
     }
+
+    #[test]
+    fn test_inlining_with_field_names() {
+        let grammar = SyntaxGrammar {
+            variables_to_inline: vec![Symbol::non_terminal(1), Symbol::non_terminal(2)],
+            variables: vec![
+                SyntaxVariable {
+                    name: "non-terminal-0".to_string(),
+                    kind: VariableType::Named,
+                    productions: vec![
+                        Production {
+                            dynamic_precedence: 0,
+                            steps: vec![
+                                ProductionStep::new(Symbol::non_terminal(1))
+                                    .with_field_name("outer_field"),
+                                ProductionStep::new(Symbol::terminal(10)),
+                            ],
+                        },
+                        Production {
+                            dynamic_precedence: 0,
+                            steps: vec![ProductionStep::new(Symbol::non_terminal(1))
+                                .with_alias("outer_alias", true)],
+                        },
+                    ],
+                },
+                SyntaxVariable {
+                    name: "non-terminal-1".to_string(),
+                    kind: VariableType::Named,
+                    productions: vec![Production {
+                        dynamic_precedence: 0,
+                        steps: vec![
+                            ProductionStep::new(Symbol::terminal(11)).with_field_name("field_1"),
+                            ProductionStep::new(Symbol::terminal(12)),
+                            ProductionStep::new(Symbol::non_terminal(2)).with_field_name("field_2"),
+                        ],
+                    }],
+                },
+                SyntaxVariable {
+                    name: "non-terminal-2".to_string(),
+                    kind: VariableType::Named,
+                    productions: vec![Production {
+                        dynamic_precedence: 0,
+                        steps: vec![
+                            ProductionStep::new(Symbol::terminal(13)).with_field_name("field_3")
+                        ],
+                    }],
+                },
+            ],
+            expected_conflicts: Vec::new(),
+            extra_symbols: Vec::new(),
+            external_tokens: Vec::new(),
+            supertype_symbols: Vec::new(),
+            word_token: None,
+            excluded_keywords: Vec::new(),
+            recovery_tokens: Vec::new(),
+            max_serialized_size: None,
+        };
+
+        let inline_map = process_inlines(&grammar);
+
+        // Fields assigned within the inlined rules are retained when the inlined
+        // step has a field of its own, including through nested inlining.
+        let productions: Vec<_> = inline_map
+            .inlined_productions(&grammar.variables[0].productions[0], 0)
+            .unwrap()
+            .collect();
+        assert_eq!(
+            productions.iter().cloned().cloned().collect::<Vec<_>>(),
+            vec![Production {
+                dynamic_precedence: 0,
+                steps: vec![
+                    ProductionStep {
+                        inner_field_names: vec!["field_1".to_string()],
+                        ..ProductionStep::new(Symbol::terminal(11)).with_field_name("outer_field")
+                    },
+                    ProductionStep::new(Symbol::terminal(12)).with_field_name("outer_field"),
+                    ProductionStep {
+                        inner_field_names: vec!["field_2".to_string()],
+                        ..ProductionStep::new(Symbol::non_terminal(2))
+                            .with_field_name("outer_field")
+                    },
+                    ProductionStep::new(Symbol::terminal(10)),
+                ]
+            }],
+        );
+        assert_eq!(
+            inline_map
+                .inlined_productions(productions[0], 2)
+                .unwrap()
+                .cloned()
+                .collect::<Vec<_>>(),
+            vec![Production {
+                dynamic_precedence: 0,
+                steps: vec![
+                    ProductionStep {
+                        inner_field_names: vec!["field_1".to_string()],
+                        ..ProductionStep::new(Symbol::terminal(11)).with_field_name("outer_field")
+                    },
+                    ProductionStep::new(Symbol::terminal(12)).with_field_name("outer_field"),
+                    ProductionStep {
+                        inner_field_names: vec!["field_3".to_string(), "field_2".to_string()],
+                        ..ProductionStep::new(Symbol::terminal(13)).with_field_name("outer_field")
+                    },
+                    ProductionStep::new(Symbol::terminal(10)),
+                ]
+            }],
+        );
+
+        // When the inlined step is aliased, the inlined rule's fields are unchanged.
+        // Nested inlined rules' fields are still retained.
+        let productions: Vec<_> = inline_map
+            .inlined_productions(&grammar.variables[0].productions[1], 0)
+            .unwrap()
+            .collect();
+        assert_eq!(
+            inline_map
+                .inlined_productions(productions[0], 2)
+                .unwrap()
+                .cloned()
+                .collect::<Vec<_>>(),
+            vec![Production {
+                dynamic_precedence: 0,
+                steps: vec![
+                    ProductionStep::new(Symbol::terminal(11))
+                        .with_alias("outer_alias", true)
+                        .with_field_name("field_1"),
+                    ProductionStep::new(Symbol::terminal(12)).with_alias("outer_alias", true),
+                    ProductionStep {
+                        inner_field_names: vec!["field_3".to_string()],
+                        ..ProductionStep::new(Symbol::terminal(13))
+                            .with_alias("outer_alias", true)
+                            .with_field_name("field_2")
+                    },
+                ]
+            }],
+        );
+    }
 }
@@ -0,0 +1,92 @@
+use serde_derive::Serialize;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+// Intermediate progress records are written at most this often. The first and last
+// records of each phase are always written.
+const MIN_REPORT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Writes the progress of parser generation as newline-delimited JSON, so that editors
+/// and other tools can display it. Each record is a JSON object with these fields:
+///
+/// * `phase` - One of `grammar`, `prepare`, `parse-table`, `lex-table`, `render` or
+///   `write`. The phases always occur in this order, though some may be skipped.
+/// * `current` - The amount of work that has been done in this phase so far, such as the
+///   number of parse states that have been discovered.
+/// * `total` - The total amount of work in this phase, if it is known.
+/// * `elapsed_ms` - The number of milliseconds since generation started.
+///
+/// Each phase starts with a record whose `current` is 0, and ends with a record whose
+/// `current` is equal to its `total`.
+pub struct Progress {
+    output: RefCell<Box<dyn Write>>,
+    start_time: Instant,
+    last_report_time: Cell<Instant>,
+    phase: Cell<&'static str>,
+}
+
+#[derive(Serialize)]
+struct ProgressRecord<'a> {
+    phase: &'a str,
+    current: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<usize>,
+    elapsed_ms: u128,
+}
+
+impl Progress {
+    pub fn new(output: Box<dyn Write>) -> Self {
+        let now = Instant::now();
+        Progress {
+            output: RefCell::new(output),
+            start_time: now,
+            last_report_time: Cell::new(now),
+            phase: Cell::new(""),
+        }
+    }
+
+    pub fn stderr() -> Self {
+        Self::new(Box::new(io::stderr()))
+    }
+
+    pub(crate) fn begin(&self, phase: &'static str, total: Option<usize>) {
+        self.phase.set(phase);
+        self.write_record(0, total);
+    }
+
+    pub(crate) fn update(&self, current: usize, total: Option<usize>) {
+        if self.last_report_time.get().elapsed() >= MIN_REPORT_INTERVAL {
+            self.write_record(current, total);
+        }
+    }
+
+    pub(crate) fn finish(&self, total: usize) {
+        self.write_record(total, Some(total));
+    }
+
+    fn write_record(&self, current: usize, total: Option<usize>) {
+        let now = Instant::now();
+        self.last_report_time.set(now);
+        let record = ProgressRecord {
+            phase: self.phase.get(),
+            current,
+            total,
+            elapsed_ms: now.duration_since(self.start_time).as_millis(),
+        };
+        let mut output = self.output.borrow_mut();
+        // Progress is only informational, so failures to write it are ignored.
+        serde_json::to_writer(&mut *output, &record).ok();
+        writeln!(output).ok();
+        output.flush().ok();
+    }
+}
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Progress")
+            .field("phase", &self.phase.get())
+            .finish()
+    }
+}
@@ -0,0 +1,260 @@
+use super::grammars::InputGrammar;
+use super::rules::Rule;
+use crate::error::{Error, Result};
+use crate::loader::{scanner_path_at_path, Loader};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::str::CharIndices;
+use tree_sitter::{Language, Query, QueryError};
+
+// Compile each of the grammar's `queries/*.scm` files against the parser that
+// was just generated in `src_path`, so that renaming a node type or a field
+// doesn't silently break the queries. Returns the location of each query
+// error, as a file, row and column, along with a description of the error.
+pub(crate) fn check_queries(
+    repo_path: &Path,
+    src_path: &Path,
+    language_name: &str,
+) -> Result<Vec<(String, String)>> {
+    let queries = read_queries(repo_path)?;
+    if queries.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let language = load_generated_language(src_path, language_name).map_err(Error::wrap(|| {
+        "Failed to load the generated parser to check queries"
+    }))?;
+
+    let mut errors = Vec::new();
+    for (path, source) in queries {
+        if let Err(error) = Query::new(language, &source) {
+            let path = path.strip_prefix(repo_path).unwrap_or(&path);
+            errors.push(describe_query_error(path, &source, error));
+        }
+    }
+    Ok(errors)
+}
+
+// Find the named node types that were listed in the previous `node-types.json`, but
+// aren't listed in the new one, and report each reference to them in the grammar's
+// `queries/*.scm` files. A node type usually disappears because the grammar now aliases
+// it to another name, so the names that the grammar aliases it to are suggested as
+// replacements. Returns the location of each reference, as a file, row and column, along
+// with a description of it.
+pub(crate) fn check_removed_node_types(
+    repo_path: &Path,
+    previous_node_types_json: &str,
+    node_types_json: &str,
+    grammar: &InputGrammar,
+) -> Result<Vec<(String, String)>> {
+    // The previous file may have been edited by hand, so it is ignored if it's invalid.
+    let previous_names = match named_node_types(previous_node_types_json) {
+        Some(names) => names,
+        None => return Ok(Vec::new()),
+    };
+    let names = named_node_types(node_types_json).unwrap_or_default();
+    let removed_names = previous_names
+        .difference(&names)
+        .map(String::as_str)
+        .collect::<BTreeSet<_>>();
+    if removed_names.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let alias_values = alias_values_by_rule_name(grammar);
+    let mut result = Vec::new();
+    for (path, source) in read_queries(repo_path)? {
+        let path = path.strip_prefix(repo_path).unwrap_or(&path);
+        for (row, column, name) in node_type_references(&source) {
+            if !removed_names.contains(name) {
+                continue;
+            }
+            let mut message = format!("Node type `{}` no longer exists", name);
+            if let Some(values) = alias_values.get(name) {
+                message += "; it is now aliased as ";
+                message += &values
+                    .iter()
+                    .map(|value| format!("`{}`", value))
+                    .collect::<Vec<_>>()
+                    .join(" or ");
+            }
+            result.push((format!("{}:{}:{}", path.display(), row, column), message));
+        }
+    }
+    Ok(result)
+}
+
+// Read each of the grammar's `queries/*.scm` files that contains any patterns, in
+// order of their paths.
+fn read_queries(repo_path: &Path) -> Result<Vec<(PathBuf, String)>> {
+    let mut query_paths = Vec::new();
+    if let Ok(entries) = fs::read_dir(repo_path.join("queries")) {
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension() == Some("scm".as_ref()) {
+                query_paths.push(path);
+            }
+        }
+    }
+    query_paths.sort();
+
+    let mut queries = Vec::new();
+    for path in query_paths {
+        let source = fs::read_to_string(&path).map_err(Error::wrap(|| {
+            format!("Failed to read query file {:?}", path)
+        }))?;
+        if !is_blank_query(&source) {
+            queries.push((path, source));
+        }
+    }
+    Ok(queries)
+}
+
+fn named_node_types(node_types_json: &str) -> Option<BTreeSet<String>> {
+    let node_types = serde_json::from_str::<serde_json::Value>(node_types_json).ok()?;
+    Some(
+        node_types
+            .as_array()?
+            .iter()
+            .filter(|node_type| node_type["named"].as_bool() == Some(true))
+            .filter_map(|node_type| node_type["type"].as_str())
+            .map(String::from)
+            .collect(),
+    )
+}
+
+// Map the name of each rule to the named aliases that are applied to it anywhere in the
+// grammar, in alphabetical order.
+fn alias_values_by_rule_name(grammar: &InputGrammar) -> HashMap<&str, Vec<&str>> {
+    let mut result = HashMap::new();
+    let mut stack = grammar
+        .variables
+        .iter()
+        .map(|variable| &variable.rule)
+        .chain(grammar.extra_symbols.iter())
+        .collect::<Vec<_>>();
+    while let Some(rule) = stack.pop() {
+        match rule {
+            Rule::Metadata { params, rule } => {
+                if let (Some(alias), Rule::NamedSymbol(name)) = (&params.alias, rule.as_ref()) {
+                    if alias.is_named && alias.value != *name {
+                        let values = result.entry(name.as_str()).or_insert_with(Vec::new);
+                        if !values.contains(&alias.value.as_str()) {
+                            values.push(alias.value.as_str());
+                        }
+                    }
+                }
+                stack.push(rule);
+            }
+            Rule::Choice(members) | Rule::Seq(members) => stack.extend(members),
+            Rule::Repeat(rule) => stack.push(rule),
+            _ => {}
+        }
+    }
+    for values in result.values_mut() {
+        values.sort_unstable();
+    }
+    result
+}
+
+// Find the node types that a query refers to, along with the row and column at which
+// each reference starts, both counting from one. These are the names that follow an
+// opening parenthesis, and the subtypes that follow a slash after them. Strings and
+// comments are skipped.
+fn node_type_references(source: &str) -> Vec<(usize, usize, &str)> {
+    let mut result = Vec::new();
+    for (row, line) in source.lines().enumerate() {
+        let mut chars = line.char_indices().peekable();
+        while let Some((_, c)) = chars.next() {
+            match c {
+                ';' => break,
+                '"' => {
+                    while let Some((_, c)) = chars.next() {
+                        match c {
+                            '\\' => {
+                                chars.next();
+                            }
+                            '"' => break,
+                            _ => {}
+                        }
+                    }
+                }
+                '(' => {
+                    while chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+                    if let Some((column, name)) = take_name(line, &mut chars) {
+                        result.push((row + 1, column + 1, name));
+                        if chars.next_if(|(_, c)| *c == '/').is_some() {
+                            if let Some((column, name)) = take_name(line, &mut chars) {
+                                result.push((row + 1, column + 1, name));
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    result
+}
+
+fn take_name<'a>(line: &'a str, chars: &mut Peekable<CharIndices>) -> Option<(usize, &'a str)> {
+    let start = chars.peek()?.0;
+    let mut end = start;
+    while let Some((i, c)) = chars.next_if(|(_, c)| c.is_alphanumeric() || *c == '_') {
+        end = i + c.len_utf8();
+    }
+    if end > start {
+        Some((start, &line[start..end]))
+    } else {
+        None
+    }
+}
+
+// Compile the generated parser into a library in a temporary directory, so that
+// the check doesn't interfere with the libraries that the loader caches.
+fn load_generated_language(src_path: &Path, language_name: &str) -> Result<Language> {
+    let library_dir = tempfile::tempdir()?;
+    Loader::new(library_dir.path().to_owned()).load_language_from_sources(
+        language_name,
+        src_path,
+        &src_path.join("parser.c"),
+        &scanner_path_at_path(src_path),
+    )
+}
+
+fn is_blank_query(source: &str) -> bool {
+    source.lines().all(|line| {
+        let line = line.trim();
+        line.is_empty() || line.starts_with(';')
+    })
+}
+
+fn describe_query_error(path: &Path, source: &str, error: QueryError) -> (String, String) {
+    let (row, name, message) = match error {
+        QueryError::NodeType(row, name) => (row, name, "Invalid node type"),
+        QueryError::Field(row, name) => (row, name, "Invalid field name"),
+        QueryError::Capture(row, name) => (row, name, "Invalid capture name"),
+        QueryError::Syntax(row, context) => {
+            // The context ends with a line containing a caret that points at
+            // the error's column.
+            let column = context.lines().last().map_or(1, |line| line.len());
+            return (
+                format!("{}:{}:{}", path.display(), row, column),
+                "Invalid syntax".to_string(),
+            );
+        }
+        QueryError::Predicate(message) => return (path.display().to_string(), message),
+    };
+
+    let column = source
+        .lines()
+        .nth(row.saturating_sub(1))
+        .and_then(|line| line.find(name.as_str()))
+        .unwrap_or(0);
+    (
+        format!("{}:{}:{}", path.display(), row, column + 1),
+        format!("{} `{}`", message, name),
+    )
+}
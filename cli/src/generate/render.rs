@@ -6,14 +6,15 @@ use super::tables::{
     ParseTableEntry,
 };
 use core::ops::Range;
+use serde_derive::Serialize;
 use std::cmp;
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fmt::Write;
-use std::mem::swap;
+use std::mem::{self, swap};
 
 // Currently, the library supports a new ABI version that has not yet been
 // stabilized, and the parser generation does not use it by default.
-const STABLE_LANGUAGE_VERSION: usize = tree_sitter::LANGUAGE_VERSION - 1;
+pub(crate) const STABLE_LANGUAGE_VERSION: usize = tree_sitter::LANGUAGE_VERSION - 1;
 
 macro_rules! add {
     ($this: tt, $($arg: tt)*) => {{
@@ -52,6 +53,82 @@ macro_rules! dedent {
 
 const SMALL_STATE_THRESHOLD: usize = 64;
 
+/// Determines which parse states are stored in the compact `ts_small_parse_table`, rather
+/// than in `ts_parse_table`. A state is stored in the small table if it has no more
+/// entries than the threshold. This only applies to parsers for the next ABI.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SmallStateThreshold {
+    /// The smaller of 64 and half of the number of symbols.
+    Default,
+    Fixed(usize),
+    /// Try several thresholds, and use the one with which the parse tables take up the
+    /// fewest bytes of `parser.c`.
+    Optimize,
+}
+
+impl Default for SmallStateThreshold {
+    fn default() -> Self {
+        SmallStateThreshold::Default
+    }
+}
+
+/// How the states of a generated parser were split between the large and the small
+/// parse table.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TableSplit {
+    pub small_state_threshold: usize,
+    pub large_state_count: usize,
+    pub small_state_count: usize,
+    /// The number of bytes of `parser.c` that each table takes up.
+    pub large_table_bytes: usize,
+    pub small_table_bytes: usize,
+    /// The number of states whose number of entries is within 10% of the threshold, and
+    /// which would move to the other table if the threshold changed slightly.
+    pub states_near_threshold: usize,
+}
+
+/// A description of every symbol in a generated parser, keyed by the numeric ids that
+/// are used in `parser.c`. This is written to `src/symbol-map.json`.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub(crate) struct SymbolMap {
+    pub symbols: Vec<SymbolInfo>,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub(crate) struct SymbolInfo {
+    pub id: usize,
+    pub identifier: String,
+    pub kind: &'static str,
+    pub name: String,
+    pub named: bool,
+    pub aliases: Vec<SymbolAlias>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub external_index: Option<usize>,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct SymbolAlias {
+    pub name: String,
+    pub named: bool,
+}
+
+/// The characters with which each token can begin, according to the lex tables. This
+/// is written to `src/lex-metadata.json`.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub(crate) struct LexMetadata {
+    pub tokens: Vec<TokenLexInfo>,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub(crate) struct TokenLexInfo {
+    pub id: usize,
+    pub name: String,
+    pub named: bool,
+    /// Inclusive ranges of unicode code points, in ascending order.
+    pub first_characters: Vec<(u32, u32)>,
+    pub single_character_punctuation: bool,
+}
+
 struct Generator {
     buffer: String,
     indent_level: usize,
@@ -60,6 +137,8 @@ struct Generator {
     main_lex_table: LexTable,
     keyword_lex_table: LexTable,
     large_state_count: usize,
+    small_state_threshold: SmallStateThreshold,
+    table_split: TableSplit,
     keyword_capture_token: Option<Symbol>,
     syntax_grammar: SyntaxGrammar,
     lexical_grammar: LexicalGrammar,
@@ -73,7 +152,7 @@ struct Generator {
 }
 
 impl Generator {
-    fn generate(mut self) -> String {
+    fn generate(mut self) -> (String, SymbolMap, LexMetadata, TableSplit) {
         self.init();
         self.add_includes();
         self.add_pragmas();
@@ -92,6 +171,7 @@ impl Generator {
             self.add_alias_sequences();
         }
 
+        let lex_metadata = self.lex_metadata();
         let mut main_lex_table = LexTable::default();
         swap(&mut main_lex_table, &mut self.main_lex_table);
         self.add_lex_function("ts_lex", main_lex_table);
@@ -111,9 +191,15 @@ impl Generator {
         }
 
         self.add_parse_table();
+
+        if self.next_abi {
+            self.add_recovery_tokens_list();
+        }
+
         self.add_parser_export();
 
-        self.buffer
+        let symbol_map = self.symbol_map();
+        (self.buffer, symbol_map, lex_metadata, self.table_split)
     }
 
     fn init(&mut self) {
@@ -142,9 +228,9 @@ impl Generator {
                     let alias_id = if let Some(symbol) = matching_symbol {
                         self.symbol_ids[&symbol].clone()
                     } else if alias.is_named {
-                        format!("alias_sym_{}", self.sanitize_identifier(&alias.value))
+                        format!("alias_sym_{}", sanitize_identifier(&alias.value))
                     } else {
-                        format!("anon_alias_sym_{}", self.sanitize_identifier(&alias.value))
+                        format!("anon_alias_sym_{}", sanitize_identifier(&alias.value))
                     };
                     self.alias_ids.entry(alias.clone()).or_insert(alias_id);
                     self.alias_map
@@ -162,21 +248,74 @@ impl Generator {
         // "small parse states". Otherwise, use the same representation for all parse
         // states.
         if self.next_abi {
-            let threshold = cmp::min(SMALL_STATE_THRESHOLD, self.parse_table.symbols.len() / 2);
-            self.large_state_count = self
-                .parse_table
-                .states
-                .iter()
-                .enumerate()
-                .take_while(|(i, s)| {
-                    *i <= 1 || s.terminal_entries.len() + s.nonterminal_entries.len() > threshold
-                })
-                .count();
+            let default_threshold =
+                cmp::min(SMALL_STATE_THRESHOLD, self.parse_table.symbols.len() / 2);
+            let threshold = match self.small_state_threshold {
+                SmallStateThreshold::Default => default_threshold,
+                SmallStateThreshold::Fixed(threshold) => threshold,
+                SmallStateThreshold::Optimize => {
+                    self.optimal_small_state_threshold(default_threshold)
+                }
+            };
+            self.split_states(threshold);
         } else {
             self.large_state_count = self.parse_table.states.len();
         }
     }
 
+    // The states are sorted by their number of entries, in descending order, except for
+    // the first two states, which are always stored in the large table. So the large
+    // states are the ones before the first state that has no more entries than the
+    // threshold.
+    fn split_states(&mut self, threshold: usize) {
+        let state_sizes = self
+            .parse_table
+            .states
+            .iter()
+            .map(|s| s.terminal_entries.len() + s.nonterminal_entries.len());
+        self.large_state_count = state_sizes
+            .clone()
+            .enumerate()
+            .take_while(|(i, size)| *i <= 1 || *size > threshold)
+            .count();
+        self.table_split = TableSplit {
+            small_state_threshold: threshold,
+            large_state_count: self.large_state_count,
+            small_state_count: self.parse_table.states.len() - self.large_state_count,
+            states_near_threshold: state_sizes
+                .skip(2)
+                .filter(|size| {
+                    size * 10 >= threshold.saturating_mul(9)
+                        && size * 10 <= threshold.saturating_mul(11)
+                })
+                .count(),
+            ..Default::default()
+        };
+    }
+
+    // Render the parse tables with the default threshold, and with each power of two
+    // that is less than the number of symbols, and return the threshold with which they
+    // are smallest. The default is preferred in case of a tie.
+    fn optimal_small_state_threshold(&mut self, default_threshold: usize) -> usize {
+        let buffer = mem::take(&mut self.buffer);
+        let symbol_count = self.parse_table.symbols.len();
+        let mut result = (default_threshold, usize::MAX);
+        for threshold in Some(default_threshold).into_iter().chain(
+            (1..usize::BITS)
+                .map(|i| 1 << i)
+                .take_while(|threshold| *threshold < symbol_count),
+        ) {
+            self.split_states(threshold);
+            self.add_parse_table();
+            if self.buffer.len() < result.1 {
+                result = (threshold, self.buffer.len());
+            }
+            self.buffer.clear();
+        }
+        self.buffer = buffer;
+        result.0
+    }
+
     fn add_includes(&mut self) {
         add_line!(self, "#include <tree_sitter/parser.h>");
         add_line!(self, "");
@@ -243,6 +382,11 @@ impl Generator {
 
         if self.next_abi {
             add_line!(self, "#define LARGE_STATE_COUNT {}", self.large_state_count);
+            add_line!(
+                self,
+                "#define SMALL_STATE_THRESHOLD {}",
+                self.table_split.small_state_threshold
+            );
         }
 
         add_line!(
@@ -261,6 +405,13 @@ impl Generator {
             "#define EXTERNAL_TOKEN_COUNT {}",
             self.syntax_grammar.external_tokens.len()
         );
+        if let Some(size) = self.syntax_grammar.max_serialized_size {
+            add_line!(
+                self,
+                "#define EXTERNAL_SCANNER_MAX_SERIALIZED_SIZE {}",
+                size
+            );
+        }
         add_line!(self, "#define FIELD_COUNT {}", self.field_names.len());
         add_line!(
             self,
@@ -320,6 +471,132 @@ impl Generator {
         add_line!(self, "");
     }
 
+    fn symbol_map(&self) -> SymbolMap {
+        let mut aliases_by_symbol = HashMap::<Symbol, BTreeSet<SymbolAlias>>::new();
+        for variable in &self.syntax_grammar.variables {
+            for production in &variable.productions {
+                for step in &production.steps {
+                    if let Some(alias) = &step.alias {
+                        aliases_by_symbol
+                            .entry(step.symbol)
+                            .or_default()
+                            .insert(SymbolAlias {
+                                name: alias.value.clone(),
+                                named: alias.is_named,
+                            });
+                    }
+                }
+            }
+        }
+
+        let mut external_indices = HashMap::new();
+        for (i, token) in self.syntax_grammar.external_tokens.iter().enumerate() {
+            let id_token = token
+                .corresponding_internal_token
+                .unwrap_or(Symbol::external(i));
+            external_indices.insert(id_token, i);
+        }
+
+        let mut symbols = Vec::new();
+        for symbol in self.parse_table.symbols.iter() {
+            let (name, kind) = self.metadata_for_symbol(*symbol);
+            let (name, named) = match self.simple_aliases.get(symbol) {
+                Some(alias) => (alias.value.as_str(), alias.is_named),
+                None => (name, kind == VariableType::Named),
+            };
+            symbols.push(SymbolInfo {
+                id: self.symbol_order[symbol],
+                identifier: self.symbol_ids[symbol].clone(),
+                kind: if kind == VariableType::Auxiliary {
+                    "auxiliary"
+                } else {
+                    match symbol.kind {
+                        SymbolType::End | SymbolType::Terminal => "terminal",
+                        SymbolType::NonTerminal => "non-terminal",
+                        SymbolType::External => "external",
+                    }
+                },
+                name: name.to_string(),
+                named,
+                aliases: aliases_by_symbol
+                    .remove(symbol)
+                    .map_or(Vec::new(), |aliases| aliases.into_iter().collect()),
+                external_index: external_indices.get(symbol).cloned(),
+            });
+        }
+
+        let mut id = symbols.len();
+        for (alias, symbol) in &self.alias_map {
+            if symbol.is_none() {
+                symbols.push(SymbolInfo {
+                    id,
+                    identifier: self.alias_ids[alias].clone(),
+                    kind: "alias",
+                    name: alias.value.clone(),
+                    named: alias.is_named,
+                    aliases: Vec::new(),
+                    external_index: None,
+                });
+                id += 1;
+            }
+        }
+
+        symbols.sort_unstable_by_key(|info| info.id);
+        SymbolMap { symbols }
+    }
+
+    fn lex_metadata(&self) -> LexMetadata {
+        let mut first_characters = HashMap::<Symbol, CharacterSet>::new();
+        let main_start_states = self
+            .parse_table
+            .states
+            .iter()
+            .map(|state| state.lex_state_id)
+            .collect::<BTreeSet<_>>();
+        add_first_characters(
+            &self.main_lex_table,
+            main_start_states,
+            &mut first_characters,
+        );
+        if self.keyword_capture_token.is_some() {
+            let keyword_start_states = Some(0).into_iter().collect();
+            add_first_characters(
+                &self.keyword_lex_table,
+                keyword_start_states,
+                &mut first_characters,
+            );
+        }
+
+        let mut tokens = Vec::new();
+        for symbol in &self.parse_table.symbols {
+            if !symbol.is_terminal() {
+                continue;
+            }
+            let variable = &self.lexical_grammar.variables[symbol.index];
+            let (name, named) = match self.simple_aliases.get(symbol) {
+                Some(alias) => (alias.value.clone(), alias.is_named),
+                None => (variable.name.clone(), variable.kind == VariableType::Named),
+            };
+            let mut chars = variable.name.chars();
+            let single_character_punctuation = variable.kind == VariableType::Anonymous
+                && match (chars.next(), chars.next()) {
+                    (Some(c), None) => !c.is_alphanumeric() && !c.is_whitespace(),
+                    _ => false,
+                };
+            tokens.push(TokenLexInfo {
+                id: self.symbol_order[symbol],
+                name,
+                named,
+                first_characters: first_characters
+                    .get(symbol)
+                    .map_or(Vec::new(), character_set_ranges),
+                single_character_punctuation,
+            });
+        }
+        tokens.sort_unstable_by_key(|info| info.id);
+        LexMetadata { tokens }
+    }
+
     fn add_field_name_enum(&mut self) {
         add_line!(self, "enum {{");
         indent!(self);
@@ -373,6 +650,9 @@ impl Generator {
                     VariableType::Hidden => {
                         add_line!(self, ".visible = false,");
                         add_line!(self, ".named = true,");
+                        if self.syntax_grammar.supertype_symbols.contains(symbol) {
+                            add_line!(self, ".supertype = true,");
+                        }
                     }
                     VariableType::Auxiliary => {
                         add_line!(self, ".visible = false,");
@@ -740,6 +1020,41 @@ impl Generator {
         add_line!(self, "");
     }
 
+    // The tokens that the grammar declares as good places to resume parsing after an
+    // error, in the order in which they were declared. Tokens that never appear in
+    // the parse table are omitted, since they can never be shifted.
+    fn recovery_token_ids(&self) -> Vec<String> {
+        self.syntax_grammar
+            .recovery_tokens
+            .iter()
+            .filter_map(|symbol| {
+                let symbol = if symbol.is_external() {
+                    self.syntax_grammar.external_tokens[symbol.index]
+                        .corresponding_internal_token
+                        .unwrap_or(*symbol)
+                } else {
+                    *symbol
+                };
+                self.symbol_ids.get(&symbol).cloned()
+            })
+            .collect()
+    }
+
+    fn add_recovery_tokens_list(&mut self) {
+        let recovery_token_ids = self.recovery_token_ids();
+        if recovery_token_ids.is_empty() {
+            return;
+        }
+        add_line!(self, "static TSSymbol ts_recovery_tokens[] = {{");
+        indent!(self);
+        for id in recovery_token_ids {
+            add_line!(self, "{},", id);
+        }
+        dedent!(self);
+        add_line!(self, "}};");
+        add_line!(self, "");
+    }
+
     fn add_external_scanner_states_list(&mut self) {
         add_line!(
             self,
@@ -770,6 +1085,7 @@ impl Generator {
     fn add_parse_table(&mut self) {
         let mut parse_table_entries = Vec::new();
         let mut next_parse_action_list_index = 0;
+        let large_table_start = self.buffer.len();
 
         self.get_parse_action_list_id(
             &ParseTableEntry {
@@ -844,7 +1160,9 @@ impl Generator {
         dedent!(self);
         add_line!(self, "}};");
         add_line!(self, "");
+        self.table_split.large_table_bytes = self.buffer.len() - large_table_start;
 
+        let small_table_start = self.buffer.len();
         if self.large_state_count < self.parse_table.states.len() {
             add_line!(self, "static uint16_t ts_small_parse_table[] = {{");
             indent!(self);
@@ -936,6 +1254,7 @@ impl Generator {
             add_line!(self, "}};");
             add_line!(self, "");
         }
+        self.table_split.small_table_bytes = self.buffer.len() - small_table_start;
 
         self.add_parse_action_list(parse_table_entries);
     }
@@ -1038,6 +1357,12 @@ impl Generator {
 
         if self.next_abi {
             add_line!(self, ".large_state_count = LARGE_STATE_COUNT,");
+
+            let recovery_token_count = self.recovery_token_ids().len();
+            if recovery_token_count > 0 {
+                add_line!(self, ".recovery_token_count = {},", recovery_token_count);
+                add_line!(self, ".recovery_tokens = ts_recovery_tokens,");
+            }
         }
 
         add_line!(self, ".symbol_metadata = ts_symbol_metadata,");
@@ -1153,10 +1478,7 @@ impl Generator {
     }
 
     fn external_token_id(&self, token: &ExternalToken) -> String {
-        format!(
-            "ts_external_token_{}",
-            self.sanitize_identifier(&token.name)
-        )
+        format!("ts_external_token_{}", sanitize_identifier(&token.name))
     }
 
     fn assign_symbol_id(&mut self, symbol: Symbol, used_identifiers: &mut HashSet<String>) {
@@ -1166,10 +1488,10 @@ impl Generator {
         } else {
             let (name, kind) = self.metadata_for_symbol(symbol);
             id = match kind {
-                VariableType::Auxiliary => format!("aux_sym_{}", self.sanitize_identifier(name)),
-                VariableType::Anonymous => format!("anon_sym_{}", self.sanitize_identifier(name)),
+                VariableType::Auxiliary => format!("aux_sym_{}", sanitize_identifier(name)),
+                VariableType::Anonymous => format!("anon_sym_{}", sanitize_identifier(name)),
                 VariableType::Hidden | VariableType::Named => {
-                    format!("sym_{}", self.sanitize_identifier(name))
+                    format!("sym_{}", sanitize_identifier(name))
                 }
             };
 
@@ -1209,62 +1531,6 @@ impl Generator {
         }
     }
 
-    fn sanitize_identifier(&self, name: &str) -> String {
-        let mut result = String::with_capacity(name.len());
-        for c in name.chars() {
-            if ('a' <= c && c <= 'z')
-                || ('A' <= c && c <= 'Z')
-                || ('0' <= c && c <= '9')
-                || c == '_'
-            {
-                result.push(c);
-            } else {
-                let replacement = match c {
-                    '~' => "TILDE",
-                    '`' => "BQUOTE",
-                    '!' => "BANG",
-                    '@' => "AT",
-                    '#' => "POUND",
-                    '$' => "DOLLAR",
-                    '%' => "PERCENT",
-                    '^' => "CARET",
-                    '&' => "AMP",
-                    '*' => "STAR",
-                    '(' => "LPAREN",
-                    ')' => "RPAREN",
-                    '-' => "DASH",
-                    '+' => "PLUS",
-                    '=' => "EQ",
-                    '{' => "LBRACE",
-                    '}' => "RBRACE",
-                    '[' => "LBRACK",
-                    ']' => "RBRACK",
-                    '\\' => "BSLASH",
-                    '|' => "PIPE",
-                    ':' => "COLON",
-                    ';' => "SEMI",
-                    '"' => "DQUOTE",
-                    '\'' => "SQUOTE",
-                    '<' => "LT",
-                    '>' => "GT",
-                    ',' => "COMMA",
-                    '.' => "DOT",
-                    '?' => "QMARK",
-                    '/' => "SLASH",
-                    '\n' => "LF",
-                    '\r' => "CR",
-                    '\t' => "TAB",
-                    _ => continue,
-                };
-                if !result.is_empty() && !result.ends_with("_") {
-                    result.push('_');
-                }
-                result += replacement;
-            }
-        }
-        result
-    }
-
     fn sanitize_string(&self, name: &str) -> String {
         let mut result = String::with_capacity(name.len());
         for c in name.chars() {
@@ -1297,7 +1563,149 @@ impl Generator {
     }
 }
 
-/// Returns a String of C code for the given components of a parser.
+fn sanitize_identifier(name: &str) -> String {
+    let mut result = String::with_capacity(name.len());
+    for c in name.chars() {
+        if ('a' <= c && c <= 'z') || ('A' <= c && c <= 'Z') || ('0' <= c && c <= '9') || c == '_' {
+            result.push(c);
+        } else {
+            let replacement = match c {
+                '~' => "TILDE",
+                '`' => "BQUOTE",
+                '!' => "BANG",
+                '@' => "AT",
+                '#' => "POUND",
+                '$' => "DOLLAR",
+                '%' => "PERCENT",
+                '^' => "CARET",
+                '&' => "AMP",
+                '*' => "STAR",
+                '(' => "LPAREN",
+                ')' => "RPAREN",
+                '-' => "DASH",
+                '+' => "PLUS",
+                '=' => "EQ",
+                '{' => "LBRACE",
+                '}' => "RBRACE",
+                '[' => "LBRACK",
+                ']' => "RBRACK",
+                '\\' => "BSLASH",
+                '|' => "PIPE",
+                ':' => "COLON",
+                ';' => "SEMI",
+                '"' => "DQUOTE",
+                '\'' => "SQUOTE",
+                '<' => "LT",
+                '>' => "GT",
+                ',' => "COMMA",
+                '.' => "DOT",
+                '?' => "QMARK",
+                '/' => "SLASH",
+                '\n' => "LF",
+                '\r' => "CR",
+                '\t' => "TAB",
+                _ => continue,
+            };
+            if !result.is_empty() && !result.ends_with("_") {
+                result.push('_');
+            }
+            result += replacement;
+        }
+    }
+    result
+}
+
+// For each token, find the characters that can begin the token, when lexing starts in
+// any of the given states. Whitespace that is skipped before a token is ignored.
+fn add_first_characters(
+    lex_table: &LexTable,
+    mut start_states: BTreeSet<usize>,
+    result: &mut HashMap<Symbol, CharacterSet>,
+) {
+    // Find the tokens that can be accepted after reaching each state.
+    let mut reachable_tokens = lex_table
+        .states
+        .iter()
+        .map(|state| state.accept_action.into_iter().collect::<BTreeSet<_>>())
+        .collect::<Vec<_>>();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (i, state) in lex_table.states.iter().enumerate() {
+            for (_, action) in &state.advance_actions {
+                if action.in_main_token && action.state != i {
+                    let tokens = reachable_tokens[action.state].clone();
+                    let count = reachable_tokens[i].len();
+                    reachable_tokens[i].extend(tokens);
+                    changed |= reachable_tokens[i].len() != count;
+                }
+            }
+        }
+    }
+
+    // Skipped characters lead to other states in which a token can begin.
+    let mut stack = start_states.iter().cloned().collect::<Vec<_>>();
+    while let Some(state_id) = stack.pop() {
+        for (_, action) in &lex_table.states[state_id].advance_actions {
+            if !action.in_main_token && start_states.insert(action.state) {
+                stack.push(action.state);
+            }
+        }
+    }
+
+    for state_id in start_states {
+        for (characters, action) in &lex_table.states[state_id].advance_actions {
+            if action.in_main_token {
+                for token in &reachable_tokens[action.state] {
+                    let entry = result.entry(*token).or_insert(CharacterSet::empty());
+                    *entry = entry.clone().add(characters);
+                }
+            }
+        }
+    }
+}
+
+// The names of the entries in the `TokenType` enum of an external scanner, in the
+// order of the grammar's `externals`.
+pub(crate) fn external_token_type_names(syntax_grammar: &SyntaxGrammar) -> Vec<String> {
+    syntax_grammar
+        .external_tokens
+        .iter()
+        .map(|token| sanitize_identifier(&token.name).to_uppercase())
+        .collect()
+}
+
+// Convert a set of characters into inclusive ranges of code points. The null character
+// is used to represent the end of the input, so it is never included.
+fn character_set_ranges(characters: &CharacterSet) -> Vec<(u32, u32)> {
+    let mut result: Vec<(u32, u32)> = Vec::new();
+    match characters {
+        CharacterSet::Include(chars) => {
+            for c in chars.iter().map(|c| *c as u32).filter(|c| *c != 0) {
+                match result.last_mut() {
+                    Some(range) if range.1 + 1 == c => range.1 = c,
+                    _ => result.push((c, c)),
+                }
+            }
+        }
+        CharacterSet::Exclude(chars) => {
+            let mut start = 1;
+            for c in chars.iter().map(|c| *c as u32) {
+                if c > start {
+                    result.push((start, c - 1));
+                }
+                start = cmp::max(start, c + 1);
+            }
+            if start <= char::MAX as u32 {
+                result.push((start, char::MAX as u32));
+            }
+        }
+    }
+    result
+}
+
+/// Returns a String of C code for the given components of a parser, along with a
+/// description of the symbols that the code defines.
 ///
 /// # Arguments
 ///
@@ -1324,12 +1732,15 @@ pub(crate) fn render_c_code(
     lexical_grammar: LexicalGrammar,
     simple_aliases: AliasMap,
     next_abi: bool,
-) -> String {
+    small_state_threshold: SmallStateThreshold,
+) -> (String, SymbolMap, LexMetadata, TableSplit) {
     Generator {
         buffer: String::new(),
         indent_level: 0,
         language_name: name.to_string(),
         large_state_count: 0,
+        small_state_threshold,
+        table_split: TableSplit::default(),
         parse_table,
         main_lex_table,
         keyword_lex_table,
@@ -1,5 +1,6 @@
 use super::nfa::CharacterSet;
 use super::rules::{Alias, Associativity, Symbol, TokenSet};
+use serde_derive::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashMap};
 pub(crate) type ProductionInfoId = usize;
 pub(crate) type ParseStateId = usize;
@@ -68,19 +69,19 @@ pub(crate) struct ParseTable {
     pub external_lex_states: Vec<TokenSet>,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub(crate) struct AdvanceAction {
     pub state: LexStateId,
     pub in_main_token: bool,
 }
 
-#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub(crate) struct LexState {
     pub accept_action: Option<Symbol>,
     pub advance_actions: Vec<(CharacterSet, AdvanceAction)>,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) struct LexTable {
     pub states: Vec<LexState>,
 }
@@ -0,0 +1,8 @@
+; Syntax highlighting queries for PARSER_NAME.
+;
+; Each pattern captures syntax nodes with the name of a highlight, e.g.:
+;
+;   (comment) @comment
+;   "return" @keyword
+;
+; `tree-sitter generate` checks these queries against the generated parser.
@@ -0,0 +1,29 @@
+#include <tree_sitter/parser.h>
+#include <assert.h>
+
+// The maximum number of bytes that `serialize` may write. This can't be larger
+// than `TREE_SITTER_SERIALIZATION_BUFFER_SIZE`.
+#define EXTERNAL_SCANNER_MAX_SERIALIZED_SIZE MAX_SERIALIZED_SIZE_VALUE
+
+enum TokenType {
+TOKEN_TYPES};
+
+void *tree_sitter_PARSER_NAME_external_scanner_create() {
+  return NULL;
+}
+
+void tree_sitter_PARSER_NAME_external_scanner_destroy(void *payload) {
+}
+
+unsigned tree_sitter_PARSER_NAME_external_scanner_serialize(void *payload, char *buffer) {
+  unsigned length = 0;
+  assert(length <= EXTERNAL_SCANNER_MAX_SERIALIZED_SIZE);
+  return length;
+}
+
+void tree_sitter_PARSER_NAME_external_scanner_deserialize(void *payload, const char *buffer, unsigned length) {
+}
+
+bool tree_sitter_PARSER_NAME_external_scanner_scan(void *payload, TSLexer *lexer, const bool *valid_symbols) {
+  return false;
+}
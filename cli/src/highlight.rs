@@ -297,7 +297,8 @@ pub fn ansi(
     let mut style_stack = vec![theme.default_style().ansi];
     for event in events {
         match event? {
-            HighlightEvent::HighlightStart(highlight) => {
+            HighlightEvent::HighlightStart(highlight)
+            | HighlightEvent::HighlightStartWithPattern { highlight, .. } => {
                 style_stack.push(theme.styles[highlight.0].ansi);
             }
             HighlightEvent::HighlightEnd => {
@@ -310,6 +311,7 @@ pub fn ansi(
                     .paint(&source[start..end])
                     .write_to(&mut stdout)?;
             }
+            HighlightEvent::LayerStart { .. } | HighlightEvent::LayerEnd => {}
         }
     }
 
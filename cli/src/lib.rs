@@ -7,6 +7,8 @@ pub mod logger;
 pub mod parse;
 pub mod query;
 pub mod test;
+#[cfg(any(test, feature = "test-loader"))]
+pub mod test_loader;
 pub mod util;
 pub mod wasm;
 pub mod web_ui;
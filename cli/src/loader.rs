@@ -4,7 +4,7 @@ use once_cell::unsync::OnceCell;
 use regex::{Regex, RegexBuilder};
 use serde_derive::Deserialize;
 use std::collections::HashMap;
-use std::io::BufReader;
+use std::io::{self, BufReader};
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::time::SystemTime;
@@ -220,7 +220,6 @@ impl Loader {
     pub fn load_language_at_path(&self, src_path: &Path, header_path: &Path) -> Result<Language> {
         let grammar_path = src_path.join("grammar.json");
         let parser_path = src_path.join("parser.c");
-        let mut scanner_path = src_path.join("scanner.c");
 
         #[derive(Deserialize)]
         struct GrammarJSON {
@@ -231,22 +230,11 @@ impl Loader {
         let grammar_json: GrammarJSON = serde_json::from_reader(BufReader::new(&mut grammar_file))
             .map_err(Error::wrap(|| "Failed to parse grammar.json"))?;
 
-        let scanner_path = if scanner_path.exists() {
-            Some(scanner_path)
-        } else {
-            scanner_path.set_extension("cc");
-            if scanner_path.exists() {
-                Some(scanner_path)
-            } else {
-                None
-            }
-        };
-
         self.load_language_from_sources(
             &grammar_json.name,
             &header_path,
             &parser_path,
-            &scanner_path,
+            &scanner_path_at_path(src_path),
         )
     }
 
@@ -311,9 +299,16 @@ impl Loader {
                 command.arg("-xc").arg(parser_path);
             }
 
-            let output = command
-                .output()
-                .map_err(Error::wrap(|| "Failed to execute C compiler"))?;
+            let output = command.output().map_err(|error| {
+                if error.kind() == io::ErrorKind::NotFound {
+                    Error::new(format!(
+                        "Failed to execute C compiler {:?}, because it was not found. Install a C compiler, or set the CXX environment variable to the path of one.",
+                        compiler.path()
+                    ))
+                } else {
+                    Error::wrap(|| "Failed to execute C compiler")(error)
+                }
+            })?;
             if !output.status.success() {
                 return Err(Error::new(format!(
                     "Parser compilation failed.\nStdout: {}\nStderr: {}",
@@ -526,6 +521,21 @@ impl LanguageConfiguration {
     }
 }
 
+// Find the external scanner in a parser's `src` directory, if it has one.
+pub(crate) fn scanner_path_at_path(src_path: &Path) -> Option<PathBuf> {
+    let mut scanner_path = src_path.join("scanner.c");
+    if scanner_path.exists() {
+        Some(scanner_path)
+    } else {
+        scanner_path.set_extension("cc");
+        if scanner_path.exists() {
+            Some(scanner_path)
+        } else {
+            None
+        }
+    }
+}
+
 fn needs_recompile(
     lib_path: &Path,
     parser_c_path: &Path,
@@ -1,6 +1,6 @@
 use clap::{App, AppSettings, Arg, SubCommand};
 use error::Error;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::{env, fs, u64};
 use tree_sitter::Language;
@@ -46,7 +46,112 @@ fn run() -> error::Result<()> {
                         .value_name("rule-name")
                         .takes_value(true),
                 )
-                .arg(Arg::with_name("no-minimize").long("no-minimize")),
+                .arg(Arg::with_name("report-dynamic-precedence").long("report-dynamic-precedence"))
+                .arg(Arg::with_name("report-keywords").long("report-keywords"))
+                .arg(Arg::with_name("report-externals").long("report-externals"))
+                .arg(Arg::with_name("report-auxiliary").long("report-auxiliary"))
+                .arg(
+                    Arg::with_name("report-token")
+                        .long("report-token")
+                        .value_name("token-name")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(Arg::with_name("report-complexity").long("report-complexity"))
+                .arg(
+                    Arg::with_name("report-complexity-limit")
+                        .long("report-complexity-limit")
+                        .value_name("count")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("report-complexity-format")
+                        .long("report-complexity-format")
+                        .takes_value(true)
+                        .possible_values(&["text", "json"]),
+                )
+                .arg(
+                    Arg::with_name("cache-dir")
+                        .long("cache-dir")
+                        .value_name("path")
+                        .takes_value(true),
+                )
+                .arg(Arg::with_name("cache").long("cache"))
+                .arg(Arg::with_name("no-cache").long("no-cache"))
+                .arg(Arg::with_name("stats").long("stats"))
+                .arg(Arg::with_name("node-types-only").long("node-types-only"))
+                .arg(Arg::with_name("symbol-map").long("symbol-map"))
+                .arg(Arg::with_name("lex-metadata").long("lex-metadata"))
+                .arg(Arg::with_name("scanner").long("scanner"))
+                .arg(Arg::with_name("compile-commands").long("compile-commands"))
+                .arg(
+                    Arg::with_name("grammar-json")
+                        .long("grammar-json")
+                        .takes_value(true)
+                        .possible_values(&["auto", "always", "never"]),
+                )
+                .arg(
+                    Arg::with_name("emit-grammar-json")
+                        .long("emit-grammar-json")
+                        .value_name("path")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("verify-grammar-json")
+                        .long("verify-grammar-json")
+                        .conflicts_with("emit-grammar-json"),
+                )
+                .arg(Arg::with_name("no-check-queries").long("no-check-queries"))
+                .arg(
+                    Arg::with_name("strict-queries")
+                        .long("strict-queries")
+                        .conflicts_with("no-check-queries"),
+                )
+                .arg(Arg::with_name("no-minimize").long("no-minimize"))
+                .arg(
+                    Arg::with_name("small-state-threshold")
+                        .long("small-state-threshold")
+                        .value_name("count")
+                        .takes_value(true)
+                        .requires("next-abi"),
+                )
+                .arg(
+                    Arg::with_name("optimize-table-split")
+                        .long("optimize-table-split")
+                        .requires("next-abi")
+                        .conflicts_with("small-state-threshold"),
+                )
+                .arg(
+                    Arg::with_name("max-rule-productions")
+                        .long("max-rule-productions")
+                        .value_name("count")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("max-productions")
+                        .long("max-productions")
+                        .value_name("count")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("warnings-as-errors")
+                        .long("warnings-as-errors")
+                        .value_name("codes")
+                        .takes_value(true)
+                        .min_values(0)
+                        .require_equals(true)
+                        .use_delimiter(true),
+                )
+                .arg(Arg::with_name("json-errors").long("json-errors"))
+                .arg(
+                    Arg::with_name("progress")
+                        .long("progress")
+                        .value_name("format")
+                        .takes_value(true)
+                        .require_equals(true)
+                        .possible_values(&["json"]),
+                ),
         )
         .subcommand(
             SubCommand::with_name("parse")
@@ -110,6 +215,11 @@ fn run() -> error::Result<()> {
                 .arg(Arg::with_name("scope").long("scope").takes_value(true))
                 .arg(Arg::with_name("html").long("html").short("h"))
                 .arg(Arg::with_name("time").long("time").short("t"))
+                .arg(
+                    Arg::with_name("query-layout")
+                        .long("query-layout")
+                        .help("Print the highlighting query, annotated with the section and captures of each pattern, instead of highlighting"),
+                )
                 .arg(Arg::with_name("q").short("q")),
         )
         .subcommand(
@@ -151,13 +261,122 @@ fn run() -> error::Result<()> {
         if matches.is_present("log") {
             logger::init();
         }
-        let next_abi = matches.is_present("next-abi");
-        generate::generate_parser_in_directory(
-            &current_dir,
+        if let Some(output_path) = matches.value_of("emit-grammar-json") {
+            return generate::emit_grammar_json(&current_dir, grammar_path, Path::new(output_path));
+        }
+        if matches.is_present("verify-grammar-json") {
+            return generate::verify_grammar_json(&current_dir, grammar_path);
+        }
+        let report_complexity = if matches.is_present("report-complexity") {
+            Some(
+                matches
+                    .value_of("report-complexity-limit")
+                    .map_or(Ok(10), |n| usize::from_str_radix(n, 10))
+                    .map_err(|e| Error::new(format!("Invalid complexity limit: {}", e)))?,
+            )
+        } else {
+            None
+        };
+        let cache_dir = if matches.is_present("no-cache") {
+            None
+        } else if let Some(path) = matches.value_of("cache-dir") {
+            Some(PathBuf::from(path))
+        } else if matches.is_present("cache") {
+            Some(config::Config::get_path(&home_dir).join("cache"))
+        } else {
+            None
+        };
+        let warnings_as_errors = if matches.is_present("warnings-as-errors") {
+            Some(
+                matches
+                    .values_of("warnings-as-errors")
+                    .map_or(Vec::new(), |codes| codes.collect::<Vec<_>>()),
+            )
+        } else {
+            None
+        };
+        let directory_options = generate::DirectoryOptions {
             grammar_path,
-            next_abi,
+            node_types_only: matches.is_present("node-types-only"),
+            validate_queries: !matches.is_present("no-check-queries"),
+            strict_queries: matches.is_present("strict-queries"),
+            compile_commands: matches.is_present("compile-commands"),
+            grammar_json: match matches.value_of("grammar-json") {
+                Some("always") => generate::WriteGrammarJson::Always,
+                Some("never") => generate::WriteGrammarJson::Never,
+                _ => generate::WriteGrammarJson::Auto,
+            },
+            warnings_as_errors: warnings_as_errors.as_deref(),
+        };
+        let report_token_names = matches
+            .values_of("report-token")
+            .map_or(Vec::new(), |names| names.collect::<Vec<_>>());
+        let json_errors = matches.is_present("json-errors");
+        let mut production_limits = generate::ProductionLimits::default();
+        if let Some(count) = matches.value_of("max-rule-productions") {
+            production_limits.max_productions_per_rule = usize::from_str_radix(count, 10)
+                .map_err(|e| Error::new(format!("Invalid production limit: {}", e)))?;
+        }
+        if let Some(count) = matches.value_of("max-productions") {
+            production_limits.max_productions = usize::from_str_radix(count, 10)
+                .map_err(|e| Error::new(format!("Invalid production limit: {}", e)))?;
+        }
+        let small_state_threshold = if let Some(count) = matches.value_of("small-state-threshold") {
+            generate::SmallStateThreshold::Fixed(
+                usize::from_str_radix(count, 10)
+                    .map_err(|e| Error::new(format!("Invalid small state threshold: {}", e)))?,
+            )
+        } else if matches.is_present("optimize-table-split") {
+            generate::SmallStateThreshold::Optimize
+        } else {
+            generate::SmallStateThreshold::Default
+        };
+        let progress = if matches.is_present("progress") {
+            Some(generate::Progress::stderr())
+        } else {
+            None
+        };
+        let options = generate::GenerateOptions {
+            next_abi: matches.is_present("next-abi"),
             report_symbol_name,
-        )?;
+            report_dynamic_precedence: matches.is_present("report-dynamic-precedence"),
+            report_keywords: matches.is_present("report-keywords"),
+            report_externals: matches.is_present("report-externals"),
+            report_complexity,
+            report_complexity_json: matches.value_of("report-complexity-format") == Some("json"),
+            report_auxiliary: matches.is_present("report-auxiliary"),
+            report_token_names: &report_token_names,
+            cache_dir: cache_dir.as_ref().map(PathBuf::as_path),
+            symbol_map: matches.is_present("symbol-map"),
+            lex_metadata: matches.is_present("lex-metadata"),
+            scanner: matches.is_present("scanner"),
+            stats: matches.is_present("stats"),
+            progress: progress.as_ref(),
+            production_limits,
+            small_state_threshold,
+        };
+        let mut diagnostics = generate::Diagnostics::new();
+        let result = generate::generate_parser_in_directory(
+            &current_dir,
+            &directory_options,
+            &options,
+            &mut diagnostics,
+        );
+        if json_errors {
+            // The errors are reported in the JSON, so exit without printing them again.
+            if let Err(error) = &result {
+                if !diagnostics.has_errors() {
+                    diagnostics.error("generate-failed", error.message());
+                }
+            }
+            println!("{}", diagnostics.to_json());
+            if result.is_err() {
+                exit(1);
+            }
+        } else {
+            eprint!("{}", diagnostics);
+            result?;
+        }
     } else if let Some(matches) = matches.subcommand_matches("test") {
         let debug = matches.is_present("debug");
         let debug_graph = matches.is_present("debug-graph");
@@ -269,7 +488,12 @@ fn run() -> error::Result<()> {
             if let Some(highlight_config) =
                 language_config.highlight_config(&config.theme.highlighter, language)?
             {
-                if html_mode {
+                if matches.is_present("query-layout") {
+                    print!(
+                        "{}",
+                        highlight_config.debug_query_layout(config.theme.highlighter.names())
+                    );
+                } else if html_mode {
                     highlight::html(&loader, &config.theme, &source, highlight_config, time)?;
                 } else {
                     highlight::ansi(&loader, &config.theme, &source, highlight_config, time)?;
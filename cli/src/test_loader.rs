@@ -0,0 +1,134 @@
+//! Helpers for tests that go from a grammar all the way to highlight events: generating a
+//! parser, compiling and loading it, and highlighting some code with it. This module is
+//! available to other crates with the `test-loader` feature.
+
+use crate::error::{Error, Result};
+use crate::generate::{generate_in_memory, Diagnostics, GenerateOptions, GeneratedArtifacts};
+use crate::loader::Loader;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use tree_sitter::Language;
+use tree_sitter_highlight::{
+    HighlightConfiguration, HighlightContext, HighlightEvent, Highlighter,
+};
+
+lazy_static! {
+    static ref COMPILED_LANGUAGES: Mutex<HashMap<(String, Option<String>), Language>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Generates a parser for the given grammar.json, compiles it to a shared library with the
+/// C compiler that `cc` finds, and loads it.
+///
+/// The parser's source code and library are written to a temporary directory, which is
+/// removed before this returns. Each grammar is only compiled once per process.
+pub fn compile_grammar(grammar_json: &str) -> Result<Language> {
+    compile(grammar_json, None)
+}
+
+/// Like `compile_grammar`, but also compiles the external scanner at the given path.
+pub fn compile_grammar_with_scanner(grammar_json: &str, scanner_path: &Path) -> Result<Language> {
+    compile(grammar_json, Some(scanner_path))
+}
+
+/// Compiles and loads a parser that was generated with `generate_in_memory`, along with
+/// an external scanner, if a path to one is given. Unlike `compile_grammar`, this
+/// compiles the parser every time that it's called.
+pub fn compile_artifacts(
+    artifacts: &GeneratedArtifacts,
+    scanner_path: Option<&Path>,
+) -> Result<Language> {
+    // The shared library can be removed once it is loaded, because the loader never
+    // unloads it.
+    let dir =
+        tempfile::tempdir().map_err(Error::wrap(|| "Failed to create a temporary directory"))?;
+    fs::create_dir(dir.path().join("tree_sitter"))?;
+    fs::write(
+        dir.path().join("tree_sitter").join("parser.h"),
+        &artifacts.parser_header,
+    )?;
+    let parser_path = dir.path().join("parser.c");
+    fs::write(&parser_path, &artifacts.c_files[0].1)?;
+    Loader::new(dir.path().to_owned()).load_language_from_sources(
+        &artifacts.language_name,
+        dir.path(),
+        &parser_path,
+        &scanner_path.map(Path::to_owned),
+    )
+}
+
+/// Creates a `HighlightConfiguration` from query strings, reporting any error in the
+/// queries along with the section that contains it.
+pub fn highlight_config(
+    highlighter: &Highlighter,
+    language: Language,
+    highlights_query: &str,
+    injections_query: &str,
+    locals_query: &str,
+) -> Result<HighlightConfiguration> {
+    Ok(highlighter.load_configuration(
+        language,
+        highlights_query,
+        injections_query,
+        locals_query,
+    )?)
+}
+
+/// Highlights the given source code, and returns each piece of it along with the names of
+/// the highlights that contain it, from outermost to innermost. Injected languages are
+/// looked up with the given callback.
+pub fn highlight_source<'a, 'b>(
+    highlighter: &'a Highlighter,
+    config: &'b HighlightConfiguration,
+    source: &'a str,
+    injection_callback: impl Fn(&str) -> Option<&'b HighlightConfiguration> + 'b,
+) -> Result<Vec<(&'a str, Vec<&'a str>)>> {
+    let mut context = HighlightContext::new();
+    let events = highlighter.highlight(&mut context, config, source.as_bytes(), None, |name| {
+        injection_callback(name)
+    })?;
+    let mut highlights = Vec::new();
+    let mut result = Vec::new();
+    for event in events {
+        match event? {
+            HighlightEvent::HighlightStart(highlight)
+            | HighlightEvent::HighlightStartWithPattern { highlight, .. } => {
+                highlights.push(highlighter.names()[highlight.0].as_str())
+            }
+            HighlightEvent::HighlightEnd => {
+                highlights.pop();
+            }
+            HighlightEvent::Source { start, end } => {
+                result.push((&source[start..end], highlights.clone()));
+            }
+            HighlightEvent::LayerStart { .. } | HighlightEvent::LayerEnd => {}
+        }
+    }
+    Ok(result)
+}
+
+fn compile(grammar_json: &str, scanner_path: Option<&Path>) -> Result<Language> {
+    let scanner_code = match scanner_path {
+        Some(path) => Some(
+            fs::read_to_string(path)
+                .map_err(Error::wrap(|| format!("Failed to read {:?}", path)))?,
+        ),
+        None => None,
+    };
+    let key = (grammar_json.to_string(), scanner_code);
+    if let Some(language) = COMPILED_LANGUAGES.lock().unwrap().get(&key) {
+        return Ok(*language);
+    }
+
+    let options = GenerateOptions {
+        next_abi: true,
+        ..Default::default()
+    };
+    let artifacts = generate_in_memory(grammar_json, &options, &mut Diagnostics::new())?;
+    let language = compile_artifacts(&artifacts, scanner_path)?;
+    COMPILED_LANGUAGES.lock().unwrap().insert(key, language);
+    Ok(language)
+}
@@ -6,6 +6,7 @@ use super::helpers::scope_sequence::ScopeSequence;
 use crate::generate;
 use crate::parse::perform_edit;
 use crate::test::{parse_tests, print_diff, print_diff_key, strip_sexp_fields, TestEntry};
+use crate::test_loader::compile_artifacts;
 use crate::util;
 use lazy_static::lazy_static;
 use std::{env, fs, time, usize};
@@ -281,6 +282,130 @@ fn test_feature_corpus_files() {
     }
 }
 
+// The threshold that splits the parse states between the large and the small parse table
+// only affects how the parse table is stored. So each test grammar's corpus should be
+// parsed in the same way with every state in the large table, and with every state that
+// can be in the small table there.
+#[test]
+fn test_feature_corpus_files_with_each_table_split() {
+    for entry in fs::read_dir(fixtures_dir().join("test_grammars")).unwrap() {
+        let entry = entry.unwrap();
+        let test_path = entry.path();
+        if !entry.metadata().unwrap().is_dir() || test_path.join("expected_error.txt").exists() {
+            continue;
+        }
+        let language_name = entry.file_name();
+        let language_name = language_name.to_str().unwrap();
+        if let Some(filter) = LANGUAGE_FILTER.as_ref() {
+            if language_name != filter.as_str() {
+                continue;
+            }
+        }
+
+        let grammar_json = fs::read_to_string(test_path.join("grammar.json")).unwrap();
+        let scanner_path = Some(test_path.join("scanner.c")).filter(|path| path.exists());
+        let tests = flatten_tests(parse_tests(&test_path.join("corpus.txt")).unwrap());
+        for threshold in &[0, usize::MAX] {
+            let options = generate::GenerateOptions {
+                next_abi: true,
+                small_state_threshold: generate::SmallStateThreshold::Fixed(*threshold),
+                ..Default::default()
+            };
+            let artifacts = generate::generate_in_memory(
+                &grammar_json,
+                &options,
+                &mut generate::Diagnostics::new(),
+            )
+            .unwrap();
+            let language = compile_artifacts(&artifacts, scanner_path.as_deref()).unwrap();
+            let mut parser = Parser::new();
+            parser.set_language(language).unwrap();
+            for (name, input, expected_output, has_fields) in &tests {
+                let tree = parser.parse(input, None).unwrap();
+                let mut actual_output = tree.root_node().to_sexp();
+                if !has_fields {
+                    actual_output = strip_sexp_fields(actual_output);
+                }
+                assert_eq!(
+                    &actual_output, expected_output,
+                    "{:?} with a small state threshold of {}: {:?}",
+                    language_name, threshold, name
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_generate_regressions() {
+    // Each of these grammars used to cause a panic or a hang in the generator.
+    // Generating them should either succeed or fail with an error.
+    let regressions_dir = fixtures_dir().join("generate_regressions");
+
+    for entry in fs::read_dir(&regressions_dir).unwrap() {
+        let entry = entry.unwrap();
+        let grammar_name = entry.file_name();
+        let grammar_name = grammar_name.to_str().unwrap();
+        if !grammar_name.ends_with(".json") {
+            continue;
+        }
+
+        eprintln!("regression grammar: {:?}", grammar_name);
+        let grammar_json = fs::read_to_string(entry.path()).unwrap();
+        let _ = generate::generate_parser_for_grammar(&grammar_json);
+    }
+}
+
+#[test]
+fn test_production_counts_of_fixture_grammars() {
+    // The limits on the number of productions should be far above what any
+    // real grammar needs.
+    let limits = generate::ProductionLimits::default();
+    let mut grammar_paths = Vec::new();
+    for entry in fs::read_dir(fixtures_dir().join("grammars")).unwrap() {
+        grammar_paths.push(entry.unwrap().path().join("src").join("grammar.json"));
+    }
+    for entry in fs::read_dir(fixtures_dir().join("test_grammars")).unwrap() {
+        let test_path = entry.unwrap().path();
+        if !test_path.join("expected_error.txt").exists() {
+            grammar_paths.push(test_path.join("grammar.json"));
+        }
+    }
+
+    for grammar_path in grammar_paths {
+        if !grammar_path.exists() {
+            continue;
+        }
+        let grammar_json = fs::read_to_string(&grammar_path).unwrap();
+        let options = generate::GenerateOptions {
+            stats: true,
+            ..Default::default()
+        };
+        let stats = generate::generate_in_memory(
+            &grammar_json,
+            &options,
+            &mut generate::Diagnostics::new(),
+        )
+        .unwrap()
+        .stats
+        .unwrap();
+        let max_count = stats.largest_rule.map_or(0, |(_, count)| count);
+        let total_count = stats.production_count;
+        assert!(
+            max_count * 10 <= limits.max_productions_per_rule,
+            "{:?} has a rule with {} productions",
+            grammar_path,
+            max_count
+        );
+        assert!(
+            total_count * 10 <= limits.max_productions,
+            "{:?} has {} productions",
+            grammar_path,
+            total_count
+        );
+    }
+}
+
 fn check_consistent_sizes(tree: &Tree, input: &Vec<u8>) {
     fn check(node: Node, line_offsets: &Vec<usize>) {
         let start_byte = node.start_byte();
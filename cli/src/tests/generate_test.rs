@@ -0,0 +1,1138 @@
+use super::helpers::fixtures::fixtures_dir;
+use crate::error::Result;
+use crate::generate::{
+    generate_in_memory, generate_node_types_for_grammar, generate_parser_for_grammar,
+    generate_parser_in_directory, Diagnostics, DirectoryOptions, GenerateOptions, Progress,
+    Severity, SmallStateThreshold, TableSplit, WriteGrammarJson,
+};
+use crate::test_loader::compile_artifacts;
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use tempfile::TempDir;
+use tree_sitter::Parser;
+
+#[test]
+fn test_node_types_generation_without_parse_tables() {
+    let grammar_path = test_grammar_path("inlined_rules_with_fields");
+    let options = GenerateOptions {
+        next_abi: true,
+        ..Default::default()
+    };
+    let repo_dir = tempfile::tempdir().unwrap();
+    generate_in_repo(
+        repo_dir.path(),
+        &grammar_path,
+        DirectoryOptions::default(),
+        &options,
+    )
+    .unwrap();
+    let node_types_json = read_src_file(repo_dir.path(), "node-types.json");
+    assert_eq!(
+        generate_node_types_for_grammar(&fs::read_to_string(&grammar_path).unwrap()).unwrap(),
+        node_types_json
+    );
+
+    let repo_dir = tempfile::tempdir().unwrap();
+    let directory_options = DirectoryOptions {
+        node_types_only: true,
+        ..Default::default()
+    };
+    generate_in_repo(repo_dir.path(), &grammar_path, directory_options, &options).unwrap();
+    assert_eq!(
+        read_src_file(repo_dir.path(), "node-types.json"),
+        node_types_json
+    );
+    assert!(!repo_dir.path().join("src").join("parser.c").exists());
+
+    // Node types can be generated for a grammar whose parse table can't be built.
+    let grammar_json = fs::read_to_string(test_grammar_path("conflicting_precedence")).unwrap();
+    assert!(generate_parser_for_grammar(&grammar_json).is_err());
+    assert!(generate_node_types_for_grammar(&grammar_json)
+        .unwrap()
+        .contains("\"type\": \"expression\""));
+}
+
+#[test]
+fn test_symbol_map_generation() {
+    let (_grammar_dir, grammar_path) = write_grammar(
+        r#"{
+            "name": "symbol_map",
+            "externals": [
+                {"type": "SYMBOL", "name": "_indent"},
+                {"type": "SYMBOL", "name": "comment"}
+            ],
+            "extras": [
+                {"type": "PATTERN", "value": "\\s"},
+                {"type": "SYMBOL", "name": "comment"}
+            ],
+            "rules": {
+                "program": {
+                    "type": "REPEAT",
+                    "content": {"type": "SYMBOL", "name": "statement"}
+                },
+                "statement": {
+                    "type": "SEQ",
+                    "members": [
+                        {
+                            "type": "ALIAS",
+                            "value": "variable",
+                            "named": true,
+                            "content": {"type": "SYMBOL", "name": "identifier"}
+                        },
+                        {"type": "STRING", "value": "="},
+                        {"type": "SYMBOL", "name": "_indent"},
+                        {"type": "SYMBOL", "name": "identifier"},
+                        {"type": "STRING", "value": ";"}
+                    ]
+                },
+                "identifier": {"type": "PATTERN", "value": "[a-z]+"}
+            }
+        }"#,
+    );
+
+    let generate = || {
+        let repo_dir = tempfile::tempdir().unwrap();
+        let options = GenerateOptions {
+            next_abi: true,
+            symbol_map: true,
+            ..Default::default()
+        };
+        generate_in_repo(
+            repo_dir.path(),
+            &grammar_path,
+            DirectoryOptions::default(),
+            &options,
+        )
+        .unwrap();
+        (
+            read_src_file(repo_dir.path(), "parser.c"),
+            read_src_file(repo_dir.path(), "symbol-map.json"),
+        )
+    };
+
+    let (parser_code, symbol_map_json) = generate();
+    assert_eq!(generate().1, symbol_map_json);
+
+    let symbol_map: serde_json::Value = serde_json::from_str(&symbol_map_json).unwrap();
+    let symbols = symbol_map["symbols"].as_array().unwrap();
+    let find_symbol = |identifier: &str| {
+        symbols
+            .iter()
+            .find(|symbol| symbol["identifier"] == identifier)
+            .unwrap()
+    };
+    for (i, symbol) in symbols.iter().enumerate() {
+        assert_eq!(symbol["id"], i);
+        if i > 0 {
+            assert!(parser_code.contains(&format!(
+                "{} = {},",
+                symbol["identifier"].as_str().unwrap(),
+                i
+            )));
+        }
+    }
+
+    let end = &symbols[0];
+    assert_eq!(end["identifier"], "ts_builtin_sym_end");
+    assert_eq!(end["kind"], "terminal");
+
+    let identifier = find_symbol("sym_identifier");
+    assert_eq!(identifier["kind"], "terminal");
+    assert_eq!(identifier["name"], "identifier");
+    assert_eq!(identifier["named"], true);
+    assert_eq!(
+        identifier["aliases"],
+        serde_json::json!([{"name": "variable", "named": true}])
+    );
+    assert!(parser_code.contains("[sym_identifier] = \"identifier\","));
+
+    let variable = find_symbol("alias_sym_variable");
+    assert_eq!(variable["kind"], "alias");
+    assert_eq!(variable["name"], "variable");
+    assert!(parser_code.contains("[alias_sym_variable] = \"variable\","));
+
+    let indent = find_symbol("sym__indent");
+    assert_eq!(indent["kind"], "external");
+    assert_eq!(indent["named"], false);
+    assert_eq!(indent["external_index"], 0);
+    assert!(parser_code.contains("ts_external_token__indent = 0,"));
+    assert!(parser_code.contains("[ts_external_token__indent] = sym__indent,"));
+
+    let comment = find_symbol("sym_comment");
+    assert_eq!(comment["kind"], "external");
+    assert_eq!(comment["external_index"], 1);
+    assert!(parser_code.contains("[ts_external_token_comment] = sym_comment,"));
+
+    assert_eq!(find_symbol("sym_statement")["kind"], "non-terminal");
+    assert_eq!(
+        find_symbol("aux_sym___program_repeat1")["kind"],
+        "auxiliary"
+    );
+    assert!(find_symbol("sym_statement").get("external_index").is_none());
+}
+
+#[test]
+fn test_generation_in_memory_matches_generation_in_directory() {
+    let grammar_json = r#"{
+        "name": "in_memory",
+        "extras": [{"type": "PATTERN", "value": "\\s"}],
+        "rules": {
+            "program": {
+                "type": "REPEAT",
+                "content": {"type": "SYMBOL", "name": "assignment"}
+            },
+            "assignment": {
+                "type": "SEQ",
+                "members": [
+                    {
+                        "type": "FIELD",
+                        "name": "left",
+                        "content": {"type": "SYMBOL", "name": "identifier"}
+                    },
+                    {"type": "STRING", "value": "="},
+                    {"type": "SYMBOL", "name": "identifier"}
+                ]
+            },
+            "identifier": {"type": "PATTERN", "value": "[a-z]+"}
+        }
+    }"#;
+    let (repo_dir, grammar_path) = write_grammar(grammar_json);
+
+    for next_abi in &[true, false] {
+        let options = GenerateOptions {
+            next_abi: *next_abi,
+            symbol_map: true,
+            ..Default::default()
+        };
+        generate_in_repo(
+            repo_dir.path(),
+            &grammar_path,
+            DirectoryOptions::default(),
+            &options,
+        )
+        .unwrap();
+        let artifacts =
+            generate_in_memory(grammar_json, &options, &mut Diagnostics::new()).unwrap();
+        assert_eq!(artifacts.language_name, "in_memory");
+        assert!(artifacts.stats.is_none());
+
+        let read = |path: &str| read_src_file(repo_dir.path(), path);
+        assert_eq!(artifacts.c_files.len(), 1);
+        for (name, c_code) in &artifacts.c_files {
+            assert_eq!(&read(name), c_code);
+        }
+        assert_eq!(read("node-types.json"), artifacts.node_types_json);
+        assert_eq!(read("tree_sitter/parser.h"), artifacts.parser_header);
+        assert_eq!(read("symbol-map.json"), artifacts.symbol_map_json.unwrap());
+        assert_eq!(
+            repo_dir.path().join("src").join("metadata.json").exists(),
+            artifacts.metadata_json.is_some()
+        );
+        assert_eq!(
+            artifacts.parser_header.contains("small_parse_table"),
+            *next_abi
+        );
+    }
+}
+
+#[test]
+fn test_compiling_generated_parsers_for_each_abi() {
+    let grammar_path = test_grammar_path("external_tokens");
+    let scanner_path = grammar_path.with_file_name("scanner.c");
+    let grammar_json = fs::read_to_string(&grammar_path).unwrap();
+    for next_abi in &[false, true] {
+        let options = GenerateOptions {
+            next_abi: *next_abi,
+            ..Default::default()
+        };
+        let artifacts =
+            generate_in_memory(&grammar_json, &options, &mut Diagnostics::new()).unwrap();
+        let language = compile_artifacts(&artifacts, Some(&scanner_path)).unwrap();
+        assert_eq!(
+            language.version(),
+            if *next_abi {
+                tree_sitter::LANGUAGE_VERSION
+            } else {
+                tree_sitter::LANGUAGE_VERSION - 1
+            }
+        );
+
+        let mut parser = Parser::new();
+        parser.set_language(language).unwrap();
+        let tree = parser
+            .parse("x + %(sup (external) scanner?)", None)
+            .unwrap();
+        assert_eq!(
+            tree.root_node().to_sexp(),
+            "(expression (sum (expression (identifier)) (expression (string))))"
+        );
+    }
+}
+
+#[test]
+fn test_choosing_the_small_state_threshold() {
+    let grammar_json = fs::read_to_string(test_grammar_path("complexity_hotspot")).unwrap();
+    let generate = |next_abi, small_state_threshold| {
+        let options = GenerateOptions {
+            next_abi,
+            stats: true,
+            small_state_threshold,
+            ..Default::default()
+        };
+        let mut artifacts =
+            generate_in_memory(&grammar_json, &options, &mut Diagnostics::new()).unwrap();
+        let (_, c_code) = artifacts.c_files.remove(0);
+        (c_code, artifacts.stats.unwrap().table_split)
+    };
+
+    let (_, split) = generate(false, SmallStateThreshold::Default);
+    assert!(split.is_none());
+
+    // The threshold is recorded in the parser, and states with more entries than the
+    // threshold are stored in the large table.
+    let (c_code, split) = generate(true, SmallStateThreshold::Fixed(4));
+    let split = split.unwrap();
+    assert!(c_code.contains("#define SMALL_STATE_THRESHOLD 4\n"));
+    assert!(c_code.contains(&format!(
+        "#define LARGE_STATE_COUNT {}\n",
+        split.large_state_count
+    )));
+    assert!(split.large_state_count > 2 && split.small_state_count > 0);
+    assert!(split.large_table_bytes > 0 && split.small_table_bytes > 0);
+
+    let (c_code, split) = generate(true, SmallStateThreshold::Fixed(usize::MAX));
+    let split = split.unwrap();
+    assert_eq!(split.large_state_count, 2);
+    assert!(c_code.contains(&format!("#define SMALL_STATE_THRESHOLD {}\n", usize::MAX)));
+
+    // The optimized threshold produces the smallest parse tables among the candidates,
+    // which include the default threshold.
+    let (default_c_code, default_split) = generate(true, SmallStateThreshold::Default);
+    let (optimized_c_code, optimized_split) = generate(true, SmallStateThreshold::Optimize);
+    let table_bytes = |split: &Option<TableSplit>| {
+        let split = split.as_ref().unwrap();
+        split.large_table_bytes + split.small_table_bytes
+    };
+    assert!(table_bytes(&optimized_split) <= table_bytes(&default_split));
+    assert!(optimized_c_code.len() <= default_c_code.len());
+    assert!(optimized_c_code.contains(&format!(
+        "#define SMALL_STATE_THRESHOLD {}\n",
+        optimized_split.unwrap().small_state_threshold
+    )));
+}
+
+#[test]
+fn test_generating_lex_metadata() {
+    let grammar_json = fs::read_to_string(test_grammar_path("complexity_hotspot")).unwrap();
+    let options = GenerateOptions {
+        lex_metadata: true,
+        ..Default::default()
+    };
+    let artifacts = generate_in_memory(&grammar_json, &options, &mut Diagnostics::new()).unwrap();
+    let lex_metadata: serde_json::Value =
+        serde_json::from_str(&artifacts.lex_metadata_json.unwrap()).unwrap();
+    let tokens = lex_metadata["tokens"].as_array().unwrap();
+
+    let ids = tokens
+        .iter()
+        .map(|token| token["id"].as_u64().unwrap())
+        .collect::<Vec<_>>();
+    let mut sorted_ids = ids.clone();
+    sorted_ids.sort();
+    assert_eq!(ids, sorted_ids);
+
+    let token = |name: &str| {
+        tokens
+            .iter()
+            .find(|token| token["name"] == name)
+            .unwrap()
+            .clone()
+    };
+    assert_eq!(
+        token("{"),
+        serde_json::json!({
+            "id": token("{")["id"],
+            "name": "{",
+            "named": false,
+            "first_characters": [['{' as u32, '{' as u32]],
+            "single_character_punctuation": true,
+        })
+    );
+    assert_eq!(
+        token("}")["first_characters"],
+        serde_json::json!([['}' as u32, '}' as u32]])
+    );
+    assert_eq!(token("}")["single_character_punctuation"], true);
+    assert_eq!(
+        token("identifier")["first_characters"],
+        serde_json::json!([
+            ['A' as u32, 'Z' as u32],
+            ['_' as u32, '_' as u32],
+            ['a' as u32, 'z' as u32],
+        ])
+    );
+    assert_eq!(token("identifier")["single_character_punctuation"], false);
+    assert_eq!(
+        token("fn")["first_characters"],
+        serde_json::json!([['f' as u32, 'f' as u32]])
+    );
+    assert_eq!(token("fn")["single_character_punctuation"], false);
+}
+
+#[test]
+fn test_generation_progress_records() {
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let buffer = SharedBuffer::default();
+    let progress = Progress::new(Box::new(buffer.clone()));
+    let options = GenerateOptions {
+        next_abi: true,
+        progress: Some(&progress),
+        ..Default::default()
+    };
+    let repo_dir = tempfile::tempdir().unwrap();
+    generate_in_repo(
+        repo_dir.path(),
+        &test_grammar_path("inlined_rules_with_fields"),
+        DirectoryOptions::default(),
+        &options,
+    )
+    .unwrap();
+
+    let output = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+    let records = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    let mut phases = records
+        .iter()
+        .map(|record| record["phase"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    phases.dedup();
+    assert_eq!(
+        phases,
+        &[
+            "grammar",
+            "prepare",
+            "parse-table",
+            "lex-table",
+            "render",
+            "write"
+        ]
+    );
+
+    for (i, record) in records.iter().enumerate() {
+        assert!(record["current"].is_u64());
+        assert!(record["elapsed_ms"].is_u64());
+        if i > 0 {
+            assert!(record["elapsed_ms"].as_u64() >= records[i - 1]["elapsed_ms"].as_u64());
+        }
+        let is_last_of_phase =
+            !matches!(records.get(i + 1), Some(next) if next["phase"] == record["phase"]);
+        if is_last_of_phase {
+            assert_eq!(record["current"], record["total"]);
+        }
+    }
+}
+
+#[test]
+fn test_query_validation_after_generation() {
+    let grammar_json = r#"{
+        "name": "renamed_nodes",
+        "extras": [{"type": "PATTERN", "value": "\\s"}],
+        "rules": {
+            "program": {
+                "type": "REPEAT",
+                "content": {"type": "SYMBOL", "name": "identifier"}
+            },
+            "identifier": {"type": "PATTERN", "value": "[a-z]+"}
+        }
+    }"#;
+
+    // When the grammar has no queries, an empty highlights query is created.
+    let (repo_dir, grammar_path) = write_grammar(grammar_json);
+    generate_parser_with_queries(
+        repo_dir.path(),
+        &grammar_path,
+        true,
+        &mut Diagnostics::new(),
+    )
+    .unwrap();
+    let highlights_path = repo_dir.path().join("queries").join("highlights.scm");
+    assert!(fs::read_to_string(&highlights_path)
+        .unwrap()
+        .starts_with("; Syntax highlighting queries for renamed_nodes."));
+
+    // A query that refers to a node type which no longer exists is reported with
+    // its position. It's only treated as an error when the queries are strict.
+    fs::write(
+        &highlights_path,
+        "; Highlights\n(identifier) @variable\n(name) @variable\n",
+    )
+    .unwrap();
+    let mut diagnostics = Diagnostics::new();
+    generate_parser_with_queries(repo_dir.path(), &grammar_path, false, &mut diagnostics).unwrap();
+    assert_eq!(
+        diagnostics.to_string(),
+        format!(
+            "warning[invalid-query]: {}:3:2: Invalid node type `name`\n",
+            Path::new("queries").join("highlights.scm").display()
+        )
+    );
+    let error = generate_parser_with_queries(
+        repo_dir.path(),
+        &grammar_path,
+        true,
+        &mut Diagnostics::new(),
+    )
+    .unwrap_err();
+    assert_eq!(
+        error.message(),
+        format!(
+            "Queries are invalid for the generated parser:\n{}:3:2: Invalid node type `name`",
+            Path::new("queries").join("highlights.scm").display()
+        )
+    );
+}
+
+#[test]
+fn test_query_validation_after_aliasing_a_node_type() {
+    let grammar_json = r#"{
+        "name": "aliased_nodes",
+        "extras": [{"type": "PATTERN", "value": "\\s"}],
+        "rules": {
+            "program": {
+                "type": "REPEAT",
+                "content": {"type": "SYMBOL", "name": "_expression"}
+            },
+            "_expression": {
+                "type": "CHOICE",
+                "members": [
+                    {"type": "SYMBOL", "name": "identifier"},
+                    {"type": "SYMBOL", "name": "number"}
+                ]
+            },
+            "identifier": {"type": "PATTERN", "value": "[a-z]+"},
+            "number": {"type": "PATTERN", "value": "\\d+"}
+        }
+    }"#;
+
+    let (repo_dir, grammar_path) = write_grammar(grammar_json);
+    fs::create_dir_all(repo_dir.path().join("queries")).unwrap();
+    fs::write(
+        repo_dir.path().join("queries").join("highlights.scm"),
+        "; (identifier) in a comment\n(identifier) @variable\n(number) @number\n",
+    )
+    .unwrap();
+    fs::write(
+        repo_dir.path().join("queries").join("locals.scm"),
+        "(program ( identifier) @local.reference)\n",
+    )
+    .unwrap();
+    let mut diagnostics = Diagnostics::new();
+    generate_parser_with_queries(repo_dir.path(), &grammar_path, false, &mut diagnostics).unwrap();
+    assert!(diagnostics.is_empty());
+
+    // Once `identifier` is aliased as `name`, and `number` is removed, the queries that
+    // refer to them are reported, along with the alias.
+    let grammar_json = grammar_json
+        .replace(
+            r#"{"type": "SYMBOL", "name": "identifier"}"#,
+            r#"{
+                "type": "ALIAS",
+                "content": {"type": "SYMBOL", "name": "identifier"},
+                "named": true,
+                "value": "name"
+            }"#,
+        )
+        .replace(
+            r#"{"type": "SYMBOL", "name": "number"}"#,
+            r#"{"type": "STRING", "value": "0"}"#,
+        );
+    fs::write(&grammar_path, grammar_json).unwrap();
+    let mut diagnostics = Diagnostics::new();
+    generate_parser_with_queries(repo_dir.path(), &grammar_path, false, &mut diagnostics).unwrap();
+    let highlights_path = Path::new("queries").join("highlights.scm");
+    let locals_path = Path::new("queries").join("locals.scm");
+    assert_eq!(
+        diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.code == "removed-node-type")
+            .map(|diagnostic| diagnostic.to_string())
+            .collect::<Vec<_>>(),
+        vec![
+            format!(
+                "warning[removed-node-type]: {}:2:2: Node type `identifier` no longer exists; it is now aliased as `name`",
+                highlights_path.display()
+            ),
+            format!(
+                "warning[removed-node-type]: {}:3:2: Node type `number` no longer exists",
+                highlights_path.display()
+            ),
+            format!(
+                "warning[removed-node-type]: {}:1:12: Node type `identifier` no longer exists; it is now aliased as `name`",
+                locals_path.display()
+            ),
+        ]
+    );
+
+    // Once the queries have been updated, nothing is reported.
+    fs::write(
+        repo_dir.path().join("queries").join("highlights.scm"),
+        "(name) @variable\n",
+    )
+    .unwrap();
+    fs::remove_file(repo_dir.path().join("queries").join("locals.scm")).unwrap();
+    let mut diagnostics = Diagnostics::new();
+    generate_parser_with_queries(repo_dir.path(), &grammar_path, false, &mut diagnostics).unwrap();
+    assert_eq!(
+        diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.code)
+            .collect::<Vec<_>>(),
+        vec!["unused-rule"]
+    );
+}
+
+#[test]
+fn test_generate_diagnostics_from_all_phases() {
+    let grammar_json = r#"{
+        "name": "dangling_else",
+        "conflicts": [["if_statement"]],
+        "extras": [{"type": "PATTERN", "value": "\\s"}],
+        "unknown_field": true,
+        "rules": {
+            "program": {
+                "type": "REPEAT",
+                "content": {"type": "SYMBOL", "name": "statement"}
+            },
+            "statement": {
+                "type": "CHOICE",
+                "members": [
+                    {"type": "SYMBOL", "name": "if_statement"},
+                    {"type": "SYMBOL", "name": "expression"}
+                ]
+            },
+            "if_statement": {
+                "type": "CHOICE",
+                "members": [
+                    {
+                        "type": "SEQ",
+                        "members": [
+                            {"type": "STRING", "value": "if"},
+                            {"type": "SYMBOL", "name": "expression"},
+                            {"type": "SYMBOL", "name": "statement"}
+                        ]
+                    },
+                    {
+                        "type": "SEQ",
+                        "members": [
+                            {"type": "STRING", "value": "if"},
+                            {"type": "SYMBOL", "name": "expression"},
+                            {"type": "SYMBOL", "name": "statement"},
+                            {"type": "STRING", "value": "else"},
+                            {"type": "SYMBOL", "name": "statement"}
+                        ]
+                    }
+                ]
+            },
+            "expression": {"type": "PATTERN", "value": "[a-z]+"},
+            "unused": {"type": "STRING", "value": "unused"}
+        }
+    }"#;
+
+    let (repo_dir, grammar_path) = write_grammar(grammar_json);
+    fs::create_dir_all(repo_dir.path().join("queries")).unwrap();
+    fs::write(
+        repo_dir.path().join("queries").join("highlights.scm"),
+        "(identifier) @variable\n",
+    )
+    .unwrap();
+
+    // The conflict's productions all have zero dynamic precedence, which is only
+    // reported along with the dynamic precedence report.
+    let directory_options = DirectoryOptions {
+        validate_queries: true,
+        ..Default::default()
+    };
+    let options = GenerateOptions {
+        next_abi: true,
+        report_dynamic_precedence: true,
+        ..Default::default()
+    };
+    let mut diagnostics = Diagnostics::new();
+    generate_in_repo_with_diagnostics(
+        repo_dir.path(),
+        &grammar_path,
+        directory_options,
+        &options,
+        &mut diagnostics,
+    )
+    .unwrap();
+    assert_eq!(
+        diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.code)
+            .collect::<Vec<_>>(),
+        vec![
+            "unknown-grammar-field",
+            "unused-rule",
+            "unresolvable-dynamic-precedence",
+            "invalid-query",
+        ]
+    );
+    assert!(!diagnostics.has_errors());
+
+    diagnostics.promote_warnings(&["unused-rule", "invalid-query"]);
+    assert_eq!(
+        diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.severity)
+            .collect::<Vec<_>>(),
+        vec![
+            Severity::Warning,
+            Severity::Error,
+            Severity::Warning,
+            Severity::Error
+        ]
+    );
+    assert!(diagnostics.to_string().contains(
+        "error[unused-rule]: Rule `unused` is not reachable from the start rule `program`\n"
+    ));
+}
+
+#[test]
+fn test_warnings_as_errors_prevent_writing_the_parser() {
+    let grammar_json = r#"{
+        "name": "unused_rule",
+        "rules": {
+            "program": {"type": "STRING", "value": "a"},
+            "unused": {"type": "STRING", "value": "b"}
+        }
+    }"#;
+
+    let (repo_dir, grammar_path) = write_grammar(grammar_json);
+
+    let mut diagnostics = Diagnostics::new();
+    let directory_options = DirectoryOptions {
+        warnings_as_errors: Some(&["unused-rule"]),
+        ..Default::default()
+    };
+    let error = generate_in_repo_with_diagnostics(
+        repo_dir.path(),
+        &grammar_path,
+        directory_options,
+        &GenerateOptions::default(),
+        &mut diagnostics,
+    )
+    .unwrap_err();
+    assert_eq!(error.message(), "Some warnings were treated as errors");
+    assert!(diagnostics.has_errors());
+    assert!(!repo_dir.path().join("src").join("parser.c").exists());
+    assert!(!repo_dir.path().join("src").join("node-types.json").exists());
+
+    // Warnings with other codes don't prevent the parser from being written.
+    let mut diagnostics = Diagnostics::new();
+    let directory_options = DirectoryOptions {
+        warnings_as_errors: Some(&["invalid-query"]),
+        ..Default::default()
+    };
+    generate_in_repo_with_diagnostics(
+        repo_dir.path(),
+        &grammar_path,
+        directory_options,
+        &GenerateOptions::default(),
+        &mut diagnostics,
+    )
+    .unwrap();
+    assert!(!diagnostics.has_errors());
+    assert!(repo_dir.path().join("src").join("parser.c").exists());
+}
+
+#[test]
+fn test_generating_recovery_tokens() {
+    let grammar_json = r#"{
+        "name": "recovery_tokens",
+        "rules": {
+            "program": {"type": "REPEAT", "content": {"type": "SYMBOL", "name": "_statement"}},
+            "_statement": {
+                "type": "CHOICE",
+                "members": [
+                    {"type": "SYMBOL", "name": "expression_statement"},
+                    {"type": "SYMBOL", "name": "block"}
+                ]
+            },
+            "expression_statement": {
+                "type": "SEQ",
+                "members": [
+                    {"type": "SYMBOL", "name": "identifier"},
+                    {"type": "SYMBOL", "name": "semicolon"}
+                ]
+            },
+            "block": {
+                "type": "SEQ",
+                "members": [
+                    {"type": "STRING", "value": "{"},
+                    {"type": "REPEAT", "content": {"type": "SYMBOL", "name": "_statement"}},
+                    {"type": "STRING", "value": "}"}
+                ]
+            },
+            "semicolon": {"type": "STRING", "value": ";"},
+            "identifier": {"type": "PATTERN", "value": "[a-z]+"}
+        },
+        "recoveryTokens": [
+            {"type": "SYMBOL", "name": "semicolon"},
+            {"type": "STRING", "value": "}"}
+        ]
+    }"#;
+
+    let options = GenerateOptions {
+        next_abi: true,
+        ..Default::default()
+    };
+    let artifacts = generate_in_memory(grammar_json, &options, &mut Diagnostics::new()).unwrap();
+    let c_code = &artifacts.c_files[0].1;
+    assert!(c_code.contains(
+        "static TSSymbol ts_recovery_tokens[] = {\n  sym_semicolon,\n  anon_sym_RBRACE,\n};"
+    ));
+    assert!(c_code.contains(".recovery_token_count = 2,"));
+    assert!(c_code.contains(".recovery_tokens = ts_recovery_tokens,"));
+    assert!(artifacts.parser_header.contains("recovery_tokens"));
+
+    // The older ABI has no place for the table, so it can't be generated silently.
+    let options = GenerateOptions {
+        next_abi: false,
+        ..Default::default()
+    };
+    let error = generate_in_memory(grammar_json, &options, &mut Diagnostics::new()).unwrap_err();
+    assert_eq!(
+        error.message(),
+        format!(
+            "The grammar's `recoveryTokens` require language ABI version {}, but the parser is being generated for version {}. Pass `--next-abi` to use the newer ABI.",
+            tree_sitter::LANGUAGE_VERSION,
+            tree_sitter::LANGUAGE_VERSION - 1
+        )
+    );
+
+    // Grammars without recovery tokens are unaffected.
+    let (_, c_code) = generate_parser_for_grammar(
+        &grammar_json.replace("\"recoveryTokens\"", "\"unusedRecoveryTokens\""),
+    )
+    .unwrap();
+    assert!(!c_code.contains("ts_recovery_tokens"));
+}
+
+#[test]
+fn test_generating_external_scanner_skeleton() {
+    let grammar_json = r#"{
+        "name": "heredocs",
+        "rules": {
+            "program": {"type": "REPEAT", "content": {"type": "SYMBOL", "name": "heredoc"}},
+            "heredoc": {
+                "type": "SEQ",
+                "members": [
+                    {"type": "STRING", "value": "<<"},
+                    {"type": "SYMBOL", "name": "heredoc_body"},
+                    {"type": "SYMBOL", "name": "_heredoc_end"}
+                ]
+            }
+        },
+        "externals": [
+            {"type": "SYMBOL", "name": "heredoc_body"},
+            {"type": "SYMBOL", "name": "_heredoc_end"}
+        ],
+        "externalScanner": {"maxSerializedSize": 256}
+    }"#;
+
+    let options = GenerateOptions {
+        scanner: true,
+        ..Default::default()
+    };
+    let artifacts = generate_in_memory(grammar_json, &options, &mut Diagnostics::new()).unwrap();
+    let c_code = &artifacts.c_files[0].1;
+    assert!(c_code.contains("\n#define EXTERNAL_SCANNER_MAX_SERIALIZED_SIZE 256\n"));
+
+    let scanner_c = artifacts.scanner_c.unwrap();
+    assert!(scanner_c.contains("\n#define EXTERNAL_SCANNER_MAX_SERIALIZED_SIZE 256\n"));
+    assert!(scanner_c.contains("enum TokenType {\n  HEREDOC_BODY,\n  _HEREDOC_END,\n};"));
+    assert!(scanner_c.contains("unsigned tree_sitter_heredocs_external_scanner_serialize("));
+    assert!(scanner_c.contains("assert(length <= EXTERNAL_SCANNER_MAX_SERIALIZED_SIZE);"));
+
+    // Without a declared size, the skeleton uses the size of the runtime's buffer, and
+    // nothing is added to the parser.
+    let grammar_json = grammar_json.replace(r#""maxSerializedSize": 256"#, "");
+    let options = GenerateOptions {
+        scanner: true,
+        ..Default::default()
+    };
+    let artifacts = generate_in_memory(&grammar_json, &options, &mut Diagnostics::new()).unwrap();
+    assert!(!artifacts.c_files[0]
+        .1
+        .contains("EXTERNAL_SCANNER_MAX_SERIALIZED_SIZE"));
+    assert!(artifacts
+        .scanner_c
+        .unwrap()
+        .contains("\n#define EXTERNAL_SCANNER_MAX_SERIALIZED_SIZE 1024\n"));
+
+    // Sizes larger than the runtime's buffer are rejected.
+    let grammar_json = grammar_json.replace(
+        r#""externalScanner": {}"#,
+        r#""externalScanner": {"maxSerializedSize": 4096}"#,
+    );
+    let error = generate_in_memory(
+        &grammar_json,
+        &GenerateOptions::default(),
+        &mut Diagnostics::new(),
+    )
+    .unwrap_err();
+    assert!(error
+        .message()
+        .contains("`externalScanner.maxSerializedSize` is 4096"));
+}
+
+#[test]
+fn test_writing_grammar_json() {
+    let grammar_js = "module.exports = grammar({\n\
+        name: 'grammar_json_test',\n\
+        rules: {program: $ => repeat($.word), word: $ => /[a-z]+/}\n\
+    });\n";
+    let grammar_dir = tempfile::tempdir().unwrap();
+    let grammar_js_path = grammar_dir.path().join("grammar.js");
+    let grammar_json_path = grammar_dir.path().join("grammar.json");
+    fs::write(&grammar_js_path, grammar_js).unwrap();
+    fs::write(
+        &grammar_json_path,
+        r#"{"name": "grammar_json_test", "rules": {"program": {"type": "STRING", "value": "a"}}}"#,
+    )
+    .unwrap();
+
+    // Returns whether `src/grammar.json` was written.
+    let generate = |grammar_path: Option<&Path>, write_grammar_json| {
+        let output_dir = tempfile::tempdir().unwrap();
+        fs::write(output_dir.path().join("grammar.js"), grammar_js).unwrap();
+        generate_parser_in_directory(
+            &output_dir.path().to_owned(),
+            &DirectoryOptions {
+                grammar_path: grammar_path.map(|path| path.to_str().unwrap()),
+                node_types_only: true,
+                grammar_json: write_grammar_json,
+                ..Default::default()
+            },
+            &GenerateOptions::default(),
+            &mut Diagnostics::new(),
+        )
+        .unwrap();
+        let grammar_json = fs::read_to_string(output_dir.path().join("src").join("grammar.json"));
+        if let Ok(grammar_json) = &grammar_json {
+            assert!(grammar_json.starts_with("{\n  \"name\": \"grammar_json_test\",\n"));
+        }
+        grammar_json.is_ok()
+    };
+
+    // By default, the grammar is only written when it is loaded from the repository's
+    // `grammar.js`.
+    assert!(generate(None, WriteGrammarJson::Auto));
+    assert!(!generate(Some(&grammar_js_path), WriteGrammarJson::Auto));
+    assert!(!generate(Some(&grammar_json_path), WriteGrammarJson::Auto));
+
+    assert!(generate(None, WriteGrammarJson::Always));
+    assert!(generate(Some(&grammar_js_path), WriteGrammarJson::Always));
+    assert!(generate(Some(&grammar_json_path), WriteGrammarJson::Always));
+
+    assert!(!generate(None, WriteGrammarJson::Never));
+    assert!(!generate(Some(&grammar_js_path), WriteGrammarJson::Never));
+    assert!(!generate(Some(&grammar_json_path), WriteGrammarJson::Never));
+}
+
+#[test]
+fn test_generating_compile_commands() {
+    let (_grammar_dir, grammar_path) = write_grammar(
+        r#"{
+            "name": "heredocs",
+            "rules": {
+                "program": {"type": "REPEAT", "content": {"type": "SYMBOL", "name": "heredoc"}}
+            },
+            "externals": [{"type": "SYMBOL", "name": "heredoc"}]
+        }"#,
+    );
+
+    // An existing database may contain entries for other files, which are preserved,
+    // and outdated entries for the generated files, which are replaced.
+    let output_dir = tempfile::tempdir().unwrap();
+    let repo_path = output_dir.path().canonicalize().unwrap();
+    let compile_commands_path = repo_path.join("compile_commands.json");
+    fs::write(
+        &compile_commands_path,
+        serde_json::to_string(&serde_json::json!([
+            {
+                "directory": repo_path,
+                "file": "bindings/c/example.c",
+                "command": "cc -c bindings/c/example.c",
+            },
+            {
+                "directory": "/",
+                "file": repo_path.join("src").join("parser.c"),
+                "command": "cc -c parser.c",
+            },
+        ]))
+        .unwrap(),
+    )
+    .unwrap();
+
+    let generate = || {
+        let directory_options = DirectoryOptions {
+            compile_commands: true,
+            ..Default::default()
+        };
+        let options = GenerateOptions {
+            scanner: true,
+            ..Default::default()
+        };
+        generate_in_repo(&repo_path, &grammar_path, directory_options, &options).unwrap();
+        let compile_commands: Vec<serde_json::Value> =
+            serde_json::from_str(&fs::read_to_string(&compile_commands_path).unwrap()).unwrap();
+        compile_commands
+    };
+
+    let compile_commands = generate();
+    assert_eq!(
+        compile_commands
+            .iter()
+            .map(|entry| entry["file"].as_str().unwrap())
+            .collect::<Vec<_>>(),
+        vec!["bindings/c/example.c", "src/parser.c", "src/scanner.c"]
+    );
+    assert_eq!(
+        compile_commands[0]["command"].as_str(),
+        Some("cc -c bindings/c/example.c")
+    );
+    for entry in &compile_commands[1..] {
+        let directory = Path::new(entry["directory"].as_str().unwrap());
+        assert_eq!(directory, repo_path);
+        let arguments = entry["arguments"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|argument| argument.as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(arguments.last(), Some(&entry["file"].as_str().unwrap()));
+        assert!(directory.join(arguments.last().unwrap()).exists());
+        let include_index = arguments.iter().position(|a| *a == "-I").unwrap();
+        assert!(directory
+            .join(arguments[include_index + 1])
+            .join("tree_sitter")
+            .join("parser.h")
+            .exists());
+    }
+    assert!(compile_commands[2]["arguments"]
+        .as_array()
+        .unwrap()
+        .contains(&serde_json::json!("-std=c99")));
+
+    // When the scanner is written in C++, its entry replaces the one for the C scanner.
+    fs::rename(
+        repo_path.join("src").join("scanner.c"),
+        repo_path.join("src").join("scanner.cc"),
+    )
+    .unwrap();
+    let compile_commands = generate();
+    assert_eq!(
+        compile_commands
+            .iter()
+            .map(|entry| entry["file"].as_str().unwrap())
+            .collect::<Vec<_>>(),
+        vec!["bindings/c/example.c", "src/parser.c", "src/scanner.cc"]
+    );
+    assert_eq!(compile_commands[2]["arguments"][0].as_str(), Some("c++"));
+}
+
+fn test_grammar_path(name: &str) -> PathBuf {
+    fixtures_dir()
+        .join("test_grammars")
+        .join(name)
+        .join("grammar.json")
+}
+
+// Creates a repository that contains only the given grammar, and returns it along with
+// the grammar's path.
+fn write_grammar(grammar_json: &str) -> (TempDir, PathBuf) {
+    let repo_dir = tempfile::tempdir().unwrap();
+    let grammar_path = repo_dir.path().join("grammar.json");
+    fs::write(&grammar_path, grammar_json).unwrap();
+    (repo_dir, grammar_path)
+}
+
+fn read_src_file(repo_path: &Path, path: &str) -> String {
+    fs::read_to_string(repo_path.join("src").join(path)).unwrap()
+}
+
+fn generate_in_repo(
+    repo_path: &Path,
+    grammar_path: &Path,
+    directory_options: DirectoryOptions,
+    options: &GenerateOptions,
+) -> Result<()> {
+    generate_in_repo_with_diagnostics(
+        repo_path,
+        grammar_path,
+        directory_options,
+        options,
+        &mut Diagnostics::new(),
+    )
+}
+
+fn generate_in_repo_with_diagnostics(
+    repo_path: &Path,
+    grammar_path: &Path,
+    directory_options: DirectoryOptions,
+    options: &GenerateOptions,
+    diagnostics: &mut Diagnostics,
+) -> Result<()> {
+    generate_parser_in_directory(
+        &repo_path.to_owned(),
+        &DirectoryOptions {
+            grammar_path: Some(grammar_path.to_str().unwrap()),
+            ..directory_options
+        },
+        options,
+        diagnostics,
+    )
+}
+
+fn generate_parser_with_queries(
+    repo_path: &Path,
+    grammar_path: &Path,
+    strict_queries: bool,
+    diagnostics: &mut Diagnostics,
+) -> Result<()> {
+    let directory_options = DirectoryOptions {
+        validate_queries: true,
+        strict_queries,
+        ..Default::default()
+    };
+    let options = GenerateOptions {
+        next_abi: true,
+        ..Default::default()
+    };
+    generate_in_repo_with_diagnostics(
+        repo_path,
+        grammar_path,
+        directory_options,
+        &options,
+        diagnostics,
+    )
+}
@@ -0,0 +1,199 @@
+use super::helpers::fixtures::fixtures_dir;
+use crate::test::{print_diff, print_diff_key};
+use crate::test_loader::compile_grammar;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::Path;
+use std::{env, fs};
+use tree_sitter_highlight::stats::PatternUsage;
+use tree_sitter_highlight::{
+    HighlightConfiguration, HighlightContext, HighlightEvent, HighlightOptions, Highlighter,
+};
+
+lazy_static! {
+    static ref UPDATE_ENABLED: bool = env::var("TREE_SITTER_TEST_UPDATE_HIGHLIGHTS").is_ok();
+    static ref HIGHLIGHTER: Highlighter = Highlighter::new(
+        [
+            "attribute",
+            "function",
+            "keyword",
+            "number",
+            "operator",
+            "punctuation.bracket",
+            "punctuation.delimiter",
+            "string",
+            "tag",
+            "variable",
+            "variable.parameter",
+        ]
+        .iter()
+        .cloned()
+        .map(String::from)
+        .collect()
+    );
+}
+
+// Each directory in `test/fixtures/highlight_golden` contains a grammar and its queries.
+// Each file in its `examples` directory is highlighted, and the events are compared with
+// the dump in the file of the same name with the extension `.events`. Languages are
+// injected by the name of their directory.
+#[test]
+fn test_highlight_golden_files() {
+    let golden_dir = fixtures_dir().join("highlight_golden");
+    let mut language_dirs = fs::read_dir(&golden_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_dir())
+        .collect::<Vec<_>>();
+    language_dirs.sort();
+
+    let configs = language_dirs
+        .iter()
+        .map(|dir| {
+            let name = dir.file_name().unwrap().to_str().unwrap().to_string();
+            (name, load_config(dir))
+        })
+        .collect::<HashMap<_, _>>();
+
+    let mut failure_count = 0;
+    for dir in &language_dirs {
+        let name = dir.file_name().unwrap().to_str().unwrap();
+        let mut example_paths = fs::read_dir(dir.join("examples"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().map_or(true, |ext| ext != "events"))
+            .collect::<Vec<_>>();
+        example_paths.sort();
+
+        eprintln!("test language: {:?}", name);
+        for example_path in example_paths {
+            eprintln!("  example: {:?}", example_path.file_name().unwrap());
+            let source = fs::read(&example_path).unwrap();
+            let actual = dump_events(&configs, &configs[name], &source);
+            let events_path = example_path.with_extension("events");
+            if *UPDATE_ENABLED {
+                fs::write(&events_path, actual).unwrap();
+                continue;
+            }
+
+            let expected = fs::read_to_string(&events_path).unwrap_or(String::new());
+            if actual != expected {
+                print_diff_key();
+                print_diff(&actual, &expected);
+                failure_count += 1;
+            }
+        }
+    }
+
+    if failure_count > 0 {
+        panic!(
+            "{} highlight dumps differ. Run with TREE_SITTER_TEST_UPDATE_HIGHLIGHTS=1 to update them.",
+            failure_count
+        );
+    }
+}
+
+#[test]
+fn test_counting_pattern_usage_over_golden_examples() {
+    // Add a pattern that matches the same nodes as an earlier pattern, so that its captures
+    // are always skipped.
+    let dir = fixtures_dir().join("highlight_golden").join("expressions");
+    let extra_highlights = "(string) @keyword\n";
+    let config = load_config_with_extra_highlights(&dir, extra_highlights);
+    let highlights_query =
+        fs::read_to_string(dir.join("highlights.scm")).unwrap() + extra_highlights;
+
+    // Count the patterns for each example separately, as if they were highlighted in
+    // parallel, and then merge the counts.
+    let total_usage = PatternUsage::new(&config);
+    let mut context = HighlightContext::new();
+    for entry in fs::read_dir(dir.join("examples")).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().map_or(false, |ext| ext == "events") {
+            continue;
+        }
+        let source = fs::read(&path).unwrap();
+        let usage = PatternUsage::new(&config);
+        let options = HighlightOptions::new().pattern_usage(&usage);
+        let events = HIGHLIGHTER
+            .highlight_with_options(&mut context, &config, &source, options, |_| None)
+            .unwrap();
+        for event in events {
+            event.unwrap();
+        }
+        total_usage.merge(&usage);
+    }
+
+    let patterns = total_usage.highlight_patterns();
+    let count_for_pattern = |pattern_source: &str| {
+        let row = highlights_query
+            .lines()
+            .collect::<Vec<_>>()
+            .iter()
+            .rposition(|line| *line == pattern_source)
+            .unwrap();
+        let pattern = patterns.iter().find(|pattern| pattern.row == row).unwrap();
+        assert!(highlights_query[pattern.offset..].starts_with(pattern_source));
+        pattern.count
+    };
+    assert_eq!(count_for_pattern("\"let\" @keyword"), 2);
+    assert!(count_for_pattern("(identifier) @variable") > 0);
+    assert_eq!(count_for_pattern("(string) @keyword"), 0);
+}
+
+fn load_config(dir: &Path) -> HighlightConfiguration {
+    load_config_with_extra_highlights(dir, "")
+}
+
+fn load_config_with_extra_highlights(dir: &Path, extra_highlights: &str) -> HighlightConfiguration {
+    let grammar_json = fs::read_to_string(dir.join("grammar.json")).unwrap();
+    let language = compile_grammar(&grammar_json).unwrap();
+    let read_query = |name| fs::read_to_string(dir.join(name)).unwrap_or(String::new());
+    HIGHLIGHTER
+        .load_configuration(
+            language,
+            &(read_query("highlights.scm") + extra_highlights),
+            &read_query("injections.scm"),
+            &read_query("locals.scm"),
+        )
+        .unwrap()
+}
+
+// Write each event on its own line. The source code between highlights is written along
+// with its offsets, and is indented by the number of highlights that contain it.
+fn dump_events(
+    configs: &HashMap<String, HighlightConfiguration>,
+    config: &HighlightConfiguration,
+    source: &[u8],
+) -> String {
+    let mut context = HighlightContext::new();
+    let events = HIGHLIGHTER
+        .highlight(&mut context, config, source, None, |name| configs.get(name))
+        .unwrap();
+
+    let mut result = String::new();
+    let mut highlight_stack = Vec::new();
+    for event in events {
+        let indent = "  ".repeat(highlight_stack.len());
+        match event.unwrap() {
+            HighlightEvent::Source { start, end } => {
+                let text = String::from_utf8_lossy(&source[start..end]);
+                writeln!(&mut result, "{}{}..{} {:?}", indent, start, end, text).unwrap();
+            }
+            HighlightEvent::HighlightStart(highlight)
+            | HighlightEvent::HighlightStartWithPattern { highlight, .. } => {
+                let name = &HIGHLIGHTER.names()[highlight.0];
+                writeln!(&mut result, "{}<{}>", indent, name).unwrap();
+                highlight_stack.push(name);
+            }
+            HighlightEvent::HighlightEnd => {
+                let name = highlight_stack.pop().unwrap();
+                let indent = "  ".repeat(highlight_stack.len());
+                writeln!(&mut result, "{}</{}>", indent, name).unwrap();
+            }
+            HighlightEvent::LayerStart { .. } | HighlightEvent::LayerEnd => {}
+        }
+    }
+    result
+}
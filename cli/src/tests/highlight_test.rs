@@ -1,10 +1,26 @@
-use super::helpers::fixtures::{get_highlight_config, get_language, get_language_queries_path};
+use super::helpers::fixtures::{
+    fixtures_dir, get_highlight_config, get_language, get_language_queries_path,
+};
+use crate::generate::generate_node_types_for_grammar;
+use crate::test_loader::{self, compile_grammar};
 use lazy_static::lazy_static;
-use std::ffi::CString;
+use std::ffi::{CStr, CString};
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::{fs, ptr, slice, str};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{fs, ptr, slice, str, thread};
+use tree_sitter::{InputEdit, Language, Parser, Point, Query, QueryCursor, QueryError, Range};
+use tree_sitter_highlight::stats::{
+    self, CoverageStats, HighlightStats, ParseStats, PatternCount, PatternUsage,
+};
 use tree_sitter_highlight::{
-    c, Error, HighlightConfiguration, HighlightContext, HighlightEvent, Highlighter, HtmlRenderer,
+    c, intersect_ranges, query_paths_from_language_dir, spans_from_events, ByteOrderMarkHandling,
+    ConfigurationError, ConfigurationWatcher, Error, Highlight, HighlightConfiguration,
+    HighlightContext, HighlightContextPool, HighlightEvent, HighlightNamesError, HighlightOptions,
+    HighlightSource, HighlightedLines, Highlighter, HtmlRenderer, LanguageRegistry, LayerRangeMap,
+    LayerTrees, LoadError, LocalBinding, OversizedSourceHandling, PatternIssue, PatternIssueKind,
+    QuerySection,
 };
 
 lazy_static! {
@@ -24,6 +40,7 @@ lazy_static! {
             "function.builtin",
             "function",
             "embedded",
+            "error",
             "keyword",
             "operator",
             "property.builtin",
@@ -530,75 +547,5467 @@ fn test_highlighting_via_c_api() {
 }
 
 #[test]
-fn test_decode_utf8_lossy() {
-    use tree_sitter_highlight::util::LossyUtf8;
+fn test_highlighting_via_c_api_with_language_dir() {
+    let highlights = vec!["class=string\0", "class=number\0"];
+    let highlight_names = highlights
+        .iter()
+        .map(|h| h["class=".len()..].as_ptr() as *const i8)
+        .collect::<Vec<_>>();
+    let highlight_attrs = highlights
+        .iter()
+        .map(|h| h.as_bytes().as_ptr() as *const i8)
+        .collect::<Vec<_>>();
+    let highlighter = c::ts_highlighter_new(
+        &highlight_names[0] as *const *const i8,
+        &highlight_attrs[0] as *const *const i8,
+        highlights.len() as u32,
+    );
 
-    let parts = LossyUtf8::new(b"hi").collect::<Vec<_>>();
-    assert_eq!(parts, vec!["hi"]);
+    let language = get_highlight_dir_test_language();
+    let dir = tempfile::tempdir().unwrap();
+    let language_dir = dir.path().join("tree-sitter-test");
+    let highlights_path = language_dir.join("queries").join("highlights.scm");
+    fs::create_dir_all(language_dir.join("queries")).unwrap();
+    fs::write(
+        &highlights_path,
+        "(number) @number\n(nonexistent) @string\n",
+    )
+    .unwrap();
 
-    let parts = LossyUtf8::new(b"hi\xc0\xc1bye").collect::<Vec<_>>();
-    assert_eq!(parts, vec!["hi", "\u{fffd}", "\u{fffd}", "bye"]);
+    let scope = c_string("source.test");
+    let path = c_string(language_dir.to_str().unwrap());
+    let add_language = || {
+        c::ts_highlighter_add_language_from_directory(
+            highlighter,
+            scope.as_ptr(),
+            ptr::null(),
+            language,
+            path.as_ptr(),
+        )
+    };
+    let last_error_message = || {
+        let message = c::ts_highlighter_last_error_message(highlighter);
+        if message.is_null() {
+            None
+        } else {
+            Some(
+                unsafe { CStr::from_ptr(message) }
+                    .to_str()
+                    .unwrap()
+                    .to_string(),
+            )
+        }
+    };
 
-    let parts = LossyUtf8::new(b"\xc0\xc1bye").collect::<Vec<_>>();
-    assert_eq!(parts, vec!["\u{fffd}", "\u{fffd}", "bye"]);
+    // An invalid pattern is reported along with its file and line.
+    assert!(matches!(add_language(), c::ErrorCode::InvalidQuery));
+    let message = last_error_message().unwrap();
+    assert!(message.contains(highlights_path.to_str().unwrap()));
+    assert!(message.contains("line 2"));
 
-    let parts = LossyUtf8::new(b"hello\xc0\xc1").collect::<Vec<_>>();
-    assert_eq!(parts, vec!["hello", "\u{fffd}", "\u{fffd}"]);
+    fs::write(&highlights_path, "(number) @number\n").unwrap();
+    assert!(matches!(add_language(), c::ErrorCode::Ok));
+    assert_eq!(last_error_message(), None);
+
+    let source_code = c_string("a 1");
+    let buffer = c::ts_highlight_buffer_new();
+    c::ts_highlighter_highlight(
+        highlighter,
+        scope.as_ptr(),
+        source_code.as_ptr(),
+        source_code.as_bytes().len() as u32,
+        buffer,
+        ptr::null_mut(),
+    );
+    let output_bytes = c::ts_highlight_buffer_content(buffer);
+    let output_len = c::ts_highlight_buffer_len(buffer);
+    let output_bytes = unsafe { slice::from_raw_parts(output_bytes, output_len as usize) };
+    assert_eq!(
+        str::from_utf8(output_bytes).unwrap(),
+        "a <span class=number>1</span>\n"
+    );
+
+    // Query files that can't be read are reported with a separate error code.
+    fs::remove_file(&highlights_path).unwrap();
+    fs::create_dir_all(language_dir.join("src")).unwrap();
+    fs::write(
+        language_dir.join("src").join("metadata.json"),
+        r#"{"name": "test", "highlights": ["queries/highlights.scm"]}"#,
+    )
+    .unwrap();
+    assert!(matches!(
+        add_language(),
+        c::ErrorCode::InvalidLanguageDirectory
+    ));
+    assert!(last_error_message()
+        .unwrap()
+        .contains(highlights_path.to_str().unwrap()));
+
+    c::ts_highlighter_delete(highlighter);
+    c::ts_highlight_buffer_delete(buffer);
 }
 
-fn c_string(s: &str) -> CString {
-    CString::new(s.as_bytes().to_vec()).unwrap()
+#[test]
+fn test_highlighting_via_c_api_error_codes() {
+    let highlights = vec!["class=number\0"];
+    let highlight_names = highlights
+        .iter()
+        .map(|h| h["class=".len()..].as_ptr() as *const i8)
+        .collect::<Vec<_>>();
+    let highlight_attrs = highlights
+        .iter()
+        .map(|h| h.as_bytes().as_ptr() as *const i8)
+        .collect::<Vec<_>>();
+    assert!(c::ts_highlighter_new(ptr::null(), ptr::null(), 1).is_null());
+    let highlighter = c::ts_highlighter_new(
+        &highlight_names[0] as *const *const i8,
+        &highlight_attrs[0] as *const *const i8,
+        highlights.len() as u32,
+    );
+
+    let language = get_highlight_dir_test_language();
+    let scope = c_string("source.test");
+    let add_language = |highlighter, scope: &CString, injection_regex: *const i8, query: &str| {
+        c::ts_highlighter_add_language(
+            highlighter,
+            scope.as_ptr(),
+            injection_regex,
+            language,
+            query.as_ptr() as *const i8,
+            ptr::null(),
+            ptr::null(),
+            query.len() as u32,
+            0,
+            0,
+        )
+    };
+
+    let query = "(number) @number\n";
+    assert!(matches!(
+        add_language(ptr::null_mut(), &scope, ptr::null(), query),
+        c::ErrorCode::NullPointer
+    ));
+    assert!(matches!(
+        add_language(
+            highlighter,
+            &CString::new(vec![0xff]).unwrap(),
+            ptr::null(),
+            query
+        ),
+        c::ErrorCode::InvalidUtf8
+    ));
+    assert!(matches!(
+        add_language(highlighter, &scope, c_string("(").as_ptr(), query),
+        c::ErrorCode::InvalidRegex
+    ));
+    assert!(matches!(
+        add_language(highlighter, &scope, ptr::null(), "(nonexistent) @number"),
+        c::ErrorCode::InvalidQuery
+    ));
+    assert!(!c::ts_highlighter_last_error_message(highlighter).is_null());
+    assert!(matches!(
+        add_language(highlighter, &scope, ptr::null(), query),
+        c::ErrorCode::Ok
+    ));
+    assert!(c::ts_highlighter_last_error_message(highlighter).is_null());
+
+    let add_language_with_aliases = |scope: &CString, aliases: &[*const i8], alias_count| {
+        c::ts_highlighter_add_language_with_aliases(
+            highlighter,
+            scope.as_ptr(),
+            ptr::null(),
+            language,
+            query.as_ptr() as *const i8,
+            ptr::null(),
+            ptr::null(),
+            query.len() as u32,
+            0,
+            0,
+            if aliases.is_empty() {
+                ptr::null()
+            } else {
+                aliases.as_ptr()
+            },
+            alias_count,
+        )
+    };
+    let other_scope = c_string("source.other");
+    let alias = c_string("test");
+    assert!(matches!(
+        add_language_with_aliases(&other_scope, &[], 1),
+        c::ErrorCode::NullPointer
+    ));
+    assert!(matches!(
+        add_language_with_aliases(&other_scope, &[alias.as_ptr(), ptr::null()], 2),
+        c::ErrorCode::NullPointer
+    ));
+    assert!(matches!(
+        add_language_with_aliases(&scope, &[alias.as_ptr()], 1),
+        c::ErrorCode::Ok
+    ));
+
+    // Aliases are unique across languages, ignoring case, but a language can be replaced
+    // by another one with the same aliases.
+    assert!(matches!(
+        add_language_with_aliases(&other_scope, &[c_string("TEST").as_ptr()], 1),
+        c::ErrorCode::DuplicateAlias
+    ));
+    assert!(!c::ts_highlighter_last_error_message(highlighter).is_null());
+    assert!(matches!(
+        add_language_with_aliases(&scope, &[alias.as_ptr()], 1),
+        c::ErrorCode::Ok
+    ));
+
+    let source_code = c_string(&"a 1 ".repeat(500));
+    let buffer = c::ts_highlight_buffer_new();
+    let highlight = |scope: &CString, buffer, cancellation_flag: *const AtomicUsize| {
+        c::ts_highlighter_highlight(
+            highlighter,
+            scope.as_ptr(),
+            source_code.as_ptr(),
+            source_code.as_bytes().len() as u32,
+            buffer,
+            cancellation_flag,
+        )
+    };
+
+    assert!(matches!(
+        highlight(&c_string("source.unknown"), buffer, ptr::null()),
+        c::ErrorCode::UnknownScope
+    ));
+    assert!(matches!(
+        highlight(&scope, ptr::null_mut(), ptr::null()),
+        c::ErrorCode::NullPointer
+    ));
+    let cancellation_flag = AtomicUsize::new(1);
+    assert!(matches!(
+        highlight(&scope, buffer, &cancellation_flag),
+        c::ErrorCode::Timeout
+    ));
+    assert!(matches!(
+        highlight(&scope, buffer, ptr::null()),
+        c::ErrorCode::Ok
+    ));
+
+    c::ts_highlighter_delete(highlighter);
+    c::ts_highlight_buffer_delete(buffer);
+    c::ts_highlighter_delete(ptr::null_mut());
+    c::ts_highlight_buffer_delete(ptr::null_mut());
 }
 
-fn test_language_for_injection_string<'a>(string: &str) -> Option<&'a HighlightConfiguration> {
-    match string {
-        "javascript" => Some(&JS_HIGHLIGHT),
-        "html" => Some(&HTML_HIGHLIGHT),
-        "rust" => Some(&RUST_HIGHLIGHT),
-        _ => None,
+#[test]
+fn test_highlighting_via_c_api_with_cancellation() {
+    let highlights = vec!["class=number\0"];
+    let highlight_names = highlights
+        .iter()
+        .map(|h| h["class=".len()..].as_ptr() as *const i8)
+        .collect::<Vec<_>>();
+    let highlight_attrs = highlights
+        .iter()
+        .map(|h| h.as_bytes().as_ptr() as *const i8)
+        .collect::<Vec<_>>();
+    let highlighter = c::ts_highlighter_new(
+        &highlight_names[0] as *const *const i8,
+        &highlight_attrs[0] as *const *const i8,
+        highlights.len() as u32,
+    );
+
+    let scope = c_string("source.test");
+    let query = "(number) @number\n";
+    c::ts_highlighter_add_language(
+        highlighter,
+        scope.as_ptr(),
+        ptr::null(),
+        get_highlight_dir_test_language(),
+        query.as_ptr() as *const i8,
+        ptr::null(),
+        ptr::null(),
+        query.len() as u32,
+        0,
+        0,
+    );
+
+    let buffer = c::ts_highlight_buffer_new();
+    let highlight = |source_code: &CString, cancellation_flag: *const AtomicUsize| {
+        c::ts_highlighter_highlight(
+            highlighter,
+            scope.as_ptr(),
+            source_code.as_ptr(),
+            source_code.as_bytes().len() as u32,
+            buffer,
+            cancellation_flag,
+        )
+    };
+
+    // Flip the flag from another thread while a large document is being highlighted.
+    let source_code = c_string(&"a 1 ".repeat(100_000));
+    let cancellation_flag = Arc::new(AtomicUsize::new(0));
+    let flag = cancellation_flag.clone();
+    let canceller = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(10));
+        flag.store(1, Ordering::SeqCst);
+    });
+    let mut cancelled = false;
+    for _ in 0..1000 {
+        match highlight(&source_code, &*cancellation_flag) {
+            c::ErrorCode::Timeout => {
+                cancelled = true;
+                break;
+            }
+            c::ErrorCode::Ok => {}
+            _ => panic!("Expected highlighting to succeed or to be cancelled"),
+        }
     }
+    canceller.join().unwrap();
+    assert!(cancelled);
+    assert_eq!(c::ts_highlight_buffer_len(buffer), 0);
+    assert_eq!(c::ts_highlight_buffer_line_count(buffer), 0);
+
+    // The buffer can be reused after a cancelled call.
+    cancellation_flag.store(0, Ordering::SeqCst);
+    let result = highlight(&c_string("a 1"), &*cancellation_flag);
+    assert!(matches!(result, c::ErrorCode::Ok));
+    let output_bytes = c::ts_highlight_buffer_content(buffer);
+    let output_len = c::ts_highlight_buffer_len(buffer);
+    let output_bytes = unsafe { slice::from_raw_parts(output_bytes, output_len as usize) };
+    assert_eq!(
+        str::from_utf8(output_bytes).unwrap(),
+        "a <span class=number>1</span>\n"
+    );
+
+    c::ts_highlighter_delete(highlighter);
+    c::ts_highlight_buffer_delete(buffer);
 }
 
-fn to_html<'a>(
-    src: &'a str,
-    language_config: &'a HighlightConfiguration,
-) -> Result<Vec<String>, Error> {
-    let src = src.as_bytes();
-    let mut renderer = HtmlRenderer::new();
-    let mut context = HighlightContext::new();
-    let events = HIGHLIGHTER.highlight(
-        &mut context,
-        language_config,
-        src,
-        None,
-        &test_language_for_injection_string,
-    )?;
+#[test]
+fn test_highlighting_events_via_c_api() {
+    let highlights = vec!["class=variable\0", "class=string\0", "class=number\0"];
+    let highlight_names = highlights
+        .iter()
+        .map(|h| h["class=".len()..].as_ptr() as *const i8)
+        .collect::<Vec<_>>();
+    let highlight_attrs = highlights
+        .iter()
+        .map(|h| h.as_bytes().as_ptr() as *const i8)
+        .collect::<Vec<_>>();
+    let highlighter = c::ts_highlighter_new(
+        &highlight_names[0] as *const *const i8,
+        &highlight_attrs[0] as *const *const i8,
+        highlights.len() as u32,
+    );
 
-    renderer
-        .render(events, src, &|highlight| HTML_ATTRS[highlight.0].as_bytes())
+    let scope = c_string("source.test");
+    let query = "(identifier) @variable\n(string) @string\n(number) @number\n";
+    c::ts_highlighter_add_language(
+        highlighter,
+        scope.as_ptr(),
+        ptr::null(),
+        get_highlight_dir_test_language(),
+        query.as_ptr() as *const i8,
+        ptr::null(),
+        ptr::null(),
+        query.len() as u32,
+        0,
+        0,
+    );
+
+    let buffer = c::ts_highlight_buffer_new();
+    let highlight_events = |source_code: &str| {
+        let source_code = c_string(source_code);
+        let result = c::ts_highlighter_highlight_events(
+            highlighter,
+            scope.as_ptr(),
+            source_code.as_ptr(),
+            source_code.as_bytes().len() as u32,
+            buffer,
+            ptr::null(),
+        );
+        assert!(matches!(result, c::ErrorCode::Ok));
+
+        let events = c::ts_highlight_buffer_events(buffer);
+        let event_count = c::ts_highlight_buffer_event_count(buffer);
+        let line_offsets = c::ts_highlight_buffer_event_line_offsets(buffer);
+        let line_count = c::ts_highlight_buffer_event_line_count(buffer);
+        let events = unsafe { slice::from_raw_parts(events, event_count as usize) };
+        let line_offsets = unsafe { slice::from_raw_parts(line_offsets, line_count as usize) };
+        (0..line_offsets.len())
+            .map(|i| {
+                let end = line_offsets
+                    .get(i + 1)
+                    .map_or(events.len(), |o| *o as usize);
+                events[line_offsets[i] as usize..end]
+                    .iter()
+                    .map(|e| (e.start_column, e.end_column, e.highlight_index))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // Highlights that span several lines are split at the line endings.
+    assert_eq!(
+        highlight_events("a 1\r\n\"bc\nd\" 2\n\nx"),
+        vec![
+            vec![(0, 1, 0), (2, 3, 2)],
+            vec![(0, 3, 1)],
+            vec![(0, 2, 1), (3, 4, 2)],
+            vec![],
+            vec![(0, 1, 0)],
+        ]
+    );
+
+    // The buffer is reused, and its previous events are replaced.
+    assert_eq!(highlight_events("12 ab"), vec![vec![(0, 2, 2), (3, 5, 0)]]);
+
+    // Rendering HTML into the buffer clears its events.
+    let source_code = c_string("a");
+    c::ts_highlighter_highlight(
+        highlighter,
+        scope.as_ptr(),
+        source_code.as_ptr(),
+        source_code.as_bytes().len() as u32,
+        buffer,
+        ptr::null(),
+    );
+    assert_eq!(c::ts_highlight_buffer_event_count(buffer), 0);
+    assert_eq!(c::ts_highlight_buffer_event_line_count(buffer), 0);
+    assert!(c::ts_highlight_buffer_len(buffer) > 0);
+
+    c::ts_highlighter_delete(highlighter);
+    c::ts_highlight_buffer_delete(buffer);
+}
+
+#[test]
+fn test_loading_configuration_from_language_dir() {
+    let language = get_highlight_dir_test_language();
+    let dir = tempfile::tempdir().unwrap();
+    let language_dir = dir.path().join("tree-sitter-test");
+    fs::create_dir_all(language_dir.join("src")).unwrap();
+    fs::create_dir_all(language_dir.join("queries")).unwrap();
+    fs::write(
+        language_dir.join("src").join("metadata.json"),
+        r#"{"name": "test", "highlights": ["queries/custom-highlights.scm"]}"#,
+    )
+    .unwrap();
+    fs::write(
+        language_dir.join("queries").join("custom-highlights.scm"),
+        "(identifier) @variable\n(number) @constant\n",
+    )
+    .unwrap();
+    fs::write(
+        language_dir.join("queries").join("injections.scm"),
+        "(string) @injection.content\n",
+    )
+    .unwrap();
+    fs::write(
+        language_dir.join("queries").join("locals.scm"),
+        "(program) @local.scope\n(identifier) @local.reference\n",
+    )
+    .unwrap();
+
+    let config = HIGHLIGHTER
+        .load_configuration_from_language_dir(language, &language_dir)
         .unwrap();
-    Ok(renderer.lines().map(|s| s.to_string()).collect())
+    assert_eq!(config.query.pattern_count(), 5);
+    assert_eq!(
+        &to_token_vector("a 1 \"b\"", &config).unwrap(),
+        &[vec![
+            ("a", vec!["variable"]),
+            (" ", vec![]),
+            ("1", vec!["constant"]),
+            (" \"b\"", vec![]),
+        ]],
+    );
 }
 
-fn to_token_vector<'a>(
-    src: &'a str,
-    language_config: &'a HighlightConfiguration,
-) -> Result<Vec<Vec<(&'a str, Vec<&'static str>)>>, Error> {
-    let src = src.as_bytes();
-    let mut context = HighlightContext::new();
-    let mut lines = Vec::new();
-    let mut highlights = Vec::new();
-    let mut line = Vec::new();
-    let events = HIGHLIGHTER.highlight(
-        &mut context,
-        language_config,
-        src,
-        None,
-        &test_language_for_injection_string,
-    )?;
-    for event in events {
-        match event? {
-            HighlightEvent::HighlightStart(s) => highlights.push(HIGHLIGHTER.names()[s.0].as_str()),
+#[test]
+fn test_loading_configuration_from_language_dir_without_a_highlighter() {
+    let language = get_highlight_dir_test_language();
+    let dir = tempfile::tempdir().unwrap();
+    let language_dir = dir.path().join("tree-sitter-test");
+    fs::create_dir_all(language_dir.join("queries")).unwrap();
+    fs::write(
+        language_dir.join("queries").join("highlights.scm"),
+        "(identifier) @variable\n(number) @constant\n",
+    )
+    .unwrap();
+
+    // No captures are recognized until the configuration is given the highlight names.
+    let mut config =
+        HighlightConfiguration::load_from_language_dir(language, &language_dir).unwrap();
+    assert_eq!(config.query.pattern_count(), 2);
+    assert_eq!(
+        config.nonconformant_capture_names(),
+        &["variable", "constant"]
+    );
+
+    config.configure(HIGHLIGHTER.names());
+    assert_eq!(
+        &to_token_vector("a 1", &config).unwrap(),
+        &[vec![
+            ("a", vec!["variable"]),
+            (" ", vec![]),
+            ("1", vec!["constant"]),
+        ]],
+    );
+}
+
+#[test]
+fn test_loading_configuration_from_language_dir_with_missing_queries() {
+    let language = get_highlight_dir_test_language();
+    let dir = tempfile::tempdir().unwrap();
+    let language_dir = dir.path().join("tree-sitter-test");
+    fs::create_dir_all(language_dir.join("queries")).unwrap();
+    fs::write(
+        language_dir.join("queries").join("highlights.scm"),
+        "(string) @string\n",
+    )
+    .unwrap();
+
+    // Without a metadata file, the default query paths are used, and missing
+    // query files are treated as empty.
+    let config = HIGHLIGHTER
+        .load_configuration_from_language_dir(language, &language_dir)
+        .unwrap();
+    assert_eq!(config.query.pattern_count(), 1);
+
+    // Query files that are listed in the metadata must exist.
+    fs::create_dir_all(language_dir.join("src")).unwrap();
+    fs::write(
+        language_dir.join("src").join("metadata.json"),
+        r#"{"name": "test", "locals": ["queries/locals.scm"]}"#,
+    )
+    .unwrap();
+    match HIGHLIGHTER.load_configuration_from_language_dir(language, &language_dir) {
+        Err(LoadError::Io(path, _)) => {
+            assert_eq!(path, language_dir.join("queries").join("locals.scm"))
+        }
+        _ => panic!("Expected an IO error"),
+    }
+}
+
+#[test]
+fn test_loading_configuration_from_language_dir_with_inherited_queries() {
+    let language = get_highlight_dir_test_language();
+    let dir = tempfile::tempdir().unwrap();
+    let base_dir = dir.path().join("tree-sitter-base");
+    let language_dir = dir.path().join("tree-sitter-test");
+    fs::create_dir_all(base_dir.join("queries")).unwrap();
+    fs::create_dir_all(language_dir.join("queries")).unwrap();
+    fs::write(
+        base_dir.join("queries").join("highlights.scm"),
+        "(number) @constant\n(string) @string\n",
+    )
+    .unwrap();
+    fs::write(
+        language_dir.join("queries").join("highlights.scm"),
+        "; inherits: base\n\n(identifier) @variable\n",
+    )
+    .unwrap();
+
+    let config = HIGHLIGHTER
+        .load_configuration_from_language_dir(language, &language_dir)
+        .unwrap();
+    assert_eq!(config.query.pattern_count(), 3);
+    assert_eq!(
+        &to_token_vector("a 1 \"b\"", &config).unwrap(),
+        &[vec![
+            ("a", vec!["variable"]),
+            (" ", vec![]),
+            ("1", vec!["constant"]),
+            (" ", vec![]),
+            ("\"b\"", vec!["string"]),
+        ]],
+    );
+
+    fs::write(
+        language_dir.join("queries").join("highlights.scm"),
+        "; inherits: missing\n(identifier) @variable\n",
+    )
+    .unwrap();
+    match HIGHLIGHTER.load_configuration_from_language_dir(language, &language_dir) {
+        Err(LoadError::InheritedLanguageNotFound(name)) => assert_eq!(name, "missing"),
+        _ => panic!("Expected an error for the missing inherited language"),
+    }
+}
+
+#[test]
+fn test_loading_configuration_from_language_dir_with_a_file_ending_in_a_comment() {
+    let language = get_highlight_dir_test_language();
+    let dir = tempfile::tempdir().unwrap();
+    let language_dir = dir.path().join("tree-sitter-test");
+    let first_path = language_dir.join("queries").join("first.scm");
+    let second_path = language_dir.join("queries").join("second.scm");
+    fs::create_dir_all(language_dir.join("src")).unwrap();
+    fs::create_dir_all(language_dir.join("queries")).unwrap();
+    fs::write(
+        language_dir.join("src").join("metadata.json"),
+        r#"{"highlights": ["queries/first.scm", "queries/second.scm"]}"#,
+    )
+    .unwrap();
+
+    // The first file ends with a comment and no newline, which must not comment out the
+    // first pattern of the second file.
+    fs::write(&first_path, "(number) @constant\n; numbers").unwrap();
+    fs::write(&second_path, "(identifier) @variable\n").unwrap();
+    let config = HIGHLIGHTER
+        .load_configuration_from_language_dir(language, &language_dir)
+        .unwrap();
+    assert_eq!(config.query.pattern_count(), 2);
+    assert_eq!(
+        &to_token_vector("a 1", &config).unwrap(),
+        &[vec![
+            ("a", vec!["variable"]),
+            (" ", vec![]),
+            ("1", vec!["constant"]),
+        ]],
+    );
+
+    // The separators are accounted for when finding the line of an invalid pattern.
+    fs::write(
+        &second_path,
+        "(identifier) @variable\n(nonexistent) @error\n",
+    )
+    .unwrap();
+    match HIGHLIGHTER.load_configuration_from_language_dir(language, &language_dir) {
+        Err(LoadError::QueryFile { path, row, .. }) => {
+            assert_eq!(path, second_path);
+            assert_eq!(row, 2);
+        }
+        _ => panic!("Expected an error for the invalid query file"),
+    }
+    fs::write(&first_path, "(number) @constant\n(nonexistent) @error").unwrap();
+    match HIGHLIGHTER.load_configuration_from_language_dir(language, &language_dir) {
+        Err(LoadError::QueryFile { path, row, .. }) => {
+            assert_eq!(path, first_path);
+            assert_eq!(row, 2);
+        }
+        _ => panic!("Expected an error for the invalid query file"),
+    }
+}
+
+#[test]
+fn test_loading_configuration_from_language_dir_with_diamond_inheritance() {
+    let language = get_highlight_dir_test_language();
+    let dir = tempfile::tempdir().unwrap();
+    let highlights_path = |name: &str| {
+        dir.path()
+            .join(format!("tree-sitter-{}", name))
+            .join("queries")
+            .join("highlights.scm")
+    };
+    for (name, query) in &[
+        ("ecma", "(number) @constant\n"),
+        ("typescript", "; inherits: ecma\n(string) @string\n"),
+        ("jsx", "; inherits: ecma\n(identifier) @variable\n"),
+        (
+            "tsx",
+            "; inherits: typescript,jsx\n(identifier) @function\n",
+        ),
+    ] {
+        fs::create_dir_all(highlights_path(name).parent().unwrap()).unwrap();
+        fs::write(highlights_path(name), query).unwrap();
+    }
+
+    // The queries of `ecma` are only included once, before those of both languages that
+    // inherit from it.
+    let tsx_dir = dir.path().join("tree-sitter-tsx");
+    assert_eq!(
+        query_paths_from_language_dir(&tsx_dir, QuerySection::Highlights).unwrap(),
+        vec![
+            highlights_path("ecma"),
+            highlights_path("typescript"),
+            highlights_path("jsx"),
+            highlights_path("tsx"),
+        ]
+    );
+    assert_eq!(
+        query_paths_from_language_dir(&tsx_dir, QuerySection::Locals).unwrap(),
+        Vec::<PathBuf>::new()
+    );
+    let config = HIGHLIGHTER
+        .load_configuration_from_language_dir(language, &tsx_dir)
+        .unwrap();
+    assert_eq!(config.query.pattern_count(), 4);
+    assert_eq!(
+        &to_token_vector("a 1", &config).unwrap(),
+        &[vec![
+            ("a", vec!["variable"]),
+            (" ", vec![]),
+            ("1", vec!["constant"]),
+        ]],
+    );
+
+    // Cycles are reported along with the chain of inheritance.
+    fs::write(
+        highlights_path("ecma"),
+        "; inherits: tsx\n(number) @constant\n",
+    )
+    .unwrap();
+    let expected_chain = ["tsx", "typescript", "ecma", "tsx"]
+        .iter()
+        .map(|name| format!("tree-sitter-{}", name))
+        .collect::<Vec<_>>();
+    match query_paths_from_language_dir(&tsx_dir, QuerySection::Highlights) {
+        Err(LoadError::InheritanceCycle(chain)) => assert_eq!(chain, expected_chain),
+        _ => panic!("Expected an inheritance cycle error"),
+    }
+    match HIGHLIGHTER.load_configuration_from_language_dir(language, &tsx_dir) {
+        Err(LoadError::InheritanceCycle(chain)) => assert_eq!(chain, expected_chain),
+        _ => panic!("Expected an inheritance cycle error"),
+    }
+}
+
+#[test]
+fn test_loading_configuration_from_dir_with_a_language_lookup() {
+    let language = get_highlight_dir_test_language();
+    let dir = tempfile::tempdir().unwrap();
+    let runtime_dir = dir.path().join("runtime");
+    let base_highlights_path = runtime_dir
+        .join("base")
+        .join("queries")
+        .join("highlights.scm");
+    let language_dir = dir.path().join("test");
+    fs::create_dir_all(base_highlights_path.parent().unwrap()).unwrap();
+    fs::create_dir_all(language_dir.join("queries")).unwrap();
+    fs::write(
+        &base_highlights_path,
+        "(number) @constant\n(string) @string\n",
+    )
+    .unwrap();
+    fs::write(
+        language_dir.join("queries").join("highlights.scm"),
+        "; inherits: base\n\n(identifier) @variable\n",
+    )
+    .unwrap();
+
+    // Inherited languages are found with the callback rather than among the siblings of
+    // the language's directory.
+    let find_language_dir = |name: &str| Some(runtime_dir.join(name));
+    match HIGHLIGHTER.load_configuration_from_dir(language, &language_dir, |_| None) {
+        Err(LoadError::InheritedLanguageNotFound(name)) => assert_eq!(name, "base"),
+        _ => panic!("Expected an error for the missing inherited language"),
+    }
+    let config = HIGHLIGHTER
+        .load_configuration_from_dir(language, &language_dir, find_language_dir)
+        .unwrap();
+    assert_eq!(config.query.pattern_count(), 3);
+    assert_eq!(
+        &to_token_vector("a 1 \"b\"", &config).unwrap(),
+        &[vec![
+            ("a", vec!["variable"]),
+            (" ", vec![]),
+            ("1", vec!["constant"]),
+            (" ", vec![]),
+            ("\"b\"", vec!["string"]),
+        ]],
+    );
+
+    // Invalid patterns are reported along with the file and the line that contain them,
+    // rather than their position within the concatenated queries.
+    fs::write(
+        &base_highlights_path,
+        "(number) @constant\n(nonexistent) @error\n(string) @string\n",
+    )
+    .unwrap();
+    match HIGHLIGHTER.load_configuration_from_dir(language, &language_dir, find_language_dir) {
+        Err(LoadError::QueryFile { path, row, error }) => {
+            assert_eq!(path, base_highlights_path);
+            assert_eq!(row, 2);
+            assert_eq!(error.section, QuerySection::Highlights);
+        }
+        _ => panic!("Expected an error for the invalid query file"),
+    }
+    fs::write(&base_highlights_path, "(number) @constant\n").unwrap();
+    fs::write(
+        language_dir.join("queries").join("highlights.scm"),
+        "; inherits: base\n\n(identifier) @variable\n(nonexistent) @error\n",
+    )
+    .unwrap();
+    match HIGHLIGHTER.load_configuration_from_dir(language, &language_dir, find_language_dir) {
+        Err(LoadError::QueryFile { path, row, .. }) => {
+            assert_eq!(path, language_dir.join("queries").join("highlights.scm"));
+            assert_eq!(row, 4);
+        }
+        _ => panic!("Expected an error for the invalid query file"),
+    }
+}
+
+#[test]
+fn test_loading_configuration_with_invalid_queries() {
+    let language = get_highlight_dir_test_language();
+    let injections_query = "(string) @injection.content\n";
+    let locals_query = "(program) @local.scope\n(identifier) @local.reference\n";
+    let highlights_query = "(identifier) @variable\n(number) @constant\n";
+
+    // Errors are reported relative to the query that contains them.
+    assert_eq!(
+        HIGHLIGHTER
+            .load_configuration(
+                language,
+                highlights_query,
+                "(string) @injection.content\n(strin) @injection.site\n",
+                locals_query,
+            )
+            .err(),
+        Some(Error::InvalidQuery(ConfigurationError {
+            section: QuerySection::Injections,
+            error: QueryError::NodeType(2, "strin".to_string()),
+            offset: 29,
+            column: 1,
+        }))
+    );
+    assert_eq!(
+        HIGHLIGHTER
+            .load_configuration(
+                language,
+                highlights_query,
+                injections_query,
+                "(program) @local.scope\n(identifier) @local.reference)\n",
+            )
+            .err(),
+        Some(Error::InvalidQuery(ConfigurationError {
+            section: QuerySection::Locals,
+            error: QueryError::Syntax(
+                2,
+                [
+                    "(identifier) @local.reference)",
+                    "                             ^",
+                ]
+                .join("\n")
+            ),
+            offset: 52,
+            column: 29,
+        }))
+    );
+    let error = HIGHLIGHTER
+        .load_configuration(
+            language,
+            "(identifier) @variable\n\n(program foo: (number)) @constant\n",
+            injections_query,
+            locals_query,
+        )
+        .err()
+        .unwrap();
+    assert_eq!(
+        error,
+        Error::InvalidQuery(ConfigurationError {
+            section: QuerySection::Highlights,
+            error: QueryError::Field(3, "foo".to_string()),
+            offset: 33,
+            column: 9,
+        })
+    );
+    assert_eq!(
+        error.to_string(),
+        "Invalid field name foo on line 3 of the highlights query"
+    );
+
+    // The C API makes the description of the error available.
+    let highlight_names = [c_string("variable")];
+    let highlight_names = highlight_names
+        .iter()
+        .map(|name| name.as_ptr())
+        .collect::<Vec<_>>();
+    let highlighter = c::ts_highlighter_new(highlight_names.as_ptr(), highlight_names.as_ptr(), 1);
+    assert!(c::ts_highlighter_last_error_message(highlighter).is_null());
+    let scope = c_string("source.test");
+    let highlights_query = "(identifier) @variable\n(program foo: (number)) @constant\n";
+    let result = c::ts_highlighter_add_language(
+        highlighter,
+        scope.as_ptr(),
+        ptr::null(),
+        language,
+        highlights_query.as_ptr() as *const i8,
+        injections_query.as_ptr() as *const i8,
+        locals_query.as_ptr() as *const i8,
+        highlights_query.len() as u32,
+        injections_query.len() as u32,
+        locals_query.len() as u32,
+    );
+    assert!(matches!(result, c::ErrorCode::InvalidQuery));
+    let message = c::ts_highlighter_last_error_message(highlighter);
+    assert_eq!(
+        unsafe { std::ffi::CStr::from_ptr(message) }
+            .to_str()
+            .unwrap(),
+        "Invalid field name foo on line 2 of the highlights query"
+    );
+
+    // A language with an unsupported ABI version is rejected before its queries are
+    // compiled. Only the version of this fake language is read.
+    let mut fake_language = [0u32; 64];
+    fake_language[0] = (tree_sitter::LANGUAGE_VERSION + 1) as u32;
+    let incompatible_language: Language = unsafe { std::mem::transmute(fake_language.as_ptr()) };
+    assert_eq!(
+        HIGHLIGHTER
+            .load_configuration(incompatible_language, highlights_query, "", "")
+            .err(),
+        Some(Error::IncompatibleLanguageVersion {
+            expected: tree_sitter::MIN_COMPATIBLE_LANGUAGE_VERSION..=tree_sitter::LANGUAGE_VERSION,
+            actual: tree_sitter::LANGUAGE_VERSION + 1,
+        })
+    );
+    let result = c::ts_highlighter_add_language(
+        highlighter,
+        scope.as_ptr(),
+        ptr::null(),
+        incompatible_language,
+        highlights_query.as_ptr() as *const i8,
+        ptr::null(),
+        ptr::null(),
+        highlights_query.len() as u32,
+        0,
+        0,
+    );
+    assert!(matches!(result, c::ErrorCode::IncompatibleLanguageVersion));
+    assert!(!c::ts_highlighter_last_error_message(highlighter).is_null());
+    c::ts_highlighter_delete(highlighter);
+}
+
+#[test]
+fn test_listing_nonconformant_capture_names() {
+    let highlighter = Highlighter::new(
+        ["function", "function.builtin", "variable"]
+            .iter()
+            .cloned()
+            .map(String::from)
+            .collect(),
+    );
+    let config = highlighter
+        .load_configuration(
+            get_highlight_dir_test_language(),
+            "(identifier) @variable.parameter\n(number) @fuction.builtin\n(string) @function.bultin\n(identifier) @constant\n(number) @fuction.builtin\n",
+            "(string) @injection.content\n",
+            "(program) @local.scope\n(identifier) @local.reference\n",
+        )
+        .unwrap();
+
+    // Capture names are matched by their dot-separated parts, so only the names that
+    // don't contain all of the parts of any highlight name are reported, once each.
+    assert_eq!(
+        config.nonconformant_capture_names(),
+        &["fuction.builtin", "constant"]
+    );
+}
+
+#[test]
+fn test_highlighting_with_pattern_debugging() {
+    use tree_sitter_highlight::util::query_line_for_offset;
+
+    let language = get_highlight_dir_test_language();
+    let highlights_query =
+        "; Comments\n(number) @constant\n\n(string) @string\n(identifier) @variable\n";
+    let config = HIGHLIGHTER
+        .load_configuration(
+            language,
+            highlights_query,
+            "(string) @injection.content\n",
+            "(program) @local.scope\n(identifier) @local.reference\n",
+        )
+        .unwrap();
+
+    let source = b"a 1 \"b\"";
+    let mut context = HighlightContext::new();
+    let options = HighlightOptions::new().debug(true);
+    let events = HIGHLIGHTER
+        .highlight_with_options(&mut context, &config, source, options, |_| None)
+        .unwrap()
+        .map(Result::unwrap)
+        .collect::<Vec<_>>();
+
+    let patterns = events
+        .iter()
+        .filter_map(|event| match event {
+            HighlightEvent::HighlightStartWithPattern {
+                highlight,
+                pattern_index,
+                pattern_offset,
+            } => Some((highlight.0, *pattern_index, *pattern_offset)),
+            HighlightEvent::HighlightStart(_) => panic!("Expected pattern information"),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(patterns.len(), 3);
+
+    // The injections and locals patterns come first in the concatenated query, but the
+    // offsets are relative to the highlights query.
+    for (highlight, pattern_index, pattern_offset) in patterns {
+        assert!(pattern_index >= 3);
+        assert!(pattern_offset < highlights_query.len());
+        let pattern_source = highlights_query[pattern_offset..].lines().next().unwrap();
+        let capture_name = &pattern_source[(pattern_source.find('@').unwrap() + 1)..];
+        assert!(HIGHLIGHTER.names()[highlight].starts_with(capture_name));
+        assert_eq!(
+            query_line_for_offset(highlights_query, pattern_offset),
+            match capture_name {
+                "constant" => 1,
+                "string" => 3,
+                _ => 4,
+            }
+        );
+    }
+
+    // Without debugging, the events don't contain any pattern information.
+    let events = HIGHLIGHTER
+        .highlight(&mut context, &config, source, None, |_| None)
+        .unwrap();
+    for event in events {
+        if let HighlightEvent::HighlightStartWithPattern { .. } = event.unwrap() {
+            panic!("Unexpected pattern information");
+        }
+    }
+}
+
+#[test]
+fn test_highlighting_with_pattern_debugging_and_highlighted_references() {
+    // The reference isn't matched by any highlights pattern, so its highlight comes from
+    // its definition, and the locals pattern that captured it has no offset within the
+    // highlights query.
+    let config = HIGHLIGHTER
+        .load_configuration(
+            get_local_definition_test_language(),
+            "(definition (name) @variable.parameter)\n",
+            "",
+            "(program) @local.scope\n(definition (name) @local.definition)\n(name) @local.reference\n",
+        )
+        .unwrap();
+
+    let source = b"let 'a' 'a'";
+    let mut context = HighlightContext::new();
+    let options = HighlightOptions::new().debug(true);
+    let events = HIGHLIGHTER
+        .highlight_with_options(&mut context, &config, source, options, |_| None)
+        .unwrap()
+        .map(Result::unwrap)
+        .filter_map(|event| match event {
+            HighlightEvent::HighlightStartWithPattern {
+                highlight,
+                pattern_index,
+                pattern_offset,
+            } => Some((highlight.0, Some((pattern_index, pattern_offset)))),
+            HighlightEvent::HighlightStart(highlight) => Some((highlight.0, None)),
+            _ => None,
+        })
+        .map(|(highlight, pattern)| (HIGHLIGHTER.names()[highlight].as_str(), pattern))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        events,
+        vec![
+            ("variable.parameter", Some((3, 0))),
+            ("variable.parameter", None)
+        ]
+    );
+}
+
+#[test]
+fn test_counting_pattern_usage_with_highlighted_references() {
+    let highlights_query = "\"let\" @keyword\n(definition (name) @variable.parameter)\n";
+    let config = HIGHLIGHTER
+        .load_configuration(
+            get_local_definition_test_language(),
+            highlights_query,
+            "",
+            "(program) @local.scope\n(definition (name) @local.definition)\n(name) @local.reference\n",
+        )
+        .unwrap();
+
+    // The reference is highlighted by the locals query alone, so no pattern is counted
+    // for it.
+    let usage = PatternUsage::new(&config);
+    let mut context = HighlightContext::new();
+    let options = HighlightOptions::new().pattern_usage(&usage);
+    let events = HIGHLIGHTER
+        .highlight_with_options(&mut context, &config, b"let 'a' 'a'", options, |_| None)
+        .unwrap();
+    for event in events {
+        event.unwrap();
+    }
+    assert_eq!(
+        usage.highlight_patterns(),
+        vec![
+            PatternCount {
+                pattern_index: 0,
+                offset: 0,
+                row: 0,
+                count: 1,
+            },
+            PatternCount {
+                pattern_index: 1,
+                offset: highlights_query.find("(definition").unwrap(),
+                row: 1,
+                count: 1,
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_highlighting_syntax_errors() {
+    let language = compile_grammar(
+        r#"{
+            "name": "highlight_errors_test",
+            "extras": [{"type": "PATTERN", "value": "\\s"}],
+            "rules": {
+                "program": {"type": "REPEAT", "content": {"type": "SYMBOL", "name": "statement"}},
+                "statement": {
+                    "type": "SEQ",
+                    "members": [
+                        {"type": "SYMBOL", "name": "identifier"},
+                        {"type": "STRING", "value": "="},
+                        {"type": "SYMBOL", "name": "expression"},
+                        {"type": "STRING", "value": ";"}
+                    ]
+                },
+                "expression": {
+                    "type": "CHOICE",
+                    "members": [
+                        {"type": "SYMBOL", "name": "identifier"},
+                        {"type": "SYMBOL", "name": "number"},
+                        {"type": "SYMBOL", "name": "call"}
+                    ]
+                },
+                "call": {
+                    "type": "SEQ",
+                    "members": [
+                        {"type": "SYMBOL", "name": "identifier"},
+                        {"type": "STRING", "value": "("},
+                        {"type": "SYMBOL", "name": "expression"},
+                        {"type": "STRING", "value": ")"}
+                    ]
+                },
+                "identifier": {"type": "PATTERN", "value": "[a-z]+"},
+                "number": {"type": "PATTERN", "value": "\\d+"}
+            }
+        }"#,
+    )
+    .unwrap();
+    let config = HIGHLIGHTER
+        .load_configuration(
+            language,
+            "(identifier) @variable\n(number) @constant\n\"=\" @operator\n",
+            "",
+            "",
+        )
+        .unwrap();
+    let source = fs::read(fixtures_dir().join("highlight_errors").join("broken.txt")).unwrap();
+    let error = Highlight(
+        HIGHLIGHTER
+            .names()
+            .iter()
+            .position(|name| name == "error")
+            .unwrap(),
+    );
+
+    // The `ERROR` node around `2 x` is highlighted, and the highlights inside of it are
+    // nested within the error highlight. The `MISSING` closing parenthesis is marked
+    // with an empty highlight.
+    let events = to_event_strings(
+        &config,
+        &source,
+        HighlightOptions::new().error_highlight(error),
+    )
+    .unwrap();
+    assert_eq!(
+        events[20..35],
+        [
+            "<constant>",
+            "11..12",
+            "</>",
+            "12..13",
+            "<error>",
+            "<constant>",
+            "13..14",
+            "</>",
+            "14..15",
+            "<variable>",
+            "15..16",
+            "</>",
+            "</>",
+            "16..18",
+            "<variable>",
+        ]
+    );
+    assert_eq!(
+        events[events.len() - 6..],
+        ["<variable>", "24..25", "</>", "<error>", "</>", "25..27"]
+    );
+
+    // Errors are only highlighted when requested.
+    assert!(!to_event_strings(&config, &source, HighlightOptions::new())
+        .unwrap()
+        .contains(&"<error>".to_string()));
+}
+
+#[test]
+fn test_highlighting_with_byte_order_mark() {
+    let language = get_highlight_dir_test_language();
+    let config = HIGHLIGHTER
+        .load_configuration(
+            language,
+            "(identifier) @variable\n(number) @constant\n",
+            "",
+            "",
+        )
+        .unwrap();
+    let source = "\u{feff}a 1".as_bytes();
+
+    // Offsets remain relative to the start of the source, and the byte order mark
+    // is emitted on its own, outside of any highlight.
+    let options = HighlightOptions::new().byte_order_mark_handling(ByteOrderMarkHandling::Skip);
+    assert_eq!(
+        to_event_strings(&config, source, options.clone()).unwrap(),
+        &[
+            "0..3",
+            "<variable>",
+            "3..4",
+            "</>",
+            "4..5",
+            "<constant>",
+            "5..6",
+            "</>"
+        ]
+    );
+
+    // Offsets are relative to the end of the byte order mark.
+    let rebased_options = HighlightOptions::new()
+        .byte_order_mark_handling(ByteOrderMarkHandling::SkipAndRebaseOffsets);
+    assert_eq!(
+        to_event_strings(&config, source, rebased_options.clone()).unwrap(),
+        &[
+            "<variable>",
+            "0..1",
+            "</>",
+            "1..2",
+            "<constant>",
+            "2..3",
+            "</>"
+        ]
+    );
+
+    // Sources without a byte order mark are unaffected.
+    assert_eq!(
+        to_event_strings(&config, b"a 1", rebased_options).unwrap(),
+        &[
+            "<variable>",
+            "0..1",
+            "</>",
+            "1..2",
+            "<constant>",
+            "2..3",
+            "</>"
+        ]
+    );
+}
+
+#[test]
+fn test_highlighting_with_negated_text_predicates() {
+    let language = get_highlight_dir_test_language();
+    let config = HIGHLIGHTER
+        .load_configuration(
+            language,
+            r#"
+            ((identifier) @constant (not-eq? @constant "self"))
+            ((number) @keyword (not-match? @keyword "^0"))
+            (identifier) @variable
+            (number) @string
+            "#,
+            "",
+            "",
+        )
+        .unwrap();
+
+    // When the first pattern for a node is rejected by its text predicate, the
+    // next pattern for the same node applies.
+    assert_eq!(
+        &to_token_vector("self abc 01 23", &config).unwrap(),
+        &[vec![
+            ("self", vec!["variable"]),
+            (" ", vec![]),
+            ("abc", vec!["constant"]),
+            (" ", vec![]),
+            ("01", vec!["string"]),
+            (" ", vec![]),
+            ("23", vec!["keyword"]),
+        ]],
+    );
+}
+
+#[test]
+fn test_loading_configuration_salvaging_unsupported_patterns() {
+    let language = get_highlight_dir_test_language();
+    let highlights_query = r#"
+; Patterns written for a newer version of the query engine.
+((identifier) @constant (any-of? @constant "self" "this"))
+[(number) (string)] @string
+(identifier) @variable
+(number) @keyword
+((number) @constant (#eq? @constant "0"))
+"#;
+
+    // Without salvaging, the whole configuration fails to load.
+    assert!(HIGHLIGHTER
+        .load_configuration(language, highlights_query, "", "")
+        .is_err());
+
+    let (config, skipped_patterns) = HIGHLIGHTER
+        .load_configuration_salvaging(language, highlights_query, "", "", 10)
+        .unwrap();
+    assert_eq!(
+        skipped_patterns
+            .iter()
+            .map(|pattern| (pattern.section, pattern.row, pattern.to_string()))
+            .collect::<Vec<_>>(),
+        vec![
+            (
+                QuerySection::Highlights,
+                3,
+                "unsupported predicate any-of? at line 3 of the highlights query".to_string()
+            ),
+            (
+                QuerySection::Highlights,
+                4,
+                "unsupported syntax at line 4 of the highlights query".to_string()
+            ),
+            (
+                QuerySection::Highlights,
+                7,
+                "unsupported syntax at line 7 of the highlights query".to_string()
+            ),
+        ]
+    );
+
+    // The supported patterns still apply.
+    assert_eq!(
+        &to_token_vector("self 0", &config).unwrap(),
+        &[vec![
+            ("self", vec!["variable"]),
+            (" ", vec![]),
+            ("0", vec!["keyword"]),
+        ]],
+    );
+
+    // The number of skipped patterns is bounded.
+    assert_eq!(
+        HIGHLIGHTER
+            .load_configuration_salvaging(language, highlights_query, "", "", 2)
+            .err(),
+        Some(Error::InvalidQuery(ConfigurationError {
+            section: QuerySection::Highlights,
+            error: QueryError::Predicate("Unknown query predicate function any-of?".to_string()),
+            offset: 0,
+            column: 0,
+        }))
+    );
+
+    // Other kinds of errors are not salvaged.
+    assert_eq!(
+        HIGHLIGHTER
+            .load_configuration_salvaging(language, "(identifer) @variable\n", "", "", 10)
+            .err(),
+        Some(Error::InvalidQuery(ConfigurationError {
+            section: QuerySection::Highlights,
+            error: QueryError::NodeType(1, "identifer".to_string()),
+            offset: 1,
+            column: 1,
+        }))
+    );
+}
+
+#[test]
+fn test_loading_configuration_salvaging_newer_syntax_but_not_syntax_errors() {
+    let language = get_highlight_dir_test_language();
+    let highlights_query = r#"
+(program (identifier)+ @keyword)
+(program . (number) @constant)
+(_) @constant
+(program (string) !field)
+(identifier) @variable
+"#;
+    let (_, skipped_patterns) = HIGHLIGHTER
+        .load_configuration_salvaging(language, highlights_query, "", "", 10)
+        .unwrap();
+    assert_eq!(
+        skipped_patterns
+            .iter()
+            .map(|pattern| pattern.row)
+            .collect::<Vec<_>>(),
+        vec![2, 3, 4, 5]
+    );
+
+    // Mistakes that aren't newer syntax are reported instead of dropping the pattern.
+    for (highlights_query, offset) in &[
+        ("(identifier) @variable\n(number @keyword\n", 31),
+        ("(identifier) @variable)\n", 22),
+        ("(identifier) @variable\n(number\n", 31),
+    ] {
+        match HIGHLIGHTER.load_configuration_salvaging(language, highlights_query, "", "", 10) {
+            Err(Error::InvalidQuery(ConfigurationError {
+                error: QueryError::Syntax(..),
+                offset: error_offset,
+                ..
+            })) => assert_eq!(error_offset, *offset, "{:?}", highlights_query),
+            result => panic!("Unexpected result {:?}", result.map(|(_, skipped)| skipped)),
+        }
+    }
+}
+
+#[test]
+fn test_highlighting_with_negated_text_predicates_and_local_variables() {
+    let language = get_highlight_dir_test_language();
+    let config = HIGHLIGHTER
+        .load_configuration(
+            language,
+            r#"
+            ((identifier) @function (is-not? local) (not-eq? @function "self"))
+            ((identifier) @constant (not-eq? @constant "self"))
+            (identifier) @variable
+            "#,
+            "",
+            r#"
+            (program) @local.scope
+            ((identifier) @local.definition (match? @local.definition "^def"))
+            (identifier) @local.reference
+            "#,
+        )
+        .unwrap();
+
+    // Local variables skip the pattern that is disabled for them, and `self` skips both
+    // patterns that exclude it by text, so each falls through to a later pattern.
+    assert_eq!(
+        &to_token_vector("defa abc self defa", &config).unwrap(),
+        &[vec![
+            ("defa", vec!["constant"]),
+            (" ", vec![]),
+            ("abc", vec!["function"]),
+            (" ", vec![]),
+            ("self", vec!["variable"]),
+            (" ", vec![]),
+            ("defa", vec!["constant"]),
+        ]],
+    );
+}
+
+#[test]
+fn test_highlighting_with_pattern_priorities() {
+    let language = get_highlight_dir_test_language();
+    let config = HIGHLIGHTER
+        .load_configuration(
+            language,
+            r#"
+            (identifier) @variable
+            (number) @constant
+            ((identifier) @keyword (eq? @keyword "if") (set! priority 10))
+            ((identifier) @function (eq? @function "if") (set! priority 10))
+            ((identifier) @function (is-not? local) (set! priority 5))
+            ((number) @string (set! priority "-1"))
+            "#,
+            "",
+            r#"
+            (program) @local.scope
+            ((identifier) @local.definition (match? @local.definition "^def"))
+            (identifier) @local.reference
+            "#,
+        )
+        .unwrap();
+
+    // The generic patterns come first, but later patterns with a higher priority win.
+    // Among patterns with the same priority, the earliest one wins, and patterns with a
+    // lower priority than the default never do. Patterns that are disabled for local
+    // variables are still skipped, regardless of their priority.
+    assert_eq!(
+        &to_token_vector("if abc 1 defa", &config).unwrap(),
+        &[vec![
+            ("if", vec!["keyword"]),
+            (" ", vec![]),
+            ("abc", vec!["function"]),
+            (" ", vec![]),
+            ("1", vec!["constant"]),
+            (" ", vec![]),
+            ("defa", vec!["variable"]),
+        ]],
+    );
+}
+
+#[test]
+fn test_highlighting_identical_ranges_in_injected_layers() {
+    let html_language = compile_grammar(
+        r#"{
+            "name": "mini_html",
+            "extras": [{"type": "PATTERN", "value": "\\s"}],
+            "rules": {
+                "element": {"type": "REPEAT", "content": {"type": "SYMBOL", "name": "attribute"}},
+                "attribute": {
+                    "type": "SEQ",
+                    "members": [
+                        {"type": "SYMBOL", "name": "attribute_name"},
+                        {"type": "STRING", "value": "="},
+                        {"type": "SYMBOL", "name": "attribute_value"}
+                    ]
+                },
+                "attribute_value": {"type": "SYMBOL", "name": "text"},
+                "attribute_name": {"type": "PATTERN", "value": "[a-z]+"},
+                "text": {"type": "SYMBOL", "name": "characters"},
+                "characters": {"type": "PATTERN", "value": "[a-z:]+;"}
+            }
+        }"#,
+    )
+    .unwrap();
+    let css_language = compile_grammar(
+        r#"{
+            "name": "mini_css",
+            "rules": {
+                "stylesheet": {"type": "SYMBOL", "name": "declaration"},
+                "declaration": {"type": "PATTERN", "value": "[a-z:]+;"}
+            }
+        }"#,
+    )
+    .unwrap();
+    let html_config = HIGHLIGHTER
+        .load_configuration(
+            html_language,
+            "(attribute_name) @attribute\n(attribute_value) @string\n",
+            "((text (characters) @injection.content) @injection.site (set! injection.language \"css\"))\n",
+            "",
+        )
+        .unwrap();
+    let css_config = HIGHLIGHTER
+        .load_configuration(css_language, "(declaration) @property\n", "", "")
+        .unwrap();
+
+    // The HTML layer highlights the `attribute_value` node before it processes the
+    // injection for the `text` node, which has the same range. Only the highlight from
+    // the deeper CSS layer is emitted.
+    let source = b"style=color:red;";
+    let mut renderer = HtmlRenderer::new();
+    let mut context = HighlightContext::new();
+    let events = HIGHLIGHTER
+        .highlight(&mut context, &html_config, source, None, |name| {
+            if name == "css" {
+                Some(&css_config)
+            } else {
+                None
+            }
+        })
+        .unwrap();
+    renderer
+        .render(events, source, &|highlight| {
+            HTML_ATTRS[highlight.0].as_bytes()
+        })
+        .unwrap();
+    assert_eq!(
+        renderer.lines().collect::<Vec<_>>(),
+        &["<span class=attribute>style</span>=<span class=property>color:red;</span>\n"]
+    );
+}
+
+#[test]
+fn test_highlighting_with_options() {
+    let language = get_highlight_dir_test_language();
+    let config = HIGHLIGHTER
+        .load_configuration(
+            language,
+            "(identifier) @variable\n(number) @constant\n",
+            "",
+            "",
+        )
+        .unwrap();
+    let source = b"abc 1 d";
+
+    // Highlights that begin before the byte range are started at its beginning.
+    assert_eq!(
+        to_event_strings(&config, source, HighlightOptions::new().byte_range(1..5)).unwrap(),
+        &[
+            "<variable>",
+            "1..3",
+            "</>",
+            "3..4",
+            "<constant>",
+            "4..5",
+            "</>"
+        ]
+    );
+
+    // Byte ranges are clamped to the length of the source.
+    assert_eq!(
+        to_event_strings(&config, source, HighlightOptions::new().byte_range(5..100)).unwrap(),
+        &["5..6", "<variable>", "6..7", "</>"]
+    );
+    assert_eq!(
+        to_event_strings(&config, source, HighlightOptions::new().byte_range(50..100)).unwrap(),
+        Vec::<String>::new()
+    );
+
+    // When both a cancellation flag and a timeout are given, either one cancels highlighting.
+    let source = "abc 1 d\n".repeat(500);
+    let source = source.as_bytes();
+    let cancellation_flag = AtomicUsize::new(0);
+    let options = HighlightOptions::new()
+        .cancellation_flag(&cancellation_flag)
+        .timeout(Duration::from_secs(60));
+    assert_eq!(
+        to_event_strings(&config, source, options.clone()).unwrap(),
+        to_event_strings(&config, source, HighlightOptions::default()).unwrap(),
+    );
+    assert_eq!(
+        to_event_strings(
+            &config,
+            source,
+            options.clone().timeout(Duration::from_secs(0))
+        ),
+        Err(Error::Cancelled)
+    );
+    cancellation_flag.store(1, Ordering::SeqCst);
+    assert_eq!(
+        to_event_strings(&config, source, options),
+        Err(Error::Cancelled)
+    );
+}
+
+#[test]
+fn test_highlighting_with_a_timeout() {
+    let config = HIGHLIGHTER
+        .load_configuration(
+            get_highlight_dir_test_language(),
+            "(identifier) @variable\n(number) @constant\n",
+            "",
+            "",
+        )
+        .unwrap();
+    let source = "abc 1 d \"e\"\n".repeat(20_000);
+
+    // Depending on how far highlighting gets before the timeout expires, either the parse
+    // of the document is cancelled, or the iterator returns a single error, and then ends.
+    let start_time = Instant::now();
+    let mut context = HighlightContext::new();
+    let options = HighlightOptions::new().timeout(Duration::from_millis(10));
+    match HIGHLIGHTER.highlight_with_options(
+        &mut context,
+        &config,
+        source.as_bytes(),
+        options,
+        |_| None,
+    ) {
+        Ok(mut events) => {
+            assert!(events.by_ref().any(|event| event.is_err()));
+            assert!(events.next().is_none());
+        }
+        Err(error) => assert_eq!(error, Error::Cancelled),
+    }
+    assert!(start_time.elapsed() < Duration::from_secs(5));
+
+    // The same is true when highlighting is cancelled by the flag.
+    let cancellation_flag = AtomicUsize::new(0);
+    let options = HighlightOptions::new().cancellation_flag(&cancellation_flag);
+    let mut events = HIGHLIGHTER
+        .highlight_with_options(&mut context, &config, source.as_bytes(), options, |_| None)
+        .unwrap();
+    assert!(events.next().unwrap().is_ok());
+    cancellation_flag.store(1, Ordering::SeqCst);
+    assert_eq!(events.find_map(|event| event.err()), Some(Error::Cancelled));
+    assert!(events.next().is_none());
+}
+
+#[test]
+fn test_highlighting_spans() {
+    let config = HIGHLIGHTER
+        .load_configuration(
+            get_highlight_dir_test_language(),
+            "(program) @keyword\n(string) @string\n(identifier) @variable\n(number) @variable\n",
+            "",
+            "",
+        )
+        .unwrap();
+    let source = "a \"b\" c1";
+
+    // The outer highlight is split around the inner ones, and the adjacent identifier and
+    // number are combined into one span.
+    let mut context = HighlightContext::new();
+    let spans = HIGHLIGHTER
+        .highlight_spans(
+            &mut context,
+            &config,
+            source.as_bytes(),
+            HighlightOptions::new(),
+            |_| None,
+        )
+        .unwrap();
+    assert_eq!(
+        spans
+            .iter()
+            .map(|(range, highlight)| (
+                &source[range.clone()],
+                HIGHLIGHTER.names()[highlight.0].as_str()
+            ))
+            .collect::<Vec<_>>(),
+        &[
+            ("a", "variable"),
+            (" ", "keyword"),
+            ("\"b\"", "string"),
+            (" ", "keyword"),
+            ("c1", "variable"),
+        ]
+    );
+
+    // Empty sources and unhighlighted regions are left out.
+    let events = vec![
+        Ok(HighlightEvent::Source { start: 0, end: 2 }),
+        Ok(HighlightEvent::HighlightStart(Highlight(1))),
+        Ok(HighlightEvent::Source { start: 2, end: 2 }),
+        Ok(HighlightEvent::HighlightEnd),
+        Ok(HighlightEvent::HighlightStart(Highlight(2))),
+        Ok(HighlightEvent::Source { start: 2, end: 3 }),
+        Ok(HighlightEvent::HighlightEnd),
+    ];
+    assert_eq!(spans_from_events(events).unwrap(), &[(2..3, Highlight(2))]);
+    assert_eq!(
+        spans_from_events(vec![Err(Error::Cancelled)]),
+        Err(Error::Cancelled)
+    );
+}
+
+#[test]
+fn test_highlighted_lines() {
+    let config = HIGHLIGHTER
+        .load_configuration(
+            get_self_injection_test_language(),
+            "(identifier) @variable\n(number) @constant\n(string) @string\n(string_content) @property\n",
+            "",
+            "",
+        )
+        .unwrap();
+    let source = "a \"b\nc\" 12\r\n'x'\n";
+    let mut context = HighlightContext::new();
+    let events = HIGHLIGHTER
+        .highlight(&mut context, &config, source.as_bytes(), None, |_| None)
+        .unwrap();
+    let lines =
+        HighlightedLines::from_events(source.as_bytes(), events, HIGHLIGHTER.names()).unwrap();
+
+    // The innermost highlight is reported for each range of columns, highlights that span
+    // several lines are split at their line endings, and every line has an entry.
+    let column_names = |row: usize| {
+        lines.lines[row]
+            .iter()
+            .map(|h| (h.columns.clone(), h.name.as_str()))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(lines.lines.len(), 4);
+    assert_eq!(
+        column_names(0),
+        &[(0..1, "variable"), (2..3, "string"), (3..4, "property")]
+    );
+    assert_eq!(
+        column_names(1),
+        &[(0..1, "property"), (1..2, "string"), (3..5, "constant")]
+    );
+    assert_eq!(
+        column_names(2),
+        &[(0..1, "string"), (1..2, "property"), (2..3, "string")]
+    );
+    assert_eq!(column_names(3), &[]);
+
+    // A caret assertion can look up the highlight at a given column.
+    assert_eq!(lines.name_at(1, 4), Some("constant"));
+    assert_eq!(lines.name_at(1, 2), None);
+    assert_eq!(lines.name_at(9, 0), None);
+
+    // The lines can be serialized, such as to print them as JSON.
+    let json = serde_json::to_string(&lines.lines[2][1]).unwrap();
+    assert_eq!(json, r#"{"columns":{"start":1,"end":2},"name":"property"}"#);
+    let persisted_lines: HighlightedLines =
+        serde_json::from_str(&serde_json::to_string(&lines).unwrap()).unwrap();
+    assert_eq!(persisted_lines, lines);
+}
+
+#[test]
+fn test_highlighting_a_byte_range_with_injections() {
+    let injected_config = HIGHLIGHTER
+        .load_configuration(
+            get_highlight_dir_test_language(),
+            "(identifier) @variable\n",
+            "",
+            "",
+        )
+        .unwrap();
+    let source = b"<script type=\"a\">b 1</script><style>cde</style><style> f</style>";
+    let style_start = 29;
+    assert_eq!(&source[style_start..style_start + 10], b"<style>cde");
+
+    for combined in &["", "(set! injection.combined)"] {
+        let injections_query = format!(
+            concat!(
+                "((script_element (raw_text) @injection.content) @injection.site\n",
+                " (set! injection.language \"injected\"))\n",
+                "((style_element (raw_text) @injection.content) @injection.site\n",
+                " (set! injection.language \"injected\") {})\n",
+            ),
+            combined
+        );
+        let config = HIGHLIGHTER
+            .load_configuration(
+                get_html_like_injection_test_language(),
+                "(attribute_value) @string\n",
+                &injections_query,
+                "",
+            )
+            .unwrap();
+        let mut context = HighlightContext::new();
+
+        // The range starts in the middle of an identifier in an injected layer. Only the
+        // injections whose content overlaps the range are parsed.
+        let range = (style_start + 8)..(style_start + 21);
+        let events = to_event_strings_with_injections(
+            &mut context,
+            &config,
+            source,
+            HighlightOptions::new().byte_range(range.clone()),
+            &injected_config,
+        );
+        assert_eq!(
+            events,
+            &[
+                "<variable>".to_string(),
+                format!("{}..{}", range.start, range.start + 2),
+                "</>".to_string(),
+                format!("{}..{}", range.start + 2, range.end),
+            ],
+            "combined: {:?}",
+            combined
+        );
+        assert_eq!(context.parse_stats().layer_count, 2);
+
+        // The range ends in the middle of an attribute value, and contains no injected
+        // content.
+        let events = to_event_strings_with_injections(
+            &mut context,
+            &config,
+            source,
+            HighlightOptions::new().byte_range(0..15),
+            &injected_config,
+        );
+
+        assert_eq!(events, &["0..14", "<string>", "14..15", "</>"]);
+        assert_eq!(context.parse_stats().layer_count, 1);
+    }
+}
+
+#[test]
+fn test_highlighting_with_a_context_pool_from_many_threads() {
+    let injected_config = HIGHLIGHTER
+        .load_configuration(
+            get_highlight_dir_test_language(),
+            "(identifier) @variable\n(number) @number\n",
+            "",
+            "",
+        )
+        .unwrap();
+    let config = HIGHLIGHTER
+        .load_configuration(
+            get_html_like_injection_test_language(),
+            "(attribute_value) @string\n",
+            concat!(
+                "((script_element (raw_text) @injection.content) @injection.site\n",
+                " (set! injection.language \"injected\"))\n",
+                "((style_element (raw_text) @injection.content) @injection.site\n",
+                " (set! injection.language \"injected\"))\n",
+            ),
+            "",
+        )
+        .unwrap();
+    let configs = Arc::new((config, injected_config));
+    let documents = (0..20)
+        .map(|i| {
+            format!(
+                "<script type=\"a\">b {}</script><style>c</style><script>{} d</script>",
+                i, i
+            )
+        })
+        .collect::<Vec<_>>();
+
+    // The events of each document, highlighted with a context of its own.
+    let expected_events = documents
+        .iter()
+        .map(|document| {
+            to_event_strings_with_injections(
+                &mut HighlightContext::new(),
+                &configs.0,
+                document.as_bytes(),
+                HighlightOptions::new(),
+                &configs.1,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    // The number of cursors that a context keeps after highlighting one of the documents.
+    let single_pool = HighlightContextPool::new(1);
+    to_event_strings_with_injections(
+        &mut single_pool.acquire(),
+        &configs.0,
+        documents[0].as_bytes(),
+        HighlightOptions::new(),
+        &configs.1,
+    );
+    let cursors_per_context = single_pool.cursor_count();
+    assert!(cursors_per_context > 0);
+
+    let pool = Arc::new(HighlightContextPool::new(4));
+    let threads = (0..8)
+        .map(|_| {
+            let pool = pool.clone();
+            let configs = configs.clone();
+            let documents = documents.clone();
+            thread::spawn(move || {
+                let mut results = Vec::new();
+                for _ in 0..10 {
+                    for document in &documents {
+                        let mut context = pool.acquire();
+                        results.push(to_event_strings_with_injections(
+                            &mut context,
+                            &configs.0,
+                            document.as_bytes(),
+                            HighlightOptions::new(),
+                            &configs.1,
+                        ));
+                    }
+                }
+                results
+            })
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        let results = thread.join().unwrap();
+        for (i, events) in results.iter().enumerate() {
+            assert_eq!(events, &expected_events[i % documents.len()]);
+        }
+    }
+
+    // The pool keeps at most its maximum number of contexts, and the contexts don't
+    // accumulate cursors as they are reused.
+    assert!(!pool.is_empty());
+    assert!(pool.len() <= pool.max_size());
+    assert!(pool.cursor_count() <= pool.max_size() * cursors_per_context);
+
+    // A detached context isn't returned to the pool until it is released.
+    let len = pool.len();
+    let context = pool.acquire().detach();
+    assert_eq!(pool.len(), len - 1);
+    pool.release(context);
+    assert_eq!(pool.len(), len);
+}
+
+#[test]
+fn test_highlighting_sibling_injections_at_the_same_offset() {
+    let language = get_highlight_dir_test_language();
+    let mut registry = LanguageRegistry::new();
+    registry.add_language(
+        "outer",
+        HIGHLIGHTER
+            .load_configuration(
+                language,
+                "(identifier) @variable\n",
+                r#"
+                ((program (string) @injection.content) @injection.site
+                 (set! injection.language "strings"))
+                ((program (string) @injection.content) @injection.site
+                 (set! injection.language "constants"))
+                "#,
+                "",
+            )
+            .unwrap(),
+    );
+    registry.add_language(
+        "strings",
+        HIGHLIGHTER
+            .load_configuration(language, "(string) @string\n", "", "")
+            .unwrap(),
+    );
+    registry.add_language(
+        "constants",
+        HIGHLIGHTER
+            .load_configuration(language, "(string) @constant\n", "", "")
+            .unwrap(),
+    );
+    let config = registry.get("outer").unwrap();
+    let source = b"a \"b\" c";
+
+    // Both injected layers have the same depth and start highlighting at the same offset.
+    // The events from the layer that was injected first come first.
+    let to_events = || {
+        let mut context = HighlightContext::new();
+        HIGHLIGHTER
+            .highlight(
+                &mut context,
+                &config,
+                source,
+                None,
+                registry.injection_callback(),
+            )
+            .unwrap()
+            .map(|event| match event.unwrap() {
+                HighlightEvent::HighlightStart(highlight)
+                | HighlightEvent::HighlightStartWithPattern { highlight, .. } => {
+                    format!("<{}>", HIGHLIGHTER.names()[highlight.0])
+                }
+                HighlightEvent::HighlightEnd => "</>".to_string(),
+                HighlightEvent::Source { start, end } => format!("{}..{}", start, end),
+                HighlightEvent::LayerStart { language, depth } => {
+                    format!("[{}:{}]", language, depth)
+                }
+                HighlightEvent::LayerEnd => "[/]".to_string(),
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let events = to_events();
+    assert_eq!(
+        events,
+        &[
+            "<variable>",
+            "0..1",
+            "</>",
+            "1..2",
+            "<string>",
+            "<constant>",
+            "2..5",
+            "</>",
+            "</>",
+            "5..6",
+            "<variable>",
+            "6..7",
+            "</>",
+        ]
+    );
+    for _ in 0..10 {
+        assert_eq!(to_events(), events);
+    }
+}
+
+#[test]
+fn test_highlighting_injections_outside_of_parent_ranges() {
+    let language = compile_grammar(
+        r#"{
+            "name": "nested_groups",
+            "extras": [{"type": "PATTERN", "value": "\\s"}],
+            "rules": {
+                "program": {"type": "REPEAT", "content": {"type": "SYMBOL", "name": "_item"}},
+                "_item": {
+                    "type": "CHOICE",
+                    "members": [
+                        {"type": "SYMBOL", "name": "word"},
+                        {"type": "SYMBOL", "name": "group"}
+                    ]
+                },
+                "group": {
+                    "type": "SEQ",
+                    "members": [
+                        {"type": "STRING", "value": "<"},
+                        {"type": "REPEAT", "content": {"type": "SYMBOL", "name": "_item"}},
+                        {"type": "STRING", "value": ">"}
+                    ]
+                },
+                "word": {"type": "PATTERN", "value": "[a-z]+"}
+            }
+        }"#,
+    )
+    .unwrap();
+
+    // The outer layer injects the inner language into each top-level group, including
+    // its children. The inner layer injects the inner language into each nested group,
+    // *excluding* its children. But nested groups consist entirely of child nodes, so
+    // none of their content lies within the included ranges, and these injections must
+    // be skipped rather than parsing the entire document.
+    let outer_config = HIGHLIGHTER
+        .load_configuration(
+            language,
+            "(word) @variable\n",
+            "((program (group) @injection.content) @injection.site (set! injection.language \"inner\") (set! injection.include-children))\n",
+            "",
+        )
+        .unwrap();
+    let inner_config = HIGHLIGHTER
+        .load_configuration(
+            language,
+            "(word) @string\n",
+            "((group (group) @injection.content) @injection.site (set! injection.language \"inner\"))\n",
+            "",
+        )
+        .unwrap();
+
+    let source = b"a <b <c>> d";
+    let mut renderer = HtmlRenderer::new();
+    let mut context = HighlightContext::new();
+    let events = HIGHLIGHTER
+        .highlight(&mut context, &outer_config, source, None, |name| {
+            if name == "inner" {
+                Some(&inner_config)
+            } else {
+                None
+            }
+        })
+        .unwrap();
+    renderer
+        .render(events, source, &|highlight| {
+            HTML_ATTRS[highlight.0].as_bytes()
+        })
+        .unwrap();
+    assert_eq!(
+        renderer.lines().collect::<Vec<_>>(),
+        &["<span class=variable>a</span> &lt;<span class=string>b</span> &lt;<span class=string>c</span>&gt;&gt; <span class=variable>d</span>\n"]
+    );
+}
+
+#[test]
+fn test_highlighting_concurrently_with_language_registry() {
+    let language = get_highlight_dir_test_language();
+    let mut registry = LanguageRegistry::new();
+    registry.add_language(
+        "test",
+        HIGHLIGHTER
+            .load_configuration(
+                language,
+                "(identifier) @variable\n(number) @constant\n",
+                "((program (string) @injection.content) @injection.site (set! injection.language \"q\"))\n",
+                "",
+            )
+            .unwrap(),
+    );
+    registry.add_language(
+        "quoted",
+        HIGHLIGHTER
+            .load_configuration(language, "(string) @string\n", "", "")
+            .unwrap(),
+    );
+    registry.add_alias("q", "quoted");
+    assert!(registry.get("q").is_some());
+    assert!(registry.get("missing").is_none());
+
+    let registry = Arc::new(registry);
+    let threads = (0..8)
+        .map(|i| {
+            let registry = registry.clone();
+            thread::spawn(move || {
+                let (language_name, source) = if i % 2 == 0 {
+                    ("test", "a 1 \"b\"")
+                } else {
+                    ("quoted", "\"c\"")
+                };
+                let config = registry.get(language_name).unwrap();
+                let mut context = HighlightContext::new();
+                let mut renderer = HtmlRenderer::new();
+                for _ in 0..20 {
+                    let events = HIGHLIGHTER
+                        .highlight(
+                            &mut context,
+                            &config,
+                            source.as_bytes(),
+                            None,
+                            registry.injection_callback(),
+                        )
+                        .unwrap();
+                    renderer.reset();
+                    renderer
+                        .render(events, source.as_bytes(), &|highlight| {
+                            HTML_ATTRS[highlight.0].as_bytes()
+                        })
+                        .unwrap();
+                }
+                renderer.lines().collect::<String>()
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for (i, thread) in threads.into_iter().enumerate() {
+        assert_eq!(
+            thread.join().unwrap(),
+            if i % 2 == 0 {
+                "<span class=variable>a</span> <span class=constant>1</span> <span class=string>&quot;b&quot;</span>\n"
+            } else {
+                "<span class=string>&quot;c&quot;</span>\n"
+            }
+        );
+    }
+}
+
+#[test]
+fn test_collecting_injection_stats() {
+    let source = concat!(
+        "<script>const s = html `<b>hi</b>`;</script>\n",
+        "<p>hi</p>\n",
+        "<script>f(x);</script>\n",
+    );
+
+    let mut context = HighlightContext::new();
+    let events = HIGHLIGHTER
+        .highlight_with_options(
+            &mut context,
+            &HTML_HIGHLIGHT,
+            source.as_bytes(),
+            HighlightOptions::new().injection_stats(true),
+            &test_language_for_injection_string,
+        )
+        .unwrap();
+    for event in events {
+        event.unwrap();
+    }
+
+    let injection_stats = context.injection_stats().unwrap();
+    let mut languages = injection_stats.keys().collect::<Vec<_>>();
+    languages.sort();
+    assert_eq!(languages, &["html", "javascript"]);
+
+    // The HTML in the template string is injected into the first script.
+    let html_stats = &injection_stats["html"];
+    assert_eq!(html_stats.layer_count, 1);
+    assert_eq!(html_stats.bytes, "`<b>hi</b>`".len());
+
+    let js_stats = &injection_stats["javascript"];
+    assert_eq!(js_stats.layer_count, 2);
+    assert_eq!(
+        js_stats.bytes,
+        "const s = html `<b>hi</b>`;".len() + "f(x);".len()
+    );
+
+    // Without the option, no statistics are collected.
+    let events = HIGHLIGHTER
+        .highlight(
+            &mut context,
+            &HTML_HIGHLIGHT,
+            source.as_bytes(),
+            None,
+            &test_language_for_injection_string,
+        )
+        .unwrap();
+    for event in events {
+        event.unwrap();
+    }
+    assert!(context.injection_stats().is_none());
+}
+
+#[test]
+fn test_highlighting_a_batch_of_documents() {
+    let language = get_highlight_dir_test_language();
+    let configs = vec![
+        HIGHLIGHTER
+            .load_configuration(
+                language,
+                "(identifier) @variable\n(number) @constant\n",
+                "",
+                "",
+            )
+            .unwrap(),
+        HIGHLIGHTER
+            .load_configuration(language, "(string) @string\n(number) @keyword\n", "", "")
+            .unwrap(),
+    ];
+    let mut paths = fs::read_dir(fixtures_dir().join("highlight_batch"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>();
+    paths.sort();
+    let sources = paths
+        .iter()
+        .map(|path| fs::read(path).unwrap())
+        .collect::<Vec<_>>();
+    let jobs = sources
+        .iter()
+        .enumerate()
+        .map(|(i, source)| (&configs[i % configs.len()], source.as_slice()));
+
+    // The events for each document are the same as when it is highlighted on its own.
+    let mut context = HighlightContext::new();
+    let expected_events = jobs
+        .clone()
+        .map(|(config, source)| {
+            let events = HIGHLIGHTER
+                .highlight(&mut context, config, source, None, |_| None)
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            format!("{:?}", events)
+        })
+        .collect::<Vec<_>>();
+    let mut batch =
+        HIGHLIGHTER.highlight_batch(&mut context, jobs.clone(), HighlightOptions::new(), |_| {
+            None
+        });
+    let mut indices = Vec::new();
+    while let Some((index, events)) = batch.next() {
+        let events = events.unwrap();
+        assert_eq!(
+            format!("{:?}", events),
+            expected_events[index],
+            "document {:?}",
+            paths[index]
+        );
+        indices.push(index);
+        batch.recycle(events);
+    }
+    assert_eq!(indices, (0..sources.len()).collect::<Vec<_>>());
+
+    // The cancellation flag is checked between documents, and ends the batch.
+    let cancellation_flag = AtomicUsize::new(0);
+    let mut batch = HIGHLIGHTER.highlight_batch(
+        &mut context,
+        jobs,
+        HighlightOptions::new().cancellation_flag(&cancellation_flag),
+        |_| None,
+    );
+    assert!(batch.next().unwrap().1.is_ok());
+    cancellation_flag.store(1, Ordering::SeqCst);
+    let (index, result) = batch.next().unwrap();
+    assert_eq!((index, result.err()), (1, Some(Error::Cancelled)));
+    assert!(batch.next().is_none());
+}
+
+#[test]
+fn test_collecting_highlight_coverage_stats() {
+    let language = get_highlight_dir_test_language();
+    let config = HIGHLIGHTER
+        .load_configuration(
+            language,
+            "(identifier) @variable\n(number) @constant\n(string) @label\n",
+            "",
+            "",
+        )
+        .unwrap();
+    let source = fs::read(fixtures_dir().join("highlight_stats").join("example.txt")).unwrap();
+
+    let counter = AtomicUsize::new(0);
+    let mut context = HighlightContext::new();
+    let events = HIGHLIGHTER
+        .highlight_with_options(
+            &mut context,
+            &config,
+            &source,
+            HighlightOptions::new().unrecognized_capture_counter(&counter),
+            |_| None,
+        )
+        .unwrap();
+    let mut stats = stats::collect(events, &source, &HIGHLIGHTER).unwrap();
+    stats.unrecognized_captures = Some(counter.load(Ordering::SeqCst));
+
+    assert_eq!(
+        stats,
+        CoverageStats {
+            total_bytes: 36,
+            highlighted_bytes: 17,
+            coverage: 17.0 / 36.0,
+            highlights: vec![
+                (
+                    "constant".to_string(),
+                    HighlightStats { count: 3, bytes: 6 }
+                ),
+                (
+                    "variable".to_string(),
+                    HighlightStats {
+                        count: 4,
+                        bytes: 11
+                    }
+                ),
+            ]
+            .into_iter()
+            .collect(),
+            unrecognized_captures: Some(3),
+        }
+    );
+    assert_eq!(
+        stats.to_string(),
+        "Highlighted 17 of 36 non-whitespace bytes (47.2%)\n\
+         Unrecognized captures: 3\n  \
+         constant: 3 highlights, 6 bytes\n  \
+         variable: 4 highlights, 11 bytes\n"
+    );
+    assert_eq!(
+        serde_json::to_string(&stats).unwrap(),
+        r#"{"total_bytes":36,"highlighted_bytes":17,"coverage":0.4722222222222222,"highlights":{"constant":{"count":3,"bytes":6},"variable":{"count":4,"bytes":11}},"unrecognized_captures":3}"#
+    );
+
+    // Without a counter, unrecognized captures are not counted.
+    let events = HIGHLIGHTER
+        .highlight(&mut context, &config, &source, None, |_| None)
+        .unwrap();
+    let stats = stats::collect(events, &source, &HIGHLIGHTER).unwrap();
+    assert_eq!(stats.unrecognized_captures, None);
+    assert_eq!(stats.highlighted_bytes, 17);
+}
+
+#[test]
+fn test_resolving_configurations_by_alias() {
+    let language = get_highlight_dir_test_language();
+    let mut registry = LanguageRegistry::new();
+    registry.add_language(
+        "c",
+        HIGHLIGHTER
+            .load_configuration(language, "(identifier) @variable\n", "", "")
+            .unwrap()
+            .with_names(vec!["c".to_string(), "h".to_string()]),
+    );
+    registry.add_language(
+        "kernel-c",
+        HIGHLIGHTER
+            .load_configuration(language, "(identifier) @constant\n", "", "")
+            .unwrap()
+            .with_names(vec!["Kernel-C".to_string(), "linux-c".to_string()]),
+    );
+    registry.set_fallback(|name| {
+        if name.ends_with("-c") {
+            Some("c".to_string())
+        } else {
+            None
+        }
+    });
+
+    let highlight_name = |name| {
+        let config = registry.get(name)?;
+        Some(to_token_vector("a", &config).unwrap()[0][0].1[0].to_string())
+    };
+    assert_eq!(highlight_name("kernel-c").as_deref(), Some("constant"));
+    assert_eq!(highlight_name("KERNEL-C").as_deref(), Some("constant"));
+    assert_eq!(highlight_name("Linux-C").as_deref(), Some("constant"));
+    assert_eq!(highlight_name("c").as_deref(), Some("variable"));
+    assert_eq!(highlight_name("C").as_deref(), Some("variable"));
+    assert_eq!(highlight_name("H").as_deref(), Some("variable"));
+
+    // Names that aren't known are resolved by the fallback handler.
+    assert_eq!(highlight_name("embedded-c").as_deref(), Some("variable"));
+    assert_eq!(highlight_name("rust"), None);
+
+    // The C API matches injected language names against the aliases of each language.
+    let highlight_names = [c_string("variable"), c_string("constant")];
+    let highlight_names = highlight_names
+        .iter()
+        .map(|name| name.as_ptr())
+        .collect::<Vec<_>>();
+    let highlighter = c::ts_highlighter_new(highlight_names.as_ptr(), highlight_names.as_ptr(), 2);
+    for (scope, highlights_query, injections_query, aliases) in [
+        (
+            "source.markdown",
+            "",
+            "((program (identifier) @injection.content) @injection.site (set! injection.language \"kernel-c\"))\n",
+            vec![],
+        ),
+        (
+            "source.c",
+            "(identifier) @variable\n",
+            "",
+            vec![c_string("c"), c_string("h")],
+        ),
+        (
+            "source.kernel-c",
+            "(identifier) @constant\n",
+            "",
+            vec![c_string("Kernel-C")],
+        ),
+    ]
+    .iter()
+    {
+        let scope = c_string(scope);
+        let injection_regex = c_string("c");
+        let aliases = aliases.iter().map(|alias| alias.as_ptr()).collect::<Vec<_>>();
+        c::ts_highlighter_add_language_with_aliases(
+            highlighter,
+            scope.as_ptr(),
+            injection_regex.as_ptr(),
+            language,
+            highlights_query.as_ptr() as *const i8,
+            injections_query.as_ptr() as *const i8,
+            ptr::null(),
+            highlights_query.len() as u32,
+            injections_query.len() as u32,
+            0,
+            aliases.as_ptr(),
+            aliases.len() as u32,
+        );
+    }
+
+    let buffer = c::ts_highlight_buffer_new();
+    let scope = c_string("source.markdown");
+    let source = c_string("a");
+    c::ts_highlighter_highlight(
+        highlighter,
+        scope.as_ptr(),
+        source.as_ptr(),
+        source.as_bytes().len() as u32,
+        buffer,
+        ptr::null_mut(),
+    );
+    let output = unsafe {
+        slice::from_raw_parts(
+            c::ts_highlight_buffer_content(buffer),
+            c::ts_highlight_buffer_len(buffer) as usize,
+        )
+    };
+    assert_eq!(str::from_utf8(output).unwrap(), "<span constant>a</span>\n");
+
+    // Both the document and its injection were parsed.
+    assert_eq!(c::ts_highlight_buffer_layer_count(buffer), 2);
+    assert_eq!(c::ts_highlight_buffer_parse_count(buffer), 2);
+    assert_eq!(c::ts_highlight_buffer_parsed_byte_count(buffer), 2);
+    assert!(!c::ts_highlight_buffer_root_has_error(buffer));
+    assert_eq!(c::ts_highlight_buffer_error_count(buffer), 0);
+
+    // Syntax errors in the document are reported.
+    let source = c_string("a#");
+    c::ts_highlighter_highlight(
+        highlighter,
+        scope.as_ptr(),
+        source.as_ptr(),
+        source.as_bytes().len() as u32,
+        buffer,
+        ptr::null_mut(),
+    );
+    assert!(c::ts_highlight_buffer_root_has_error(buffer));
+    assert_eq!(c::ts_highlight_buffer_error_count(buffer), 1);
+
+    c::ts_highlighter_delete(highlighter);
+    c::ts_highlight_buffer_delete(buffer);
+}
+
+#[test]
+fn test_validating_queries_against_node_types() {
+    let grammar_json = fs::read_to_string(
+        fixtures_dir()
+            .join("test_grammars")
+            .join("inlined_rules_with_fields")
+            .join("grammar.json"),
+    )
+    .unwrap();
+    let language = compile_grammar(&grammar_json).unwrap();
+    let node_types_json = generate_node_types_for_grammar(&grammar_json).unwrap();
+
+    let highlights_query = r#"
+(identifier) @variable
+(number) @constant
+(program name: (identifier)) @variable
+(statement name: (number)) @constant
+((identifier) @constant (eq? @constant "a"))
+"let" @keyword
+"#;
+    let locals_query = "(program literal: (number) @local.definition)\n";
+    let config = HIGHLIGHTER
+        .load_configuration(language, highlights_query, "", locals_query)
+        .unwrap();
+
+    assert_eq!(
+        config
+            .validate_against_node_types(&node_types_json)
+            .unwrap(),
+        &[
+            PatternIssue {
+                pattern_index: 0,
+                section: QuerySection::Locals,
+                offset: locals_query.find("literal").unwrap(),
+                kind: PatternIssueKind::FieldNotOnNode {
+                    node_kind: "program".to_string(),
+                    field: "literal".to_string(),
+                },
+            },
+            PatternIssue {
+                pattern_index: 3,
+                section: QuerySection::Highlights,
+                offset: highlights_query.find("name: (identifier)").unwrap(),
+                kind: PatternIssueKind::FieldNotOnNode {
+                    node_kind: "program".to_string(),
+                    field: "name".to_string(),
+                },
+            },
+            PatternIssue {
+                pattern_index: 4,
+                section: QuerySection::Highlights,
+                offset: highlights_query.find("number)) @constant").unwrap(),
+                kind: PatternIssueKind::InvalidFieldValue {
+                    field: "name".to_string(),
+                    kind: "number".to_string(),
+                },
+            },
+        ]
+    );
+
+    // Node types that are missing from node-types.json are reported, which happens when
+    // it was generated from a different version of the grammar.
+    let mut stale_node_types = serde_json::from_str::<serde_json::Value>(&node_types_json).unwrap();
+    stale_node_types
+        .as_array_mut()
+        .unwrap()
+        .retain(|node_type| node_type["type"] != "let");
+    assert_eq!(
+        config
+            .validate_against_node_types(&stale_node_types.to_string())
+            .unwrap()
+            .last()
+            .unwrap(),
+        &PatternIssue {
+            pattern_index: 6,
+            section: QuerySection::Highlights,
+            offset: highlights_query.find("let").unwrap(),
+            kind: PatternIssueKind::UnknownNodeType {
+                kind: "let".to_string(),
+                named: false,
+            },
+        },
+    );
+
+    // Patterns that only reference valid node types and fields have no issues.
+    assert_eq!(
+        HIGHLIGHTER
+            .load_configuration(language, "(statement name: (identifier) @variable)", "", "")
+            .unwrap()
+            .validate_against_node_types(&node_types_json)
+            .unwrap(),
+        &[]
+    );
+}
+
+#[test]
+fn test_layer_range_map() {
+    let range = |start_byte, end_byte| Range {
+        start_byte,
+        end_byte,
+        start_point: Point::new(0, start_byte),
+        end_point: Point::new(0, end_byte),
+    };
+    let map = LayerRangeMap::new(vec![range(2, 5), range(8, 10), range(14, 20)]);
+
+    // Offsets at the start of a range are included, and offsets at the end of a range
+    // or in a gap are not.
+    let to_local = [0, 1, 2, 4, 5, 7, 8, 9, 10, 13, 14, 19, 20, 100]
+        .iter()
+        .map(|offset| map.to_local(*offset))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        to_local,
+        &[
+            None,
+            None,
+            Some(0),
+            Some(2),
+            None,
+            None,
+            Some(3),
+            Some(4),
+            None,
+            None,
+            Some(5),
+            Some(10),
+            None,
+            None
+        ]
+    );
+
+    // Offsets at the boundary between two ranges map to the start of the later range,
+    // and offsets past the end of the content map past the end of the last range.
+    let to_document = [0, 2, 3, 4, 5, 10, 11, 12]
+        .iter()
+        .map(|offset| map.to_document(*offset))
+        .collect::<Vec<_>>();
+    assert_eq!(to_document, &[2, 4, 8, 9, 14, 19, 20, 21]);
+
+    for offset in 0..11 {
+        assert_eq!(map.to_local(map.to_document(offset)), Some(offset));
+    }
+
+    assert_eq!(map.gaps().collect::<Vec<_>>(), &[5..8, 10..14]);
+
+    // Adjacent ranges don't have a gap between them.
+    let map = LayerRangeMap::new(vec![range(0, 3), range(3, 5)]);
+    assert_eq!(map.gaps().count(), 0);
+    assert_eq!(map.to_local(3), Some(3));
+    assert_eq!(map.to_document(3), 3);
+}
+
+#[test]
+fn test_intersecting_injection_ranges() {
+    let range = |start_byte, end_byte| Range {
+        start_byte,
+        end_byte,
+        start_point: Point::new(0, start_byte),
+        end_point: Point::new(0, end_byte),
+    };
+    let language = compile_grammar(
+        r#"{
+            "name": "injection_ranges_test",
+            "extras": [{"type": "PATTERN", "value": "\\s"}],
+            "rules": {
+                "program": {
+                    "type": "REPEAT",
+                    "content": {
+                        "type": "CHOICE",
+                        "members": [
+                            {"type": "SYMBOL", "name": "identifier"},
+                            {"type": "SYMBOL", "name": "number"},
+                            {"type": "SYMBOL", "name": "string"}
+                        ]
+                    }
+                },
+                "identifier": {"type": "PATTERN", "value": "[a-z]+"},
+                "number": {"type": "PATTERN", "value": "\\d+"},
+                "string": {"type": "PATTERN", "value": "\"[^\"]*\""}
+            }
+        }"#,
+    )
+    .unwrap();
+    let mut parser = Parser::new();
+    parser.set_language(language).unwrap();
+    let source = "abc 123 \"hi\" de 45";
+    let tree = parser.parse(source, None).unwrap();
+    let program = tree.root_node();
+    let tokens = program.children().collect::<Vec<_>>();
+    assert_eq!(
+        tokens.iter().map(|n| n.byte_range()).collect::<Vec<_>>(),
+        &[0..3, 4..7, 8..12, 13..15, 16..18]
+    );
+
+    // A parent with several ranges, and content nodes that are not in document order.
+    let parent_ranges = vec![range(2, 5), range(6, 10), range(14, 18)];
+    let nodes = vec![tokens[3], tokens[0], tokens[2], tokens[1]];
+    assert_eq!(
+        intersect_ranges(&parent_ranges, &nodes, true),
+        &[
+            range(2, 3),
+            range(4, 5),
+            range(6, 7),
+            range(8, 10),
+            range(14, 15)
+        ]
+    );
+
+    // Without the children of the content node, only the gaps between them remain.
+    assert_eq!(
+        intersect_ranges(&parent_ranges, &[program], false),
+        &[range(3, 4), range(7, 8), range(15, 16)]
+    );
+
+    // Nested content nodes produce overlapping ranges, which are merged.
+    assert_eq!(
+        intersect_ranges(&parent_ranges, &[tokens[1], program], true),
+        parent_ranges
+    );
+
+    // Ranges that touch are merged, but ranges separated by a gap are not.
+    assert_eq!(
+        intersect_ranges(&[range(0, 4), range(4, 8)], &[program], true),
+        &[range(0, 8)]
+    );
+    assert_eq!(
+        intersect_ranges(&[range(0, 4), range(4, 8)], &[tokens[1], tokens[0]], true),
+        &[range(0, 3), range(4, 7)]
+    );
+
+    // The result is sorted and disjoint for every order of the nodes.
+    for i in 0..tokens.len() {
+        let mut nodes = tokens.clone();
+        nodes.rotate_left(i);
+        let ranges = intersect_ranges(&parent_ranges, &nodes, true);
+        assert!(ranges
+            .windows(2)
+            .all(|pair| pair[0].end_byte < pair[1].start_byte));
+        assert_eq!(
+            ranges,
+            &[
+                range(2, 3),
+                range(4, 5),
+                range(6, 7),
+                range(8, 10),
+                range(14, 15),
+                range(16, 18)
+            ]
+        );
+    }
+}
+
+#[test]
+fn test_decode_utf8_lossy() {
+    use tree_sitter_highlight::util::LossyUtf8;
+
+    let parts = LossyUtf8::new(b"hi").collect::<Vec<_>>();
+    assert_eq!(parts, vec!["hi"]);
+
+    let parts = LossyUtf8::new(b"hi\xc0\xc1bye").collect::<Vec<_>>();
+    assert_eq!(parts, vec!["hi", "\u{fffd}", "\u{fffd}", "bye"]);
+
+    let parts = LossyUtf8::new(b"\xc0\xc1bye").collect::<Vec<_>>();
+    assert_eq!(parts, vec!["\u{fffd}", "\u{fffd}", "bye"]);
+
+    let parts = LossyUtf8::new(b"hello\xc0\xc1").collect::<Vec<_>>();
+    assert_eq!(parts, vec!["hello", "\u{fffd}", "\u{fffd}"]);
+}
+
+#[test]
+fn test_decode_utf8_lossy_chunks() {
+    use std::borrow::Cow;
+    use tree_sitter_highlight::util::{InvalidUtf8, LossyUtf8, LossyUtf8Chunk};
+
+    let chunks = LossyUtf8::new(b"hi\xc0\xe2\x82bye\xf0\x9f")
+        .chunks()
+        .collect::<Vec<_>>();
+    assert_eq!(
+        chunks,
+        vec![
+            LossyUtf8Chunk {
+                range: 0..2,
+                text: Some("hi")
+            },
+            LossyUtf8Chunk {
+                range: 2..3,
+                text: None
+            },
+            LossyUtf8Chunk {
+                range: 3..5,
+                text: None
+            },
+            LossyUtf8Chunk {
+                range: 5..8,
+                text: Some("bye")
+            },
+            LossyUtf8Chunk {
+                range: 8..10,
+                text: None
+            },
+        ]
+    );
+
+    let parts = LossyUtf8::new(b"hi\xc0\xe2\x82bye")
+        .invalid_utf8(InvalidUtf8::Skip)
+        .collect::<Vec<_>>();
+    assert_eq!(parts, vec!["hi", "bye"]);
+
+    assert!(matches!(
+        LossyUtf8::new(b"hi").to_cow(),
+        Cow::Borrowed("hi")
+    ));
+    assert_eq!(LossyUtf8::new(b"hi\xc0").to_cow(), "hi\u{fffd}");
+}
+
+#[test]
+fn test_decode_utf8_lossy_chunks_of_random_bytes() {
+    use rand::{Rng, SeedableRng};
+    use tree_sitter_highlight::util::{InvalidUtf8, LossyUtf8};
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    for _ in 0..2000 {
+        // Mostly ASCII and the bytes of multi-byte characters, so that valid characters
+        // and invalid sequences are both common.
+        let len = rng.gen_range(0, 32);
+        let bytes = (0..len)
+            .map(|_| match rng.gen_range(0, 4) {
+                0 => rng.gen_range(b'a', b'z'),
+                1 => rng.gen_range(0x80, 0xc0),
+                2 => rng.gen_range(0xc0, 0xf8),
+                _ => rng.gen(),
+            })
+            .collect::<Vec<u8>>();
+
+        // The chunks partition the input, and each one is either valid UTF-8 with the
+        // same text as the input, or a non-empty invalid sequence.
+        let mut offset = 0;
+        for chunk in LossyUtf8::new(&bytes).chunks() {
+            assert_eq!(chunk.range.start, offset, "{:?}", bytes);
+            assert!(chunk.range.end > chunk.range.start, "{:?}", bytes);
+            match chunk.text {
+                Some(text) => assert_eq!(text.as_bytes(), &bytes[chunk.range.clone()]),
+                None => assert!(std::str::from_utf8(&bytes[chunk.range.clone()]).is_err()),
+            }
+            offset = chunk.range.end;
+        }
+        assert_eq!(offset, bytes.len(), "{:?}", bytes);
+
+        assert_eq!(
+            LossyUtf8::new(&bytes).collect::<String>(),
+            String::from_utf8_lossy(&bytes),
+            "{:?}",
+            bytes
+        );
+        assert_eq!(
+            LossyUtf8::new(&bytes)
+                .invalid_utf8(InvalidUtf8::Skip)
+                .collect::<String>(),
+            String::from_utf8_lossy(&bytes).replace('\u{fffd}', ""),
+            "{:?}",
+            bytes
+        );
+    }
+}
+
+#[test]
+fn test_highlighting_local_variables_with_invalid_utf8_names() {
+    let language = compile_grammar(
+        r#"{
+            "name": "lossy_local_names_test",
+            "rules": {
+                "program": {
+                    "type": "REPEAT",
+                    "content": {
+                        "type": "CHOICE",
+                        "members": [
+                            {"type": "SYMBOL", "name": "definition"},
+                            {"type": "SYMBOL", "name": "name"}
+                        ]
+                    }
+                },
+                "definition": {
+                    "type": "SEQ",
+                    "members": [
+                        {"type": "STRING", "value": "let"},
+                        {"type": "SYMBOL", "name": "name"}
+                    ]
+                },
+                "name": {"type": "PATTERN", "value": "'[^']*'"}
+            }
+        }"#,
+    )
+    .unwrap();
+    let config = HIGHLIGHTER
+        .load_configuration(
+            language,
+            r#"
+            ((name) @function (is-not? local))
+            (name) @string
+            "#,
+            "",
+            r#"
+            (program) @local.scope
+            (definition (name) @local.definition)
+            (name) @local.reference
+            "#,
+        )
+        .unwrap();
+
+    let source = b"let 'a\xff' 'a\xff'";
+    let highlights = |options: HighlightOptions| {
+        let mut context = HighlightContext::new();
+        let events = HIGHLIGHTER
+            .highlight_with_options(&mut context, &config, source, options, |_| None)
+            .unwrap();
+        spans_from_events(events)
+            .unwrap()
+            .into_iter()
+            .map(|(range, highlight)| (range, HIGHLIGHTER.names()[highlight.0].as_str()))
+            .collect::<Vec<_>>()
+    };
+
+    // By default, names that aren't valid UTF-8 are ignored, so neither string is a local
+    // variable.
+    assert_eq!(
+        highlights(HighlightOptions::new()),
+        vec![(4..8, "function"), (9..13, "function")]
+    );
+
+    // With lossy names, the reference resolves to the definition.
+    assert_eq!(
+        highlights(HighlightOptions::new().lossy_local_names(true)),
+        vec![(4..8, "string"), (9..13, "string")]
+    );
+}
+
+#[test]
+fn test_highlighting_injections_with_language_from_another_capture() {
+    let html_like_language = get_html_like_injection_test_language();
+    let injected_config = HIGHLIGHTER
+        .load_configuration(
+            get_highlight_dir_test_language(),
+            "(identifier) @variable\n(number) @constant\n",
+            "",
+            "",
+        )
+        .unwrap();
+
+    let injections_query = concat!(
+        "((script_element\n",
+        "   (attribute (attribute_value) @lang)\n",
+        "   (raw_text) @injection.content) @injection.site\n",
+        " (set! injection.language \"@lang\"))\n",
+        "((style_element (raw_text) @injection.content) @injection.site\n",
+        " (set! injection.language \"@missing\")\n",
+        " (set! injection.language \"text/x-default\"))\n",
+    );
+    let config = HIGHLIGHTER
+        .load_configuration(
+            html_like_language,
+            "(attribute_value) @string\n",
+            injections_query,
+            "",
+        )
+        .unwrap();
+
+    let requested_languages = std::cell::RefCell::new(Vec::new());
+    let source = concat!(
+        "<script type=\"text/x-foo\">a 1</script>",
+        "<style>b</style>",
+        "<script type=\"text/x-bar\">c</script>",
+    );
+    let mut context = HighlightContext::new();
+    let events = HIGHLIGHTER
+        .highlight(&mut context, &config, source.as_bytes(), None, |name| {
+            requested_languages.borrow_mut().push(name.to_string());
+            if name == "text/x-foo" || name == "text/x-default" {
+                Some(&injected_config)
+            } else {
+                None
+            }
+        })
+        .unwrap();
+
+    let mut highlighted = Vec::new();
+    let mut stack = Vec::new();
+    for event in events {
+        match event.unwrap() {
+            HighlightEvent::HighlightStart(highlight) => stack.push(highlight.0),
+            HighlightEvent::HighlightEnd => {
+                stack.pop();
+            }
+            HighlightEvent::Source { start, end } => {
+                if let Some(highlight) = stack.last() {
+                    highlighted.push((
+                        &source[start..end],
+                        HIGHLIGHTER.names()[*highlight].as_str(),
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    assert_eq!(
+        requested_languages.into_inner(),
+        &["text/x-foo", "text/x-default", "text/x-bar"]
+    );
+    assert_eq!(
+        highlighted,
+        &[
+            ("text/x-foo", "string"),
+            ("a", "variable"),
+            ("1", "constant"),
+            ("b", "variable"),
+            ("text/x-bar", "string"),
+        ]
+    );
+}
+
+#[test]
+fn test_highlighting_injections_with_language_from_shebang() {
+    let injected_config = HIGHLIGHTER
+        .load_configuration(
+            get_highlight_dir_test_language(),
+            "(identifier) @variable\n",
+            "",
+            "",
+        )
+        .unwrap();
+    let injections_query = concat!(
+        "((script_element (raw_text) @injection.content) @injection.site\n",
+        " (set! injection.language-from-shebang)\n",
+        " (set! injection.language \"text/x-default\"))\n",
+    );
+    let config = HIGHLIGHTER
+        .load_configuration(
+            get_html_like_injection_test_language(),
+            "",
+            injections_query,
+            "",
+        )
+        .unwrap();
+
+    // The interpreter's name is used without its directory and version, and a script
+    // without a shebang falls back to the literal language name. The callback also
+    // receives the text of each injection site.
+    let source = concat!(
+        "<script>#!/usr/bin/env python3\na</script>",
+        "<script> #!/bin/bash -e\nb</script>",
+        "<script type=\"text/x-ruby\">c</script>",
+    );
+    let requested_injections = std::cell::RefCell::new(Vec::new());
+    let mut context = HighlightContext::new();
+    let events = HIGHLIGHTER
+        .highlight_with_injection_info(
+            &mut context,
+            &config,
+            source.as_bytes(),
+            HighlightOptions::new(),
+            |injection| {
+                requested_injections.borrow_mut().push((
+                    injection.language.to_string(),
+                    injection.site.map(str::to_string),
+                ));
+                if injection.language == "python" {
+                    Some(&injected_config)
+                } else {
+                    None
+                }
+            },
+        )
+        .unwrap();
+    let highlighted = spans_from_events(events)
+        .unwrap()
+        .into_iter()
+        .map(|(range, _)| &source[range])
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        requested_injections.into_inner(),
+        &[
+            (
+                "python".to_string(),
+                Some("<script>#!/usr/bin/env python3\na</script>".to_string())
+            ),
+            (
+                "bash".to_string(),
+                Some("<script> #!/bin/bash -e\nb</script>".to_string())
+            ),
+            (
+                "text/x-default".to_string(),
+                Some("<script type=\"text/x-ruby\">c</script>".to_string())
+            ),
+        ]
+    );
+    assert!(highlighted.contains(&"a"));
+    assert!(!highlighted.contains(&"b") && !highlighted.contains(&"c"));
+
+    // Callbacks that only receive the language name are given the same names.
+    let requested_languages = std::cell::RefCell::new(Vec::new());
+    let events = HIGHLIGHTER
+        .highlight(&mut context, &config, source.as_bytes(), None, |name| {
+            requested_languages.borrow_mut().push(name.to_string());
+            None
+        })
+        .unwrap();
+    assert!(events.collect::<Result<Vec<_>, _>>().is_ok());
+    assert_eq!(
+        requested_languages.into_inner(),
+        &["python", "bash", "text/x-default"]
+    );
+}
+
+#[test]
+fn test_highlighting_injections_of_the_current_and_parent_languages() {
+    let language = get_self_injection_test_language();
+    let highlights_query = "(identifier) @variable\n(number) @constant\n";
+    let config = HIGHLIGHTER
+        .load_configuration(
+            language,
+            highlights_query,
+            concat!(
+                "((string (string_content) @injection.content) @injection.site\n",
+                " (set! injection.self))\n",
+            ),
+            "",
+        )
+        .unwrap();
+
+    // Each string's content is highlighted as the same language, including the content of
+    // the string nested inside of it.
+    let source = "a \"b 'c 2' 1\"";
+    let mut context = HighlightContext::new();
+    assert_eq!(
+        to_spans(&mut context, &config, source, HighlightOptions::new()),
+        &[
+            ("a", "variable"),
+            ("b", "variable"),
+            ("c", "variable"),
+            ("2", "constant"),
+            ("1", "constant"),
+        ]
+    );
+    assert_eq!(context.parse_stats().layer_count, 3);
+
+    // A language that injects itself into all of its strings would be nested without end,
+    // so the depth of the injections is limited.
+    let recursive_config = HIGHLIGHTER
+        .load_configuration(
+            language,
+            highlights_query,
+            concat!(
+                "((program (string) @injection.content) @injection.site\n",
+                " (set! injection.self)\n",
+                " (set! injection.include-children))\n",
+            ),
+            "",
+        )
+        .unwrap();
+    to_spans(
+        &mut context,
+        &recursive_config,
+        "\"a\"",
+        HighlightOptions::new(),
+    );
+    assert_eq!(context.parse_stats().layer_count, 33);
+    let options = HighlightOptions::new().max_injection_depth(2);
+    to_spans(&mut context, &recursive_config, "\"a\"", options);
+    assert_eq!(context.parse_stats().layer_count, 3);
+
+    // A language can also inject the language that it is injected into. The root layer
+    // has no parent, so it doesn't inject anything.
+    let html_like_config = HIGHLIGHTER
+        .load_configuration(
+            get_html_like_injection_test_language(),
+            "",
+            concat!(
+                "((script_element (raw_text) @injection.content) @injection.site\n",
+                " (set! injection.parent))\n",
+            ),
+            "",
+        )
+        .unwrap();
+    let config = HIGHLIGHTER
+        .load_configuration(
+            language,
+            highlights_query,
+            concat!(
+                "((string (string_content) @injection.content) @injection.site\n",
+                " (set! injection.language \"html\"))\n",
+            ),
+            "",
+        )
+        .unwrap();
+    let source = "a \"<script>b 1</script>\"";
+    let events = HIGHLIGHTER
+        .highlight(&mut context, &config, source.as_bytes(), None, |name| {
+            if name == "html" {
+                Some(&html_like_config)
+            } else {
+                None
+            }
+        })
+        .unwrap();
+    let spans = spans_from_events(events).unwrap();
+    assert_eq!(
+        spans
+            .into_iter()
+            .map(|(range, highlight)| (&source[range], HIGHLIGHTER.names()[highlight.0].as_str()))
+            .collect::<Vec<_>>(),
+        &[("a", "variable"), ("b", "variable"), ("1", "constant")]
+    );
+    assert_eq!(context.parse_stats().layer_count, 3);
+    to_spans(
+        &mut context,
+        &html_like_config,
+        "<script>b 1</script>",
+        HighlightOptions::new(),
+    );
+    assert_eq!(context.parse_stats().layer_count, 1);
+}
+
+#[test]
+fn test_highlighting_with_layer_events() {
+    let injected_config = HIGHLIGHTER
+        .load_configuration(
+            get_highlight_dir_test_language(),
+            "(identifier) @variable\n(number) @constant\n",
+            "",
+            "",
+        )
+        .unwrap();
+    let config = HIGHLIGHTER
+        .load_configuration(
+            get_html_like_injection_test_language(),
+            "(script_element) @tag\n",
+            concat!(
+                "((script_element (raw_text) @injection.content) @injection.site\n",
+                " (set! injection.language \"dir\"))\n",
+            ),
+            "",
+        )
+        .unwrap();
+    let source = "<script>a1</script> <script>b</script>";
+    let mut context = HighlightContext::new();
+
+    // The document's layer is given the caller's name, and each injected layer the name
+    // of its language. The layers are nested within the highlights that contain them.
+    let options = HighlightOptions::new().layer_events("html");
+    assert_eq!(
+        to_event_strings_with_injections(
+            &mut context,
+            &config,
+            source.as_bytes(),
+            options,
+            &injected_config
+        ),
+        &[
+            "[html:0]",
+            "<tag>",
+            "0..8",
+            "[dir:1]",
+            "<variable>",
+            "8..9",
+            "</>",
+            "<constant>",
+            "9..10",
+            "</>",
+            "[/]",
+            "10..19",
+            "</>",
+            "19..20",
+            "<tag>",
+            "20..28",
+            "[dir:1]",
+            "<variable>",
+            "28..29",
+            "</>",
+            "[/]",
+            "29..38",
+            "</>",
+            "[/]",
+        ]
+    );
+
+    // Layers that begin before a restricted byte range are started at its beginning, and
+    // layers that end after it are ended at its end.
+    let options = HighlightOptions::new()
+        .layer_events("html")
+        .byte_range(9..29);
+    assert_eq!(
+        to_event_strings_with_injections(
+            &mut context,
+            &config,
+            source.as_bytes(),
+            options,
+            &injected_config
+        ),
+        &[
+            "[html:0]",
+            "<tag>",
+            "[dir:1]",
+            "<constant>",
+            "9..10",
+            "</>",
+            "[/]",
+            "10..19",
+            "</>",
+            "19..20",
+            "<tag>",
+            "20..28",
+            "[dir:1]",
+            "<variable>",
+            "28..29",
+            "</>",
+            "[/]",
+            "</>",
+            "[/]",
+        ]
+    );
+
+    // Without the option, there are no layer events.
+    let events = to_event_strings_with_injections(
+        &mut context,
+        &config,
+        source.as_bytes(),
+        HighlightOptions::new(),
+        &injected_config,
+    );
+    assert!(!events.iter().any(|event| event.starts_with('[')));
+}
+
+#[test]
+fn test_rendering_layer_events_to_html() {
+    let injected_config = HIGHLIGHTER
+        .load_configuration(
+            get_highlight_dir_test_language(),
+            "(identifier) @variable\n",
+            "",
+            "",
+        )
+        .unwrap();
+    let config = HIGHLIGHTER
+        .load_configuration(
+            get_html_like_injection_test_language(),
+            "(script_element) @tag\n",
+            concat!(
+                "((script_element (raw_text) @injection.content) @injection.site\n",
+                " (set! injection.language \"dir\"))\n",
+            ),
+            "",
+        )
+        .unwrap();
+
+    // The renderer wraps the document and each injected region in a span with the name
+    // of its language, as the events are streamed from the highlighter.
+    let source = b"<script>a</script>\n<script>b</script>";
+    let mut context = HighlightContext::new();
+    let events = HIGHLIGHTER
+        .highlight_with_options(
+            &mut context,
+            &config,
+            source,
+            HighlightOptions::new().layer_events("html"),
+            |_| Some(&injected_config),
+        )
+        .unwrap();
+    let mut renderer = HtmlRenderer::new();
+    renderer
+        .render(events, source, &|highlight| {
+            HTML_ATTRS[highlight.0].as_bytes()
+        })
+        .unwrap();
+    assert_eq!(
+        renderer.lines().collect::<Vec<_>>(),
+        &[
+            concat!(
+                "<span data-language=\"html\"><span class=tag>&lt;script&gt;",
+                "<span data-language=\"dir\"><span class=variable>a</span></span>",
+                "&lt;/script&gt;</span></span>\n",
+            ),
+            concat!(
+                "<span data-language=\"html\"><span class=tag>&lt;script&gt;",
+                "<span data-language=\"dir\"><span class=variable>b</span></span>",
+                "&lt;/script&gt;</span></span>\n",
+            ),
+        ]
+    );
+}
+
+#[test]
+fn test_highlighting_with_positions() {
+    let injected_config = HIGHLIGHTER
+        .load_configuration(
+            get_highlight_dir_test_language(),
+            "(identifier) @variable\n(number) @constant\n",
+            "",
+            "",
+        )
+        .unwrap();
+    let config = HIGHLIGHTER
+        .load_configuration(
+            get_html_like_injection_test_language(),
+            "(attribute_value) @string\n",
+            concat!(
+                "((script_element (raw_text) @injection.content) @injection.site\n",
+                " (set! injection.language \"injected\"))\n",
+                "((style_element (raw_text) @injection.content) @injection.site\n",
+                " (set! injection.language \"injected\"))\n",
+            ),
+            "",
+        )
+        .unwrap();
+
+    // The injected layers start in the middle of a line, and contain line breaks.
+    let source = concat!(
+        "<style>\n",
+        "a\n",
+        "  1 b</style>\n",
+        "<script type=\"text/x-foo\">c\n",
+        "2</script>",
+    );
+    let mut context = HighlightContext::new();
+    let events = HIGHLIGHTER
+        .highlight_with_positions(
+            &mut context,
+            &config,
+            source.as_bytes(),
+            HighlightOptions::new(),
+            |_| Some(&injected_config),
+        )
+        .unwrap();
+
+    let mut highlighted = Vec::new();
+    let mut stack = Vec::new();
+    let mut previous_range: Option<Range> = None;
+    for event in events {
+        let (event, range) = event.unwrap();
+        if let Some(previous_range) = previous_range {
+            assert_eq!(range.start_byte, previous_range.end_byte);
+            assert_eq!(range.start_point, previous_range.end_point);
+        }
+        previous_range = Some(range);
+        match event {
+            HighlightEvent::HighlightStart(highlight) => stack.push(highlight.0),
+            HighlightEvent::HighlightEnd => {
+                stack.pop();
+            }
+            HighlightEvent::Source { start, end } => {
+                assert_eq!((range.start_byte, range.end_byte), (start, end));
+                if let Some(highlight) = stack.last() {
+                    highlighted.push((
+                        &source[start..end],
+                        HIGHLIGHTER.names()[*highlight].as_str(),
+                        range.start_point,
+                        range.end_point,
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    assert_eq!(
+        highlighted,
+        &[
+            ("a", "variable", Point::new(1, 0), Point::new(1, 1)),
+            ("1", "constant", Point::new(2, 2), Point::new(2, 3)),
+            ("b", "variable", Point::new(2, 4), Point::new(2, 5)),
+            ("text/x-foo", "string", Point::new(3, 14), Point::new(3, 24)),
+            ("c", "variable", Point::new(3, 26), Point::new(3, 27)),
+            ("2", "constant", Point::new(4, 0), Point::new(4, 1)),
+        ]
+    );
+    assert_eq!(previous_range.unwrap().end_point, Point::new(4, 10));
+}
+
+#[test]
+fn test_taking_the_trees_of_all_layers() {
+    let injected_config = HIGHLIGHTER
+        .load_configuration(
+            get_highlight_dir_test_language(),
+            "(identifier) @variable\n",
+            "",
+            "",
+        )
+        .unwrap();
+    let config = HIGHLIGHTER
+        .load_configuration(
+            get_html_like_injection_test_language(),
+            "(attribute_value) @string\n",
+            concat!(
+                "((script_element (raw_text) @injection.content) @injection.site\n",
+                " (set! injection.language \"script\"))\n",
+                "((style_element (raw_text) @injection.content) @injection.site\n",
+                " (set! injection.language \"style\"))\n",
+            ),
+            "",
+        )
+        .unwrap();
+
+    let source = "<script>a 1 b</script><style>c</style>";
+    let mut context = HighlightContext::new();
+    let options = HighlightOptions::new().retain_trees(true);
+    to_event_strings_with_injections(
+        &mut context,
+        &config,
+        source.as_bytes(),
+        options,
+        &injected_config,
+    );
+    let layers = context.take_trees().unwrap().into_layers();
+    assert_eq!(
+        layers
+            .iter()
+            .map(|(name, tree, ranges)| (name.as_deref(), tree.root_node().kind(), ranges.clone()))
+            .collect::<Vec<_>>(),
+        &[
+            (None, "document", vec![0..usize::MAX]),
+            (Some("script"), "program", vec![8..13]),
+            (Some("style"), "program", vec![29..30]),
+        ]
+    );
+
+    // The trees can be queried again without parsing the document again.
+    let query = Query::new(get_highlight_dir_test_language(), "(identifier) @name").unwrap();
+    let mut cursor = QueryCursor::new();
+    let names = cursor
+        .matches(&query, layers[1].1.root_node(), |node| {
+            &source.as_bytes()[node.byte_range()]
+        })
+        .map(|m| m.captures[0].node.utf8_text(source.as_bytes()).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(names, &["a", "b"]);
+}
+
+#[test]
+fn test_highlighting_incrementally_with_previous_trees() {
+    let injected_config = HIGHLIGHTER
+        .load_configuration(
+            get_highlight_dir_test_language(),
+            "(identifier) @variable\n(number) @constant\n",
+            "",
+            "",
+        )
+        .unwrap();
+    let config = HIGHLIGHTER
+        .load_configuration(
+            get_html_like_injection_test_language(),
+            "(attribute_value) @string\n",
+            concat!(
+                "((script_element (raw_text) @injection.content) @injection.site\n",
+                " (set! injection.language \"injected\"))\n",
+                "((style_element (raw_text) @injection.content) @injection.site\n",
+                " (set! injection.language \"injected\"))\n",
+            ),
+            "",
+        )
+        .unwrap();
+
+    let mut context = HighlightContext::new();
+    let mut highlight = |source: &str, previous_trees: Option<&LayerTrees>| {
+        let mut options = HighlightOptions::new().retain_trees(true);
+        if let Some(previous_trees) = previous_trees {
+            options = options.previous_trees(previous_trees);
+        }
+        let events = to_event_strings_with_injections(
+            &mut context,
+            &config,
+            source.as_bytes(),
+            options,
+            &injected_config,
+        );
+        (events, context.parse_stats(), context.take_trees().unwrap())
+    };
+
+    let source = "<script type=\"a\">b 1</script><style>c</style>";
+    let (_, stats, mut trees) = highlight(source, None);
+    assert_eq!((stats.layer_count, stats.reused_tree_count), (3, 0));
+    assert_eq!(trees.len(), 3);
+
+    // Insert a number into the script. Each of the layers reuses its previous tree.
+    let (edit, source) = insert(source, source.find(" 1").unwrap(), " 23");
+    trees.edit(&edit);
+    let (events, stats, mut trees) = highlight(&source, Some(&trees));
+    let (expected_events, _, expected_trees) = highlight(&source, None);
+    assert_eq!((stats.layer_count, stats.reused_tree_count), (3, 3));
+    assert_eq!(events, expected_events);
+    assert_eq!(
+        trees.root().unwrap().root_node().to_sexp(),
+        expected_trees.root().unwrap().root_node().to_sexp()
+    );
+
+    // Insert a new element between the others. Its layer is parsed from scratch.
+    let (edit, source) = insert(&source, source.find("<style>").unwrap(), "<style>4</style>");
+    trees.edit(&edit);
+    let (events, stats, _) = highlight(&source, Some(&trees));
+    assert_eq!((stats.layer_count, stats.reused_tree_count), (4, 3));
+    assert_eq!(events, highlight(&source, None).0);
+    assert_eq!(
+        events
+            .iter()
+            .filter(|event| event.starts_with('<') && *event != "</>")
+            .collect::<Vec<_>>(),
+        &[
+            "<string>",
+            "<variable>",
+            "<constant>",
+            "<constant>",
+            "<constant>",
+            "<variable>",
+        ]
+    );
+}
+
+#[test]
+fn test_highlighting_injections_that_replace_the_highlights_of_their_parent() {
+    let injected_config = HIGHLIGHTER
+        .load_configuration(
+            get_highlight_dir_test_language(),
+            "(identifier) @variable\n(number) @constant\n",
+            "",
+            "",
+        )
+        .unwrap();
+    let highlight = |injections_query: &str| {
+        let config = HIGHLIGHTER
+            .load_configuration(
+                get_html_like_injection_test_language(),
+                "(raw_text) @string\n",
+                injections_query,
+                "",
+            )
+            .unwrap();
+        let source = "<script>a 1</script><style> b 2</style><style> c 3</style>";
+        let mut context = HighlightContext::new();
+        let events = HIGHLIGHTER
+            .highlight_with_options(
+                &mut context,
+                &config,
+                source.as_bytes(),
+                HighlightOptions::new(),
+                |_| Some(&injected_config),
+            )
+            .unwrap();
+        spans_from_events(events)
+            .unwrap()
+            .into_iter()
+            .map(|(range, highlight)| (&source[range], HIGHLIGHTER.names()[highlight.0].as_str()))
+            .collect::<Vec<_>>()
+    };
+
+    // The raw text of the scripts is only highlighted by the injected layer, so the space
+    // between its tokens isn't highlighted as a string. The styles keep their highlights.
+    assert_eq!(
+        highlight(concat!(
+            "((script_element (raw_text) @injection.content) @injection.site\n",
+            " (set! injection.language \"code\")\n",
+            " (set! injection.replace))\n",
+            "((style_element (raw_text) @injection.content) @injection.site\n",
+            " (set! injection.language \"code\"))\n",
+        )),
+        &[
+            ("a", "variable"),
+            ("1", "constant"),
+            (" ", "string"),
+            ("b", "variable"),
+            (" ", "string"),
+            ("2", "constant"),
+            (" ", "string"),
+            ("c", "variable"),
+            (" ", "string"),
+            ("3", "constant"),
+        ]
+    );
+
+    // The same applies to combined injections.
+    assert_eq!(
+        highlight(concat!(
+            "((style_element (raw_text) @injection.content) @injection.site\n",
+            " (set! injection.language \"code\")\n",
+            " (set! injection.combined)\n",
+            " (set! injection.replace))\n",
+        )),
+        &[
+            ("a 1", "string"),
+            ("b", "variable"),
+            ("2", "constant"),
+            ("c", "variable"),
+            ("3", "constant"),
+        ]
+    );
+}
+
+#[test]
+fn test_highlighting_combined_injections() {
+    let template_language = compile_grammar(
+        r#"{
+            "name": "combined_injection_test_template",
+            "rules": {
+                "template": {
+                    "type": "REPEAT",
+                    "content": {
+                        "type": "CHOICE",
+                        "members": [
+                            {"type": "SYMBOL", "name": "content"},
+                            {"type": "SYMBOL", "name": "directive"}
+                        ]
+                    }
+                },
+                "directive": {
+                    "type": "SEQ",
+                    "members": [
+                        {"type": "STRING", "value": "<%"},
+                        {"type": "SYMBOL", "name": "code"},
+                        {"type": "STRING", "value": "%>"}
+                    ]
+                },
+                "code": {"type": "PATTERN", "value": "[^%]+"},
+                "content": {"type": "PATTERN", "value": "[^<]+"}
+            }
+        }"#,
+    )
+    .unwrap();
+    let code_language = compile_grammar(
+        r#"{
+            "name": "combined_injection_test_code",
+            "rules": {
+                "program": {
+                    "type": "REPEAT",
+                    "content": {"type": "SYMBOL", "name": "_statement"}
+                },
+                "_statement": {
+                    "type": "CHOICE",
+                    "members": [
+                        {"type": "SYMBOL", "name": "if_statement"},
+                        {"type": "SYMBOL", "name": "identifier"}
+                    ]
+                },
+                "if_statement": {
+                    "type": "SEQ",
+                    "members": [
+                        {"type": "STRING", "value": "if"},
+                        {
+                            "type": "FIELD",
+                            "name": "condition",
+                            "content": {"type": "SYMBOL", "name": "identifier"}
+                        },
+                        {"type": "REPEAT", "content": {"type": "SYMBOL", "name": "_statement"}},
+                        {"type": "STRING", "value": "end"}
+                    ]
+                },
+                "identifier": {"type": "PATTERN", "value": "[a-z]+"}
+            }
+        }"#,
+    )
+    .unwrap();
+    let code_config = HIGHLIGHTER
+        .load_configuration(
+            code_language,
+            "(if_statement condition: (identifier) @constant)\n(identifier) @variable\n",
+            "",
+            "",
+        )
+        .unwrap();
+
+    // The `if` statement is opened in one directive, and closed in another.
+    let source = "<% if a %>x<% b %>y<% end %><% c %>";
+    let highlight = |injections_query: &str| {
+        let config = HIGHLIGHTER
+            .load_configuration(template_language, "", injections_query, "")
+            .unwrap();
+        let mut context = HighlightContext::new();
+        let options = HighlightOptions::new().injection_stats(true);
+        let events = HIGHLIGHTER
+            .highlight_with_options(&mut context, &config, source.as_bytes(), options, |_| {
+                Some(&code_config)
+            })
+            .unwrap();
+        let mut highlighted = Vec::new();
+        let mut stack = Vec::new();
+        for event in events {
+            match event.unwrap() {
+                HighlightEvent::HighlightStart(highlight) => stack.push(highlight.0),
+                HighlightEvent::HighlightEnd => {
+                    stack.pop();
+                }
+                HighlightEvent::Source { start, end } => {
+                    if let Some(highlight) = stack.last() {
+                        highlighted.push((
+                            &source[start..end],
+                            HIGHLIGHTER.names()[*highlight].as_str(),
+                        ));
+                    }
+                }
+                _ => {}
+            }
+        }
+        let layer_count = context.injection_stats().unwrap()["code"].layer_count;
+        (highlighted, layer_count)
+    };
+
+    // When the directives are combined, their code is parsed as a single layer.
+    assert_eq!(
+        highlight(
+            "((directive (code) @injection.content) @injection.site\n (set! injection.language \"code\")\n (set! injection.combined))\n"
+        ),
+        (
+            vec![
+                ("a", "constant"),
+                ("b", "variable"),
+                ("c", "variable"),
+            ],
+            1
+        )
+    );
+
+    // Otherwise, each directive is parsed separately, so the `if` statement is incomplete.
+    let (highlighted, layer_count) =
+        highlight("((directive (code) @injection.content) @injection.site\n (set! injection.language \"code\"))\n");
+    assert_eq!(layer_count, 4);
+    assert!(!highlighted.contains(&("a", "constant")));
+}
+
+#[test]
+fn test_highlighter_with_duplicate_and_empty_names() {
+    let names = |names: &[&str]| names.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+    let highlighter =
+        Highlighter::try_new(names(&["function", "function.builtin", "function"])).unwrap();
+    assert_eq!(highlighter.names(), &["function", "function.builtin"]);
+
+    assert_eq!(
+        Highlighter::try_new(names(&["function", "function.builtin", "", "function"])).unwrap_err(),
+        HighlightNamesError::EmptyName(2)
+    );
+
+    // `new` keeps the list as it is, but only the first occurrence of a name is used.
+    let highlighter = Highlighter::new(names(&["variable", "function", "", "function"]));
+    assert_eq!(highlighter.names().len(), 4);
+    assert_eq!(highlights_for_capture(&highlighter, "function"), vec![1]);
+}
+
+#[test]
+fn test_highlighter_chooses_first_of_equally_specific_names() {
+    let highlighter = Highlighter::new(
+        [
+            "function",
+            "function.method",
+            "function.builtin",
+            "variable",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect(),
+    );
+    assert_eq!(
+        highlights_for_capture(&highlighter, "function.builtin.method"),
+        vec![1]
+    );
+    assert_eq!(
+        highlights_for_capture(&highlighter, "function.method.builtin"),
+        vec![1]
+    );
+    assert_eq!(
+        highlights_for_capture(&highlighter, "function.other"),
+        vec![0]
+    );
+}
+
+#[test]
+fn test_highlighter_with_strict_name_matching() {
+    let names = ["function", "function.builtin", "string.special", "keyword"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>();
+    let highlighter = Highlighter::new(names.clone());
+    let strict_highlighter = Highlighter::new_strict(names);
+
+    // The capture names, with the names of the highlights that they resolve to by default
+    // and with strict matching.
+    let matrix = [
+        ("function", Some("function"), Some("function")),
+        ("function.method", Some("function"), Some("function")),
+        (
+            "function.builtin",
+            Some("function.builtin"),
+            Some("function.builtin"),
+        ),
+        (
+            "function.builtin.static",
+            Some("function.builtin"),
+            Some("function.builtin"),
+        ),
+        ("builtin.function", Some("function.builtin"), None),
+        ("builtin.function.weird", Some("function.builtin"), None),
+        ("method.function", Some("function"), None),
+        (
+            "string.special",
+            Some("string.special"),
+            Some("string.special"),
+        ),
+        (
+            "string.special.url",
+            Some("string.special"),
+            Some("string.special"),
+        ),
+        ("special.string", Some("string.special"), None),
+        ("string", None, None),
+        ("keyword.function", Some("function"), Some("keyword")),
+        ("variable", None, None),
+    ];
+    for (capture_name, expected, expected_strict) in matrix.iter() {
+        let resolve = |highlighter: &Highlighter| {
+            highlights_for_capture(highlighter, capture_name)
+                .first()
+                .map(|i| highlighter.names()[*i].clone())
+        };
+        assert_eq!(
+            (
+                resolve(&highlighter).as_deref(),
+                resolve(&strict_highlighter).as_deref()
+            ),
+            (*expected, *expected_strict),
+            "capture name {}",
+            capture_name
+        );
+    }
+}
+
+#[test]
+fn test_reconfiguring_the_highlight_names_of_a_configuration() {
+    let highlights_query = "(identifier) @variable.builtin\n(number) @constant\n(string) @string\n";
+    let mut config = HIGHLIGHTER
+        .load_configuration(get_highlight_dir_test_language(), highlights_query, "", "")
+        .unwrap();
+    let source = b"a 1 \"b\"";
+    let highlights = |highlighter: &Highlighter, config: &HighlightConfiguration| {
+        let mut context = HighlightContext::new();
+        let events = highlighter
+            .highlight(&mut context, config, source, None, |_| None)
+            .unwrap();
+        spans_from_events(events)
+            .unwrap()
+            .into_iter()
+            .map(|(range, highlight)| (range, highlight.0))
+            .collect::<Vec<_>>()
+    };
+
+    // After reconfiguring, the highlights are the same as those of a configuration that
+    // was loaded by a highlighter with the new names.
+    let names = ["string", "variable", "number"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>();
+    config.configure(&names);
+    let highlighter = Highlighter::new(names);
+    let expected_config = highlighter
+        .load_configuration(get_highlight_dir_test_language(), highlights_query, "", "")
+        .unwrap();
+    assert_eq!(
+        highlights(&highlighter, &config),
+        highlights(&highlighter, &expected_config)
+    );
+    assert_eq!(highlights(&highlighter, &config), &[(0..1, 1), (4..7, 0)]);
+    assert_eq!(config.nonconformant_capture_names(), &["constant"]);
+
+    // The configuration can be changed back.
+    config.configure(HIGHLIGHTER.names());
+    assert_eq!(
+        highlights(&HIGHLIGHTER, &config),
+        highlights(
+            &HIGHLIGHTER,
+            &HIGHLIGHTER
+                .load_configuration(get_highlight_dir_test_language(), highlights_query, "", "")
+                .unwrap()
+        )
+    );
+
+    // A strict highlighter's configurations are reconfigured with strict matching.
+    let mut config = Highlighter::new_strict(Vec::new())
+        .load_configuration(get_highlight_dir_test_language(), highlights_query, "", "")
+        .unwrap();
+    config.configure(&["builtin".to_string(), "variable".to_string()]);
+    assert_eq!(highlights(&HIGHLIGHTER, &config), &[(0..1, 1)]);
+}
+
+#[test]
+fn test_reconfiguring_a_large_configuration_is_fast() {
+    // A query with thousands of patterns, each with its own capture name.
+    let highlights_query = (0..3000)
+        .map(|i| {
+            format!(
+                "((identifier) @variable.x{0} (eq? @variable.x{0} \"x{0}\"))\n",
+                i
+            )
+        })
+        .collect::<String>();
+    let start_time = Instant::now();
+    let mut config = HIGHLIGHTER
+        .load_configuration(get_highlight_dir_test_language(), &highlights_query, "", "")
+        .unwrap();
+    let load_time = start_time.elapsed();
+
+    let names = vec!["variable".to_string()];
+    let start_time = Instant::now();
+    config.configure(&names);
+    let configure_time = start_time.elapsed();
+    assert!(
+        configure_time * 10 < load_time,
+        "reconfiguring took {:?}, and loading took {:?}",
+        configure_time,
+        load_time
+    );
+    assert!(config.nonconformant_capture_names().is_empty());
+}
+
+// Highlight a single identifier with a query that gives it the given capture name, and
+// return the indices of the highlights that are applied to it.
+fn highlights_for_capture(highlighter: &Highlighter, capture_name: &str) -> Vec<usize> {
+    let config = highlighter
+        .load_configuration(
+            get_highlight_dir_test_language(),
+            &format!("(identifier) @{}", capture_name),
+            "",
+            "",
+        )
+        .unwrap();
+    let mut context = HighlightContext::new();
+    highlighter
+        .highlight(&mut context, &config, b"a", None, |_| None)
+        .unwrap()
+        .filter_map(|event| match event.unwrap() {
+            HighlightEvent::HighlightStart(highlight) => Some(highlight.0),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn test_choosing_non_overlapping_injection_ranges() {
+    use tree_sitter_highlight::util::non_overlapping_ranges;
+
+    // Nested ranges: by default, the outermost range is kept.
+    let nested = [5..8, 0..20, 2..10, 12..15];
+    assert_eq!(non_overlapping_ranges(&nested, false), vec![1]);
+    assert_eq!(non_overlapping_ranges(&nested, true), vec![0, 3]);
+
+    // Ranges with the same start: the longer range contains the shorter one.
+    let same_start = [0..4, 0..10];
+    assert_eq!(non_overlapping_ranges(&same_start, false), vec![1]);
+    assert_eq!(non_overlapping_ranges(&same_start, true), vec![0]);
+
+    // Partially overlapping ranges: the range that starts first is kept.
+    let partially_overlapping = [6..12, 0..8, 10..14];
+    assert_eq!(
+        non_overlapping_ranges(&partially_overlapping, false),
+        vec![1, 2]
+    );
+    assert_eq!(
+        non_overlapping_ranges(&partially_overlapping, true),
+        vec![1, 2]
+    );
+
+    // Disjoint ranges are all kept, in order of their position.
+    let disjoint = [10..12, 0..4, 4..6, 6..6];
+    assert_eq!(non_overlapping_ranges(&disjoint, false), vec![1, 2, 3, 0]);
+    assert_eq!(non_overlapping_ranges(&disjoint, true), vec![1, 2, 3, 0]);
+}
+
+#[test]
+fn test_collecting_parse_stats() {
+    let injected_config = HIGHLIGHTER
+        .load_configuration(
+            get_highlight_dir_test_language(),
+            "(identifier) @variable\n",
+            "",
+            "",
+        )
+        .unwrap();
+    let config = HIGHLIGHTER
+        .load_configuration(
+            get_html_like_injection_test_language(),
+            "",
+            "((style_element (raw_text) @injection.content) @injection.site\n\
+             (set! injection.language \"text\"))\n",
+            "",
+        )
+        .unwrap();
+
+    // The injected content is parsed twice: once as part of the document, and once on
+    // its own.
+    let source = "<style>abc12</style>";
+    let mut context = HighlightContext::new();
+    let events = HIGHLIGHTER
+        .highlight(&mut context, &config, source.as_bytes(), None, |_| {
+            Some(&injected_config)
+        })
+        .unwrap();
+    for event in events {
+        event.unwrap();
+    }
+    assert_eq!(
+        context.parse_stats(),
+        ParseStats {
+            layer_count: 2,
+            parse_count: 2,
+            parsed_bytes: source.len() + "abc12".len(),
+            reused_tree_count: 0,
+            root_has_error: false,
+            error_count: 0,
+            skipped_injection_count: 0,
+        }
+    );
+
+    // The counts are reset by each highlighting call.
+    let events = HIGHLIGHTER
+        .highlight(&mut context, &config, source.as_bytes(), None, |_| None)
+        .unwrap();
+    for event in events {
+        event.unwrap();
+    }
+    assert_eq!(
+        context.parse_stats(),
+        ParseStats {
+            layer_count: 1,
+            parse_count: 1,
+            parsed_bytes: source.len(),
+            reused_tree_count: 0,
+            root_has_error: false,
+            error_count: 0,
+            skipped_injection_count: 0,
+        }
+    );
+}
+
+#[test]
+fn test_collecting_syntax_error_stats() {
+    let injected_config = HIGHLIGHTER
+        .load_configuration(
+            get_highlight_dir_test_language(),
+            "(identifier) @variable\n",
+            "",
+            "",
+        )
+        .unwrap();
+    let config = HIGHLIGHTER
+        .load_configuration(
+            get_html_like_injection_test_language(),
+            "",
+            "((style_element (raw_text) @injection.content) @injection.site\n\
+             (set! injection.language \"text\"))\n",
+            "",
+        )
+        .unwrap();
+
+    let mut context = HighlightContext::new();
+    let mut parse_stats = |config, source: &str| {
+        let events = HIGHLIGHTER
+            .highlight_with_options(
+                &mut context,
+                config,
+                source.as_bytes(),
+                HighlightOptions::new().injection_stats(true),
+                |_| Some(&injected_config),
+            )
+            .unwrap();
+        for event in events {
+            event.unwrap();
+        }
+        let injection_error_count = context
+            .injection_stats()
+            .unwrap()
+            .get("text")
+            .map_or(0, |stats| stats.error_count);
+        let stats = context.parse_stats();
+        (
+            stats.root_has_error,
+            stats.error_count,
+            injection_error_count,
+        )
+    };
+
+    assert_eq!(parse_stats(&injected_config, "abc12"), (false, 0, 0));
+    assert_eq!(parse_stats(&injected_config, "abc#12$"), (true, 2, 0));
+
+    // Errors in an injected language are counted, but don't affect the document's flag.
+    assert_eq!(parse_stats(&config, "<style>abc12</style>"), (false, 0, 0));
+    assert_eq!(parse_stats(&config, "<style>abc#12</style>"), (false, 1, 1));
+    assert_eq!(
+        parse_stats(&config, "<style>abc#12</style><style>"),
+        (true, 2, 1)
+    );
+}
+
+#[test]
+fn test_resolving_local_bindings() {
+    let config = HIGHLIGHTER
+        .load_configuration(
+            get_self_injection_test_language(),
+            "(identifier) @variable\n",
+            concat!(
+                "((string (string_content) @injection.content) @injection.site\n",
+                " (match? @injection.site \" \")\n",
+                " (set! injection.self))\n",
+            ),
+            concat!(
+                "(program) @local.scope\n",
+                "(string \"'\" (string_content) @local.definition)\n",
+                "(identifier) @local.reference\n",
+            ),
+        )
+        .unwrap();
+
+    // Single-quoted strings define variables, which are referenced by identifiers. The
+    // content of each string that contains a space is a separate layer, so its references
+    // are only resolved to the definitions within it.
+    let source = "'a' a \"'b' b a\" a";
+    let mut context = HighlightContext::new();
+    let options = HighlightOptions::new().layer_events("self");
+    let bindings = HIGHLIGHTER
+        .resolve_locals(&mut context, &config, source.as_bytes(), options, |_| None)
+        .unwrap();
+    assert_eq!(
+        bindings,
+        vec![
+            LocalBinding {
+                layer: 0,
+                language: "self".to_string(),
+                depth: 0,
+                definition: 1..2,
+                references: vec![4..5, 16..17],
+            },
+            LocalBinding {
+                layer: 1,
+                language: "self".to_string(),
+                depth: 1,
+                definition: 8..9,
+                references: vec![11..12],
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_highlighting_source_in_chunks() {
+    struct Chunks(Vec<Vec<u8>>);
+
+    impl HighlightSource for Chunks {
+        fn chunk_at(&self, mut byte: usize) -> &[u8] {
+            for chunk in &self.0 {
+                if byte < chunk.len() {
+                    return &chunk[byte..];
+                }
+                byte -= chunk.len();
+            }
+            &[]
+        }
+
+        fn len(&self) -> usize {
+            self.0.iter().map(Vec::len).sum()
+        }
+    }
+
+    let config = HIGHLIGHTER
+        .load_configuration(
+            get_self_injection_test_language(),
+            concat!(
+                "(string \"'\" (string_content) @variable.parameter)\n",
+                "(identifier) @variable\n",
+            ),
+            "",
+            concat!(
+                "(program) @local.scope\n",
+                "(string \"'\" (string_content) @local.definition)\n",
+                "(identifier) @local.reference\n",
+            ),
+        )
+        .unwrap();
+
+    // The source is split in the middle of the second identifier, which is still resolved
+    // to the definition in the string.
+    let source = "'abcd' abcd abc";
+    let chunks = Chunks(vec![b"'abcd' ab".to_vec(), b"cd abc".to_vec()]);
+    let mut context = HighlightContext::new();
+    let events = HIGHLIGHTER
+        .highlight_chunks(
+            &mut context,
+            &config,
+            &chunks,
+            HighlightOptions::new(),
+            |_| None,
+        )
+        .unwrap();
+    let spans = spans_from_events(events).unwrap();
+    assert_eq!(
+        spans
+            .iter()
+            .map(|(range, highlight)| (
+                &source[range.clone()],
+                HIGHLIGHTER.names()[highlight.0].as_str()
+            ))
+            .collect::<Vec<_>>(),
+        vec![
+            ("abcd", "variable.parameter"),
+            ("abcd", "variable.parameter"),
+            ("abc", "variable"),
+        ]
+    );
+
+    let events = HIGHLIGHTER
+        .highlight(&mut context, &config, source.as_bytes(), None, |_| None)
+        .unwrap();
+    assert_eq!(spans_from_events(events).unwrap(), spans);
+}
+
+#[test]
+fn test_skipping_injections_beyond_the_maximum_depth() {
+    // The identifiers are injected into themselves, so without a limit, the layers would
+    // be nested without end.
+    let config = HIGHLIGHTER
+        .load_configuration(
+            get_self_injection_test_language(),
+            "(identifier) @variable\n",
+            concat!(
+                "((program (identifier) @injection.content) @injection.site\n",
+                " (set! injection.self))\n",
+            ),
+            "",
+        )
+        .unwrap();
+    let source = "a b";
+    let mut context = HighlightContext::new();
+    to_spans(&mut context, &config, source, HighlightOptions::new());
+    let stats = context.parse_stats();
+    assert_eq!(stats.layer_count, 1 + 32);
+    assert_eq!(stats.skipped_injection_count, 1);
+
+    let options = HighlightOptions::new().max_injection_depth(3);
+    to_spans(&mut context, &config, source, options);
+    let stats = context.parse_stats();
+    assert_eq!(stats.layer_count, 1 + 3);
+    assert_eq!(stats.skipped_injection_count, 1);
+
+    // Injections are only skipped if they would be too deep.
+    let options = HighlightOptions::new().max_injection_depth(0);
+    to_spans(&mut context, &config, "", options);
+    assert_eq!(context.parse_stats().skipped_injection_count, 0);
+}
+
+#[test]
+fn test_highlighting_an_injection_site_with_a_layer_without_captures() {
+    let injected_config = HIGHLIGHTER
+        .load_configuration(
+            get_highlight_dir_test_language(),
+            "(identifier) @variable\n(number) @constant\n",
+            "",
+            "",
+        )
+        .unwrap();
+    let string_config = HIGHLIGHTER
+        .load_configuration(
+            get_highlight_dir_test_language(),
+            "(string) @string\n",
+            "",
+            "",
+        )
+        .unwrap();
+    let config = HIGHLIGHTER
+        .load_configuration(
+            get_html_like_injection_test_language(),
+            "(attribute_value) @string\n",
+            concat!(
+                "((script_element (raw_text) @injection.content) @injection.site\n",
+                " (set! injection.language \"injected\"))\n",
+                "((script_element (raw_text) @injection.content) @injection.site\n",
+                " (set! injection.language \"strings\"))\n",
+            ),
+            "",
+        )
+        .unwrap();
+
+    // Each script is injected twice, but it doesn't contain any strings, so the second layer
+    // has no highlights. This doesn't hold back the highlights of the first layer.
+    let source = "<script>a 1</script><script a=\"b\">c</script>";
+    let mut context = HighlightContext::new();
+    let events = HIGHLIGHTER
+        .highlight_with_options(
+            &mut context,
+            &config,
+            source.as_bytes(),
+            HighlightOptions::new(),
+            |name| match name {
+                "strings" => Some(&string_config),
+                _ => Some(&injected_config),
+            },
+        )
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    check_event_invariants(&events);
+    assert_eq!(
+        spans_from_events(events.into_iter().map(Ok))
+            .unwrap()
+            .into_iter()
+            .map(|(range, highlight)| (&source[range], HIGHLIGHTER.names()[highlight.0].as_str()))
+            .collect::<Vec<_>>(),
+        &[
+            ("a", "variable"),
+            ("1", "constant"),
+            ("b", "string"),
+            ("c", "variable"),
+        ]
+    );
+}
+
+#[test]
+fn test_highlight_events_are_coalesced_and_balanced() {
+    let injected_config = HIGHLIGHTER
+        .load_configuration(
+            get_highlight_dir_test_language(),
+            "(identifier) @variable\n(number) @constant\n",
+            "",
+            "",
+        )
+        .unwrap();
+    let string_config = HIGHLIGHTER
+        .load_configuration(
+            get_highlight_dir_test_language(),
+            "(string) @string\n",
+            "",
+            "",
+        )
+        .unwrap();
+    let config = HIGHLIGHTER
+        .load_configuration(
+            get_html_like_injection_test_language(),
+            "(attribute_value) @string\n(script_element) @tag\n",
+            concat!(
+                "((script_element (raw_text) @injection.content) @injection.site\n",
+                " (set! injection.language \"injected\"))\n",
+                "((style_element (raw_text) @injection.content) @injection.site\n",
+                " (set! injection.language \"injected\"))\n",
+                "((style_element (raw_text) @injection.content) @injection.site\n",
+                " (set! injection.language \"strings\"))\n",
+            ),
+            "",
+        )
+        .unwrap();
+
+    // Every prefix of a document with many injections, so that layers end in the middle of
+    // the document, or are cut off by a syntax error. Styles are injected twice, and most of
+    // them don't contain any strings.
+    let document = concat!(
+        "<script type=\"a\">b 1 cd</script><style> e </style><style>12 \"s\" t</style>",
+        "<script>f</script><script a=\"b\" c=\"d\">  g 3  </script><style>h</style>",
+        "<style>  </style><script>i</script><style> </style>",
+    );
+    let mut context = HighlightContext::new();
+    for end in 0..=document.len() {
+        let source = &document.as_bytes()[..end];
+        for options in [
+            HighlightOptions::new(),
+            HighlightOptions::new().layer_events("html"),
+            HighlightOptions::new().max_injection_depth(0),
+            HighlightOptions::new().byte_range(end / 3..end - end / 3),
+            HighlightOptions::new().error_highlight(Highlight(6)),
+        ] {
+            let events = HIGHLIGHTER
+                .highlight_with_options(&mut context, &config, source, options, |name| match name {
+                    "strings" => Some(&string_config),
+                    _ => Some(&injected_config),
+                })
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            check_event_invariants(&events);
+        }
+    }
+}
+
+#[test]
+fn test_reloading_a_watched_configuration() {
+    let dir = tempfile::tempdir().unwrap();
+    let highlights_path = dir.path().join("highlights.scm");
+    fs::write(&highlights_path, "(identifier) @variable\n").unwrap();
+    let watcher = Arc::new(
+        ConfigurationWatcher::new(
+            &HIGHLIGHTER,
+            get_highlight_dir_test_language(),
+            &highlights_path,
+        )
+        .names(vec!["test".to_string()]),
+    );
+
+    // The queries are read by the first reload, and only rebuilt when they change.
+    assert!(watcher.current().is_none());
+    assert!(watcher.reload().unwrap());
+    assert!(!watcher.reload().unwrap());
+    let old_config = watcher.current().unwrap();
+    assert_eq!(old_config.names(), &["test".to_string()]);
+
+    let source = b"a 1 b";
+    let highlight_strings = |config: &HighlightConfiguration, context: &mut HighlightContext| {
+        HIGHLIGHTER
+            .highlight(context, config, source, None, |_| None)
+            .unwrap()
+            .map(|event| format!("{:?}", event.unwrap()))
+            .collect::<Vec<_>>()
+    };
+    let mut context = HighlightContext::new();
+    let old_events = highlight_strings(&old_config, &mut context);
+
+    // Change the query on another thread while a highlight is in progress. The highlight
+    // finishes with the configuration that it was started with.
+    let mut highlights = HIGHLIGHTER
+        .highlight(&mut context, &old_config, source, None, |_| None)
+        .unwrap();
+    let mut events = vec![format!("{:?}", highlights.next().unwrap().unwrap())];
+    thread::spawn({
+        let watcher = watcher.clone();
+        let highlights_path = highlights_path.clone();
+        move || {
+            fs::write(
+                &highlights_path,
+                "(identifier) @constant\n(number) @string\n",
+            )
+            .unwrap();
+            assert!(watcher.reload().unwrap());
+        }
+    })
+    .join()
+    .unwrap();
+    events.extend(highlights.map(|event| format!("{:?}", event.unwrap())));
+    assert_eq!(events, old_events);
+
+    // Later highlights use the new configuration, which keeps the configured names.
+    let new_config = watcher.current().unwrap();
+    assert!(!Arc::ptr_eq(&old_config, &new_config));
+    assert_eq!(new_config.names(), &["test".to_string()]);
+    assert_eq!(
+        &to_token_vector("a 1 b", &new_config).unwrap(),
+        &[vec![
+            ("a", vec!["constant"]),
+            (" ", vec![]),
+            ("1", vec!["string"]),
+            (" ", vec![]),
+            ("b", vec!["constant"]),
+        ]],
+    );
+    assert_eq!(
+        &to_token_vector("a 1 b", &old_config).unwrap(),
+        &[vec![
+            ("a", vec!["variable"]),
+            (" 1 ", vec![]),
+            ("b", vec!["variable"])
+        ]],
+    );
+
+    // An invalid query is reported, and the previous configuration is kept.
+    fs::write(&highlights_path, "(identifier @constant\n").unwrap();
+    match watcher.reload() {
+        Err(LoadError::Query(error)) => assert_eq!(error.section, QuerySection::Highlights),
+        _ => panic!("Expected a query error"),
+    }
+    assert!(Arc::ptr_eq(&watcher.current().unwrap(), &new_config));
+
+    // Registries can share the watcher's configuration.
+    let mut registry = LanguageRegistry::new();
+    registry.add_shared_language("test", watcher.current().unwrap());
+    assert!(Arc::ptr_eq(&registry.get("test").unwrap(), &new_config));
+}
+
+#[test]
+fn test_highlighting_with_a_source_size_limit() {
+    let config = HIGHLIGHTER
+        .load_configuration(
+            get_highlight_dir_test_language(),
+            "(identifier) @variable\n(string) @string\n",
+            "",
+            "",
+        )
+        .unwrap();
+
+    // The string contains a two-byte character, at offsets 3 and 4.
+    let source = "a \"\u{e9}\" b".as_bytes();
+    assert_eq!(source.len(), 8);
+    let events = |max_bytes, handling| {
+        to_event_strings(
+            &config,
+            source,
+            HighlightOptions::new().max_source_bytes(max_bytes, handling),
+        )
+    };
+    let full_events = vec![
+        "<variable>",
+        "0..1",
+        "</>",
+        "1..2",
+        "<string>",
+        "2..6",
+        "</>",
+        "6..7",
+        "<variable>",
+        "7..8",
+        "</>",
+    ];
+
+    // A source whose length is exactly the limit is highlighted completely.
+    assert_eq!(
+        events(8, OversizedSourceHandling::Reject).unwrap(),
+        full_events
+    );
+    assert_eq!(
+        events(8, OversizedSourceHandling::Truncate).unwrap(),
+        full_events
+    );
+
+    // A longer source is rejected before it is parsed.
+    assert_eq!(
+        events(7, OversizedSourceHandling::Reject),
+        Err(Error::SourceTooLarge)
+    );
+
+    // Otherwise, the rest of the source after the limit is emitted without highlights.
+    // Highlights end before the rest of the source.
+    let truncated_events = vec![
+        "<variable>",
+        "0..1",
+        "</>",
+        "1..2",
+        "<string>",
+        "2..6",
+        "</>",
+        "6..8",
+    ];
+    assert_eq!(
+        events(7, OversizedSourceHandling::Truncate).unwrap(),
+        truncated_events
+    );
+    assert_eq!(
+        events(6, OversizedSourceHandling::Truncate).unwrap(),
+        truncated_events
+    );
+
+    // The source isn't cut within a character. Here, the string is cut off before its
+    // contents, so it is not highlighted.
+    assert_eq!(
+        events(4, OversizedSourceHandling::Truncate).unwrap(),
+        vec!["<variable>", "0..1", "</>", "1..8"]
+    );
+    assert_eq!(
+        events(0, OversizedSourceHandling::Truncate).unwrap(),
+        vec!["0..8"]
+    );
+
+    // The limit applies after a byte order mark is removed, and the events' offsets
+    // cover the rest of the source.
+    let source_with_bom = [&b"\xEF\xBB\xBF"[..], source].concat();
+    assert_eq!(
+        to_event_strings(
+            &config,
+            &source_with_bom,
+            HighlightOptions::new()
+                .byte_order_mark_handling(ByteOrderMarkHandling::SkipAndRebaseOffsets)
+                .max_source_bytes(2, OversizedSourceHandling::Truncate),
+        )
+        .unwrap(),
+        vec!["<variable>", "0..1", "</>", "1..8"]
+    );
+}
+
+#[test]
+fn test_highlighting_with_final_patterns() {
+    let markdown_language = compile_grammar(
+        r#"{
+            "name": "final_highlight_markdown_test",
+            "extras": [{"type": "PATTERN", "value": "\\s"}],
+            "rules": {
+                "document": {
+                    "type": "REPEAT",
+                    "content": {
+                        "type": "CHOICE",
+                        "members": [
+                            {"type": "SYMBOL", "name": "paragraph"},
+                            {"type": "SYMBOL", "name": "fenced_code_block"}
+                        ]
+                    }
+                },
+                "fenced_code_block": {"type": "SYMBOL", "name": "block"},
+                "block": {
+                    "type": "SEQ",
+                    "members": [
+                        {"type": "SYMBOL", "name": "fence_delimiter"},
+                        {"type": "SYMBOL", "name": "info_string"},
+                        {"type": "SYMBOL", "name": "code"},
+                        {"type": "SYMBOL", "name": "fence_delimiter"}
+                    ]
+                },
+                "fence_delimiter": {"type": "STRING", "value": "```"},
+                "info_string": {"type": "PATTERN", "value": "[a-z]+"},
+                "code": {"type": "PATTERN", "value": "[^`]+"},
+                "paragraph": {"type": "PATTERN", "value": "[^`\\s]+"}
+            }
+        }"#,
+    )
+    .unwrap();
+    let code_language = compile_grammar(
+        r#"{
+            "name": "final_highlight_code_test",
+            "extras": [{"type": "PATTERN", "value": "\\s"}],
+            "rules": {
+                "program": {
+                    "type": "REPEAT",
+                    "content": {
+                        "type": "CHOICE",
+                        "members": [
+                            {"type": "SYMBOL", "name": "identifier"},
+                            {"type": "SYMBOL", "name": "number"},
+                            {"type": "SYMBOL", "name": "backticks"}
+                        ]
+                    }
+                },
+                "identifier": {"type": "PATTERN", "value": "[a-z]+"},
+                "number": {"type": "PATTERN", "value": "\\d+"},
+                "backticks": {"type": "PATTERN", "value": "`+"}
+            }
+        }"#,
+    )
+    .unwrap();
+    let code_config = HIGHLIGHTER
+        .load_configuration(
+            code_language,
+            "(identifier) @variable\n(number) @constant\n(backticks) @operator\n",
+            "",
+            "",
+        )
+        .unwrap();
+
+    // The whole fenced block, including its delimiters, is injected, so both layers
+    // highlight the delimiters and the info string.
+    let injections_query = "((fenced_code_block (block) @injection.content) @injection.site\n\
+                            (set! injection.language \"code\")\n\
+                            (set! injection.include-children))\n";
+    let load_markdown_config = |highlights_query| {
+        HIGHLIGHTER
+            .load_configuration(markdown_language, highlights_query, injections_query, "")
+            .unwrap()
+    };
+    let highlight = |config| {
+        let source = "a\n```js\nx 1\n```\n";
+        let mut context = HighlightContext::new();
+        let events = HIGHLIGHTER
+            .highlight(&mut context, config, source.as_bytes(), None, |name| {
+                if name == "code" {
+                    Some(&code_config)
+                } else {
+                    None
+                }
+            })
+            .unwrap();
+        let mut result = Vec::new();
+        for event in events {
+            match event.unwrap() {
+                HighlightEvent::HighlightStart(highlight) => {
+                    result.push(format!("<{}>", HIGHLIGHTER.names()[highlight.0]))
+                }
+                HighlightEvent::HighlightEnd => result.push("</>".to_string()),
+                HighlightEvent::Source { start, end } => result.push(source[start..end].into()),
+                _ => unreachable!(),
+            }
+        }
+        result
+    };
+
+    // Usually, the highlights of the deeper layer take precedence.
+    let config =
+        load_markdown_config("(fence_delimiter) @punctuation.delimiter\n(info_string) @string\n");
+    assert_eq!(
+        highlight(&config),
+        &[
+            "a\n",
+            "<operator>",
+            "```",
+            "</>",
+            "<variable>",
+            "js",
+            "</>",
+            "\n",
+            "<variable>",
+            "x",
+            "</>",
+            " ",
+            "<constant>",
+            "1",
+            "</>",
+            "\n",
+            "<operator>",
+            "```",
+            "</>",
+            "\n",
+        ]
+    );
+
+    // The highlights of final patterns take precedence over those of deeper layers.
+    let config = load_markdown_config(
+        "((fence_delimiter) @punctuation.delimiter (set! highlight.final))\n\
+         ((info_string) @string (set! highlight.final))\n",
+    );
+    assert_eq!(
+        highlight(&config),
+        &[
+            "a\n",
+            "<punctuation.delimiter>",
+            "```",
+            "</>",
+            "<string>",
+            "js",
+            "</>",
+            "\n",
+            "<variable>",
+            "x",
+            "</>",
+            " ",
+            "<constant>",
+            "1",
+            "</>",
+            "\n",
+            "<punctuation.delimiter>",
+            "```",
+            "</>",
+            "\n",
+        ]
+    );
+
+    // A final highlight suppresses all of the deeper layers' highlights within it.
+    let config = load_markdown_config("((fenced_code_block) @string (set! highlight.final))\n");
+    assert_eq!(
+        highlight(&config),
+        &["a\n", "<string>", "```js\nx 1\n```", "</>", "\n"]
+    );
+}
+
+#[test]
+fn test_debugging_query_layout() {
+    let config = HIGHLIGHTER
+        .load_configuration(
+            get_highlight_dir_test_language(),
+            "; Literals\n\
+             (number) @constant (string) @string\n\
+             \n\
+             (identifier) @variable.other\n\
+             ((identifier) @spell (eq? @spell \"todo\"))\n",
+            "((program (string) @injection.content) @injection.site\n \
+             (set! injection.language \"@lang\"))\n",
+            "(program) @local.scope\n\
+             (identifier) @local.reference\n",
+        )
+        .unwrap();
+    assert_eq!(
+        config.debug_query_layout(HIGHLIGHTER.names()),
+        concat!(
+            ";; injections section: bytes 0..91, patterns 0..1\n",
+            ";; pattern 0 (injections): @injection.content @injection.site\n",
+            "((program (string) @injection.content) @injection.site\n",
+            " (set! injection.language \"@lang\"))\n",
+            ";; locals section: bytes 91..144, patterns 1..3\n",
+            ";; pattern 1 (locals): @local.scope\n",
+            "(program) @local.scope\n",
+            ";; pattern 2 (locals): @local.reference\n",
+            "(identifier) @local.reference\n",
+            ";; highlights section: bytes 144..263, patterns 3..7\n",
+            "; Literals\n",
+            ";; pattern 3 (highlights): @constant\n",
+            ";; pattern 4 (highlights): @string\n",
+            "(number) @constant (string) @string\n",
+            "\n",
+            ";; pattern 5 (highlights): @variable.other\n",
+            "(identifier) @variable.other\n",
+            ";; pattern 6 (highlights): @spell\n",
+            "((identifier) @spell (eq? @spell \"todo\"))\n",
+            ";; captures\n",
+            ";; 0 @injection.content\n",
+            ";; 1 @injection.site\n",
+            ";; 2 @local.scope\n",
+            ";; 3 @local.reference\n",
+            ";; 4 @constant -> constant\n",
+            ";; 5 @string -> string\n",
+            ";; 6 @variable.other -> variable\n",
+            ";; 7 @spell\n",
+        )
+    );
+}
+
+#[test]
+fn test_classifying_patterns_with_empty_query_sections() {
+    let injections_query = "((program (string) @injection.content) @injection.site\n \
+                            (set! injection.language \"text\"))\n";
+    let locals_query = "(program) @local.scope\n\
+                        (identifier) @local.reference\n";
+    let highlights_query = "(number) @constant\n\
+                            (string) @string\n\
+                            (identifier) @variable\n";
+
+    for i in 0..8 {
+        let injections_query = if i & 1 != 0 { injections_query } else { "" };
+        let locals_query = if i & 2 != 0 { locals_query } else { "" };
+        let highlights_query = if i & 4 != 0 { highlights_query } else { "" };
+        let config = HIGHLIGHTER
+            .load_configuration(
+                get_highlight_dir_test_language(),
+                highlights_query,
+                injections_query,
+                locals_query,
+            )
+            .unwrap();
+
+        // Each section's patterns start where the previous section's patterns end, even
+        // when some of the sections are empty.
+        let injection_count = injections_query.lines().count() / 2;
+        let locals_count = locals_query.lines().count();
+        let highlights_count = highlights_query.lines().count();
+        let sections = config
+            .debug_query_layout(HIGHLIGHTER.names())
+            .lines()
+            .filter(|line| line.contains(" section: "))
+            .map(|line| line[line.find("patterns ").unwrap()..].to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sections,
+            &[
+                format!("patterns 0..{}", injection_count),
+                format!(
+                    "patterns {}..{}",
+                    injection_count,
+                    injection_count + locals_count
+                ),
+                format!(
+                    "patterns {}..{}",
+                    injection_count + locals_count,
+                    injection_count + locals_count + highlights_count
+                ),
+            ],
+            "injections: {:?}, locals: {:?}, highlights: {:?}",
+            !injections_query.is_empty(),
+            !locals_query.is_empty(),
+            !highlights_query.is_empty(),
+        );
+
+        // The highlights are only produced by the highlights patterns. A configuration
+        // without any patterns doesn't parse the document at all.
+        let source = "a 1";
+        let mut context = HighlightContext::new();
+        let events = HIGHLIGHTER
+            .highlight(&mut context, &config, source.as_bytes(), None, |_| None)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        check_event_invariants(&events);
+        if highlights_query.is_empty() {
+            assert!(matches!(
+                events[..],
+                [HighlightEvent::Source { start: 0, end: 3 }]
+            ));
+        } else {
+            assert_eq!(
+                to_token_vector(source, &config).unwrap(),
+                &[&[
+                    ("a", vec!["variable"]),
+                    (" ", vec![]),
+                    ("1", vec!["constant"])
+                ]],
+            );
+        }
+        assert_eq!(
+            context.parse_stats().parse_count,
+            if i == 0 { 0 } else { 1 }
+        );
+    }
+}
+
+#[test]
+fn test_routing_captures_with_empty_query_sections() {
+    let injected_config = HIGHLIGHTER
+        .load_configuration(
+            get_highlight_dir_test_language(),
+            "(identifier) @variable\n",
+            "",
+            "",
+        )
+        .unwrap();
+    let injections_query = "((style_element (raw_text) @injection.content) @injection.site\n\
+                            (set! injection.language \"text\"))\n";
+    let locals_query = "(attribute_name) @local.definition\n\
+                        (attribute_value) @local.reference\n";
+    let highlights_query = "(attribute_name) @attribute\n\
+                            (attribute_value) @string\n\
+                            \"<style>\" @tag\n";
+
+    // The second attribute's value refers to the first attribute's name, so it is only
+    // highlighted as an attribute if the locals patterns are processed. The style element's
+    // content is only highlighted if the injections patterns are processed.
+    let source = "<script a=\"b\" b=\"a\">x</script><style>xyz</style>";
+    for (injections_query, locals_query) in [
+        ("", ""),
+        (injections_query, ""),
+        ("", locals_query),
+        (injections_query, locals_query),
+    ]
+    .iter()
+    {
+        let config = HIGHLIGHTER
+            .load_configuration(
+                get_html_like_injection_test_language(),
+                highlights_query,
+                injections_query,
+                locals_query,
+            )
+            .unwrap();
+
+        let mut context = HighlightContext::new();
+        let events = HIGHLIGHTER
+            .highlight(&mut context, &config, source.as_bytes(), None, |_| {
+                Some(&injected_config)
+            })
+            .unwrap();
+        let mut highlights = Vec::new();
+        let mut highlighted_tokens = Vec::new();
+        for event in events {
+            match event.unwrap() {
+                HighlightEvent::HighlightStart(highlight)
+                | HighlightEvent::HighlightStartWithPattern { highlight, .. } => {
+                    highlights.push(HIGHLIGHTER.names()[highlight.0].as_str())
+                }
+                HighlightEvent::HighlightEnd => {
+                    highlights.pop();
+                }
+                HighlightEvent::Source { start, end } => {
+                    if let Some(highlight) = highlights.last() {
+                        highlighted_tokens.push((&source[start..end], *highlight));
+                    }
+                }
+                HighlightEvent::LayerStart { .. } | HighlightEvent::LayerEnd => {}
+            }
+        }
+
+        let mut expected = vec![
+            ("a", "attribute"),
+            ("b", "string"),
+            ("b", "attribute"),
+            (
+                "a",
+                if locals_query.is_empty() {
+                    "string"
+                } else {
+                    "attribute"
+                },
+            ),
+            ("<style>", "tag"),
+        ];
+        if !injections_query.is_empty() {
+            expected.push(("xyz", "variable"));
+        }
+        assert_eq!(
+            highlighted_tokens,
+            expected,
+            "injections: {:?}, locals: {:?}",
+            !injections_query.is_empty(),
+            !locals_query.is_empty()
+        );
+    }
+}
+
+#[test]
+fn test_highlighting_with_the_test_loader() {
+    let language = compile_grammar(
+        r#"{
+            "name": "test_loader_test",
+            "extras": [{"type": "PATTERN", "value": "\\s"}],
+            "rules": {
+                "program": {"type": "REPEAT", "content": {"type": "SYMBOL", "name": "call"}},
+                "call": {
+                    "type": "SEQ",
+                    "members": [
+                        {"type": "SYMBOL", "name": "identifier"},
+                        {"type": "STRING", "value": "("},
+                        {"type": "SYMBOL", "name": "number"},
+                        {"type": "STRING", "value": ")"}
+                    ]
+                },
+                "identifier": {"type": "PATTERN", "value": "[a-z]+"},
+                "number": {"type": "PATTERN", "value": "\\d+"}
+            }
+        }"#,
+    )
+    .unwrap();
+    let config = test_loader::highlight_config(
+        &HIGHLIGHTER,
+        language,
+        "(call (identifier) @function)\n(number) @constant\n",
+        "",
+        "",
+    )
+    .unwrap();
+    assert_eq!(
+        test_loader::highlight_source(&HIGHLIGHTER, &config, "f(1)", |_| None).unwrap(),
+        &[
+            ("f", vec!["function"]),
+            ("(", vec![]),
+            ("1", vec!["constant"]),
+            (")", vec![]),
+        ]
+    );
+
+    // Query errors are reported with the section that contains them.
+    let error = match test_loader::highlight_config(&HIGHLIGHTER, language, "(cal) @x\n", "", "") {
+        Ok(_) => panic!("Expected a query error"),
+        Err(error) => error,
+    };
+    assert!(
+        error.message().contains("highlights"),
+        "{}",
+        error.message()
+    );
+}
+
+fn get_html_like_injection_test_language() -> Language {
+    compile_grammar(
+        r#"{
+            "name": "html_like_injection_test",
+            "rules": {
+                "document": {
+                    "type": "REPEAT",
+                    "content": {
+                        "type": "CHOICE",
+                        "members": [
+                            {"type": "SYMBOL", "name": "script_element"},
+                            {"type": "SYMBOL", "name": "style_element"}
+                        ]
+                    }
+                },
+                "script_element": {
+                    "type": "SEQ",
+                    "members": [
+                        {"type": "STRING", "value": "<script"},
+                        {"type": "REPEAT", "content": {"type": "SYMBOL", "name": "attribute"}},
+                        {"type": "STRING", "value": ">"},
+                        {"type": "SYMBOL", "name": "raw_text"},
+                        {"type": "STRING", "value": "</script>"}
+                    ]
+                },
+                "style_element": {
+                    "type": "SEQ",
+                    "members": [
+                        {"type": "STRING", "value": "<style>"},
+                        {"type": "SYMBOL", "name": "raw_text"},
+                        {"type": "STRING", "value": "</style>"}
+                    ]
+                },
+                "attribute": {
+                    "type": "SEQ",
+                    "members": [
+                        {"type": "SYMBOL", "name": "attribute_name"},
+                        {"type": "STRING", "value": "=\""},
+                        {"type": "SYMBOL", "name": "attribute_value"},
+                        {"type": "STRING", "value": "\""}
+                    ]
+                },
+                "attribute_name": {"type": "PATTERN", "value": "[a-z]+"},
+                "attribute_value": {"type": "PATTERN", "value": "[^\"]+"},
+                "raw_text": {"type": "PATTERN", "value": "[^<]+"}
+            }
+        }"#,
+    )
+    .unwrap()
+}
+
+// A language whose strings can contain strings with the other kind of quotes.
+fn get_self_injection_test_language() -> Language {
+    compile_grammar(
+        r#"{
+            "name": "self_injection_test",
+            "extras": [{"type": "PATTERN", "value": "\\s"}],
+            "rules": {
+                "program": {
+                    "type": "REPEAT",
+                    "content": {
+                        "type": "CHOICE",
+                        "members": [
+                            {"type": "SYMBOL", "name": "identifier"},
+                            {"type": "SYMBOL", "name": "number"},
+                            {"type": "SYMBOL", "name": "string"}
+                        ]
+                    }
+                },
+                "string": {
+                    "type": "CHOICE",
+                    "members": [
+                        {
+                            "type": "SEQ",
+                            "members": [
+                                {"type": "STRING", "value": "\""},
+                                {"type": "ALIAS", "content": {"type": "PATTERN", "value": "[^\"]+"}, "named": true, "value": "string_content"},
+                                {"type": "STRING", "value": "\""}
+                            ]
+                        },
+                        {
+                            "type": "SEQ",
+                            "members": [
+                                {"type": "STRING", "value": "'"},
+                                {"type": "ALIAS", "content": {"type": "PATTERN", "value": "[^']+"}, "named": true, "value": "string_content"},
+                                {"type": "STRING", "value": "'"}
+                            ]
+                        }
+                    ]
+                },
+                "identifier": {"type": "PATTERN", "value": "[a-z]+"},
+                "number": {"type": "PATTERN", "value": "\\d+"}
+            }
+        }"#,
+    )
+    .unwrap()
+}
+
+fn get_highlight_dir_test_language() -> Language {
+    compile_grammar(
+        r#"{
+            "name": "highlight_dir_test",
+            "rules": {
+                "program": {
+                    "type": "REPEAT",
+                    "content": {
+                        "type": "CHOICE",
+                        "members": [
+                            {"type": "SYMBOL", "name": "identifier"},
+                            {"type": "SYMBOL", "name": "number"},
+                            {"type": "SYMBOL", "name": "string"}
+                        ]
+                    }
+                },
+                "identifier": {"type": "PATTERN", "value": "[a-z]+"},
+                "number": {"type": "PATTERN", "value": "\\d+"},
+                "string": {"type": "PATTERN", "value": "\"[^\"]*\""}
+            }
+        }"#,
+    )
+    .unwrap()
+}
+
+fn get_local_definition_test_language() -> Language {
+    compile_grammar(
+        r#"{
+            "name": "local_definition_test",
+            "extras": [{"type": "PATTERN", "value": "\\s"}],
+            "rules": {
+                "program": {
+                    "type": "REPEAT",
+                    "content": {
+                        "type": "CHOICE",
+                        "members": [
+                            {"type": "SYMBOL", "name": "definition"},
+                            {"type": "SYMBOL", "name": "name"}
+                        ]
+                    }
+                },
+                "definition": {
+                    "type": "SEQ",
+                    "members": [
+                        {"type": "STRING", "value": "let"},
+                        {"type": "SYMBOL", "name": "name"}
+                    ]
+                },
+                "name": {"type": "PATTERN", "value": "'[^']*'"}
+            }
+        }"#,
+    )
+    .unwrap()
+}
+
+fn to_event_strings<'a>(
+    config: &'a HighlightConfiguration,
+    source: &'a [u8],
+    options: HighlightOptions<'a>,
+) -> Result<Vec<String>, Error> {
+    let mut context = HighlightContext::new();
+    let events = HIGHLIGHTER
+        .highlight_with_options(&mut context, config, source, options, |_| None)?
+        .map(|event| Ok(event_string(event?)))
+        .collect();
+    events
+}
+
+fn event_string(event: HighlightEvent) -> String {
+    match event {
+        HighlightEvent::HighlightStart(highlight)
+        | HighlightEvent::HighlightStartWithPattern { highlight, .. } => {
+            format!("<{}>", HIGHLIGHTER.names()[highlight.0])
+        }
+        HighlightEvent::HighlightEnd => "</>".to_string(),
+        HighlightEvent::Source { start, end } => format!("{}..{}", start, end),
+        HighlightEvent::LayerStart { language, depth } => {
+            format!("[{}:{}]", language, depth)
+        }
+        HighlightEvent::LayerEnd => "[/]".to_string(),
+    }
+}
+
+// Check that the source events are neither empty nor adjacent, that each highlight start
+// has a matching end, and that each layer start has a matching end.
+fn check_event_invariants(events: &[HighlightEvent]) {
+    let mut highlight_depth = 0;
+    let mut layer_depth = 0;
+    let mut previous_source_end = None;
+    for (i, event) in events.iter().enumerate() {
+        match event {
+            HighlightEvent::Source { start, end } => {
+                assert!(start < end, "empty source event {} in {:?}", i, events);
+                assert!(
+                    !matches!(events.get(i + 1), Some(HighlightEvent::Source { .. })),
+                    "adjacent source events {} in {:?}",
+                    i,
+                    events
+                );
+                if let Some(previous_end) = previous_source_end {
+                    assert_eq!(*start, previous_end, "source event {} in {:?}", i, events);
+                }
+                previous_source_end = Some(*end);
+            }
+            HighlightEvent::HighlightStart(_)
+            | HighlightEvent::HighlightStartWithPattern { .. } => highlight_depth += 1,
+            HighlightEvent::HighlightEnd => {
+                assert!(highlight_depth > 0, "unmatched end {} in {:?}", i, events);
+                highlight_depth -= 1;
+            }
+            HighlightEvent::LayerStart { .. } => layer_depth += 1,
+            HighlightEvent::LayerEnd => {
+                assert!(layer_depth > 0, "unmatched layer end {} in {:?}", i, events);
+                layer_depth -= 1;
+            }
+        }
+    }
+    assert_eq!(highlight_depth, 0, "unmatched start in {:?}", events);
+    assert_eq!(layer_depth, 0, "unmatched layer start in {:?}", events);
+}
+
+fn to_spans<'a>(
+    context: &'a mut HighlightContext,
+    config: &'a HighlightConfiguration,
+    source: &'a str,
+    options: HighlightOptions<'a>,
+) -> Vec<(&'a str, &'a str)> {
+    HIGHLIGHTER
+        .highlight_spans(context, config, source.as_bytes(), options, |_| None)
+        .unwrap()
+        .into_iter()
+        .map(|(range, highlight)| (&source[range], HIGHLIGHTER.names()[highlight.0].as_str()))
+        .collect()
+}
+
+fn to_event_strings_with_injections<'a>(
+    context: &'a mut HighlightContext,
+    config: &'a HighlightConfiguration,
+    source: &'a [u8],
+    options: HighlightOptions<'a>,
+    injected_config: &'a HighlightConfiguration,
+) -> Vec<String> {
+    HIGHLIGHTER
+        .highlight_with_options(context, config, source, options, |_| Some(injected_config))
+        .unwrap()
+        .map(|event| event_string(event.unwrap()))
+        .collect()
+}
+
+// Inserts text into a single-line source string, returning the edit and the new string.
+fn insert(source: &str, offset: usize, text: &str) -> (InputEdit, String) {
+    let edit = InputEdit {
+        start_byte: offset,
+        old_end_byte: offset,
+        new_end_byte: offset + text.len(),
+        start_position: Point::new(0, offset),
+        old_end_position: Point::new(0, offset),
+        new_end_position: Point::new(0, offset + text.len()),
+    };
+    let mut source = source.to_string();
+    source.insert_str(offset, text);
+    (edit, source)
+}
+
+fn c_string(s: &str) -> CString {
+    CString::new(s.as_bytes().to_vec()).unwrap()
+}
+
+fn test_language_for_injection_string<'a>(string: &str) -> Option<&'a HighlightConfiguration> {
+    match string {
+        "javascript" => Some(&JS_HIGHLIGHT),
+        "html" => Some(&HTML_HIGHLIGHT),
+        "rust" => Some(&RUST_HIGHLIGHT),
+        _ => None,
+    }
+}
+
+fn to_html<'a>(
+    src: &'a str,
+    language_config: &'a HighlightConfiguration,
+) -> Result<Vec<String>, Error> {
+    let src = src.as_bytes();
+    let mut renderer = HtmlRenderer::new();
+    let mut context = HighlightContext::new();
+    let events = HIGHLIGHTER.highlight(
+        &mut context,
+        language_config,
+        src,
+        None,
+        &test_language_for_injection_string,
+    )?;
+
+    renderer
+        .render(events, src, &|highlight| HTML_ATTRS[highlight.0].as_bytes())
+        .unwrap();
+    Ok(renderer.lines().map(|s| s.to_string()).collect())
+}
+
+fn to_token_vector<'a>(
+    src: &'a str,
+    language_config: &'a HighlightConfiguration,
+) -> Result<Vec<Vec<(&'a str, Vec<&'static str>)>>, Error> {
+    let src = src.as_bytes();
+    let mut context = HighlightContext::new();
+    let mut lines = Vec::new();
+    let mut highlights = Vec::new();
+    let mut line = Vec::new();
+    let events = HIGHLIGHTER.highlight(
+        &mut context,
+        language_config,
+        src,
+        None,
+        &test_language_for_injection_string,
+    )?;
+    for event in events {
+        match event? {
+            HighlightEvent::HighlightStart(s)
+            | HighlightEvent::HighlightStartWithPattern { highlight: s, .. } => {
+                highlights.push(HIGHLIGHTER.names()[s.0].as_str())
+            }
             HighlightEvent::HighlightEnd => {
                 highlights.pop();
             }
@@ -615,6 +6024,7 @@ fn to_token_vector<'a>(
                     }
                 }
             }
+            HighlightEvent::LayerStart { .. } | HighlightEvent::LayerEnd => {}
         }
     }
     if line.len() > 0 {
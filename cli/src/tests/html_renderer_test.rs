@@ -0,0 +1,755 @@
+use super::corpus_test::SEED;
+use super::helpers::random::Rand;
+use std::cell::Cell;
+use std::io;
+use std::sync::Arc;
+use tree_sitter_highlight::util::{html_escape_attribute, HtmlEscapes};
+use tree_sitter_highlight::{
+    Error, Highlight, HighlightEvent, Highlighter, HtmlRenderer, LineEndingHandling, RenderError,
+    RenderToError,
+};
+
+const TRIAL_COUNT: usize = 500;
+const HIGHLIGHT_COUNT: usize = 4;
+const MAX_DEPTH: usize = 4;
+const MAX_UNIT_COUNT: usize = 40;
+
+// The pieces from which random sources are assembled. Event boundaries only fall between
+// pieces, so a multi-byte character is never split across two `Source` events. A `\r\n`
+// sequence can be, when a `\r` piece is followed by a `\n` piece.
+const SOURCE_UNITS: &[&[u8]] = &[
+    b"a",
+    b"bc",
+    b" ",
+    b"\n",
+    b"\r\n",
+    b"\r",
+    b"<",
+    b">",
+    b"&",
+    b"'",
+    b"\"",
+    b"\xc3\xa9",
+    b"\xe2\x86\x92",
+    b"\xe2\x86",
+    b"\xff",
+];
+
+#[test]
+fn test_html_renderer_against_reference_renderer() {
+    eprintln!("\n\nRandom seed: {}\n", *SEED);
+    let attrs = (0..HIGHLIGHT_COUNT)
+        .map(|i| {
+            // One highlight has no attributes, so it is rendered as a bare `<span>`.
+            if i == 0 {
+                String::new()
+            } else {
+                format!("class=h{}", i)
+            }
+        })
+        .collect::<Vec<_>>();
+
+    for trial in 0..TRIAL_COUNT {
+        let mut rand = Rand::new(*SEED + trial);
+        let (source, boundaries) = random_source(&mut rand);
+        let events = random_events(&mut rand, &boundaries);
+
+        let mut renderer = HtmlRenderer::new();
+        renderer
+            .render(events.iter().cloned().map(Ok), &source, &|h: Highlight| {
+                attrs[h.0].as_bytes()
+            })
+            .unwrap();
+        let html = String::from_utf8(renderer.html.clone()).unwrap();
+        let expected_html = render_reference(&events, &source, &attrs);
+
+        let context = format!(
+            "seed: {}, trial: {}\nsource: {:?}\nevents: {:?}\nhtml: {:?}\nexpected html: {:?}",
+            *SEED,
+            trial,
+            String::from_utf8_lossy(&source),
+            events,
+            html,
+            expected_html
+        );
+        assert_eq!(
+            normalize_html(&html),
+            normalize_html(&expected_html),
+            "{}",
+            context
+        );
+
+        // Each line of the output ends in a newline, and the line offsets cover the entire
+        // output.
+        let lines = renderer.lines().collect::<Vec<_>>();
+        assert_eq!(lines.concat(), html, "{}", context);
+        assert!(lines.iter().all(|line| line.ends_with('\n')), "{}", context);
+        assert_eq!(lines.len(), html.matches('\n').count(), "{}", context);
+    }
+}
+
+#[test]
+fn test_html_renderer_with_events_round_tripped_through_json() {
+    let attrs = ["", "class=h1", "class=h2", "class=h3"];
+    for trial in 0..TRIAL_COUNT {
+        let mut rand = Rand::new(*SEED + trial);
+        let (source, boundaries) = random_source(&mut rand);
+        let events = random_events(&mut rand, &boundaries);
+
+        let json = serde_json::to_string(&events).unwrap();
+        let persisted_events: Vec<HighlightEvent> = serde_json::from_str(&json).unwrap();
+
+        let mut renderer = HtmlRenderer::new();
+        renderer
+            .render(events.into_iter().map(Ok), &source, &|h: Highlight| {
+                attrs[h.0].as_bytes()
+            })
+            .unwrap();
+        let mut persisted_renderer = HtmlRenderer::new();
+        persisted_renderer
+            .render(
+                persisted_events.into_iter().map(Ok),
+                &source,
+                &|h: Highlight| attrs[h.0].as_bytes(),
+            )
+            .unwrap();
+        assert_eq!(
+            persisted_renderer.html, renderer.html,
+            "seed: {}, trial: {}\njson: {}",
+            *SEED, trial, json
+        );
+    }
+}
+
+#[test]
+fn test_html_renderer_writing_to_a_writer() {
+    let attrs = ["", "class=h1", "class=h2", "class=h3"];
+    let mut streaming_renderer = HtmlRenderer::new();
+    for trial in 0..TRIAL_COUNT {
+        let mut rand = Rand::new(*SEED + trial);
+        let (source, boundaries) = random_source(&mut rand);
+        let events = random_events(&mut rand, &boundaries);
+        let attribute_callback = |h: Highlight| attrs[h.0].as_bytes();
+
+        let mut renderer = HtmlRenderer::new();
+        renderer
+            .render(events.iter().cloned().map(Ok), &source, &attribute_callback)
+            .unwrap();
+
+        // The same HTML is written, and the line callback receives the same lines.
+        let mut html = Vec::new();
+        let mut lines = Vec::new();
+        streaming_renderer
+            .render_to(
+                events.iter().cloned().map(Ok),
+                &source,
+                &attribute_callback,
+                &mut html,
+                |line| lines.push(line.to_string()),
+            )
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(html).unwrap(),
+            String::from_utf8(renderer.html.clone()).unwrap(),
+            "seed: {}, trial: {}",
+            *SEED,
+            trial
+        );
+        assert_eq!(lines, renderer.lines().collect::<Vec<_>>());
+        assert!(streaming_renderer.html.is_empty());
+    }
+}
+
+#[test]
+fn test_html_renderer_writing_to_a_failing_writer() {
+    struct FailingWriter;
+
+    impl io::Write for FailingWriter {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::Other, "disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Rendering stops at the first line that can't be written.
+    let line_count = Cell::new(0);
+    let events = vec![HighlightEvent::Source { start: 0, end: 4 }];
+    let mut renderer = HtmlRenderer::new();
+    match renderer.render_to(
+        events.into_iter().map(Ok),
+        b"a\nb\n",
+        &|_| b"",
+        FailingWriter,
+        |_| line_count.set(line_count.get() + 1),
+    ) {
+        Err(RenderToError::Io(error)) => assert_eq!(error.to_string(), "disk full"),
+        _ => panic!("Expected an IO error"),
+    }
+    assert_eq!(line_count.get(), 1);
+}
+
+#[test]
+fn test_html_renderer_with_events_for_a_truncated_source() {
+    let source = b"let x = \"hello\";\n";
+    let events = vec![
+        HighlightEvent::HighlightStart(Highlight(0)),
+        HighlightEvent::Source { start: 0, end: 3 },
+        HighlightEvent::HighlightEnd,
+        HighlightEvent::Source { start: 3, end: 8 },
+        HighlightEvent::HighlightStart(Highlight(1)),
+        HighlightEvent::Source { start: 8, end: 15 },
+        HighlightEvent::HighlightEnd,
+        HighlightEvent::Source { start: 15, end: 17 },
+    ];
+    let attrs = ["class=keyword", "class=string"];
+
+    let mut renderer = HtmlRenderer::new();
+    renderer
+        .render(events.iter().cloned().map(Ok), source, &|h: Highlight| {
+            attrs[h.0].as_bytes()
+        })
+        .unwrap();
+    assert_eq!(
+        renderer.lines().collect::<Vec<_>>(),
+        vec!["<span class=keyword>let</span> x = <span class=string>&quot;hello&quot;</span>;\n"]
+    );
+
+    // The source was truncated after the events were produced.
+    let truncated_source = &source[0..10];
+    renderer.reset();
+    assert_eq!(
+        renderer.render(
+            events.iter().cloned().map(Ok),
+            truncated_source,
+            &|h: Highlight| { attrs[h.0].as_bytes() }
+        ),
+        Err(Error::InvalidSourceRange { start: 8, end: 15 })
+    );
+
+    // A range whose start is after its end is rejected too.
+    renderer.reset();
+    assert_eq!(
+        renderer.render(
+            vec![Ok(HighlightEvent::Source { start: 5, end: 2 })].into_iter(),
+            source,
+            &|h: Highlight| attrs[h.0].as_bytes()
+        ),
+        Err(Error::InvalidSourceRange { start: 5, end: 2 })
+    );
+}
+
+#[test]
+fn test_html_renderer_with_a_fallible_attribute_callback() {
+    // A multi-line highlight, whose span is reopened on the second line.
+    let source = b"a \"b\nc\" d;\n";
+    let events = vec![
+        HighlightEvent::HighlightStart(Highlight(0)),
+        HighlightEvent::Source { start: 0, end: 1 },
+        HighlightEvent::HighlightEnd,
+        HighlightEvent::Source { start: 1, end: 2 },
+        HighlightEvent::HighlightStart(Highlight(1)),
+        HighlightEvent::Source { start: 2, end: 7 },
+        HighlightEvent::HighlightEnd,
+        HighlightEvent::Source { start: 7, end: 11 },
+    ];
+    let expected_lines = vec![
+        "<span class=variable>a</span> <span class=string>&quot;b</span>\n",
+        "<span class=string>c&quot;</span> d;\n",
+    ];
+
+    // The callback fails when the string's span is reopened, in the middle of the document.
+    let call_count = Cell::new(0);
+    let failing_callback = |h: Highlight| {
+        call_count.set(call_count.get() + 1);
+        match (h.0, call_count.get()) {
+            (0, _) => Ok(&b"class=variable"[..]),
+            (1, 2) => Ok(&b"class=string"[..]),
+            _ => Err("missing style"),
+        }
+    };
+    let mut renderer = HtmlRenderer::new();
+    assert_eq!(
+        renderer.try_render(events.iter().cloned().map(Ok), source, &failing_callback),
+        Err(RenderError::Attribute("missing style"))
+    );
+    assert_eq!(call_count.get(), 3);
+
+    // After a reset, the renderer can be reused.
+    let callback = |h: Highlight| match h.0 {
+        0 => Ok::<_, ()>(&b"class=variable"[..]),
+        _ => Ok(&b"class=string"[..]),
+    };
+    renderer.reset();
+    renderer
+        .try_render(events.iter().cloned().map(Ok), source, &callback)
+        .unwrap();
+    assert_eq!(renderer.lines().collect::<Vec<_>>(), expected_lines);
+
+    // Errors from the highlighter are distinguished from the callback's errors.
+    renderer.reset();
+    let events_with_error = events[..3]
+        .iter()
+        .cloned()
+        .map(Ok)
+        .chain(vec![Err(Error::Cancelled)]);
+    assert_eq!(
+        renderer.try_render(events_with_error, source, &callback),
+        Err(RenderError::Highlight(Error::Cancelled))
+    );
+    renderer.reset();
+    renderer
+        .try_render(events.iter().cloned().map(Ok), source, &callback)
+        .unwrap();
+    assert_eq!(renderer.lines().collect::<Vec<_>>(), expected_lines);
+}
+
+#[test]
+fn test_html_renderer_with_css_classes() {
+    let highlighter = Highlighter::new(vec![
+        "function.builtin".to_string(),
+        "string".to_string(),
+        "odd name\"<x>".to_string(),
+    ]);
+    let source = b"f(\"a\nb\") c\n";
+    let events = vec![
+        HighlightEvent::HighlightStart(Highlight(0)),
+        HighlightEvent::Source { start: 0, end: 1 },
+        HighlightEvent::HighlightEnd,
+        HighlightEvent::Source { start: 1, end: 2 },
+        HighlightEvent::HighlightStart(Highlight(1)),
+        HighlightEvent::Source { start: 2, end: 7 },
+        HighlightEvent::HighlightEnd,
+        HighlightEvent::Source { start: 7, end: 9 },
+        HighlightEvent::HighlightStart(Highlight(2)),
+        HighlightEvent::Source { start: 9, end: 10 },
+        HighlightEvent::HighlightEnd,
+        HighlightEvent::Source { start: 10, end: 11 },
+    ];
+
+    // The class names are derived from the highlight names, and escaped.
+    let mut renderer = HtmlRenderer::with_css_classes(&highlighter, "-");
+    renderer
+        .render(events.iter().cloned().map(Ok), source, &|_| &[])
+        .unwrap();
+    assert_eq!(
+        renderer.lines().collect::<Vec<_>>(),
+        &[
+            "<span class=\"function-builtin\">f</span>(<span class=\"string\">&quot;a</span>\n",
+            "<span class=\"string\">b&quot;</span>) <span class=\"odd_name&quot;&lt;x&gt;\">c</span>\n",
+        ]
+    );
+
+    // The output is the same as with a callback that returns the same attributes, and the
+    // callback can override the class of a highlight.
+    let mut renderer = HtmlRenderer::with_css_classes(&highlighter, "__");
+    renderer
+        .render(events.iter().cloned().map(Ok), source, &|h: Highlight| {
+            if h.0 == 1 {
+                &b"style=\"color: red\""[..]
+            } else {
+                &[]
+            }
+        })
+        .unwrap();
+    let attrs = [
+        "class=\"function__builtin\"",
+        "style=\"color: red\"",
+        "class=\"odd_name&quot;&lt;x&gt;\"",
+    ];
+    let mut expected_renderer = HtmlRenderer::new();
+    expected_renderer
+        .render(events.iter().cloned().map(Ok), source, &|h: Highlight| {
+            attrs[h.0].as_bytes()
+        })
+        .unwrap();
+    assert_eq!(
+        renderer.lines().collect::<Vec<_>>(),
+        expected_renderer.lines().collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_html_renderer_with_layer_events() {
+    let source = b"a(b\nc) d\n";
+    let events = vec![
+        HighlightEvent::LayerStart {
+            language: Arc::from("md"),
+            depth: 0,
+        },
+        HighlightEvent::Source { start: 0, end: 2 },
+        HighlightEvent::LayerStart {
+            language: Arc::from("c\"s"),
+            depth: 1,
+        },
+        HighlightEvent::HighlightStart(Highlight(0)),
+        HighlightEvent::Source { start: 2, end: 3 },
+        HighlightEvent::HighlightEnd,
+        HighlightEvent::Source { start: 3, end: 5 },
+        HighlightEvent::LayerEnd,
+        HighlightEvent::Source { start: 5, end: 9 },
+        HighlightEvent::LayerEnd,
+    ];
+
+    // Each layer is wrapped in a span with its escaped language name, which is reopened
+    // on each line like the spans of highlights. The layer that encloses the final newline
+    // doesn't produce an extra line.
+    let mut renderer = HtmlRenderer::new();
+    renderer
+        .render(events.into_iter().map(Ok), source, &|_| b"class=h")
+        .unwrap();
+    assert_eq!(
+        renderer.lines().collect::<Vec<_>>(),
+        &[
+            "<span data-language=\"md\">a(<span data-language=\"c&quot;s\"><span class=h>b</span></span></span>\n",
+            "<span data-language=\"md\"><span data-language=\"c&quot;s\">c</span>) d</span>\n",
+        ]
+    );
+}
+
+#[test]
+fn test_html_renderer_with_a_highlight_ending_within_a_crlf() {
+    let source = b"a\r\nb\r\n";
+    let events = vec![
+        HighlightEvent::HighlightStart(Highlight(0)),
+        HighlightEvent::Source { start: 0, end: 2 },
+        HighlightEvent::HighlightEnd,
+        HighlightEvent::Source { start: 2, end: 3 },
+        HighlightEvent::HighlightStart(Highlight(0)),
+        HighlightEvent::Source { start: 3, end: 5 },
+        HighlightEvent::HighlightEnd,
+        HighlightEvent::Source { start: 5, end: 6 },
+    ];
+
+    // The `\r` at the end of the highlight is dropped, rather than a byte of the HTML that
+    // was written after it.
+    let mut renderer = HtmlRenderer::new();
+    renderer
+        .render(events.iter().cloned().map(Ok), source, &|_| b"class=h")
+        .unwrap();
+    assert_eq!(
+        renderer.lines().collect::<Vec<_>>(),
+        &["<span class=h>a</span>\n", "<span class=h>b</span>\n"]
+    );
+    assert_eq!(renderer.line_offsets, &[0, 23]);
+
+    // Line endings can also be preserved, in which case each `\r\n` follows the closing
+    // tags of its line. A `\r` that isn't followed by a `\n` is always kept where it is.
+    let mut renderer = HtmlRenderer::new().line_ending_handling(LineEndingHandling::Preserve);
+    renderer
+        .render(events.iter().cloned().map(Ok), source, &|_| b"class=h")
+        .unwrap();
+    assert_eq!(
+        renderer.lines().collect::<Vec<_>>(),
+        &["<span class=h>a</span>\r\n", "<span class=h>b</span>\r\n"]
+    );
+    let source = b"a\rb";
+    let events = vec![
+        HighlightEvent::HighlightStart(Highlight(0)),
+        HighlightEvent::Source { start: 0, end: 2 },
+        HighlightEvent::HighlightEnd,
+        HighlightEvent::Source { start: 2, end: 3 },
+    ];
+    for handling in vec![LineEndingHandling::Normalize, LineEndingHandling::Preserve] {
+        let mut renderer = HtmlRenderer::new().line_ending_handling(handling);
+        renderer
+            .render(events.iter().cloned().map(Ok), source, &|_| b"class=h")
+            .unwrap();
+        assert_eq!(
+            renderer.lines().collect::<Vec<_>>(),
+            &["<span class=h>a\r</span>b\n"]
+        );
+    }
+}
+
+#[test]
+fn test_html_renderer_escaping_every_escapable_character() {
+    let source = b"<>&'\"/ <>&'\"/\n";
+    let events = vec![
+        HighlightEvent::Source { start: 0, end: 7 },
+        HighlightEvent::HighlightStart(Highlight(0)),
+        HighlightEvent::Source { start: 7, end: 13 },
+        HighlightEvent::HighlightEnd,
+        HighlightEvent::Source { start: 13, end: 14 },
+    ];
+
+    // By default, `/` isn't escaped, inside or outside of highlights.
+    let mut renderer = HtmlRenderer::new();
+    renderer
+        .render(events.iter().cloned().map(Ok), source, &|_| b"class=h")
+        .unwrap();
+    assert_eq!(
+        renderer.lines().collect::<Vec<_>>(),
+        &["&lt;&gt;&amp;&#39;&quot;/ <span class=h>&lt;&gt;&amp;&#39;&quot;/</span>\n"]
+    );
+
+    // Additional characters are escaped with character references.
+    let mut renderer = HtmlRenderer::new().escapes(HtmlEscapes::new().with("/"));
+    renderer
+        .render(events.iter().cloned().map(Ok), source, &|_| b"class=h")
+        .unwrap();
+    assert_eq!(
+        renderer.lines().collect::<Vec<_>>(),
+        &["&lt;&gt;&amp;&#39;&quot;&#x2F; <span class=h>&lt;&gt;&amp;&#39;&quot;&#x2F;</span>\n"]
+    );
+}
+
+#[test]
+fn test_html_renderer_with_escaped_attribute_values() {
+    let source = b"a b/c\n";
+    let events = vec![
+        HighlightEvent::HighlightStart(Highlight(0)),
+        HighlightEvent::Source { start: 0, end: 1 },
+        HighlightEvent::HighlightEnd,
+        HighlightEvent::Source { start: 1, end: 2 },
+        HighlightEvent::HighlightStart(Highlight(1)),
+        HighlightEvent::Source { start: 2, end: 5 },
+        HighlightEvent::HighlightEnd,
+        HighlightEvent::Source { start: 5, end: 6 },
+    ];
+    let scopes = ["", "x\"y'<z>&\nw/"];
+
+    // The values are quoted and escaped, including their line endings, and highlights
+    // with an empty value have no attributes.
+    let mut renderer = HtmlRenderer::new().escaped_attribute("data-scope");
+    renderer
+        .render(events.iter().cloned().map(Ok), source, &|h: Highlight| {
+            scopes[h.0].as_bytes()
+        })
+        .unwrap();
+    assert_eq!(
+        renderer.lines().collect::<Vec<_>>(),
+        &["<span>a</span> <span data-scope=\"x&quot;y&#39;&lt;z&gt;&amp;&#10;w/\">b/c</span>\n"]
+    );
+
+    // The renderer's escapes apply to the values, and to the classes of highlights.
+    let highlighter = Highlighter::new(vec!["a/b".to_string(), "c".to_string()]);
+    let mut renderer = HtmlRenderer::with_css_classes(&highlighter, ".")
+        .escapes(HtmlEscapes::new().with("/"))
+        .escaped_attribute("data-scope");
+    renderer
+        .render(events.iter().cloned().map(Ok), source, &|h: Highlight| {
+            scopes[h.0].as_bytes()
+        })
+        .unwrap();
+    assert_eq!(
+        renderer.lines().collect::<Vec<_>>(),
+        &["<span class=\"a&#x2F;b\">a</span> <span data-scope=\"x&quot;y&#39;&lt;z&gt;&amp;&#10;w&#x2F;\">b&#x2F;c</span>\n"]
+    );
+
+    assert_eq!(html_escape_attribute(b'\r'), Some(&b"&#13;"[..]));
+    assert_eq!(html_escape_attribute(b'a'), None);
+    assert!(HtmlEscapes::new().with("/é").contains(b'/'));
+    assert!(!HtmlEscapes::new().contains(b'/'));
+}
+
+#[test]
+fn test_html_renderer_removing_a_final_line_of_reopened_spans() {
+    // The attribute strings that the callback returns aren't escaped, so they can contain
+    // a `>`. The final line is removed because it has no text, not because of its bytes.
+    for (source, expected_lines) in &[
+        (&b"a\n"[..], &["<span title=\">\">a</span>\n"][..]),
+        (
+            &b"a\nb"[..],
+            &[
+                "<span title=\">\">a</span>\n",
+                "<span title=\">\">b</span>\n",
+            ][..],
+        ),
+    ] {
+        let events = vec![
+            HighlightEvent::HighlightStart(Highlight(0)),
+            HighlightEvent::Source {
+                start: 0,
+                end: source.len(),
+            },
+            HighlightEvent::HighlightEnd,
+        ];
+        let mut renderer = HtmlRenderer::new();
+        renderer
+            .render(events.into_iter().map(Ok), source, &|_| b"title=\">\"")
+            .unwrap();
+        assert_eq!(renderer.lines().collect::<Vec<_>>(), *expected_lines);
+    }
+}
+
+fn random_source(rand: &mut Rand) -> (Vec<u8>, Vec<usize>) {
+    let mut source = Vec::new();
+    let mut boundaries = vec![0];
+    for _ in 0..rand.unsigned(MAX_UNIT_COUNT) {
+        let unit = SOURCE_UNITS[rand.unsigned(SOURCE_UNITS.len() - 1)];
+        source.extend_from_slice(unit);
+        boundaries.push(source.len());
+    }
+    (source, boundaries)
+}
+
+// Generates a well-formed event stream: every `HighlightStart` has a matching
+// `HighlightEnd`, and the `Source` events cover the whole source, in order.
+fn random_events(rand: &mut Rand, boundaries: &[usize]) -> Vec<HighlightEvent> {
+    let mut events = Vec::new();
+    let mut depth = 0;
+    let mut position = 0;
+    let last = boundaries.len() - 1;
+    while position < last || depth > 0 {
+        match rand.unsigned(2) {
+            0 if depth < MAX_DEPTH => {
+                let highlight = Highlight(rand.unsigned(HIGHLIGHT_COUNT - 1));
+                events.push(HighlightEvent::HighlightStart(highlight));
+                depth += 1;
+            }
+            1 if depth > 0 => {
+                events.push(HighlightEvent::HighlightEnd);
+                depth -= 1;
+            }
+            _ if position < last => {
+                let end = position + 1 + rand.unsigned((last - position - 1).min(4));
+                events.push(HighlightEvent::Source {
+                    start: boundaries[position],
+                    end: boundaries[end],
+                });
+                position = end;
+            }
+            _ => {
+                events.push(HighlightEvent::HighlightEnd);
+                depth -= 1;
+            }
+        }
+    }
+    events
+}
+
+// A deliberately simple renderer: spans are opened and closed exactly where the events
+// say, without being split at line boundaries.
+fn render_reference(events: &[HighlightEvent], source: &[u8], attrs: &[String]) -> String {
+    let mut html = String::new();
+    for event in events {
+        match event {
+            HighlightEvent::HighlightStart(highlight) => {
+                let attr = &attrs[highlight.0];
+                if attr.is_empty() {
+                    html += "<span>";
+                } else {
+                    html += &format!("<span {}>", attr);
+                }
+            }
+            HighlightEvent::HighlightEnd => html += "</span>",
+            HighlightEvent::Source { start, end } => {
+                let mut text = String::from_utf8_lossy(&source[*start..*end]).replace("\r\n", "\n");
+                if text.ends_with('\r') && source.get(*end) == Some(&b'\n') {
+                    text.pop();
+                }
+                for c in text.chars() {
+                    match c {
+                        '<' => html += "&lt;",
+                        '>' => html += "&gt;",
+                        '&' => html += "&amp;",
+                        '\'' => html += "&#39;",
+                        '"' => html += "&quot;",
+                        _ => html.push(c),
+                    }
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+    html
+}
+
+// Two HTML documents are considered equivalent if they contain the same lines, and within
+// each line, the same text is enclosed by the same stack of span attributes. So it doesn't
+// matter whether adjacent spans with the same attributes are merged, or whether spans are
+// closed before a newline and reopened after it, and empty spans are ignored. Trailing blank
+// lines are also ignored, because `HtmlRenderer` always ends its output with a newline.
+fn normalize_html(html: &str) -> Vec<Vec<(Vec<&str>, String)>> {
+    let mut lines = vec![Vec::<(Vec<&str>, String)>::new()];
+    let mut attr_stack = Vec::new();
+    let mut rest = html;
+    while let Some(c) = rest.chars().next() {
+        let (text, len) = if rest.starts_with("<span") {
+            let tag_end = rest.find('>').unwrap();
+            attr_stack.push(rest[5..tag_end].trim_start());
+            (None, tag_end + 1)
+        } else if rest.starts_with("</span>") {
+            attr_stack.pop().expect("unbalanced closing tag");
+            (None, 7)
+        } else if c == '&' {
+            let entity_end = rest.find(';').unwrap();
+            let c = match &rest[..entity_end + 1] {
+                "&lt;" => '<',
+                "&gt;" => '>',
+                "&amp;" => '&',
+                "&#39;" => '\'',
+                "&quot;" => '"',
+                entity => panic!("unexpected entity {}", entity),
+            };
+            (Some(c), entity_end + 1)
+        } else {
+            (Some(c), c.len_utf8())
+        };
+        rest = &rest[len..];
+
+        match text {
+            Some('\n') => lines.push(Vec::new()),
+            Some(c) => {
+                let line = lines.last_mut().unwrap();
+                match line.last_mut() {
+                    Some((attrs, text)) if *attrs == attr_stack => text.push(c),
+                    _ => line.push((attr_stack.clone(), c.to_string())),
+                }
+            }
+            None => {}
+        }
+    }
+    assert!(attr_stack.is_empty(), "unclosed span");
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+    lines
+}
+
+#[test]
+fn test_html_renderer_with_a_long_line_split_into_many_source_events() {
+    // A single-line string literal, whose highlights are ended and restarted around
+    // every small piece of it, as happens when an injection's content is interrupted by
+    // many excluded child nodes.
+    let source = vec![b'x'; 4 * 1024 * 1024];
+    let mut events = Vec::new();
+    for start in (0..source.len()).step_by(8) {
+        events.push(HighlightEvent::HighlightStart(Highlight(0)));
+        events.push(HighlightEvent::HighlightStart(Highlight(1)));
+        events.push(HighlightEvent::Source {
+            start,
+            end: start + 8,
+        });
+        events.push(HighlightEvent::HighlightEnd);
+        events.push(HighlightEvent::HighlightEnd);
+    }
+    let attrs = ["class=string", "class=embedded"];
+
+    let mut renderer = HtmlRenderer::new();
+    renderer
+        .render(events.into_iter().map(Ok), &source, &|h: Highlight| {
+            attrs[h.0].as_bytes()
+        })
+        .unwrap();
+
+    // The spans are opened once and closed once, instead of around every piece.
+    let prefix = "<span class=string><span class=embedded>";
+    let suffix = "</span></span>\n";
+    assert_eq!(
+        renderer.html.len(),
+        prefix.len() + source.len() + suffix.len()
+    );
+    assert!(renderer.html.starts_with(prefix.as_bytes()));
+    assert!(renderer.html.ends_with(suffix.as_bytes()));
+    assert!(
+        renderer.html[prefix.len()..renderer.html.len() - suffix.len()]
+            .iter()
+            .all(|c| *c == b'x')
+    );
+    assert_eq!(renderer.line_offsets, vec![0]);
+    assert_eq!(renderer.lines().count(), 1);
+}
@@ -1,6 +1,9 @@
 mod corpus_test;
+mod generate_test;
 mod helpers;
+mod highlight_golden_test;
 mod highlight_test;
+mod html_renderer_test;
 mod node_test;
 mod parser_test;
 mod query_test;
@@ -1,10 +1,9 @@
-use super::helpers::fixtures::get_test_language;
-use crate::generate::generate_parser_for_grammar;
+use crate::test_loader::compile_grammar;
 use tree_sitter::Parser;
 
 #[test]
 fn test_basic_node_refs() {
-    let (parser_name, parser_code) = generate_parser_for_grammar(
+    let language = compile_grammar(
         r#"
         {
             "name": "test_grammar_with_refs",
@@ -52,7 +51,6 @@ fn test_basic_node_refs() {
     .unwrap();
 
     let mut parser = Parser::new();
-    let language = get_test_language(&parser_name, &parser_code, None);
     parser.set_language(language).unwrap();
 
     let tree = parser.parse("child-1 child-2 child-3", None).unwrap();
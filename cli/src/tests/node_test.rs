@@ -1,8 +1,9 @@
 use super::helpers::edits::get_random_edit;
 use super::helpers::fixtures::{fixtures_dir, get_language, get_test_language};
 use super::helpers::random::Rand;
-use crate::generate::generate_parser_for_grammar;
+use crate::generate::{generate_in_memory, Diagnostics, GenerateOptions};
 use crate::parse::perform_edit;
+use crate::test_loader::compile_grammar;
 use std::fs;
 use tree_sitter::{Node, Parser, Point, Tree};
 
@@ -242,13 +243,10 @@ fn test_node_named_child() {
 
 #[test]
 fn test_node_named_child_with_aliases_and_extras() {
-    let (parser_name, parser_code) =
-        generate_parser_for_grammar(GRAMMAR_WITH_ALIASES_AND_EXTRAS).unwrap();
+    let language = compile_grammar(GRAMMAR_WITH_ALIASES_AND_EXTRAS).unwrap();
 
     let mut parser = Parser::new();
-    parser
-        .set_language(get_test_language(&parser_name, &parser_code, None))
-        .unwrap();
+    parser.set_language(language).unwrap();
 
     let tree = parser.parse("b ... b ... c", None).unwrap();
     let root = tree.root_node();
@@ -439,7 +437,7 @@ fn test_node_sexp() {
 
 #[test]
 fn test_node_field_names() {
-    let (parser_name, parser_code) = generate_parser_for_grammar(
+    let language = compile_grammar(
         r#"
         {
             "name": "test_grammar_with_fields",
@@ -511,7 +509,6 @@ fn test_node_field_names() {
     .unwrap();
 
     let mut parser = Parser::new();
-    let language = get_test_language(&parser_name, &parser_code, None);
     parser.set_language(language).unwrap();
 
     let tree = parser
@@ -547,9 +544,94 @@ fn test_node_field_names() {
     assert_eq!(cursor.field_name(), Some("field_3"));
 }
 
+#[test]
+fn test_node_field_names_in_inlined_rules() {
+    let grammar_json = fs::read_to_string(
+        fixtures_dir()
+            .join("test_grammars")
+            .join("inlined_rules_with_fields")
+            .join("grammar.json"),
+    )
+    .unwrap();
+    let options = GenerateOptions {
+        next_abi: true,
+        ..Default::default()
+    };
+    let artifacts = generate_in_memory(&grammar_json, &options, &mut Diagnostics::new()).unwrap();
+    let parser_code = &artifacts.c_files[0].1;
+    let node_types: serde_json::Value = serde_json::from_str(&artifacts.node_types_json).unwrap();
+    let statement_fields = &node_types
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|node_type| node_type["type"] == "statement")
+        .unwrap()["fields"];
+
+    let mut parser = Parser::new();
+    let language = get_test_language("inlined_rules_with_fields", parser_code, None);
+    parser.set_language(language).unwrap();
+
+    // Fields within an inlined rule.
+    let tree = parser.parse("let a = 1;", None).unwrap();
+    let statement_node = tree.root_node().child(0).unwrap();
+    assert_eq!(
+        statement_node.child_by_field_name("name"),
+        statement_node.child(1)
+    );
+    assert_eq!(
+        statement_node.child_by_field_name("value"),
+        statement_node.child(3)
+    );
+
+    // Fields within a nested inlined rule, when the nested rule is assigned
+    // to a field of its own.
+    assert_eq!(
+        statement_node.child_by_field_name("literal"),
+        statement_node.child(3)
+    );
+    assert!(statement_fields["literal"].is_object());
+
+    // Fields within an inlined rule, when the inlined rule is assigned to a field.
+    let tree = parser.parse("const a = 1;", None).unwrap();
+    let statement_node = tree.root_node().child(0).unwrap();
+    assert_eq!(
+        statement_node.child_by_field_name("constant"),
+        statement_node.child(1)
+    );
+    assert_eq!(
+        statement_node.child_by_field_name("name"),
+        statement_node.child(1)
+    );
+    assert_eq!(
+        statement_node.child_by_field_name("value"),
+        statement_node.child(3)
+    );
+    assert!(statement_fields["constant"].is_object());
+
+    // Fields within an inlined rule, when the inlined rule is aliased.
+    let tree = parser.parse("var a = 1;", None).unwrap();
+    let statement_node = tree.root_node().child(0).unwrap();
+    assert_eq!(statement_node.child(1).unwrap().kind(), "binding");
+    assert_eq!(
+        statement_node.child_by_field_name("name"),
+        statement_node.child(1)
+    );
+    assert_eq!(
+        statement_node.child_by_field_name("value"),
+        statement_node.child(3)
+    );
+    for field_name in &["name", "value"] {
+        assert!(statement_fields[field_name]["types"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|t| t["type"] == "binding"));
+    }
+}
+
 #[test]
 fn test_node_field_calls_in_language_without_fields() {
-    let (parser_name, parser_code) = generate_parser_for_grammar(
+    let language = compile_grammar(
         r#"
         {
             "name": "test_grammar_with_no_fields",
@@ -581,7 +663,6 @@ fn test_node_field_calls_in_language_without_fields() {
     .unwrap();
 
     let mut parser = Parser::new();
-    let language = get_test_language(&parser_name, &parser_code, None);
     parser.set_language(language).unwrap();
 
     let tree = parser.parse("b c d", None).unwrap();
@@ -598,7 +679,7 @@ fn test_node_field_calls_in_language_without_fields() {
 
 #[test]
 fn test_node_is_named_but_aliased_as_anonymous() {
-    let (parser_name, parser_code) = generate_parser_for_grammar(
+    let language = compile_grammar(
         &fs::read_to_string(
             &fixtures_dir()
                 .join("test_grammars")
@@ -610,7 +691,6 @@ fn test_node_is_named_but_aliased_as_anonymous() {
     .unwrap();
 
     let mut parser = Parser::new();
-    let language = get_test_language(&parser_name, &parser_code, None);
     parser.set_language(language).unwrap();
 
     let tree = parser.parse("B C B", None).unwrap();
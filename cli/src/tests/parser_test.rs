@@ -1,8 +1,8 @@
 use super::helpers::allocations;
 use super::helpers::edits::ReadRecorder;
-use super::helpers::fixtures::{get_language, get_test_language};
-use crate::generate::generate_parser_for_grammar;
+use super::helpers::fixtures::get_language;
 use crate::parse::{perform_edit, Edit};
+use crate::test_loader::compile_grammar;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::{thread, time};
 use tree_sitter::{InputEdit, LogType, Parser, Point, Range};
@@ -938,7 +938,7 @@ fn test_parsing_with_a_newly_included_range() {
 
 #[test]
 fn test_parsing_with_included_ranges_and_missing_tokens() {
-    let (parser_name, parser_code) = generate_parser_for_grammar(
+    let language = compile_grammar(
         r#"{
             "name": "test_leading_missing_token",
             "rules": {
@@ -963,9 +963,7 @@ fn test_parsing_with_included_ranges_and_missing_tokens() {
     .unwrap();
 
     let mut parser = Parser::new();
-    parser
-        .set_language(get_test_language(&parser_name, &parser_code, None))
-        .unwrap();
+    parser.set_language(language).unwrap();
 
     // There's a missing `a` token at the beginning of the code. It must be inserted
     // at the beginning of the first included range, not at {0, 0}.
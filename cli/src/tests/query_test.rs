@@ -1,8 +1,10 @@
 use super::helpers::allocations;
-use super::helpers::fixtures::get_language;
+use super::helpers::fixtures::{fixtures_dir, get_language};
+use crate::test_loader::compile_grammar;
 use std::fmt::Write;
+use std::fs;
 use tree_sitter::{
-    Node, Parser, Query, QueryCapture, QueryCursor, QueryError, QueryMatch, QueryProperty,
+    Language, Node, Parser, Query, QueryCapture, QueryCursor, QueryError, QueryMatch, QueryProperty,
 };
 
 #[test]
@@ -529,6 +531,118 @@ fn test_query_matches_within_byte_range() {
     });
 }
 
+#[test]
+fn test_query_matches_within_byte_range_ending_mid_pattern() {
+    let language = get_query_range_test_language();
+    allocations::record(|| {
+        let query = Query::new(
+            language,
+            "(statement (identifier) @left (identifier) @right)",
+        )
+        .unwrap();
+
+        let source = "let a = b;";
+        let mut parser = Parser::new();
+        parser.set_language(language).unwrap();
+        let tree = parser.parse(source, None).unwrap();
+
+        // The range ends after the first identifier, so the pattern can't be completed.
+        let mut cursor = QueryCursor::new();
+        let matches =
+            cursor
+                .set_byte_range(0, 6)
+                .matches(&query, tree.root_node(), to_callback(source));
+        assert_eq!(collect_matches(matches, &query, source), &[]);
+
+        // The discarded state doesn't affect the next use of the cursor.
+        let matches = cursor.set_byte_range(0, source.len()).matches(
+            &query,
+            tree.root_node(),
+            to_callback(source),
+        );
+        assert_eq!(
+            collect_matches(matches, &query, source),
+            &[(0, vec![("left", "a"), ("right", "b")])]
+        );
+    });
+}
+
+#[test]
+fn test_query_matches_within_byte_range_with_partial_matches_at_the_end() {
+    let language = get_query_range_test_language();
+    allocations::record(|| {
+        let query = Query::new(
+            language,
+            "(statement (identifier) @left (identifier) @right)",
+        )
+        .unwrap();
+
+        let source = "let a = b;\nlet c = d;";
+        let mut parser = Parser::new();
+        parser.set_language(language).unwrap();
+        let tree = parser.parse(source, None).unwrap();
+
+        // The second statement begins within the range, but its second identifier is
+        // after the end of the range, so only the first statement matches.
+        let mut cursor = QueryCursor::new();
+        let matches =
+            cursor
+                .set_byte_range(0, 17)
+                .matches(&query, tree.root_node(), to_callback(source));
+        assert_eq!(
+            collect_matches(matches, &query, source),
+            &[(0, vec![("left", "a"), ("right", "b")])]
+        );
+
+        let captures =
+            cursor
+                .set_byte_range(0, 17)
+                .captures(&query, tree.root_node(), to_callback(source));
+        assert_eq!(
+            collect_captures(captures, &query, source),
+            &[("left", "a"), ("right", "b")]
+        );
+    });
+}
+
+#[test]
+fn test_query_captures_within_byte_range_after_partial_matches() {
+    let language = get_query_range_test_language();
+    allocations::record(|| {
+        let query = Query::new(
+            language,
+            r#"
+                (statement (identifier) @left (identifier) @right)
+                "=" @operator
+            "#,
+        )
+        .unwrap();
+
+        let source = "let a = b;\nlet c = d;";
+        let mut parser = Parser::new();
+        parser.set_language(language).unwrap();
+        let tree = parser.parse(source, None).unwrap();
+
+        // The second `=` is within the range, and its match is finished. It must still be
+        // returned, even though it comes after the first capture of the second statement's
+        // match, which can't be finished within the range.
+        let mut cursor = QueryCursor::new();
+        let captures =
+            cursor
+                .set_byte_range(0, 18)
+                .captures(&query, tree.root_node(), to_callback(source));
+        assert_eq!(
+            collect_captures(captures, &query, source),
+            &[
+                ("left", "a"),
+                ("operator", "="),
+                ("right", "b"),
+                ("operator", "="),
+            ]
+        );
+    });
+}
+
 #[test]
 fn test_query_matches_different_queries_same_cursor() {
     allocations::record(|| {
@@ -1147,6 +1261,94 @@ fn test_query_comments() {
     });
 }
 
+#[test]
+fn test_query_matching_supertypes() {
+    let grammar_dir = fixtures_dir().join("test_grammars").join("supertypes");
+    let grammar_json = fs::read_to_string(grammar_dir.join("grammar.json")).unwrap();
+    let language = compile_grammar(&grammar_json).unwrap();
+
+    allocations::record(|| {
+        let query = Query::new(
+            language,
+            r#"
+                (return_statement (_expression) @returned)
+                (binary_expression (_literal) @operand)
+                (_statement (_expression) @expression)
+            "#,
+        )
+        .unwrap();
+
+        let source = "a + 1;\nreturn \"b\";";
+        let mut parser = Parser::new();
+        parser.set_language(language).unwrap();
+        let tree = parser.parse(source, None).unwrap();
+        let mut cursor = QueryCursor::new();
+        let matches = cursor.matches(&query, tree.root_node(), to_callback(source));
+        assert_eq!(
+            collect_matches(matches, &query, source),
+            &[
+                (2, vec![("expression", "a + 1")]),
+                (1, vec![("operand", "1")]),
+                (2, vec![("expression", "\"b\"")]),
+                (0, vec![("returned", "\"b\"")]),
+            ],
+        );
+    });
+}
+
+#[test]
+fn test_query_matching_supertypes_with_inlined_alternatives() {
+    let grammar_dir = fixtures_dir()
+        .join("test_grammars")
+        .join("supertype_with_inlined_alternative");
+    let grammar_json = fs::read_to_string(grammar_dir.join("grammar.json")).unwrap();
+    let language = compile_grammar(&grammar_json).unwrap();
+
+    allocations::record(|| {
+        let query = Query::new(language, "(assignment (_pattern) @pattern)").unwrap();
+
+        let source = "a = b;\na.b = c;\n...a = b;\n1 = a;";
+        let mut parser = Parser::new();
+        parser.set_language(language).unwrap();
+        let tree = parser.parse(source, None).unwrap();
+        let mut cursor = QueryCursor::new();
+        let matches = cursor.matches(&query, tree.root_node(), to_callback(source));
+        assert_eq!(
+            collect_matches(matches, &query, source),
+            &[
+                (0, vec![("pattern", "a")]),
+                (0, vec![("pattern", "a.b")]),
+                (0, vec![("pattern", "...a")]),
+                (0, vec![("pattern", "1")]),
+            ],
+        );
+    });
+}
+
+fn get_query_range_test_language() -> Language {
+    compile_grammar(
+        r#"{
+            "name": "query_range_test",
+            "extras": [{"type": "PATTERN", "value": "\\s"}],
+            "rules": {
+                "program": {"type": "REPEAT", "content": {"type": "SYMBOL", "name": "statement"}},
+                "statement": {
+                    "type": "SEQ",
+                    "members": [
+                        {"type": "STRING", "value": "let"},
+                        {"type": "SYMBOL", "name": "identifier"},
+                        {"type": "STRING", "value": "="},
+                        {"type": "SYMBOL", "name": "identifier"},
+                        {"type": "STRING", "value": ";"}
+                    ]
+                },
+                "identifier": {"type": "PATTERN", "value": "[a-z]+"}
+            }
+        }"#,
+    )
+    .unwrap()
+}
+
 fn collect_matches<'a>(
     matches: impl Iterator<Item = QueryMatch<'a>>,
     query: &'a Query,
@@ -5,27 +5,54 @@
 extern "C" {
 #endif
 
+#include <stdbool.h>
 #include <stdint.h>
 
+// The result of the functions of this API that can fail. Passing NULL for a
+// required pointer results in `TSHighlightNullPointer`, and a failure inside
+// the library that would otherwise crash the process results in
+// `TSHighlightPanic`.
 typedef enum {
   TSHighlightOk,
   TSHighlightUnknownScope,
   TSHighlightTimeout,
   TSHighlightInvalidLanguage,
+  TSHighlightInvalidUtf8,
+  TSHighlightInvalidRegex,
+  TSHighlightInvalidQuery,
+  TSHighlightOutputTooLarge,
+  TSHighlightIncompatibleLanguageVersion,
+  TSHighlightInvalidLanguageDirectory,
+  TSHighlightUnknown,
+  TSHighlightSourceTooLarge,
+  TSHighlightNullPointer,
+  TSHighlightPanic,
+  TSHighlightDuplicateAlias,
 } TSHighlightError;
 
 typedef struct TSHighlighter TSHighlighter;
 typedef struct TSHighlightBuffer TSHighlightBuffer;
 
+// A range of columns within a line that has a highlight. The columns are
+// zero-based, measured in bytes, and don't include the line ending. The
+// `highlight_index` is the index of the highlight's name in the list that was
+// passed to `ts_highlighter_new`.
+typedef struct {
+  uint32_t start_column;
+  uint32_t end_column;
+  uint32_t highlight_index;
+} TSHighlightEvent;
+
 // Construct a `TSHighlighter` by providing a list of strings containing
 // the HTML attributes that should be applied for each highlight value.
+// Returns NULL if the highlighter could not be created.
 TSHighlighter *ts_highlighter_new(
   const char **highlight_names,
   const char **attribute_strings,
   uint32_t highlight_count
 );
 
-// Delete a syntax highlighter.
+// Delete a syntax highlighter. Passing NULL does nothing.
 void ts_highlighter_delete(TSHighlighter *);
 
 // Add a `TSLanguage` to a highlighter. The language is associated with a
@@ -48,8 +75,59 @@ int ts_highlighter_add_language(
   uint32_t locals_query_len
 );
 
+// Add a `TSLanguage` to a highlighter, like `ts_highlighter_add_language`,
+// along with a list of aliases, which are matched case-insensitively against
+// the names of embedded languages before any injection regexes are tried.
+// Returns `TSHighlightDuplicateAlias` if another language already has one of
+// the aliases.
+int ts_highlighter_add_language_with_aliases(
+  TSHighlighter *self,
+  const char *scope_name,
+  const char *injection_regex,
+  const TSLanguage *language,
+  const char *highlight_query,
+  const char *injection_query,
+  const char *locals_query,
+  uint32_t highlight_query_len,
+  uint32_t injection_query_len,
+  uint32_t locals_query_len,
+  const char **aliases,
+  uint32_t alias_count
+);
+
+// Add a `TSLanguage` to a highlighter, like `ts_highlighter_add_language`,
+// but load its queries from the query files of the language directory at
+// the given path, as listed in its `src/metadata.json` file or in the
+// default `queries/highlights.scm`, `queries/injections.scm` and
+// `queries/locals.scm` files. Returns `TSHighlightInvalidQuery` if one of
+// the files contains an invalid pattern, and
+// `TSHighlightInvalidLanguageDirectory` if the files could not be read.
+int ts_highlighter_add_language_from_directory(
+  TSHighlighter *self,
+  const char *scope_name,
+  const char *injection_regex,
+  const TSLanguage *language,
+  const char *path
+);
+
+// Get a description of the error that caused the most recent call to
+// `ts_highlighter_add_language`, `ts_highlighter_add_language_with_aliases` or
+// `ts_highlighter_add_language_from_directory` to fail because one of its queries was invalid, including the query and the
+// line on which the error occurred, because one of its query files could not be
+// read, or because the language's ABI version is not supported. Returns NULL if
+// that call did not fail for one of these reasons.
+const char *ts_highlighter_last_error_message(const TSHighlighter *);
+
 // Compute syntax highlighting for a given document. You must first
-// create a `TSHighlightBuffer` to hold the output.
+// create a `TSHighlightBuffer` to hold the output. Returns
+// `TSHighlightUnknownScope` if no language was added with the given scope
+// name, and `TSHighlightTimeout` if highlighting was cancelled.
+//
+// If a `cancellation_flag` is given, highlighting is cancelled when another
+// thread sets it to a nonzero value. The flag is checked while parsing, and
+// periodically while the highlights are computed. A cancelled call leaves the
+// buffer empty, and the buffer can be reused for the next call. Pass NULL to
+// highlight without cancellation.
 int ts_highlighter_highlight(
   const TSHighlighter *self,
   const char *scope_name,
@@ -59,11 +137,31 @@ int ts_highlighter_highlight(
   const size_t *cancellation_flag
 );
 
-// TSHighlightBuffer: This struct stores the HTML output of syntax
-// highlighting. It can be reused for multiple highlighting calls.
+// Compute syntax highlighting for a given document, like
+// `ts_highlighter_highlight`, but store the innermost highlight of each range
+// of the document as a list of `TSHighlightEvent`s for each line, instead of
+// rendering HTML. Highlights that span several lines are split into an event
+// for each line.
+int ts_highlighter_highlight_events(
+  const TSHighlighter *self,
+  const char *scope_name,
+  const char *source_code,
+  uint32_t source_code_len,
+  TSHighlightBuffer *output,
+  const size_t *cancellation_flag
+);
+
+// TSHighlightBuffer: This struct stores the output of syntax highlighting,
+// either as HTML or as highlight events. It can be reused for multiple
+// highlighting calls, each of which replaces the previous output while
+// keeping its allocated memory.
+//
+// The pointers returned by the accessors below are owned by the buffer. They
+// remain valid until the buffer is passed to another highlighting call or is
+// deleted, and must not be freed by the caller.
 TSHighlightBuffer *ts_highlight_buffer_new();
 
-// Delete a highlight buffer.
+// Delete a highlight buffer. Passing NULL does nothing.
 void ts_highlight_buffer_delete(TSHighlightBuffer *);
 
 // Access the HTML content of a highlight buffer.
@@ -72,6 +170,29 @@ const uint32_t *ts_highlight_buffer_line_offsets(const TSHighlightBuffer *);
 uint32_t ts_highlight_buffer_len(const TSHighlightBuffer *);
 uint32_t ts_highlight_buffer_line_count(const TSHighlightBuffer *);
 
+// Access the highlight events of a buffer that was passed to
+// `ts_highlighter_highlight_events`. The events of all of the lines are
+// stored in order in a single array. For each line of the document, the
+// line offsets contain the index of the line's first event, so the events of
+// line `i` end where those of line `i + 1` begin, or at the event count for
+// the last line. There is an offset for every line, including lines without
+// any highlights.
+const TSHighlightEvent *ts_highlight_buffer_events(const TSHighlightBuffer *);
+uint32_t ts_highlight_buffer_event_count(const TSHighlightBuffer *);
+const uint32_t *ts_highlight_buffer_event_line_offsets(const TSHighlightBuffer *);
+uint32_t ts_highlight_buffer_event_line_count(const TSHighlightBuffer *);
+
+// Access the amount of parsing done by the most recent highlighting call,
+// across the document and all of its injections.
+uint32_t ts_highlight_buffer_layer_count(const TSHighlightBuffer *);
+uint32_t ts_highlight_buffer_parse_count(const TSHighlightBuffer *);
+uint64_t ts_highlight_buffer_parsed_byte_count(const TSHighlightBuffer *);
+
+// Check whether the document parsed by the most recent highlighting call contained
+// syntax errors, and count the `ERROR` and `MISSING` nodes across all of its layers.
+bool ts_highlight_buffer_root_has_error(const TSHighlightBuffer *);
+uint32_t ts_highlight_buffer_error_count(const TSHighlightBuffer *);
+
 #ifdef __cplusplus
 }
 #endif
@@ -0,0 +1,116 @@
+use super::{
+    Error, HighlightConfiguration, HighlightContext, HighlightEvent, HighlightOptions, Highlighter,
+};
+use std::iter;
+use std::sync::atomic::Ordering;
+
+/// An iterator over the results of `Highlighter::highlight_batch`, which yields the index
+/// of each document along with its events.
+pub struct HighlightBatch<'a, I, F> {
+    highlighter: &'a Highlighter,
+    context: &'a mut HighlightContext,
+    jobs: iter::Enumerate<I>,
+    options: HighlightOptions<'a>,
+    injection_callback: F,
+    event_vecs: Vec<Vec<HighlightEvent>>,
+    cancelled: bool,
+}
+
+impl Highlighter {
+    /// Highlight a sequence of documents, each with its own configuration, collecting the
+    /// events for each document into a vector.
+    ///
+    /// This avoids much of the overhead of calling `highlight_with_options` once per
+    /// document, which dominates when highlighting many small documents: the context's
+    /// parser and query cursors are reused for every document, and the vectors of events
+    /// can be returned with `HighlightBatch::recycle` so that their allocations are reused.
+    ///
+    /// The events for each document are the same as those that `highlight_with_options`
+    /// would produce with the same options. A timeout applies to each document separately.
+    /// The cancellation flag is checked before each document, as well as while it is
+    /// highlighted. Once highlighting is cancelled by the flag, a `Cancelled` error is
+    /// returned for the current document and the iterator ends.
+    pub fn highlight_batch<'a, 'b, I, F>(
+        &'a self,
+        context: &'a mut HighlightContext,
+        jobs: I,
+        options: HighlightOptions<'a>,
+        injection_callback: F,
+    ) -> HighlightBatch<'a, I::IntoIter, F>
+    where
+        I: IntoIterator<Item = (&'b HighlightConfiguration, &'b [u8])>,
+        F: Fn(&str) -> Option<&'b HighlightConfiguration>,
+    {
+        HighlightBatch {
+            highlighter: self,
+            context,
+            jobs: jobs.into_iter().enumerate(),
+            options,
+            injection_callback,
+            event_vecs: Vec::new(),
+            cancelled: false,
+        }
+    }
+}
+
+impl<'a, I, F> HighlightBatch<'a, I, F> {
+    /// Return a vector of events that is no longer needed, so that its allocation is used
+    /// for the events of a later document.
+    pub fn recycle(&mut self, mut events: Vec<HighlightEvent>) {
+        events.clear();
+        self.event_vecs.push(events);
+    }
+
+    fn is_cancelled(&self) -> bool {
+        matches!(
+            self.options.cancellation_flag,
+            Some(flag) if flag.load(Ordering::Relaxed) != 0
+        )
+    }
+}
+
+impl<'a, 'b, I, F> Iterator for HighlightBatch<'a, I, F>
+where
+    I: Iterator<Item = (&'b HighlightConfiguration, &'b [u8])>,
+    F: Fn(&str) -> Option<&'b HighlightConfiguration>,
+{
+    type Item = (usize, Result<Vec<HighlightEvent>, Error>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cancelled {
+            return None;
+        }
+        let (index, (config, source)) = self.jobs.next()?;
+        if self.is_cancelled() {
+            self.cancelled = true;
+            return Some((index, Err(Error::Cancelled)));
+        }
+
+        let mut events = self.event_vecs.pop().unwrap_or_default();
+        let injection_callback = &self.injection_callback;
+        let result = self
+            .highlighter
+            .highlight_with_options(self.context, config, source, self.options.clone(), |name| {
+                injection_callback(name)
+            })
+            .and_then(|highlights| {
+                for event in highlights {
+                    events.push(event?);
+                }
+                Ok(())
+            });
+
+        match result {
+            Ok(()) => Some((index, Ok(events))),
+            Err(error) => {
+                // A document that exceeds the timeout doesn't prevent the others from
+                // being highlighted, but the cancellation flag applies to the whole batch.
+                if error == Error::Cancelled && self.is_cancelled() {
+                    self.cancelled = true;
+                }
+                self.recycle(events);
+                Some((index, Err(error)))
+            }
+        }
+    }
+}
@@ -1,22 +1,45 @@
-use super::{Error, HighlightConfiguration, HighlightContext, Highlighter, HtmlRenderer};
+use super::{
+    Error, Highlight, HighlightConfiguration, HighlightContext, HighlightEvent, Highlighter,
+    HtmlRenderer,
+};
 use regex::Regex;
 use std::collections::HashMap;
-use std::ffi::CStr;
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
 use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
 use std::process::abort;
 use std::sync::atomic::AtomicUsize;
-use std::{fmt, slice, str};
+use std::{ptr, slice, str};
 use tree_sitter::Language;
 
 pub struct TSHighlighter {
     languages: HashMap<String, (Option<Regex>, HighlightConfiguration)>,
     attribute_strings: Vec<&'static [u8]>,
     highlighter: Highlighter,
+    last_error_message: Option<CString>,
 }
 
 pub struct TSHighlightBuffer {
     context: HighlightContext,
     renderer: HtmlRenderer,
+    // The renderer's line offsets, converted to the integer type used by the C API.
+    line_offsets: Vec<u32>,
+    events: Vec<TSHighlightEvent>,
+    // The index within `events` of the first event of each line.
+    event_line_offsets: Vec<u32>,
+    highlight_stack: Vec<Highlight>,
+}
+
+/// A range of columns within a line that has a highlight, as computed by
+/// `ts_highlighter_highlight_events`. The columns are zero-based, measured in bytes,
+/// and don't include the line ending.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TSHighlightEvent {
+    pub start_column: u32,
+    pub end_column: u32,
+    pub highlight_index: u32,
 }
 
 #[repr(C)]
@@ -28,6 +51,27 @@ pub enum ErrorCode {
     InvalidUtf8,
     InvalidRegex,
     InvalidQuery,
+    OutputTooLarge,
+    IncompatibleLanguageVersion,
+    InvalidLanguageDirectory,
+    Unknown,
+    SourceTooLarge,
+    NullPointer,
+    Panic,
+    DuplicateAlias,
+}
+
+impl From<Error> for ErrorCode {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Cancelled => ErrorCode::Timeout,
+            Error::InvalidLanguage => ErrorCode::InvalidLanguage,
+            Error::InvalidQuery(_) => ErrorCode::InvalidQuery,
+            Error::IncompatibleLanguageVersion { .. } => ErrorCode::IncompatibleLanguageVersion,
+            Error::SourceTooLarge => ErrorCode::SourceTooLarge,
+            Error::Unknown | Error::InvalidSourceRange { .. } => ErrorCode::Unknown,
+        }
+    }
 }
 
 #[no_mangle]
@@ -36,24 +80,37 @@ pub extern "C" fn ts_highlighter_new(
     attribute_strings: *const *const c_char,
     highlight_count: u32,
 ) -> *mut TSHighlighter {
-    let highlight_names =
-        unsafe { slice::from_raw_parts(highlight_names, highlight_count as usize) };
-    let attribute_strings =
-        unsafe { slice::from_raw_parts(attribute_strings, highlight_count as usize) };
-    let highlight_names = highlight_names
-        .into_iter()
-        .map(|s| unsafe { CStr::from_ptr(*s).to_string_lossy().to_string() })
-        .collect();
-    let attribute_strings = attribute_strings
-        .into_iter()
-        .map(|s| unsafe { CStr::from_ptr(*s).to_bytes() })
-        .collect();
-    let highlighter = Highlighter::new(highlight_names);
-    Box::into_raw(Box::new(TSHighlighter {
-        languages: HashMap::new(),
-        attribute_strings,
-        highlighter,
-    }))
+    if highlight_count > 0 && (highlight_names.is_null() || attribute_strings.is_null()) {
+        return ptr::null_mut();
+    }
+    let result = panic::catch_unwind(|| {
+        let highlight_names = if highlight_count > 0 {
+            unsafe { slice::from_raw_parts(highlight_names, highlight_count as usize) }
+        } else {
+            &[]
+        };
+        let attribute_strings = if highlight_count > 0 {
+            unsafe { slice::from_raw_parts(attribute_strings, highlight_count as usize) }
+        } else {
+            &[]
+        };
+        let highlight_names = highlight_names
+            .iter()
+            .map(|s| unsafe { CStr::from_ptr(*s).to_string_lossy().to_string() })
+            .collect();
+        let attribute_strings = attribute_strings
+            .iter()
+            .map(|s| unsafe { CStr::from_ptr(*s).to_bytes() })
+            .collect();
+        let highlighter = Highlighter::new(highlight_names);
+        Box::into_raw(Box::new(TSHighlighter {
+            languages: HashMap::new(),
+            attribute_strings,
+            highlighter,
+            last_error_message: None,
+        }))
+    });
+    result.unwrap_or(ptr::null_mut())
 }
 
 #[no_mangle]
@@ -69,20 +126,41 @@ pub extern "C" fn ts_highlighter_add_language(
     injection_query_len: u32,
     locals_query_len: u32,
 ) -> ErrorCode {
-    let f = move || {
-        let this = unwrap_mut_ptr(this);
-        let scope_name = unsafe { CStr::from_ptr(scope_name) };
-        let scope_name = scope_name
-            .to_str()
-            .or(Err(ErrorCode::InvalidUtf8))?
-            .to_string();
-        let injection_regex = if injection_regex.is_null() {
-            None
-        } else {
-            let pattern = unsafe { CStr::from_ptr(injection_regex) };
-            let pattern = pattern.to_str().or(Err(ErrorCode::InvalidUtf8))?;
-            Some(Regex::new(pattern).or(Err(ErrorCode::InvalidRegex))?)
-        };
+    ts_highlighter_add_language_with_aliases(
+        this,
+        scope_name,
+        injection_regex,
+        language,
+        highlight_query,
+        injection_query,
+        locals_query,
+        highlight_query_len,
+        injection_query_len,
+        locals_query_len,
+        ptr::null(),
+        0,
+    )
+}
+
+#[no_mangle]
+pub extern "C" fn ts_highlighter_add_language_with_aliases(
+    this: *mut TSHighlighter,
+    scope_name: *const c_char,
+    injection_regex: *const c_char,
+    language: Language,
+    highlight_query: *const c_char,
+    injection_query: *const c_char,
+    locals_query: *const c_char,
+    highlight_query_len: u32,
+    injection_query_len: u32,
+    locals_query_len: u32,
+    aliases: *const *const c_char,
+    alias_count: u32,
+) -> ErrorCode {
+    catch_errors(|| {
+        let this = ptr_mut(this)?;
+        this.last_error_message = None;
+        let (scope_name, injection_regex) = unwrap_scope(scope_name, injection_regex)?;
 
         let highlight_query = unsafe {
             slice::from_raw_parts(highlight_query as *const u8, highlight_query_len as usize)
@@ -107,23 +185,94 @@ pub extern "C" fn ts_highlighter_add_language(
             ""
         };
 
-        this.languages.insert(
-            scope_name,
-            (
-                injection_regex,
-                this.highlighter
-                    .load_configuration(language, highlight_query, injection_query, locals_query)
-                    .or(Err(ErrorCode::InvalidQuery))?,
-            ),
-        );
+        let aliases = if alias_count > 0 {
+            if aliases.is_null() {
+                return Err(ErrorCode::NullPointer);
+            }
+            unsafe { slice::from_raw_parts(aliases, alias_count as usize) }
+        } else {
+            &[]
+        };
+        let aliases = aliases
+            .iter()
+            .map(|alias| c_str(*alias).map(str::to_string))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Each alias must select a single language. A language that replaces another one
+        // with the same scope name can reuse its aliases.
+        if let Some(alias) = aliases.iter().find(|alias| {
+            this.languages
+                .iter()
+                .any(|(other_scope_name, (_, config))| {
+                    *other_scope_name != scope_name
+                        && config
+                            .names()
+                            .iter()
+                            .any(|name| name.eq_ignore_ascii_case(alias))
+                })
+        }) {
+            this.last_error_message =
+                CString::new(format!("The alias {:?} is used by another language", alias)).ok();
+            return Err(ErrorCode::DuplicateAlias);
+        }
+
+        let configuration = this
+            .highlighter
+            .load_configuration(language, highlight_query, injection_query, locals_query)
+            .map_err(|error| {
+                this.last_error_message = CString::new(error.to_string()).ok();
+                ErrorCode::from(error)
+            })?
+            .with_names(aliases);
+        this.languages
+            .insert(scope_name, (injection_regex, configuration));
 
         Ok(())
-    };
+    })
+}
 
-    match f() {
-        Ok(()) => ErrorCode::Ok,
-        Err(e) => e,
-    }
+#[cfg(feature = "fs")]
+#[no_mangle]
+pub extern "C" fn ts_highlighter_add_language_from_directory(
+    this: *mut TSHighlighter,
+    scope_name: *const c_char,
+    injection_regex: *const c_char,
+    language: Language,
+    path: *const c_char,
+) -> ErrorCode {
+    use super::LoadError;
+    use std::path::Path;
+
+    catch_errors(|| {
+        let this = ptr_mut(this)?;
+        this.last_error_message = None;
+        let (scope_name, injection_regex) = unwrap_scope(scope_name, injection_regex)?;
+        let path = c_str(path)?;
+
+        let configuration = this
+            .highlighter
+            .load_configuration_from_language_dir(language, Path::new(path))
+            .map_err(|error| {
+                this.last_error_message = CString::new(error.to_string()).ok();
+                match error {
+                    LoadError::Query(_) | LoadError::QueryFile { .. } => ErrorCode::InvalidQuery,
+                    LoadError::Configuration(error) => ErrorCode::from(error),
+                    _ => ErrorCode::InvalidLanguageDirectory,
+                }
+            })?;
+        this.languages
+            .insert(scope_name, (injection_regex, configuration));
+
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn ts_highlighter_last_error_message(this: *const TSHighlighter) -> *const c_char {
+    let this = unwrap_ptr(this);
+    this.last_error_message
+        .as_ref()
+        .map_or(std::ptr::null(), |message| message.as_ptr())
 }
 
 #[no_mangle]
@@ -131,17 +280,25 @@ pub extern "C" fn ts_highlight_buffer_new() -> *mut TSHighlightBuffer {
     Box::into_raw(Box::new(TSHighlightBuffer {
         context: HighlightContext::new(),
         renderer: HtmlRenderer::new(),
+        line_offsets: Vec::new(),
+        events: Vec::new(),
+        event_line_offsets: Vec::new(),
+        highlight_stack: Vec::new(),
     }))
 }
 
 #[no_mangle]
 pub extern "C" fn ts_highlighter_delete(this: *mut TSHighlighter) {
-    drop(unsafe { Box::from_raw(this) })
+    if !this.is_null() {
+        drop(unsafe { Box::from_raw(this) })
+    }
 }
 
 #[no_mangle]
 pub extern "C" fn ts_highlight_buffer_delete(this: *mut TSHighlightBuffer) {
-    drop(unsafe { Box::from_raw(this) })
+    if !this.is_null() {
+        drop(unsafe { Box::from_raw(this) })
+    }
 }
 
 #[no_mangle]
@@ -153,7 +310,7 @@ pub extern "C" fn ts_highlight_buffer_content(this: *const TSHighlightBuffer) ->
 #[no_mangle]
 pub extern "C" fn ts_highlight_buffer_line_offsets(this: *const TSHighlightBuffer) -> *const u32 {
     let this = unwrap_ptr(this);
-    this.renderer.line_offsets.as_slice().as_ptr()
+    this.line_offsets.as_slice().as_ptr()
 }
 
 #[no_mangle]
@@ -165,7 +322,65 @@ pub extern "C" fn ts_highlight_buffer_len(this: *const TSHighlightBuffer) -> u32
 #[no_mangle]
 pub extern "C" fn ts_highlight_buffer_line_count(this: *const TSHighlightBuffer) -> u32 {
     let this = unwrap_ptr(this);
-    this.renderer.line_offsets.len() as u32
+    this.line_offsets.len() as u32
+}
+
+#[no_mangle]
+pub extern "C" fn ts_highlight_buffer_events(
+    this: *const TSHighlightBuffer,
+) -> *const TSHighlightEvent {
+    let this = unwrap_ptr(this);
+    this.events.as_slice().as_ptr()
+}
+
+#[no_mangle]
+pub extern "C" fn ts_highlight_buffer_event_count(this: *const TSHighlightBuffer) -> u32 {
+    let this = unwrap_ptr(this);
+    this.events.len() as u32
+}
+
+#[no_mangle]
+pub extern "C" fn ts_highlight_buffer_event_line_offsets(
+    this: *const TSHighlightBuffer,
+) -> *const u32 {
+    let this = unwrap_ptr(this);
+    this.event_line_offsets.as_slice().as_ptr()
+}
+
+#[no_mangle]
+pub extern "C" fn ts_highlight_buffer_event_line_count(this: *const TSHighlightBuffer) -> u32 {
+    let this = unwrap_ptr(this);
+    this.event_line_offsets.len() as u32
+}
+
+#[no_mangle]
+pub extern "C" fn ts_highlight_buffer_layer_count(this: *const TSHighlightBuffer) -> u32 {
+    let this = unwrap_ptr(this);
+    this.context.parse_stats().layer_count as u32
+}
+
+#[no_mangle]
+pub extern "C" fn ts_highlight_buffer_parse_count(this: *const TSHighlightBuffer) -> u32 {
+    let this = unwrap_ptr(this);
+    this.context.parse_stats().parse_count as u32
+}
+
+#[no_mangle]
+pub extern "C" fn ts_highlight_buffer_parsed_byte_count(this: *const TSHighlightBuffer) -> u64 {
+    let this = unwrap_ptr(this);
+    this.context.parse_stats().parsed_bytes as u64
+}
+
+#[no_mangle]
+pub extern "C" fn ts_highlight_buffer_root_has_error(this: *const TSHighlightBuffer) -> bool {
+    let this = unwrap_ptr(this);
+    this.context.parse_stats().root_has_error
+}
+
+#[no_mangle]
+pub extern "C" fn ts_highlight_buffer_error_count(this: *const TSHighlightBuffer) -> u32 {
+    let this = unwrap_ptr(this);
+    this.context.parse_stats().error_count as u32
 }
 
 #[no_mangle]
@@ -177,88 +392,258 @@ pub extern "C" fn ts_highlighter_highlight(
     output: *mut TSHighlightBuffer,
     cancellation_flag: *const AtomicUsize,
 ) -> ErrorCode {
-    let this = unwrap_ptr(this);
-    let output = unwrap_mut_ptr(output);
-    let scope_name = unwrap(unsafe { CStr::from_ptr(scope_name).to_str() });
-    let source_code =
-        unsafe { slice::from_raw_parts(source_code as *const u8, source_code_len as usize) };
-    let cancellation_flag = unsafe { cancellation_flag.as_ref() };
-    this.highlight(source_code, scope_name, output, cancellation_flag)
+    catch_errors(|| {
+        let this = ptr_ref(this)?;
+        let output = ptr_mut(output)?;
+        let scope_name = c_str(scope_name)?;
+        let source_code = source_slice(source_code, source_code_len)?;
+        let cancellation_flag = unsafe { cancellation_flag.as_ref() };
+        output.reset();
+        let highlights = this.highlights(
+            &mut output.context,
+            source_code,
+            scope_name,
+            cancellation_flag,
+        )?;
+        let result = output
+            .renderer
+            .render(highlights, source_code, &|s| this.attribute_strings[s.0]);
+        if let Err(error) = result {
+            output.renderer.reset();
+            return Err(error.into());
+        }
+
+        // The C API represents offsets into the output as 32-bit integers.
+        if u32::try_from(output.renderer.html.len()).is_err() {
+            output.renderer.reset();
+            return Err(ErrorCode::OutputTooLarge);
+        }
+        output
+            .line_offsets
+            .extend(output.renderer.line_offsets.iter().map(|o| *o as u32));
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn ts_highlighter_highlight_events(
+    this: *const TSHighlighter,
+    scope_name: *const c_char,
+    source_code: *const c_char,
+    source_code_len: u32,
+    output: *mut TSHighlightBuffer,
+    cancellation_flag: *const AtomicUsize,
+) -> ErrorCode {
+    catch_errors(|| {
+        let this = ptr_ref(this)?;
+        let output = ptr_mut(output)?;
+        let scope_name = c_str(scope_name)?;
+        let source_code = source_slice(source_code, source_code_len)?;
+        let cancellation_flag = unsafe { cancellation_flag.as_ref() };
+        output.reset();
+        let highlights = this.highlights(
+            &mut output.context,
+            source_code,
+            scope_name,
+            cancellation_flag,
+        )?;
+        let result = collect_line_events(
+            highlights,
+            source_code,
+            &mut output.events,
+            &mut output.event_line_offsets,
+            &mut output.highlight_stack,
+        );
+        if let Err(error) = result {
+            output.reset();
+            return Err(error.into());
+        }
+        Ok(())
+    })
 }
 
 impl TSHighlighter {
-    fn highlight(
-        &self,
-        source_code: &[u8],
+    fn highlights<'a>(
+        &'a self,
+        context: &'a mut HighlightContext,
+        source_code: &'a [u8],
         scope_name: &str,
-        output: &mut TSHighlightBuffer,
-        cancellation_flag: Option<&AtomicUsize>,
-    ) -> ErrorCode {
-        let entry = self.languages.get(scope_name);
-        if entry.is_none() {
-            return ErrorCode::UnknownScope;
-        }
-        let (_, configuration) = entry.unwrap();
+        cancellation_flag: Option<&'a AtomicUsize>,
+    ) -> Result<impl Iterator<Item = Result<HighlightEvent, Error>> + 'a, ErrorCode> {
+        let (_, configuration) = self
+            .languages
+            .get(scope_name)
+            .ok_or(ErrorCode::UnknownScope)?;
         let languages = &self.languages;
 
         let highlights = self.highlighter.highlight(
-            &mut output.context,
+            context,
             configuration,
             source_code,
             cancellation_flag,
             move |injection_string| {
-                languages.values().find_map(|(injection_regex, config)| {
-                    injection_regex.as_ref().and_then(|regex| {
-                        if regex.is_match(injection_string) {
+                // Languages whose aliases include the injection string take precedence
+                // over languages whose injection regex matches it.
+                languages
+                    .values()
+                    .find_map(|(_, config)| {
+                        if config
+                            .names()
+                            .iter()
+                            .any(|alias| alias.eq_ignore_ascii_case(injection_string))
+                        {
                             Some(config)
                         } else {
                             None
                         }
                     })
-                })
+                    .or_else(|| {
+                        languages.values().find_map(|(injection_regex, config)| {
+                            injection_regex.as_ref().and_then(|regex| {
+                                if regex.is_match(injection_string) {
+                                    Some(config)
+                                } else {
+                                    None
+                                }
+                            })
+                        })
+                    })
             },
-        );
+        )?;
+        Ok(highlights)
+    }
+}
 
-        if let Ok(highlights) = highlights {
-            output.renderer.reset();
-            let result = output
-                .renderer
-                .render(highlights, source_code, &|s| self.attribute_strings[s.0]);
-            match result {
-                Err(Error::Cancelled) => {
-                    return ErrorCode::Timeout;
-                }
-                Err(Error::InvalidLanguage) => {
-                    return ErrorCode::InvalidLanguage;
-                }
-                Err(Error::Unknown) => {
-                    return ErrorCode::Timeout;
+impl TSHighlightBuffer {
+    // Clear the output of the previous highlighting call, keeping the allocations.
+    fn reset(&mut self) {
+        self.renderer.reset();
+        self.line_offsets.clear();
+        self.events.clear();
+        self.event_line_offsets.clear();
+        self.highlight_stack.clear();
+    }
+}
+
+// Split the innermost highlight of each range of the source code into ranges of columns
+// on each line, appending them to `events`. Ranges of the same highlight that are next to
+// each other on a line are merged.
+fn collect_line_events(
+    highlights: impl Iterator<Item = Result<HighlightEvent, Error>>,
+    source_code: &[u8],
+    events: &mut Vec<TSHighlightEvent>,
+    line_offsets: &mut Vec<u32>,
+    highlight_stack: &mut Vec<Highlight>,
+) -> Result<(), Error> {
+    let mut line_start = 0;
+    line_offsets.push(0);
+    for event in highlights {
+        match event? {
+            HighlightEvent::HighlightStart(highlight)
+            | HighlightEvent::HighlightStartWithPattern { highlight, .. } => {
+                highlight_stack.push(highlight)
+            }
+            HighlightEvent::HighlightEnd => {
+                highlight_stack.pop();
+            }
+            HighlightEvent::Source { start, end } => {
+                let end = end.min(source_code.len());
+                let mut start = start.min(end);
+                while start < end {
+                    let newline = source_code[start..end].iter().position(|c| *c == b'\n');
+                    let mut segment_end = newline.map_or(end, |i| start + i);
+                    if newline.is_some() && source_code[..segment_end].ends_with(b"\r") {
+                        segment_end = segment_end.saturating_sub(1).max(start);
+                    }
+                    if let Some(highlight) = highlight_stack.last() {
+                        if start < segment_end {
+                            let event = TSHighlightEvent {
+                                start_column: (start - line_start) as u32,
+                                end_column: (segment_end - line_start) as u32,
+                                highlight_index: highlight.0 as u32,
+                            };
+                            let line_offset = *line_offsets.last().unwrap() as usize;
+                            match events[line_offset..].last_mut() {
+                                Some(last)
+                                    if last.end_column == event.start_column
+                                        && last.highlight_index == event.highlight_index =>
+                                {
+                                    last.end_column = event.end_column
+                                }
+                                _ => events.push(event),
+                            }
+                        }
+                    }
+                    match newline {
+                        Some(i) => {
+                            start += i + 1;
+                            line_start = start;
+                            line_offsets.push(events.len() as u32);
+                        }
+                        None => start = end,
+                    }
                 }
-                Ok(()) => ErrorCode::Ok,
             }
-        } else {
-            ErrorCode::Timeout
+            HighlightEvent::LayerStart { .. } | HighlightEvent::LayerEnd => {}
         }
     }
+    Ok(())
 }
 
-fn unwrap_ptr<'a, T>(result: *const T) -> &'a T {
-    unsafe { result.as_ref() }.unwrap_or_else(|| {
-        eprintln!("{}:{} - pointer must not be null", file!(), line!());
-        abort();
-    })
+fn unwrap_scope(
+    scope_name: *const c_char,
+    injection_regex: *const c_char,
+) -> Result<(String, Option<Regex>), ErrorCode> {
+    let scope_name = c_str(scope_name)?.to_string();
+    let injection_regex = if injection_regex.is_null() {
+        None
+    } else {
+        let pattern = c_str(injection_regex)?;
+        Some(Regex::new(pattern).or(Err(ErrorCode::InvalidRegex))?)
+    };
+    Ok((scope_name, injection_regex))
 }
 
-fn unwrap_mut_ptr<'a, T>(result: *mut T) -> &'a mut T {
-    unsafe { result.as_mut() }.unwrap_or_else(|| {
-        eprintln!("{}:{} - pointer must not be null", file!(), line!());
-        abort();
-    })
+// Run the body of a fallible function of the C API. A panic must not unwind into the
+// caller's frames, so it is reported as an error code instead.
+fn catch_errors(f: impl FnOnce() -> Result<(), ErrorCode>) -> ErrorCode {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => ErrorCode::Ok,
+        Ok(Err(error)) => error,
+        Err(_) => ErrorCode::Panic,
+    }
+}
+
+fn ptr_ref<'a, T>(pointer: *const T) -> Result<&'a T, ErrorCode> {
+    unsafe { pointer.as_ref() }.ok_or(ErrorCode::NullPointer)
+}
+
+fn ptr_mut<'a, T>(pointer: *mut T) -> Result<&'a mut T, ErrorCode> {
+    unsafe { pointer.as_mut() }.ok_or(ErrorCode::NullPointer)
 }
 
-fn unwrap<T, E: fmt::Display>(result: Result<T, E>) -> T {
-    result.unwrap_or_else(|error| {
-        eprintln!("tree-sitter highlight error: {}", error);
+fn c_str<'a>(string: *const c_char) -> Result<&'a str, ErrorCode> {
+    if string.is_null() {
+        return Err(ErrorCode::NullPointer);
+    }
+    unsafe { CStr::from_ptr(string) }
+        .to_str()
+        .or(Err(ErrorCode::InvalidUtf8))
+}
+
+fn source_slice<'a>(source_code: *const c_char, len: u32) -> Result<&'a [u8], ErrorCode> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    if source_code.is_null() {
+        return Err(ErrorCode::NullPointer);
+    }
+    Ok(unsafe { slice::from_raw_parts(source_code as *const u8, len as usize) })
+}
+
+fn unwrap_ptr<'a, T>(result: *const T) -> &'a T {
+    unsafe { result.as_ref() }.unwrap_or_else(|| {
+        eprintln!("{}:{} - pointer must not be null", file!(), line!());
         abort();
     })
 }
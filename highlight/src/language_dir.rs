@@ -0,0 +1,330 @@
+use super::{ConfigurationError, Error, HighlightConfiguration, Highlighter, QuerySection};
+use serde_json::{Map, Value};
+use std::path::{Path, PathBuf};
+use std::{fmt, fs, io};
+use tree_sitter::{Language, QueryError};
+
+/// Represents the reason why a `HighlightConfiguration` could not be loaded from a
+/// language directory.
+#[derive(Debug)]
+pub enum LoadError {
+    Io(PathBuf, io::Error),
+    Metadata(PathBuf, serde_json::Error),
+    InheritedLanguageNotFound(String),
+    /// A language inherits queries from itself, directly or indirectly. This contains the
+    /// names of the directories in the chain of inheritance, starting and ending with the
+    /// same directory.
+    InheritanceCycle(Vec<String>),
+    Query(ConfigurationError),
+    /// A query file contains an invalid pattern. The `row` is the one-based line number of
+    /// the error within that file, while the `error` describes the error relative to the
+    /// concatenation of all of the query files of its section.
+    QueryFile {
+        path: PathBuf,
+        row: usize,
+        error: ConfigurationError,
+    },
+    /// The configuration could not be created for a reason other than an invalid query,
+    /// such as an incompatible language version.
+    Configuration(Error),
+}
+
+#[derive(Default)]
+struct LanguageMetadata {
+    highlights: Option<Vec<String>>,
+    injections: Option<Vec<String>>,
+    locals: Option<Vec<String>>,
+}
+
+impl LanguageMetadata {
+    fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        let mut object = serde_json::from_str::<Map<String, Value>>(json)?;
+        let mut paths = |key| -> Result<Option<Vec<String>>, serde_json::Error> {
+            serde_json::from_value(object.remove(key).unwrap_or(Value::Null))
+        };
+        Ok(LanguageMetadata {
+            highlights: paths("highlights")?,
+            injections: paths("injections")?,
+            locals: paths("locals")?,
+        })
+    }
+}
+
+impl HighlightConfiguration {
+    /// Creates a `HighlightConfiguration` for a language directory, reading the query files
+    /// that are listed in its `src/metadata.json` and resolving their `; inherits:`
+    /// comments, as described in `Highlighter::load_configuration_from_language_dir`.
+    ///
+    /// The configuration's captures aren't matched against any highlight names until
+    /// `configure` is called with the names that the application recognizes.
+    pub fn load_from_language_dir(language: Language, dir: &Path) -> Result<Self, LoadError> {
+        Highlighter::new(Vec::new()).load_configuration_from_language_dir(language, dir)
+    }
+}
+
+impl Highlighter {
+    /// Creates a `HighlightConfiguration` for a language directory, using the query
+    /// paths listed in the `src/metadata.json` file that `tree-sitter generate` writes.
+    ///
+    /// If the metadata does not list any paths for a given kind of query, the default
+    /// path (`queries/highlights.scm`, `queries/injections.scm` or `queries/locals.scm`)
+    /// is used, if it exists. A query file can begin with a comment of the form
+    /// `; inherits: name1, name2`, in which case the same kind of query is loaded from
+    /// the sibling directories `name1` (or `tree-sitter-name1`) and `name2`, and
+    /// prepended to the query.
+    ///
+    /// Each language's queries are included at most once, before the queries of every
+    /// language that inherits them, even if several of the languages in the chain of
+    /// inheritance inherit from the same language. A language that inherits from itself
+    /// is an error. Use `query_paths_from_language_dir` to see the order in which the
+    /// query files are included.
+    pub fn load_configuration_from_language_dir(
+        &self,
+        language: Language,
+        language_dir: &Path,
+    ) -> Result<HighlightConfiguration, LoadError> {
+        self.load_configuration_from_query_files(language, language_dir, &sibling_language_dir)
+    }
+
+    /// Creates a `HighlightConfiguration` from the query files of a directory, like
+    /// `load_configuration_from_language_dir`, but the languages named in `; inherits:`
+    /// comments are found with the given callback, which returns the directory of the
+    /// language with the given name. This is useful when the languages are not checked
+    /// out next to each other, such as in an editor's runtime directory.
+    ///
+    /// An invalid pattern is reported as a `LoadError::QueryFile`, along with the file and
+    /// the line that contain it.
+    pub fn load_configuration_from_dir(
+        &self,
+        language: Language,
+        dir: &Path,
+        inherited_language_dir: impl Fn(&str) -> Option<PathBuf>,
+    ) -> Result<HighlightConfiguration, LoadError> {
+        let find_dir = |_: &Path, name: &str| inherited_language_dir(name);
+        self.load_configuration_from_query_files(language, dir, &find_dir)
+    }
+
+    fn load_configuration_from_query_files(
+        &self,
+        language: Language,
+        language_dir: &Path,
+        find_dir: &dyn Fn(&Path, &str) -> Option<PathBuf>,
+    ) -> Result<HighlightConfiguration, LoadError> {
+        let highlights_files = query_files(language_dir, QuerySection::Highlights, find_dir)?;
+        let injections_files = query_files(language_dir, QuerySection::Injections, find_dir)?;
+        let locals_files = query_files(language_dir, QuerySection::Locals, find_dir)?;
+        // The files are separated by newlines, so that a file that ends with a comment
+        // and no trailing newline doesn't comment out the first line of the next file.
+        let concat = |files: &[(PathBuf, String)]| -> String {
+            let queries = files.iter().map(|(_, query)| query.as_str());
+            queries.collect::<Vec<_>>().join("\n")
+        };
+        self.load_configuration(
+            language,
+            &concat(&highlights_files),
+            &concat(&injections_files),
+            &concat(&locals_files),
+        )
+        .map_err(|error| match error {
+            Error::InvalidQuery(error) => {
+                let files = match error.section {
+                    QuerySection::Highlights => &highlights_files,
+                    QuerySection::Injections => &injections_files,
+                    QuerySection::Locals => &locals_files,
+                };
+                query_file_error(files, error)
+            }
+            error => LoadError::from(error),
+        })
+    }
+}
+
+impl From<Error> for LoadError {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::InvalidQuery(error) => LoadError::Query(error),
+            error => LoadError::Configuration(error),
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(path, error) => {
+                write!(f, "Failed to read {}: {}", path.display(), error)
+            }
+            LoadError::Metadata(path, error) => {
+                write!(f, "Invalid metadata in {}: {}", path.display(), error)
+            }
+            LoadError::InheritedLanguageNotFound(name) => {
+                write!(f, "Inherited language {} was not found", name)
+            }
+            LoadError::InheritanceCycle(chain) => {
+                write!(f, "Query inheritance cycle: {}", chain.join(" -> "))
+            }
+            LoadError::Query(error) => write!(f, "{}", error),
+            LoadError::QueryFile { path, row, error } => write!(
+                f,
+                "Invalid query on line {} of {}: {}",
+                row,
+                path.display(),
+                error
+            ),
+            LoadError::Configuration(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+/// Returns the paths of the query files that `load_configuration_from_language_dir`
+/// concatenates to create the given section of a language directory's configuration, in
+/// the order in which they are included.
+pub fn query_paths_from_language_dir(
+    language_dir: &Path,
+    section: QuerySection,
+) -> Result<Vec<PathBuf>, LoadError> {
+    let files = query_files(language_dir, section, &sibling_language_dir)?;
+    Ok(files.into_iter().map(|(path, _)| path).collect())
+}
+
+fn query_files(
+    language_dir: &Path,
+    section: QuerySection,
+    find_dir: &dyn Fn(&Path, &str) -> Option<PathBuf>,
+) -> Result<Vec<(PathBuf, String)>, LoadError> {
+    let mut files = Vec::new();
+    collect_query_files(
+        language_dir,
+        section,
+        find_dir,
+        &mut Vec::new(),
+        &mut Vec::new(),
+        &mut files,
+    )?;
+    Ok(files)
+}
+
+// Find the file that contains the position of a query error within the concatenated query
+// files of its section, which are separated by newlines. An error at the position of a
+// separator is attributed to the end of the file before it. Predicate errors don't have a
+// position, so they can't be attributed to a file.
+fn query_file_error(files: &[(PathBuf, String)], error: ConfigurationError) -> LoadError {
+    if let QueryError::Predicate(_) = error.error {
+        return LoadError::Query(error);
+    }
+    let mut file_start = 0;
+    for (i, (path, query)) in files.iter().enumerate() {
+        let file_end = file_start + query.len();
+        if error.offset <= file_end || i + 1 == files.len() {
+            let offset = error.offset.saturating_sub(file_start).min(query.len());
+            let row = query[..offset].matches('\n').count() + 1;
+            return LoadError::QueryFile {
+                path: path.clone(),
+                row,
+                error,
+            };
+        }
+        file_start = file_end + 1;
+    }
+    LoadError::Query(error)
+}
+
+// Find the directory of an inherited language among the siblings of the directory of the
+// language that inherits it, named either `name` or `tree-sitter-name`.
+fn sibling_language_dir(language_dir: &Path, name: &str) -> Option<PathBuf> {
+    let parent_dir = language_dir.parent().unwrap_or(language_dir);
+    [name.to_string(), format!("tree-sitter-{}", name)]
+        .iter()
+        .map(|dir_name| parent_dir.join(dir_name))
+        .find(|dir| dir.is_dir())
+}
+
+// Collect the query files of a language directory, preceded by the query files of the
+// languages that they inherit from, in depth-first order. `ancestors` contains the
+// directories whose query files are currently being collected, which are used to detect
+// cycles, and `visited_dirs` contains the directories whose query files have already been
+// collected, which are skipped.
+fn collect_query_files(
+    language_dir: &Path,
+    section: QuerySection,
+    find_dir: &dyn Fn(&Path, &str) -> Option<PathBuf>,
+    ancestors: &mut Vec<PathBuf>,
+    visited_dirs: &mut Vec<PathBuf>,
+    files: &mut Vec<(PathBuf, String)>,
+) -> Result<(), LoadError> {
+    if let Some(i) = ancestors.iter().position(|dir| dir == language_dir) {
+        let chain = ancestors[i..]
+            .iter()
+            .map(PathBuf::as_path)
+            .chain(Some(language_dir))
+            .map(|dir| dir.file_name().unwrap_or_default().to_string_lossy().into())
+            .collect();
+        return Err(LoadError::InheritanceCycle(chain));
+    }
+    if visited_dirs.iter().any(|dir| dir == language_dir) {
+        return Ok(());
+    }
+
+    let metadata_path = language_dir.join("src").join("metadata.json");
+    let metadata = if metadata_path.exists() {
+        let json = read_file(&metadata_path)?;
+        LanguageMetadata::from_json(&json).map_err(|e| LoadError::Metadata(metadata_path, e))?
+    } else {
+        LanguageMetadata::default()
+    };
+
+    let (paths, kind) = match section {
+        QuerySection::Highlights => (metadata.highlights, "highlights"),
+        QuerySection::Injections => (metadata.injections, "injections"),
+        QuerySection::Locals => (metadata.locals, "locals"),
+    };
+    let paths = match paths {
+        Some(paths) => paths.iter().map(|path| language_dir.join(path)).collect(),
+        None => {
+            let path = language_dir.join("queries").join(format!("{}.scm", kind));
+            if path.exists() {
+                vec![path]
+            } else {
+                Vec::new()
+            }
+        }
+    };
+
+    ancestors.push(language_dir.to_owned());
+    for path in paths {
+        let query = read_file(&path)?;
+        for name in inherited_language_names(&query) {
+            let inherited_dir = find_dir(language_dir, name)
+                .ok_or_else(|| LoadError::InheritedLanguageNotFound(name.to_string()))?;
+            collect_query_files(
+                &inherited_dir,
+                section,
+                find_dir,
+                ancestors,
+                visited_dirs,
+                files,
+            )?;
+        }
+        files.push((path, query));
+    }
+    ancestors.pop();
+    visited_dirs.push(language_dir.to_owned());
+    Ok(())
+}
+
+fn inherited_language_names(query: &str) -> impl Iterator<Item = &str> {
+    query
+        .lines()
+        .take_while(|line| line.starts_with(';'))
+        .filter_map(|line| {
+            let line = line.trim_start_matches(';').trim();
+            line.strip_prefix("inherits:").map(|names| names.split(','))
+        })
+        .flatten()
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+}
+
+pub(crate) fn read_file(path: &Path) -> Result<String, LoadError> {
+    fs::read_to_string(path).map_err(|e| LoadError::Io(path.to_owned(), e))
+}
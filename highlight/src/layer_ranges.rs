@@ -0,0 +1,210 @@
+use std::ops;
+use tree_sitter::{Node, Point, Range};
+
+/// Translates byte offsets between a document and the content of an injected layer.
+///
+/// An injected layer is parsed from a set of included ranges of the document. Its content
+/// is the concatenation of those ranges, so an offset within the layer's content is an
+/// offset into that concatenation. The ranges must be sorted and must not overlap, which
+/// is always the case for the included ranges of a layer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LayerRangeMap {
+    ranges: Vec<ops::Range<usize>>,
+}
+
+impl LayerRangeMap {
+    pub fn new(ranges: Vec<Range>) -> Self {
+        LayerRangeMap {
+            ranges: ranges
+                .into_iter()
+                .map(|range| range.start_byte..range.end_byte)
+                .collect(),
+        }
+    }
+
+    /// Returns the byte ranges of the document that belong to the layer.
+    pub fn ranges(&self) -> &[ops::Range<usize>] {
+        &self.ranges
+    }
+
+    /// Converts an offset in the document into an offset within the layer's content.
+    /// Returns `None` if the offset is not within any of the layer's ranges. Ranges don't
+    /// include their end offsets.
+    pub fn to_local(&self, offset: usize) -> Option<usize> {
+        let mut local_start = 0;
+        for range in &self.ranges {
+            if offset < range.start {
+                break;
+            }
+            if offset < range.end {
+                return Some(local_start + offset - range.start);
+            }
+            local_start += range.end - range.start;
+        }
+        None
+    }
+
+    /// Converts an offset within the layer's content into an offset in the document.
+    ///
+    /// An offset at the boundary between two ranges is converted into the start of the
+    /// later range. An offset at or past the end of the layer's content is converted into
+    /// an offset relative to the end of the last range.
+    pub fn to_document(&self, local_offset: usize) -> usize {
+        let mut local_start = 0;
+        for range in &self.ranges {
+            let len = range.end - range.start;
+            if local_offset < local_start + len {
+                return range.start + local_offset - local_start;
+            }
+            local_start += len;
+        }
+        let end = self.ranges.last().map_or(0, |range| range.end);
+        end + local_offset - local_start
+    }
+
+    /// Iterates over the byte ranges of the document that lie between the layer's ranges.
+    /// The parts of the document before the first range and after the last range are not
+    /// included.
+    pub fn gaps<'a>(&'a self) -> impl Iterator<Item = ops::Range<usize>> + 'a {
+        self.ranges
+            .windows(2)
+            .map(|pair| pair[0].end..pair[1].start)
+            .filter(|gap| gap.start < gap.end)
+    }
+}
+
+/// Computes the ranges that should be included when parsing an injection, as passed to
+/// `Parser::set_included_ranges`. This takes into account three things:
+///
+/// * `parent_ranges` - The new injection may be nested inside of *another* injection
+///   (e.g. JavaScript within HTML within ERB). The parent injection's ranges must
+///   be taken into account.
+/// * `nodes` - Every injection takes place within a set of nodes. The injection ranges
+///   are the ranges of those nodes.
+/// * `includes_children` - For some injections, the content nodes' children should be
+///   excluded from the nested document, so that only the content nodes' *own* content
+///   is reparsed. For other injections, the content nodes' entire ranges should be
+///   reparsed, including the ranges of their children.
+///
+/// The result is sorted, and ranges that overlap or touch are merged, regardless of the
+/// order of the nodes and of the parent ranges.
+pub fn intersect_ranges(
+    parent_ranges: &[Range],
+    nodes: &[Node],
+    includes_children: bool,
+) -> Vec<Range> {
+    let mut result = Vec::new();
+    for node in nodes.iter() {
+        // Each node is intersected with all of the parent ranges, so that the nodes
+        // don't need to be in any particular order.
+        let mut parent_range_iter = parent_ranges.iter();
+        let mut parent_range = match parent_range_iter.next() {
+            Some(range) => range,
+            None => break,
+        };
+
+        let mut preceding_range = Range {
+            start_byte: 0,
+            start_point: Point::new(0, 0),
+            end_byte: node.start_byte(),
+            end_point: node.start_position(),
+        };
+        let following_range = Range {
+            start_byte: node.end_byte(),
+            start_point: node.end_position(),
+            end_byte: usize::MAX,
+            end_point: Point::new(usize::MAX, usize::MAX),
+        };
+
+        'excluded_ranges: for excluded_range in node
+            .children()
+            .filter_map(|child| {
+                if includes_children {
+                    None
+                } else {
+                    Some(child.range())
+                }
+            })
+            .chain([following_range].iter().cloned())
+        {
+            let mut range = Range {
+                start_byte: preceding_range.end_byte,
+                start_point: preceding_range.end_point,
+                end_byte: excluded_range.start_byte,
+                end_point: excluded_range.start_point,
+            };
+            preceding_range = excluded_range;
+
+            if range.end_byte < parent_range.start_byte {
+                continue;
+            }
+
+            while parent_range.start_byte <= range.end_byte {
+                if parent_range.end_byte > range.start_byte {
+                    if range.start_byte < parent_range.start_byte {
+                        range.start_byte = parent_range.start_byte;
+                        range.start_point = parent_range.start_point;
+                    }
+
+                    if parent_range.end_byte < range.end_byte {
+                        if range.start_byte < parent_range.end_byte {
+                            result.push(Range {
+                                start_byte: range.start_byte,
+                                start_point: range.start_point,
+                                end_byte: parent_range.end_byte,
+                                end_point: parent_range.end_point,
+                            });
+                        }
+                        range.start_byte = parent_range.end_byte;
+                        range.start_point = parent_range.end_point;
+                    } else {
+                        if range.start_byte < range.end_byte {
+                            result.push(range);
+                        }
+                        break;
+                    }
+                }
+
+                if let Some(next_range) = parent_range_iter.next() {
+                    parent_range = next_range;
+                } else {
+                    break 'excluded_ranges;
+                }
+            }
+        }
+    }
+    normalize_ranges(result)
+}
+
+// Sort the given ranges, and merge the ones that overlap or touch. The parser's behavior
+// is undefined for included ranges that are unsorted or that overlap.
+fn normalize_ranges(mut ranges: Vec<Range>) -> Vec<Range> {
+    ranges.sort_unstable_by_key(|range| (range.start_byte, range.end_byte));
+    let mut result: Vec<Range> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        if let Some(last) = result.last_mut() {
+            if range.start_byte <= last.end_byte {
+                if range.end_byte > last.end_byte {
+                    last.end_byte = range.end_byte;
+                    last.end_point = range.end_point;
+                }
+                continue;
+            }
+        }
+        result.push(range);
+    }
+    debug_assert!(
+        result
+            .iter()
+            .all(|range| range.start_byte < range.end_byte && range.start_point <= range.end_point),
+        "Included ranges should not be empty or reversed"
+    );
+    debug_assert!(
+        result
+            .windows(2)
+            .all(|pair| pair[0].end_byte < pair[1].start_byte
+                && pair[0].end_point <= pair[1].start_point),
+        "Included ranges should be sorted and disjoint"
+    );
+    result
+}
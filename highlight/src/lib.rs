@@ -1,17 +1,68 @@
+mod batch;
+#[cfg(feature = "c-api")]
 pub mod c_lib;
+#[cfg(feature = "fs")]
+mod language_dir;
+mod layer_ranges;
+mod lines;
+mod locals;
+mod pool;
+mod positions;
+mod query_layout;
+mod query_salvage;
+mod query_validation;
+mod registry;
+mod source;
+mod spans;
+pub mod stats;
+mod trees;
 pub mod util;
+#[cfg(feature = "fs")]
+mod watcher;
+pub use batch::HighlightBatch;
+#[cfg(feature = "c-api")]
 pub use c_lib as c;
-
+#[cfg(feature = "fs")]
+pub use language_dir::{query_paths_from_language_dir, LoadError};
+pub use layer_ranges::{intersect_ranges, LayerRangeMap};
+pub use lines::{HighlightedColumns, HighlightedLines};
+pub use locals::LocalBinding;
+pub use pool::{HighlightContextPool, PooledHighlightContext};
+pub use positions::HighlightPositions;
+pub use query_salvage::SkippedPattern;
+pub use query_validation::{PatternIssue, PatternIssueKind, QuerySection};
+pub use registry::LanguageRegistry;
+pub use source::HighlightSource;
+use source::SourceText;
+pub use spans::spans_from_events;
+pub use trees::LayerTrees;
+#[cfg(feature = "fs")]
+pub use watcher::ConfigurationWatcher;
+
+#[cfg(feature = "serde")]
+use serde_derive::{Deserialize, Serialize};
+use stats::{InjectionStats, ParseStats, PatternUsage};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::convert::Infallible;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::{iter, mem, ops, str, usize};
+use std::sync::Arc;
+#[cfg(feature = "timeout")]
+use std::time::Duration;
+use std::time::Instant;
+use std::{cmp, fmt, io, iter, mem, ops, ptr, str};
 use tree_sitter::{
-    Language, Node, Parser, Point, Query, QueryCaptures, QueryCursor, QueryError, Range, Tree,
+    Language, Node, Parser, Point, Query, QueryCaptures, QueryCursor, QueryError, QueryMatch,
+    Range, Tree,
 };
 
 const CANCELLATION_CHECK_INTERVAL: usize = 100;
+const DEFAULT_MAX_INJECTION_DEPTH: usize = 32;
+const BYTE_ORDER_MARK: &[u8] = b"\xEF\xBB\xBF";
 
 /// Indicates which highlight should be applied to a region of source code.
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Highlight(pub usize);
 
 /// Represents the reason why syntax highlighting failed.
@@ -20,14 +71,168 @@ pub enum Error {
     Cancelled,
     InvalidLanguage,
     Unknown,
+    /// A `Source` event passed to `HtmlRenderer::render` refers to a range that is not
+    /// within the source code, for example because the events were persisted and the
+    /// source code has changed since then.
+    InvalidSourceRange {
+        start: usize,
+        end: usize,
+    },
+    /// The source code is longer than the limit set with `HighlightOptions::max_source_bytes`.
+    SourceTooLarge,
+    /// One of the queries passed to `Highlighter::load_configuration` is invalid.
+    InvalidQuery(ConfigurationError),
+    /// The language was generated with a version of Tree-sitter whose ABI version is not
+    /// supported by this library. `expected` is the range of supported ABI versions.
+    IncompatibleLanguageVersion {
+        expected: ops::RangeInclusive<usize>,
+        actual: usize,
+    },
+}
+
+/// Represents the reason why `HtmlRenderer::try_render` failed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RenderError<E> {
+    /// The highlighting iterator returned an error, or an event was invalid.
+    Highlight(Error),
+    /// The attribute callback returned an error.
+    Attribute(E),
+}
+
+/// Represents the reason why `HtmlRenderer::render_to` failed.
+#[derive(Debug)]
+pub enum RenderToError {
+    /// The highlighting iterator returned an error, or an event was invalid.
+    Highlight(Error),
+    /// The HTML couldn't be written.
+    Io(io::Error),
+}
+
+/// Represents an error in one of the queries passed to `Highlighter::load_configuration`.
+/// The row of the query error is relative to the start of the query given by `section`,
+/// and so is the byte `offset` at which the error occurred. The `column` is zero-based.
+/// Predicate errors don't have a position, so their offset and column are zero.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ConfigurationError {
+    pub section: QuerySection,
+    pub error: QueryError,
+    pub offset: usize,
+    pub column: usize,
+}
+
+/// Represents the reason why a list of highlight names was rejected by
+/// `Highlighter::try_new`. The index refers to the list that was passed in.
+#[derive(Debug, PartialEq, Eq)]
+pub enum HighlightNamesError {
+    EmptyName(usize),
+}
+
+/// Determines how a UTF-8 byte order mark at the start of the source code is handled.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ByteOrderMarkHandling {
+    /// The byte order mark is parsed like any other text.
+    #[default]
+    Parse,
+    /// The byte order mark is skipped when parsing, but emitted offsets remain relative
+    /// to the start of the source, so the first `Source` event includes the byte order mark.
+    Skip,
+    /// The byte order mark is skipped when parsing, and emitted offsets are relative to
+    /// the end of the byte order mark.
+    SkipAndRebaseOffsets,
+}
+
+/// Determines how source code that is longer than the limit set with
+/// `HighlightOptions::max_source_bytes` is handled.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum OversizedSourceHandling {
+    /// Highlighting fails with `Error::SourceTooLarge` before anything is parsed.
+    #[default]
+    Reject,
+    /// Only the source code up to the limit is parsed and highlighted, and the rest is
+    /// emitted as a single `Source` event without any highlights. If the limit is within
+    /// a UTF-8 character, the source code is cut before that character.
+    Truncate,
+}
+
+/// Determines how `HtmlRenderer` renders the line endings of the source code.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum LineEndingHandling {
+    /// Each `\r\n` is rendered as `\n`.
+    #[default]
+    Normalize,
+    /// Each `\r\n` is rendered as `\r\n`, after the closing tags of the spans that are
+    /// open at the end of the line.
+    Preserve,
+}
+
+/// Configures a call to `Highlighter::highlight_with_options`.
+///
+/// If both a cancellation flag and a timeout are given, highlighting is cancelled as soon
+/// as either the flag is set or the timeout expires.
+#[derive(Clone, Debug, Default)]
+pub struct HighlightOptions<'a> {
+    cancellation_flag: Option<&'a AtomicUsize>,
+    #[cfg(feature = "timeout")]
+    timeout: Option<Duration>,
+    byte_range: Option<ops::Range<usize>>,
+    byte_order_mark_handling: ByteOrderMarkHandling,
+    max_source_bytes: Option<(usize, OversizedSourceHandling)>,
+    debug: bool,
+    unrecognized_capture_counter: Option<&'a AtomicUsize>,
+    error_highlight: Option<Highlight>,
+    injection_stats: bool,
+    pattern_usage: Option<&'a PatternUsage>,
+    previous_trees: Option<&'a LayerTrees>,
+    retain_trees: bool,
+    max_injection_depth: Option<usize>,
+    layer_events: Option<&'a str>,
+    lossy_local_names: bool,
 }
 
 /// Represents a single step in rendering a syntax-highlighted document.
-#[derive(Copy, Clone, Debug)]
+///
+/// With the `serde` feature, events can be serialized, so that they can be persisted
+/// and rendered later.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum HighlightEvent {
-    Source { start: usize, end: usize },
+    /// A range of the source code. Source events are never empty, and are never adjacent to
+    /// each other, except where a range is split by a `LayerStart` or `LayerEnd` event.
+    Source {
+        start: usize,
+        end: usize,
+    },
     HighlightStart(Highlight),
+    HighlightStartWithPattern {
+        highlight: Highlight,
+        pattern_index: usize,
+        pattern_offset: usize,
+    },
     HighlightEnd,
+    /// The start of a region that belongs to a language layer: either the whole document,
+    /// or one of the ranges of an injected language. These events are only emitted if
+    /// they are enabled with `HighlightOptions::layer_events`.
+    ///
+    /// The `language` is the name that was passed to `HighlightOptions::layer_events` for
+    /// the document, or the name that an injection was resolved with. The events of a
+    /// highlighting call share each name, so cloning them is cheap.
+    LayerStart {
+        language: Arc<str>,
+        depth: usize,
+    },
+    LayerEnd,
+}
+
+/// Describes an injected language that the injection callback of
+/// `Highlighter::highlight_with_injection_info` is asked to find.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Injection<'a> {
+    /// The name of the language, as determined by the injections query.
+    pub language: &'a str,
+    /// The text of the node captured as `@injection.site`, such as a whole `script`
+    /// element, from which details like its attributes can be read. This is `None` for
+    /// combined injections, which don't have a single site.
+    pub site: Option<&'a str>,
 }
 
 /// Contains the data neeeded to higlight code written in a particular language.
@@ -36,11 +241,19 @@ pub enum HighlightEvent {
 pub struct HighlightConfiguration {
     pub language: Language,
     pub query: Query,
+    names: Vec<String>,
     injections_query: Query,
+    query_source: String,
     locals_pattern_index: usize,
     highlights_pattern_index: usize,
+    locals_query_offset: usize,
+    highlights_query_offset: usize,
     highlight_indices: Vec<Option<Highlight>>,
+    strict_name_matching: bool,
     non_local_variable_patterns: Vec<bool>,
+    final_highlight_patterns: Vec<bool>,
+    highlight_priorities: Vec<i32>,
+    combined_injection_patterns: Vec<bool>,
     injection_site_capture_index: Option<u32>,
     injection_content_capture_index: Option<u32>,
     injection_language_capture_index: Option<u32>,
@@ -67,29 +280,62 @@ pub struct HighlightConfiguration {
 #[derive(Clone, Debug)]
 pub struct Highlighter {
     highlight_names: Vec<String>,
+    strict_name_matching: bool,
 }
 
 /// Carries the mutable state required for syntax highlighting.
 ///
 /// For the best performance `HighlightContext` values should be reused between
 /// syntax highlighting calls. A separate context is needed for each thread that
-/// is performing highlighting.
+/// is performing highlighting. Contexts can be moved between threads, and a
+/// `HighlightContextPool` can hand them out to the threads of a server.
 pub struct HighlightContext {
     parser: Parser,
     cursors: Vec<QueryCursor>,
+    injection_stats: Option<HashMap<String, InjectionStats>>,
+    parse_stats: ParseStats,
+    trees: Option<LayerTrees>,
 }
 
 /// Converts a general-purpose syntax highlighting iterator into a sequence of lines of HTML.
+///
+/// If the events include `LayerStart` and `LayerEnd` events, each layer's region is
+/// wrapped in a `span` with a `data-language` attribute that contains its language name.
 pub struct HtmlRenderer {
     pub html: Vec<u8>,
-    pub line_offsets: Vec<u32>,
+    pub line_offsets: Vec<usize>,
+    class_names: Vec<String>,
+    layer_attributes: Vec<Vec<u8>>,
+    line_ending_handling: LineEndingHandling,
+    pending_carriage_return: Option<usize>,
+    // Whether any source text has been written on the line that is being rendered, as
+    // opposed to only the tags of the spans that are reopened after a newline.
+    line_has_text: bool,
+    escapes: util::HtmlEscapes,
+    escaped_attribute: Option<String>,
+}
+
+// Receives the lines of HTML that `HtmlRenderer::render_to` writes.
+type LineSink<'a, E> = dyn FnMut(&str) -> Result<(), E> + 'a;
+
+// A highlight start event that hasn't been returned yet, along with the start, end and
+// layer depth of its range, and the pattern that produced it, if it is counted.
+type PendingHighlight = (HighlightEvent, (usize, usize, usize), Option<usize>);
+
+// A span that is open while rendering HTML: either a highlight, or a language layer, whose
+// attributes are stored in the renderer's `layer_attributes`.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum RenderedSpan {
+    Highlight(Highlight),
+    Layer(usize),
 }
 
 #[derive(Debug)]
 struct LocalDef<'a> {
-    name: &'a str,
+    name: Cow<'a, str>,
     value_range: ops::Range<usize>,
     highlight: Option<Highlight>,
+    binding: Option<usize>,
 }
 
 #[derive(Debug)]
@@ -101,29 +347,114 @@ struct LocalScope<'a> {
 
 struct HighlightIter<'a, F>
 where
-    F: Fn(&str) -> Option<&'a HighlightConfiguration> + 'a,
+    F: Fn(Injection) -> Option<&'a HighlightConfiguration> + 'a,
 {
-    source: &'a [u8],
+    source: SourceText<'a>,
     byte_offset: usize,
+    config: &'a HighlightConfiguration,
     context: &'a mut HighlightContext,
     injections_cursor: QueryCursor,
     injection_callback: F,
-    cancellation_flag: Option<&'a AtomicUsize>,
+    options: HighlightOptions<'a>,
+    deadline: Option<Instant>,
+    end_byte: usize,
     layers: Vec<HighlightIterLayer<'a>>,
+    next_layer_id: usize,
     iter_count: usize,
     next_event: Option<HighlightEvent>,
+    deferred_event: Option<HighlightEvent>,
+    pending_highlight: Option<PendingHighlight>,
     last_highlight_range: Option<(usize, usize, usize)>,
+    last_highlight_pattern: Option<usize>,
+    final_highlight_ranges: Vec<(usize, usize, usize)>,
+    layer_boundaries: Vec<LayerBoundary>,
+    layer_languages: Vec<Arc<str>>,
+    layer_events_offset: usize,
+    open_highlight_depths: Vec<usize>,
+    emitted_highlight_depth: usize,
+    held_event: Option<HighlightEvent>,
+    root_layer_state: RootLayerState,
+    local_bindings: Option<Vec<LocalBinding>>,
+    failed: bool,
+    after_source: bool,
 }
 
 struct HighlightIterLayer<'a> {
-    _tree: Tree,
+    tree: Tree,
     cursor: QueryCursor,
-    captures: iter::Peekable<QueryCaptures<'a, &'a [u8]>>,
+    captures: iter::Peekable<QueryCaptures<'a, Cow<'a, [u8]>>>,
     config: &'a HighlightConfiguration,
+    language_name: Cow<'a, str>,
+    parent: Option<LayerLanguage<'a>>,
     highlight_end_stack: Vec<usize>,
     scope_stack: Vec<LocalScope<'a>>,
+    error_ranges: Vec<ops::Range<usize>>,
+    replaced_ranges: Vec<ops::Range<usize>>,
     ranges: Vec<Range>,
+    combined_injections: Vec<(InjectionLanguage<'a>, Vec<Range>)>,
     depth: usize,
+    id: usize,
+}
+
+// The language of an injection. A language name is looked up with the injection callback,
+// but a pattern can also inject the language of the layer that contains the injection, or
+// the language of that layer's parent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum InjectionLanguage<'a> {
+    Name(Cow<'a, str>),
+    Current,
+    Parent,
+}
+
+// The configuration of a layer, along with the name of its language, which is reported in
+// `LayerStart` events.
+#[derive(Clone)]
+struct LayerLanguage<'a> {
+    config: &'a HighlightConfiguration,
+    name: Cow<'a, str>,
+}
+
+// The start or the end of one of the ranges of a layer, which is emitted as a `LayerStart`
+// or `LayerEnd` event once the highlight events have reached its offset.
+struct LayerBoundary {
+    offset: usize,
+    depth: usize,
+    language: Option<Arc<str>>,
+}
+
+impl LayerBoundary {
+    fn sort_key(&self) -> (usize, bool, isize) {
+        let depth = self.depth as isize;
+        match self.language {
+            Some(_) => (self.offset, true, depth),
+            None => (self.offset, false, -depth),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum RootLayerState {
+    NotStarted,
+    Started,
+    Ended,
+}
+
+// The settings of an injection pattern, which are read from its `set!` predicates.
+#[derive(Default)]
+struct InjectionSettings<'a> {
+    language: Option<&'a str>,
+    include_children: bool,
+    prefer_inner: bool,
+    replace: bool,
+    language_from_shebang: bool,
+    current_language: bool,
+    parent_language: bool,
+}
+
+impl Default for HighlightContext {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl HighlightContext {
@@ -131,14 +462,277 @@ impl HighlightContext {
         HighlightContext {
             parser: Parser::new(),
             cursors: Vec::new(),
+            injection_stats: None,
+            parse_stats: ParseStats::default(),
+            trees: None,
         }
     }
+
+    /// Returns the statistics about injected languages from the most recent highlighting
+    /// call that used this context, keyed by the name of the injected language. These are
+    /// only collected if they were enabled with `HighlightOptions::injection_stats`.
+    pub fn injection_stats(&self) -> Option<&HashMap<String, InjectionStats>> {
+        self.injection_stats.as_ref()
+    }
+
+    /// Returns the amount of parsing done by the most recent highlighting call that used
+    /// this context, across all of its layers. This is complete once the call's iterator
+    /// has finished.
+    pub fn parse_stats(&self) -> ParseStats {
+        self.parse_stats
+    }
+
+    /// Takes the syntax trees of the layers from the most recent highlighting call that
+    /// used this context. These are only kept if this was enabled with
+    /// `HighlightOptions::retain_trees`, and they are complete once the call's iterator has
+    /// finished, because injected layers are parsed as they are reached.
+    pub fn take_trees(&mut self) -> Option<LayerTrees> {
+        self.trees.take()
+    }
+}
+
+impl HighlightConfiguration {
+    /// Sets the names by which this configuration can be selected, such as the language
+    /// names used in injections. This is useful when several configurations share the
+    /// same `Language`. A `LanguageRegistry` makes the configuration available under
+    /// each of these names.
+    pub fn with_names(mut self, names: Vec<String>) -> Self {
+        self.names = names;
+        self
+    }
+
+    /// Returns the names by which this configuration can be selected.
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// Changes the highlight names that this configuration's captures are matched against,
+    /// such as when the user switches to a theme that recognizes different names. Only the
+    /// mapping from capture names to highlights is recomputed, so this is much faster than
+    /// loading the configuration again, which compiles its queries.
+    ///
+    /// The captures are matched in the same way as by the highlighter that loaded this
+    /// configuration. Afterwards, the `Highlight` values that are produced refer to the
+    /// given list, rather than to the names of that highlighter.
+    pub fn configure(&mut self, highlight_names: &[String]) {
+        self.highlight_indices = highlight_indices(
+            self.query.capture_names(),
+            highlight_names,
+            self.strict_name_matching,
+        );
+    }
+
+    /// Returns the capture names in this configuration's queries that don't match any of
+    /// the recognized highlight names, which are those of the highlighter, or those given
+    /// to `configure`. The names are listed in the order in which they first occur.
+    /// Capture names are matched in the same way as when highlighting: a capture name
+    /// matches a highlight name if it contains each of its dot-separated parts. So with
+    /// the highlight names `function` and `function.builtin`, the capture name
+    /// `function.bultin` still matches `function`, but `fuction.builtin` doesn't match
+    /// anything. The `injection.*` and `local.*` captures are used by the highlighter
+    /// itself, and are never reported.
+    ///
+    /// This is useful in a grammar's tests, to check that its queries only use the
+    /// highlight names that applications are expected to recognize.
+    pub fn nonconformant_capture_names(&self) -> Vec<&str> {
+        self.query
+            .capture_names()
+            .iter()
+            .zip(&self.highlight_indices)
+            .filter(|(name, highlight)| {
+                let prefix = name.split('.').next().unwrap_or_default();
+                highlight.is_none() && prefix != "injection" && prefix != "local"
+            })
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+}
+
+impl<'a> HighlightOptions<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets a flag that cancels highlighting when it is set to a non-zero value.
+    pub fn cancellation_flag(mut self, cancellation_flag: &'a AtomicUsize) -> Self {
+        self.cancellation_flag = Some(cancellation_flag);
+        self
+    }
+
+    /// Sets the maximum duration of highlighting, including parsing. When it expires,
+    /// highlighting is cancelled: the iterator returns a `Cancelled` error, and then ends.
+    /// The parse of each layer, including injected layers, is limited to the time that
+    /// remains, so a slow parse can't exceed the timeout either.
+    #[cfg(feature = "timeout")]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Restricts highlighting to the given range of bytes. The emitted events only cover
+    /// this range, but highlights that begin before the range are still started at the
+    /// beginning of the range, and highlights that end after it are ended at its end.
+    /// Injections whose content lies entirely outside of the range are not parsed.
+    pub fn byte_range(mut self, byte_range: ops::Range<usize>) -> Self {
+        self.byte_range = Some(byte_range);
+        self
+    }
+
+    /// Sets how a UTF-8 byte order mark at the start of the source code is handled. When
+    /// offsets are rebased, the byte range is also relative to the end of the byte order mark.
+    pub fn byte_order_mark_handling(mut self, handling: ByteOrderMarkHandling) -> Self {
+        self.byte_order_mark_handling = handling;
+        self
+    }
+
+    /// Limits the length of the source code that is highlighted, so that enormous documents
+    /// don't have to be parsed. The length is measured after any byte order mark whose
+    /// offsets are rebased has been removed.
+    pub fn max_source_bytes(mut self, max_bytes: usize, handling: OversizedSourceHandling) -> Self {
+        self.max_source_bytes = Some((max_bytes, handling));
+        self
+    }
+
+    /// Enables or disables query debugging. When enabled, highlights are started with
+    /// `HighlightStartWithPattern` events instead of `HighlightStart` events. These events
+    /// also contain the index of the query pattern that produced the highlight, and the
+    /// byte offset of that pattern within the highlights query. Local variable references
+    /// that are only highlighted because of their definition aren't produced by any
+    /// highlights pattern, so they are still started with `HighlightStart` events.
+    pub fn debug(mut self, debug: bool) -> Self {
+        self.debug = debug;
+        self
+    }
+
+    /// Sets a counter that is incremented for each highlight capture whose name doesn't
+    /// match any of the highlighter's recognized highlight names. Those captures don't
+    /// produce any events, so this is the only way to observe them. See the `stats` module.
+    pub fn unrecognized_capture_counter(mut self, counter: &'a AtomicUsize) -> Self {
+        self.unrecognized_capture_counter = Some(counter);
+        self
+    }
+
+    /// Enables highlighting of syntax errors with the given highlight, which is usually
+    /// the index of a highlight name like `error`. The range of each `ERROR` node is
+    /// highlighted, and so is the position of each `MISSING` node, using a highlight that
+    /// contains no source code. Highlights within `ERROR` nodes are nested inside of the
+    /// error highlight. This doesn't require any query patterns, and error highlights are
+    /// never started with `HighlightStartWithPattern` events.
+    pub fn error_highlight(mut self, highlight: Highlight) -> Self {
+        self.error_highlight = Some(highlight);
+        self
+    }
+
+    /// Enables or disables the collection of statistics about injected languages. When
+    /// enabled, the number of layers, the number of bytes and the parse time of each
+    /// injected language are recorded, and can be retrieved after highlighting using
+    /// `HighlightContext::injection_stats`. Parse times are only measured with the
+    /// `timeout` feature.
+    pub fn injection_stats(mut self, enabled: bool) -> Self {
+        self.injection_stats = enabled;
+        self
+    }
+
+    /// Sets a counter of the highlights that each pattern of the configuration produces.
+    /// The counter must have been created for the configuration that is passed to the
+    /// highlighter, so when it's used with `highlight_batch`, every document in the batch
+    /// must use that configuration. See `stats::PatternUsage`.
+    pub fn pattern_usage(mut self, usage: &'a PatternUsage) -> Self {
+        self.pattern_usage = Some(usage);
+        self
+    }
+
+    /// Sets the syntax trees from a previous highlighting call for the same document, so
+    /// that each layer whose tree can be matched up is parsed incrementally. The edits that
+    /// were made to the document since then must have been applied with `LayerTrees::edit`.
+    pub fn previous_trees(mut self, trees: &'a LayerTrees) -> Self {
+        self.previous_trees = Some(trees);
+        self
+    }
+
+    /// Enables or disables keeping the syntax trees of the layers, which can be retrieved
+    /// after highlighting using `HighlightContext::take_trees`.
+    pub fn retain_trees(mut self, enabled: bool) -> Self {
+        self.retain_trees = enabled;
+        self
+    }
+
+    /// Limits how deeply languages can be injected into each other. The document itself
+    /// has a depth of zero, the languages injected into it have a depth of one, and so on.
+    /// Injections that would be deeper than this are skipped, and counted in
+    /// `ParseStats::skipped_injection_count`. This guards against unbounded recursion,
+    /// such as a language that is injected into itself with the `injection.self` property.
+    /// The default limit is 32.
+    pub fn max_injection_depth(mut self, depth: usize) -> Self {
+        self.max_injection_depth = Some(depth);
+        self
+    }
+
+    /// Enables `LayerStart` and `LayerEnd` events, which surround the document and each
+    /// range of an injected language, so that a renderer can tell which language produced
+    /// each region. The document's own layer is reported with the given language name,
+    /// and each injected layer with the name that its injection was resolved with.
+    pub fn layer_events(mut self, language: &'a str) -> Self {
+        self.layer_events = Some(language);
+        self
+    }
+
+    /// Enables or disables resolving the names of local variables that contain invalid
+    /// UTF-8. By default, a definition or reference whose name isn't valid UTF-8 is
+    /// ignored by the locals query. When this is enabled, the invalid sequences in names
+    /// are replaced with U+FFFD, as with `util::LossyUtf8`, so that the references to a
+    /// definition still resolve. Names that differ only in their invalid sequences are
+    /// then treated as the same name.
+    pub fn lossy_local_names(mut self, enabled: bool) -> Self {
+        self.lossy_local_names = enabled;
+        self
+    }
 }
 
 impl Highlighter {
     /// Creates a highlighter with a given list of recognized highlight names.
+    ///
+    /// The list is used as-is, so that the indices of the names are preserved. If a name
+    /// occurs more than once, only its first occurrence is ever used. An empty name never
+    /// matches any capture. Use `try_new` to reject these lists instead.
     pub fn new(highlight_names: Vec<String>) -> Self {
-        Highlighter { highlight_names }
+        Highlighter {
+            highlight_names,
+            strict_name_matching: false,
+        }
+    }
+
+    /// Creates a highlighter with a given list of recognized highlight names, like `new`,
+    /// but which matches the names against capture names more strictly.
+    ///
+    /// By default, a highlight name matches a capture name if each of its dot-separated
+    /// parts occurs anywhere in the capture name, so `function.builtin` matches both
+    /// `@function.builtin.static` and `@builtin.function`. With strict matching, the parts
+    /// of the highlight name must instead be the leading parts of the capture name, in the
+    /// same order, so `function.builtin` only matches the first of these captures.
+    pub fn new_strict(highlight_names: Vec<String>) -> Self {
+        Highlighter {
+            highlight_names,
+            strict_name_matching: true,
+        }
+    }
+
+    /// Creates a highlighter with a given list of recognized highlight names, after
+    /// validating the list. Empty names are rejected. Duplicate names are removed, keeping
+    /// the first occurrence of each name, so the indices of the names that follow a
+    /// duplicate are shifted. The `Highlight` values that are produced refer to the list
+    /// returned by `names`.
+    pub fn try_new(highlight_names: Vec<String>) -> Result<Self, HighlightNamesError> {
+        let mut unique_names = Vec::<String>::with_capacity(highlight_names.len());
+        for (i, name) in highlight_names.into_iter().enumerate() {
+            if name.is_empty() {
+                return Err(HighlightNamesError::EmptyName(i));
+            }
+            if !unique_names.contains(&name) {
+                unique_names.push(name);
+            }
+        }
+        Ok(Self::new(unique_names))
     }
 
     /// Returns the list of highlight names with which this Highlighter was constructed.
@@ -153,20 +747,25 @@ impl Highlighter {
     ///
     /// * `language`  - The Tree-sitter `Language` that should be used for parsing.
     /// * `highlights_query` - A string containing tree patterns for syntax highlighting. This
-    ///   should be non-empty, otherwise no syntax highlights will be added.
+    ///   can be empty, in which case the configuration only injects other languages. If all
+    ///   three queries are empty, the document isn't parsed when it is highlighted.
     /// * `injections_query` -  A string containing tree patterns for injecting other languages
     ///   into the document. This can be empty if no injections are desired.
     /// * `locals_query` - A string containing tree patterns for tracking local variable
     ///   definitions and references. This can be empty if local variable tracking is not needed.
     ///
     /// Returns a `HighlightConfiguration` that can then be used with the `highlight` method.
+    /// Fails with `Error::InvalidQuery` if one of the queries is invalid, or with
+    /// `Error::IncompatibleLanguageVersion` if the language's ABI version is not supported.
     pub fn load_configuration(
         &self,
         language: Language,
         highlights_query: &str,
         injection_query: &str,
         locals_query: &str,
-    ) -> Result<HighlightConfiguration, QueryError> {
+    ) -> Result<HighlightConfiguration, Error> {
+        check_language_version(language)?;
+
         // Concatenate the query strings, keeping track of the start offset of each section.
         let mut query_source = String::new();
         query_source.push_str(injection_query);
@@ -175,10 +774,29 @@ impl Highlighter {
         let highlights_query_offset = query_source.len();
         query_source.push_str(highlights_query);
 
-        // Construct a query with the concatenated string.
-        let mut query = Query::new(language, &query_source)?;
-
-        let injections_query = Query::new(language, injection_query)?;
+        // Construct a query with the concatenated string. If it is invalid, then find
+        // the section that contains the error, so that the error's position can be
+        // reported relative to that section.
+        let sections = [
+            (QuerySection::Injections, injection_query, 0),
+            (QuerySection::Locals, locals_query, locals_query_offset),
+            (
+                QuerySection::Highlights,
+                highlights_query,
+                highlights_query_offset,
+            ),
+        ];
+        let mut query = Query::new(language, &query_source).map_err(|error| {
+            Error::InvalidQuery(section_error(language, &query_source, &sections, error))
+        })?;
+
+        let injections_query = Query::new(language, injection_query).map_err(|error| {
+            Error::InvalidQuery(ConfigurationError::new(
+                QuerySection::Injections,
+                injection_query,
+                error,
+            ))
+        })?;
         for injection_capture in injections_query.capture_names() {
             if injection_capture != "injection.site" {
                 query.disable_capture(injection_capture);
@@ -186,58 +804,59 @@ impl Highlighter {
         }
 
         // Determine the range of pattern indices that belong to each section of the query.
-        let mut locals_pattern_index = 0;
-        let mut highlights_pattern_index = 0;
-        for i in 0..(query.pattern_count()) {
-            let pattern_offset = query.start_byte_for_pattern(i);
-            if pattern_offset < highlights_query_offset {
-                if pattern_offset < highlights_query_offset {
-                    highlights_pattern_index += 1;
-                }
-                if pattern_offset < locals_query_offset {
-                    locals_pattern_index += 1;
-                }
-            }
-        }
+        // Patterns are numbered in the order in which they occur, so each section's first
+        // pattern index is the number of patterns that start before the section. An empty
+        // section starts at the same index as the section that follows it.
+        let pattern_count_before = |offset| {
+            (0..query.pattern_count())
+                .take_while(|i| query.start_byte_for_pattern(*i) < offset)
+                .count()
+        };
+        let locals_pattern_index = pattern_count_before(locals_query_offset);
+        let highlights_pattern_index = pattern_count_before(highlights_query_offset);
+
+        let highlight_indices = highlight_indices(
+            query.capture_names(),
+            &self.highlight_names,
+            self.strict_name_matching,
+        );
 
-        let mut capture_parts = Vec::new();
+        let non_local_variable_patterns = (0..query.pattern_count())
+            .map(|i| {
+                query
+                    .property_predicates(i)
+                    .iter()
+                    .any(|(prop, positive)| !*positive && prop.key.as_ref() == "local")
+            })
+            .collect();
 
-        // Compute a mapping from the query's capture ids to the indices of the highlighter's
-        // recognized highlight names.
-        let highlight_indices = query
-            .capture_names()
-            .iter()
-            .map(move |capture_name| {
-                capture_parts.clear();
-                capture_parts.extend(capture_name.split('.'));
-
-                let mut best_index = None;
-                let mut best_match_len = 0;
-                for (i, highlight_name) in self.highlight_names.iter().enumerate() {
-                    let mut len = 0;
-                    let mut matches = true;
-                    for part in highlight_name.split('.') {
-                        len += 1;
-                        if !capture_parts.contains(&part) {
-                            matches = false;
-                            break;
-                        }
-                    }
-                    if matches && len > best_match_len {
-                        best_index = Some(i);
-                        best_match_len = len;
-                    }
-                }
-                best_index.map(Highlight)
+        let final_highlight_patterns = (0..query.pattern_count())
+            .map(|i| {
+                query
+                    .property_settings(i)
+                    .iter()
+                    .any(|prop| prop.key.as_ref() == "highlight.final")
             })
             .collect();
 
-        let non_local_variable_patterns = (0..query.pattern_count())
+        // Patterns without a valid `priority` property have a priority of zero.
+        let highlight_priorities = (0..query.pattern_count())
             .map(|i| {
                 query
-                    .property_predicates(i)
+                    .property_settings(i)
                     .iter()
-                    .any(|(prop, positive)| !*positive && prop.key.as_ref() == "local")
+                    .find(|prop| prop.key.as_ref() == "priority")
+                    .and_then(|prop| prop.value.as_ref()?.parse().ok())
+                    .unwrap_or(0)
+            })
+            .collect();
+
+        let combined_injection_patterns = (0..locals_pattern_index)
+            .map(|i| {
+                query
+                    .property_settings(i)
+                    .iter()
+                    .any(|prop| prop.key.as_ref() == "injection.combined")
             })
             .collect();
 
@@ -265,11 +884,19 @@ impl Highlighter {
         Ok(HighlightConfiguration {
             language,
             query,
+            names: Vec::new(),
             injections_query,
+            query_source,
             locals_pattern_index,
             highlights_pattern_index,
+            locals_query_offset,
+            highlights_query_offset,
             highlight_indices,
+            strict_name_matching: self.strict_name_matching,
             non_local_variable_patterns,
+            final_highlight_patterns,
+            highlight_priorities,
+            combined_injection_patterns,
             injection_content_capture_index,
             injection_language_capture_index,
             injection_site_capture_index,
@@ -281,6 +908,12 @@ impl Highlighter {
     }
 
     /// Iterate over the highlighted regions for a given slice of source code.
+    ///
+    /// The order of the events is deterministic. When highlights from several language
+    /// layers start or end at the same position, the events from deeper layers come first,
+    /// and among layers of the same depth, the events from the layer that was injected
+    /// first come first. Layers injected at the same site are ordered by the index of
+    /// their injection pattern.
     pub fn highlight<'a>(
         &'a self,
         context: &'a mut HighlightContext,
@@ -289,56 +922,699 @@ impl Highlighter {
         cancellation_flag: Option<&'a AtomicUsize>,
         injection_callback: impl Fn(&str) -> Option<&'a HighlightConfiguration> + 'a,
     ) -> Result<impl Iterator<Item = Result<HighlightEvent, Error>> + 'a, Error> {
-        let layer = HighlightIterLayer::new(
+        let mut options = HighlightOptions::new();
+        if let Some(cancellation_flag) = cancellation_flag {
+            options = options.cancellation_flag(cancellation_flag);
+        }
+        self.highlight_with_options(context, config, source, options, injection_callback)
+    }
+
+    /// Iterate over the highlighted regions for a given slice of source code, using the
+    /// given `HighlightOptions`.
+    pub fn highlight_with_options<'a>(
+        &'a self,
+        context: &'a mut HighlightContext,
+        config: &'a HighlightConfiguration,
+        source: &'a [u8],
+        options: HighlightOptions<'a>,
+        injection_callback: impl Fn(&str) -> Option<&'a HighlightConfiguration> + 'a,
+    ) -> Result<impl Iterator<Item = Result<HighlightEvent, Error>> + 'a, Error> {
+        self.highlight_with_injection_info(context, config, source, options, move |injection| {
+            injection_callback(injection.language)
+        })
+    }
+
+    /// Iterate over the highlighted regions for a given slice of source code, like
+    /// `highlight_with_options`, but with an injection callback that receives more than
+    /// the name of each injected language. This is useful when the name alone doesn't
+    /// identify the language, such as a MIME type in an attribute of the injection site.
+    pub fn highlight_with_injection_info<'a>(
+        &'a self,
+        context: &'a mut HighlightContext,
+        config: &'a HighlightConfiguration,
+        source: &'a [u8],
+        options: HighlightOptions<'a>,
+        injection_callback: impl Fn(Injection) -> Option<&'a HighlightConfiguration> + 'a,
+    ) -> Result<impl Iterator<Item = Result<HighlightEvent, Error>> + 'a, Error> {
+        self.highlight_iter(
+            context,
             config,
-            source,
+            SourceText::Bytes(source),
+            options,
+            injection_callback,
+        )
+    }
+
+    /// Iterate over the highlighted regions of source code that isn't necessarily
+    /// contiguous in memory, such as the text of an editor's rope. The source code is read
+    /// one chunk at a time, and text that spans several chunks, like the name of a local
+    /// variable, is only copied when it is needed.
+    pub fn highlight_chunks<'a>(
+        &'a self,
+        context: &'a mut HighlightContext,
+        config: &'a HighlightConfiguration,
+        source: &'a dyn HighlightSource,
+        options: HighlightOptions<'a>,
+        injection_callback: impl Fn(&str) -> Option<&'a HighlightConfiguration> + 'a,
+    ) -> Result<impl Iterator<Item = Result<HighlightEvent, Error>> + 'a, Error> {
+        self.highlight_iter(
             context,
-            cancellation_flag,
-            0,
-            vec![Range {
-                start_byte: 0,
-                end_byte: usize::MAX,
-                start_point: Point::new(0, 0),
-                end_point: Point::new(usize::MAX, usize::MAX),
-            }],
-        )?;
+            config,
+            SourceText::chunks(source),
+            options,
+            move |injection| injection_callback(injection.language),
+        )
+    }
 
-        let injections_cursor = context.cursors.pop().unwrap_or(QueryCursor::new());
+    fn highlight_iter<'a, F>(
+        &'a self,
+        context: &'a mut HighlightContext,
+        config: &'a HighlightConfiguration,
+        source: SourceText<'a>,
+        options: HighlightOptions<'a>,
+        injection_callback: F,
+    ) -> Result<HighlightIter<'a, F>, Error>
+    where
+        F: Fn(Injection) -> Option<&'a HighlightConfiguration> + 'a,
+    {
+        // Reading the clock isn't supported on every target, so without the `timeout`
+        // feature there is never a deadline.
+        #[cfg(feature = "timeout")]
+        let deadline = options.timeout.map(|timeout| Instant::now() + timeout);
+        #[cfg(not(feature = "timeout"))]
+        let deadline = None;
+
+        let mut source = source;
+        let mut start_byte = 0;
+        if source.text(0..BYTE_ORDER_MARK.len()) == BYTE_ORDER_MARK {
+            match options.byte_order_mark_handling {
+                ByteOrderMarkHandling::Parse => {}
+                ByteOrderMarkHandling::Skip => start_byte = BYTE_ORDER_MARK.len(),
+                ByteOrderMarkHandling::SkipAndRebaseOffsets => {
+                    source = source.slice(BYTE_ORDER_MARK.len()..source.len())
+                }
+            }
+        }
+
+        // Only the part of the source code that is parsed is passed to the layers. The
+        // offsets of the events still cover the whole source code.
+        let source_len = source.len();
+        if let Some((max_bytes, handling)) = options.max_source_bytes {
+            if source.len() > max_bytes {
+                match handling {
+                    OversizedSourceHandling::Reject => return Err(Error::SourceTooLarge),
+                    OversizedSourceHandling::Truncate => {
+                        source = source.slice(0..utf8_char_boundary(source, max_bytes))
+                    }
+                }
+            }
+        }
+
+        context.parse_stats = ParseStats::default();
+        context.injection_stats = if options.injection_stats {
+            Some(HashMap::new())
+        } else {
+            None
+        };
+        context.trees = if options.retain_trees {
+            Some(LayerTrees::new())
+        } else {
+            None
+        };
 
-        Ok(HighlightIter {
+        let (byte_offset, end_byte) = match &options.byte_range {
+            Some(range) => {
+                let end = cmp::min(range.end, source_len);
+                (cmp::min(range.start, end), end)
+            }
+            None => (0, source_len),
+        };
+
+        let root = LayerLanguage {
+            config,
+            name: Cow::Borrowed(options.layer_events.unwrap_or("")),
+        };
+
+        // A configuration without any patterns can't produce any highlights or injections,
+        // so unless something else needs the document's syntax tree, it isn't parsed at all,
+        // and the whole document is emitted as source code.
+        let mut layers = Vec::new();
+        let mut combined_injections = Vec::new();
+        if config.query.pattern_count() > 0
+            || options.error_highlight.is_some()
+            || options.retain_trees
+            || options.layer_events.is_some()
+        {
+            let mut layer = HighlightIterLayer::new(
+                config,
+                source,
+                context,
+                &options,
+                deadline,
+                0,
+                vec![Range {
+                    start_byte,
+                    end_byte: usize::MAX,
+                    start_point: Point::new(0, start_byte),
+                    end_point: Point::new(usize::MAX, usize::MAX),
+                }],
+            )?;
+            if let Some(trees) = &mut context.trees {
+                trees.push(config.language, None, 0, &layer.ranges, layer.tree.clone());
+            }
+            layer.language_name = root.name.clone();
+            combined_injections = mem::take(&mut layer.combined_injections);
+            layers.push(layer);
+        }
+
+        let mut injections_cursor = context.cursors.pop().unwrap_or(QueryCursor::new());
+        injections_cursor.set_byte_range(0, usize::MAX);
+
+        let layer_languages = options.layer_events.map(Arc::from).into_iter().collect();
+        let mut result = HighlightIter {
             source,
-            byte_offset: 0,
+            byte_offset,
+            config,
             injection_callback,
-            cancellation_flag,
+            options,
+            deadline,
+            end_byte,
             injections_cursor,
             context,
             iter_count: 0,
-            layers: vec![layer],
+            layers,
+            next_layer_id: 1,
             next_event: None,
+            deferred_event: None,
+            pending_highlight: None,
             last_highlight_range: None,
+            last_highlight_pattern: None,
+            final_highlight_ranges: Vec::new(),
+            layer_boundaries: Vec::new(),
+            layer_languages,
+            layer_events_offset: byte_offset,
+            open_highlight_depths: Vec::new(),
+            emitted_highlight_depth: 0,
+            held_event: None,
+            root_layer_state: RootLayerState::NotStarted,
+            local_bindings: None,
+            failed: false,
+            after_source: false,
+        };
+        if !combined_injections.is_empty() {
+            result.add_combined_injections(combined_injections, root, None, 1)?;
+            result.sort_layers();
+        }
+        Ok(result)
+    }
+}
+
+// Compute a mapping from a query's capture ids to the indices of the recognized highlight
+// names. Each capture is given the matching highlight name with the most parts.
+fn highlight_indices(
+    capture_names: &[String],
+    highlight_names: &[String],
+    strict_name_matching: bool,
+) -> Vec<Option<Highlight>> {
+    let mut capture_parts = Vec::new();
+    capture_names
+        .iter()
+        .map(move |capture_name| {
+            capture_parts.clear();
+            capture_parts.extend(capture_name.split('.'));
+
+            let mut best_index = None;
+            let mut best_match_len = 0;
+            for (i, highlight_name) in highlight_names.iter().enumerate() {
+                let mut len = 0;
+                let mut matches = true;
+                for part in highlight_name.split('.') {
+                    let found = if strict_name_matching {
+                        capture_parts.get(len) == Some(&part)
+                    } else {
+                        capture_parts.contains(&part)
+                    };
+                    len += 1;
+                    if !found {
+                        matches = false;
+                        break;
+                    }
+                }
+                // Among equally specific highlight names, the one that occurs first in
+                // the list is chosen.
+                if matches && len > best_match_len {
+                    best_index = Some(i);
+                    best_match_len = len;
+                }
+            }
+            best_index.map(Highlight)
         })
+        .collect()
+}
+
+// Find the largest offset that is at most `offset` and is not within a UTF-8 character.
+fn utf8_char_boundary(source: SourceText, mut offset: usize) -> usize {
+    while offset > 0 && matches!(source.byte(offset), Some(c) if c & 0b1100_0000 == 0b1000_0000) {
+        offset -= 1;
+    }
+    offset
+}
+
+// Find the section of the concatenated query that caused an error. Each section is
+// compiled on its own, so that the error is reported relative to that section. If
+// each section is valid on its own, then the section is determined by the row at
+// which the error occurred in the concatenated query.
+fn section_error(
+    language: Language,
+    query_source: &str,
+    sections: &[(QuerySection, &str, usize)],
+    error: QueryError,
+) -> ConfigurationError {
+    for (section, source, _) in sections {
+        if let Err(error) = Query::new(language, source) {
+            return ConfigurationError::new(*section, source, error);
+        }
+    }
+
+    let row = match &error {
+        QueryError::Syntax(row, _)
+        | QueryError::NodeType(row, _)
+        | QueryError::Field(row, _)
+        | QueryError::Capture(row, _) => *row,
+        QueryError::Predicate(_) => 1,
+    };
+    let (section, source, start_row) = sections
+        .iter()
+        .map(|(section, source, offset)| {
+            let start_row = query_source[..*offset].matches('\n').count() + 1;
+            (*section, *source, start_row)
+        })
+        .rev()
+        .find(|(_, _, start_row)| *start_row <= row)
+        .unwrap();
+    let row = row + 1 - start_row;
+    let error = match error {
+        QueryError::Syntax(_, message) => QueryError::Syntax(row, message),
+        QueryError::NodeType(_, name) => QueryError::NodeType(row, name),
+        QueryError::Field(_, name) => QueryError::Field(row, name),
+        QueryError::Capture(_, name) => QueryError::Capture(row, name),
+        QueryError::Predicate(message) => QueryError::Predicate(message),
+    };
+    ConfigurationError::new(section, source, error)
+}
+
+// A parser can't use a language whose ABI version is not supported, so a configuration
+// for that language is rejected before its queries are compiled.
+pub(crate) fn check_language_version(language: Language) -> Result<(), Error> {
+    let expected = tree_sitter::MIN_COMPATIBLE_LANGUAGE_VERSION..=tree_sitter::LANGUAGE_VERSION;
+    let actual = language.version();
+    if expected.contains(&actual) {
+        Ok(())
+    } else {
+        Err(Error::IncompatibleLanguageVersion { expected, actual })
+    }
+}
+
+// Find the language name of an injection in the text of a capture that is referenced by
+// an `injection.language` property, like `(set! injection.language "@lang")`. If there
+// are several such properties, the first one whose capture is present in the match is used.
+fn injection_language_from_capture<'a>(
+    query: &Query,
+    mat: &QueryMatch,
+    source: SourceText<'a>,
+) -> Option<Cow<'a, str>> {
+    query
+        .property_settings(mat.pattern_index)
+        .iter()
+        .filter(|prop| prop.key.as_ref() == "injection.language")
+        .filter_map(|prop| prop.value.as_ref()?.strip_prefix('@'))
+        .filter_map(|name| query.capture_names().iter().position(|n| n == name))
+        .find_map(|index| {
+            mat.captures
+                .iter()
+                .find(|capture| capture.index as usize == index)
+        })
+        .and_then(|capture| source.str(capture.node.byte_range()))
+}
+
+// Read the settings of an injection pattern from its `set!` predicates.
+fn injection_settings(query: &Query, pattern_index: usize) -> InjectionSettings<'_> {
+    let mut settings = InjectionSettings::default();
+    for prop in query.property_settings(pattern_index) {
+        match prop.key.as_ref() {
+            // In addition to specifying the language name via the text of a captured node,
+            // it can also be hard-coded via a `set!` predicate that sets the
+            // injection.language key. Values that refer to captures are handled by
+            // `injection_language_from_capture`.
+            "injection.language" if settings.language.is_none() => {
+                settings.language = prop
+                    .value
+                    .as_ref()
+                    .map(|s| s.as_ref())
+                    .filter(|s: &&str| !s.starts_with('@'))
+            }
+
+            // By default, injections do not include the *children* of an
+            // `injection.content` node - only the ranges that belong to the node itself.
+            // This can be changed using a `set!` predicate that sets the
+            // `injection.include-children` key.
+            "injection.include-children" => settings.include_children = true,
+
+            // When one content node contains another, only the outer node is used, so that
+            // the included ranges don't overlap. The inner node can be used instead by
+            // setting the `injection.prefer-inner` key.
+            "injection.prefer-inner" => settings.prefer_inner = true,
+
+            // The highlights of the injected language are normally combined with those of
+            // the layer that contains the injection. With the `injection.replace` key, the
+            // containing layer's highlights within the injection's ranges are skipped.
+            "injection.replace" => settings.replace = true,
+
+            // Scripts often declare their language with a `#!` line, which is used when
+            // the `injection.language-from-shebang` key is set and no language name was
+            // captured. See `language_from_shebang`.
+            "injection.language-from-shebang" => settings.language_from_shebang = true,
+
+            // A language can be injected into itself, like the code in a Rust macro, or
+            // into a language that it is injected into, like a template language. These
+            // languages are used directly, without calling the injection callback.
+            "injection.self" => settings.current_language = true,
+            "injection.parent" => settings.parent_language = true,
+            _ => {}
+        }
+    }
+    settings
+}
+
+impl<'a> InjectionSettings<'a> {
+    // Determine the language of an injection, given the language name that was found in
+    // the captures of its match, if any.
+    fn injection_language(
+        &self,
+        captured_name: Option<Cow<'a, str>>,
+        source: SourceText<'a>,
+        content_nodes: &[Node],
+    ) -> Option<InjectionLanguage<'a>> {
+        if self.current_language {
+            return Some(InjectionLanguage::Current);
+        }
+        if self.parent_language {
+            return Some(InjectionLanguage::Parent);
+        }
+        let mut name = captured_name;
+        if name.is_none() && self.language_from_shebang {
+            name = language_from_shebang(source, content_nodes);
+        }
+        name.or_else(|| self.language.map(Cow::Borrowed))
+            .map(InjectionLanguage::Name)
+    }
+}
+
+// Find the name of the interpreter in a `#!` line at the start of an injection's content,
+// after any whitespace. The directory and a trailing version number are removed from the
+// interpreter's name, and if the interpreter is run with `env`, the name of the program
+// that `env` runs is used instead, so both `#!/bin/bash` and `#!/usr/bin/env python3`
+// are recognized, as `bash` and `python`.
+fn language_from_shebang<'a>(
+    source: SourceText<'a>,
+    content_nodes: &[Node],
+) -> Option<Cow<'a, str>> {
+    let start = content_nodes.iter().map(|node| node.start_byte()).min()?;
+    match source.line_after_whitespace(start) {
+        Cow::Borrowed(line) => shebang_interpreter(line).map(Cow::Borrowed),
+        Cow::Owned(line) => shebang_interpreter(&line).map(|name| Cow::Owned(name.to_string())),
+    }
+}
+
+fn shebang_interpreter(line: &[u8]) -> Option<&str> {
+    let line = str::from_utf8(line.strip_prefix(b"#!")?).ok()?;
+    let mut words = line.split_whitespace();
+    let mut name = words.next()?.rsplit('/').next()?;
+    if name == "env" {
+        name = words.find(|word| !word.starts_with('-') && !word.contains('='))?;
+    }
+    let name = name.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+// Determine whether none of an injection's content nodes overlap the byte range that is
+// being highlighted, in which case the injection doesn't need to be parsed.
+fn outside_byte_range(byte_range: &Option<ops::Range<usize>>, content_nodes: &[Node]) -> bool {
+    match byte_range {
+        Some(range) => !content_nodes
+            .iter()
+            .any(|node| node.end_byte() > range.start && node.start_byte() < range.end),
+        None => false,
+    }
+}
+
+// Collect the ranges of the `ERROR` and `MISSING` nodes within the given node that
+// intersect the given byte range, in the order in which they occur. Nodes that are
+// nested inside of `ERROR` nodes are not collected.
+fn collect_error_ranges(
+    node: Node,
+    byte_range: &ops::Range<usize>,
+    error_ranges: &mut Vec<ops::Range<usize>>,
+) {
+    // The tree is walked with a cursor, because accessing the children of a node by index
+    // takes time proportional to the index, and a tree with many errors can have a node
+    // with a very large number of children.
+    let mut cursor = node.walk();
+    loop {
+        let node = cursor.node();
+        let range = node.byte_range();
+        let descend = if range.start > byte_range.end
+            || (range.end <= byte_range.start && range.start < range.end)
+        {
+            false
+        } else if node.is_error() || node.is_missing() {
+            error_ranges.push(range);
+            false
+        } else {
+            node.has_error()
+        };
+        if descend && cursor.goto_first_child() {
+            continue;
+        }
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                return;
+            }
+        }
+    }
+}
+
+impl ConfigurationError {
+    // The query error only contains the row of the error, so its column is found in the
+    // text of that row. The message of a syntax error points to the column with a caret,
+    // and the other kinds of errors occur at the start of the name that they contain.
+    pub(crate) fn new(section: QuerySection, query: &str, error: QueryError) -> Self {
+        let row = match &error {
+            QueryError::Syntax(row, _)
+            | QueryError::NodeType(row, _)
+            | QueryError::Field(row, _)
+            | QueryError::Capture(row, _) => *row,
+            QueryError::Predicate(_) => {
+                return ConfigurationError {
+                    section,
+                    error,
+                    offset: 0,
+                    column: 0,
+                }
+            }
+        };
+        let line_start = query
+            .split('\n')
+            .take(row.saturating_sub(1))
+            .map(|line| line.len() + 1)
+            .sum::<usize>()
+            .min(query.len());
+        let line = query[line_start..].split('\n').next().unwrap_or("");
+        let column = match &error {
+            QueryError::Syntax(_, message) => message
+                .lines()
+                .nth(1)
+                .and_then(|caret_line| caret_line.find('^')),
+            QueryError::NodeType(_, name)
+            | QueryError::Field(_, name)
+            | QueryError::Capture(_, name) => {
+                let is_name_char = |c: char| c.is_alphanumeric() || c == '_' || c == '-';
+                line.match_indices(name.as_str()).map(|(i, _)| i).find(|i| {
+                    !line[..*i].ends_with(is_name_char)
+                        && !line[*i + name.len()..].starts_with(is_name_char)
+                })
+            }
+            QueryError::Predicate(_) => None,
+        }
+        .unwrap_or(0)
+        .min(line.len());
+        ConfigurationError {
+            section,
+            error,
+            offset: line_start + column,
+            column,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Cancelled => write!(f, "Highlighting was cancelled"),
+            Error::InvalidLanguage => write!(f, "Invalid language"),
+            Error::Unknown => write!(f, "Unknown error"),
+            Error::InvalidSourceRange { start, end } => write!(
+                f,
+                "Source range {}..{} is not within the source code",
+                start, end
+            ),
+            Error::SourceTooLarge => write!(f, "Source code is too large"),
+            Error::InvalidQuery(error) => write!(f, "{}", error),
+            Error::IncompatibleLanguageVersion { expected, actual } => write!(
+                f,
+                "Incompatible language version {}. Expected minimum {}, maximum {}",
+                actual,
+                expected.start(),
+                expected.end()
+            ),
+        }
+    }
+}
+
+impl fmt::Display for HighlightNamesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HighlightNamesError::EmptyName(index) => {
+                write!(f, "Highlight name at index {} is empty", index)
+            }
+        }
+    }
+}
+
+impl fmt::Display for ConfigurationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let section = match self.section {
+            QuerySection::Injections => "injections",
+            QuerySection::Locals => "locals",
+            QuerySection::Highlights => "highlights",
+        };
+        match &self.error {
+            QueryError::Syntax(row, message) => write!(
+                f,
+                "Invalid syntax on line {} of the {} query:\n{}",
+                row, section, message
+            ),
+            QueryError::NodeType(row, name) => write!(
+                f,
+                "Invalid node type {} on line {} of the {} query",
+                name, row, section
+            ),
+            QueryError::Field(row, name) => write!(
+                f,
+                "Invalid field name {} on line {} of the {} query",
+                name, row, section
+            ),
+            QueryError::Capture(row, name) => write!(
+                f,
+                "Invalid capture name {} on line {} of the {} query",
+                name, row, section
+            ),
+            QueryError::Predicate(message) => {
+                write!(f, "Invalid predicate in the {} query: {}", section, message)
+            }
+        }
     }
 }
 
 impl<'a> HighlightIterLayer<'a> {
     fn new(
         config: &'a HighlightConfiguration,
-        source: &'a [u8],
+        source: SourceText<'a>,
         context: &mut HighlightContext,
-        cancellation_flag: Option<&'a AtomicUsize>,
+        options: &HighlightOptions<'a>,
+        deadline: Option<Instant>,
         depth: usize,
         ranges: Vec<Range>,
     ) -> Result<Self, Error> {
-        context
-            .parser
-            .set_language(config.language)
-            .map_err(|_| Error::InvalidLanguage)?;
-        unsafe { context.parser.set_cancellation_flag(cancellation_flag) };
+        debug_assert!(
+            !ranges.is_empty(),
+            "Layers should only be constructed with non-empty ranges vectors"
+        );
+        // Setting the language recreates the external scanner, so it is only done when the
+        // language changes. Otherwise, the parser is reset, so that a parse that was
+        // cancelled is not resumed.
+        if context.parser.language() == Some(config.language) {
+            context.parser.reset();
+        } else {
+            context
+                .parser
+                .set_language(config.language)
+                .map_err(|_| Error::InvalidLanguage)?;
+        }
+        unsafe {
+            context
+                .parser
+                .set_cancellation_flag(options.cancellation_flag)
+        };
+
+        // The parser's timeout is the time remaining until the deadline. A timeout of
+        // zero would disable the timeout, so it is at least one microsecond.
+        let timeout_micros = match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(Error::Cancelled);
+                }
+                cmp::max((deadline - now).as_micros() as u64, 1)
+            }
+            None => 0,
+        };
+        context.parser.set_timeout_micros(timeout_micros);
 
         context.parser.set_included_ranges(&ranges);
 
-        let tree = context.parser.parse(source, None).ok_or(Error::Cancelled)?;
+        let old_tree = options
+            .previous_trees
+            .and_then(|trees| trees.find(config.language, depth, &ranges));
+        context.parse_stats.parse_count += 1;
+        if old_tree.is_some() {
+            context.parse_stats.reused_tree_count += 1;
+        }
+        for range in &ranges {
+            let end_byte = cmp::min(range.end_byte, source.len());
+            context.parse_stats.parsed_bytes += end_byte.saturating_sub(range.start_byte);
+        }
+        let tree = context
+            .parser
+            .parse_with(&mut |offset, _| source.chunk_at(offset), old_tree)
+            .ok_or(Error::Cancelled)?;
+        context.parse_stats.layer_count += 1;
+        if tree.root_node().has_error() {
+            let mut all_error_ranges = Vec::new();
+            collect_error_ranges(tree.root_node(), &(0..usize::MAX), &mut all_error_ranges);
+            context.parse_stats.error_count += all_error_ranges.len();
+            if depth == 0 {
+                context.parse_stats.root_has_error = true;
+            }
+        }
+        // The query only needs to visit the part of the tree that lies within the layer's
+        // ranges, and within the byte range that is being highlighted.
         let mut cursor = context.cursors.pop().unwrap_or(QueryCursor::new());
+        let mut start_byte = ranges.iter().map(|r| r.start_byte).min().unwrap_or(0);
+        let mut end_byte = ranges
+            .iter()
+            .map(|r| r.end_byte)
+            .max()
+            .unwrap_or(usize::MAX);
+        if let Some(range) = &options.byte_range {
+            start_byte = cmp::max(start_byte, range.start);
+            end_byte = cmp::max(cmp::min(end_byte, range.end), start_byte);
+        }
+        cursor.set_byte_range(start_byte, end_byte);
 
         // The `captures` iterator borrows the `Tree` and the `QueryCursor`, which
         // prevents them from being moved. But both of these values are really just
@@ -347,137 +1623,160 @@ impl<'a> HighlightIterLayer<'a> {
         let cursor_ref = unsafe { mem::transmute::<_, &'static mut QueryCursor>(&mut cursor) };
         let captures = cursor_ref
             .captures(&config.query, tree_ref.root_node(), move |n| {
-                &source[n.byte_range()]
+                source.text(n.byte_range())
             })
             .peekable();
 
+        // The content of all of the matches of a combined injection pattern is parsed as a
+        // single layer, so these injections are found in the whole tree up front, rather
+        // than when their sites are reached.
+        let mut combined_injections = Vec::new();
+        let mut replaced_ranges = Vec::new();
+        if config.combined_injection_patterns.contains(&true) {
+            let mut injections = Vec::<(usize, Option<Cow<'a, str>>, Vec<Node>)>::new();
+            let mut injections_cursor = context.cursors.pop().unwrap_or(QueryCursor::new());
+            injections_cursor.set_byte_range(0, usize::MAX);
+            for mat in injections_cursor.matches(&config.injections_query, tree.root_node(), |n| {
+                source.text(n.byte_range())
+            }) {
+                if !config.combined_injection_patterns[mat.pattern_index] {
+                    continue;
+                }
+                let entry =
+                    if let Some(entry) = injections.iter_mut().find(|e| e.0 == mat.pattern_index) {
+                        entry
+                    } else {
+                        injections.push((mat.pattern_index, None, Vec::new()));
+                        injections.last_mut().unwrap()
+                    };
+                for capture in mat.captures {
+                    let index = Some(capture.index);
+                    if index == config.injection_language_capture_index && entry.1.is_none() {
+                        entry.1 = source.str(capture.node.byte_range());
+                    } else if index == config.injection_content_capture_index {
+                        entry.2.push(capture.node);
+                    }
+                }
+                if entry.1.is_none() {
+                    entry.1 =
+                        injection_language_from_capture(&config.injections_query, &mat, source);
+                }
+            }
+            context.cursors.push(injections_cursor);
+
+            injections.sort_unstable_by_key(|(pattern_index, ..)| *pattern_index);
+            for (pattern_index, language, content_nodes) in injections {
+                if outside_byte_range(&options.byte_range, &content_nodes) {
+                    continue;
+                }
+                let settings = injection_settings(&config.query, pattern_index);
+                if let Some(language) =
+                    settings.injection_language(language, source, &content_nodes)
+                {
+                    let content_ranges = content_nodes
+                        .iter()
+                        .map(|node| node.byte_range())
+                        .collect::<Vec<_>>();
+                    let content_nodes =
+                        util::non_overlapping_ranges(&content_ranges, settings.prefer_inner)
+                            .into_iter()
+                            .map(|i| content_nodes[i])
+                            .collect::<Vec<_>>();
+                    let injection_ranges =
+                        intersect_ranges(&ranges, &content_nodes, settings.include_children);
+                    if !injection_ranges.is_empty() && injection_ranges != ranges {
+                        if settings.replace {
+                            replaced_ranges
+                                .extend(injection_ranges.iter().map(|r| r.start_byte..r.end_byte));
+                        }
+                        combined_injections.push((language, injection_ranges));
+                    }
+                }
+            }
+        }
+
+        let mut error_ranges = Vec::new();
+        if options.error_highlight.is_some() && tree.root_node().has_error() {
+            let byte_range = options.byte_range.clone().unwrap_or(0..usize::MAX);
+            collect_error_ranges(tree.root_node(), &byte_range, &mut error_ranges);
+            error_ranges.reverse();
+        }
+
         Ok(HighlightIterLayer {
             highlight_end_stack: Vec::new(),
+            error_ranges,
             scope_stack: vec![LocalScope {
                 inherits: false,
                 range: 0..usize::MAX,
                 local_defs: Vec::new(),
             }],
+            replaced_ranges,
             cursor,
             depth,
-            _tree: tree,
+            id: 0,
+            tree,
             captures,
             config,
+            language_name: Cow::Borrowed(""),
+            parent: None,
             ranges,
+            combined_injections,
         })
     }
 
-    // Compute the ranges that should be included when parsing an injection.
-    // This takes into account three things:
-    // * `parent_ranges` - The new injection may be nested inside of *another* injection
-    //   (e.g. JavaScript within HTML within ERB). The parent injection's ranges must
-    //   be taken into account.
-    // * `nodes` - Every injection takes place within a set of nodes. The injection ranges
-    //   are the ranges of those nodes.
-    // * `includes_children` - For some injections, the content nodes' children should be
-    //   excluded from the nested document, so that only the content nodes' *own* content
-    //   is reparsed. For other injections, the content nodes' entire ranges should be
-    //   reparsed, including the ranges of their children.
-    fn intersect_ranges(&self, nodes: &Vec<Node>, includes_children: bool) -> Vec<Range> {
-        let mut result = Vec::new();
-        let mut parent_range_iter = self.ranges.iter();
-        let mut parent_range = parent_range_iter
-            .next()
-            .expect("Layers should only be constructed with non-empty ranges vectors");
-        for node in nodes.iter() {
-            let mut preceding_range = Range {
-                start_byte: 0,
-                start_point: Point::new(0, 0),
-                end_byte: node.start_byte(),
-                end_point: node.start_position(),
-            };
-            let following_range = Range {
-                start_byte: node.end_byte(),
-                start_point: node.end_position(),
-                end_byte: usize::MAX,
-                end_point: Point::new(usize::MAX, usize::MAX),
-            };
-
-            for excluded_range in node
-                .children()
-                .filter_map(|child| {
-                    if includes_children {
-                        None
-                    } else {
-                        Some(child.range())
-                    }
-                })
-                .chain([following_range].iter().cloned())
-            {
-                let mut range = Range {
-                    start_byte: preceding_range.end_byte,
-                    start_point: preceding_range.end_point,
-                    end_byte: excluded_range.start_byte,
-                    end_point: excluded_range.start_point,
-                };
-                preceding_range = excluded_range;
-
-                if range.end_byte < parent_range.start_byte {
-                    continue;
-                }
-
-                while parent_range.start_byte <= range.end_byte {
-                    if parent_range.end_byte > range.start_byte {
-                        if range.start_byte < parent_range.start_byte {
-                            range.start_byte = parent_range.start_byte;
-                            range.start_point = parent_range.start_point;
-                        }
-
-                        if parent_range.end_byte < range.end_byte {
-                            if range.start_byte < parent_range.end_byte {
-                                result.push(Range {
-                                    start_byte: range.start_byte,
-                                    start_point: range.start_point,
-                                    end_byte: parent_range.end_byte,
-                                    end_point: parent_range.end_point,
-                                });
-                            }
-                            range.start_byte = parent_range.end_byte;
-                            range.start_point = parent_range.end_point;
-                        } else {
-                            if range.start_byte < range.end_byte {
-                                result.push(range);
-                            }
-                            break;
-                        }
-                    }
-
-                    if let Some(next_range) = parent_range_iter.next() {
-                        parent_range = next_range;
-                    } else {
-                        return result;
-                    }
-                }
+    // The next error node that starts at or before the given capture should be
+    // highlighted before it, unless it is nested inside of the captured node.
+    fn next_error_precedes(&self, capture_range: Option<ops::Range<usize>>) -> bool {
+        match (self.error_ranges.last(), capture_range) {
+            (Some(error_range), Some(capture_range)) => {
+                error_range.start < capture_range.start
+                    || (error_range.start == capture_range.start
+                        && error_range.end >= capture_range.end)
             }
+            (Some(_), None) => true,
+            (None, _) => false,
         }
-        result
     }
 
     // First, sort scope boundaries by their byte offset in the document. At a
-    // given position, emit scope endings before scope beginnings. Finally, emit
-    // scope boundaries from deeper layers first.
-    fn sort_key(&mut self) -> Option<(usize, bool, isize)> {
+    // given position, emit scope endings before scope beginnings. Then, emit the
+    // beginnings of final highlights first, so that they can suppress the highlights
+    // of deeper layers that begin at the same position. Then, emit scope boundaries
+    // from deeper layers first. Finally, emit scope boundaries from layers that were
+    // created earlier first, so that the order of events never depends on the order
+    // in which the layers happened to be sorted.
+    fn sort_key(&mut self) -> Option<(usize, bool, bool, isize, usize)> {
         let depth = -(self.depth as isize);
-        let next_start = self
-            .captures
-            .peek()
-            .map(|(m, i)| m.captures[*i].node.start_byte());
+        let id = self.id;
+        let config = self.config;
+        let next_capture = self.captures.peek().map(|(m, i)| {
+            let is_final = config.final_highlight_patterns[m.pattern_index];
+            (m.captures[*i].node.start_byte(), is_final)
+        });
+        let next_error_start = self.error_ranges.last().map(|range| range.start);
+        let next_start = match (next_capture, next_error_start) {
+            (Some((a, is_final)), Some(b)) => {
+                if a <= b {
+                    Some((a, is_final && a < b))
+                } else {
+                    Some((b, false))
+                }
+            }
+            (Some(capture), None) => Some(capture),
+            (None, Some(b)) => Some((b, false)),
+            (None, None) => None,
+        };
         let next_end = self.highlight_end_stack.last().cloned();
         match (next_start, next_end) {
-            (Some(start), Some(end)) => {
+            (Some((start, is_final)), Some(end)) => {
                 if start < end {
-                    Some((start, true, depth))
+                    Some((start, true, !is_final, depth, id))
                 } else {
-                    Some((end, false, depth))
+                    Some((end, false, true, depth, id))
                 }
             }
-            (Some(i), None) => Some((i, true, depth)),
-            (None, Some(j)) => Some((j, false, depth)),
+            (Some((i, is_final)), None) => Some((i, true, !is_final, depth, id)),
+            (None, Some(j)) => Some((j, false, true, depth, id)),
             _ => None,
         }
     }
@@ -485,51 +1784,252 @@ impl<'a> HighlightIterLayer<'a> {
 
 impl<'a, F> HighlightIter<'a, F>
 where
-    F: Fn(&str) -> Option<&'a HighlightConfiguration> + 'a,
+    F: Fn(Injection) -> Option<&'a HighlightConfiguration> + 'a,
 {
+    // A pending highlight can no longer be replaced once it is emitted, so this is where
+    // its pattern is counted.
+    fn emit_pending_highlight(
+        &mut self,
+        (event, (_, _, depth), pattern): PendingHighlight,
+    ) -> HighlightEvent {
+        if let (Some(usage), Some(pattern_index)) = (self.options.pattern_usage, pattern) {
+            usage.increment(pattern_index);
+        }
+        self.emitted_highlight_depth = depth;
+        event
+    }
+
+    // Emit the source code up to the given offset, if there is any, followed by the given
+    // event. Every source event is followed by another event, except for the one at the end
+    // of the document, so that consecutive source events are never emitted.
     fn emit_event(
         &mut self,
         offset: usize,
-        event: Option<HighlightEvent>,
+        event: HighlightEvent,
     ) -> Option<Result<HighlightEvent, Error>> {
+        let offset = cmp::min(offset, self.end_byte);
         let result;
         if self.byte_offset < offset {
-            result = Some(Ok(HighlightEvent::Source {
+            result = HighlightEvent::Source {
                 start: self.byte_offset,
                 end: offset,
-            }));
+            };
             self.byte_offset = offset;
-            self.next_event = event;
+            self.next_event = Some(event);
         } else {
-            result = event.map(Ok);
+            result = event;
         }
         self.sort_layers();
-        result
+        Some(Ok(result))
+    }
+
+    fn sort_layers(&mut self) {
+        if let Some(sort_key) = self.layers[0].sort_key() {
+            let mut i = 0;
+            while i + 1 < self.layers.len() {
+                if let Some(next_offset) = self.layers[i + 1].sort_key() {
+                    if next_offset < sort_key {
+                        i += 1;
+                        continue;
+                    }
+                }
+                break;
+            }
+            if i > 0 {
+                &self.layers[0..(i + 1)].rotate_left(1);
+            }
+        } else {
+            let layer = self.layers.remove(0);
+            self.context.cursors.push(layer.cursor);
+        }
+    }
+
+    // Find the configuration of an injected language, and the name that its layer is
+    // reported with. `current` and `parent` are the languages of the layer that contains
+    // the injection and of its parent.
+    fn injection_config(
+        &self,
+        language: &InjectionLanguage<'a>,
+        site: Option<&str>,
+        current: &LayerLanguage<'a>,
+        parent: Option<&LayerLanguage<'a>>,
+    ) -> Option<LayerLanguage<'a>> {
+        match language {
+            InjectionLanguage::Name(name) => (self.injection_callback)(Injection {
+                language: name,
+                site,
+            })
+            .map(|config| LayerLanguage {
+                config,
+                name: name.clone(),
+            }),
+            InjectionLanguage::Current => Some(current.clone()),
+            InjectionLanguage::Parent => parent.cloned(),
+        }
+    }
+
+    fn add_injection_layer(
+        &mut self,
+        language: InjectionLanguage<'a>,
+        injected: LayerLanguage<'a>,
+        parent: LayerLanguage<'a>,
+        depth: usize,
+        ranges: Vec<Range>,
+    ) -> Result<(), Error> {
+        let config = injected.config;
+        let bytes: usize = ranges
+            .iter()
+            .map(|range| range.end_byte - range.start_byte)
+            .sum();
+        #[cfg(feature = "timeout")]
+        let start_time = self
+            .context
+            .injection_stats
+            .as_ref()
+            .map(|_| Instant::now());
+        let error_count = self.context.parse_stats.error_count;
+        let mut layer = HighlightIterLayer::new(
+            config,
+            self.source,
+            self.context,
+            &self.options,
+            self.deadline,
+            depth,
+            ranges,
+        )?;
+        if let Some(trees) = &mut self.context.trees {
+            let name = Some(injected.name.to_string());
+            trees.push(
+                config.language,
+                name,
+                depth,
+                &layer.ranges,
+                layer.tree.clone(),
+            );
+        }
+        layer.language_name = injected.name.clone();
+        layer.parent = Some(parent.clone());
+        if self.options.layer_events.is_some() {
+            let language = self.layer_language(&injected.name);
+            for range in &layer.ranges {
+                self.add_layer_boundaries(range, depth, &language);
+            }
+        }
+        let combined_injections = mem::take(&mut layer.combined_injections);
+        self.insert_layer(layer);
+        if let Some(injection_stats) = &mut self.context.injection_stats {
+            // Injections of the current or the parent language are recorded under the
+            // first name of that language's configuration.
+            let name = match &language {
+                InjectionLanguage::Name(name) => name,
+                _ => config.names().first().map_or("", String::as_str),
+            };
+            let stats = injection_stats.entry(name.to_string()).or_default();
+            stats.layer_count += 1;
+            stats.bytes += bytes;
+            stats.error_count += self.context.parse_stats.error_count - error_count;
+            #[cfg(feature = "timeout")]
+            if let Some(start_time) = start_time {
+                stats.parse_time += start_time.elapsed();
+            }
+        }
+        self.add_combined_injections(combined_injections, injected, Some(parent), depth + 1)
+    }
+
+    // Check whether an injection at the given depth is deeper than the maximum injection
+    // depth, and count it as skipped if it is. This is checked before the injection's
+    // language is resolved, so that the injection callback isn't called for injections
+    // that are skipped anyway.
+    fn injection_depth_exceeded(&mut self, depth: usize) -> bool {
+        let max_depth = self
+            .options
+            .max_injection_depth
+            .unwrap_or(DEFAULT_MAX_INJECTION_DEPTH);
+        if depth > max_depth {
+            self.context.parse_stats.skipped_injection_count += 1;
+            return true;
+        }
+        false
     }
 
-    fn sort_layers(&mut self) {
-        if let Some(sort_key) = self.layers[0].sort_key() {
-            let mut i = 0;
-            while i + 1 < self.layers.len() {
-                if let Some(next_offset) = self.layers[i + 1].sort_key() {
-                    if next_offset < sort_key {
-                        i += 1;
-                        continue;
-                    }
-                }
-                break;
+    fn add_combined_injections(
+        &mut self,
+        injections: Vec<(InjectionLanguage<'a>, Vec<Range>)>,
+        current: LayerLanguage<'a>,
+        parent: Option<LayerLanguage<'a>>,
+        depth: usize,
+    ) -> Result<(), Error> {
+        for (language, ranges) in injections {
+            if self.injection_depth_exceeded(depth) {
+                continue;
             }
-            if i > 0 {
-                &self.layers[0..(i + 1)].rotate_left(1);
+            if let Some(injected) =
+                self.injection_config(&language, None, &current, parent.as_ref())
+            {
+                self.add_injection_layer(language, injected, current.clone(), depth, ranges)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Queue the events for the start and the end of one of a layer's ranges. The boundaries
+    // are kept in the order in which their events are emitted when they have the same
+    // offset: the ends of layers come before their starts, the ends of deeper layers come
+    // first, and the starts of deeper layers come last.
+    fn add_layer_boundaries(&mut self, range: &Range, depth: usize, language: &Arc<str>) {
+        let start = range.start_byte;
+        let end = cmp::min(range.end_byte, self.end_byte);
+        if start >= end {
+            return;
+        }
+        let boundaries = vec![
+            LayerBoundary {
+                offset: start,
+                depth,
+                language: Some(language.clone()),
+            },
+            LayerBoundary {
+                offset: end,
+                depth,
+                language: None,
+            },
+        ];
+        for boundary in boundaries {
+            let key = boundary.sort_key();
+            let index = self
+                .layer_boundaries
+                .partition_point(|other| other.sort_key() <= key);
+            self.layer_boundaries.insert(index, boundary);
+        }
+    }
+
+    // Returns the shared name of a layer's language for its `LayerStart` events, so that
+    // the layers of each language share one allocation.
+    fn layer_language(&mut self, name: &str) -> Arc<str> {
+        match self
+            .layer_languages
+            .iter()
+            .find(|language| language.as_ref() == name)
+        {
+            Some(language) => language.clone(),
+            None => {
+                let language = Arc::<str>::from(name);
+                self.layer_languages.push(language.clone());
+                language
             }
-        } else {
-            let layer = self.layers.remove(0);
-            self.context.cursors.push(layer.cursor);
         }
     }
 
     fn insert_layer(&mut self, mut layer: HighlightIterLayer<'a>) {
+        layer.id = self.next_layer_id;
+        self.next_layer_id += 1;
+        // A layer without any captures has no events, and would keep the layers after it
+        // from being sorted, so it is dropped right away.
         let sort_key = layer.sort_key();
+        if sort_key.is_none() {
+            self.context.cursors.push(layer.cursor);
+            return;
+        }
         let mut i = 1;
         while i < self.layers.len() {
             if self.layers[i].sort_key() > sort_key {
@@ -542,13 +2042,11 @@ where
     }
 }
 
-impl<'a, F> Iterator for HighlightIter<'a, F>
+impl<'a, F> HighlightIter<'a, F>
 where
-    F: Fn(&str) -> Option<&'a HighlightConfiguration> + 'a,
+    F: Fn(Injection) -> Option<&'a HighlightConfiguration> + 'a,
 {
-    type Item = Result<HighlightEvent, Error>;
-
-    fn next(&mut self) -> Option<Self::Item> {
+    fn advance(&mut self) -> Option<Result<HighlightEvent, Error>> {
         loop {
             // If we've already determined the next highlight boundary, just return it.
             if let Some(e) = self.next_event.take() {
@@ -556,25 +2054,32 @@ where
             }
 
             // Periodically check for cancellation, returning `Cancelled` error if the
-            // cancellation flag was flipped.
-            if let Some(cancellation_flag) = self.cancellation_flag {
+            // cancellation flag was flipped or the timeout expired.
+            if self.options.cancellation_flag.is_some() || self.deadline.is_some() {
                 self.iter_count += 1;
                 if self.iter_count >= CANCELLATION_CHECK_INTERVAL {
                     self.iter_count = 0;
-                    if cancellation_flag.load(Ordering::Relaxed) != 0 {
-                        return Some(Err(Error::Cancelled));
+                    if let Some(cancellation_flag) = self.options.cancellation_flag {
+                        if cancellation_flag.load(Ordering::Relaxed) != 0 {
+                            return Some(Err(Error::Cancelled));
+                        }
+                    }
+                    if let Some(deadline) = self.deadline {
+                        if Instant::now() >= deadline {
+                            return Some(Err(Error::Cancelled));
+                        }
                     }
                 }
             }
 
             // If none of the layers have any more scope boundaries, terminate.
             if self.layers.is_empty() {
-                if self.byte_offset < self.source.len() {
+                if self.byte_offset < self.end_byte {
                     let result = Some(Ok(HighlightEvent::Source {
                         start: self.byte_offset,
-                        end: self.source.len(),
+                        end: self.end_byte,
                     }));
-                    self.byte_offset = self.source.len();
+                    self.byte_offset = self.end_byte;
                     return result;
                 } else {
                     return None;
@@ -588,6 +2093,34 @@ where
             let mut capture;
             let mut pattern_index;
             let layer = &mut self.layers[0];
+
+            // If error highlighting is enabled and an error node comes before the next
+            // capture, then highlight the error node, after ending any previous highlights
+            // that end before it.
+            if let Some(error_highlight) = self.options.error_highlight {
+                let next_capture_range = layer
+                    .captures
+                    .peek()
+                    .map(|(m, i)| m.captures[*i].node.byte_range());
+                if layer.next_error_precedes(next_capture_range) {
+                    let error_range = layer.error_ranges.last().unwrap().clone();
+                    if let Some(end_byte) = layer.highlight_end_stack.last().cloned() {
+                        if end_byte <= error_range.start {
+                            layer.highlight_end_stack.pop();
+                            return self.emit_event(end_byte, HighlightEvent::HighlightEnd);
+                        }
+                    }
+                    layer.error_ranges.pop();
+                    layer.highlight_end_stack.push(error_range.end);
+                    self.last_highlight_range =
+                        Some((error_range.start, error_range.end, layer.depth));
+                    return self.emit_event(
+                        error_range.start,
+                        HighlightEvent::HighlightStart(error_highlight),
+                    );
+                }
+            }
+
             if let Some((m, capture_index)) = layer.captures.peek() {
                 match_ = m;
                 captures = match_.captures;
@@ -595,9 +2128,12 @@ where
                 capture = captures[*capture_index];
             } else if let Some(end_byte) = layer.highlight_end_stack.last().cloned() {
                 layer.highlight_end_stack.pop();
-                return self.emit_event(end_byte, Some(HighlightEvent::HighlightEnd));
+                return self.emit_event(end_byte, HighlightEvent::HighlightEnd);
             } else {
-                return self.emit_event(self.source.len(), None);
+                // This layer is finished. The rest of the source code is emitted along with
+                // the next event of another layer, or at the end of the document.
+                self.sort_layers();
+                continue;
             };
 
             // If any previous highlight ends before this node starts, then before
@@ -607,7 +2143,7 @@ where
             if let Some(end_byte) = layer.highlight_end_stack.last().cloned() {
                 if end_byte <= range.start {
                     layer.highlight_end_stack.pop();
-                    return self.emit_event(end_byte, Some(HighlightEvent::HighlightEnd));
+                    return self.emit_event(end_byte, HighlightEvent::HighlightEnd);
                 }
             }
 
@@ -636,6 +2172,12 @@ where
                 // in the stream of captures.
                 layer.captures.next().unwrap().0.remove();
 
+                // Combined injections were already processed when the layer was created.
+                if layer.config.combined_injection_patterns[pattern_index] {
+                    self.sort_layers();
+                    continue;
+                }
+
                 if let Some(site_node) = site_node {
                     // Discard any subsequent matches for same injection site.
                     while let Some((next_match, _)) = layer.captures.peek() {
@@ -656,18 +2198,21 @@ where
                     // captures being intermixed with other captures related to local variables
                     // and syntax highlighting.
                     let source = self.source;
-                    let mut injections = Vec::<(usize, Option<&str>, Vec<Node>, bool)>::new();
+                    let mut injections = Vec::<(usize, Option<Cow<str>>, Vec<Node>)>::new();
                     for mat in self.injections_cursor.matches(
                         &layer.config.injections_query,
                         site_node,
-                        move |node| &source[node.byte_range()],
+                        move |node| source.text(node.byte_range()),
                     ) {
+                        if layer.config.combined_injection_patterns[mat.pattern_index] {
+                            continue;
+                        }
                         let entry = if let Some(entry) =
                             injections.iter_mut().find(|e| e.0 == mat.pattern_index)
                         {
                             entry
                         } else {
-                            injections.push((mat.pattern_index, None, Vec::new(), false));
+                            injections.push((mat.pattern_index, None, Vec::new()));
                             injections.last_mut().unwrap()
                         };
 
@@ -678,51 +2223,96 @@ where
                                     break;
                                 }
                             } else if index == language_capture_index && entry.1.is_none() {
-                                entry.1 = capture.node.utf8_text(self.source).ok();
+                                entry.1 = self.source.str(capture.node.byte_range());
                             } else if index == content_capture_index {
                                 entry.2.push(capture.node);
                             }
                         }
-                    }
-
-                    for (pattern_index, language, _, include_children) in injections.iter_mut() {
-                        for prop in layer.config.query.property_settings(*pattern_index) {
-                            match prop.key.as_ref() {
-                                // In addition to specifying the language name via the text of a
-                                // captured node, it can also be hard-coded via a `set!` predicate
-                                // that sets the injection.language key.
-                                "injection.language" => {
-                                    if language.is_none() {
-                                        *language = prop.value.as_ref().map(|s| s.as_ref())
-                                    }
-                                }
 
-                                // By default, injections do not include the *children* of an
-                                // `injection.content` node - only the ranges that belong to the
-                                // node itself. This can be changed using a `set!` predicate that
-                                // sets the `injection.include-children` key.
-                                "injection.include-children" => *include_children = true,
-                                _ => {}
-                            }
+                        // The language name can also be taken from another capture in the
+                        // same match, using a `set!` predicate whose value is the capture's
+                        // name, prefixed with `@`.
+                        if entry.1.is_none() {
+                            entry.1 = injection_language_from_capture(
+                                &layer.config.injections_query,
+                                &mat,
+                                self.source,
+                            );
                         }
                     }
 
-                    for (_, language, content_nodes, include_children) in injections {
-                        // If a language is found with the given name, then add a new language layer
-                        // to the highlighted document.
-                        if let Some(config) = language.and_then(&self.injection_callback) {
-                            if !content_nodes.is_empty() {
-                                match HighlightIterLayer::new(
-                                    config,
-                                    self.source,
-                                    self.context,
-                                    self.cancellation_flag,
-                                    self.layers[0].depth + 1,
-                                    self.layers[0]
-                                        .intersect_ranges(&content_nodes, include_children),
+                    // Create the layers in the order of their injection patterns, which
+                    // determines their order when their highlights coincide.
+                    let current = LayerLanguage {
+                        config: layer.config,
+                        name: layer.language_name.clone(),
+                    };
+                    let parent = layer.parent.clone();
+                    let depth = layer.depth + 1;
+                    injections.sort_unstable_by_key(|(pattern_index, ..)| *pattern_index);
+                    for (pattern_index, language, content_nodes) in injections {
+                        if outside_byte_range(&self.options.byte_range, &content_nodes) {
+                            continue;
+                        }
+
+                        // If a language is found for the injection, then add a new language
+                        // layer to the highlighted document.
+                        let settings = injection_settings(&current.config.query, pattern_index);
+                        let language =
+                            settings.injection_language(language, self.source, &content_nodes);
+                        if language.is_some() && self.injection_depth_exceeded(depth) {
+                            continue;
+                        }
+                        // The root node of an injected layer can extend past the end of
+                        // the document, so the site's text is clipped to the document.
+                        let site = if site_node.start_byte() <= self.source.len() {
+                            self.source.str(site_node.byte_range())
+                        } else {
+                            None
+                        };
+                        let injected = language.as_ref().and_then(|language| {
+                            self.injection_config(
+                                language,
+                                site.as_deref(),
+                                &current,
+                                parent.as_ref(),
+                            )
+                        });
+                        if let (Some(language), Some(injected)) = (language, injected) {
+                            let content_ranges = content_nodes
+                                .iter()
+                                .map(|node| node.byte_range())
+                                .collect::<Vec<_>>();
+                            let content_nodes = util::non_overlapping_ranges(
+                                &content_ranges,
+                                settings.prefer_inner,
+                            )
+                            .into_iter()
+                            .map(|i| content_nodes[i])
+                            .collect::<Vec<_>>();
+
+                            // If none of the content lies within the current layer's ranges,
+                            // then skip the injection. An empty list of included ranges would
+                            // cause the entire document to be parsed.
+                            let ranges = intersect_ranges(
+                                &self.layers[0].ranges,
+                                &content_nodes,
+                                settings.include_children,
+                            );
+                            if !ranges.is_empty() {
+                                if settings.replace {
+                                    let replaced_ranges = &mut self.layers[0].replaced_ranges;
+                                    replaced_ranges
+                                        .extend(ranges.iter().map(|r| r.start_byte..r.end_byte));
+                                }
+                                if let Err(e) = self.add_injection_layer(
+                                    language,
+                                    injected,
+                                    current.clone(),
+                                    depth,
+                                    ranges,
                                 ) {
-                                    Ok(layer) => self.insert_layer(layer),
-                                    Err(e) => return Some(Err(e)),
+                                    return Some(Err(e));
                                 }
                             }
                         }
@@ -751,12 +2341,9 @@ where
                         local_defs: Vec::new(),
                     };
                     for prop in layer.config.query.property_settings(pattern_index) {
-                        match prop.key.as_ref() {
-                            "local.scope-inherits" => {
-                                scope.inherits =
-                                    prop.value.as_ref().map_or(true, |r| r.as_ref() == "true");
-                            }
-                            _ => {}
+                        if prop.key.as_ref() == "local.scope-inherits" {
+                            scope.inherits =
+                                !matches!(&prop.value, Some(value) if value.as_ref() != "true");
                         }
                     }
                     layer.scope_stack.push(scope);
@@ -775,11 +2362,26 @@ where
                         }
                     }
 
-                    if let Ok(name) = str::from_utf8(&self.source[range.clone()]) {
+                    let name = self
+                        .source
+                        .str_or_lossy(range.clone(), self.options.lossy_local_names);
+                    if let Some(name) = name {
+                        let mut binding = None;
+                        if let Some(bindings) = &mut self.local_bindings {
+                            binding = Some(bindings.len());
+                            bindings.push(LocalBinding {
+                                layer: layer.id,
+                                language: layer.language_name.to_string(),
+                                depth: layer.depth,
+                                definition: range.clone(),
+                                references: Vec::new(),
+                            });
+                        }
                         scope.local_defs.push(LocalDef {
                             name,
                             value_range,
                             highlight: None,
+                            binding,
                         });
                         definition_highlight =
                             scope.local_defs.last_mut().map(|s| &mut s.highlight);
@@ -787,26 +2389,34 @@ where
                 }
                 // If the node represents a reference, then try to find the corresponding
                 // definition in the scope stack.
-                else if Some(capture.index) == layer.config.local_ref_capture_index {
-                    if definition_highlight.is_none() {
-                        definition_highlight = None;
-                        if let Ok(name) = str::from_utf8(&self.source[range.clone()]) {
-                            for scope in layer.scope_stack.iter().rev() {
-                                if let Some(highlight) =
-                                    scope.local_defs.iter().rev().find_map(|def| {
-                                        if def.name == name && range.start >= def.value_range.end {
-                                            Some(def.highlight)
-                                        } else {
-                                            None
-                                        }
-                                    })
+                else if Some(capture.index) == layer.config.local_ref_capture_index
+                    && definition_highlight.is_none()
+                {
+                    definition_highlight = None;
+                    let name = self
+                        .source
+                        .str_or_lossy(range.clone(), self.options.lossy_local_names);
+                    if let Some(name) = name {
+                        for scope in layer.scope_stack.iter().rev() {
+                            if let Some((highlight, binding)) =
+                                scope.local_defs.iter().rev().find_map(|def| {
+                                    if def.name == name && range.start >= def.value_range.end {
+                                        Some((def.highlight, def.binding))
+                                    } else {
+                                        None
+                                    }
+                                })
+                            {
+                                reference_highlight = highlight;
+                                if let (Some(bindings), Some(binding)) =
+                                    (&mut self.local_bindings, binding)
                                 {
-                                    reference_highlight = highlight;
-                                    break;
-                                }
-                                if !scope.inherits {
-                                    break;
+                                    bindings[binding].references.push(range.clone());
                                 }
+                                break;
+                            }
+                            if !scope.inherits {
+                                break;
                             }
                         }
                     }
@@ -830,6 +2440,9 @@ where
                 break;
             }
 
+            // When multiple layers highlight the same range, only the highlight from the
+            // deepest layer is emitted. If a deeper layer has already highlighted this range,
+            // then skip this highlight.
             let mut has_highlight = true;
             if let Some((last_start, last_end, last_depth)) = self.last_highlight_range {
                 if range.start == last_start && range.end == last_end && layer.depth < last_depth {
@@ -837,10 +2450,41 @@ where
                 }
             }
 
+            // Conversely, highlights from patterns with the `highlight.final` property take
+            // precedence over the highlights of deeper layers that are within their range.
+            // Final ranges are kept until a capture begins after they have ended.
+            let layer_depth = layer.depth;
+            self.final_highlight_ranges
+                .retain(|(_, end, _)| *end > range.start);
+            if self
+                .final_highlight_ranges
+                .iter()
+                .any(|(start, end, depth)| {
+                    layer_depth > *depth && range.start >= *start && range.end <= *end
+                })
+            {
+                has_highlight = false;
+            }
+
+            // Nodes within the ranges of an injection with the `injection.replace` property are
+            // only highlighted by the injected layer.
+            layer.replaced_ranges.retain(|r| r.end > range.start);
+            if layer
+                .replaced_ranges
+                .iter()
+                .any(|r| range.start >= r.start && range.end <= r.end)
+            {
+                has_highlight = false;
+            }
+
             // If the current node was found to be a local variable, then skip over any
-            // highlighting patterns that are disabled for local variables.
+            // highlighting patterns that are disabled for local variables, falling through
+            // to the next pattern for the same node. Matches whose text predicates failed
+            // (including negated ones like `not-eq?`) never reach this point, because the
+            // captures iterator discards them.
+            let is_local_variable = definition_highlight.is_some() || reference_highlight.is_some();
             while has_highlight
-                && (definition_highlight.is_some() || reference_highlight.is_some())
+                && is_local_variable
                 && layer.config.non_local_variable_patterns[pattern_index]
             {
                 has_highlight = false;
@@ -859,12 +2503,23 @@ where
 
             if has_highlight {
                 // Once a highlighting pattern is found for the current node, skip over
-                // any later highlighting patterns that also match this node. Captures
-                // for a given node are ordered by pattern index, so these subsequent
-                // captures are guaranteed to be for highlighting, not injections or
-                // local variables.
+                // any later highlighting patterns that also match this node, unless one
+                // of them has a higher `priority`. Captures for a given node are ordered
+                // by pattern index, so these subsequent captures are guaranteed to be for
+                // highlighting, not injections or local variables, and among patterns
+                // with the same priority, the earliest one wins.
                 while let Some((next_match, next_capture_index)) = layer.captures.peek() {
-                    if next_match.captures[*next_capture_index].node == capture.node {
+                    let next_capture = next_match.captures[*next_capture_index];
+                    if next_capture.node == capture.node {
+                        let next_pattern_index = next_match.pattern_index;
+                        let priorities = &layer.config.highlight_priorities;
+                        if priorities[next_pattern_index] > priorities[pattern_index]
+                            && !(is_local_variable
+                                && layer.config.non_local_variable_patterns[next_pattern_index])
+                        {
+                            capture = next_capture;
+                            pattern_index = next_pattern_index;
+                        }
                         layer.captures.next();
                     } else {
                         break;
@@ -872,6 +2527,13 @@ where
                 }
 
                 let current_highlight = layer.config.highlight_indices[capture.index as usize];
+                if current_highlight.is_none()
+                    && pattern_index >= layer.config.highlights_pattern_index
+                {
+                    if let Some(counter) = self.options.unrecognized_capture_counter {
+                        counter.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
 
                 // If this node represents a local definition, then store the current
                 // highlight value on the local scope entry representing this node.
@@ -881,10 +2543,51 @@ where
 
                 // Emit a scope start event and push the node's end position to the stack.
                 if let Some(highlight) = reference_highlight.or(current_highlight) {
-                    self.last_highlight_range = Some((range.start, range.end, layer.depth));
+                    let depth = layer.depth;
+                    self.last_highlight_range = Some((range.start, range.end, depth));
+                    self.last_highlight_pattern = if ptr::eq(layer.config, self.config) {
+                        Some(pattern_index)
+                    } else {
+                        None
+                    };
+                    if layer.config.final_highlight_patterns[pattern_index] {
+                        self.final_highlight_ranges
+                            .push((range.start, range.end, depth));
+                    }
                     layer.highlight_end_stack.push(range.end);
-                    return self
-                        .emit_event(range.start, Some(HighlightEvent::HighlightStart(highlight)));
+                    // A reference that is only highlighted because of its definition has
+                    // no highlights pattern, so it is started with a plain event.
+                    let event = if self.options.debug
+                        && pattern_index >= layer.config.highlights_pattern_index
+                    {
+                        HighlightEvent::HighlightStartWithPattern {
+                            highlight,
+                            pattern_index,
+                            pattern_offset: layer
+                                .config
+                                .query
+                                .start_byte_for_pattern(pattern_index)
+                                - layer.config.highlights_query_offset,
+                        }
+                    } else {
+                        HighlightEvent::HighlightStart(highlight)
+                    };
+
+                    // Conversely, if a shallower layer has just started a highlight for this
+                    // range, then replace that highlight, which hasn't been returned yet.
+                    if let Some((_, (start, end, pending_depth), _)) = self.pending_highlight {
+                        if range.start == start && range.end == end && pending_depth < depth {
+                            self.pending_highlight = None;
+                            if let Some(layer) = self.layers.iter_mut().find(|layer| {
+                                layer.depth == pending_depth
+                                    && layer.highlight_end_stack.last() == Some(&end)
+                            }) {
+                                layer.highlight_end_stack.pop();
+                            }
+                        }
+                    }
+
+                    return self.emit_event(range.start, event);
                 }
             }
 
@@ -893,18 +2596,267 @@ where
     }
 }
 
+// Return the query cursors to the context, so that they are reused by later highlighting
+// calls, even if the iterator is dropped before it is finished.
+impl<'a, F> Drop for HighlightIter<'a, F>
+where
+    F: Fn(Injection) -> Option<&'a HighlightConfiguration> + 'a,
+{
+    fn drop(&mut self) {
+        let cursors = &mut self.context.cursors;
+        cursors.push(mem::replace(
+            &mut self.injections_cursor,
+            QueryCursor::new(),
+        ));
+        cursors.extend(self.layers.drain(..).map(|layer| layer.cursor));
+    }
+}
+
+impl<'a, F> Iterator for HighlightIter<'a, F>
+where
+    F: Fn(Injection) -> Option<&'a HighlightConfiguration> + 'a,
+{
+    type Item = Result<HighlightEvent, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let event = if self.options.layer_events.is_some() {
+            self.next_with_layer_events()
+        } else {
+            self.next_highlight_event()
+        };
+        if cfg!(debug_assertions) {
+            let is_source = match &event {
+                Some(Ok(HighlightEvent::Source { start, end })) => {
+                    debug_assert!(start < end, "empty source event {}..{}", start, end);
+                    true
+                }
+                _ => false,
+            };
+            debug_assert!(!(is_source && self.after_source), "adjacent source events");
+            self.after_source = is_source;
+        }
+        event
+    }
+}
+
+impl<'a, F> HighlightIter<'a, F>
+where
+    F: Fn(Injection) -> Option<&'a HighlightConfiguration> + 'a,
+{
+    // Each highlight start event is held back until the following event has been determined,
+    // so that it can still be replaced by a highlight from a deeper layer for the same range.
+    // After an error, such as a cancellation, the iterator ends.
+    fn next_highlight_event(&mut self) -> Option<Result<HighlightEvent, Error>> {
+        if self.failed {
+            return None;
+        }
+        if let Some(event) = self.deferred_event.take() {
+            return Some(Ok(event));
+        }
+
+        loop {
+            match self.advance() {
+                Some(Ok(event @ HighlightEvent::HighlightStart(_)))
+                | Some(Ok(event @ HighlightEvent::HighlightStartWithPattern { .. })) => {
+                    let range = self.last_highlight_range.unwrap();
+                    let pattern = self.last_highlight_pattern;
+                    if let Some(pending) = self.pending_highlight.replace((event, range, pattern)) {
+                        return Some(Ok(self.emit_pending_highlight(pending)));
+                    }
+                }
+                Some(Ok(event)) => {
+                    if let Some(pending) = self.pending_highlight.take() {
+                        self.deferred_event = Some(event);
+                        return Some(Ok(self.emit_pending_highlight(pending)));
+                    }
+                    return Some(Ok(event));
+                }
+                Some(Err(error)) => {
+                    self.failed = true;
+                    return Some(Err(error));
+                }
+                None => {
+                    return self
+                        .pending_highlight
+                        .take()
+                        .map(|pending| Ok(self.emit_pending_highlight(pending)))
+                }
+            }
+        }
+    }
+
+    // Interleave the highlight events with the `LayerStart` and `LayerEnd` events of the
+    // layers' ranges. At a given offset, the start of a layer is emitted before the first
+    // highlight of that layer or of a deeper layer, and the end of a layer is emitted after
+    // the last highlight end of that layer or of a deeper layer, so that the layers and the
+    // highlights stay properly nested.
+    fn next_with_layer_events(&mut self) -> Option<Result<HighlightEvent, Error>> {
+        if self.root_layer_state == RootLayerState::NotStarted {
+            self.root_layer_state = RootLayerState::Started;
+            return Some(Ok(HighlightEvent::LayerStart {
+                language: self.layer_languages[0].clone(),
+                depth: 0,
+            }));
+        }
+
+        let event = match self.held_event.take() {
+            Some(event) => event,
+            None => match self.next_highlight_event() {
+                Some(Ok(event)) => event,
+                Some(Err(error)) => return Some(Err(error)),
+                None if self.failed => return None,
+                None => {
+                    if !self.layer_boundaries.is_empty() {
+                        return Some(Ok(self.emit_layer_boundary()));
+                    }
+                    if self.root_layer_state == RootLayerState::Started {
+                        self.root_layer_state = RootLayerState::Ended;
+                        return Some(Ok(HighlightEvent::LayerEnd));
+                    }
+                    return None;
+                }
+            },
+        };
+
+        let offset = self.layer_events_offset;
+        let boundary = self
+            .layer_boundaries
+            .first()
+            .map(|b| (b.offset, b.depth, b.language.is_some()));
+        let precedes_event = match (&event, boundary) {
+            (_, None) => false,
+            (HighlightEvent::Source { start, end }, Some((boundary_offset, ..))) => {
+                // A source range that contains a boundary is split at the boundary.
+                if boundary_offset > *start && boundary_offset < *end {
+                    self.held_event = Some(HighlightEvent::Source {
+                        start: boundary_offset,
+                        end: *end,
+                    });
+                    self.layer_events_offset = boundary_offset;
+                    return Some(Ok(HighlightEvent::Source {
+                        start: *start,
+                        end: boundary_offset,
+                    }));
+                }
+                boundary_offset <= *start
+            }
+            (
+                HighlightEvent::HighlightStart(_)
+                | HighlightEvent::HighlightStartWithPattern { .. },
+                Some((boundary_offset, depth, is_start)),
+            ) => boundary_offset <= offset && (!is_start || depth <= self.emitted_highlight_depth),
+            (HighlightEvent::HighlightEnd, Some((boundary_offset, depth, is_start))) => {
+                let highlight_depth = self.open_highlight_depths.last();
+                boundary_offset <= offset
+                    && !is_start
+                    && !matches!(highlight_depth, Some(highlight_depth) if *highlight_depth >= depth)
+            }
+            _ => false,
+        };
+        if precedes_event {
+            self.held_event = Some(event);
+            return Some(Ok(self.emit_layer_boundary()));
+        }
+
+        match &event {
+            HighlightEvent::Source { end, .. } => self.layer_events_offset = *end,
+            HighlightEvent::HighlightStart(_)
+            | HighlightEvent::HighlightStartWithPattern { .. } => self
+                .open_highlight_depths
+                .push(self.emitted_highlight_depth),
+            HighlightEvent::HighlightEnd => {
+                self.open_highlight_depths.pop();
+            }
+            _ => {}
+        }
+        Some(Ok(event))
+    }
+
+    fn emit_layer_boundary(&mut self) -> HighlightEvent {
+        let boundary = self.layer_boundaries.remove(0);
+        match boundary.language {
+            Some(language) => HighlightEvent::LayerStart {
+                language,
+                depth: boundary.depth,
+            },
+            None => HighlightEvent::LayerEnd,
+        }
+    }
+}
+
+impl Default for HtmlRenderer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl HtmlRenderer {
     pub fn new() -> Self {
         HtmlRenderer {
             html: Vec::new(),
             line_offsets: vec![0],
+            class_names: Vec::new(),
+            layer_attributes: Vec::new(),
+            line_ending_handling: LineEndingHandling::default(),
+            pending_carriage_return: None,
+            line_has_text: false,
+            escapes: util::HtmlEscapes::default(),
+            escaped_attribute: None,
         }
     }
 
+    /// Creates a renderer that gives each highlight's `span` a CSS class, which is derived
+    /// from the highlight's name by replacing its dots with the given separator. For
+    /// example, with a separator of `-`, the highlight `function.builtin` is rendered as
+    /// `<span class="function-builtin">`.
+    ///
+    /// The class names are HTML-escaped, and any whitespace in them is replaced with `_`,
+    /// so that each highlight has exactly one class. The attribute callback that is passed
+    /// to `render` can still override the attributes of a highlight: the class is only used
+    /// when the callback returns an empty string.
+    pub fn with_css_classes(highlighter: &Highlighter, separator: &str) -> Self {
+        let mut result = Self::new();
+        result.class_names = highlighter
+            .names()
+            .iter()
+            .map(|name| css_class_name(name, separator))
+            .collect();
+        result
+    }
+
+    /// Sets how the line endings of the source code are rendered. By default, each `\r\n`
+    /// is rendered as `\n`. A `\r` that isn't followed by a `\n` is always kept.
+    pub fn line_ending_handling(mut self, handling: LineEndingHandling) -> Self {
+        self.line_ending_handling = handling;
+        self
+    }
+
+    /// Sets the characters that are escaped in the source code and in the attribute values
+    /// that the renderer writes: the CSS classes, the languages of layer events, and the
+    /// values of the `escaped_attribute`. The attribute strings that the callback returns
+    /// are written as they are, unless `escaped_attribute` is set.
+    pub fn escapes(mut self, escapes: util::HtmlEscapes) -> Self {
+        self.escapes = escapes;
+        self
+    }
+
+    /// Treats the strings that the attribute callback returns as values of the attribute
+    /// with the given name, rather than as complete attribute strings. Each value is
+    /// escaped like `util::html_escape_attribute` and written as `name="value"`, so the
+    /// callback can return strings that come from users, like the names of scopes. An
+    /// empty value still means that the highlight has no attributes.
+    pub fn escaped_attribute(mut self, name: &str) -> Self {
+        self.escaped_attribute = Some(name.to_string());
+        self
+    }
+
     pub fn reset(&mut self) {
         self.html.clear();
         self.line_offsets.clear();
         self.line_offsets.push(0);
+        self.layer_attributes.clear();
+        self.pending_carriage_return = None;
+        self.line_has_text = false;
     }
 
     pub fn render<'a, F>(
@@ -915,85 +2867,319 @@ impl HtmlRenderer {
     ) -> Result<(), Error>
     where
         F: Fn(Highlight) -> &'a [u8],
+    {
+        let attribute_callback = |highlight| Ok::<_, Infallible>(attribute_callback(highlight));
+        self.try_render(highlighter, source, &attribute_callback)
+            .map_err(|error| match error {
+                RenderError::Highlight(error) => error,
+                RenderError::Attribute(error) => match error {},
+            })
+    }
+
+    /// Like `render`, but with an attribute callback that can fail. The first error that
+    /// is returned by the callback or by the highlighting iterator stops rendering.
+    ///
+    /// After an error, the HTML that has been written so far is kept, but it is incomplete
+    /// and may contain spans that are never closed. Call `reset` before rendering again.
+    pub fn try_render<'a, F, E>(
+        &mut self,
+        highlighter: impl Iterator<Item = Result<HighlightEvent, Error>>,
+        source: &'a [u8],
+        attribute_callback: &F,
+    ) -> Result<(), RenderError<E>>
+    where
+        F: Fn(Highlight) -> Result<&'a [u8], E>,
+    {
+        self.render_lines(highlighter, source, attribute_callback, None)
+    }
+
+    /// Like `render`, but writes the HTML to the given writer instead of collecting it in
+    /// `html`, which avoids holding the HTML of a large document in memory. Each line is
+    /// written as soon as its newline has been rendered, after it has been passed to the
+    /// line callback, which receives the same lines as `lines` would.
+    ///
+    /// Afterwards, `html` is empty. An error writing to the writer stops rendering, like
+    /// an error from the highlighting iterator.
+    pub fn render_to<'a, F, W, L>(
+        &mut self,
+        highlighter: impl Iterator<Item = Result<HighlightEvent, Error>>,
+        source: &'a [u8],
+        attribute_callback: &F,
+        mut writer: W,
+        mut line_callback: L,
+    ) -> Result<(), RenderToError>
+    where
+        F: Fn(Highlight) -> &'a [u8],
+        W: io::Write,
+        L: FnMut(&str),
+    {
+        let attribute_callback = |highlight| Ok::<_, io::Error>(attribute_callback(highlight));
+        let mut write_line = |line: &str| {
+            line_callback(line);
+            writer.write_all(line.as_bytes())
+        };
+        self.render_lines(
+            highlighter,
+            source,
+            &attribute_callback,
+            Some(&mut write_line),
+        )
+        .map_err(|error| match error {
+            RenderError::Highlight(error) => RenderToError::Highlight(error),
+            RenderError::Attribute(error) => RenderToError::Io(error),
+        })?;
+        writer.flush().map_err(RenderToError::Io)
+    }
+
+    // Render the events, either into `html`, or, if a line sink is given, by passing each
+    // line to the sink once it is complete. Errors from the sink are reported like errors
+    // from the attribute callback.
+    fn render_lines<'a, F, E>(
+        &mut self,
+        highlighter: impl Iterator<Item = Result<HighlightEvent, Error>>,
+        source: &'a [u8],
+        attribute_callback: &F,
+        mut line_sink: Option<&mut LineSink<E>>,
+    ) -> Result<(), RenderError<E>>
+    where
+        F: Fn(Highlight) -> Result<&'a [u8], E>,
     {
         let mut highlights = Vec::new();
+        let mut has_flushed_lines = false;
+
+        // Highlights that have ended, but whose closing tags have not been written yet,
+        // innermost first. When a source range is split into several `Source` events, for
+        // example around the children of an injection, the highlighter often ends and then
+        // restarts the same highlights between them. In that case, the existing spans are
+        // continued instead of being closed and reopened.
+        let mut ended_highlights = Vec::new();
+
         for event in highlighter {
             match event {
-                Ok(HighlightEvent::HighlightStart(s)) => {
-                    highlights.push(s);
-                    self.start_highlight(s, attribute_callback);
+                Ok(HighlightEvent::HighlightStart(s))
+                | Ok(HighlightEvent::HighlightStartWithPattern { highlight: s, .. }) => {
+                    let span = RenderedSpan::Highlight(s);
+                    highlights.push(span);
+                    if ended_highlights.last() == Some(&span) {
+                        ended_highlights.pop();
+                    } else {
+                        self.end_highlights(&mut ended_highlights);
+                        self.start_span(span, attribute_callback)
+                            .map_err(RenderError::Attribute)?;
+                    }
                 }
-                Ok(HighlightEvent::HighlightEnd) => {
-                    highlights.pop();
-                    self.end_highlight();
+                Ok(HighlightEvent::LayerStart { language, .. }) => {
+                    let span = RenderedSpan::Layer(self.layer_attributes.len());
+                    let attribute = html_attribute("data-language", &language, &self.escapes);
+                    self.layer_attributes.push(attribute);
+                    highlights.push(span);
+                    self.end_highlights(&mut ended_highlights);
+                    self.start_span(span, attribute_callback)
+                        .map_err(RenderError::Attribute)?;
+                }
+                Ok(HighlightEvent::HighlightEnd) | Ok(HighlightEvent::LayerEnd) => {
+                    match highlights.pop() {
+                        Some(s) => ended_highlights.push(s),
+                        None => self.end_highlight(),
+                    }
                 }
                 Ok(HighlightEvent::Source { start, end }) => {
-                    self.add_text(&source[start..end], &highlights, attribute_callback);
+                    if start > end || end > source.len() {
+                        return Err(RenderError::Highlight(Error::InvalidSourceRange {
+                            start,
+                            end,
+                        }));
+                    }
+                    if start < end {
+                        self.end_highlights(&mut ended_highlights);
+                        self.add_text(&source[start..end], &highlights, attribute_callback)
+                            .map_err(RenderError::Attribute)?;
+                        if let Some(sink) = &mut line_sink {
+                            has_flushed_lines |= self
+                                .flush_complete_lines(*sink)
+                                .map_err(RenderError::Attribute)?;
+                        }
+                    }
                 }
-                Err(a) => return Err(a),
+                Err(a) => return Err(RenderError::Highlight(a)),
             }
         }
-        if self.html.last() != Some(&b'\n') {
+        self.end_highlights(&mut ended_highlights);
+        self.flush_carriage_return();
+
+        // Spans that are open at a newline are reopened after it, so if the source ends
+        // with a newline, the spans of an enclosing layer or highlight can leave a final
+        // line that contains nothing but empty spans. That line is removed.
+        let last_line_start = self.line_offsets.last().cloned().unwrap_or(0);
+        let follows_newline = last_line_start > 0 || has_flushed_lines;
+        if follows_newline && !self.line_has_text {
+            self.html.truncate(last_line_start);
+        }
+        self.line_has_text = false;
+        if self.html.last() != Some(&b'\n') && !(self.html.is_empty() && has_flushed_lines) {
             self.html.push(b'\n');
         }
-        if self.line_offsets.last() == Some(&(self.html.len() as u32)) {
+        if self.line_offsets.last() == Some(&self.html.len()) {
             self.line_offsets.pop();
         }
+        if let Some(sink) = line_sink {
+            for line in self.lines() {
+                sink(line).map_err(RenderError::Attribute)?;
+            }
+            self.html.clear();
+            self.line_offsets.clear();
+            self.line_offsets.push(0);
+        }
         Ok(())
     }
 
+    // Pass the lines that have been completed to the sink, and remove them from `html`,
+    // keeping the line that is still being rendered. Returns whether there were any.
+    fn flush_complete_lines<E>(&mut self, sink: &mut LineSink<E>) -> Result<bool, E> {
+        let current_line_start = self.line_offsets.last().cloned().unwrap_or(0);
+        if current_line_start == 0 {
+            return Ok(false);
+        }
+        for line in self.lines().take(self.line_offsets.len() - 1) {
+            sink(line)?;
+        }
+        self.html.drain(..current_line_start);
+        self.line_offsets.clear();
+        self.line_offsets.push(0);
+        if let Some(offset) = &mut self.pending_carriage_return {
+            *offset -= current_line_start;
+        }
+        Ok(true)
+    }
+
     pub fn lines(&self) -> impl Iterator<Item = &str> {
         self.line_offsets
             .iter()
             .enumerate()
             .map(move |(i, line_start)| {
-                let line_start = *line_start as usize;
+                let line_start = *line_start;
                 let line_end = if i + 1 == self.line_offsets.len() {
                     self.html.len()
                 } else {
-                    self.line_offsets[i + 1] as usize
+                    self.line_offsets[i + 1]
                 };
                 str::from_utf8(&self.html[line_start..line_end]).unwrap()
             })
     }
 
-    fn start_highlight<'a, F>(&mut self, h: Highlight, attribute_callback: &F)
+    fn start_highlight<'a, F, E>(&mut self, h: Highlight, attribute_callback: &F) -> Result<(), E>
     where
-        F: Fn(Highlight) -> &'a [u8],
+        F: Fn(Highlight) -> Result<&'a [u8], E>,
     {
-        let attribute_string = (attribute_callback)(h);
+        let attribute_string = (attribute_callback)(h)?;
         self.html.extend(b"<span");
         if !attribute_string.is_empty() {
             self.html.extend(b" ");
-            self.html.extend(attribute_string);
+            if let Some(name) = &self.escaped_attribute {
+                self.html.extend(name.as_bytes());
+                self.html.extend(b"=\"");
+                for part in util::LossyUtf8::new(attribute_string) {
+                    self.escapes
+                        .escape_attribute(part.as_bytes(), &mut self.html);
+                }
+                self.html.extend(b"\"");
+            } else {
+                self.html.extend(attribute_string);
+            }
+        } else if let Some(class_name) = self.class_names.get(h.0) {
+            self.html.extend(b" class=\"");
+            self.escapes
+                .escape_attribute(class_name.as_bytes(), &mut self.html);
+            self.html.extend(b"\"");
         }
         self.html.extend(b">");
+        Ok(())
+    }
+
+    fn start_span<'a, F, E>(&mut self, span: RenderedSpan, attribute_callback: &F) -> Result<(), E>
+    where
+        F: Fn(Highlight) -> Result<&'a [u8], E>,
+    {
+        match span {
+            RenderedSpan::Highlight(h) => self.start_highlight(h, attribute_callback),
+            RenderedSpan::Layer(i) => {
+                self.html.extend(b"<span ");
+                self.html.extend(&self.layer_attributes[i]);
+                self.html.extend(b">");
+                Ok(())
+            }
+        }
     }
 
     fn end_highlight(&mut self) {
         self.html.extend(b"</span>");
     }
 
-    fn add_text<'a, F>(&mut self, src: &[u8], highlights: &Vec<Highlight>, attribute_callback: &F)
+    fn end_highlights(&mut self, highlights: &mut Vec<RenderedSpan>) {
+        highlights.drain(..).for_each(|_| self.end_highlight());
+    }
+
+    fn add_text<'a, F, E>(
+        &mut self,
+        src: &[u8],
+        highlights: &[RenderedSpan],
+        attribute_callback: &F,
+    ) -> Result<(), E>
     where
-        F: Fn(Highlight) -> &'a [u8],
+        F: Fn(Highlight) -> Result<&'a [u8], E>,
     {
         for c in util::LossyUtf8::new(src).flat_map(|p| p.bytes()) {
+            // A `\r` is only written once the next character is known, because the `\n`
+            // of a `\r\n` can be in a later `Source` event, after the end of a highlight.
+            // Until then, the position of the `\r` in the HTML is stored.
             if c == b'\n' {
-                if self.html.ends_with(b"\r") {
-                    self.html.pop();
-                }
                 highlights.iter().for_each(|_| self.end_highlight());
+                if self.pending_carriage_return.take().is_some()
+                    && self.line_ending_handling == LineEndingHandling::Preserve
+                {
+                    self.html.push(b'\r');
+                }
                 self.html.push(c);
-                self.line_offsets.push(self.html.len() as u32);
-                highlights
-                    .iter()
-                    .for_each(|scope| self.start_highlight(*scope, attribute_callback));
-            } else if let Some(escape) = util::html_escape(c) {
-                self.html.extend_from_slice(escape);
+                self.line_offsets.push(self.html.len());
+                self.line_has_text = false;
+                for span in highlights {
+                    self.start_span(*span, attribute_callback)?;
+                }
+                continue;
+            }
+            self.flush_carriage_return();
+            self.line_has_text = true;
+            if c == b'\r' {
+                self.pending_carriage_return = Some(self.html.len());
             } else {
-                self.html.push(c);
+                self.escapes.push(c, util::html_escape, &mut self.html);
             }
         }
+        Ok(())
+    }
+
+    // Write a `\r` that turned out not to be followed by a `\n`, at the position where
+    // it occurred, before any tags that were written after it.
+    fn flush_carriage_return(&mut self) {
+        if let Some(offset) = self.pending_carriage_return.take() {
+            self.html.insert(offset, b'\r');
+        }
     }
 }
+
+// The CSS class of a highlight, before it is escaped. Whitespace is replaced, so that
+// each highlight has exactly one class.
+fn css_class_name(highlight_name: &str, separator: &str) -> String {
+    highlight_name
+        .replace('.', separator)
+        .chars()
+        .map(|c| if c.is_ascii_whitespace() { '_' } else { c })
+        .collect()
+}
+
+fn html_attribute(name: &str, value: &str, escapes: &util::HtmlEscapes) -> Vec<u8> {
+    let mut result = format!("{}=\"", name).into_bytes();
+    escapes.escape_attribute(value.as_bytes(), &mut result);
+    result.push(b'"');
+    result
+}
@@ -0,0 +1,102 @@
+use super::{spans_from_events, Error, HighlightEvent};
+#[cfg(feature = "serde")]
+use serde_derive::{Deserialize, Serialize};
+use std::ops;
+
+/// The names of the highlights on each line of a document, such as for checking that a
+/// grammar's queries highlight each token as expected.
+///
+/// With the `serde` feature, this can be serialized, for example to print it as JSON.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct HighlightedLines {
+    /// The highlighted columns of each line, in order. There is an entry for every line of
+    /// the source code, including lines without any highlights.
+    pub lines: Vec<Vec<HighlightedColumns>>,
+}
+
+/// A range of columns within a line that has a highlight.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct HighlightedColumns {
+    /// The zero-based columns, measured in bytes, like the columns of Tree-sitter's points.
+    pub columns: ops::Range<usize>,
+    /// The name of the innermost highlight at these columns.
+    pub name: String,
+}
+
+impl HighlightedLines {
+    /// Collects a sequence of highlight events for the given source code into the names of
+    /// the highlights on each line. The `names` are the highlight names that the events
+    /// refer to, which are usually those of the `Highlighter`.
+    ///
+    /// Where highlights are nested, only the innermost highlight is reported, in the same
+    /// way as `spans_from_events`. Highlights that span several lines are split into a
+    /// range for each line, which doesn't include the line ending.
+    pub fn from_events(
+        source: &[u8],
+        events: impl IntoIterator<Item = Result<HighlightEvent, Error>>,
+        names: &[String],
+    ) -> Result<Self, Error> {
+        let mut line_starts = vec![0];
+        line_starts.extend(
+            source
+                .iter()
+                .enumerate()
+                .filter(|(_, c)| **c == b'\n')
+                .map(|(i, _)| i + 1),
+        );
+        let mut lines = vec![Vec::new(); line_starts.len()];
+
+        for (range, highlight) in spans_from_events(events)? {
+            let name = match names.get(highlight.0) {
+                Some(name) => name,
+                None => continue,
+            };
+            let end = range.end.min(source.len());
+            let mut start = range.start.min(end);
+            let mut row = match line_starts.binary_search(&start) {
+                Ok(row) => row,
+                Err(row) => row - 1,
+            };
+            while start < end {
+                let line_start = line_starts[row];
+                let line_end = line_starts.get(row + 1).map_or(source.len(), |s| s - 1);
+                let mut segment_end = end.min(line_end);
+                if segment_end == line_end && source[..segment_end].ends_with(b"\r") {
+                    segment_end -= 1;
+                }
+                if start < segment_end {
+                    let columns = (start - line_start)..(segment_end - line_start);
+                    let line: &mut Vec<HighlightedColumns> = &mut lines[row];
+                    match line.last_mut() {
+                        Some(last) if last.columns.end == columns.start && last.name == *name => {
+                            last.columns.end = columns.end
+                        }
+                        _ => line.push(HighlightedColumns {
+                            columns,
+                            name: name.clone(),
+                        }),
+                    }
+                }
+                start = line_end + 1;
+                row += 1;
+            }
+        }
+
+        Ok(HighlightedLines { lines })
+    }
+
+    /// Returns the name of the innermost highlight at the given zero-based row and byte
+    /// column, if there is one. This can be used to check assertions like a `^ keyword`
+    /// comment, whose caret points at a column of the previous line.
+    pub fn name_at(&self, row: usize, column: usize) -> Option<&str> {
+        self.lines.get(row)?.iter().find_map(|highlight| {
+            if highlight.columns.contains(&column) {
+                Some(highlight.name.as_str())
+            } else {
+                None
+            }
+        })
+    }
+}
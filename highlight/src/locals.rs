@@ -0,0 +1,59 @@
+use super::{
+    Error, HighlightConfiguration, HighlightContext, HighlightOptions, Highlighter, Injection,
+    SourceText,
+};
+use std::ops;
+
+/// A local variable definition, along with the references that were resolved to it.
+///
+/// The references are resolved with the `local.scope`, `local.definition` and
+/// `local.reference` captures of the locals query, using the same rules as highlighting,
+/// including `local.scope-inherits`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LocalBinding {
+    /// Identifies the language layer that contains the binding. The document's own layer
+    /// is `0`, and each injected layer is numbered in the order in which it was created.
+    pub layer: usize,
+    /// The name of the language of the layer. Injected layers are named after the language
+    /// name that their injection was resolved with. The document's own layer is named after
+    /// `HighlightOptions::layer_events`, or has an empty name if that isn't set.
+    pub language: String,
+    /// The number of injections that the layer is nested within.
+    pub depth: usize,
+    /// The byte range of the definition's node.
+    pub definition: ops::Range<usize>,
+    /// The byte ranges of the references to the definition, in the order of the document.
+    pub references: Vec<ops::Range<usize>>,
+}
+
+impl Highlighter {
+    /// Find the local variable definitions in a slice of source code, along with the
+    /// references to each of them, such as for highlighting every occurrence of a
+    /// variable in an editor.
+    ///
+    /// The document is processed like `highlight_with_options` would process it, including
+    /// its injections, and the bindings are returned in the order in which their
+    /// definitions were found. Bindings from several layers can be interleaved. The options
+    /// that limit highlighting, like `byte_range`, also limit the bindings that are found.
+    pub fn resolve_locals<'a>(
+        &'a self,
+        context: &'a mut HighlightContext,
+        config: &'a HighlightConfiguration,
+        source: &'a [u8],
+        options: HighlightOptions<'a>,
+        injection_callback: impl Fn(&str) -> Option<&'a HighlightConfiguration> + 'a,
+    ) -> Result<Vec<LocalBinding>, Error> {
+        let mut iter = self.highlight_iter(
+            context,
+            config,
+            SourceText::Bytes(source),
+            options,
+            move |injection: Injection| injection_callback(injection.language),
+        )?;
+        iter.local_bindings = Some(Vec::new());
+        for event in &mut iter {
+            event?;
+        }
+        Ok(iter.local_bindings.take().unwrap_or_default())
+    }
+}
@@ -0,0 +1,110 @@
+use super::HighlightContext;
+use std::ops;
+use std::sync::Mutex;
+
+/// A pool of `HighlightContext`s that can be shared between threads, such as the worker
+/// threads of a language server, so that each highlighting call reuses the parser and the
+/// query cursors of an earlier one, whichever thread made it.
+///
+/// `acquire` takes a context out of the pool, or creates one if the pool is empty. The
+/// context is returned to the pool when the guard is dropped. At most `max_size` idle
+/// contexts are kept, and contexts that are returned to a full pool are dropped, so a
+/// burst of concurrent requests doesn't keep its parsers and cursors alive afterwards.
+pub struct HighlightContextPool {
+    contexts: Mutex<Vec<HighlightContext>>,
+    max_size: usize,
+}
+
+/// A `HighlightContext` that was acquired from a `HighlightContextPool`. It derefs to the
+/// context, so it can be passed to `Highlighter::highlight` as `&mut context`, and it is
+/// returned to the pool when it is dropped.
+pub struct PooledHighlightContext<'a> {
+    pool: &'a HighlightContextPool,
+    context: Option<HighlightContext>,
+}
+
+impl HighlightContextPool {
+    /// Creates an empty pool that keeps at most `max_size` idle contexts.
+    pub fn new(max_size: usize) -> Self {
+        HighlightContextPool {
+            contexts: Mutex::new(Vec::new()),
+            max_size,
+        }
+    }
+
+    /// Takes an idle context from the pool, or creates one if there are none.
+    pub fn acquire(&self) -> PooledHighlightContext<'_> {
+        let context = self.contexts.lock().unwrap().pop();
+        PooledHighlightContext {
+            pool: self,
+            context: Some(context.unwrap_or_default()),
+        }
+    }
+
+    /// Adds a context to the pool, unless the pool is full. This is done automatically
+    /// when a `PooledHighlightContext` is dropped, but it can also be used for contexts
+    /// that were created separately, or detached with `PooledHighlightContext::detach`.
+    ///
+    /// The syntax trees that the context retained from its last highlighting call are
+    /// dropped, along with its cancellation flag.
+    pub fn release(&self, mut context: HighlightContext) {
+        context.trees = None;
+        unsafe { context.parser.set_cancellation_flag(None) };
+        let mut contexts = self.contexts.lock().unwrap();
+        if contexts.len() < self.max_size {
+            contexts.push(context);
+        }
+    }
+
+    /// Returns the maximum number of idle contexts that the pool keeps.
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Returns the number of idle contexts in the pool.
+    pub fn len(&self) -> usize {
+        self.contexts.lock().unwrap().len()
+    }
+
+    /// Returns whether the pool has no idle contexts.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of query cursors that are held by the idle contexts in the pool,
+    /// to be reused by later highlighting calls.
+    pub fn cursor_count(&self) -> usize {
+        let contexts = self.contexts.lock().unwrap();
+        contexts.iter().map(|context| context.cursors.len()).sum()
+    }
+}
+
+impl<'a> PooledHighlightContext<'a> {
+    /// Removes the context from the pool's control, so that it isn't returned to the pool
+    /// when it is dropped.
+    pub fn detach(mut self) -> HighlightContext {
+        self.context.take().unwrap()
+    }
+}
+
+impl<'a> ops::Deref for PooledHighlightContext<'a> {
+    type Target = HighlightContext;
+
+    fn deref(&self) -> &HighlightContext {
+        self.context.as_ref().unwrap()
+    }
+}
+
+impl<'a> ops::DerefMut for PooledHighlightContext<'a> {
+    fn deref_mut(&mut self) -> &mut HighlightContext {
+        self.context.as_mut().unwrap()
+    }
+}
+
+impl<'a> Drop for PooledHighlightContext<'a> {
+    fn drop(&mut self) {
+        if let Some(context) = self.context.take() {
+            self.pool.release(context);
+        }
+    }
+}
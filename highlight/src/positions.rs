@@ -0,0 +1,120 @@
+use super::{
+    ByteOrderMarkHandling, Error, HighlightConfiguration, HighlightContext, HighlightEvent,
+    HighlightOptions, Highlighter, BYTE_ORDER_MARK,
+};
+use tree_sitter::{Point, Range};
+
+/// An iterator over highlight events, along with the range of source code that each event
+/// applies to. See `Highlighter::highlight_with_positions`.
+///
+/// The range of a `Source` event is the range of its source code. The other events apply
+/// to a single position, so their ranges are empty, and are located where the next
+/// `Source` event starts.
+pub struct HighlightPositions<'a, I> {
+    source: &'a [u8],
+    events: I,
+    byte_offset: usize,
+    position: Point,
+}
+
+impl Highlighter {
+    /// Iterate over the highlighted regions for a given slice of source code, like
+    /// `highlight_with_options`, along with the byte range and the row and column range of
+    /// each event.
+    ///
+    /// As in the rest of Tree-sitter, rows and columns are zero-based, and columns are
+    /// measured in bytes. The positions are relative to the start of the whole document,
+    /// including in injected languages, whose layers may start in the middle of a line.
+    pub fn highlight_with_positions<'a>(
+        &'a self,
+        context: &'a mut HighlightContext,
+        config: &'a HighlightConfiguration,
+        source: &'a [u8],
+        options: HighlightOptions<'a>,
+        injection_callback: impl Fn(&str) -> Option<&'a HighlightConfiguration> + 'a,
+    ) -> Result<
+        HighlightPositions<'a, impl Iterator<Item = Result<HighlightEvent, Error>> + 'a>,
+        Error,
+    > {
+        // The offsets of the events are relative to the part of the source code that
+        // follows a byte order mark if that mark is skipped and the offsets are rebased.
+        let offsets_source = match options.byte_order_mark_handling {
+            ByteOrderMarkHandling::SkipAndRebaseOffsets if source.starts_with(BYTE_ORDER_MARK) => {
+                &source[BYTE_ORDER_MARK.len()..]
+            }
+            _ => source,
+        };
+        let events =
+            self.highlight_with_options(context, config, source, options, injection_callback)?;
+        Ok(HighlightPositions::new(offsets_source, events))
+    }
+}
+
+impl<'a, I> HighlightPositions<'a, I>
+where
+    I: Iterator<Item = Result<HighlightEvent, Error>>,
+{
+    /// Computes the positions of a sequence of events for the given source code, such as
+    /// events that were stored and are now being rendered. The `Source` events must be in
+    /// order, as they are when they come from the highlighter.
+    pub fn new(source: &'a [u8], events: impl IntoIterator<IntoIter = I>) -> Self {
+        HighlightPositions {
+            source,
+            events: events.into_iter(),
+            byte_offset: 0,
+            position: Point::new(0, 0),
+        }
+    }
+
+    fn range_at_position(&self) -> Range {
+        Range {
+            start_byte: self.byte_offset,
+            end_byte: self.byte_offset,
+            start_point: self.position,
+            end_point: self.position,
+        }
+    }
+
+    fn advance_to(&mut self, offset: usize) {
+        let offset = offset.min(self.source.len());
+        if offset < self.byte_offset {
+            self.byte_offset = 0;
+            self.position = Point::new(0, 0);
+        }
+        let text = &self.source[self.byte_offset..offset];
+        match text.iter().rposition(|byte| *byte == b'\n') {
+            Some(i) => {
+                self.position.row += text.iter().filter(|byte| **byte == b'\n').count();
+                self.position.column = text.len() - i - 1;
+            }
+            None => self.position.column += text.len(),
+        }
+        self.byte_offset = offset;
+    }
+}
+
+impl<'a, I> Iterator for HighlightPositions<'a, I>
+where
+    I: Iterator<Item = Result<HighlightEvent, Error>>,
+{
+    type Item = Result<(HighlightEvent, Range), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let event = match self.events.next()? {
+            Ok(event) => event,
+            Err(error) => return Some(Err(error)),
+        };
+        let range = match event {
+            HighlightEvent::Source { start, end } => {
+                self.advance_to(start);
+                let mut range = self.range_at_position();
+                self.advance_to(end);
+                range.end_byte = self.byte_offset;
+                range.end_point = self.position;
+                range
+            }
+            _ => self.range_at_position(),
+        };
+        Some(Ok((event, range)))
+    }
+}
@@ -0,0 +1,147 @@
+use super::{HighlightConfiguration, QuerySection};
+use std::fmt::Write;
+
+impl HighlightConfiguration {
+    /// Returns the query that this configuration compiled, which is the concatenation of
+    /// its injections, locals and highlights queries, annotated for debugging.
+    ///
+    /// Each section is preceded by a comment with its byte range in the concatenated query
+    /// and the range of pattern indices that the highlighter treats as belonging to it.
+    /// Each pattern is preceded by a comment with its index, the section that it is
+    /// treated as belonging to, and its captures. The query is followed by a table that
+    /// maps each capture index to the highlight that it produces, given the highlighter's
+    /// list of highlight names.
+    pub fn debug_query_layout(&self, highlight_names: &[String]) -> String {
+        let pattern_count = self.query.pattern_count();
+        let sections = [
+            (
+                QuerySection::Injections,
+                0..self.locals_query_offset,
+                0..self.locals_pattern_index,
+            ),
+            (
+                QuerySection::Locals,
+                self.locals_query_offset..self.highlights_query_offset,
+                self.locals_pattern_index..self.highlights_pattern_index,
+            ),
+            (
+                QuerySection::Highlights,
+                self.highlights_query_offset..self.query_source.len(),
+                self.highlights_pattern_index..pattern_count,
+            ),
+        ];
+
+        // Collect the annotations along with the offsets before which they are written.
+        let mut annotations = Vec::new();
+        for (section, bytes, patterns) in &sections {
+            annotations.push((
+                bytes.start,
+                format!(
+                    ";; {} section: bytes {}..{}, patterns {}..{}",
+                    section_name(*section),
+                    bytes.start,
+                    bytes.end,
+                    patterns.start,
+                    patterns.end
+                ),
+            ));
+        }
+        for i in 0..pattern_count {
+            let start = self.query.start_byte_for_pattern(i);
+            let end = if i + 1 < pattern_count {
+                self.query.start_byte_for_pattern(i + 1)
+            } else {
+                self.query_source.len()
+            };
+            let section = sections
+                .iter()
+                .find(|(_, _, patterns)| patterns.contains(&i))
+                .map_or(QuerySection::Highlights, |(section, _, _)| *section);
+            let mut annotation = format!(";; pattern {} ({}):", i, section_name(section));
+            for name in capture_names(&self.query_source[start..end]) {
+                annotation += " @";
+                annotation += name;
+            }
+            annotations.push((start, annotation));
+        }
+        annotations.sort_by_key(|(offset, _)| *offset);
+
+        // Write each annotation before the line that contains its offset.
+        let mut result = String::new();
+        let mut annotations = annotations.into_iter().peekable();
+        let mut line_start = 0;
+        for line in self.query_source.split_inclusive('\n') {
+            let line_end = line_start + line.len();
+            while let Some((_, annotation)) = annotations.next_if(|(offset, _)| *offset < line_end)
+            {
+                result += &annotation;
+                result.push('\n');
+            }
+            result += line;
+            if !line.ends_with('\n') {
+                result.push('\n');
+            }
+            line_start = line_end;
+        }
+        for (_, annotation) in annotations {
+            result += &annotation;
+            result.push('\n');
+        }
+
+        result += ";; captures\n";
+        for (i, name) in self.query.capture_names().iter().enumerate() {
+            write!(&mut result, ";; {} @{}", i, name).unwrap();
+            if let Some(highlight) = self.highlight_indices[i] {
+                write!(&mut result, " -> {}", highlight_names[highlight.0]).unwrap();
+            }
+            result.push('\n');
+        }
+        result
+    }
+}
+
+fn section_name(section: QuerySection) -> &'static str {
+    match section {
+        QuerySection::Injections => "injections",
+        QuerySection::Locals => "locals",
+        QuerySection::Highlights => "highlights",
+    }
+}
+
+// Find the names of the captures in the source of a pattern, in the order in which they
+// first occur, skipping over strings and comments.
+fn capture_names(source: &str) -> Vec<&str> {
+    let mut result = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some((_, c)) = chars.next() {
+        match c {
+            ';' => while chars.next_if(|(_, c)| *c != '\n').is_some() {},
+            '"' => {
+                while let Some((_, c)) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            '@' => {
+                let start = chars.peek().map_or(source.len(), |(i, _)| *i);
+                let mut end = start;
+                while let Some((i, c)) =
+                    chars.next_if(|(_, c)| c.is_alphanumeric() || "_.-".contains(*c))
+                {
+                    end = i + c.len_utf8();
+                }
+                let name = &source[start..end];
+                if !name.is_empty() && !result.contains(&name) {
+                    result.push(name);
+                }
+            }
+            _ => {}
+        }
+    }
+    result
+}
@@ -0,0 +1,189 @@
+use super::query_validation::top_level_pattern_ranges;
+use super::{
+    check_language_version, ConfigurationError, Error, HighlightConfiguration, Highlighter,
+    QuerySection,
+};
+use std::fmt;
+use std::ops::Range;
+use tree_sitter::{Language, Query, QueryError};
+
+const UNKNOWN_PREDICATE_PREFIX: &str = "Unknown query predicate function ";
+
+// The characters that begin the query syntax that later versions of the query engine
+// added: alternations, quantifiers, anchors, negated fields and `#`-prefixed predicates.
+// A syntax error at any other character is a mistake in the query, not a newer feature.
+const NEWER_SYNTAX_CHARACTERS: &[u8] = b"[]+?.!#";
+
+// The wildcard node, which is parsed as a node type by this version of the query engine.
+const WILDCARD_NODE_TYPE: &str = "_";
+
+/// A pattern that was removed from a query by `Highlighter::load_configuration_salvaging`,
+/// because it uses a predicate or a syntax that this version of the query engine does not
+/// support. The `row` is the line on which the pattern starts, relative to the start of
+/// the query given by `section`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SkippedPattern {
+    pub section: QuerySection,
+    pub row: usize,
+    pub error: QueryError,
+}
+
+impl Highlighter {
+    /// Creates a `HighlightConfiguration` like `load_configuration`, but instead of failing
+    /// when a pattern uses an unknown predicate or an unsupported syntax, removes that
+    /// pattern and tries again. This allows query files that were written for a newer
+    /// version of Tree-sitter to be used, without their newer patterns. The unsupported
+    /// syntax that is recognized consists of alternations, quantifiers, anchors, negated
+    /// fields, wildcard nodes and `#`-prefixed predicates.
+    ///
+    /// At most `max_skipped_patterns` patterns are removed. If there are more, or if a
+    /// query has any other kind of error, such as an unknown node type or a syntax error
+    /// that isn't caused by one of those features, then the error is returned. Otherwise, the configuration is returned along with the patterns that were
+    /// skipped, so that they can be reported to the user.
+    pub fn load_configuration_salvaging(
+        &self,
+        language: Language,
+        highlights_query: &str,
+        injection_query: &str,
+        locals_query: &str,
+        max_skipped_patterns: usize,
+    ) -> Result<(HighlightConfiguration, Vec<SkippedPattern>), Error> {
+        check_language_version(language)?;
+        let mut skipped_patterns = Vec::new();
+        let mut injection_query = injection_query.to_string();
+        let mut locals_query = locals_query.to_string();
+        let mut highlights_query = highlights_query.to_string();
+        for (section, source) in [
+            (QuerySection::Injections, &mut injection_query),
+            (QuerySection::Locals, &mut locals_query),
+            (QuerySection::Highlights, &mut highlights_query),
+        ] {
+            let first_skipped_pattern = skipped_patterns.len();
+            while let Err(error) = Query::new(language, source) {
+                let range = match unsupported_pattern_range(source, &error) {
+                    Some(range) if skipped_patterns.len() < max_skipped_patterns => range,
+                    _ => {
+                        return Err(Error::InvalidQuery(ConfigurationError::new(
+                            section, source, error,
+                        )))
+                    }
+                };
+                skipped_patterns.push(SkippedPattern {
+                    section,
+                    row: source[..range.start].matches('\n').count() + 1,
+                    error,
+                });
+
+                // Replace the pattern with spaces, so that the rows and offsets of the
+                // remaining patterns are preserved.
+                let blank = source[range.clone()]
+                    .bytes()
+                    .map(|c| if c == b'\n' { '\n' } else { ' ' })
+                    .collect::<String>();
+                source.replace_range(range, &blank);
+            }
+            skipped_patterns[first_skipped_pattern..].sort_by_key(|pattern| pattern.row);
+        }
+
+        let configuration =
+            self.load_configuration(language, &highlights_query, &injection_query, &locals_query)?;
+        Ok((configuration, skipped_patterns))
+    }
+}
+
+impl fmt::Display for SkippedPattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let section = match self.section {
+            QuerySection::Injections => "injections",
+            QuerySection::Locals => "locals",
+            QuerySection::Highlights => "highlights",
+        };
+        match &self.error {
+            QueryError::Predicate(message) if message.starts_with(UNKNOWN_PREDICATE_PREFIX) => {
+                write!(
+                    f,
+                    "unsupported predicate {} at line {} of the {} query",
+                    &message[UNKNOWN_PREDICATE_PREFIX.len()..],
+                    self.row,
+                    section
+                )
+            }
+            _ => write!(
+                f,
+                "unsupported syntax at line {} of the {} query",
+                self.row, section
+            ),
+        }
+    }
+}
+
+// Find the range of the top-level pattern that caused a query error, if the error
+// indicates that the pattern uses a feature that the query engine does not support.
+fn unsupported_pattern_range(source: &str, error: &QueryError) -> Option<Range<usize>> {
+    let pattern_ranges = top_level_pattern_ranges(source);
+    match error {
+        QueryError::Syntax(row, message) => {
+            // The message ends with a line containing a caret beneath the error. If the
+            // error is at the end of the query, there is no caret, and the query is
+            // just incomplete.
+            let caret_line = message.rsplit('\n').next()?;
+            if !message.contains('\n') || !caret_line.ends_with('^') {
+                return None;
+            }
+            let offset = row_start(source, *row) + caret_line.len() - 1;
+            if !NEWER_SYNTAX_CHARACTERS.contains(source.as_bytes().get(offset)?) {
+                return None;
+            }
+            pattern_containing_offset(pattern_ranges, offset)
+        }
+        QueryError::NodeType(row, name) if name == WILDCARD_NODE_TYPE => {
+            // Node type errors don't have a column, so use the first wildcard on the row.
+            let row_start = row_start(source, *row);
+            let line = source[row_start..].split('\n').next()?;
+            let is_name_char = |c: u8| c.is_ascii_alphanumeric() || c == b'_' || c == b'-';
+            let column = line.match_indices(WILDCARD_NODE_TYPE).find_map(|(i, _)| {
+                let before = line.as_bytes()[..i].last().copied();
+                let after = line.as_bytes().get(i + 1).copied();
+                if !matches!(before, Some(c) if is_name_char(c))
+                    && !matches!(after, Some(c) if is_name_char(c))
+                {
+                    Some(i)
+                } else {
+                    None
+                }
+            })?;
+            pattern_containing_offset(pattern_ranges, row_start + column)
+        }
+        QueryError::Predicate(message) if message.starts_with(UNKNOWN_PREDICATE_PREFIX) => {
+            let name = &message[UNKNOWN_PREDICATE_PREFIX.len()..];
+            pattern_ranges.into_iter().find(|range| {
+                source[range.clone()].match_indices(name).any(|(i, _)| {
+                    let before = source[range.start..range.start + i].trim_end();
+                    let after = &source[range.start + i + name.len()..range.end];
+                    before.ends_with('(')
+                        && after.starts_with(|c: char| c.is_whitespace() || c == ')')
+                })
+            })
+        }
+        _ => None,
+    }
+}
+
+fn pattern_containing_offset(
+    pattern_ranges: Vec<Range<usize>>,
+    offset: usize,
+) -> Option<Range<usize>> {
+    pattern_ranges
+        .into_iter()
+        .take_while(|range| range.start <= offset)
+        .last()
+}
+
+// The byte offset of the start of the given one-based row.
+fn row_start(source: &str, row: usize) -> usize {
+    source
+        .split('\n')
+        .take(row - 1)
+        .map(|line| line.len() + 1)
+        .sum()
+}
@@ -0,0 +1,492 @@
+use super::HighlightConfiguration;
+use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+/// Identifies one of the queries from which a `HighlightConfiguration` was created.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum QuerySection {
+    Injections,
+    Locals,
+    Highlights,
+}
+
+/// Describes why a query pattern can never match any syntax tree produced by a grammar.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PatternIssueKind {
+    UnknownNodeType { kind: String, named: bool },
+    UnknownField(String),
+    FieldNotOnNode { node_kind: String, field: String },
+    InvalidFieldValue { field: String, kind: String },
+}
+
+/// A problem with a query pattern, found by `validate_against_node_types`. The `offset`
+/// is the byte offset of the offending node or field within the query given by `section`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PatternIssue {
+    pub pattern_index: usize,
+    pub section: QuerySection,
+    pub offset: usize,
+    pub kind: PatternIssueKind,
+}
+
+// These are read from `serde_json::Value`s instead of with derived `Deserialize` impls,
+// so that the crate only depends on `serde` directly when its `serde` feature is enabled.
+struct NodeTypeJSON {
+    kind: String,
+    named: bool,
+    fields: Vec<(String, Vec<NodeRefJSON>)>,
+    subtypes: Vec<NodeRefJSON>,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct NodeRefJSON {
+    kind: String,
+    named: bool,
+}
+
+struct PatternNode {
+    offset: usize,
+    kind: Option<NodeRefJSON>,
+    field: Option<(String, usize)>,
+    children: Vec<PatternNode>,
+}
+
+struct QueryParser<'a> {
+    source: &'a [u8],
+    position: usize,
+}
+
+struct NodeTypes {
+    kinds: HashSet<NodeRefJSON>,
+    field_names: HashSet<String>,
+    fields_by_kind: HashMap<String, HashMap<String, Vec<NodeRefJSON>>>,
+    subtypes: HashMap<NodeRefJSON, Vec<NodeRefJSON>>,
+}
+
+impl HighlightConfiguration {
+    /// Checks the node types and fields referenced by this configuration's queries against
+    /// a grammar's `node-types.json` file, and returns the patterns that can never match.
+    ///
+    /// The queries are also checked against the `Language` when they are compiled, but that
+    /// check does not detect hidden node types, or fields that are used on node types that
+    /// never have them.
+    pub fn validate_against_node_types(
+        &self,
+        node_types_json: &str,
+    ) -> Result<Vec<PatternIssue>, serde_json::Error> {
+        let node_types = serde_json::from_str::<Vec<Value>>(node_types_json)?
+            .into_iter()
+            .map(NodeTypeJSON::from_json)
+            .collect::<Result<_, _>>()?;
+        let node_types = NodeTypes::new(node_types);
+        let mut issues = Vec::new();
+        let mut parser = QueryParser {
+            source: self.query_source.as_bytes(),
+            position: 0,
+        };
+        let mut pattern_index = 0;
+        while let Some(pattern) = parser.parse_top_level_pattern() {
+            let mut pattern_issues = Vec::new();
+            node_types.check_pattern(&pattern, None, &mut pattern_issues);
+            for (offset, kind) in pattern_issues {
+                let (section, offset) = self.query_section_for_offset(offset);
+                issues.push(PatternIssue {
+                    pattern_index,
+                    section,
+                    offset,
+                    kind,
+                });
+            }
+            pattern_index += 1;
+        }
+        Ok(issues)
+    }
+
+    fn query_section_for_offset(&self, offset: usize) -> (QuerySection, usize) {
+        if offset >= self.highlights_query_offset {
+            (
+                QuerySection::Highlights,
+                offset - self.highlights_query_offset,
+            )
+        } else if offset >= self.locals_query_offset {
+            (QuerySection::Locals, offset - self.locals_query_offset)
+        } else {
+            (QuerySection::Injections, offset)
+        }
+    }
+}
+
+impl NodeTypeJSON {
+    fn from_json(value: Value) -> Result<Self, serde_json::Error> {
+        let mut object = serde_json::from_value::<Map<String, Value>>(value)?;
+        let fields =
+            serde_json::from_value::<Option<Map<String, Value>>>(take(&mut object, "fields"))?
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(name, field)| {
+                    let mut field = serde_json::from_value::<Map<String, Value>>(field)?;
+                    Ok((
+                        name,
+                        NodeRefJSON::list_from_json(take(&mut field, "types"))?,
+                    ))
+                })
+                .collect::<Result<_, serde_json::Error>>()?;
+        let subtypes = match take(&mut object, "subtypes") {
+            Value::Null => Vec::new(),
+            subtypes => NodeRefJSON::list_from_json(subtypes)?,
+        };
+        Ok(NodeTypeJSON {
+            kind: serde_json::from_value(take(&mut object, "type"))?,
+            named: serde_json::from_value(take(&mut object, "named"))?,
+            fields,
+            subtypes,
+        })
+    }
+}
+
+impl NodeRefJSON {
+    fn list_from_json(value: Value) -> Result<Vec<Self>, serde_json::Error> {
+        serde_json::from_value::<Vec<Map<String, Value>>>(value)?
+            .into_iter()
+            .map(|mut object| {
+                Ok(NodeRefJSON {
+                    kind: serde_json::from_value(take(&mut object, "type"))?,
+                    named: serde_json::from_value(take(&mut object, "named"))?,
+                })
+            })
+            .collect()
+    }
+}
+
+fn take(object: &mut Map<String, Value>, key: &str) -> Value {
+    object.remove(key).unwrap_or(Value::Null)
+}
+
+impl NodeTypes {
+    fn new(node_types: Vec<NodeTypeJSON>) -> Self {
+        let mut result = NodeTypes {
+            kinds: HashSet::new(),
+            field_names: HashSet::new(),
+            fields_by_kind: HashMap::new(),
+            subtypes: HashMap::new(),
+        };
+        for node_type in node_types {
+            let node_ref = NodeRefJSON {
+                kind: node_type.kind,
+                named: node_type.named,
+            };
+            if !node_type.subtypes.is_empty() {
+                result.subtypes.insert(node_ref.clone(), node_type.subtypes);
+            }
+            if node_ref.named {
+                let fields = result
+                    .fields_by_kind
+                    .entry(node_ref.kind.clone())
+                    .or_default();
+                for (name, types) in node_type.fields {
+                    result.field_names.insert(name.clone());
+                    fields.entry(name).or_default().extend(types);
+                }
+            }
+            result.kinds.insert(node_ref);
+        }
+        result
+    }
+
+    fn check_pattern(
+        &self,
+        node: &PatternNode,
+        parent_kind: Option<&str>,
+        issues: &mut Vec<(usize, PatternIssueKind)>,
+    ) {
+        // Error nodes don't appear in node-types.json, and they can contain any children.
+        if let Some(kind) = &node.kind {
+            if kind.named && kind.kind == "ERROR" {
+                return;
+            }
+            if !self.kinds.contains(kind) {
+                issues.push((
+                    node.offset,
+                    PatternIssueKind::UnknownNodeType {
+                        kind: kind.kind.clone(),
+                        named: kind.named,
+                    },
+                ));
+            }
+        }
+
+        if let Some((field, field_offset)) = &node.field {
+            if !self.field_names.contains(field) {
+                issues.push((*field_offset, PatternIssueKind::UnknownField(field.clone())));
+            } else if let Some((parent_kind, fields)) = parent_kind
+                .and_then(|kind| self.fields_by_kind.get(kind).map(|fields| (kind, fields)))
+            {
+                match fields.get(field) {
+                    None => issues.push((
+                        *field_offset,
+                        PatternIssueKind::FieldNotOnNode {
+                            node_kind: parent_kind.to_string(),
+                            field: field.clone(),
+                        },
+                    )),
+                    Some(field_types) => {
+                        if let Some(kind) = &node.kind {
+                            if self.kinds.contains(kind)
+                                && !field_types.iter().any(|t| self.is_subtype(kind, t))
+                            {
+                                issues.push((
+                                    node.offset,
+                                    PatternIssueKind::InvalidFieldValue {
+                                        field: field.clone(),
+                                        kind: kind.kind.clone(),
+                                    },
+                                ));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        let kind = node
+            .kind
+            .as_ref()
+            .filter(|kind| kind.named)
+            .map(|kind| kind.kind.as_str());
+        for child in &node.children {
+            self.check_pattern(child, kind, issues);
+        }
+    }
+
+    fn is_subtype(&self, kind: &NodeRefJSON, supertype: &NodeRefJSON) -> bool {
+        kind == supertype
+            || self.subtypes.get(supertype).is_some_and(|subtypes| {
+                subtypes
+                    .iter()
+                    .any(|subtype| self.is_subtype(kind, subtype))
+            })
+    }
+}
+
+// A lenient parser for the S-expression syntax of queries. The query has already been
+// compiled successfully, so the parser stops at any unexpected input rather than
+// reporting syntax errors.
+// Find the byte range of each top-level pattern in a query, including its predicates and
+// captures. The patterns are not validated, so this also works for queries that can't be
+// compiled. Comments between patterns are not included in any range.
+pub(crate) fn top_level_pattern_ranges(source: &str) -> Vec<Range<usize>> {
+    let mut parser = QueryParser {
+        source: source.as_bytes(),
+        position: 0,
+    };
+    let mut result = Vec::new();
+    loop {
+        parser.skip_whitespace();
+        let start = parser.position;
+        match parser.peek() {
+            None => break,
+            Some(b'(') | Some(b'[') => parser.skip_list(),
+            Some(b'"') => {
+                parser.position += 1;
+                while parser.peek().is_some_and(|c| c != b'"') {
+                    parser.position += 1;
+                }
+                parser.position += 1;
+            }
+            Some(_) => {
+                if parser.parse_identifier().is_none() {
+                    parser.position += 1;
+                }
+            }
+        }
+        while parser.peek().is_some_and(|c| b"+*?".contains(&c)) {
+            parser.position += 1;
+        }
+        let end = parser.position;
+        parser.skip_whitespace();
+        if parser.peek() == Some(b'@') {
+            parser.skip_capture();
+        } else {
+            parser.position = end;
+        }
+        parser.position = parser.position.min(source.len());
+        result.push(start..parser.position);
+    }
+    result
+}
+
+impl<'a> QueryParser<'a> {
+    fn parse_top_level_pattern(&mut self) -> Option<PatternNode> {
+        self.skip_whitespace();
+        if self.peek() == Some(b'(') {
+            let start = self.position;
+            self.position += 1;
+            self.skip_whitespace();
+
+            // A nested list contains a pattern followed by predicates.
+            if self.peek() == Some(b'(') {
+                let pattern = self.parse_pattern()?;
+                self.skip_whitespace();
+                while self.peek() == Some(b'(') {
+                    self.skip_list();
+                    self.skip_whitespace();
+                }
+                if self.peek() != Some(b')') {
+                    return None;
+                }
+                self.position += 1;
+                self.skip_capture();
+                return Some(pattern);
+            }
+            self.position = start;
+        }
+        self.parse_pattern()
+    }
+
+    fn parse_pattern(&mut self) -> Option<PatternNode> {
+        self.skip_whitespace();
+        let start = self.position;
+        let result = match self.peek()? {
+            b'(' => {
+                self.position += 1;
+                self.skip_whitespace();
+                let offset = self.position;
+                let kind = if self.peek() == Some(b'*') {
+                    self.position += 1;
+                    None
+                } else {
+                    Some(NodeRefJSON {
+                        kind: self.parse_identifier()?,
+                        named: true,
+                    })
+                };
+                let mut children = Vec::new();
+                loop {
+                    self.skip_whitespace();
+                    if self.peek()? == b')' {
+                        self.position += 1;
+                        break;
+                    }
+                    children.push(self.parse_pattern()?);
+                }
+                PatternNode {
+                    offset,
+                    kind,
+                    field: None,
+                    children,
+                }
+            }
+            b'"' => {
+                self.position += 1;
+                let offset = self.position;
+                while self.peek()? != b'"' {
+                    self.position += 1;
+                }
+                let kind = String::from_utf8_lossy(&self.source[offset..self.position]);
+                self.position += 1;
+                PatternNode {
+                    offset,
+                    kind: Some(NodeRefJSON {
+                        kind: kind.to_string(),
+                        named: false,
+                    }),
+                    field: None,
+                    children: Vec::new(),
+                }
+            }
+            b'*' => {
+                self.position += 1;
+                PatternNode {
+                    offset: start,
+                    kind: None,
+                    field: None,
+                    children: Vec::new(),
+                }
+            }
+            _ => {
+                let field = self.parse_identifier()?;
+                self.skip_whitespace();
+                if self.peek()? != b':' {
+                    return None;
+                }
+                self.position += 1;
+                let mut pattern = self.parse_pattern()?;
+                pattern.field = Some((field, start));
+                return Some(pattern);
+            }
+        };
+        self.skip_capture();
+        Some(result)
+    }
+
+    fn parse_identifier(&mut self) -> Option<String> {
+        let start = self.position;
+        while let Some(c) = self.peek() {
+            if c.is_ascii_alphanumeric() || b"_-.?!".contains(&c) || c >= 0x80 {
+                self.position += 1;
+            } else {
+                break;
+            }
+        }
+        if self.position == start {
+            None
+        } else {
+            Some(String::from_utf8_lossy(&self.source[start..self.position]).to_string())
+        }
+    }
+
+    fn skip_capture(&mut self) {
+        self.skip_whitespace();
+        if self.peek() == Some(b'@') {
+            self.position += 1;
+            self.parse_identifier();
+        }
+    }
+
+    fn skip_list(&mut self) {
+        let mut depth = 0;
+        while let Some(c) = self.peek() {
+            self.position += 1;
+            match c {
+                b'(' | b'[' => depth += 1,
+                b')' | b']' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return;
+                    }
+                }
+                b'"' => {
+                    while self.peek().is_some_and(|c| c != b'"') {
+                        self.position += 1;
+                    }
+                    self.position += 1;
+                }
+                b';' => self.skip_comment(),
+                _ => {}
+            }
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            if c.is_ascii_whitespace() {
+                self.position += 1;
+            } else if c == b';' {
+                self.skip_comment();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        while self.peek().is_some_and(|c| c != b'\n') {
+            self.position += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.source.get(self.position).cloned()
+    }
+}
@@ -0,0 +1,123 @@
+use super::HighlightConfiguration;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Owns the `HighlightConfiguration`s for a set of languages, and looks them up by name
+/// or by alias.
+///
+/// Names other than the exact names that languages were added with are matched
+/// case-insensitively. These include aliases and the configurations' own names. If a name
+/// can't be resolved, the registry's fallback handler, if any, is asked for the name of
+/// a language to use instead.
+///
+/// The registry can be shared between threads, and can be used directly as the injection
+/// callback for `Highlighter::highlight`, via `injection_callback`.
+#[derive(Clone, Default)]
+pub struct LanguageRegistry {
+    configurations: HashMap<String, Arc<HighlightConfiguration>>,
+    aliases: HashMap<String, String>,
+    fallback: Option<Arc<FallbackHandler>>,
+}
+
+// Returns the name of a language to use for a name that the registry can't resolve.
+type FallbackHandler = dyn Fn(&str) -> Option<String> + Send + Sync;
+
+impl LanguageRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a language to the registry, replacing any existing language with the same
+    /// name, and returns a shared reference to its configuration. The language is also
+    /// available under each of the configuration's names.
+    pub fn add_language(
+        &mut self,
+        name: &str,
+        configuration: HighlightConfiguration,
+    ) -> Arc<HighlightConfiguration> {
+        let configuration = Arc::new(configuration);
+        self.add_shared_language(name, configuration.clone());
+        configuration
+    }
+
+    /// Adds a language whose configuration is shared with other owners, such as a
+    /// `ConfigurationWatcher`, replacing any existing language with the same name.
+    ///
+    /// Highlighting that has already looked up the language's previous configuration
+    /// keeps using it. A registry that is shared between threads can be updated by
+    /// cloning it, which is cheap, and replacing the shared registry with the clone.
+    pub fn add_shared_language(&mut self, name: &str, configuration: Arc<HighlightConfiguration>) {
+        self.add_alias(name, name);
+        for alias in configuration.names() {
+            self.add_alias(alias, name);
+        }
+        self.configurations.insert(name.to_string(), configuration);
+    }
+
+    /// Makes a language that has been added to the registry available under another
+    /// name, such as a file extension or an alternative name used in injections.
+    pub fn add_alias(&mut self, alias: &str, name: &str) {
+        self.aliases.insert(alias.to_lowercase(), name.to_string());
+    }
+
+    /// Sets a handler that is called with any name that doesn't match a language or an
+    /// alias, and returns the name or alias of the language to use for it.
+    pub fn set_fallback(
+        &mut self,
+        fallback: impl Fn(&str) -> Option<String> + Send + Sync + 'static,
+    ) {
+        self.fallback = Some(Arc::new(fallback));
+    }
+
+    /// Returns a shared reference to the configuration for a given language name or alias.
+    pub fn get(&self, name: &str) -> Option<Arc<HighlightConfiguration>> {
+        self.resolve(name)
+            .and_then(|name| self.configurations.get(name))
+            .cloned()
+    }
+
+    /// Returns the configuration for a given language name or alias.
+    pub fn get_ref(&self, name: &str) -> Option<&HighlightConfiguration> {
+        self.resolve(name)
+            .and_then(|name| self.configurations.get(name))
+            .map(|configuration| configuration.as_ref())
+    }
+
+    /// Returns a callback that can be passed to `Highlighter::highlight` in order to
+    /// look up injected languages in this registry.
+    pub fn injection_callback<'a>(
+        &'a self,
+    ) -> impl Fn(&str) -> Option<&'a HighlightConfiguration> + 'a {
+        move |name| self.get_ref(name)
+    }
+
+    /// Returns the names of the languages in the registry, not including aliases.
+    pub fn language_names(&self) -> impl Iterator<Item = &str> {
+        self.configurations.keys().map(String::as_str)
+    }
+
+    fn resolve(&self, name: &str) -> Option<&str> {
+        self.resolve_name(name).or_else(|| {
+            let fallback = self.fallback.as_ref()?;
+            self.resolve_name(&fallback(name)?)
+        })
+    }
+
+    fn resolve_name(&self, name: &str) -> Option<&str> {
+        if let Some((name, _)) = self.configurations.get_key_value(name) {
+            Some(name)
+        } else {
+            self.aliases.get(&name.to_lowercase()).map(String::as_str)
+        }
+    }
+}
+
+// The registry is meant to be shared between threads that are highlighting concurrently.
+#[allow(dead_code)]
+fn assert_send_sync() {
+    fn check<T: Send + Sync>() {}
+    check::<HighlightConfiguration>();
+    check::<LanguageRegistry>();
+    #[cfg(feature = "fs")]
+    check::<super::ConfigurationWatcher>();
+}
@@ -0,0 +1,156 @@
+use super::util::LossyUtf8;
+use std::borrow::Cow;
+use std::{ops, str};
+
+/// Source code that is not necessarily contiguous in memory, such as the text of an
+/// editor's rope, which can be highlighted with `Highlighter::highlight_chunks`.
+pub trait HighlightSource {
+    /// Returns the text that starts at the given byte offset, up to the end of the chunk
+    /// that contains it. The text must not be empty if the offset is less than `len`.
+    fn chunk_at(&self, byte: usize) -> &[u8];
+
+    /// Returns the length of the source code in bytes.
+    fn len(&self) -> usize;
+
+    /// Returns whether the source code is empty.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl HighlightSource for &[u8] {
+    fn chunk_at(&self, byte: usize) -> &[u8] {
+        &self[byte.min(self.len())..]
+    }
+
+    fn len(&self) -> usize {
+        <[u8]>::len(self)
+    }
+}
+
+// The source code that a highlighter reads: either a slice, or a `HighlightSource` whose
+// text is read in chunks. Either way, the text can be limited to a range of the original
+// source code, whose start is then at offset zero.
+#[derive(Copy, Clone)]
+pub(crate) enum SourceText<'a> {
+    Bytes(&'a [u8]),
+    Chunks {
+        source: &'a dyn HighlightSource,
+        start: usize,
+        end: usize,
+    },
+}
+
+impl<'a> SourceText<'a> {
+    pub(crate) fn chunks(source: &'a dyn HighlightSource) -> Self {
+        SourceText::Chunks {
+            source,
+            start: 0,
+            end: source.len(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            SourceText::Bytes(bytes) => bytes.len(),
+            SourceText::Chunks { start, end, .. } => end - start,
+        }
+    }
+
+    // Returns the text from the given offset up to the end of its chunk.
+    pub(crate) fn chunk_at(&self, offset: usize) -> &'a [u8] {
+        match self {
+            SourceText::Bytes(bytes) => &bytes[offset.min(bytes.len())..],
+            SourceText::Chunks { source, start, end } => {
+                if offset >= end - start {
+                    return &[];
+                }
+                let chunk = source.chunk_at(start + offset);
+                &chunk[..chunk.len().min(end - start - offset)]
+            }
+        }
+    }
+
+    // Restricts the text to a range of the current text.
+    pub(crate) fn slice(&self, range: ops::Range<usize>) -> Self {
+        match self {
+            SourceText::Bytes(bytes) => SourceText::Bytes(&bytes[range]),
+            SourceText::Chunks { source, start, .. } => SourceText::Chunks {
+                source: *source,
+                start: start + range.start,
+                end: start + range.end,
+            },
+        }
+    }
+
+    pub(crate) fn byte(&self, offset: usize) -> Option<u8> {
+        self.chunk_at(offset).first().cloned()
+    }
+
+    // Returns the text within a range, which is clipped to the end of the text. The text is
+    // borrowed, unless it spans several chunks, in which case those chunks are copied.
+    pub(crate) fn text(&self, range: ops::Range<usize>) -> Cow<'a, [u8]> {
+        let end = range.end.min(self.len());
+        let start = range.start.min(end);
+        let chunk = self.chunk_at(start);
+        if chunk.len() >= end - start {
+            return Cow::Borrowed(&chunk[..end - start]);
+        }
+        let mut text = Vec::with_capacity(end - start);
+        while start + text.len() < end {
+            let chunk = self.chunk_at(start + text.len());
+            if chunk.is_empty() {
+                break;
+            }
+            let len = chunk.len().min(end - start - text.len());
+            text.extend_from_slice(&chunk[..len]);
+        }
+        Cow::Owned(text)
+    }
+
+    // Returns the text within a range as a string, if it is valid UTF-8.
+    pub(crate) fn str(&self, range: ops::Range<usize>) -> Option<Cow<'a, str>> {
+        match self.text(range) {
+            Cow::Borrowed(text) => str::from_utf8(text).ok().map(Cow::Borrowed),
+            Cow::Owned(text) => String::from_utf8(text).ok().map(Cow::Owned),
+        }
+    }
+
+    // Returns the text within a range as a string, like `str`, but if `lossy` is true,
+    // invalid UTF-8 sequences are replaced instead of causing `None` to be returned.
+    pub(crate) fn str_or_lossy(
+        &self,
+        range: ops::Range<usize>,
+        lossy: bool,
+    ) -> Option<Cow<'a, str>> {
+        if !lossy {
+            return self.str(range);
+        }
+        match self.text(range) {
+            Cow::Borrowed(text) => Some(LossyUtf8::new(text).to_cow()),
+            Cow::Owned(text) => Some(Cow::Owned(LossyUtf8::new(&text).collect())),
+        }
+    }
+
+    // Returns the line that contains the first character at or after the given offset that
+    // isn't whitespace, starting at that character and not including the line ending.
+    pub(crate) fn line_after_whitespace(&self, offset: usize) -> Cow<'a, [u8]> {
+        let mut start = offset.min(self.len());
+        while matches!(self.byte(start), Some(c) if c.is_ascii_whitespace()) {
+            start += 1;
+        }
+        let mut end = start;
+        loop {
+            let chunk = self.chunk_at(end);
+            match chunk.iter().position(|c| *c == b'\n') {
+                Some(i) => {
+                    end += i;
+                    break;
+                }
+                None if chunk.is_empty() => break,
+                None => end += chunk.len(),
+            }
+        }
+        self.text(start..end)
+    }
+}
@@ -0,0 +1,63 @@
+use super::{
+    Error, Highlight, HighlightConfiguration, HighlightContext, HighlightEvent, HighlightOptions,
+    Highlighter,
+};
+use std::ops;
+
+impl Highlighter {
+    /// Highlight a slice of source code, like `highlight_with_options`, and collect the
+    /// highlighted regions into a list of non-overlapping spans, in document order.
+    ///
+    /// Where highlights are nested, the innermost highlight is used, and the spans of the
+    /// outer highlights are split around it. Adjacent spans with the same highlight are
+    /// combined into one, and source code without any highlight is left out.
+    pub fn highlight_spans<'a>(
+        &'a self,
+        context: &'a mut HighlightContext,
+        config: &'a HighlightConfiguration,
+        source: &'a [u8],
+        options: HighlightOptions<'a>,
+        injection_callback: impl Fn(&str) -> Option<&'a HighlightConfiguration> + 'a,
+    ) -> Result<Vec<(ops::Range<usize>, Highlight)>, Error> {
+        let events =
+            self.highlight_with_options(context, config, source, options, injection_callback)?;
+        spans_from_events(events)
+    }
+}
+
+/// Collects a sequence of highlight events into a list of non-overlapping spans, in the
+/// same way as `Highlighter::highlight_spans`. This is useful for events that were stored
+/// and are now being used.
+pub fn spans_from_events(
+    events: impl IntoIterator<Item = Result<HighlightEvent, Error>>,
+) -> Result<Vec<(ops::Range<usize>, Highlight)>, Error> {
+    let mut spans = Vec::<(ops::Range<usize>, Highlight)>::new();
+    let mut highlights = Vec::new();
+    for event in events {
+        match event? {
+            HighlightEvent::HighlightStart(highlight)
+            | HighlightEvent::HighlightStartWithPattern { highlight, .. } => {
+                highlights.push(highlight)
+            }
+            HighlightEvent::HighlightEnd => {
+                highlights.pop();
+            }
+            HighlightEvent::Source { start, end } => {
+                let highlight = match highlights.last() {
+                    Some(highlight) if start < end => *highlight,
+                    _ => continue,
+                };
+                match spans.last_mut() {
+                    Some((range, last_highlight))
+                        if range.end == start && *last_highlight == highlight =>
+                    {
+                        range.end = end
+                    }
+                    _ => spans.push((start..end, highlight)),
+                }
+            }
+            HighlightEvent::LayerStart { .. } | HighlightEvent::LayerEnd => {}
+        }
+    }
+    Ok(spans)
+}
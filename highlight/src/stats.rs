@@ -0,0 +1,269 @@
+use super::{Error, HighlightConfiguration, HighlightEvent, Highlighter};
+#[cfg(feature = "serde")]
+use serde_derive::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Summarizes how much of a document was highlighted, and with which highlights. This is
+/// useful for tuning highlight queries.
+///
+/// Only non-whitespace bytes are counted. Each highlighted byte is attributed to its
+/// innermost highlight.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct CoverageStats {
+    /// The number of non-whitespace bytes in the highlighted region of the document.
+    pub total_bytes: usize,
+    /// The number of non-whitespace bytes that received any highlight.
+    pub highlighted_bytes: usize,
+    /// The fraction of non-whitespace bytes that received any highlight.
+    pub coverage: f64,
+    /// The number of times each highlight was applied, and the number of bytes that it
+    /// covered, keyed by highlight name.
+    pub highlights: BTreeMap<String, HighlightStats>,
+    /// The number of highlight captures whose names didn't match any recognized highlight
+    /// name. This is only known if the events were produced with an
+    /// `unrecognized_capture_counter`, whose value should be stored here.
+    pub unrecognized_captures: Option<usize>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct HighlightStats {
+    pub count: usize,
+    pub bytes: usize,
+}
+
+/// Describes the layers that were created for one injected language while highlighting
+/// a document. See `HighlightOptions::injection_stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InjectionStats {
+    /// The number of layers that were created for the language.
+    pub layer_count: usize,
+    /// The total number of bytes in the ranges of those layers.
+    pub bytes: usize,
+    /// The total time spent creating those layers, which is mostly spent parsing.
+    pub parse_time: Duration,
+    /// The number of `ERROR` and `MISSING` nodes in the syntax trees of those layers.
+    pub error_count: usize,
+}
+
+/// Describes the parsing that was done while highlighting a document, including the
+/// parsing of injected languages. See `HighlightContext::parse_stats`.
+///
+/// Documents with many injections may parse the same regions of the document several
+/// times, so `parsed_bytes` measures the work done more accurately than the length of
+/// the document.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ParseStats {
+    /// The number of layers that were created, including the document's own layer.
+    pub layer_count: usize,
+    /// The number of parses that were started. This is greater than `layer_count` if a
+    /// parse was cancelled or timed out.
+    pub parse_count: usize,
+    /// The total number of bytes in the included ranges of the parses.
+    pub parsed_bytes: usize,
+    /// The number of parses that reused a tree from a previous highlighting call. See
+    /// `HighlightOptions::previous_trees`.
+    pub reused_tree_count: usize,
+    /// Whether the syntax tree of the document's own layer contains any errors. Errors
+    /// in injected languages don't affect this.
+    pub root_has_error: bool,
+    /// The number of `ERROR` and `MISSING` nodes in the syntax trees of all of the
+    /// layers. Nodes that are nested inside of `ERROR` nodes are not counted.
+    pub error_count: usize,
+    /// The number of injections that were skipped because they were nested more deeply
+    /// than `HighlightOptions::max_injection_depth` allows.
+    pub skipped_injection_count: usize,
+}
+
+/// Counts the highlights that each pattern of a configuration's highlights query produced,
+/// across one or more highlighting calls. See `HighlightOptions::pattern_usage`.
+///
+/// A highlight is only counted once its event is emitted. Captures that are skipped,
+/// because an earlier pattern already highlighted the same node, or because a highlight
+/// from an injected language replaced them, aren't counted. Highlights produced by
+/// injected languages are only counted if they use the same configuration, and local
+/// variable references that are only highlighted because of their definition aren't
+/// counted, because they aren't produced by any highlights pattern.
+///
+/// Patterns are identified by their index within the highlights query, not within the
+/// configuration's combined query.
+#[derive(Debug)]
+pub struct PatternUsage {
+    counts: Vec<AtomicUsize>,
+    patterns: Vec<(usize, usize)>,
+    highlights_pattern_index: usize,
+}
+
+/// The number of highlights that a pattern produced, along with its position in the
+/// highlights query, so that it can be attributed to a line of the query file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct PatternCount {
+    /// The index of the pattern within the highlights query.
+    pub pattern_index: usize,
+    /// The byte offset of the pattern within the highlights query.
+    pub offset: usize,
+    /// The zero-based row of the pattern within the highlights query.
+    pub row: usize,
+    pub count: usize,
+}
+
+impl PatternUsage {
+    /// Creates a counter for each pattern of the given configuration's highlights query,
+    /// starting at zero.
+    pub fn new(config: &HighlightConfiguration) -> Self {
+        let highlights_query = &config.query_source[config.highlights_query_offset..];
+        let mut row = 0;
+        let mut row_offset = 0;
+        let patterns = (config.highlights_pattern_index..config.query.pattern_count())
+            .map(|pattern_index| {
+                let offset = config.query.start_byte_for_pattern(pattern_index)
+                    - config.highlights_query_offset;
+                row += highlights_query[row_offset..offset].matches('\n').count();
+                row_offset = offset;
+                (offset, row)
+            })
+            .collect();
+        PatternUsage {
+            counts: (config.highlights_pattern_index..config.query.pattern_count())
+                .map(|_| AtomicUsize::new(0))
+                .collect(),
+            patterns,
+            highlights_pattern_index: config.highlights_pattern_index,
+        }
+    }
+
+    /// Returns the number of highlights that the pattern with the given index within the
+    /// highlights query produced.
+    pub fn count(&self, pattern_index: usize) -> usize {
+        self.counts
+            .get(pattern_index)
+            .map_or(0, |count| count.load(Ordering::Relaxed))
+    }
+
+    /// Returns the count of each pattern in the highlights query, in order.
+    pub fn highlight_patterns(&self) -> Vec<PatternCount> {
+        self.patterns
+            .iter()
+            .enumerate()
+            .map(|(pattern_index, (offset, row))| PatternCount {
+                pattern_index,
+                offset: *offset,
+                row: *row,
+                count: self.count(pattern_index),
+            })
+            .collect()
+    }
+
+    /// Adds the counts of another counter for the same configuration to this one. This is
+    /// useful for combining the counts from documents that were highlighted in parallel.
+    ///
+    /// Panics if the counters were created for configurations with different numbers of
+    /// patterns.
+    pub fn merge(&self, other: &PatternUsage) {
+        assert_eq!(
+            self.counts.len(),
+            other.counts.len(),
+            "Pattern usage counters must be for the same configuration"
+        );
+        for (count, other_count) in self.counts.iter().zip(&other.counts) {
+            count.fetch_add(other_count.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+    }
+
+    // The pattern index is within the combined query, so patterns from the injections and
+    // locals queries are ignored.
+    pub(crate) fn increment(&self, pattern_index: usize) {
+        if let Some(index) = pattern_index.checked_sub(self.highlights_pattern_index) {
+            if let Some(count) = self.counts.get(index) {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Consumes a stream of highlight events for the given source code, and computes
+/// `CoverageStats` for it. The highlighter is used to look up highlight names.
+///
+/// To also count the unrecognized captures, pass a counter to the highlighter using
+/// `HighlightOptions::unrecognized_capture_counter`:
+///
+/// ```ignore
+/// let counter = AtomicUsize::new(0);
+/// let options = HighlightOptions::new().unrecognized_capture_counter(&counter);
+/// let events = highlighter.highlight_with_options(&mut context, &config, source, options, |_| None)?;
+/// let mut stats = stats::collect(events, source, &highlighter)?;
+/// stats.unrecognized_captures = Some(counter.load(Ordering::Relaxed));
+/// ```
+pub fn collect(
+    events: impl IntoIterator<Item = Result<HighlightEvent, Error>>,
+    source: &[u8],
+    highlighter: &Highlighter,
+) -> Result<CoverageStats, Error> {
+    let names = highlighter.names();
+    let mut counts = vec![HighlightStats::default(); names.len()];
+    let mut stack = Vec::new();
+    let mut stats = CoverageStats::default();
+    for event in events {
+        match event? {
+            HighlightEvent::HighlightStart(highlight)
+            | HighlightEvent::HighlightStartWithPattern { highlight, .. } => {
+                counts[highlight.0].count += 1;
+                stack.push(highlight);
+            }
+            HighlightEvent::HighlightEnd => {
+                stack.pop();
+            }
+            HighlightEvent::Source { start, end } => {
+                let bytes = source[start..end]
+                    .iter()
+                    .filter(|byte| !byte.is_ascii_whitespace())
+                    .count();
+                stats.total_bytes += bytes;
+                if let Some(highlight) = stack.last() {
+                    stats.highlighted_bytes += bytes;
+                    counts[highlight.0].bytes += bytes;
+                }
+            }
+            HighlightEvent::LayerStart { .. } | HighlightEvent::LayerEnd => {}
+        }
+    }
+
+    if stats.total_bytes > 0 {
+        stats.coverage = stats.highlighted_bytes as f64 / stats.total_bytes as f64;
+    }
+    stats.highlights = names
+        .iter()
+        .cloned()
+        .zip(counts)
+        .filter(|(_, counts)| counts.count > 0)
+        .collect();
+    Ok(stats)
+}
+
+impl fmt::Display for CoverageStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Highlighted {} of {} non-whitespace bytes ({:.1}%)",
+            self.highlighted_bytes,
+            self.total_bytes,
+            self.coverage * 100.0
+        )?;
+        if let Some(count) = self.unrecognized_captures {
+            writeln!(f, "Unrecognized captures: {}", count)?;
+        }
+        for (name, stats) in &self.highlights {
+            writeln!(
+                f,
+                "  {}: {} highlights, {} bytes",
+                name, stats.count, stats.bytes
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+use std::ops;
+use tree_sitter::{InputEdit, Language, Range, Tree};
+
+/// The syntax trees of the layers of a highlighted document, which are used to parse the
+/// document incrementally when it is highlighted again after an edit.
+///
+/// Keep the trees with `HighlightOptions::retain_trees`, and retrieve them afterwards with
+/// `HighlightContext::take_trees`. When the document is edited, apply each edit to the
+/// trees with `LayerTrees::edit`, and then pass them to `HighlightOptions::previous_trees`
+/// for the next highlighting call.
+///
+/// The layers of the edited document are matched up with the previous trees by their
+/// language, their depth, and the byte ranges of the injections, so a layer reuses the
+/// tree of the previous layer whose ranges overlap its own ranges the most.
+#[derive(Clone, Debug, Default)]
+pub struct LayerTrees {
+    layers: Vec<LayerTree>,
+}
+
+#[derive(Clone, Debug)]
+struct LayerTree {
+    language: Language,
+    language_name: Option<String>,
+    depth: usize,
+    ranges: Vec<ops::Range<usize>>,
+    tree: Tree,
+}
+
+impl LayerTrees {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of layers whose trees were kept.
+    pub fn len(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    /// Returns the syntax tree of the document's own layer.
+    pub fn root(&self) -> Option<&Tree> {
+        self.layers
+            .iter()
+            .find(|layer| layer.depth == 0)
+            .map(|layer| &layer.tree)
+    }
+
+    /// Applies an edit of the document to each of the trees, and to the ranges of the
+    /// layers. Edits must be applied in the order in which they were made.
+    pub fn edit(&mut self, edit: &InputEdit) {
+        for layer in &mut self.layers {
+            layer.tree.edit(edit);
+            for range in &mut layer.ranges {
+                // As in Tree-sitter's own editing of included ranges, a range that ends
+                // at `usize::MAX` extends to the end of the document.
+                if range.end >= edit.old_end_byte {
+                    if range.end != usize::MAX {
+                        range.end = edit.new_end_byte + (range.end - edit.old_end_byte);
+                    }
+                } else if range.end > edit.start_byte {
+                    range.end = edit.start_byte;
+                }
+                if range.start >= edit.old_end_byte {
+                    range.start = edit.new_end_byte + (range.start - edit.old_end_byte);
+                } else if range.start > edit.start_byte {
+                    range.start = edit.start_byte;
+                }
+            }
+        }
+    }
+
+    /// Returns the layers whose trees were kept, in the order in which they were parsed,
+    /// starting with the document's own layer. Each layer's name is the name of its
+    /// injected language, or `None` for the document's own layer. Its byte ranges are the
+    /// parts of the document that it was parsed from, and the last range of the document's
+    /// own layer ends at `usize::MAX`.
+    ///
+    /// This is useful to run other queries, such as for code folding, on the same trees
+    /// that were used for highlighting, instead of parsing the document again.
+    pub fn into_layers(self) -> Vec<(Option<String>, Tree, Vec<ops::Range<usize>>)> {
+        self.layers
+            .into_iter()
+            .map(|layer| (layer.language_name, layer.tree, layer.ranges))
+            .collect()
+    }
+
+    pub(crate) fn push(
+        &mut self,
+        language: Language,
+        language_name: Option<String>,
+        depth: usize,
+        ranges: &[Range],
+        tree: Tree,
+    ) {
+        self.layers.push(LayerTree {
+            language,
+            language_name,
+            depth,
+            ranges: ranges
+                .iter()
+                .map(|range| range.start_byte..range.end_byte)
+                .collect(),
+            tree,
+        });
+    }
+
+    // Ranges that only touch each other are still considered to overlap, so that a layer
+    // whose content was deleted entirely can still be matched.
+    pub(crate) fn find(&self, language: Language, depth: usize, ranges: &[Range]) -> Option<&Tree> {
+        let mut result = None;
+        let mut max_overlap = 0;
+        for layer in &self.layers {
+            if layer.language != language || layer.depth != depth {
+                continue;
+            }
+            let mut overlaps = false;
+            let mut overlap = 0;
+            for range in ranges {
+                for previous_range in &layer.ranges {
+                    let start = range.start_byte.max(previous_range.start);
+                    let end = range.end_byte.min(previous_range.end);
+                    if start <= end {
+                        overlaps = true;
+                        overlap += end - start;
+                    }
+                }
+            }
+            if overlaps && (result.is_none() || overlap > max_overlap) {
+                result = Some(&layer.tree);
+                max_overlap = overlap;
+            }
+        }
+        result
+    }
+}
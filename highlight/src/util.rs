@@ -1,15 +1,82 @@
-use std::str;
+use std::borrow::Cow;
+use std::{ops, str};
 
+/// Determines what `LossyUtf8` yields for the invalid UTF-8 sequences of its input.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum InvalidUtf8 {
+    /// Each invalid sequence is replaced with a single U+FFFD replacement character, in
+    /// the same way as `String::from_utf8_lossy`. An incomplete character at the end of
+    /// the input counts as an invalid sequence.
+    #[default]
+    Replace,
+    /// Invalid sequences are left out.
+    Skip,
+}
+
+/// An iterator over the text of a byte string that may contain invalid UTF-8, which
+/// yields the valid parts of the string as they are, and handles each invalid sequence
+/// according to an `InvalidUtf8` setting, which replaces it by default.
+///
+/// Use `chunks` to also get the byte range of each part, such as to keep track of
+/// offsets into the original bytes.
+#[derive(Clone, Debug)]
 pub struct LossyUtf8<'a> {
+    chunks: LossyUtf8Chunks<'a>,
+    invalid: InvalidUtf8,
+}
+
+/// A part of the input of `LossyUtf8Chunks`, which is either valid UTF-8, or a single
+/// invalid sequence.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LossyUtf8Chunk<'a> {
+    /// The range of the chunk within the input, in bytes.
+    pub range: ops::Range<usize>,
+    /// The text of the chunk, or `None` if the chunk is an invalid sequence.
+    pub text: Option<&'a str>,
+}
+
+/// An iterator over the chunks of a byte string, in order. The ranges of the chunks
+/// partition the string: each starts where the previous one ended, and none are empty.
+#[derive(Clone, Debug)]
+pub struct LossyUtf8Chunks<'a> {
     bytes: &'a [u8],
-    in_replacement: bool,
+    offset: usize,
+    pending_invalid: Option<ops::Range<usize>>,
 }
 
 impl<'a> LossyUtf8<'a> {
     pub fn new(bytes: &'a [u8]) -> Self {
         LossyUtf8 {
-            bytes,
-            in_replacement: false,
+            chunks: LossyUtf8Chunks::new(bytes),
+            invalid: InvalidUtf8::default(),
+        }
+    }
+
+    /// Sets how invalid sequences are handled.
+    pub fn invalid_utf8(mut self, invalid: InvalidUtf8) -> Self {
+        self.invalid = invalid;
+        self
+    }
+
+    /// Returns an iterator over the remaining chunks of the input, along with their byte
+    /// ranges.
+    pub fn chunks(self) -> LossyUtf8Chunks<'a> {
+        self.chunks
+    }
+
+    /// Collects the text into a string, which is only allocated if the input contains an
+    /// invalid sequence.
+    pub fn to_cow(self) -> Cow<'a, str> {
+        let mut chunks = self.chunks.clone();
+        match (chunks.next(), chunks.next()) {
+            (None, _) => Cow::Borrowed(""),
+            (
+                Some(LossyUtf8Chunk {
+                    text: Some(text), ..
+                }),
+                None,
+            ) => Cow::Borrowed(text),
+            _ => Cow::Owned(self.collect()),
         }
     }
 }
@@ -18,39 +85,74 @@ impl<'a> Iterator for LossyUtf8<'a> {
     type Item = &'a str;
 
     fn next(&mut self) -> Option<&'a str> {
+        loop {
+            let chunk = self.chunks.next()?;
+            match (chunk.text, self.invalid) {
+                (Some(text), _) => return Some(text),
+                (None, InvalidUtf8::Replace) => return Some("\u{fffd}"),
+                (None, InvalidUtf8::Skip) => {}
+            }
+        }
+    }
+}
+
+impl<'a> LossyUtf8Chunks<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        LossyUtf8Chunks {
+            bytes,
+            offset: 0,
+            pending_invalid: None,
+        }
+    }
+}
+
+impl<'a> Iterator for LossyUtf8Chunks<'a> {
+    type Item = LossyUtf8Chunk<'a>;
+
+    fn next(&mut self) -> Option<LossyUtf8Chunk<'a>> {
+        if let Some(range) = self.pending_invalid.take() {
+            return Some(LossyUtf8Chunk { range, text: None });
+        }
         if self.bytes.is_empty() {
             return None;
         }
-        if self.in_replacement {
-            self.in_replacement = false;
-            return Some("\u{fffd}");
-        }
+        let start = self.offset;
         match str::from_utf8(self.bytes) {
             Ok(valid) => {
                 self.bytes = &[];
-                Some(valid)
+                self.offset += valid.len();
+                Some(LossyUtf8Chunk {
+                    range: start..self.offset,
+                    text: Some(valid),
+                })
             }
             Err(error) => {
-                if let Some(error_len) = error.error_len() {
-                    let error_start = error.valid_up_to();
-                    if error_start > 0 {
-                        let result =
-                            unsafe { str::from_utf8_unchecked(&self.bytes[..error_start]) };
-                        self.bytes = &self.bytes[(error_start + error_len)..];
-                        self.in_replacement = true;
-                        Some(result)
-                    } else {
-                        self.bytes = &self.bytes[error_len..];
-                        Some("\u{fffd}")
-                    }
+                // An incomplete character at the end of the input counts as an invalid
+                // sequence.
+                let error_start = error.valid_up_to();
+                let error_len = error.error_len().unwrap_or(self.bytes.len() - error_start);
+                let valid = unsafe { str::from_utf8_unchecked(&self.bytes[..error_start]) };
+                let invalid_range = (start + error_start)..(start + error_start + error_len);
+                self.bytes = &self.bytes[(error_start + error_len)..];
+                self.offset = invalid_range.end;
+                if error_start > 0 {
+                    self.pending_invalid = Some(invalid_range);
+                    Some(LossyUtf8Chunk {
+                        range: start..(start + error_start),
+                        text: Some(valid),
+                    })
                 } else {
-                    None
+                    Some(LossyUtf8Chunk {
+                        range: invalid_range,
+                        text: None,
+                    })
                 }
             }
         }
     }
 }
 
+/// Returns the HTML escape sequence for a character of a text node, if it needs one.
 pub fn html_escape(c: u8) -> Option<&'static [u8]> {
     match c as char {
         '>' => Some(b"&gt;"),
@@ -61,3 +163,114 @@ pub fn html_escape(c: u8) -> Option<&'static [u8]> {
         _ => None,
     }
 }
+
+/// Returns the HTML escape sequence for a character of a quoted attribute value, if it
+/// needs one. In addition to the characters that `html_escape` escapes, line endings are
+/// escaped, so that a value never spans several lines of the rendered HTML.
+pub fn html_escape_attribute(c: u8) -> Option<&'static [u8]> {
+    match c as char {
+        '\n' => Some(b"&#10;"),
+        '\r' => Some(b"&#13;"),
+        _ => html_escape(c),
+    }
+}
+
+/// The set of characters that `HtmlRenderer` escapes. By default, these are the characters
+/// that `html_escape` escapes in text, and that `html_escape_attribute` escapes in
+/// attribute values. More ASCII characters can be added, such as `/` for pages that are
+/// checked by a strict content security policy. They are written as hexadecimal character
+/// references, like `&#x2F;`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct HtmlEscapes {
+    // A bit for each additional ASCII character.
+    additional: u128,
+}
+
+impl HtmlEscapes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the given characters to the set. Characters that aren't ASCII are ignored.
+    pub fn with(mut self, chars: &str) -> Self {
+        for c in chars.bytes().filter(u8::is_ascii) {
+            self.additional |= 1 << c;
+        }
+        self
+    }
+
+    /// Returns whether the given character is escaped in text.
+    pub fn contains(&self, c: u8) -> bool {
+        html_escape(c).is_some() || (c.is_ascii() && self.additional & (1 << c) != 0)
+    }
+
+    /// Appends text to the output, escaping the characters in the set.
+    pub fn escape_text(&self, text: &[u8], output: &mut Vec<u8>) {
+        for c in text {
+            self.push(*c, html_escape, output);
+        }
+    }
+
+    /// Appends the value of a quoted attribute to the output, escaping the characters in
+    /// the set, along with the line endings that `html_escape_attribute` escapes.
+    pub fn escape_attribute(&self, value: &[u8], output: &mut Vec<u8>) {
+        for c in value {
+            self.push(*c, html_escape_attribute, output);
+        }
+    }
+
+    pub(crate) fn push(
+        &self,
+        c: u8,
+        escape: fn(u8) -> Option<&'static [u8]>,
+        output: &mut Vec<u8>,
+    ) {
+        if let Some(escape) = escape(c) {
+            output.extend_from_slice(escape);
+        } else if c.is_ascii() && self.additional & (1 << c) != 0 {
+            output.extend_from_slice(format!("&#x{:X};", c).as_bytes());
+        } else {
+            output.push(c);
+        }
+    }
+}
+
+/// Returns the zero-based line number of the given byte offset within a query
+/// source, such as the `pattern_offset` of a `HighlightStartWithPattern` event.
+pub fn query_line_for_offset(query_source: &str, offset: usize) -> usize {
+    query_source.as_bytes()[..offset.min(query_source.len())]
+        .iter()
+        .filter(|c| **c == b'\n')
+        .count()
+}
+
+/// Chooses which of the content ranges of an injection to keep, so that the kept ranges
+/// don't overlap, and returns their indices in order of their position.
+///
+/// When one range contains another, the outer range is kept, unless `prefer_inner` is
+/// true, in which case the inner range is kept. When two ranges overlap without one
+/// containing the other, the one that starts first is kept.
+pub fn non_overlapping_ranges(ranges: &[ops::Range<usize>], prefer_inner: bool) -> Vec<usize> {
+    let mut indices = (0..ranges.len()).collect::<Vec<_>>();
+    indices.sort_by_key(|i| (ranges[*i].start, usize::MAX - ranges[*i].end));
+
+    let mut result: Vec<usize> = Vec::with_capacity(indices.len());
+    for i in indices {
+        let range = &ranges[i];
+        if prefer_inner {
+            while let Some(last) = result.last() {
+                let last = &ranges[*last];
+                if last.start <= range.start && range.start < last.end && range.end <= last.end {
+                    result.pop();
+                } else {
+                    break;
+                }
+            }
+        }
+        match result.last() {
+            Some(last) if range.start < ranges[*last].end => {}
+            _ => result.push(i),
+        }
+    }
+    result
+}
@@ -0,0 +1,108 @@
+use super::language_dir::read_file;
+use super::{HighlightConfiguration, Highlighter, LoadError};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use tree_sitter::Language;
+
+/// Owns the paths of the query files for a `HighlightConfiguration`, and rebuilds the
+/// configuration when their contents change. This is meant for development workflows,
+/// where queries are edited while an application is running.
+///
+/// The configuration is handed out as an `Arc`, which `reload` replaces atomically.
+/// Highlighting that is already in progress keeps using the configuration that it was
+/// started with, and only later calls to `current` return the new one. To make a
+/// reloaded configuration available for injections, add it to a `LanguageRegistry` with
+/// `add_shared_language`.
+///
+/// The watcher can be shared between threads.
+pub struct ConfigurationWatcher {
+    highlighter: Highlighter,
+    language: Language,
+    highlights_path: PathBuf,
+    injections_path: Option<PathBuf>,
+    locals_path: Option<PathBuf>,
+    names: Vec<String>,
+    sources: Mutex<Option<[String; 3]>>,
+    configuration: RwLock<Option<Arc<HighlightConfiguration>>>,
+}
+
+impl ConfigurationWatcher {
+    /// Creates a watcher for a language's highlights query. Nothing is read until the
+    /// first call to `reload`.
+    pub fn new(highlighter: &Highlighter, language: Language, highlights_path: &Path) -> Self {
+        Self {
+            highlighter: highlighter.clone(),
+            language,
+            highlights_path: highlights_path.to_owned(),
+            injections_path: None,
+            locals_path: None,
+            names: Vec::new(),
+            sources: Mutex::new(None),
+            configuration: RwLock::new(None),
+        }
+    }
+
+    /// Sets the path of the injections query.
+    pub fn injections_path(mut self, path: &Path) -> Self {
+        self.injections_path = Some(path.to_owned());
+        self
+    }
+
+    /// Sets the path of the locals query.
+    pub fn locals_path(mut self, path: &Path) -> Self {
+        self.locals_path = Some(path.to_owned());
+        self
+    }
+
+    /// Sets the names of each configuration that is built, as with
+    /// `HighlightConfiguration::with_names`.
+    pub fn names(mut self, names: Vec<String>) -> Self {
+        self.names = names;
+        self
+    }
+
+    /// Returns the most recently built configuration, or `None` if no configuration has
+    /// been built successfully yet.
+    pub fn current(&self) -> Option<Arc<HighlightConfiguration>> {
+        self.configuration.read().unwrap().clone()
+    }
+
+    /// Reads the query files, and builds a new configuration if their contents have
+    /// changed since the last successful reload. Returns whether the configuration was
+    /// replaced.
+    ///
+    /// If a file can't be read or a query is invalid, the error is returned and the
+    /// current configuration is kept, so the next call will try again.
+    pub fn reload(&self) -> Result<bool, LoadError> {
+        // Holding this lock while building ensures that concurrent reloads don't build
+        // the same configuration twice, while `current` remains available.
+        let mut sources = self.sources.lock().unwrap();
+        let new_sources = [
+            read_file(&self.highlights_path)?,
+            read_optional_file(self.injections_path.as_deref())?,
+            read_optional_file(self.locals_path.as_deref())?,
+        ];
+        if sources.as_ref() == Some(&new_sources) {
+            return Ok(false);
+        }
+
+        let [highlights_query, injections_query, locals_query] = &new_sources;
+        let configuration = self
+            .highlighter
+            .load_configuration(
+                self.language,
+                highlights_query,
+                injections_query,
+                locals_query,
+            )
+            .map_err(LoadError::from)?
+            .with_names(self.names.clone());
+        *self.configuration.write().unwrap() = Some(Arc::new(configuration));
+        *sources = Some(new_sources);
+        Ok(true)
+    }
+}
+
+fn read_optional_file(path: Option<&Path>) -> Result<String, LoadError> {
+    path.map_or(Ok(String::new()), read_file)
+}
@@ -18,6 +18,7 @@ use std::sync::atomic::AtomicUsize;
 use std::{char, fmt, ptr, slice, str, u16};
 
 pub const LANGUAGE_VERSION: usize = ffi::TREE_SITTER_LANGUAGE_VERSION;
+pub const MIN_COMPATIBLE_LANGUAGE_VERSION: usize = ffi::TREE_SITTER_MIN_COMPATIBLE_LANGUAGE_VERSION;
 pub const PARSER_HEADER: &'static str = include_str!("../include/tree_sitter/parser.h");
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -73,9 +74,9 @@ pub struct TreeCursor<'a>(ffi::TSTreeCursor, PhantomData<&'a ()>);
 
 #[derive(Debug)]
 enum TextPredicate {
-    CaptureEqString(u32, String),
-    CaptureEqCapture(u32, u32),
-    CaptureMatchString(u32, regex::bytes::Regex),
+    CaptureEqString(u32, String, bool),
+    CaptureEqCapture(u32, u32, bool),
+    CaptureMatchString(u32, regex::bytes::Regex, bool),
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -890,7 +891,11 @@ impl Query {
                 let mut length = 0u32;
                 let raw_predicates =
                     ffi::ts_query_predicates_for_pattern(ptr, i as u32, &mut length as *mut u32);
-                slice::from_raw_parts(raw_predicates, length as usize)
+                if length > 0 {
+                    slice::from_raw_parts(raw_predicates, length as usize)
+                } else {
+                    &[]
+                }
             };
 
             let type_done = ffi::TSQueryPredicateStepType_TSQueryPredicateStepTypeDone;
@@ -915,46 +920,57 @@ impl Query {
                 // Build a predicate for each of the known predicate function names.
                 let operator_name = &string_values[p[0].value_id as usize];
                 match operator_name.as_str() {
-                    "eq?" => {
+                    "eq?" | "not-eq?" => {
                         if p.len() != 3 {
                             return Err(QueryError::Predicate(format!(
-                                "Wrong number of arguments to eq? predicate. Expected 2, got {}.",
+                                "Wrong number of arguments to {} predicate. Expected 2, got {}.",
+                                operator_name,
                                 p.len() - 1
                             )));
                         }
                         if p[1].type_ != type_capture {
                             return Err(QueryError::Predicate(format!(
-                                "First argument to eq? predicate must be a capture name. Got literal \"{}\".",
+                                "First argument to {} predicate must be a capture name. Got literal \"{}\".",
+                                operator_name,
                                 string_values[p[1].value_id as usize],
                             )));
                         }
 
+                        let is_positive = operator_name == "eq?";
                         text_predicates.push(if p[2].type_ == type_capture {
-                            TextPredicate::CaptureEqCapture(p[1].value_id, p[2].value_id)
+                            TextPredicate::CaptureEqCapture(
+                                p[1].value_id,
+                                p[2].value_id,
+                                is_positive,
+                            )
                         } else {
                             TextPredicate::CaptureEqString(
                                 p[1].value_id,
                                 string_values[p[2].value_id as usize].clone(),
+                                is_positive,
                             )
                         });
                     }
 
-                    "match?" => {
+                    "match?" | "not-match?" => {
                         if p.len() != 3 {
                             return Err(QueryError::Predicate(format!(
-                                "Wrong number of arguments to match? predicate. Expected 2, got {}.",
+                                "Wrong number of arguments to {} predicate. Expected 2, got {}.",
+                                operator_name,
                                 p.len() - 1
                             )));
                         }
                         if p[1].type_ != type_capture {
                             return Err(QueryError::Predicate(format!(
-                                "First argument to match? predicate must be a capture name. Got literal \"{}\".",
+                                "First argument to {} predicate must be a capture name. Got literal \"{}\".",
+                                operator_name,
                                 string_values[p[1].value_id as usize],
                             )));
                         }
                         if p[2].type_ == type_capture {
                             return Err(QueryError::Predicate(format!(
-                                "Second argument to match? predicate must be a literal. Got capture @{}.",
+                                "Second argument to {} predicate must be a literal. Got capture @{}.",
+                                operator_name,
                                 result.capture_names[p[2].value_id as usize],
                             )));
                         }
@@ -965,6 +981,7 @@ impl Query {
                             regex::bytes::Regex::new(regex).map_err(|_| {
                                 QueryError::Predicate(format!("Invalid regex '{}'", regex))
                             })?,
+                            operator_name == "match?",
                         ));
                     }
 
@@ -1103,11 +1120,11 @@ impl QueryCursor {
         QueryCursor(unsafe { NonNull::new_unchecked(ffi::ts_query_cursor_new()) })
     }
 
-    pub fn matches<'a>(
+    pub fn matches<'a, T: AsRef<[u8]>>(
         &mut self,
         query: &'a Query,
         node: Node<'a>,
-        mut text_callback: impl FnMut(Node<'a>) -> &[u8] + 'a,
+        mut text_callback: impl FnMut(Node<'a>) -> T + 'a,
     ) -> impl Iterator<Item = QueryMatch<'a>> + 'a {
         let ptr = self.0.as_ptr();
         unsafe { ffi::ts_query_cursor_exec(ptr, query.ptr.as_ptr(), node.0) };
@@ -1166,11 +1183,15 @@ impl<'a> QueryMatch<'a> {
             cursor,
             id: m.id,
             pattern_index: m.pattern_index as usize,
-            captures: unsafe {
-                slice::from_raw_parts(
-                    m.captures as *const QueryCapture<'a>,
-                    m.capture_count as usize,
-                )
+            captures: if m.capture_count > 0 {
+                unsafe {
+                    slice::from_raw_parts(
+                        m.captures as *const QueryCapture<'a>,
+                        m.capture_count as usize,
+                    )
+                }
+            } else {
+                &[]
             },
         }
     }
@@ -1183,18 +1204,18 @@ impl<'a> QueryMatch<'a> {
         query.text_predicates[self.pattern_index]
             .iter()
             .all(|predicate| match predicate {
-                TextPredicate::CaptureEqCapture(i, j) => {
+                TextPredicate::CaptureEqCapture(i, j, is_positive) => {
                     let node1 = self.capture_for_index(*i).unwrap();
                     let node2 = self.capture_for_index(*j).unwrap();
-                    text_callback(node1).as_ref() == text_callback(node2).as_ref()
+                    (text_callback(node1).as_ref() == text_callback(node2).as_ref()) == *is_positive
                 }
-                TextPredicate::CaptureEqString(i, s) => {
+                TextPredicate::CaptureEqString(i, s, is_positive) => {
                     let node = self.capture_for_index(*i).unwrap();
-                    text_callback(node).as_ref() == s.as_bytes()
+                    (text_callback(node).as_ref() == s.as_bytes()) == *is_positive
                 }
-                TextPredicate::CaptureMatchString(i, r) => {
+                TextPredicate::CaptureMatchString(i, r, is_positive) => {
                     let node = self.capture_for_index(*i).unwrap();
-                    r.is_match(text_callback(node).as_ref())
+                    r.is_match(text_callback(node).as_ref()) == *is_positive
                 }
             })
     }
@@ -1332,6 +1353,7 @@ impl<'a> Into<ffi::TSInputEdit> for &'a InputEdit {
 unsafe impl Send for Language {}
 unsafe impl Send for Parser {}
 unsafe impl Send for Query {}
+unsafe impl Send for QueryCursor {}
 unsafe impl Send for Tree {}
 unsafe impl Sync for Language {}
 unsafe impl Sync for Query {}
@@ -740,13 +740,15 @@ class Language {
             throw new Error('Predicates must begin with a literal value');
           }
           const operator = steps[0].value;
+          const isPositive = !operator.startsWith('not-');
           switch (operator) {
             case 'eq?':
+            case 'not-eq?':
               if (steps.length !== 3) throw new Error(
-                `Wrong number of arguments to \`eq?\` predicate. Expected 2, got ${steps.length - 1}`
+                `Wrong number of arguments to \`${operator}\` predicate. Expected 2, got ${steps.length - 1}`
               );
               if (steps[1].type !== 'capture') throw new Error(
-                `First argument of \`eq?\` predicate must be a capture. Got "${steps[1].value}"`
+                `First argument of \`${operator}\` predicate must be a capture. Got "${steps[1].value}"`
               );
               if (steps[2].type === 'capture') {
                 const captureName1 = steps[1].name;
@@ -757,14 +759,14 @@ class Language {
                     if (c.name === captureName1) node1 = c.node;
                     if (c.name === captureName2) node2 = c.node;
                   }
-                  return node1.text === node2.text
+                  return (node1.text === node2.text) === isPositive
                 });
               } else {
                 const captureName = steps[1].name;
                 const stringValue = steps[2].value;
                 predicates[i].push(function(captures) {
                   for (const c of captures) {
-                    if (c.name === captureName) return c.node.text === stringValue;
+                    if (c.name === captureName) return (c.node.text === stringValue) === isPositive;
                   }
                   return false;
                 });
@@ -772,20 +774,21 @@ class Language {
               break;
 
             case 'match?':
+            case 'not-match?':
               if (steps.length !== 3) throw new Error(
-                `Wrong number of arguments to \`match?\` predicate. Expected 2, got ${steps.length - 1}.`
+                `Wrong number of arguments to \`${operator}\` predicate. Expected 2, got ${steps.length - 1}.`
               );
               if (steps[1].type !== 'capture') throw new Error(
-                `First argument of \`match?\` predicate must be a capture. Got "${steps[1].value}".`
+                `First argument of \`${operator}\` predicate must be a capture. Got "${steps[1].value}".`
               );
               if (steps[2].type !== 'string') throw new Error(
-                `Second argument of \`match?\` predicate must be a string. Got @${steps[2].value}.`
+                `Second argument of \`${operator}\` predicate must be a string. Got @${steps[2].value}.`
               );
               const captureName = steps[1].name;
               const regex = new RegExp(steps[2].value);
               predicates[i].push(function(captures) {
                 for (const c of captures) {
-                  if (c.name === captureName) return regex.test(c.node.text);
+                  if (c.name === captureName) return regex.test(c.node.text) === isPositive;
                 }
                 return false;
               });
@@ -35,6 +35,7 @@ typedef uint16_t TSStateId;
 typedef struct {
   bool visible : 1;
   bool named : 1;
+  bool supertype : 1;
 } TSSymbolMetadata;
 
 typedef struct TSLexer TSLexer;
@@ -114,9 +115,13 @@ struct TSLanguage {
   const TSFieldMapSlice *field_map_slices;
   const TSFieldMapEntry *field_map_entries;
   const char **field_names;
+  // begin next-abi
   uint32_t large_state_count;
   const uint16_t *small_parse_table;
   const uint32_t *small_parse_table_map;
+  uint32_t recovery_token_count;
+  const TSSymbol *recovery_tokens;
+  // end next-abi
 };
 
 /*
@@ -158,7 +163,9 @@ struct TSLanguage {
  *  Parse Table Macros
  */
 
+// begin next-abi
 #define SMALL_STATE(id) id - LARGE_STATE_COUNT
+// end next-abi
 
 #define STATE(id) id
 
@@ -140,6 +140,10 @@ static const uint16_t NONE = UINT16_MAX;
 static const TSSymbol WILDCARD_SYMBOL = 0;
 static const uint16_t MAX_STATE_COUNT = 32;
 
+// The maximum number of invisible supertype nodes that can be matched around
+// a single visible node.
+#define MAX_SUPERTYPE_COUNT 8
+
 // #define LOG printf
 #define LOG(...)
 
@@ -1043,10 +1047,14 @@ static inline bool ts_query_cursor__advance(TSQueryCursor *self) {
     } else {
       bool can_have_later_siblings;
       bool can_have_later_siblings_with_this_field;
+      TSSymbol supertypes[MAX_SUPERTYPE_COUNT];
+      unsigned supertype_count = MAX_SUPERTYPE_COUNT;
       TSFieldId field_id = ts_tree_cursor_current_status(
         &self->cursor,
         &can_have_later_siblings,
-        &can_have_later_siblings_with_this_field
+        &can_have_later_siblings_with_this_field,
+        supertypes,
+        &supertype_count
       );
       TSNode node = ts_tree_cursor_current_node(&self->cursor);
       TSSymbol symbol = ts_node_symbol(node);
@@ -1066,11 +1074,23 @@ static inline bool ts_query_cursor__advance(TSQueryCursor *self) {
         continue;
       }
 
-      // If this node is after the selected range, then stop walking.
+      // If this node is after the selected range, then stop walking. The
+      // unfinished matches can never be completed, so discard them. Otherwise,
+      // they would prevent the captures of the finished matches that follow
+      // them from being returned.
       if (
         self->end_byte <= ts_node_start_byte(node) ||
         point_lte(self->end_point, ts_node_start_point(node))
-      ) return false;
+      ) {
+        for (unsigned i = 0; i < self->states.size; i++) {
+          capture_list_pool_release(
+            &self->capture_list_pool,
+            self->states.contents[i].capture_list_id
+          );
+        }
+        array_clear(&self->states);
+        return self->finished_states.size > 0;
+      }
 
       LOG(
         "enter node %s. row:%u state_count:%u, finished_state_count: %u\n",
@@ -1101,9 +1121,12 @@ static inline bool ts_query_cursor__advance(TSQueryCursor *self) {
         }));
       }
 
-      // Add new states for any patterns whose root node matches this node.
-      unsigned i;
-      if (ts_query__pattern_map_search(self->query, symbol, &i)) {
+      // Add new states for any patterns whose root node matches this node,
+      // or one of the invisible supertype nodes that wrap it.
+      for (unsigned j = 0; j <= supertype_count; j++) {
+        TSSymbol root_symbol = j < supertype_count ? supertypes[j] : symbol;
+        unsigned i;
+        if (!ts_query__pattern_map_search(self->query, root_symbol, &i)) continue;
         PatternEntry *slice = &self->query->pattern_map.contents[i];
         QueryStep *step = &self->query->steps.contents[slice->step_index];
         do {
@@ -1136,7 +1159,7 @@ static inline bool ts_query_cursor__advance(TSQueryCursor *self) {
           if (i == self->query->pattern_map.size) break;
           slice = &self->query->pattern_map.contents[i];
           step = &self->query->steps.contents[slice->step_index];
-        } while (step->symbol == symbol);
+        } while (step->symbol == root_symbol);
       }
 
       // Update all of the in-progress states with current node.
@@ -1152,6 +1175,9 @@ static inline bool ts_query_cursor__advance(TSQueryCursor *self) {
         // if this node can have later siblings that match this step of the
         // pattern.
         bool node_does_match = !step->symbol || step->symbol == symbol;
+        for (unsigned j = 0; !node_does_match && j < supertype_count; j++) {
+          if (step->symbol == supertypes[j]) node_does_match = true;
+        }
         bool later_sibling_can_match = can_have_later_siblings;
         if (step->field) {
           if (step->field == field_id) {
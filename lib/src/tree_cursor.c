@@ -247,10 +247,14 @@ TSNode ts_tree_cursor_current_node(const TSTreeCursor *_self) {
 TSFieldId ts_tree_cursor_current_status(
   const TSTreeCursor *_self,
   bool *can_have_later_siblings,
-  bool *can_have_later_siblings_with_this_field
+  bool *can_have_later_siblings_with_this_field,
+  TSSymbol *supertypes,
+  unsigned *supertype_count
 ) {
   const TreeCursor *self = (const TreeCursor *)_self;
   TSFieldId result = 0;
+  unsigned max_supertype_count = *supertype_count;
+  *supertype_count = 0;
   *can_have_later_siblings = false;
   *can_have_later_siblings_with_this_field = false;
 
@@ -270,6 +274,18 @@ TSFieldId ts_tree_cursor_current_status(
       if (alias_sequence && alias_sequence[entry->structural_child_index]) {
         break;
       }
+
+      // Record any invisible supertype nodes that wrap the current node, so
+      // that they can be matched by queries.
+      TSSymbol entry_symbol = ts_subtree_symbol(*entry->subtree);
+      TSSymbolMetadata metadata = ts_language_symbol_metadata(
+        self->tree->language,
+        entry_symbol
+      );
+      if (metadata.supertype && *supertype_count < max_supertype_count) {
+        supertypes[*supertype_count] = entry_symbol;
+        (*supertype_count)++;
+      }
     }
 
     if (ts_subtree_child_count(*parent_entry->subtree) > entry->child_index + 1) {
@@ -0,0 +1,17 @@
+"let" @keyword
+
+(number) @number
+(string) @string
+
+(call_expression function: (identifier) @function)
+(let_statement name: (identifier) @variable.parameter)
+(identifier) @variable
+
+"=" @operator
+"+" @operator
+";" @punctuation.delimiter
+"," @punctuation.delimiter
+"(" @punctuation.bracket
+")" @punctuation.bracket
+"{" @punctuation.bracket
+"}" @punctuation.bracket
@@ -0,0 +1,5 @@
+(block) @local.scope
+
+(let_statement name: (identifier) @local.definition)
+
+(identifier) @local.reference
@@ -0,0 +1,11 @@
+(tag_name) @tag
+"<script>" @tag
+"<script" @tag
+"</script>" @tag
+(attribute_name) @attribute
+(attribute_value) @string
+
+"<" @punctuation.bracket
+">" @punctuation.bracket
+"</" @punctuation.bracket
+"=" @punctuation.delimiter
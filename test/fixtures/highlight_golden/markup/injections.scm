@@ -0,0 +1,10 @@
+; The language of a script is given by its attribute, and defaults to `expressions`.
+((script_element
+  (script_start_tag (attribute (attribute_value) @lang))
+  (raw_text) @injection.content) @injection.site
+ (set! injection.language "@lang"))
+
+((script_element
+  (script_start_tag "<script>")
+  (raw_text) @injection.content) @injection.site
+ (set! injection.language "expressions"))